@@ -0,0 +1,240 @@
+//! Import benchmarks on recorded data.
+//!
+//! Fixtures are not stored in the repo, see `benches/fixtures/README.md`.
+//! Paths could be overridden with `BENCH_ARCHIVE` and `BENCH_STATE` env vars.
+//! Benchmarks fail if the fixtures are missing.
+//!
+//! ```bash
+//! cargo bench --features test-util --bench import
+//! ```
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use ton_indexer::test_util::*;
+
+const STATE_PACKET_SIZE: usize = 1 << 20;
+
+fn fixture_path(env: &str, name: &str) -> PathBuf {
+    let path = match std::env::var_os(env) {
+        Some(path) => PathBuf::from(path),
+        None => PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("benches")
+            .join("fixtures")
+            .join(name),
+    };
+
+    assert!(
+        path.exists(),
+        "fixture {} not found, see benches/fixtures/README.md",
+        path.display()
+    );
+    path
+}
+
+fn import_package_benchmark(c: &mut Criterion) {
+    let path = fixture_path("BENCH_ARCHIVE", "archive.pack");
+    let data = bytes::Bytes::from(std::fs::read(path).unwrap());
+    let block_count = import_package(data.clone()).unwrap();
+
+    let mut group = c.benchmark_group("import_package");
+    group.throughput(Throughput::Elements(block_count as u64));
+    group.bench_function("blocks", |b| {
+        b.iter(|| import_package(data.clone()).unwrap())
+    });
+    group.finish();
+}
+
+fn import_archive_benchmark(c: &mut Criterion) {
+    let path = fixture_path("BENCH_ARCHIVE", "archive.pack");
+    let data = bytes::Bytes::from(std::fs::read(path).unwrap());
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let temp_dir =
+        std::env::temp_dir().join(format!("ton_indexer_import_archive_{}", std::process::id()));
+    let stats = rt
+        .block_on(import_archive(&temp_dir, data.clone()))
+        .unwrap();
+    assert!(stats.blocks > 0, "archive fixture is empty");
+    assert!(stats.bytes_written > 0);
+
+    let mut group = c.benchmark_group("import_archive");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(stats.blocks as u64));
+    group.bench_function("blocks", |b| {
+        b.to_async(&rt).iter_custom(|iters| {
+            let data = &data;
+            let temp_dir = &temp_dir;
+            async move {
+                let mut total = Duration::ZERO;
+                for _ in 0..iters {
+                    total += import_archive(temp_dir, data.clone())
+                        .await
+                        .unwrap()
+                        .import_time;
+                }
+                total
+            }
+        })
+    });
+    group.finish();
+}
+
+fn load_blocks_benchmark(c: &mut Criterion) {
+    const ROUNDS: usize = 10;
+
+    let path = fixture_path("BENCH_ARCHIVE", "archive.pack");
+    let data = bytes::Bytes::from(std::fs::read(path).unwrap());
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+
+    let mut group = c.benchmark_group("load_blocks");
+    group.sample_size(10);
+    let temp_dir =
+        std::env::temp_dir().join(format!("ton_indexer_load_blocks_{}", std::process::id()));
+    let blocks = rt.block_on(StoredBlocks::new(temp_dir, data)).unwrap();
+    assert!(blocks.block_count() > 0, "archive fixture has no blocks");
+
+    group.throughput(Throughput::Elements((blocks.block_count() * ROUNDS) as u64));
+    for (name, mode) in [
+        ("owned", BlockReadMode::Owned),
+        ("pinned", BlockReadMode::Pinned),
+    ] {
+        group.bench_function(name, |b| {
+            b.to_async(&rt).iter(|| async {
+                for _ in 0..ROUNDS {
+                    blocks.load_all(mode).await.unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn finalize_state_benchmark(c: &mut Criterion) {
+    let path = fixture_path("BENCH_STATE", "state.boc");
+    let state = RecordedState::load(path).unwrap();
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let temp_dir = std::env::temp_dir().join(format!("ton_indexer_bench_{}", std::process::id()));
+    let cell_count = rt
+        .block_on(import_state(&temp_dir, &state, STATE_PACKET_SIZE))
+        .unwrap()
+        .cells;
+
+    let mut group = c.benchmark_group("finalize");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(cell_count));
+    group.bench_function("cells", |b| {
+        b.to_async(&rt).iter_custom(|iters| {
+            let state = &state;
+            let temp_dir = &temp_dir;
+            async move {
+                let mut total = Duration::ZERO;
+                for _ in 0..iters {
+                    total += import_state(temp_dir, state, STATE_PACKET_SIZE)
+                        .await
+                        .unwrap()
+                        .finalize_time;
+                }
+                total
+            }
+        })
+    });
+    group.finish();
+}
+
+/// Way of removing a contiguous range of keys
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum PruneMode {
+    /// Tombstone per key in a single batch
+    PointDeletes,
+    /// Single range tombstone
+    DeleteRange,
+}
+
+/// Fills a fresh DB in the specified directory with `key_count` sequential keys
+/// (as archive ids are stored) and removes all of them.
+///
+/// Returns the time spent on the removal and the following full scan, which
+/// has to skip all written tombstones.
+///
+/// NOTE: the directory is removed afterwards
+fn prune_keys(path: &Path, key_count: u32, mode: PruneMode) -> anyhow::Result<Duration> {
+    const CF_NAME: &str = "archives";
+
+    let mut options = rocksdb::Options::default();
+    options.create_if_missing(true);
+    options.create_missing_column_families(true);
+    let db = rocksdb::DB::open_cf(&options, path, [CF_NAME])?;
+
+    let result = (|| {
+        let cf = db.cf_handle(CF_NAME).expect("column family must exist");
+
+        let mut batch = rocksdb::WriteBatch::default();
+        for id in 0..key_count {
+            batch.put_cf(&cf, id.to_be_bytes(), [0; 128]);
+        }
+        db.write(batch)?;
+        db.flush_cf(&cf)?;
+
+        let started_at = Instant::now();
+        let mut batch = rocksdb::WriteBatch::default();
+        match mode {
+            PruneMode::PointDeletes => {
+                for id in 0..key_count {
+                    batch.delete_cf(&cf, id.to_be_bytes());
+                }
+            }
+            PruneMode::DeleteRange => {
+                batch.delete_range_cf(&cf, 0u32.to_be_bytes(), key_count.to_be_bytes());
+            }
+        }
+        db.write(batch)?;
+
+        let mut iter = db.raw_iterator_cf(&cf);
+        iter.seek_to_first();
+        anyhow::ensure!(!iter.valid(), "Keys were not removed");
+        iter.status()?;
+
+        Ok(started_at.elapsed())
+    })();
+
+    drop(db);
+    std::fs::remove_dir_all(path)?;
+    result
+}
+
+fn prune_benchmark(c: &mut Criterion) {
+    const KEY_COUNT: u32 = 1_000_000;
+
+    let temp_dir = std::env::temp_dir().join(format!("ton_indexer_prune_{}", std::process::id()));
+
+    let mut group = c.benchmark_group("prune");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(KEY_COUNT as u64));
+    for (name, mode) in [
+        ("point_deletes", PruneMode::PointDeletes),
+        ("delete_range", PruneMode::DeleteRange),
+    ] {
+        group.bench_function(name, |b| {
+            b.iter_custom(|iters| {
+                (0..iters)
+                    .map(|_| prune_keys(&temp_dir, KEY_COUNT, mode).unwrap())
+                    .sum()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    import_package_benchmark,
+    import_archive_benchmark,
+    load_blocks_benchmark,
+    finalize_state_benchmark,
+    prune_benchmark
+);
+criterion_main!(benches);
@@ -26,6 +26,10 @@ pub struct App {
     /// path to the global config with zerostate and static dht nodes
     #[argh(option, default = "String::from(\"ton-global.config.json\")")]
     pub global_config: String,
+
+    /// only check the key block proofs chain used by the cold boot and exit
+    #[argh(switch)]
+    pub verify_only: bool,
 }
 
 #[tokio::main]
@@ -55,6 +59,16 @@ async fn run(app: App) -> Result<()> {
     let subscribers =
         vec![Arc::new(LoggerSubscriber::default()) as Arc<dyn ton_indexer::Subscriber>];
 
+    if app.verify_only {
+        let report = Engine::verify_boot(config.indexer, global_config).await?;
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        anyhow::ensure!(
+            report.proof_chain_verified,
+            "key block proofs chain is invalid"
+        );
+        return Ok(());
+    }
+
     let engine = Engine::new(config.indexer, global_config, subscribers).await?;
     engine.start().await?;
 
@@ -0,0 +1,194 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use argh::FromArgs;
+use serde::Serialize;
+
+use ton_indexer::maintenance::MaintenanceDb;
+use ton_indexer::utils::parse_block_id;
+
+#[derive(Debug, FromArgs)]
+#[argh(description = "Offline maintenance of the stopped node DB")]
+struct App {
+    /// path to the rocksdb directory
+    #[argh(option, default = "PathBuf::from(\"db/rocksdb\")")]
+    rocks_db_path: PathBuf,
+
+    /// path to the file db directory
+    #[argh(option, default = "PathBuf::from(\"db/files\")")]
+    file_db_path: PathBuf,
+
+    /// rocksdb cache size in bytes
+    #[argh(option, default = "256 << 20")]
+    mem_limit: usize,
+
+    #[argh(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand)]
+enum Command {
+    Status(CmdStatus),
+    VerifyStorage(CmdVerifyStorage),
+    ExportArchive(CmdExportArchive),
+    ExportState(CmdExportState),
+    Gc(CmdGc),
+    RebuildIndex(CmdRebuildIndex),
+    AuditLog(CmdAuditLog),
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "status")]
+/// Prints node state pointers
+struct CmdStatus {}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "verify-storage")]
+/// Checks blocks and proofs in the masterchain range (inclusive)
+struct CmdVerifyStorage {
+    /// masterchain seqno range in `FROM..TO` format
+    #[argh(option, from_str_fn(parse_range))]
+    range: (u32, u32),
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "export-archive")]
+/// Writes blocks in the masterchain range (inclusive) into the archive package
+struct CmdExportArchive {
+    /// first masterchain seqno
+    #[argh(option)]
+    from: u32,
+
+    /// last masterchain seqno
+    #[argh(option)]
+    to: u32,
+
+    /// output file
+    #[argh(option)]
+    out: PathBuf,
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "export-state")]
+/// Writes the shard state as a BOC
+struct CmdExportState {
+    /// full block id in `(workchain,shard,seqno):root_hash:file_hash` format
+    #[argh(option, from_str_fn(parse_block_id_arg))]
+    block_id: ton_block::BlockIdExt,
+
+    /// output file
+    #[argh(option)]
+    out: PathBuf,
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "gc")]
+/// Removes blocks before the key block with seqno not greater than the specified one
+struct CmdGc {
+    /// masterchain seqno
+    #[argh(option)]
+    blocks_before_seqno: u32,
+
+    /// max number of blocks removed in one batch
+    #[argh(option)]
+    max_blocks_per_batch: Option<usize>,
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "rebuild-index")]
+/// Restores the key blocks index
+struct CmdRebuildIndex {}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "audit-log")]
+/// Prints the latest chain progress decisions, newest first
+struct CmdAuditLog {
+    /// max number of entries
+    #[argh(option, default = "100")]
+    limit: usize,
+}
+
+/// Exits with code 2 if the command finished but found problems
+fn main() {
+    let app: App = argh::from_env();
+
+    let runtime = match tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("Failed to create runtime: {e:?}");
+            std::process::exit(1);
+        }
+    };
+
+    let code = match runtime.block_on(run(app)) {
+        Ok(true) => 0,
+        Ok(false) => 2,
+        Err(e) => {
+            eprintln!("Fatal error: {e:?}");
+            1
+        }
+    };
+
+    // NOTE: `exit` does not run destructors
+    drop(runtime);
+    std::process::exit(code);
+}
+
+/// Returns `false` if the command finished but found problems
+async fn run(app: App) -> Result<bool> {
+    // NOTE: only modifying commands require the node to be stopped
+    let read_only = !matches!(app.command, Command::Gc(_) | Command::RebuildIndex(_));
+    let db = MaintenanceDb::open(
+        app.rocks_db_path,
+        app.file_db_path,
+        app.mem_limit,
+        read_only,
+    )
+    .await?;
+
+    match app.command {
+        Command::Status(_) => print_json(&db.status()?),
+        Command::VerifyStorage(cmd) => {
+            let report = db.verify_storage(cmd.range.0, cmd.range.1).await?;
+            print_json(&report)?;
+            return Ok(report.is_ok());
+        }
+        Command::ExportArchive(cmd) => {
+            print_json(&db.export_archive(cmd.from, cmd.to, cmd.out).await?)
+        }
+        Command::ExportState(cmd) => print_json(&db.export_state(&cmd.block_id, cmd.out).await?),
+        Command::Gc(cmd) => print_json(
+            &db.gc_blocks(cmd.blocks_before_seqno, cmd.max_blocks_per_batch)
+                .await?,
+        ),
+        Command::RebuildIndex(_) => print_json(&db.rebuild_index()?),
+        Command::AuditLog(cmd) => print_json(&db.audit_log(cmd.limit)?),
+    }?;
+
+    Ok(true)
+}
+
+fn print_json<T: Serialize>(value: &T) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+fn parse_range(value: &str) -> Result<(u32, u32), String> {
+    let (from, to) = value
+        .split_once("..")
+        .ok_or_else(|| "expected `FROM..TO`".to_owned())?;
+    let from = from.parse::<u32>().map_err(|e| e.to_string())?;
+    let to = to.parse::<u32>().map_err(|e| e.to_string())?;
+    if from > to {
+        return Err("invalid range".to_owned());
+    }
+    Ok((from, to))
+}
+
+fn parse_block_id_arg(value: &str) -> Result<ton_block::BlockIdExt, String> {
+    parse_block_id(value).map_err(|e| e.to_string())
+}
@@ -19,21 +19,76 @@ pub struct NodeConfig {
 
     pub rocks_db_path: PathBuf,
     pub file_db_path: PathBuf,
+    /// Directory for temporary files (state import, large archives).
+    /// Relative path is resolved against `file_db_path`, the directory must be inside it.
+    /// Default: `downloads`
+    pub temp_files_path: PathBuf,
 
     pub state_gc_options: Option<StateGcOptions>,
     pub blocks_gc_options: Option<BlocksGcOptions>,
+    /// Free disk space thresholds for the RocksDB and file DB volumes.
+    /// Disk space is not watched if not specified. Default: None
+    pub disk_watermarks: Option<DiskWatermarksOptions>,
     pub shard_state_cache_options: Option<ShardStateCacheOptions>,
+    /// Caches warm-up after boot. Disabled if not specified. Default: enabled
+    pub warmup_options: Option<WarmupOptions>,
+    /// Log of the chain progress decisions. Disabled if not specified. Default: enabled
+    pub audit_log_options: Option<AuditLogOptions>,
 
     pub max_db_memory_usage: usize,
+    pub db_options: DbOptions,
+
+    /// Max memory occupied by in-flight archives and state packets.
+    /// Default: 2147483648 (2 GB)
+    pub max_sync_memory_usage: usize,
 
     pub archive_options: Option<ArchiveOptions>,
     pub sync_options: SyncOptions,
+    pub broadcast_options: BroadcastOptions,
+
+    /// Whether to notify subscribers about shard blocks of each masterchain block
+    /// in a stable order (by workchain, seqno and shard) after all of them are applied.
+    ///
+    /// Blocks of each shard are delivered in strictly increasing seqno and
+    /// masterchain block is delivered only after all shard blocks it commits.
+    ///
+    /// NOTE: the position of the delivered notifications is stored in the DB, so
+    /// already applied blocks which were not delivered before the restart are
+    /// delivered after it. Blocks from archives are always delivered in this order.
+    /// Default: false
+    pub ordered_shard_notifications: bool,
+
+    /// Whether to index transactions by the hash of their inbound message.
+    ///
+    /// NOTE: only blocks applied while the index is enabled are indexed,
+    /// entries of the removed blocks are removed by the blocks GC. Default: false
+    pub index_messages: bool,
+
+    /// Error policy of all subscribers, overrides the one returned by
+    /// `Subscriber::error_policy`. Default: None
+    pub subscriber_error_policy: Option<SubscriberErrorPolicy>,
+
+    /// Whether to refuse starting with an empty DB instead of running
+    /// the cold boot from the network. Used when the DB is seeded from
+    /// a trusted snapshot. Default: false
+    pub require_preseeded: bool,
+
+    /// Masterchain block seqno from which the indexing starts. Older blocks are ignored.
+    ///
+    /// Cold boot starts from the oldest persistent key block at or after it,
+    /// and the historical sync (if enabled) never goes below it.
+    /// Blocks GC (if enabled) removes blocks before the key block at or before it.
+    ///
+    /// NOTE: the value can't be lowered (or removed) for the existing DB.
+    /// Default: None (the latest suitable key block, see `sync_options.sync_from_seqno`)
+    pub start_from_seqno: Option<u32>,
 
     pub adnl_options: adnl::NodeOptions,
     pub rldp_options: rldp::NodeOptions,
     pub dht_options: dht::NodeOptions,
     pub overlay_shard_options: overlay::OverlayOptions,
     pub neighbours_options: NeighboursOptions,
+    pub network_options: NetworkOptions,
 }
 
 impl Default for NodeConfig {
@@ -43,17 +98,253 @@ impl Default for NodeConfig {
             adnl_keys: Default::default(),
             rocks_db_path: "db/rocksdb".into(),
             file_db_path: "db/file".into(),
+            temp_files_path: "downloads".into(),
             state_gc_options: None,
             blocks_gc_options: None,
+            disk_watermarks: None,
             shard_state_cache_options: Some(Default::default()),
+            warmup_options: Some(Default::default()),
+            audit_log_options: Some(Default::default()),
             archive_options: Some(Default::default()),
             max_db_memory_usage: default_max_db_memory_usage(),
+            db_options: Default::default(),
+            max_sync_memory_usage: 2048 * 1024 * 1024,
             sync_options: Default::default(),
+            broadcast_options: Default::default(),
+            ordered_shard_notifications: false,
+            index_messages: false,
+            subscriber_error_policy: None,
+            require_preseeded: false,
+            start_from_seqno: None,
             adnl_options: Default::default(),
             rldp_options: Default::default(),
             dht_options: Default::default(),
             overlay_shard_options: Default::default(),
             neighbours_options: Default::default(),
+            network_options: Default::default(),
+        }
+    }
+}
+
+impl NodeConfig {
+    /// Checks config values and returns all found problems at once
+    pub fn validate(&self) -> Result<(), NodeConfigErrors> {
+        let mut errors = Vec::new();
+
+        if self.ip_address.port() == 0 {
+            errors.push(NodeConfigError::ZeroPort);
+        }
+
+        if self.rocks_db_path.as_os_str().is_empty() {
+            errors.push(NodeConfigError::EmptyPath("rocks_db_path"));
+        }
+        if self.file_db_path.as_os_str().is_empty() {
+            errors.push(NodeConfigError::EmptyPath("file_db_path"));
+        }
+        if self.temp_files_path.as_os_str().is_empty() {
+            errors.push(NodeConfigError::EmptyPath("temp_files_path"));
+        }
+        if !self.rocks_db_path.as_os_str().is_empty() && self.rocks_db_path == self.file_db_path {
+            errors.push(NodeConfigError::SameDbPaths);
+        }
+
+        if self.max_db_memory_usage == 0 {
+            errors.push(NodeConfigError::ZeroValue("max_db_memory_usage"));
+        }
+        if self.max_sync_memory_usage == 0 {
+            errors.push(NodeConfigError::ZeroValue("max_sync_memory_usage"));
+        }
+        if self.sync_options.parallel_archive_downloads == 0 {
+            errors.push(NodeConfigError::ZeroValue(
+                "sync_options.parallel_archive_downloads",
+            ));
+        }
+
+        if self.start_from_seqno.is_some() && self.sync_options.sync_from_seqno.is_some() {
+            errors.push(NodeConfigError::ConflictingOptions(
+                "start_from_seqno",
+                "sync_options.sync_from_seqno",
+            ));
+        }
+
+        if self.network_options.max_archive_size == 0 {
+            errors.push(NodeConfigError::ZeroValue(
+                "network_options.max_archive_size",
+            ));
+        }
+
+        if self.db_options.archive_slice_size == 0 {
+            errors.push(NodeConfigError::ZeroValue("db_options.archive_slice_size"));
+        }
+
+        if self.sync_options.archive_stride == 0 {
+            errors.push(NodeConfigError::ZeroValue("sync_options.archive_stride"));
+        }
+        if self.sync_options.key_block_archive_stride == Some(0) {
+            errors.push(NodeConfigError::ZeroValue(
+                "sync_options.key_block_archive_stride",
+            ));
+        }
+
+        if self.sync_options.max_pending_archives < self.sync_options.parallel_archive_downloads {
+            errors.push(NodeConfigError::PendingArchivesLimit);
+        }
+        if self.sync_options.parallel_state_downloads == 0 {
+            errors.push(NodeConfigError::ZeroValue(
+                "sync_options.parallel_state_downloads",
+            ));
+        }
+        if self.sync_options.verification_threads == 0 {
+            errors.push(NodeConfigError::ZeroValue(
+                "sync_options.verification_threads",
+            ));
+        }
+        if self.sync_options.shard_apply_concurrency == 0 {
+            errors.push(NodeConfigError::ZeroValue(
+                "sync_options.shard_apply_concurrency",
+            ));
+        }
+        if self.sync_options.max_import_attempts == 0 {
+            errors.push(NodeConfigError::ZeroValue(
+                "sync_options.max_import_attempts",
+            ));
+        }
+        if let Some(options) = &self.sync_options.balance_history {
+            if matches!(options.to_seqno, Some(to) if to < options.from_seqno) {
+                errors.push(NodeConfigError::BalanceHistoryRange);
+            }
+        }
+        match self.sync_options.progress_log {
+            SyncProgressLog::EveryNthArchive { n: 0 } => {
+                errors.push(NodeConfigError::ZeroValue("sync_options.progress_log.n"));
+            }
+            SyncProgressLog::Summary { interval_sec: 0 } => {
+                errors.push(NodeConfigError::ZeroValue(
+                    "sync_options.progress_log.interval_sec",
+                ));
+            }
+            _ => {}
+        }
+        if self.broadcast_options.buffer_size == 0 {
+            errors.push(NodeConfigError::ZeroValue("broadcast_options.buffer_size"));
+        }
+        if let BroadcastStorePolicy::StoreWithinHorizon { buffer_size: 0, .. } =
+            self.broadcast_options.store_policy
+        {
+            errors.push(NodeConfigError::ZeroValue(
+                "broadcast_options.store_policy.buffer_size",
+            ));
+        }
+
+        if let Some(options) = &self.state_gc_options {
+            if options.interval_sec == 0 {
+                errors.push(NodeConfigError::ZeroValue("state_gc_options.interval_sec"));
+            }
+        }
+
+        if let Some(options) = &self.blocks_gc_options {
+            if options.max_blocks_per_batch == Some(0) {
+                errors.push(NodeConfigError::ZeroValue(
+                    "blocks_gc_options.max_blocks_per_batch",
+                ));
+            }
+        }
+
+        if let Some(options) = &self.audit_log_options {
+            if options.capacity == 0 {
+                errors.push(NodeConfigError::ZeroValue("audit_log_options.capacity"));
+            }
+            if options.queue_size == 0 {
+                errors.push(NodeConfigError::ZeroValue("audit_log_options.queue_size"));
+            }
+        }
+
+        if let Some(options) = &self.disk_watermarks {
+            if options.check_interval_sec == 0 {
+                errors.push(NodeConfigError::ZeroValue(
+                    "disk_watermarks.check_interval_sec",
+                ));
+            }
+            if options.hard_threshold > options.soft_threshold {
+                errors.push(NodeConfigError::DiskWatermarksOrder);
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(NodeConfigErrors(errors))
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct NodeConfigErrors(pub Vec<NodeConfigError>);
+
+impl std::fmt::Display for NodeConfigErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Invalid node config:")?;
+        for error in &self.0 {
+            write!(f, "\n- {error}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for NodeConfigErrors {}
+
+#[derive(thiserror::Error, Debug, Copy, Clone, Eq, PartialEq)]
+pub enum NodeConfigError {
+    #[error("`ip_address` must have a non-zero port")]
+    ZeroPort,
+    #[error("`{0}` must not be empty")]
+    EmptyPath(&'static str),
+    #[error("`rocks_db_path` and `file_db_path` must be different")]
+    SameDbPaths,
+    #[error("`{0}` must be greater than zero")]
+    ZeroValue(&'static str),
+    #[error("`disk_watermarks.hard_threshold` must not exceed `soft_threshold`")]
+    DiskWatermarksOrder,
+    #[error(
+        "`sync_options.max_pending_archives` must not be less than `parallel_archive_downloads`"
+    )]
+    PendingArchivesLimit,
+    #[error("`{0}` and `{1}` can't be used together")]
+    ConflictingOptions(&'static str, &'static str),
+    #[error("`sync_options.balance_history.to_seqno` must not be less than `from_seqno`")]
+    BalanceHistoryRange,
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DbOptions {
+    /// How long to keep temp files of the resumable state downloads.
+    /// All other temp files are removed at startup. Default: 86400
+    pub temp_files_ttl_sec: u64,
+    /// Whether to repair the corrupted DB at startup instead of failing.
+    ///
+    /// NOTE: the DB is repaired only if all corrupted columns are derivative
+    /// (message index), they are cleared and rebuilt from the stored blocks.
+    /// Otherwise the DB is left as is to be restored from backup.
+    /// Default: false
+    pub recover_corrupt_cfs: bool,
+    /// Number of masterchain blocks in the slice of stored archives. Archive ids
+    /// are aligned to the slice start (or to the last key block inside it).
+    ///
+    /// NOTE: must match the archive layout of the network peers. Default: 20000
+    pub archive_slice_size: u32,
+    /// How long concurrent stores of the same shard state wait for the first one
+    /// before failing. Default: 600
+    pub store_state_wait_timeout_sec: u64,
+}
+
+impl Default for DbOptions {
+    fn default() -> Self {
+        Self {
+            temp_files_ttl_sec: 86400,
+            recover_corrupt_cfs: false,
+            archive_slice_size: 20_000,
+            store_state_wait_timeout_sec: 600,
         }
     }
 }
@@ -84,27 +375,250 @@ impl Default for ArchivesGcInterval {
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkOptions {
+    /// Max size of the archive received from a peer. Download is aborted
+    /// and the peer is penalized when it is exceeded. Default: 134217728 (128 MB)
+    pub max_archive_size: u64,
+}
+
+impl Default for NetworkOptions {
+    fn default() -> Self {
+        Self {
+            max_archive_size: 128 * 1024 * 1024,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct SyncOptions {
     /// Whether to sync very old blocks
     pub old_blocks_policy: OldBlocksPolicy,
+    /// Where to get archives from. Default: `network`
+    pub source: SyncSource,
+    /// How shard blocks from archives are verified. Default: `full`
+    pub proof_mode: ArchiveProofMode,
     /// Default: 16
     pub parallel_archive_downloads: usize,
+    /// Expected number of masterchain blocks in archives served by peers.
+    /// Used as the initial guess, the actual size is learned from received archives.
+    /// Default: 100
+    pub archive_stride: u32,
+    /// Expected number of masterchain blocks in archives which start with a key block
+    /// (for networks where key blocks start new archives of a different size).
+    /// Default: None (same as `archive_stride`)
+    pub key_block_archive_stride: Option<u32>,
+    /// Max number of archives which are being downloaded or wait for the import.
+    /// Prefetched archives with the highest seqno are dropped when it is exceeded.
+    /// Default: 64
+    pub max_pending_archives: usize,
+    /// Max number of shard blocks with states downloaded at the same time
+    /// during cold boot (after the masterchain state). Default: 4
+    pub parallel_state_downloads: usize,
     /// Default: 1073741824 (1 GB)
     pub save_to_disk_threshold: usize,
+    /// Archives larger than this are written to the temp file
+    /// regardless of `save_to_disk_threshold`. Default: 33554432 (32 MB)
+    pub large_archive_threshold: usize,
+    /// Max number of proof checks running at the same time
+    /// outside the async runtime threads. Default: half of CPUs
+    pub verification_threads: usize,
+    /// How often to log the progress of imported archives. Default: `each_archive`
+    pub progress_log: SyncProgressLog,
+    /// Masterchain block seqno from which to start the forward sync on cold boot.
+    /// Subscribers are notified starting from the blocks of this masterchain block.
+    ///
+    /// Peers serve states only for persistent key blocks, so the state is downloaded
+    /// for the latest such key block not newer than the seqno, and blocks between them
+    /// are applied without notifications.
+    ///
+    /// NOTE: blocks before that key block are not downloaded (unless the historical
+    /// sync is enabled by `old_blocks_policy`), so such node can't serve the full history.
+    /// Ignored if the node has already booted. Default: None (the latest suitable key block)
+    pub sync_from_seqno: Option<u32>,
+    /// Max allowed difference between the local clock and the key blocks
+    /// received from peers during cold boot. Default: 600
+    pub max_clock_skew_sec: u32,
+    /// Whether to stop cold boot when the local clock is skewed
+    /// (otherwise only an error is logged). Default: false
+    pub refuse_boot_on_clock_skew: bool,
+    /// Max allowed difference between the generation time of the applied block
+    /// and the local time. Such blocks are still applied, but reported
+    /// and their time is clamped for the sync lag computation. Default: 60
+    pub max_block_time_ahead_sec: u32,
+    /// Max number of import attempts of the downloaded archive
+    /// before downloading it again. Default: 3
+    pub max_import_attempts: u32,
+    /// Delay before the second import attempt, doubled for each next one.
+    /// Default: 1000
+    pub import_retry_interval_ms: u64,
+    /// Max number of key block proofs of the next batch downloaded during cold boot
+    /// while the current batch is verified. Prefetch is disabled if zero. Default: 5
+    pub key_block_proofs_prefetch: usize,
+    /// Max number of shard blocks applied at the same time, shared by the shard client
+    /// and the archives import. Default: number of physical cores, at most 8
+    pub shard_apply_concurrency: usize,
+    /// Balance changes of the watched accounts extracted during the historical sync.
+    ///
+    /// NOTE: records are passed to the sink set by `Engine::set_balance_sink`.
+    /// Default: None
+    pub balance_history: Option<BalanceHistoryOptions>,
 }
 
 impl Default for SyncOptions {
     fn default() -> Self {
         Self {
             old_blocks_policy: Default::default(),
+            source: Default::default(),
+            proof_mode: Default::default(),
             parallel_archive_downloads: 16,
+            archive_stride: 100,
+            key_block_archive_stride: None,
+            max_pending_archives: 64,
+            parallel_state_downloads: 4,
             save_to_disk_threshold: 1024 * 1024 * 1024,
+            large_archive_threshold: 32 * 1024 * 1024,
+            verification_threads: std::cmp::max(num_cpus::get() / 2, 1),
+            progress_log: Default::default(),
+            sync_from_seqno: None,
+            max_clock_skew_sec: 600,
+            refuse_boot_on_clock_skew: false,
+            max_block_time_ahead_sec: 60,
+            max_import_attempts: 3,
+            import_retry_interval_ms: 1000,
+            key_block_proofs_prefetch: 5,
+            shard_apply_concurrency: std::cmp::min(num_cpus::get_physical(), 8),
+            balance_history: None,
         }
     }
 }
 
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BalanceHistoryOptions {
+    /// Watched accounts in `workchain:hex` format
+    #[serde(with = "serde_accounts")]
+    pub accounts: Vec<(i32, ton_types::UInt256)>,
+    /// First masterchain block seqno of the range (inclusive). Default: 0
+    pub from_seqno: u32,
+    /// Last masterchain block seqno of the range (inclusive).
+    /// Default: None (until the end of the historical sync)
+    pub to_seqno: Option<u32>,
+}
+
+impl BalanceHistoryOptions {
+    pub fn contains_seqno(&self, mc_seq_no: u32) -> bool {
+        mc_seq_no >= self.from_seqno && matches!(self.to_seqno, None | Some(to) if mc_seq_no <= to)
+    }
+}
+
+mod serde_accounts {
+    use serde::de::Error;
+
+    use super::*;
+
+    pub fn serialize<S>(
+        accounts: &[(i32, ton_types::UInt256)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        accounts
+            .iter()
+            .map(|(workchain, account)| format!("{workchain}:{}", account.to_hex_string()))
+            .collect::<Vec<_>>()
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<(i32, ton_types::UInt256)>, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|account| {
+                parse_account(account)
+                    .ok_or_else(|| D::Error::custom(format!("Invalid account: {account}")))
+            })
+            .collect()
+    }
+
+    fn parse_account(account: &str) -> Option<(i32, ton_types::UInt256)> {
+        let (workchain, account) = account.split_once(':')?;
+        let workchain = workchain.parse().ok()?;
+        let account: [u8; 32] = hex::decode(account).ok()?.try_into().ok()?;
+        Some((workchain, ton_types::UInt256::from(account)))
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum SyncProgressLog {
+    /// Log each imported archive
+    EachArchive,
+    /// Log only every Nth imported archive
+    EveryNthArchive { n: u32 },
+    /// Log the summary of imported archives once per interval
+    Summary { interval_sec: u64 },
+}
+
+impl Default for SyncProgressLog {
+    fn default() -> Self {
+        Self::EachArchive
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum SubscriberErrorPolicy {
+    /// Propagate error to the block application
+    Fail,
+    /// Log error and continue with the next subscriber
+    LogAndContinue,
+    /// Call subscriber again up to `retries` times, then propagate error.
+    /// The delay before each retry starts from `backoff_ms` and is doubled
+    Retry { retries: usize, backoff_ms: u64 },
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncSource {
+    /// Download all archives from peers
+    Network,
+    /// Use the archives stored in the local DB and download only the missing ones.
+    ///
+    /// NOTE: each local archive is used only once, so the archive which
+    /// was rejected during the import is downloaded from peers
+    LocalFirst,
+}
+
+impl Default for SyncSource {
+    fn default() -> Self {
+        Self::Network
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveProofMode {
+    /// Check shard block proof links and ensure that shard blocks
+    /// are the ones committed by the masterchain blocks
+    Full,
+    /// Check only shard block proof links.
+    ///
+    /// NOTE: should only be used for replays of the trusted archives
+    LinkOnly,
+}
+
+impl Default for ArchiveProofMode {
+    fn default() -> Self {
+        Self::Full
+    }
+}
+
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
 pub enum OldBlocksPolicy {
@@ -118,6 +632,63 @@ impl Default for OldBlocksPolicy {
     }
 }
 
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BroadcastOptions {
+    /// Max number of block broadcasts (per workchain) being processed at the same time.
+    /// Default: 256
+    pub buffer_size: usize,
+    /// What to do with new broadcasts when the buffer is full. Default: `drop`
+    pub overflow_policy: BroadcastOverflowPolicy,
+    /// Which broadcast blocks to write into the storage. Default: `store_all`
+    pub store_policy: BroadcastStorePolicy,
+}
+
+impl Default for BroadcastOptions {
+    fn default() -> Self {
+        Self {
+            buffer_size: 256,
+            overflow_policy: Default::default(),
+            store_policy: Default::default(),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BroadcastOverflowPolicy {
+    /// Drop new broadcasts until there is free space in the buffer
+    Drop,
+    /// Stop receiving broadcasts until there is free space in the buffer
+    /// (they will be dropped by the overlay instead)
+    Wait,
+}
+
+impl Default for BroadcastOverflowPolicy {
+    fn default() -> Self {
+        Self::Drop
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum BroadcastStorePolicy {
+    /// Store all received blocks
+    StoreAll,
+    /// Store only blocks which reference masterchain blocks not further than `horizon`
+    /// from the corresponding client. Other blocks are kept in memory (up to `buffer_size`)
+    /// until the client reaches them
+    StoreWithinHorizon { horizon: u32, buffer_size: usize },
+    /// Store only blocks which could be applied immediately
+    ApplyOnly,
+}
+
+impl Default for BroadcastStorePolicy {
+    fn default() -> Self {
+        Self::StoreAll
+    }
+}
+
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct StateGcOptions {
@@ -136,6 +707,70 @@ impl Default for StateGcOptions {
     }
 }
 
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WarmupOptions {
+    /// Number of the last key blocks to preload with their proofs. Default: 4
+    pub key_blocks: usize,
+    /// Number of the last block handles (with their seqno lookup entries)
+    /// to preload for each shard. Default: 16
+    pub shard_blocks: usize,
+    /// Depth of the top shard states cells tree to prefetch. Default: 4
+    pub state_depth: usize,
+}
+
+impl Default for WarmupOptions {
+    fn default() -> Self {
+        Self {
+            key_blocks: 4,
+            shard_blocks: 16,
+            state_depth: 4,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditLogOptions {
+    /// Max number of the stored entries. Default: 100000
+    pub capacity: u64,
+    /// Max number of entries waiting to be written.
+    /// New entries are dropped when the queue is full. Default: 4096
+    pub queue_size: usize,
+}
+
+impl Default for AuditLogOptions {
+    fn default() -> Self {
+        Self {
+            capacity: 100_000,
+            queue_size: 4096,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiskWatermarksOptions {
+    /// Default: 10
+    pub check_interval_sec: u64,
+    /// Available space in bytes below which GC is triggered immediately
+    /// and historical sync is paused. Default: 21474836480 (20 GB)
+    pub soft_threshold: u64,
+    /// Available space in bytes below which all downloads and block
+    /// application are paused. Default: 5368709120 (5 GB)
+    pub hard_threshold: u64,
+}
+
+impl Default for DiskWatermarksOptions {
+    fn default() -> Self {
+        Self {
+            check_interval_sec: 10,
+            soft_threshold: 20 << 30,
+            hard_threshold: 5 << 30,
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct BlocksGcOptions {
@@ -188,3 +823,74 @@ pub fn default_max_db_memory_usage() -> usize {
     let total = sys.total_memory() * 1024;
     (total / 3) as usize
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_config_is_valid() {
+        NodeConfig::default().validate().unwrap();
+    }
+
+    #[test]
+    fn all_problems_are_reported() {
+        let mut config = NodeConfig::default();
+        config.file_db_path = config.rocks_db_path.clone();
+        config.sync_options.parallel_archive_downloads = 0;
+
+        let errors = config.validate().unwrap_err();
+        assert_eq!(
+            errors.0,
+            [
+                NodeConfigError::SameDbPaths,
+                NodeConfigError::ZeroValue("sync_options.parallel_archive_downloads"),
+            ]
+        );
+    }
+
+    #[test]
+    fn zero_archive_stride() {
+        let mut config = NodeConfig::default();
+        config.db_options.archive_slice_size = 0;
+        config.sync_options.archive_stride = 0;
+        config.sync_options.key_block_archive_stride = Some(0);
+
+        let errors = config.validate().unwrap_err();
+        assert_eq!(
+            errors.0,
+            [
+                NodeConfigError::ZeroValue("db_options.archive_slice_size"),
+                NodeConfigError::ZeroValue("sync_options.archive_stride"),
+                NodeConfigError::ZeroValue("sync_options.key_block_archive_stride"),
+            ]
+        );
+    }
+
+    #[test]
+    fn balance_history_accounts() {
+        let options: BalanceHistoryOptions = serde_json::from_value(serde_json::json!({
+            "accounts": [format!("-1:{}", "33".repeat(32))],
+            "from_seqno": 100,
+        }))
+        .unwrap();
+        assert_eq!(
+            options.accounts,
+            [(-1, ton_types::UInt256::from([0x33; 32]))]
+        );
+        assert!(!options.contains_seqno(99));
+        assert!(options.contains_seqno(100));
+        assert!(options.contains_seqno(u32::MAX));
+
+        let roundtrip: BalanceHistoryOptions =
+            serde_json::from_value(serde_json::to_value(&options).unwrap()).unwrap();
+        assert_eq!(roundtrip.accounts, options.accounts);
+
+        for invalid in ["0:1234", "abc:00", "0"] {
+            let result = serde_json::from_value::<BalanceHistoryOptions>(
+                serde_json::json!({ "accounts": [invalid] }),
+            );
+            assert!(result.is_err(), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,359 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use ton_types::ByteOrderRead;
+
+use super::{columns, Tree};
+use crate::utils::*;
+
+/// Bounded append-only log of the chain progress decisions
+pub struct AuditLogStorage {
+    entries: Tree<columns::AuditLog>,
+    first_id: AtomicU64,
+    next_id: AtomicU64,
+}
+
+impl AuditLogStorage {
+    pub fn with_db(db: &Arc<rocksdb::DB>) -> Result<Self> {
+        let entries = Tree::<columns::AuditLog>::new(db)?;
+
+        let (first_id, next_id) = {
+            let mut iter = entries.raw_iterator();
+
+            iter.seek_to_first();
+            let first_id = iter.key().map(read_entry_id).transpose()?;
+
+            iter.seek_to_last();
+            let last_id = iter.key().map(read_entry_id).transpose()?;
+            iter.status()?;
+
+            match (first_id, last_id) {
+                (Some(first_id), Some(last_id)) => (first_id, last_id + 1),
+                _ => (0, 0),
+            }
+        };
+
+        Ok(Self {
+            entries,
+            first_id: AtomicU64::new(first_id),
+            next_id: AtomicU64::new(next_id),
+        })
+    }
+
+    /// Appends entries and removes the oldest ones so that
+    /// at most `capacity` entries are stored.
+    ///
+    /// NOTE: must be called from a single writer
+    pub fn append(&self, entries: &[AuditLogEntry], capacity: u64) -> Result<()> {
+        const MAX_POINT_DELETES: u64 = 16;
+
+        let cf = self.entries.get_cf();
+        let mut batch = rocksdb::WriteBatch::default();
+
+        let mut next_id = self.next_id.load(Ordering::Acquire);
+        for entry in entries {
+            batch.put_cf(&cf, next_id.to_be_bytes(), entry.to_vec());
+            next_id += 1;
+        }
+
+        let first_id = self.first_id.load(Ordering::Acquire);
+        let new_first_id = std::cmp::max(next_id.saturating_sub(capacity), first_id);
+        if new_first_id - first_id > MAX_POINT_DELETES {
+            // NOTE: happens only after the capacity was reduced
+            batch.delete_range_cf(&cf, first_id.to_be_bytes(), new_first_id.to_be_bytes());
+        } else {
+            for id in first_id..new_first_id {
+                batch.delete_cf(&cf, id.to_be_bytes());
+            }
+        }
+
+        self.entries.raw_db_handle().write(batch)?;
+
+        self.first_id.store(new_first_id, Ordering::Release);
+        self.next_id.store(next_id, Ordering::Release);
+        Ok(())
+    }
+
+    /// Loads at most `limit` latest entries, newest first
+    pub fn load(&self, limit: usize) -> Result<Vec<AuditLogEntry>> {
+        let mut result = Vec::with_capacity(std::cmp::min(limit, 1024));
+
+        let mut iter = self.entries.raw_iterator();
+        iter.seek_to_last();
+        while result.len() < limit {
+            match iter.value() {
+                Some(value) => result.push(AuditLogEntry::from_slice(value)?),
+                None => break,
+            }
+            iter.prev();
+        }
+        iter.status()?;
+
+        Ok(result)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct AuditLogEntry {
+    /// Unix timestamp in milliseconds
+    pub timestamp: u64,
+    pub kind: AuditLogEventKind,
+    /// Serialized in the archive entry filename format
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_block_id",
+        deserialize_with = "deserialize_block_id"
+    )]
+    pub block_id: Option<ton_block::BlockIdExt>,
+    pub reason: String,
+}
+
+impl StoredValue for AuditLogEntry {
+    /// 8 bytes timestamp,
+    /// 1 byte kind,
+    /// 1 byte block id flag and optional block id,
+    /// 2 bytes reason length and the reason
+    const SIZE_HINT: usize = 8 + 1 + 1 + ton_block::BlockIdExt::SIZE_HINT + 2 + 64;
+
+    type OnStackSlice = [u8; Self::SIZE_HINT];
+
+    fn serialize<T: StoredValueBuffer>(&self, buffer: &mut T) {
+        buffer.write_raw_slice(&self.timestamp.to_le_bytes());
+        buffer.write_byte(self.kind as u8);
+        match &self.block_id {
+            Some(block_id) => {
+                buffer.write_byte(1);
+                StoredValue::serialize(block_id, buffer);
+            }
+            None => buffer.write_byte(0),
+        }
+
+        // NOTE: reasons are short static descriptions, so they are just truncated
+        let reason = truncate_str(&self.reason, u16::MAX as usize);
+        buffer.write_raw_slice(&(reason.len() as u16).to_le_bytes());
+        buffer.write_raw_slice(reason.as_bytes());
+    }
+
+    fn deserialize(reader: &mut &[u8]) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        let timestamp = reader.read_le_u64()?;
+        let kind = AuditLogEventKind::from_u8(reader.read_byte()?)
+            .ok_or(AuditLogStorageError::InvalidEventKind)?;
+        let block_id = match reader.read_byte()? {
+            0 => None,
+            1 => Some(ton_block::BlockIdExt::deserialize(reader)?),
+            _ => return Err(AuditLogStorageError::InvalidEntry.into()),
+        };
+
+        let len = reader.read_le_u16()? as usize;
+        if reader.len() < len {
+            return Err(AuditLogStorageError::InvalidEntry.into());
+        }
+        let (reason, rest) = reader.split_at(len);
+        let reason = String::from_utf8(reason.to_vec())?;
+        *reader = rest;
+
+        Ok(Self {
+            timestamp,
+            kind,
+            block_id,
+            reason,
+        })
+    }
+}
+
+fn serialize_block_id<S>(
+    block_id: &Option<ton_block::BlockIdExt>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    let block_id = block_id.as_ref().map(GetFileName::filename);
+    serde::Serialize::serialize(&block_id, serializer)
+}
+
+fn deserialize_block_id<'de, D>(deserializer: D) -> Result<Option<ton_block::BlockIdExt>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::Error;
+
+    match Option::<String>::deserialize(deserializer)? {
+        Some(block_id) => parse_block_id(&block_id).map(Some).map_err(Error::custom),
+        None => Ok(None),
+    }
+}
+
+/// Returns the longest prefix of at most `max_len` bytes on the char boundary
+fn truncate_str(s: &str, max_len: usize) -> &str {
+    if s.len() <= max_len {
+        return s;
+    }
+    let mut len = max_len;
+    while !s.is_char_boundary(len) {
+        len -= 1;
+    }
+    &s[..len]
+}
+
+/// NOTE: discriminants are stored in the DB, so they must not be changed
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[repr(u8)]
+pub enum AuditLogEventKind {
+    /// Masterchain block from which the node started after boot
+    BootAnchor,
+    /// Persistent state key block used as the initial block
+    InitMcBlock,
+    LastAppliedMcBlock,
+    ShardsClientMcBlock,
+    /// Pointers were moved back to a consistent position
+    PointerRewind,
+    /// Proof check was skipped for the hard fork block
+    HardFork,
+    /// Blocks before the key block were removed
+    BlocksGc,
+    /// States older than the block were removed
+    StatesGc,
+    ArchivesGc,
+}
+
+impl AuditLogEventKind {
+    fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0 => Self::BootAnchor,
+            1 => Self::InitMcBlock,
+            2 => Self::LastAppliedMcBlock,
+            3 => Self::ShardsClientMcBlock,
+            4 => Self::PointerRewind,
+            5 => Self::HardFork,
+            6 => Self::BlocksGc,
+            7 => Self::StatesGc,
+            8 => Self::ArchivesGc,
+            _ => return None,
+        })
+    }
+}
+
+fn read_entry_id(key: &[u8]) -> Result<u64> {
+    match key.try_into() {
+        Ok(key) => Ok(u64::from_be_bytes(key)),
+        Err(_) => Err(AuditLogStorageError::InvalidEntryId.into()),
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+enum AuditLogStorageError {
+    #[error("Invalid audit log entry id")]
+    InvalidEntryId,
+    #[error("Invalid audit log entry")]
+    InvalidEntry,
+    #[error("Invalid audit log event kind")]
+    InvalidEventKind,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tree::DbCaches;
+    use crate::test_helpers::*;
+
+    #[test]
+    fn bounded_ring() {
+        let dir = TempDir::new("audit_log");
+        let caches = DbCaches::with_capacity(0).unwrap();
+        let db = test_db_builder(&dir, &caches)
+            .column::<columns::AuditLog>()
+            .build()
+            .unwrap();
+
+        let entry = |timestamp: u64| AuditLogEntry {
+            timestamp,
+            kind: AuditLogEventKind::LastAppliedMcBlock,
+            block_id: None,
+            reason: "test".to_owned(),
+        };
+        let timestamps = |entries: Vec<AuditLogEntry>| {
+            entries
+                .into_iter()
+                .map(|entry| entry.timestamp)
+                .collect::<Vec<_>>()
+        };
+
+        let storage = AuditLogStorage::with_db(&db).unwrap();
+        for i in 0..100 {
+            storage
+                .append(&[entry(i * 2), entry(i * 2 + 1)], 50)
+                .unwrap();
+        }
+        assert_eq!(timestamps(storage.load(3).unwrap()), [199, 198, 197]);
+        assert_eq!(storage.load(1000).unwrap().len(), 50);
+
+        // Continues after restart, capacity is reduced
+        let storage = AuditLogStorage::with_db(&db).unwrap();
+        storage.append(&[entry(200)], 10).unwrap();
+        assert_eq!(
+            timestamps(storage.load(1000).unwrap()),
+            (191..=200).rev().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn compact_entry_encoding() {
+        let entry = AuditLogEntry {
+            timestamp: 1_650_000_000_000,
+            kind: AuditLogEventKind::PointerRewind,
+            block_id: Some(mc_block_id(123)),
+            reason: "rewind to the last key block".to_owned(),
+        };
+        let data = entry.to_vec();
+        assert_eq!(
+            data.len(),
+            8 + 1 + 1 + ton_block::BlockIdExt::SIZE_HINT + 2 + entry.reason.len()
+        );
+        assert_eq!(AuditLogEntry::from_slice(&data).unwrap(), entry);
+
+        let entry = AuditLogEntry {
+            block_id: None,
+            reason: String::new(),
+            ..entry
+        };
+        assert_eq!(AuditLogEntry::from_slice(&entry.to_vec()).unwrap(), entry);
+
+        // Unknown event kind
+        let mut data = entry.to_vec();
+        data[8] = u8::MAX;
+        assert!(AuditLogEntry::from_slice(&data).is_err());
+
+        assert_eq!(truncate_str("abc", 2), "ab");
+        assert_eq!(truncate_str("аб", 3), "а");
+    }
+
+    #[test]
+    fn json_roundtrip() {
+        let entry = AuditLogEntry {
+            timestamp: 1_650_000_000_000,
+            kind: AuditLogEventKind::ShardsClientMcBlock,
+            block_id: Some(mc_block_id(123)),
+            reason: "applied".to_owned(),
+        };
+        let json = serde_json::to_string(&entry).unwrap();
+        assert!(json.contains("\"shards_client_mc_block\""));
+        assert_eq!(serde_json::from_str::<AuditLogEntry>(&json).unwrap(), entry);
+
+        // Block id is optional
+        let entry = AuditLogEntry {
+            block_id: None,
+            ..entry
+        };
+        let json = serde_json::to_string(&entry).unwrap();
+        assert!(!json.contains("block_id"));
+        assert_eq!(serde_json::from_str::<AuditLogEntry>(&json).unwrap(), entry);
+    }
+}
@@ -2,12 +2,15 @@ use std::sync::Arc;
 
 use anyhow::Result;
 
-use super::{columns, read_block_id_le, write_block_id_le, BlockHandle, Column, StoredValue, Tree};
+use super::{
+    columns, read_block_id_le, write_block_id_le, BlockHandle, Column, KeyBlocksIndex, StoredValue,
+    Tree,
+};
 
 /// Stores relations between blocks
 pub struct BlockConnectionStorage {
     block_handles: Tree<columns::BlockHandles>,
-    key_blocks: Tree<columns::KeyBlocks>,
+    key_blocks: Arc<KeyBlocksIndex>,
     prev1_block_db: Tree<columns::Prev1>,
     prev2_block_db: Tree<columns::Prev2>,
     next1_block_db: Tree<columns::Next1>,
@@ -15,10 +18,10 @@ pub struct BlockConnectionStorage {
 }
 
 impl BlockConnectionStorage {
-    pub fn with_db(db: &Arc<rocksdb::DB>) -> Result<Self> {
+    pub fn with_db(db: &Arc<rocksdb::DB>, key_blocks: &Arc<KeyBlocksIndex>) -> Result<Self> {
         Ok(Self {
             block_handles: Tree::new(db)?,
-            key_blocks: Tree::new(db)?,
+            key_blocks: key_blocks.clone(),
             prev1_block_db: Tree::new(db)?,
             prev2_block_db: Tree::new(db)?,
             next1_block_db: Tree::new(db)?,
@@ -75,13 +78,8 @@ impl BlockConnectionStorage {
                     id.root_hash.as_slice(),
                     handle.meta().to_vec(),
                 );
-                write_batch.put_cf(
-                    &self.key_blocks.get_cf(),
-                    id.seq_no.to_be_bytes(),
-                    id.to_vec(),
-                );
 
-                self.block_handles.raw_db_handle().write(write_batch)?;
+                self.key_blocks.write_with(id, write_batch)?;
             } else {
                 self.block_handles
                     .insert(id.root_hash.as_slice(), handle.meta().to_vec())?;
@@ -103,6 +101,21 @@ impl BlockConnectionStorage {
             BlockConnection::Next2 => load_block_connection_impl(&self.next2_block_db, block_id),
         }
     }
+
+    /// Same as `load_connection`, but returns `None` if there is no such connection
+    pub fn find_connection(
+        &self,
+        block_id: &ton_block::BlockIdExt,
+        direction: BlockConnection,
+    ) -> Result<Option<ton_block::BlockIdExt>> {
+        match self.load_connection(block_id, direction) {
+            Ok(id) => Ok(Some(id)),
+            Err(e) => match e.downcast_ref::<BlockConnectionStorageError>() {
+                Some(BlockConnectionStorageError::NotFound) => Ok(None),
+                _ => Err(e),
+            },
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -4,19 +4,28 @@
 /// - replaced old `failure` crate with `anyhow`
 /// - moved all flags to meta
 ///
+use std::future::Future;
+use std::marker::PhantomData;
+use std::panic::Location;
 use std::sync::{Arc, Weak};
 
 use anyhow::Result;
-use tokio::sync::RwLock;
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 use ton_types::FxDashMap;
 
 use super::block_meta::BlockMeta;
 
+/// NOTE: the block data lock must always be acquired before the proof lock
+/// (see [`BlockHandle::lock_data_and_proof`]). In debug builds the order is checked
+/// and acquiring the block data lock while holding the proof lock in the same
+/// [`lock_owner_scope`] panics
 pub struct BlockHandle {
     id: ton_block::BlockIdExt,
     meta: BlockMeta,
     block_data_lock: RwLock<()>,
-    proof_data_block: RwLock<()>,
+    proof_data_lock: RwLock<()>,
+    #[cfg(debug_assertions)]
+    proof_lock_owners: lock_order::ProofLockOwners,
     cache: Arc<FxDashMap<ton_block::BlockIdExt, Weak<BlockHandle>>>,
 }
 
@@ -30,7 +39,9 @@ impl BlockHandle {
             id,
             meta,
             block_data_lock: Default::default(),
-            proof_data_block: Default::default(),
+            proof_data_lock: Default::default(),
+            #[cfg(debug_assertions)]
+            proof_lock_owners: Default::default(),
             cache,
         }
     }
@@ -45,23 +56,100 @@ impl BlockHandle {
         &self.meta
     }
 
+    /// Whether the block is a masterchain key block or the masterchain zerostate.
+    ///
+    /// NOTE: zerostates of other workchains are also marked as key blocks,
+    /// but they must not get into the key blocks index
     #[inline]
     pub fn is_key_block(&self) -> bool {
-        self.meta.is_key_block() || self.id.seq_no == 0
+        self.id.shard().is_masterchain() && (self.meta.is_key_block() || self.id.seq_no == 0)
     }
 
-    #[inline]
-    pub fn block_data_lock(&self) -> &RwLock<()> {
-        &self.block_data_lock
+    // NOTE: lock methods are not `async fn` to capture the caller location
+    // for the lock order check
+
+    #[track_caller]
+    pub fn read_block_data(&self) -> impl Future<Output = HandleReadGuard<'_>> + '_ {
+        let location = Location::caller();
+        async move {
+            self.check_lock_order(location);
+            HandleLockGuard::untracked(self.block_data_lock.read().await)
+        }
     }
 
-    #[inline]
-    pub fn proof_data_lock(&self) -> &RwLock<()> {
-        &self.proof_data_block
+    #[track_caller]
+    pub fn write_block_data(&self) -> impl Future<Output = HandleWriteGuard<'_>> + '_ {
+        let location = Location::caller();
+        async move {
+            self.check_lock_order(location);
+            HandleLockGuard::untracked(self.block_data_lock.write().await)
+        }
+    }
+
+    #[track_caller]
+    pub fn read_proof_data(&self) -> impl Future<Output = HandleReadGuard<'_>> + '_ {
+        let location = Location::caller();
+        async move {
+            let guard = self.proof_data_lock.read().await;
+            self.track_proof_lock(guard, location)
+        }
+    }
+
+    #[track_caller]
+    pub fn write_proof_data(&self) -> impl Future<Output = HandleWriteGuard<'_>> + '_ {
+        let location = Location::caller();
+        async move {
+            let guard = self.proof_data_lock.write().await;
+            self.track_proof_lock(guard, location)
+        }
     }
 
+    /// Acquires both block data and proof locks for writing in the right order
+    pub async fn lock_data_and_proof(&self) -> DataAndProofGuard<'_> {
+        let block = self.write_block_data().await;
+        let proof = self.write_proof_data().await;
+        DataAndProofGuard {
+            _block: block,
+            _proof: proof,
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    fn check_lock_order(&self, location: &'static Location<'static>) {
+        self.proof_lock_owners.check_block_lock(&self.id, location);
+    }
+
+    #[cfg(not(debug_assertions))]
+    fn check_lock_order(&self, _: &'static Location<'static>) {}
+
+    #[cfg(debug_assertions)]
+    fn track_proof_lock<G>(
+        &self,
+        guard: G,
+        location: &'static Location<'static>,
+    ) -> HandleLockGuard<'_, G> {
+        HandleLockGuard {
+            _guard: guard,
+            _owner: self.proof_lock_owners.register(location),
+            _marker: PhantomData,
+        }
+    }
+
+    #[cfg(not(debug_assertions))]
+    fn track_proof_lock<G>(
+        &self,
+        guard: G,
+        _: &'static Location<'static>,
+    ) -> HandleLockGuard<'_, G> {
+        HandleLockGuard::untracked(guard)
+    }
+
+    /// Checks whether the proof link (for shard blocks) or the full proof is stored.
+    ///
+    /// NOTE: proof link is not stored when the full proof is present,
+    /// so `is_link` is reset in that case
     pub fn has_proof_or_link(&self, is_link: &mut bool) -> bool {
-        *is_link = !self.id.shard().is_masterchain();
+        *is_link = !self.id.shard().is_masterchain() && !self.meta.has_proof();
         if *is_link {
             self.meta.has_proof_link()
         } else {
@@ -77,15 +165,50 @@ impl BlockHandle {
         }
     }
 
+    /// Sets masterchain ref seqno. First writer wins, same value writes are idempotent.
+    ///
+    /// Returns `Ok(true)` if the value was changed by this call
     pub fn set_masterchain_ref_seqno(&self, masterchain_ref_seqno: u32) -> Result<bool> {
-        match self.meta.set_masterchain_ref_seqno(masterchain_ref_seqno) {
-            0 => Ok(true),
-            prev_seqno if prev_seqno == masterchain_ref_seqno => Ok(false),
-            _ => Err(BlockHandleError::RefSeqnoAlreadySet.into()),
+        self.meta
+            .set_masterchain_ref_seqno(masterchain_ref_seqno)
+            .map_err(|prev| {
+                BlockHandleError::RefSeqnoAlreadySet {
+                    prev,
+                    new: masterchain_ref_seqno,
+                }
+                .into()
+            })
+    }
+}
+
+/// Guard of the block data or proof lock
+pub struct HandleLockGuard<'a, G> {
+    _guard: G,
+    #[cfg(debug_assertions)]
+    _owner: Option<lock_order::OwnerGuard<'a>>,
+    _marker: PhantomData<&'a ()>,
+}
+
+impl<G> HandleLockGuard<'_, G> {
+    fn untracked(guard: G) -> Self {
+        Self {
+            _guard: guard,
+            #[cfg(debug_assertions)]
+            _owner: None,
+            _marker: PhantomData,
         }
     }
 }
 
+pub type HandleReadGuard<'a> = HandleLockGuard<'a, RwLockReadGuard<'a, ()>>;
+pub type HandleWriteGuard<'a> = HandleLockGuard<'a, RwLockWriteGuard<'a, ()>>;
+
+/// Both block data and proof locks acquired for writing
+pub struct DataAndProofGuard<'a> {
+    _block: HandleWriteGuard<'a>,
+    _proof: HandleWriteGuard<'a>,
+}
+
 impl Drop for BlockHandle {
     fn drop(&mut self) {
         self.cache
@@ -95,6 +218,282 @@ impl Drop for BlockHandle {
 
 #[derive(thiserror::Error, Debug)]
 enum BlockHandleError {
-    #[error("Different masterchain ref seqno has already been set")]
-    RefSeqnoAlreadySet,
+    #[error("Different masterchain ref seqno has already been set (prev: {prev}, new: {new})")]
+    RefSeqnoAlreadySet { prev: u32, new: u32 },
+}
+
+/// Runs the future as a separate owner of the block handle locks.
+///
+/// NOTE: the lock order is only checked for the futures running inside this scope.
+/// Concurrent branches of one task (e.g. `join!`) must be wrapped separately,
+/// otherwise they are treated as a single owner
+#[cfg(debug_assertions)]
+pub fn lock_owner_scope<F: Future>(f: F) -> impl Future<Output = F::Output> {
+    lock_order::scope(f)
+}
+
+#[cfg(not(debug_assertions))]
+pub fn lock_owner_scope<F: Future>(f: F) -> impl Future<Output = F::Output> {
+    f
+}
+
+/// Lock dependency tracker for debug builds.
+///
+/// Lock owners are identified by the task-local id assigned in [`lock_owner_scope`].
+/// Locks acquired outside of any scope are not tracked
+#[cfg(debug_assertions)]
+mod lock_order {
+    use std::future::Future;
+    use std::panic::Location;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use parking_lot::Mutex;
+
+    tokio::task_local! {
+        static OWNER_ID: u64;
+    }
+
+    static NEXT_OWNER_ID: AtomicU64 = AtomicU64::new(0);
+
+    pub fn scope<F: Future>(f: F) -> impl Future<Output = F::Output> {
+        OWNER_ID.scope(NEXT_OWNER_ID.fetch_add(1, Ordering::Relaxed), f)
+    }
+
+    fn current_owner_id() -> Option<u64> {
+        OWNER_ID.try_with(|id| *id).ok()
+    }
+
+    /// Scopes which currently hold the proof lock
+    #[derive(Default)]
+    pub struct ProofLockOwners {
+        owners: Mutex<Vec<Owner>>,
+        next_guard_id: AtomicU64,
+    }
+
+    impl ProofLockOwners {
+        /// Panics if the current scope holds the proof lock
+        pub fn check_block_lock(
+            &self,
+            block_id: &ton_block::BlockIdExt,
+            requested_at: &'static Location<'static>,
+        ) {
+            let owner_id = match current_owner_id() {
+                Some(id) => id,
+                None => return,
+            };
+
+            let acquired_at = {
+                let owners = self.owners.lock();
+                match owners.iter().find(|owner| owner.owner_id == owner_id) {
+                    Some(owner) => owner.acquired_at,
+                    None => return,
+                }
+            };
+
+            panic!(
+                "Block data lock of {block_id} was requested at {requested_at} \
+                while holding its proof lock acquired at {acquired_at}"
+            );
+        }
+
+        pub fn register(&self, acquired_at: &'static Location<'static>) -> Option<OwnerGuard<'_>> {
+            let owner_id = current_owner_id()?;
+            let guard_id = self.next_guard_id.fetch_add(1, Ordering::Relaxed);
+            self.owners.lock().push(Owner {
+                guard_id,
+                owner_id,
+                acquired_at,
+            });
+            Some(OwnerGuard {
+                owners: self,
+                guard_id,
+            })
+        }
+    }
+
+    pub struct OwnerGuard<'a> {
+        owners: &'a ProofLockOwners,
+        guard_id: u64,
+    }
+
+    impl Drop for OwnerGuard<'_> {
+        fn drop(&mut self) {
+            self.owners
+                .owners
+                .lock()
+                .retain(|owner| owner.guard_id != self.guard_id);
+        }
+    }
+
+    struct Owner {
+        guard_id: u64,
+        owner_id: u64,
+        acquired_at: &'static Location<'static>,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::test_helpers::*;
+
+    fn new_handle() -> Arc<BlockHandle> {
+        Arc::new(BlockHandle::with_values(
+            mc_block_id(1),
+            BlockMeta::default(),
+            Default::default(),
+        ))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn ordered_locks_do_not_deadlock() {
+        let handle = new_handle();
+
+        // Archiver used to hold the block lock while waiting for the proof lock,
+        // and readers held the proof lock while waiting for the block lock
+        let archivers = (0..4).map(|_| {
+            let handle = handle.clone();
+            tokio::spawn(lock_owner_scope(async move {
+                for _ in 0..100 {
+                    let _guard = handle.lock_data_and_proof().await;
+                    tokio::task::yield_now().await;
+                }
+            }))
+        });
+        let readers = (0..4).map(|_| {
+            let handle = handle.clone();
+            tokio::spawn(lock_owner_scope(async move {
+                for _ in 0..100 {
+                    let _block = handle.read_block_data().await;
+                    tokio::task::yield_now().await;
+                    let _proof = handle.read_proof_data().await;
+                }
+            }))
+        });
+        let tasks = archivers.chain(readers).collect::<Vec<_>>();
+
+        tokio::time::timeout(
+            Duration::from_secs(10),
+            futures_util::future::join_all(tasks),
+        )
+        .await
+        .expect("deadlock")
+        .into_iter()
+        .for_each(|result| result.unwrap());
+    }
+
+    #[cfg(debug_assertions)]
+    #[tokio::test]
+    #[should_panic(expected = "was requested while holding its proof lock")]
+    async fn inverted_locks_are_detected() {
+        let handle = new_handle();
+        lock_owner_scope(async {
+            let _proof = handle.read_proof_data().await;
+            let _block = handle.read_block_data().await;
+        })
+        .await;
+    }
+
+    #[cfg(debug_assertions)]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn inverted_locks_with_waiting_archiver_are_detected() {
+        let handle = new_handle();
+        let (proof_locked_tx, proof_locked_rx) = tokio::sync::oneshot::channel();
+        let (archiver_waits_tx, archiver_waits_rx) = tokio::sync::oneshot::channel();
+
+        // Reader holds the proof lock (e.g. a loaded proof data)
+        // and then requests the block lock which would never be acquired
+        let reader = tokio::spawn({
+            let handle = handle.clone();
+            lock_owner_scope(async move {
+                let _proof = handle.read_proof_data().await;
+                proof_locked_tx.send(()).unwrap();
+                archiver_waits_rx.await.unwrap();
+                drop(handle.read_block_data().await);
+            })
+        });
+        proof_locked_rx.await.unwrap();
+
+        // Archiver acquires the block lock and waits for the proof lock
+        let archiver = tokio::spawn({
+            let handle = handle.clone();
+            lock_owner_scope(async move {
+                drop(handle.lock_data_and_proof().await);
+            })
+        });
+        while handle.block_data_lock.try_read().is_ok() {
+            tokio::task::yield_now().await;
+        }
+        archiver_waits_tx.send(()).unwrap();
+
+        let error = tokio::time::timeout(Duration::from_secs(10), reader)
+            .await
+            .expect("deadlock")
+            .unwrap_err();
+        assert!(error.is_panic());
+
+        // Proof lock is released with the panicked task
+        tokio::time::timeout(Duration::from_secs(10), archiver)
+            .await
+            .expect("deadlock")
+            .unwrap();
+    }
+
+    #[cfg(debug_assertions)]
+    #[tokio::test]
+    async fn proof_lock_of_other_scope_is_ignored() {
+        let handle = new_handle();
+        lock_owner_scope(async {
+            let proof = handle.read_proof_data().await;
+
+            let task = tokio::spawn({
+                let handle = handle.clone();
+                lock_owner_scope(async move {
+                    drop(handle.read_block_data().await);
+                })
+            });
+            task.await.unwrap();
+            drop(proof);
+
+            let _proof = handle.read_proof_data().await;
+            drop(_proof);
+            let _block = handle.read_block_data().await;
+        })
+        .await;
+    }
+
+    #[cfg(debug_assertions)]
+    #[tokio::test]
+    async fn join_branches_are_separate_owners() {
+        let handle = new_handle();
+        let (proof_locked_tx, proof_locked_rx) = tokio::sync::oneshot::channel();
+
+        // Both branches share the task (and its waker), but not the owner scope
+        let reader = lock_owner_scope(async {
+            let _proof = handle.read_proof_data().await;
+            proof_locked_tx.send(()).unwrap();
+            tokio::task::yield_now().await;
+        });
+        let writer = lock_owner_scope(async {
+            proof_locked_rx.await.unwrap();
+            drop(handle.read_block_data().await);
+        });
+        tokio::join!(reader, writer);
+    }
+
+    #[cfg(debug_assertions)]
+    #[tokio::test]
+    #[should_panic(expected = "was requested while holding its proof lock")]
+    async fn proof_lock_is_tracked_until_last_guard() {
+        let handle = new_handle();
+        lock_owner_scope(async {
+            let first = handle.read_proof_data().await;
+            let _second = handle.read_proof_data().await;
+            drop(first);
+            drop(handle.read_block_data().await);
+        })
+        .await;
+    }
 }
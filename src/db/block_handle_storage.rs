@@ -9,24 +9,34 @@ use std::sync::{Arc, Weak};
 use anyhow::Result;
 use ton_types::FxDashMap;
 
-use super::{columns, BlockHandle, BlockMeta, BlockMetaData, StoredValue, Tree};
+use super::{
+    columns, BlockHandle, BlockMeta, BlockMetaData, BriefBlockMeta, KeyBlocksIndex,
+    KeyBlocksIndexUpdate, StoredValue, Tree,
+};
 use crate::utils::*;
 
 pub struct BlockHandleStorage {
     cache: Arc<FxDashMap<ton_block::BlockIdExt, Weak<BlockHandle>>>,
     block_handles: Tree<columns::BlockHandles>,
-    key_blocks: Tree<columns::KeyBlocks>,
+    key_blocks: Arc<KeyBlocksIndex>,
 }
 
 impl BlockHandleStorage {
     pub fn with_db(db: &Arc<rocksdb::DB>) -> Result<Self> {
         Ok(Self {
-            cache: Arc::new(Default::default()),
+            cache: Arc::new(FxDashMap::with_hasher_and_shard_amount(
+                Default::default(),
+                handles_cache_shard_amount(),
+            )),
             block_handles: Tree::new(db)?,
-            key_blocks: Tree::new(db)?,
+            key_blocks: Arc::new(KeyBlocksIndex::with_db(db)?),
         })
     }
 
+    pub fn key_blocks_index(&self) -> &Arc<KeyBlocksIndex> {
+        &self.key_blocks
+    }
+
     pub fn store_block_applied(&self, handle: &Arc<BlockHandle>) -> Result<bool> {
         if handle.meta().set_is_applied() {
             self.store_handle(handle)?;
@@ -36,14 +46,35 @@ impl BlockHandleStorage {
         }
     }
 
+    /// Same as [`BlockHandleStorage::store_block_applied`], but the batch
+    /// is written atomically with the handle.
+    ///
+    /// NOTE: the batch is dropped if the block was already applied
+    pub fn store_block_applied_with(
+        &self,
+        handle: &Arc<BlockHandle>,
+        mut batch: rocksdb::WriteBatch,
+    ) -> Result<bool> {
+        if !handle.meta().set_is_applied() {
+            return Ok(false);
+        }
+
+        let update = self.store_handle_batch(handle, &mut batch)?;
+        self.block_handles.raw_db_handle().write(batch)?;
+        update.commit();
+        Ok(true)
+    }
+
     pub fn assign_mc_ref_seq_no(
         &self,
         handle: &Arc<BlockHandle>,
         mc_ref_seq_no: u32,
     ) -> Result<()> {
-        if handle.set_masterchain_ref_seqno(mc_ref_seq_no)? {
-            self.store_handle(handle)?;
-        }
+        // NOTE: handle is stored even if the same value was already set,
+        // so that the value is persisted when this call returns
+        // (the first writer could still be storing it)
+        handle.set_masterchain_ref_seqno(mc_ref_seq_no)?;
+        self.store_handle(handle)?;
         Ok(())
     }
 
@@ -91,26 +122,96 @@ impl BlockHandleStorage {
         })
     }
 
+    /// Loads only the block meta without creating the handle
+    pub fn load_meta(&self, block_id: &ton_block::BlockIdExt) -> Result<Option<BriefBlockMeta>> {
+        // NOTE: cached handle could contain flags which are not stored yet
+        if let Some(weak) = self.cache.get(block_id) {
+            if let Some(handle) = weak.upgrade() {
+                return Ok(Some(handle.meta().brief()));
+            }
+        }
+
+        Ok(
+            match self.block_handles.get(block_id.root_hash.as_slice())? {
+                Some(meta) => Some(BlockMeta::from_slice(meta.as_ref())?.brief()),
+                None => None,
+            },
+        )
+    }
+
+    /// Returns the cached instance of the handle with the same id.
+    ///
+    /// Flags of the specified handle are merged into the cached one,
+    /// so that they are not lost when the specified instance was evicted from the cache.
+    pub fn canonical_handle(&self, handle: Arc<BlockHandle>) -> Result<Arc<BlockHandle>> {
+        use dashmap::mapref::entry::Entry;
+
+        // NOTE: upgraded handle must not be dropped while the entry is locked
+        let cached = match self.cache.entry(handle.id().clone()) {
+            Entry::Occupied(mut entry) => match entry.get().upgrade() {
+                Some(cached) => cached,
+                None => {
+                    entry.insert(Arc::downgrade(&handle));
+                    return Ok(handle);
+                }
+            },
+            Entry::Vacant(entry) => {
+                entry.insert(Arc::downgrade(&handle));
+                return Ok(handle);
+            }
+        };
+
+        if !Arc::ptr_eq(&cached, &handle) && cached.meta().merge_flags(handle.meta()) {
+            self.store_handle(&cached)?;
+        }
+
+        Ok(cached)
+    }
+
     pub fn store_handle(&self, handle: &BlockHandle) -> Result<()> {
         let id = handle.id();
 
-        self.block_handles
-            .insert(id.root_hash.as_slice(), handle.meta().to_vec())?;
-
         if handle.is_key_block() {
-            self.key_blocks
-                .insert(id.seq_no.to_be_bytes(), id.to_vec())?;
+            let mut batch = rocksdb::WriteBatch::default();
+            batch.put_cf(
+                &self.block_handles.get_cf(),
+                id.root_hash.as_slice(),
+                handle.meta().to_vec(),
+            );
+            self.key_blocks.write_with(id, batch)
+        } else {
+            self.block_handles
+                .insert(id.root_hash.as_slice(), handle.meta().to_vec())
         }
+    }
 
-        Ok(())
+    /// Adds handle update to the batch (see [`BlockHandleStorage::store_handle`]).
+    ///
+    /// NOTE: the returned update must be committed after the batch is written
+    pub fn store_handle_batch(
+        &self,
+        handle: &BlockHandle,
+        batch: &mut rocksdb::WriteBatch,
+    ) -> Result<KeyBlocksIndexUpdate<'_>> {
+        let id = handle.id();
+
+        batch.put_cf(
+            &self.block_handles.get_cf(),
+            id.root_hash.as_slice(),
+            handle.meta().to_vec(),
+        );
+
+        if handle.is_key_block() {
+            self.key_blocks.add_to_batch(id, batch)
+        } else {
+            Ok(KeyBlocksIndexUpdate::empty())
+        }
     }
 
     pub fn load_key_block_handle(&self, seq_no: u32) -> Result<Arc<BlockHandle>> {
         let key_block_id = self
             .key_blocks
-            .get(seq_no.to_be_bytes())?
-            .map(|value| ton_block::BlockIdExt::from_slice(value.as_ref()))
-            .transpose()?
+            .get(seq_no)
             .ok_or(BlockHandleStorageError::KeyBlockNotFound)?;
 
         self.load_handle(&key_block_id)?.ok_or_else(|| {
@@ -119,14 +220,9 @@ impl BlockHandleStorage {
     }
 
     pub fn find_last_key_block(&self) -> Result<Arc<BlockHandle>> {
-        let mut iter = self.key_blocks.raw_iterator();
-        iter.seek_to_last();
-
-        // Load key block from current iterator value
-        let key_block_id = iter
-            .value()
-            .map(ton_block::BlockIdExt::from_slice)
-            .transpose()?
+        let key_block_id = self
+            .key_blocks
+            .last()
             .ok_or(BlockHandleStorageError::KeyBlockNotFound)?;
 
         self.load_handle(&key_block_id)?.ok_or_else(|| {
@@ -139,14 +235,8 @@ impl BlockHandleStorage {
             return Ok(None);
         }
 
-        // Create iterator and move it to the previous key block before the specified
-        let mut iter = self.key_blocks.raw_iterator();
-        iter.seek_for_prev((seq_no - 1u32).to_be_bytes());
-
-        // Load key block from current iterator value
-        iter.value()
-            .map(ton_block::BlockIdExt::from_slice)
-            .transpose()?
+        self.key_blocks
+            .prev(seq_no)
             .map(|key_block_id| {
                 self.load_handle(&key_block_id)?.ok_or_else(|| {
                     BlockHandleStorageError::KeyBlockHandleNotFound(key_block_id.seq_no).into()
@@ -160,18 +250,11 @@ impl BlockHandleStorage {
             return Ok(None);
         }
 
-        // Create iterator and move it to the previous key block before the specified
-        let mut iter = self.key_blocks.raw_iterator();
-        iter.seek_for_prev((seq_no - 1u32).to_be_bytes());
-
-        // Loads key block from current iterator value and moves it backward
+        // Loads the previous key block and moves the bound backward
+        let mut bound = seq_no;
         let mut get_key_block = move || -> Result<Option<Arc<BlockHandle>>> {
             // Load key block id
-            let key_block_id = match iter
-                .value()
-                .map(ton_block::BlockIdExt::from_slice)
-                .transpose()?
-            {
+            let key_block_id = match self.key_blocks.prev(bound) {
                 Some(prev_key_block) => prev_key_block,
                 None => return Ok(None),
             };
@@ -181,8 +264,8 @@ impl BlockHandleStorage {
                 BlockHandleStorageError::KeyBlockHandleNotFound(key_block_id.seq_no),
             )?;
 
-            // Move iterator backward
-            iter.prev();
+            // Move bound backward
+            bound = key_block_id.seq_no;
 
             // Done
             Ok(Some(handle))
@@ -214,20 +297,14 @@ impl BlockHandleStorage {
         &self,
         direction: KeyBlocksDirection,
     ) -> impl Iterator<Item = Result<ton_block::BlockIdExt>> + '_ {
-        let mut raw_iterator = self.key_blocks.raw_iterator();
-        let reverse = match direction {
-            KeyBlocksDirection::ForwardFrom(seq_no) => {
-                raw_iterator.seek(seq_no.to_be_bytes());
-                false
-            }
-            KeyBlocksDirection::Backward => {
-                raw_iterator.seek_to_last();
-                true
-            }
+        let (next, reverse) = match direction {
+            KeyBlocksDirection::ForwardFrom(seq_no) => (self.key_blocks.next(seq_no), false),
+            KeyBlocksDirection::Backward => (self.key_blocks.last(), true),
         };
 
         KeyBlocksIterator {
-            raw_iterator,
+            index: &self.key_blocks,
+            next,
             reverse,
         }
     }
@@ -274,6 +351,12 @@ impl BlockHandleStorage {
                 entry.insert(Arc::downgrade(&handle));
                 handle
             }
+            // Replace the handle which is being dropped
+            Entry::Occupied(mut entry) if entry.get().strong_count() == 0 => {
+                let handle = Arc::new(BlockHandle::with_values(block_id, meta, self.cache.clone()));
+                entry.insert(Arc::downgrade(&handle));
+                handle
+            }
             Entry::Occupied(_) => return Ok(None),
         };
 
@@ -295,8 +378,10 @@ pub enum KeyBlocksDirection {
     Backward,
 }
 
+/// Iterates over the key blocks index without holding its lock
 struct KeyBlocksIterator<'a> {
-    raw_iterator: rocksdb::DBRawIterator<'a>,
+    index: &'a KeyBlocksIndex,
+    next: Option<ton_block::BlockIdExt>,
     reverse: bool,
 }
 
@@ -304,17 +389,17 @@ impl Iterator for KeyBlocksIterator<'_> {
     type Item = Result<ton_block::BlockIdExt>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let value = self
-            .raw_iterator
-            .value()
-            .map(ton_block::BlockIdExt::from_slice)?;
-        if self.reverse {
-            self.raw_iterator.prev();
+        let value = self.next.take()?;
+        self.next = if self.reverse {
+            self.index.prev(value.seq_no)
         } else {
-            self.raw_iterator.next();
-        }
+            value
+                .seq_no
+                .checked_add(1)
+                .and_then(|seq_no| self.index.next(seq_no))
+        };
 
-        Some(value)
+        Some(Ok(value))
     }
 }
 
@@ -327,3 +412,177 @@ enum BlockHandleStorageError {
     #[error("Key block handle not found: {}", .0)]
     KeyBlockHandleNotFound(u32),
 }
+
+/// Handles are accessed from all shard block tasks at once,
+/// so the cache uses more shards than the dashmap default
+fn handles_cache_shard_amount() -> usize {
+    (num_cpus::get() * 8).next_power_of_two()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tree::DbCaches;
+    use crate::test_helpers::*;
+
+    #[test]
+    fn applied_flag_is_written_with_batch() {
+        let dir = TempDir::new("applied_with_batch");
+        let caches = DbCaches::with_capacity(0).unwrap();
+        let db = test_db_builder(&dir, &caches)
+            .column::<columns::BlockHandles>()
+            .column::<columns::KeyBlocks>()
+            .column::<columns::MessageIndex>()
+            .build()
+            .unwrap();
+
+        let storage = BlockHandleStorage::with_db(&db).unwrap();
+        let entries = Tree::<columns::MessageIndex>::new(&db).unwrap();
+
+        let block_id = mc_block_id(1);
+        let meta_data = BlockMetaData {
+            is_key_block: false,
+            gen_utime: 1,
+            mc_ref_seqno: Some(1),
+        };
+        let (handle, _) = storage.create_or_load_handle(&block_id, meta_data).unwrap();
+
+        let mut batch = rocksdb::WriteBatch::default();
+        batch.put_cf(&entries.get_cf(), b"first", b"");
+        assert!(storage.store_block_applied_with(&handle, batch).unwrap());
+        assert!(entries.get(b"first").unwrap().is_some());
+
+        // Applied flag is persisted along with the batch
+        storage.cache.remove(&block_id);
+        let stored = storage.load_handle(&block_id).unwrap().unwrap();
+        assert!(stored.meta().is_applied());
+
+        // Batch is dropped for the already applied block
+        let mut batch = rocksdb::WriteBatch::default();
+        batch.put_cf(&entries.get_cf(), b"second", b"");
+        assert!(!storage.store_block_applied_with(&stored, batch).unwrap());
+        assert!(entries.get(b"second").unwrap().is_none());
+    }
+
+    #[test]
+    fn concurrent_data_and_proof_flags() {
+        let dir = TempDir::new("block_handle_storage");
+        let caches = DbCaches::with_capacity(0).unwrap();
+        let db = test_db_builder(&dir, &caches)
+            .column::<columns::BlockHandles>()
+            .column::<columns::KeyBlocks>()
+            .build()
+            .unwrap();
+
+        let storage = Arc::new(BlockHandleStorage::with_db(&db).unwrap());
+
+        for seq_no in 1..100u32 {
+            let block_id = mc_block_id(seq_no);
+            let meta_data = BlockMetaData {
+                is_key_block: false,
+                gen_utime: seq_no,
+                mc_ref_seqno: None,
+            };
+
+            // Simulate a handle which was evicted from the cache while still being used
+            let (evicted, _) = storage.create_or_load_handle(&block_id, meta_data).unwrap();
+            storage.cache.remove(&block_id);
+
+            let store_data = std::thread::spawn({
+                let storage = storage.clone();
+                let block_id = block_id.clone();
+                move || {
+                    let (handle, _) = storage.create_or_load_handle(&block_id, meta_data)?;
+                    if handle.meta().set_has_data() {
+                        storage.store_handle(&handle)?;
+                    }
+                    Ok::<_, anyhow::Error>(handle)
+                }
+            });
+            let store_proof = std::thread::spawn({
+                let storage = storage.clone();
+                move || {
+                    let handle = storage.canonical_handle(evicted)?;
+                    if handle.meta().set_has_proof() {
+                        storage.store_handle(&handle)?;
+                    }
+                    Ok::<_, anyhow::Error>(handle)
+                }
+            });
+
+            let data_handle = store_data.join().unwrap().unwrap();
+            let proof_handle = store_proof.join().unwrap().unwrap();
+            assert!(Arc::ptr_eq(&data_handle, &proof_handle));
+            assert!(data_handle.meta().has_data());
+            assert!(data_handle.meta().has_proof());
+            drop((data_handle, proof_handle));
+
+            // Check persisted flags
+            let handle = storage.load_handle(&block_id).unwrap().unwrap();
+            assert!(handle.meta().has_data());
+            assert!(handle.meta().has_proof());
+        }
+    }
+
+    #[test]
+    fn key_block_index_reapply_and_conflict() {
+        let dir = TempDir::new("key_block_index");
+        let caches = DbCaches::with_capacity(0).unwrap();
+        let db = test_db_builder(&dir, &caches)
+            .column::<columns::BlockHandles>()
+            .column::<columns::KeyBlocks>()
+            .build()
+            .unwrap();
+
+        let storage = BlockHandleStorage::with_db(&db).unwrap();
+
+        let key_block_id = |seq_no: u32, hash: u8| ton_block::BlockIdExt {
+            shard_id: ton_block::ShardIdent::masterchain(),
+            seq_no,
+            root_hash: ton_types::UInt256::from_slice(&[hash; 32]),
+            file_hash: Default::default(),
+        };
+        let meta_data = |seq_no: u32| BlockMetaData {
+            is_key_block: true,
+            gen_utime: seq_no,
+            mc_ref_seqno: Some(seq_no),
+        };
+
+        // Index a range of key blocks
+        for seq_no in 1..=10 {
+            let (handle, _) = storage
+                .create_or_load_handle(&key_block_id(seq_no, seq_no as u8), meta_data(seq_no))
+                .unwrap();
+            storage.store_block_applied(&handle).unwrap();
+        }
+
+        // Re-apply the already indexed range (e.g. after the pointers were rewound)
+        for seq_no in 1..=10 {
+            let (handle, _) = storage
+                .create_or_load_handle(&key_block_id(seq_no, seq_no as u8), meta_data(seq_no))
+                .unwrap();
+            storage.store_handle(&handle).unwrap();
+
+            let mut batch = rocksdb::WriteBatch::default();
+            let update = storage.store_handle_batch(&handle, &mut batch).unwrap();
+            db.write(batch).unwrap();
+            update.commit();
+        }
+        assert_eq!(
+            storage.find_last_key_block().unwrap().id(),
+            &key_block_id(10, 10)
+        );
+
+        // Different block with the already indexed seqno
+        assert!(storage
+            .create_or_load_handle(&key_block_id(5, 0xff), meta_data(5))
+            .is_err());
+
+        // Index is not changed
+        assert_eq!(storage.key_blocks.get(5).unwrap(), key_block_id(5, 5));
+        assert_eq!(
+            KeyBlocksIndex::with_db(&db).unwrap().get(5).unwrap(),
+            key_block_id(5, 5)
+        );
+    }
+}
@@ -5,9 +5,10 @@
 /// - moved all flags here from block handle
 /// - removed temporary unused flags
 ///
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 
 use anyhow::Result;
+use serde::Serialize;
 use ton_types::ByteOrderRead;
 
 use super::{StoredValue, StoredValueBuffer};
@@ -44,6 +45,8 @@ impl BriefBlockInfo {
 pub struct BlockMeta {
     flags: AtomicU64,
     gen_utime: u32,
+    /// Seqno of the key block against which the proof was checked
+    proof_key_block_seqno: AtomicU32,
 }
 
 impl BlockMeta {
@@ -57,9 +60,15 @@ impl BlockMeta {
                 } | data.mc_ref_seqno.unwrap_or_default() as u64,
             ),
             gen_utime: data.gen_utime,
+            proof_key_block_seqno: Default::default(),
         }
     }
 
+    /// Returns a snapshot of all flags
+    pub fn flags(&self) -> BlockFlags {
+        BlockFlags::from_raw(self.flags.load(Ordering::Acquire), self.gen_utime)
+    }
+
     pub fn brief(&self) -> BriefBlockMeta {
         BriefBlockMeta {
             flags: self.flags.load(Ordering::Acquire),
@@ -71,8 +80,30 @@ impl BlockMeta {
         self.flags.load(Ordering::Acquire) as u32
     }
 
-    pub fn set_masterchain_ref_seqno(&self, seqno: u32) -> u32 {
-        self.flags.fetch_or(seqno as u64, Ordering::Release) as u32
+    /// Atomically sets masterchain ref seqno if it was not set yet (first writer wins).
+    ///
+    /// Returns `Ok(true)` if the value was set by this call, `Ok(false)` if the same value
+    /// was already set (or zero was passed), or `Err` with the previously set value
+    pub fn set_masterchain_ref_seqno(&self, seqno: u32) -> Result<bool, u32> {
+        let mut current = self.flags.load(Ordering::Acquire);
+        loop {
+            match current as u32 {
+                0 if seqno == 0 => return Ok(false),
+                0 => {}
+                prev if prev == seqno => return Ok(false),
+                prev => return Err(prev),
+            }
+
+            match self.flags.compare_exchange_weak(
+                current,
+                current | seqno as u64,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return Ok(true),
+                Err(actual) => current = actual,
+            }
+        }
     }
 
     #[inline]
@@ -80,7 +111,45 @@ impl BlockMeta {
         self.gen_utime
     }
 
+    /// Sets all flags of the other meta (and its masterchain ref seqno if it was not set).
+    ///
+    /// Returns `true` if any new flag was set
+    pub fn merge_flags(&self, other: &Self) -> bool {
+        const SEQNO_MASK: u64 = u32::MAX as u64;
+
+        let proof_key_block_seqno = other.proof_key_block_seqno.load(Ordering::Acquire);
+        let other = other.flags.load(Ordering::Acquire);
+        let flags = other & !SEQNO_MASK;
+
+        if flags & BLOCK_META_FLAG_PROOF_CHECKED != 0 {
+            self.proof_key_block_seqno
+                .store(proof_key_block_seqno, Ordering::Release);
+        }
+        let updated = self.flags.fetch_or(flags, Ordering::AcqRel) & flags != flags;
+
+        // NOTE: a different seqno is an error of the caller, so first writer wins
+        matches!(self.set_masterchain_ref_seqno(other as u32), Ok(true)) || updated
+    }
+
+    /// Marks the proof as checked against the specified key block.
+    ///
+    /// Returns `true` if the flag was not set before or the key block changed
+    pub fn set_proof_checked(&self, key_block_seqno: u32) -> bool {
+        // NOTE: seqno is stored before the flag so that it is visible with the flag
+        let prev = self
+            .proof_key_block_seqno
+            .swap(key_block_seqno, Ordering::AcqRel);
+        self.set_flag(BLOCK_META_FLAG_PROOF_CHECKED) || prev != key_block_seqno
+    }
+
+    /// Seqno of the key block against which the proof was checked
+    pub fn proof_checked_by(&self) -> Option<u32> {
+        self.test_flag(BLOCK_META_FLAG_PROOF_CHECKED)
+            .then(|| self.proof_key_block_seqno.load(Ordering::Acquire))
+    }
+
     pub fn clear_data_and_proof(&self) {
+        // NOTE: `CLEAR_DATA_MASK` also resets the proof checked flag
         self.flags.fetch_and(CLEAR_DATA_MASK, Ordering::Release);
     }
 
@@ -194,7 +263,8 @@ impl BlockMeta {
 impl StoredValue for BlockMeta {
     /// 8 bytes flags
     /// 4 bytes gen_utime
-    const SIZE_HINT: usize = 8 + 4;
+    /// 4 bytes proof key block seqno
+    const SIZE_HINT: usize = 8 + 4 + 4;
 
     type OnStackSlice = [u8; Self::SIZE_HINT];
 
@@ -204,6 +274,12 @@ impl StoredValue for BlockMeta {
 
         buffer.write_raw_slice(&flags.to_le_bytes());
         buffer.write_raw_slice(&self.gen_utime.to_le_bytes());
+        buffer.write_raw_slice(
+            &self
+                .proof_key_block_seqno
+                .load(Ordering::Acquire)
+                .to_le_bytes(),
+        );
     }
 
     fn deserialize(reader: &mut &[u8]) -> Result<Self>
@@ -213,9 +289,17 @@ impl StoredValue for BlockMeta {
         let flags = reader.read_le_u64()?;
         let gen_utime = reader.read_le_u32()?;
 
+        // NOTE: handles stored by the previous versions don't have this field
+        let proof_key_block_seqno = if reader.len() >= 4 {
+            reader.read_le_u32()?
+        } else {
+            0
+        };
+
         Ok(Self {
             flags: AtomicU64::new(flags),
             gen_utime,
+            proof_key_block_seqno: AtomicU32::new(proof_key_block_seqno),
         })
     }
 }
@@ -237,17 +321,91 @@ impl BriefBlockMeta {
         self.flags as u32
     }
 
+    /// Returns all flags
+    pub fn flags(&self) -> BlockFlags {
+        BlockFlags::from_raw(self.flags, self.gen_utime)
+    }
+
     #[inline]
     pub fn is_key_block(&self) -> bool {
         self.test_flag(BLOCK_META_FLAG_IS_KEY_BLOCK)
     }
 
+    #[inline]
+    pub fn is_applied(&self) -> bool {
+        self.test_flag(BLOCK_META_FLAG_IS_APPLIED)
+    }
+
+    #[inline]
+    pub fn has_data(&self) -> bool {
+        self.test_flag(BLOCK_META_FLAG_HAS_DATA)
+    }
+
     #[inline]
     fn test_flag(&self, flag: u64) -> bool {
         self.flags & flag == flag
     }
 }
 
+/// Snapshot of the block meta flags
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Serialize)]
+pub struct BlockFlags {
+    pub mc_ref_seqno: u32,
+    pub gen_utime: u32,
+    pub has_data: bool,
+    pub has_proof: bool,
+    pub has_proof_link: bool,
+    pub has_state: bool,
+    pub has_persistent_state: bool,
+    pub has_next1: bool,
+    pub has_next2: bool,
+    pub has_prev1: bool,
+    pub has_prev2: bool,
+    pub is_applied: bool,
+    pub is_key_block: bool,
+    pub is_moving_to_archive: bool,
+    pub is_archived: bool,
+    pub proof_checked: bool,
+}
+
+impl BlockFlags {
+    fn from_raw(flags: u64, gen_utime: u32) -> Self {
+        let test = |flag: u64| flags & flag == flag;
+        Self {
+            mc_ref_seqno: flags as u32,
+            gen_utime,
+            has_data: test(BLOCK_META_FLAG_HAS_DATA),
+            has_proof: test(BLOCK_META_FLAG_HAS_PROOF),
+            has_proof_link: test(BLOCK_META_FLAG_HAS_PROOF_LINK),
+            has_state: test(BLOCK_META_FLAG_HAS_STATE),
+            has_persistent_state: test(BLOCK_META_FLAG_HAS_PERSISTENT_STATE),
+            has_next1: test(BLOCK_META_FLAG_HAS_NEXT_1),
+            has_next2: test(BLOCK_META_FLAG_HAS_NEXT_2),
+            has_prev1: test(BLOCK_META_FLAG_HAS_PREV_1),
+            has_prev2: test(BLOCK_META_FLAG_HAS_PREV_2),
+            is_applied: test(BLOCK_META_FLAG_IS_APPLIED),
+            is_key_block: test(BLOCK_META_FLAG_IS_KEY_BLOCK),
+            is_moving_to_archive: test(BLOCK_META_FLAG_MOVING_TO_ARCHIVE),
+            is_archived: test(BLOCK_META_FLAG_MOVED_TO_ARCHIVE),
+            proof_checked: test(BLOCK_META_FLAG_PROOF_CHECKED),
+        }
+    }
+}
+
+// Layout of the `flags` field:
+//
+// | bits    | value                                                   |
+// |---------|---------------------------------------------------------|
+// | 0..32   | masterchain ref seqno                                   |
+// | 32..47  | flags below                                             |
+// | 47..48  | free                                                    |
+// | 48..64  | runtime only, not persisted (see `BlockMeta::serialize`)|
+//
+// NOTE: bit 35 was used by an external listener in the original node
+// and must not be reused, because it could be set in the existing DBs.
+// New persistent flags (e.g. `has_archive`) must use the free bits below 48,
+// values which don't fit there (e.g. `data_size`) must be stored as separate fields.
+
 const BLOCK_META_FLAG_HAS_DATA: u64 = 1 << 32;
 const BLOCK_META_FLAG_HAS_PROOF: u64 = 1 << (32 + 1);
 const BLOCK_META_FLAG_HAS_PROOF_LINK: u64 = 1 << (32 + 2);
@@ -263,9 +421,12 @@ const BLOCK_META_FLAG_IS_KEY_BLOCK: u64 = 1 << (32 + 11);
 
 const BLOCK_META_FLAG_MOVING_TO_ARCHIVE: u64 = 1 << (32 + 12);
 const BLOCK_META_FLAG_MOVED_TO_ARCHIVE: u64 = 1 << (32 + 13);
+const BLOCK_META_FLAG_PROOF_CHECKED: u64 = 1 << (32 + 14);
 
-const CLEAR_DATA_MASK: u64 =
-    !(BLOCK_META_FLAG_HAS_DATA | BLOCK_META_FLAG_HAS_PROOF | BLOCK_META_FLAG_HAS_PROOF_LINK);
+const CLEAR_DATA_MASK: u64 = !(BLOCK_META_FLAG_HAS_DATA
+    | BLOCK_META_FLAG_HAS_PROOF
+    | BLOCK_META_FLAG_HAS_PROOF_LINK
+    | BLOCK_META_FLAG_PROOF_CHECKED);
 
 #[cfg(test)]
 mod tests {
@@ -275,4 +436,133 @@ mod tests {
     pub fn fully_on_stack() {
         assert!(!BlockMeta::default().to_vec().spilled());
     }
+
+    #[test]
+    fn flags_snapshot() {
+        let meta = BlockMeta::with_data(BlockMetaData {
+            is_key_block: true,
+            gen_utime: 123,
+            mc_ref_seqno: Some(456),
+        });
+        meta.set_has_data();
+        meta.set_is_applied();
+        meta.set_proof_checked(1);
+
+        let flags = meta.flags();
+        assert_eq!(
+            flags,
+            BlockFlags {
+                mc_ref_seqno: 456,
+                gen_utime: 123,
+                has_data: true,
+                is_applied: true,
+                is_key_block: true,
+                proof_checked: true,
+                ..Default::default()
+            }
+        );
+        assert_eq!(meta.brief().flags(), flags);
+        assert_eq!(
+            BlockMeta::from_slice(&meta.to_vec()).unwrap().flags(),
+            flags
+        );
+    }
+
+    #[test]
+    fn flags_layout() {
+        const PERSISTENT_MASK: u64 = 0x0000_ffff_0000_0000;
+
+        let flags = [
+            BLOCK_META_FLAG_HAS_DATA,
+            BLOCK_META_FLAG_HAS_PROOF,
+            BLOCK_META_FLAG_HAS_PROOF_LINK,
+            BLOCK_META_FLAG_HAS_STATE,
+            BLOCK_META_FLAG_HAS_PERSISTENT_STATE,
+            BLOCK_META_FLAG_HAS_NEXT_1,
+            BLOCK_META_FLAG_HAS_NEXT_2,
+            BLOCK_META_FLAG_HAS_PREV_1,
+            BLOCK_META_FLAG_HAS_PREV_2,
+            BLOCK_META_FLAG_IS_APPLIED,
+            BLOCK_META_FLAG_IS_KEY_BLOCK,
+            BLOCK_META_FLAG_MOVING_TO_ARCHIVE,
+            BLOCK_META_FLAG_MOVED_TO_ARCHIVE,
+            BLOCK_META_FLAG_PROOF_CHECKED,
+        ];
+
+        let mut all = 0;
+        for flag in flags {
+            assert_eq!(flag.count_ones(), 1);
+            assert_eq!(flag & PERSISTENT_MASK, flag);
+            assert_eq!(all & flag, 0, "flags collide");
+            all |= flag;
+        }
+
+        // Reserved bit is not used
+        assert_eq!(all & (1 << (32 + 3)), 0);
+    }
+
+    #[test]
+    fn proof_checked_flag() {
+        let meta = BlockMeta::default();
+        assert_eq!(meta.proof_checked_by(), None);
+        assert!(meta.set_proof_checked(123));
+        assert_eq!(meta.proof_checked_by(), Some(123));
+
+        // Persisted with the handle
+        let stored = BlockMeta::from_slice(&meta.to_vec()).unwrap();
+        assert_eq!(stored.proof_checked_by(), Some(123));
+
+        // Merged into the cached handle
+        let cached = BlockMeta::default();
+        assert!(cached.merge_flags(&stored));
+        assert_eq!(cached.proof_checked_by(), Some(123));
+
+        // Reset with the proof
+        cached.clear_data_and_proof();
+        assert_eq!(cached.proof_checked_by(), None);
+
+        // Handles stored by the previous versions
+        let meta = BlockMeta::default();
+        meta.set_has_proof();
+        let mut old = meta.to_vec().to_vec();
+        old.truncate(8 + 4);
+        let stored = BlockMeta::from_slice(&old).unwrap();
+        assert!(stored.has_proof());
+        assert_eq!(stored.proof_checked_by(), None);
+    }
+
+    #[test]
+    fn concurrent_mc_ref_seqno_assignment() {
+        use std::sync::atomic::AtomicUsize;
+        use std::sync::Arc;
+
+        for _ in 0..100 {
+            let meta = Arc::new(BlockMeta::default());
+            let written = Arc::new(AtomicUsize::new(0));
+
+            let threads = (0..16u32)
+                .map(|i| {
+                    let meta = meta.clone();
+                    let written = written.clone();
+                    std::thread::spawn(move || {
+                        let seqno = 1 + i % 2;
+                        match meta.set_masterchain_ref_seqno(seqno) {
+                            Ok(true) => {
+                                written.fetch_add(1, Ordering::AcqRel);
+                            }
+                            Ok(false) => assert_eq!(meta.masterchain_ref_seqno(), seqno),
+                            Err(prev) => assert_ne!(prev, seqno),
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            for thread in threads {
+                thread.join().unwrap();
+            }
+
+            assert_eq!(written.load(Ordering::Acquire), 1);
+            assert!(matches!(meta.masterchain_ref_seqno(), 1 | 2));
+        }
+    }
 }
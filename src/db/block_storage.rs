@@ -16,8 +16,8 @@ use anyhow::{Context, Result};
 use parking_lot::RwLock;
 
 use super::{
-    columns, BlockHandle, BlockHandleStorage, BlockMetaData, Column, HandleCreationStatus,
-    StoredValue, Tree,
+    columns, message_index_storage, BlockHandle, BlockHandleStorage, BlockMetaData, Column,
+    HandleCreationStatus, HandleReadGuard, StoredValue, Tree,
 };
 use crate::config::BlocksGcKind;
 use crate::utils::*;
@@ -29,12 +29,14 @@ pub struct BlockStorage {
     package_entries: Tree<columns::PackageEntries>,
     block_handles: Tree<columns::BlockHandles>,
     archive_ids: RwLock<BTreeSet<u32>>,
+    archive_slice_size: u32,
 }
 
 impl BlockStorage {
     pub fn with_db(
         db: &Arc<rocksdb::DB>,
         block_handle_storage: &Arc<BlockHandleStorage>,
+        archive_slice_size: u32,
     ) -> Result<Self> {
         let manager = Self {
             block_handle_storage: block_handle_storage.clone(),
@@ -42,6 +44,7 @@ impl BlockStorage {
             package_entries: Tree::new(db)?,
             block_handles: Tree::new(db)?,
             archive_ids: Default::default(),
+            archive_slice_size,
         };
 
         manager.preload()?;
@@ -94,7 +97,7 @@ impl BlockStorage {
         if !handle.meta().has_data() {
             let data = block.new_archive_data()?;
 
-            let _lock = handle.block_data_lock().write().await;
+            let _lock = handle.write_block_data().await;
             if !handle.meta().has_data() {
                 self.add_data(&archive_id, data)?;
                 if handle.meta().set_has_data() {
@@ -111,9 +114,94 @@ impl BlockStorage {
         })
     }
 
+    /// Loads and parses the block directly from the pinned value
     pub async fn load_block_data(&self, handle: &BlockHandle) -> Result<BlockStuff> {
         let raw_block = self.load_block_data_raw_ref(handle).await?;
-        BlockStuff::deserialize(handle.id().clone(), raw_block.as_ref())
+        BlockStuff::deserialize_unchecked(handle.id().clone(), raw_block.as_ref())
+    }
+
+    /// Calls the closure for each stored block which has a handle.
+    ///
+    /// Returns the number of visited blocks
+    pub fn for_each_stored_block<F>(&self, mut f: F) -> Result<usize>
+    where
+        F: FnMut(&BlockHandle, BlockStuff) -> Result<()>,
+    {
+        use sha2::{Digest, Sha256};
+
+        let mut total = 0;
+
+        let mut iter = self.package_entries.raw_iterator();
+        iter.seek_to_first();
+        loop {
+            let (key, value) = match (iter.key(), iter.value()) {
+                (Some(key), Some(value)) => (key, value),
+                _ => break iter.status()?,
+            };
+
+            // Key structure:
+            // [workchain id, 4 bytes]
+            // [shard id, 8 bytes]
+            // [seqno, 4 bytes]
+            // [root hash, 32 bytes]
+            // [entry type, 1 byte] <-
+            if key.len() == 49 && key[48] == 0 {
+                let (shard_id, seq_no) =
+                    BlockIdShort::deserialize(&mut std::convert::identity(key))?;
+                let block_id = ton_block::BlockIdExt {
+                    shard_id,
+                    seq_no,
+                    root_hash: ton_types::UInt256::from_slice(&key[16..48]),
+                    file_hash: ton_types::UInt256::from_slice(Sha256::digest(value).as_slice()),
+                };
+
+                if let Some(handle) = self.block_handle_storage.load_handle(&block_id)? {
+                    f(&handle, BlockStuff::deserialize_unchecked(block_id, value)?)?;
+                    total += 1;
+                }
+            }
+
+            iter.next();
+        }
+
+        Ok(total)
+    }
+
+    /// Finds the id of the stored block with the specified seqno in the shard.
+    ///
+    /// Returns `None` if the block data was not stored or was already removed
+    pub fn find_block_id(
+        &self,
+        shard_id: &ton_block::ShardIdent,
+        seq_no: u32,
+    ) -> Result<Option<ton_block::BlockIdExt>> {
+        use sha2::{Digest, Sha256};
+
+        let mut prefix = [0; 16];
+        prefix[..4].copy_from_slice(&shard_id.workchain_id().to_be_bytes());
+        prefix[4..12].copy_from_slice(&shard_id.shard_prefix_with_tag().to_be_bytes());
+        prefix[12..].copy_from_slice(&seq_no.to_be_bytes());
+
+        let mut iter = self.package_entries.raw_iterator();
+        iter.seek(prefix);
+        loop {
+            let (key, value) = match (iter.key(), iter.value()) {
+                (Some(key), Some(value)) if key.starts_with(&prefix) => (key, value),
+                _ => break iter.status().map(|_| None).map_err(From::from),
+            };
+
+            // NOTE: see the key structure in `for_each_stored_block`
+            if key.len() == 49 && key[48] == 0 {
+                break Ok(Some(ton_block::BlockIdExt {
+                    shard_id: shard_id.clone(),
+                    seq_no,
+                    root_hash: ton_types::UInt256::from_slice(&key[16..48]),
+                    file_hash: ton_types::UInt256::from_slice(Sha256::digest(value).as_slice()),
+                }));
+            }
+
+            iter.next();
+        }
     }
 
     pub async fn load_block_data_raw(&self, handle: &BlockHandle) -> Result<Vec<u8>> {
@@ -124,10 +212,15 @@ impl BlockStorage {
             .await
     }
 
+    /// Loads the block data without copying it out of RocksDB.
+    ///
+    /// NOTE: the block data lock is held while the value is alive, so it must not be
+    /// kept for long or passed to the spawned tasks, use [`Self::load_block_data_raw`]
+    /// for that
     pub async fn load_block_data_raw_ref<'a>(
         &'a self,
         handle: &'a BlockHandle,
-    ) -> Result<impl AsRef<[u8]> + 'a> {
+    ) -> Result<PinnedBlockData<'a>> {
         if !handle.meta().has_data() {
             return Err(BlockStorageError::BlockDataNotFound.into());
         }
@@ -139,6 +232,23 @@ impl BlockStorage {
         &self,
         proof: &BlockProofStuffAug,
         handle: BlockProofHandle,
+    ) -> Result<StoreBlockResult> {
+        self.store_checked_block_proof(proof, handle, None).await
+    }
+
+    /// Stores block proof and marks it as checked against the specified key block.
+    ///
+    /// Proof link is not stored when the full proof is already present, because
+    /// the full proof contains the same merkle proof along with the signatures.
+    /// It saves a few kilobytes per block (e.g. for masterchain blocks whose links
+    /// are downloaded during cold boot), readers fall back to the full proof.
+    ///
+    /// NOTE: the flag is persisted in the same write as the proof
+    pub async fn store_checked_block_proof(
+        &self,
+        proof: &BlockProofStuffAug,
+        handle: BlockProofHandle,
+        proof_key_block_seqno: Option<u32>,
     ) -> Result<StoreBlockResult> {
         let block_id = proof.id();
         if matches!(&handle, BlockProofHandle::Existing(handle) if handle.id() != block_id) {
@@ -146,43 +256,65 @@ impl BlockStorage {
         }
 
         let (handle, status) = match handle {
-            BlockProofHandle::Existing(handle) => (handle, HandleCreationStatus::Fetched),
+            BlockProofHandle::Existing(handle) => (
+                self.block_handle_storage.canonical_handle(handle)?,
+                HandleCreationStatus::Fetched,
+            ),
             BlockProofHandle::New(meta_data) => self
                 .block_handle_storage
                 .create_or_load_handle(block_id, meta_data)?,
         };
 
+        let is_link = proof.is_link();
+        let has_proof = |handle: &BlockHandle| match is_link {
+            true => handle.meta().has_proof_link() || handle.meta().has_proof(),
+            false => handle.meta().has_proof(),
+        };
+
         let mut updated = false;
-        if proof.is_link() {
-            let archive_id = PackageEntryId::ProofLink(block_id);
-            if !handle.meta().has_proof_link() {
-                let data = proof.new_archive_data()?;
-
-                let _lock = handle.proof_data_lock().write().await;
-                if !handle.meta().has_proof_link() {
-                    self.add_data(&archive_id, data)?;
-                    if handle.meta().set_has_proof_link() {
-                        self.block_handle_storage.store_handle(&handle)?;
-                        updated = true;
-                    }
+        if !has_proof(&handle) {
+            let data = proof.new_archive_data()?;
+
+            let _lock = handle.write_proof_data().await;
+            if !has_proof(&handle) {
+                let archive_id = match is_link {
+                    true => PackageEntryId::ProofLink(block_id),
+                    false => PackageEntryId::Proof(block_id),
+                };
+
+                let mut batch = rocksdb::WriteBatch::default();
+                batch.put_cf(&self.package_entries.get_cf(), archive_id.to_vec(), data);
+
+                if let Some(seqno) = proof_key_block_seqno {
+                    handle.meta().set_proof_checked(seqno);
                 }
-            }
-        } else {
-            let archive_id = PackageEntryId::Proof(block_id);
-            if !handle.meta().has_proof() {
-                let data = proof.new_archive_data()?;
-
-                let _lock = handle.proof_data_lock().write().await;
-                if !handle.meta().has_proof() {
-                    self.add_data(&archive_id, data)?;
-                    if handle.meta().set_has_proof() {
-                        self.block_handle_storage.store_handle(&handle)?;
-                        updated = true;
-                    }
+                let set = match is_link {
+                    true => handle.meta().set_has_proof_link(),
+                    false => handle.meta().set_has_proof(),
+                };
+                let mut key_block_update = None;
+                if set {
+                    key_block_update = Some(
+                        self.block_handle_storage
+                            .store_handle_batch(&handle, &mut batch)?,
+                    );
+                    updated = true;
+                }
+
+                self.package_entries.raw_db_handle().write(batch)?;
+                if let Some(update) = key_block_update {
+                    update.commit();
                 }
             }
         }
 
+        // Proof could have been stored before it was checked
+        if let Some(seqno) = proof_key_block_seqno {
+            if handle.meta().set_proof_checked(seqno) {
+                self.block_handle_storage.store_handle(&handle)?;
+            }
+        }
+
         Ok(StoreBlockResult {
             handle,
             updated,
@@ -190,6 +322,20 @@ impl BlockStorage {
         })
     }
 
+    /// Loads the full proof for masterchain blocks or the proof link for shard blocks.
+    ///
+    /// Returns `None` if the suitable proof was not stored yet
+    pub async fn load_best_block_proof(
+        &self,
+        handle: &BlockHandle,
+    ) -> Result<Option<BlockProofStuff>> {
+        let mut is_link = false;
+        if !handle.has_proof_or_link(&mut is_link) {
+            return Ok(None);
+        }
+        self.load_block_proof(handle, is_link).await.map(Some)
+    }
+
     pub async fn load_block_proof(
         &self,
         handle: &BlockHandle,
@@ -227,7 +373,7 @@ impl BlockStorage {
         &'a self,
         handle: &'a BlockHandle,
         is_link: bool,
-    ) -> Result<impl AsRef<[u8]> + 'a> {
+    ) -> Result<PinnedBlockData<'a>> {
         let (archive_id, exists) = if is_link {
             (
                 PackageEntryId::ProofLink(handle.id()),
@@ -262,28 +408,22 @@ impl BlockStorage {
         let mut is_link = false;
         let has_proof = handle.has_proof_or_link(&mut is_link);
 
-        let block_data = if has_data {
-            let lock = handle.block_data_lock().write().await;
+        let _lock = handle.lock_data_and_proof().await;
 
+        let block_data = if has_data {
             let entry_id = PackageEntryId::Block(block_id);
-            let data = self.make_archive_segment(&entry_id)?;
-
-            Some((lock, data))
+            Some(self.make_archive_segment(&entry_id)?)
         } else {
             None
         };
 
         let block_proof_data = if has_proof {
-            let lock = handle.proof_data_lock().write().await;
-
             let entry_id = if is_link {
                 PackageEntryId::ProofLink(block_id)
             } else {
                 PackageEntryId::Proof(block_id)
             };
-            let data = self.make_archive_segment(&entry_id)?;
-
-            Some((lock, data))
+            Some(self.make_archive_segment(&entry_id)?)
         } else {
             None
         };
@@ -299,11 +439,11 @@ impl BlockStorage {
         // 0. Create transaction
         let mut batch = rocksdb::WriteBatch::default();
         // 1. Append archive segment with block data
-        if let Some((_, data)) = &block_data {
+        if let Some(data) = &block_data {
             batch.merge_cf(&storage_cf, archive_id_bytes, data);
         }
         // 2. Append archive segment with block proof data
-        if let Some((_, data)) = &block_proof_data {
+        if let Some(data) = &block_proof_data {
             batch.merge_cf(&storage_cf, archive_id_bytes, data);
         }
         // 3. Update block handle meta
@@ -387,7 +527,7 @@ impl BlockStorage {
             // NOTE: handles case when mc_seq_no is far in the future.
             // However if there is a key block between `id` and `mc_seq_no`,
             // this will return an archive without that specified block.
-            Some(id) if mc_seq_no < id + ARCHIVE_PACKAGE_SIZE => Some(*id),
+            Some(id) if mc_seq_no - id < self.archive_slice_size => Some(*id),
             _ => None,
         }
     }
@@ -445,6 +585,11 @@ impl BlockStorage {
         }
     }
 
+    /// Returns the whole stored archive without copying it
+    pub fn get_archive(&self, id: u32) -> Result<Option<rocksdb::DBPinnableSlice<'_>>> {
+        self.archives.get(id.to_be_bytes())
+    }
+
     pub fn get_archive_slice(
         &self,
         id: u32,
@@ -466,9 +611,10 @@ impl BlockStorage {
         key_block_id: &ton_block::BlockIdExt,
         max_blocks_per_batch: Option<usize>,
         gc_type: BlocksGcKind,
+        start_from_seqno: Option<u32>,
     ) -> Result<()> {
         // Find target block
-        let target_block = match gc_type {
+        let mut target_block = match gc_type {
             BlocksGcKind::BeforePreviousKeyBlock => self
                 .block_handle_storage
                 .find_prev_key_block(key_block_id.seq_no)?,
@@ -477,19 +623,28 @@ impl BlockStorage {
                 .find_prev_persistent_key_block(key_block_id.seq_no)?,
         };
 
+        // Blocks before the indexing start are not needed, so remove them
+        // up to the key block at or before it (but never after the previous key block)
+        if let Some(start_from_seqno) = start_from_seqno {
+            let bound = std::cmp::min(start_from_seqno.saturating_add(1), key_block_id.seq_no);
+            if let Some(start_key_block) = self.block_handle_storage.find_prev_key_block(bound)? {
+                let start_seq_no = start_key_block.id().seq_no;
+                match &target_block {
+                    Some(handle) if handle.id().seq_no >= start_seq_no => {}
+                    _ => target_block = Some(start_key_block),
+                }
+            }
+        }
+
         // Load target block data
-        let top_blocks = match target_block {
+        let target_block = match target_block {
             Some(handle) if handle.meta().has_data() => {
                 tracing::info!(
                     key_block_id = %key_block_id.display(),
                     target_block_id = %handle.id().display(),
                     "starting blocks GC",
                 );
-                self.load_block_data(&handle)
-                    .await
-                    .context("Failed to load target key block data")
-                    .and_then(|block_data| TopBlocks::from_mc_block(&block_data))
-                    .context("Failed to compute top blocks for target block")?
+                handle
             }
             _ => {
                 tracing::info!(
@@ -500,25 +655,16 @@ impl BlockStorage {
             }
         };
 
-        // Remove all expired entries
-        let total_cached_handles_removed = self.block_handle_storage.gc_handles_cache(&top_blocks);
-
-        let db = self.package_entries.raw_db_handle().clone();
-        let BlockGcStats {
-            mc_package_entries_removed,
-            total_package_entries_removed,
-            total_handles_removed,
-        } = tokio::task::spawn_blocking(move || {
-            remove_blocks(&db, max_blocks_per_batch, &top_blocks)
-        })
-        .await??;
+        let stats = self
+            .remove_blocks_before(&target_block, max_blocks_per_batch)
+            .await?;
 
         tracing::info!(
             key_block_id = %key_block_id.display(),
-            total_cached_handles_removed,
-            mc_package_entries_removed,
-            total_package_entries_removed,
-            total_handles_removed,
+            mc_package_entries_removed = stats.mc_package_entries_removed,
+            total_package_entries_removed = stats.total_package_entries_removed,
+            total_handles_removed = stats.total_handles_removed,
+            message_index_entries_removed = stats.message_index_entries_removed,
             "finished blocks GC"
         );
 
@@ -526,6 +672,28 @@ impl BlockStorage {
         Ok(())
     }
 
+    /// Removes all blocks which are not needed for the specified masterchain block
+    pub async fn remove_blocks_before(
+        &self,
+        target_block: &BlockHandle,
+        max_blocks_per_batch: Option<usize>,
+    ) -> Result<BlockGcStats> {
+        let top_blocks = self
+            .load_block_data(target_block)
+            .await
+            .context("Failed to load target key block data")
+            .and_then(|block_data| TopBlocks::from_mc_block(&block_data))
+            .context("Failed to compute top blocks for target block")?;
+
+        // Remove all expired entries
+        let total_cached_handles_removed = self.block_handle_storage.gc_handles_cache(&top_blocks);
+        tracing::debug!(total_cached_handles_removed, "removed cached handles");
+
+        let db = self.package_entries.raw_db_handle().clone();
+        tokio::task::spawn_blocking(move || remove_blocks(&db, max_blocks_per_batch, &top_blocks))
+            .await?
+    }
+
     pub fn remove_outdated_archives(&self, until_id: u32) -> Result<()> {
         let mut archive_ids = self.archive_ids.write();
 
@@ -542,8 +710,8 @@ impl BlockStorage {
         let removed_ids = std::mem::replace(&mut *archive_ids, retained_ids);
 
         // Print removed range bounds
-        match (removed_ids.iter().next(), removed_ids.iter().next_back()) {
-            (Some(first), Some(last)) => {
+        let (first, last) = match (removed_ids.iter().next(), removed_ids.iter().next_back()) {
+            (Some(&first), Some(&last)) => {
                 let len = removed_ids.len();
                 tracing::info!(
                     archive_count = len,
@@ -551,23 +719,28 @@ impl BlockStorage {
                     last,
                     "archives GC: removing archives"
                 );
+                (first, last)
             }
             _ => {
                 tracing::info!("archives GC: nothing to remove");
                 return Ok(());
             }
-        }
-
-        // Remove archives
-        let archives_cf = self.archives.get_cf();
+        };
 
-        let mut batch = rocksdb::WriteBatch::default();
-        for id in removed_ids {
-            batch.delete_cf(&archives_cf, id.to_be_bytes());
+        // Remove archives. All ids before the retained ones are removed,
+        // so they form a contiguous range of keys
+        match last.checked_add(1) {
+            Some(end) => self
+                .archives
+                .delete_range(first.to_be_bytes(), end.to_be_bytes())?,
+            // NOTE: range end is exclusive, so the last possible key is removed separately
+            None => {
+                self.archives
+                    .delete_range(first.to_be_bytes(), last.to_be_bytes())?;
+                self.archives.remove(last.to_be_bytes())?;
+            }
         }
 
-        self.archives.raw_db_handle().write(batch)?;
-
         tracing::info!("archives GC: done");
         Ok(())
     }
@@ -592,9 +765,9 @@ impl BlockStorage {
         I: Borrow<ton_block::BlockIdExt> + Hash,
     {
         let _lock = match &id {
-            PackageEntryId::Block(_) => handle.block_data_lock().read().await,
+            PackageEntryId::Block(_) => handle.read_block_data().await,
             PackageEntryId::Proof(_) | PackageEntryId::ProofLink(_) => {
-                handle.proof_data_lock().read().await
+                handle.read_proof_data().await
             }
         };
 
@@ -608,19 +781,19 @@ impl BlockStorage {
         &'a self,
         handle: &'a BlockHandle,
         id: &PackageEntryId<I>,
-    ) -> Result<impl AsRef<[u8]> + 'a>
+    ) -> Result<PinnedBlockData<'a>>
     where
         I: Borrow<ton_block::BlockIdExt> + Hash,
     {
         let lock = match id {
-            PackageEntryId::Block(_) => handle.block_data_lock().read().await,
+            PackageEntryId::Block(_) => handle.read_block_data().await,
             PackageEntryId::Proof(_) | PackageEntryId::ProofLink(_) => {
-                handle.proof_data_lock().read().await
+                handle.read_proof_data().await
             }
         };
 
         match self.package_entries.get(id.to_vec())? {
-            Some(data) => Ok(BlockContentsLock { _lock: lock, data }),
+            Some(data) => Ok(PinnedBlockData { _lock: lock, data }),
             None => Err(BlockStorageError::InvalidBlockData.into()),
         }
     }
@@ -633,23 +806,16 @@ impl BlockStorage {
             return mc_seq_no;
         }
 
-        let mut archive_id = mc_seq_no - mc_seq_no % ARCHIVE_SLICE_SIZE;
-
-        let prev_id = {
-            let latest_archives = self.archive_ids.read();
-            latest_archives.range(..=mc_seq_no).next_back().cloned()
-        };
+        let mut archive_id = mc_seq_no - mc_seq_no % self.archive_slice_size;
 
-        if let Some(prev_id) = prev_id {
-            if archive_id < prev_id {
-                archive_id = prev_id;
+        // NOTE: the lock is held, so that the id is not split by a concurrent key block
+        let mut archive_ids = self.archive_ids.write();
+        if let Some(prev_id) = archive_ids.range(..=mc_seq_no).next_back() {
+            if archive_id < *prev_id {
+                archive_id = *prev_id;
             }
         }
-
-        if mc_seq_no.saturating_sub(archive_id) >= ARCHIVE_PACKAGE_SIZE {
-            self.archive_ids.write().insert(mc_seq_no);
-            archive_id = mc_seq_no;
-        }
+        archive_ids.insert(archive_id);
 
         archive_id
     }
@@ -694,6 +860,8 @@ fn remove_blocks(
     max_blocks_per_batch: Option<usize>,
     top_blocks: &TopBlocks,
 ) -> Result<BlockGcStats> {
+    use ton_block::Deserializable;
+
     let mut stats = BlockGcStats::default();
 
     // Cache cfs before loop
@@ -706,6 +874,9 @@ fn remove_blocks(
     let key_blocks_cf = db
         .cf_handle(columns::KeyBlocks::NAME)
         .expect("Shouldn't fail");
+    let message_index_cf = db
+        .cf_handle(columns::MessageIndex::NAME)
+        .expect("Shouldn't fail");
 
     // Create batch
     let mut batch = rocksdb::WriteBatch::default();
@@ -717,6 +888,19 @@ fn remove_blocks(
     let mut key_blocks_readopts = Default::default();
     columns::KeyBlocks::read_options(&mut key_blocks_readopts);
 
+    let is_retained = |shard_ident: &ton_block::ShardIdent, seq_no: u32| -> Result<bool> {
+        // Don't gc latest blocks
+        if top_blocks.contains_shard_seq_no(shard_ident, seq_no) || seq_no == 0 {
+            return Ok(true);
+        }
+
+        // Additionally check whether this item is a key block
+        Ok(shard_ident.is_masterchain()
+            && db
+                .get_pinned_cf_opt(&key_blocks_cf, seq_no.to_be_bytes(), &key_blocks_readopts)?
+                .is_some())
+    };
+
     // Iterate all entries and find expired items
     let mut blocks_iter = db.raw_iterator_cf_opt(&blocks_cf, package_entries_readopts);
     blocks_iter.seek_to_first();
@@ -730,20 +914,8 @@ fn remove_blocks(
         // Read only prefix with shard ident and seqno
         let (shard_ident, seq_no) = BlockIdShort::deserialize(&mut std::convert::identity(key))?;
 
-        // Don't gc latest blocks
-        if top_blocks.contains_shard_seq_no(&shard_ident, seq_no) {
-            blocks_iter.next();
-            continue;
-        }
-
-        // Additionally check whether this item is a key block
-        if seq_no == 0
-            || shard_ident.is_masterchain()
-                && db
-                    .get_pinned_cf_opt(&key_blocks_cf, seq_no.to_be_bytes(), &key_blocks_readopts)?
-                    .is_some()
-        {
-            // Don't remove key blocks
+        // Don't remove latest and key blocks
+        if is_retained(&shard_ident, seq_no)? {
             blocks_iter.next();
             continue;
         }
@@ -766,22 +938,22 @@ fn remove_blocks(
             stats.total_handles_removed += 1;
         }
 
-        batch_len += 1;
-        if matches!(
-            max_blocks_per_batch,
-            Some(max_blocks_per_batch) if batch_len >= max_blocks_per_batch
-        ) {
-            tracing::info!(
-                total_package_entries_removed = stats.total_package_entries_removed,
-                "applying intermediate batch",
-            );
-            let batch = std::mem::take(&mut batch);
-            db.write(batch)?;
-            batch_len = 0;
+        // Remove index entries of the block messages
+        // NOTE: only the removed blocks are parsed, so the message index is not scanned
+        if key.len() == 49 && key[48] == 0 {
+            let data = blocks_iter.value().unwrap_or_default();
+            let block = ton_block::Block::construct_from_bytes(data)
+                .context("Failed to parse the removed block")?;
+            stats.message_index_entries_removed +=
+                message_index_storage::remove_block_batch(&message_index_cf, &block, &mut batch)?;
         }
 
+        batch_len += 1;
+        write_intermediate_batch(db, &mut batch, &mut batch_len, max_blocks_per_batch)?;
+
         blocks_iter.next();
     }
+    drop(blocks_iter);
 
     if batch_len > 0 {
         tracing::info!("applying final batch");
@@ -792,27 +964,43 @@ fn remove_blocks(
     Ok(stats)
 }
 
+fn write_intermediate_batch(
+    db: &rocksdb::DB,
+    batch: &mut rocksdb::WriteBatch,
+    batch_len: &mut usize,
+    max_blocks_per_batch: Option<usize>,
+) -> Result<()> {
+    if matches!(
+        max_blocks_per_batch,
+        Some(max_blocks_per_batch) if *batch_len >= max_blocks_per_batch
+    ) {
+        tracing::info!(batch_len = *batch_len, "applying intermediate batch");
+        db.write(std::mem::take(batch))?;
+        *batch_len = 0;
+    }
+    Ok(())
+}
+
 #[derive(Debug, Copy, Clone, Default)]
 pub struct BlockGcStats {
     pub mc_package_entries_removed: usize,
     pub total_package_entries_removed: usize,
     pub total_handles_removed: usize,
+    pub message_index_entries_removed: usize,
 }
 
-struct BlockContentsLock<'a> {
-    _lock: tokio::sync::RwLockReadGuard<'a, ()>,
+/// Block or proof data pinned in the RocksDB block cache along with the data lock
+pub struct PinnedBlockData<'a> {
+    _lock: HandleReadGuard<'a>,
     data: rocksdb::DBPinnableSlice<'a>,
 }
 
-impl<'a> AsRef<[u8]> for BlockContentsLock<'a> {
+impl<'a> AsRef<[u8]> for PinnedBlockData<'a> {
     fn as_ref(&self) -> &[u8] {
         self.data.as_ref()
     }
 }
 
-pub const ARCHIVE_PACKAGE_SIZE: u32 = 100;
-pub const ARCHIVE_SLICE_SIZE: u32 = 20_000;
-
 #[derive(thiserror::Error, Debug)]
 enum BlockStorageError {
     #[error("Block data not found")]
@@ -826,3 +1014,171 @@ enum BlockStorageError {
     #[error("Offset is outside of the archive slice")]
     InvalidOffset,
 }
+
+#[cfg(test)]
+mod tests {
+    use ton_block::Serializable;
+
+    use super::*;
+    use crate::test_helpers::*;
+
+    #[test]
+    fn archive_ids_use_configured_slice_size() {
+        let dir = TempDir::new("archive_slice_size");
+        let caches = crate::db::tree::DbCaches::with_capacity(0).unwrap();
+        let db = test_db_builder(&dir, &caches)
+            .column::<columns::Archives>()
+            .column::<columns::PackageEntries>()
+            .column::<columns::BlockHandles>()
+            .column::<columns::KeyBlocks>()
+            .build()
+            .unwrap();
+
+        let block_handle_storage = Arc::new(BlockHandleStorage::with_db(&db).unwrap());
+        let storage = BlockStorage::with_db(&db, &block_handle_storage, 10).unwrap();
+
+        let archive_id = |seq_no: u32, is_key_block: bool| {
+            let meta_data = BlockMetaData {
+                is_key_block,
+                gen_utime: seq_no,
+                mc_ref_seqno: Some(seq_no),
+            };
+            let (handle, _) = block_handle_storage
+                .create_or_load_handle(&mc_block_id(seq_no), meta_data)
+                .unwrap();
+            storage.compute_archive_id(&handle)
+        };
+
+        // Slice starts
+        assert_eq!(archive_id(3, false), 0);
+        assert_eq!(archive_id(9, false), 0);
+        assert_eq!(archive_id(12, false), 10);
+
+        // Key block starts a new archive inside the slice
+        assert_eq!(archive_id(14, true), 14);
+        assert_eq!(archive_id(17, false), 14);
+        assert_eq!(archive_id(25, false), 20);
+
+        assert_eq!(storage.get_archive_id(5), Some(0));
+        assert_eq!(storage.get_archive_id(13), Some(10));
+        assert_eq!(storage.get_archive_id(19), Some(14));
+        assert_eq!(storage.get_archive_id(29), Some(20));
+        assert_eq!(storage.get_archive_id(30), None);
+    }
+
+    #[tokio::test]
+    async fn gc_removes_blocks_before_start_seqno() {
+        let dir = TempDir::new("gc_start_seqno");
+        let db = test_db(&dir).await;
+        let block_storage = db.block_storage();
+        let block_handle_storage = db.block_handle_storage();
+
+        let key_blocks = [10, 20, 30];
+        for seq_no in 1..=35 {
+            let is_key_block = key_blocks.contains(&seq_no);
+            let (id, data) = match is_key_block {
+                true => make_key_block_with_config(seq_no, seq_no - 10, Default::default()),
+                false => make_mc_block(seq_no),
+            };
+            let block = BlockStuff::deserialize_checked(id, &data).unwrap();
+            let meta_data = BlockMetaData {
+                is_key_block,
+                gen_utime: seq_no,
+                mc_ref_seqno: Some(seq_no),
+            };
+            block_storage
+                .store_block_data(&BlockStuffAug::new(block, data), meta_data)
+                .await
+                .unwrap();
+        }
+
+        let has_block = |seq_no: u32| {
+            block_storage
+                .find_block_id(&ton_block::ShardIdent::masterchain(), seq_no)
+                .unwrap()
+                .is_some()
+        };
+        let last_key_block = block_handle_storage.find_last_key_block().unwrap();
+
+        // There is no persistent key block, so the GC is skipped without the start seqno
+        block_storage
+            .remove_outdated_blocks(
+                last_key_block.id(),
+                None,
+                BlocksGcKind::BeforePreviousPersistentState,
+                None,
+            )
+            .await
+            .unwrap();
+        assert!((1..=35).all(has_block));
+
+        // Blocks before the key block at or before the start seqno are removed
+        block_storage
+            .remove_outdated_blocks(
+                last_key_block.id(),
+                None,
+                BlocksGcKind::BeforePreviousPersistentState,
+                Some(25),
+            )
+            .await
+            .unwrap();
+        assert!((1..10).chain(11..20).all(|seq_no| !has_block(seq_no)));
+        assert!([10].into_iter().chain(20..=35).all(has_block));
+
+        // Start seqno after the last key block never removes more than the previous key block
+        block_storage
+            .remove_outdated_blocks(
+                last_key_block.id(),
+                None,
+                BlocksGcKind::BeforePreviousPersistentState,
+                Some(100),
+            )
+            .await
+            .unwrap();
+        assert!((20..=35).all(has_block));
+    }
+
+    #[tokio::test]
+    async fn gc_removes_message_index_entries() {
+        let dir = TempDir::new("gc_message_index");
+        let db = test_db(&dir).await;
+        let message_index = db.message_index();
+
+        let shard = ton_block::ShardIdent::with_tagged_prefix(0, 0x8000_0000_0000_0000).unwrap();
+        let (old_block, old_msg_hashes) =
+            make_block_with_messages(shard, 5, &[ton_types::UInt256::from([1; 32])]);
+        let (new_block, new_msg_hashes) =
+            make_block_with_messages(shard, 15, &[ton_types::UInt256::from([2; 32])]);
+        for block in [&old_block, &new_block] {
+            let data = ton_types::serialize_toc(&block.block().serialize().unwrap()).unwrap();
+            let meta_data = BlockMetaData {
+                is_key_block: false,
+                gen_utime: 0,
+                mc_ref_seqno: Some(block.id().seq_no),
+            };
+            db.block_storage()
+                .store_block_data(&BlockStuffAug::new(block.clone(), data), meta_data)
+                .await
+                .unwrap();
+            message_index.index_block(block).unwrap();
+        }
+
+        let top_blocks = TopBlocks {
+            mc_block: mc_block_id(100),
+            shard_heights: [(shard, 10)].into_iter().collect(),
+        };
+        let raw_db = db.block_storage().package_entries.raw_db_handle().clone();
+        let stats = remove_blocks(&raw_db, Some(1), &top_blocks).unwrap();
+
+        assert_eq!(stats.message_index_entries_removed, 1);
+        assert!(message_index.find(&old_msg_hashes[0]).unwrap().is_none());
+        assert_eq!(
+            message_index
+                .find(&new_msg_hashes[0])
+                .unwrap()
+                .unwrap()
+                .block_id,
+            *new_block.id()
+        );
+    }
+}
@@ -128,6 +128,29 @@ impl Column for NodeStates {
     }
 }
 
+/// Ring of the last chain progress decisions
+/// - Key: `u64 (BE)` (entry id)
+/// - Value: `AuditLogEntry`
+pub struct AuditLog;
+impl Column for AuditLog {
+    const NAME: &'static str = "audit_log";
+}
+
+/// Maps inbound message hash to the transaction
+/// - Key: `ton_types::UInt256` (message hash)
+/// - Value: `ton_block::BlockIdExt (LE), ton_types::UInt256 (account), u64 (LE, lt)`
+pub struct MessageIndex;
+impl Column for MessageIndex {
+    const NAME: &'static str = "message_index";
+    const DERIVATIVE: bool = true;
+
+    fn options(opts: &mut Options, caches: &DbCaches) {
+        default_block_based_table_factory(opts, caches);
+
+        opts.optimize_for_point_lookup(10);
+    }
+}
+
 /// Stores connections data
 /// - Key: `ton_types::UInt256` (block root hash)
 /// - Value: `ton_block::BlockIdExt (LE)`
@@ -0,0 +1,358 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::RangeBounds;
+use std::sync::Arc;
+
+use anyhow::Result;
+use parking_lot::{RwLock, RwLockWriteGuard};
+
+use super::{columns, StoredValue, Tree};
+
+/// In-memory copy of the key blocks column.
+///
+/// Key blocks are rare, so all ids are loaded at startup and lookups
+/// don't touch RocksDB. The column is written only when a new key block is indexed.
+pub struct KeyBlocksIndex {
+    key_blocks: Tree<columns::KeyBlocks>,
+    ids: RwLock<BTreeMap<u32, ton_block::BlockIdExt>>,
+}
+
+impl KeyBlocksIndex {
+    pub fn with_db(db: &Arc<rocksdb::DB>) -> Result<Self> {
+        let key_blocks = Tree::<columns::KeyBlocks>::new(db)?;
+
+        let mut ids = BTreeMap::new();
+        let mut iter = key_blocks.raw_iterator();
+        iter.seek_to_first();
+        while let Some(value) = iter.value() {
+            let id = ton_block::BlockIdExt::from_slice(value)?;
+            ids.insert(id.seq_no, id);
+            iter.next();
+        }
+        iter.status()?;
+
+        Ok(Self {
+            key_blocks,
+            ids: RwLock::new(ids),
+        })
+    }
+
+    pub fn get(&self, seq_no: u32) -> Option<ton_block::BlockIdExt> {
+        self.ids.read().get(&seq_no).cloned()
+    }
+
+    pub fn last(&self) -> Option<ton_block::BlockIdExt> {
+        self.ids.read().values().next_back().cloned()
+    }
+
+    /// Returns the last key block with seqno less than the specified one
+    pub fn prev(&self, seq_no: u32) -> Option<ton_block::BlockIdExt> {
+        self.ids
+            .read()
+            .range(..seq_no)
+            .next_back()
+            .map(|(_, id)| id.clone())
+    }
+
+    /// Returns the first key block with seqno greater than or equal to the specified one
+    pub fn next(&self, seq_no: u32) -> Option<ton_block::BlockIdExt> {
+        self.ids
+            .read()
+            .range(seq_no..)
+            .next()
+            .map(|(_, id)| id.clone())
+    }
+
+    /// Returns seqnos of the key blocks in the specified range
+    pub fn seq_nos<R: RangeBounds<u32>>(&self, range: R) -> BTreeSet<u32> {
+        self.ids
+            .read()
+            .range(range)
+            .map(|(seq_no, _)| *seq_no)
+            .collect()
+    }
+
+    /// Returns ids of all key blocks sorted by seqno
+    pub fn ids(&self) -> Vec<ton_block::BlockIdExt> {
+        self.ids.read().values().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.read().is_empty()
+    }
+
+    /// Writes the batch with the key block entry added if it is not indexed yet.
+    ///
+    /// NOTE: the same block is stored again after the node state pointers are rewound,
+    /// so only a different block with the same seqno is treated as an error
+    pub fn write_with(
+        &self,
+        block_id: &ton_block::BlockIdExt,
+        mut batch: rocksdb::WriteBatch,
+    ) -> Result<()> {
+        let db = self.key_blocks.raw_db_handle();
+
+        if self.check(block_id)? {
+            db.write(batch)?;
+            return Ok(());
+        }
+
+        // NOTE: the entry is inserted under the lock, so that the column and
+        // the cache are updated in the same order
+        let mut ids = self.ids.write();
+        if !check_entry(&ids, block_id)? {
+            batch.put_cf(
+                &self.key_blocks.get_cf(),
+                block_id.seq_no.to_be_bytes(),
+                block_id.to_vec(),
+            );
+        }
+        db.write(batch)?;
+        ids.insert(block_id.seq_no, block_id.clone());
+
+        Ok(())
+    }
+
+    /// Adds the key block entry to the batch if it is not indexed yet.
+    ///
+    /// NOTE: the returned update must be committed after the batch is written,
+    /// so that the cache never contains entries which are not in the column.
+    /// The index is locked until the update is committed or dropped, so that
+    /// a different block with the same seqno can't be added in the meantime
+    pub fn add_to_batch(
+        &self,
+        block_id: &ton_block::BlockIdExt,
+        batch: &mut rocksdb::WriteBatch,
+    ) -> Result<KeyBlocksIndexUpdate<'_>> {
+        if self.check(block_id)? {
+            return Ok(KeyBlocksIndexUpdate::empty());
+        }
+
+        let ids = self.ids.write();
+        if check_entry(&ids, block_id)? {
+            return Ok(KeyBlocksIndexUpdate::empty());
+        }
+
+        batch.put_cf(
+            &self.key_blocks.get_cf(),
+            block_id.seq_no.to_be_bytes(),
+            block_id.to_vec(),
+        );
+
+        Ok(KeyBlocksIndexUpdate {
+            entry: Some((ids, block_id.clone())),
+        })
+    }
+
+    /// Returns `true` if the same block is already indexed
+    fn check(&self, block_id: &ton_block::BlockIdExt) -> Result<bool> {
+        check_entry(&self.ids.read(), block_id)
+    }
+}
+
+/// Cache update of the key block entry added to the batch
+/// (see [`KeyBlocksIndex::add_to_batch`])
+#[must_use = "cache must be updated after the batch is written"]
+pub struct KeyBlocksIndexUpdate<'a> {
+    /// Locked cache with the new entry
+    entry: Option<(
+        RwLockWriteGuard<'a, BTreeMap<u32, ton_block::BlockIdExt>>,
+        ton_block::BlockIdExt,
+    )>,
+}
+
+impl<'a> KeyBlocksIndexUpdate<'a> {
+    pub fn empty() -> Self {
+        Self { entry: None }
+    }
+
+    /// Makes the written entry visible in the cache and unlocks the index
+    pub fn commit(self) {
+        if let Some((mut ids, block_id)) = self.entry {
+            ids.insert(block_id.seq_no, block_id);
+        }
+    }
+}
+
+fn check_entry(
+    ids: &BTreeMap<u32, ton_block::BlockIdExt>,
+    block_id: &ton_block::BlockIdExt,
+) -> Result<bool> {
+    match ids.get(&block_id.seq_no) {
+        Some(stored) if stored == block_id => Ok(true),
+        Some(_) => Err(KeyBlocksIndexError::KeyBlockConflict(block_id.seq_no).into()),
+        None => Ok(false),
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+enum KeyBlocksIndexError {
+    #[error("Different key block is already stored with seqno {}", .0)]
+    KeyBlockConflict(u32),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tree::DbCaches;
+    use crate::test_helpers::*;
+
+    #[test]
+    fn cache_is_consistent_with_column() {
+        let dir = TempDir::new("key_blocks_index");
+        let caches = DbCaches::with_capacity(0).unwrap();
+        let db = test_db_builder(&dir, &caches)
+            .column::<columns::KeyBlocks>()
+            .build()
+            .unwrap();
+
+        let key_block_id = |seq_no: u32, hash: u8| ton_block::BlockIdExt {
+            shard_id: ton_block::ShardIdent::masterchain(),
+            seq_no,
+            root_hash: ton_types::UInt256::from_slice(&[hash; 32]),
+            file_hash: Default::default(),
+        };
+
+        let index = Arc::new(KeyBlocksIndex::with_db(&db).unwrap());
+
+        // Concurrent inserts of overlapping ranges
+        let threads = (0..8u32)
+            .map(|thread| {
+                let index = index.clone();
+                std::thread::spawn(move || {
+                    for seq_no in (thread * 50..thread * 50 + 200).step_by(3) {
+                        let id = key_block_id(seq_no, seq_no as u8);
+                        if seq_no % 2 == 0 {
+                            index.write_with(&id, Default::default()).unwrap();
+                        } else {
+                            let mut batch = Default::default();
+                            let update = index.add_to_batch(&id, &mut batch).unwrap();
+                            index.key_blocks.raw_db_handle().write(batch).unwrap();
+                            update.commit();
+                        }
+
+                        // Different block with the same seqno
+                        assert!(index
+                            .write_with(&key_block_id(seq_no, !seq_no as u8), Default::default())
+                            .is_err());
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // Cache of the reopened index must be the same
+        let reloaded = KeyBlocksIndex::with_db(&db).unwrap();
+        assert_eq!(*index.ids.read(), *reloaded.ids.read());
+        assert_eq!(index.len(), reloaded.len());
+
+        let expected = (0..8u32)
+            .flat_map(|thread| (thread * 50..thread * 50 + 200).step_by(3))
+            .collect::<std::collections::BTreeSet<_>>();
+        assert!(reloaded
+            .ids
+            .read()
+            .keys()
+            .copied()
+            .eq(expected.iter().copied()));
+
+        // Lookups
+        let first = *expected.iter().next().unwrap();
+        let last = *expected.iter().next_back().unwrap();
+        assert_eq!(index.last().unwrap().seq_no, last);
+        assert_eq!(index.next(0).unwrap().seq_no, first);
+        assert!(index.prev(first).is_none());
+        for &seq_no in &expected {
+            assert_eq!(
+                index.get(seq_no).unwrap(),
+                key_block_id(seq_no, seq_no as u8)
+            );
+            assert_eq!(index.prev(seq_no + 1).unwrap().seq_no, seq_no);
+            assert_eq!(index.next(seq_no).unwrap().seq_no, seq_no);
+        }
+    }
+
+    #[test]
+    fn failed_batch_is_not_cached() {
+        let dir = TempDir::new("key_blocks_index_batch");
+        let caches = DbCaches::with_capacity(0).unwrap();
+        let db = test_db_builder(&dir, &caches)
+            .column::<columns::KeyBlocks>()
+            .build()
+            .unwrap();
+
+        let key_block_id = ton_block::BlockIdExt {
+            shard_id: ton_block::ShardIdent::masterchain(),
+            seq_no: 10,
+            root_hash: ton_types::UInt256::from_slice(&[1; 32]),
+            file_hash: Default::default(),
+        };
+
+        let index = KeyBlocksIndex::with_db(&db).unwrap();
+
+        // Batch was not written
+        let mut batch = rocksdb::WriteBatch::default();
+        let update = index.add_to_batch(&key_block_id, &mut batch).unwrap();
+        drop((update, batch));
+        assert!(index.get(10).is_none());
+        assert!(index.is_empty());
+
+        let mut batch = rocksdb::WriteBatch::default();
+        let update = index.add_to_batch(&key_block_id, &mut batch).unwrap();
+        db.write(batch).unwrap();
+        update.commit();
+        assert_eq!(index.get(10).unwrap(), key_block_id);
+        assert_eq!(
+            KeyBlocksIndex::with_db(&db).unwrap().get(10).unwrap(),
+            key_block_id
+        );
+    }
+
+    #[test]
+    fn conflicting_batches_are_serialized() {
+        let dir = TempDir::new("key_blocks_index_conflict");
+        let caches = DbCaches::with_capacity(0).unwrap();
+        let db = test_db_builder(&dir, &caches)
+            .column::<columns::KeyBlocks>()
+            .build()
+            .unwrap();
+
+        let key_block_id = |hash: u8| ton_block::BlockIdExt {
+            shard_id: ton_block::ShardIdent::masterchain(),
+            seq_no: 10,
+            root_hash: ton_types::UInt256::from_slice(&[hash; 32]),
+            file_hash: Default::default(),
+        };
+
+        let index = Arc::new(KeyBlocksIndex::with_db(&db).unwrap());
+
+        let mut batch = rocksdb::WriteBatch::default();
+        let update = index.add_to_batch(&key_block_id(1), &mut batch).unwrap();
+
+        // Different block with the same seqno waits for the pending update
+        let (tx, rx) = std::sync::mpsc::channel();
+        let thread = std::thread::spawn({
+            let index = index.clone();
+            move || {
+                let mut batch = rocksdb::WriteBatch::default();
+                let result = index.add_to_batch(&key_block_id(2), &mut batch).map(drop);
+                tx.send(result.is_err()).unwrap();
+            }
+        });
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(rx.try_recv().is_err());
+
+        db.write(batch).unwrap();
+        update.commit();
+
+        // Conflict is detected after the first update is committed
+        assert!(rx.recv().unwrap());
+        thread.join().unwrap();
+        assert_eq!(index.get(10).unwrap(), key_block_id(1));
+    }
+}
@@ -0,0 +1,226 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use ton_block::HashmapAugType;
+use ton_types::UInt256;
+
+use super::{columns, read_block_id_le, write_block_id_le, BlockStorage, Tree};
+use crate::utils::*;
+
+/// Index of the transactions by the hash of their inbound message
+pub struct MessageIndexStorage {
+    entries: Tree<columns::MessageIndex>,
+}
+
+impl MessageIndexStorage {
+    pub fn with_db(db: &Arc<rocksdb::DB>) -> Result<Self> {
+        Ok(Self {
+            entries: Tree::new(db)?,
+        })
+    }
+
+    /// Adds inbound messages of all block transactions to the index.
+    /// Returns the number of indexed messages
+    ///
+    /// NOTE: the same message could be processed only once, so the entry
+    /// is overwritten only when the block is indexed again
+    pub fn index_block(&self, block: &BlockStuff) -> Result<usize> {
+        let mut batch = rocksdb::WriteBatch::default();
+        let count = self.index_block_batch(block, &mut batch)?;
+        self.entries.raw_db_handle().write(batch)?;
+        Ok(count)
+    }
+
+    /// Adds index entries of the block to the batch (see [`MessageIndexStorage::index_block`])
+    pub fn index_block_batch(
+        &self,
+        block: &BlockStuff,
+        batch: &mut rocksdb::WriteBatch,
+    ) -> Result<usize> {
+        let cf = self.entries.get_cf();
+        let mut count = 0;
+
+        let block_id = write_block_id_le(block.id());
+        for_each_in_msg(block.block(), |account, lt, msg_hash| {
+            let location = TransactionLocation::write(&block_id, account, lt);
+            batch.put_cf(&cf, msg_hash.as_slice(), location);
+            count += 1;
+        })?;
+
+        Ok(count)
+    }
+
+    /// Indexes all stored applied blocks.
+    /// Returns the number of indexed messages
+    pub fn rebuild(&self, block_storage: &BlockStorage) -> Result<usize> {
+        let mut count = 0;
+        block_storage.for_each_stored_block(|handle, block| {
+            if handle.meta().is_applied() {
+                count += self.index_block(&block)?;
+            }
+            Ok(())
+        })?;
+        Ok(count)
+    }
+
+    pub fn find(&self, msg_hash: &UInt256) -> Result<Option<TransactionLocation>> {
+        match self.entries.get(msg_hash.as_slice())? {
+            Some(value) => Ok(Some(TransactionLocation::read(&value)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Adds removal of the index entries of the block to the batch (e.g. when
+/// the block is removed by GC). Returns the number of removed entries
+///
+/// NOTE: the same message could be processed only once, so its entry
+/// always references this block
+pub(super) fn remove_block_batch(
+    cf: &impl rocksdb::AsColumnFamilyRef,
+    block: &ton_block::Block,
+    batch: &mut rocksdb::WriteBatch,
+) -> Result<usize> {
+    let mut count = 0;
+    for_each_in_msg(block, |_, _, msg_hash| {
+        batch.delete_cf(cf, msg_hash.as_slice());
+        count += 1;
+    })?;
+    Ok(count)
+}
+
+/// Calls `f` with the account, lt and inbound message hash of each block transaction
+fn for_each_in_msg<F>(block: &ton_block::Block, mut f: F) -> Result<()>
+where
+    F: FnMut(&UInt256, u64, UInt256),
+{
+    block
+        .read_extra()?
+        .read_account_blocks()?
+        .iterate_objects(|account_block| {
+            let account = UInt256::from_slice(&account_block.account_id().get_bytestring(0));
+
+            account_block
+                .transactions()
+                .iterate_objects(|ton_block::InRefValue(tx)| {
+                    if let Some(in_msg) = tx.in_msg_cell() {
+                        f(&account, tx.lt, in_msg.repr_hash());
+                    }
+                    Ok(true)
+                })?;
+
+            Ok(true)
+        })?;
+    Ok(())
+}
+
+/// Transaction triggered by the message
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TransactionLocation {
+    pub block_id: ton_block::BlockIdExt,
+    pub account: UInt256,
+    pub lt: u64,
+}
+
+impl TransactionLocation {
+    const SIZE: usize = 80 + 32 + 8;
+
+    fn write(block_id: &[u8; 80], account: &UInt256, lt: u64) -> [u8; Self::SIZE] {
+        let mut result = [0; Self::SIZE];
+        result[..80].copy_from_slice(block_id);
+        result[80..112].copy_from_slice(account.as_slice());
+        result[112..].copy_from_slice(&lt.to_le_bytes());
+        result
+    }
+
+    fn read(data: &[u8]) -> Result<Self> {
+        if data.len() != Self::SIZE {
+            return Err(MessageIndexStorageError::InvalidTransactionLocation.into());
+        }
+
+        let block_id =
+            read_block_id_le(data).ok_or(MessageIndexStorageError::InvalidTransactionLocation)?;
+        let account = UInt256::from_slice(&data[80..112]);
+
+        let mut lt = [0; 8];
+        lt.copy_from_slice(&data[112..]);
+        let lt = u64::from_le_bytes(lt);
+
+        Ok(Self {
+            block_id,
+            account,
+            lt,
+        })
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+enum MessageIndexStorageError {
+    #[error("Invalid transaction location")]
+    InvalidTransactionLocation,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::*;
+
+    #[tokio::test]
+    async fn index_and_find_messages() {
+        let dir = TempDir::new("index_and_find_messages");
+        let db = test_db(&dir).await;
+        let message_index = db.message_index();
+
+        let shard = ton_block::ShardIdent::with_tagged_prefix(0, 0x8000_0000_0000_0000).unwrap();
+        let accounts = [UInt256::from([1; 32]), UInt256::from([2; 32])];
+        let (block, msg_hashes) = make_block_with_messages(shard, 10, &accounts);
+
+        assert_eq!(message_index.index_block(&block).unwrap(), 2);
+        for (i, (msg_hash, account)) in msg_hashes.iter().zip(&accounts).enumerate() {
+            let location = message_index.find(msg_hash).unwrap().unwrap();
+            assert_eq!(&location.block_id, block.id());
+            assert_eq!(&location.account, account);
+            assert_eq!(location.lt, i as u64 + 1);
+        }
+
+        // Unknown message
+        assert!(message_index
+            .find(&UInt256::from([3; 32]))
+            .unwrap()
+            .is_none());
+
+        // Indexing the same block again is idempotent
+        assert_eq!(message_index.index_block(&block).unwrap(), 2);
+        assert_eq!(
+            message_index
+                .find(&msg_hashes[0])
+                .unwrap()
+                .unwrap()
+                .block_id,
+            *block.id()
+        );
+    }
+
+    #[test]
+    fn transaction_location_roundtrip() {
+        let location = TransactionLocation {
+            block_id: ton_block::BlockIdExt {
+                shard_id: ton_block::ShardIdent::with_tagged_prefix(0, 0x4000_0000_0000_0000)
+                    .unwrap(),
+                seq_no: 123,
+                root_hash: UInt256::from([1; 32]),
+                file_hash: UInt256::from([2; 32]),
+            },
+            account: UInt256::from([3; 32]),
+            lt: 456,
+        };
+
+        let data = TransactionLocation::write(
+            &write_block_id_le(&location.block_id),
+            &location.account,
+            location.lt,
+        );
+        assert_eq!(TransactionLocation::read(&data).unwrap(), location);
+        assert!(TransactionLocation::read(&data[..100]).is_err());
+    }
+}
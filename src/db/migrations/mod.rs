@@ -12,37 +12,33 @@ use super::tree::Tree;
 mod v2_0_7;
 mod v2_0_8;
 
-const CURRENT_VERSION: Semver = [2, 0, 8];
+pub(super) const CURRENT_VERSION: Semver = [2, 0, 8];
 
-pub async fn apply(db: &Arc<rocksdb::DB>) -> Result<()> {
-    const DB_VERSION_KEY: &str = "db_version";
+/// NOTE: the version of the new DB is stored by the [`DbBuilder`]
+///
+/// [`DbBuilder`]: super::tree::DbBuilder
+pub(super) const DB_VERSION_KEY: &str = "db_version";
 
+/// Checks that the DB doesn't need migrations without modifying it
+pub fn check(db: &Arc<rocksdb::DB>) -> Result<()> {
+    let state = Tree::<columns::NodeStates>::new(db)?;
+    let version = load_version(&state)?;
+    if version != CURRENT_VERSION {
+        return Err(MigrationsError::IncompatibleDbVersion).with_context(|| {
+            format!("Found version: {version:?}. Expected version: {CURRENT_VERSION:?}")
+        });
+    }
+    Ok(())
+}
+
+pub async fn apply(db: &Arc<rocksdb::DB>) -> Result<()> {
     let mut migrations = Migrations::default();
     v2_0_7::register(&mut migrations).context("Failed to register v2.0.7")?;
     v2_0_8::register(&mut migrations).context("Failed to register v2.0.8")?;
 
     let state = Tree::<columns::NodeStates>::new(db)?;
-    let is_empty = state
-        .iterator(rocksdb::IteratorMode::Start)
-        .next()
-        .transpose()?
-        .is_none();
-    if is_empty {
-        tracing::info!("starting with empty db");
-        state
-            .insert(DB_VERSION_KEY, CURRENT_VERSION)
-            .context("Failed to save new DB version")?;
-        return Ok(());
-    }
-
     loop {
-        let version: [u8; 3] = state
-            .get(DB_VERSION_KEY)?
-            .map(|v| v.to_vec())
-            .ok_or(MigrationsError::VersionNotFound)?
-            .try_into()
-            .map_err(|_| MigrationsError::InvalidDbVersion)?;
-
+        let version = load_version(&state)?;
         match version.cmp(&CURRENT_VERSION) {
             std::cmp::Ordering::Less => {}
             std::cmp::Ordering::Equal => {
@@ -101,6 +97,16 @@ impl Migrations {
     }
 }
 
+fn load_version(state: &Tree<columns::NodeStates>) -> Result<Semver> {
+    let version = state
+        .get(DB_VERSION_KEY)?
+        .map(|v| v.to_vec())
+        .ok_or(MigrationsError::VersionNotFound)?
+        .try_into()
+        .map_err(|_| MigrationsError::InvalidDbVersion)?;
+    Ok(version)
+}
+
 type Semver = [u8; 3];
 type Migration = Box<dyn Fn(Arc<rocksdb::DB>) -> BoxFuture<'static, Result<Semver>>>;
 
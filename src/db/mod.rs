@@ -1,28 +1,39 @@
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{Context, Result};
 use rlimit::Resource;
 use rocksdb::perf::MemoryUsageStats;
 use rocksdb::DBCompressionType;
 
+pub use self::audit_log_storage::*;
 pub use self::block_connection_storage::*;
 pub use self::block_handle::*;
 pub use self::block_handle_storage::*;
 pub use self::block_meta::*;
+pub(crate) use self::block_storage::PinnedBlockData;
 use self::block_storage::*;
-use self::node_state_storage::*;
+pub use self::key_blocks_index::*;
+pub use self::message_index_storage::*;
+pub use self::node_state_storage::*;
 pub use self::runtime_storage::*;
 use self::shard_state_storage::*;
 use self::tree::*;
+#[cfg(test)]
+pub(crate) use self::tree::{DbBuilder, DbCaches};
+use crate::config::DbOptions;
 use crate::utils::*;
 
+mod audit_log_storage;
 mod block_connection_storage;
 mod block_handle;
 mod block_handle_storage;
 mod block_meta;
 mod block_storage;
 mod columns;
+mod key_blocks_index;
+mod message_index_storage;
 mod migrations;
 mod node_state_storage;
 mod persistent_state_keeper;
@@ -32,12 +43,15 @@ mod tree;
 
 pub struct Db {
     file_db_path: PathBuf,
+    temp_files_path: PathBuf,
     runtime_storage: Arc<RuntimeStorage>,
     block_handle_storage: Arc<BlockHandleStorage>,
     block_storage: Arc<BlockStorage>,
     shard_state_storage: ShardStateStorage,
     block_connection_storage: BlockConnectionStorage,
     node_state_storage: NodeStateStorage,
+    audit_log_storage: AuditLogStorage,
+    message_index_storage: MessageIndexStorage,
 
     db: Arc<rocksdb::DB>,
     caches: DbCaches,
@@ -51,16 +65,87 @@ pub struct RocksdbStats {
     pub compressed_block_cache_pined_usage: usize,
 }
 
+/// Temp files dir of the DB opened with [`Db::open_existing`].
+/// Nothing is written there
+const DEFAULT_TEMP_FILES_DIR: &str = "downloads";
+
+/// How the DB is opened
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum DbAccess {
+    /// The DB is created if missing, migrated and repaired if configured.
+    /// Stale temp files are removed and interrupted operations are resumed
+    Node,
+    /// The existing DB is opened as is
+    Existing,
+    /// The existing DB is opened as is in read-only mode
+    ReadOnly,
+}
+
 impl Db {
-    pub async fn new<PS, PF>(
+    /// NOTE: relative `temp_files_path` is resolved against `file_db_path`
+    pub async fn new<PS, PF, PT>(
+        rocksdb_path: PS,
+        file_db_path: PF,
+        temp_files_path: PT,
+        mem_limit: usize,
+        options: DbOptions,
+    ) -> Result<Arc<Self>>
+    where
+        PS: AsRef<Path>,
+        PF: AsRef<Path>,
+        PT: AsRef<Path>,
+    {
+        Self::open(
+            rocksdb_path.as_ref(),
+            file_db_path.as_ref(),
+            temp_files_path.as_ref(),
+            mem_limit,
+            options,
+            DbAccess::Node,
+        )
+        .await
+    }
+
+    /// Opens the existing DB without creating, migrating or repairing anything.
+    /// Temp files are left as is, interrupted operations are not resumed.
+    ///
+    /// NOTE: the DB is not locked in read-only mode, so it could be opened
+    /// while the node is running
+    pub async fn open_existing<PS, PF>(
         rocksdb_path: PS,
         file_db_path: PF,
         mem_limit: usize,
+        read_only: bool,
     ) -> Result<Arc<Self>>
     where
         PS: AsRef<Path>,
         PF: AsRef<Path>,
     {
+        let access = match read_only {
+            true => DbAccess::ReadOnly,
+            false => DbAccess::Existing,
+        };
+        Self::open(
+            rocksdb_path.as_ref(),
+            file_db_path.as_ref(),
+            Path::new(DEFAULT_TEMP_FILES_DIR),
+            mem_limit,
+            Default::default(),
+            access,
+        )
+        .await
+    }
+
+    async fn open(
+        rocksdb_path: &Path,
+        file_db_path: &Path,
+        temp_files_path: &Path,
+        mem_limit: usize,
+        options: DbOptions,
+        access: DbAccess,
+    ) -> Result<Arc<Self>> {
+        let is_node = access == DbAccess::Node;
+
         let limit = match fdlimit::raise_fd_limit() {
             // New fd limit
             Some(limit) => limit,
@@ -70,7 +155,7 @@ impl Db {
 
         let caches = DbCaches::with_capacity(mem_limit)?;
 
-        let db = DbBuilder::new(rocksdb_path, &caches)
+        let (db, recovery_report) = DbBuilder::new(rocksdb_path, &caches)
             .options(|opts, _| {
                 opts.set_level_compaction_dynamic_level_bytes(true);
 
@@ -87,8 +172,8 @@ impl Db {
                 opts.set_recycle_log_file_num(2);
 
                 // cf
-                opts.create_if_missing(true);
-                opts.create_missing_column_families(true);
+                opts.create_if_missing(is_node);
+                opts.create_missing_column_families(is_node);
 
                 // cpu
                 opts.set_max_background_jobs(std::cmp::max((num_cpus::get() as i32) / 2, 2));
@@ -109,29 +194,80 @@ impl Db {
             .column::<columns::Next1>()
             .column::<columns::Next2>()
             .column::<columns::PackageEntries>()
-            .build()
+            .column::<columns::AuditLog>()
+            .column::<columns::MessageIndex>()
+            .schema_version::<columns::NodeStates>(
+                migrations::DB_VERSION_KEY,
+                migrations::CURRENT_VERSION,
+            )
+            .recover_corrupt_columns(options.recover_corrupt_cfs && is_node)
+            .read_only(access == DbAccess::ReadOnly)
+            .build_with_report()
             .context("Failed building db")?;
 
-        migrations::apply(&db)
-            .await
-            .context("Failed to apply migrations")?;
+        if is_node {
+            migrations::apply(&db)
+                .await
+                .context("Failed to apply migrations")?;
+        } else {
+            migrations::check(&db).context("DB must be migrated by the node first")?;
+        }
 
         let block_handle_storage = Arc::new(BlockHandleStorage::with_db(&db)?);
         let runtime_storage = Arc::new(RuntimeStorage::new(&block_handle_storage));
-        let block_storage = Arc::new(BlockStorage::with_db(&db, &block_handle_storage)?);
-        let shard_state_storage =
-            ShardStateStorage::with_db(&db, &block_handle_storage, &block_storage, &file_db_path)
-                .await?;
+        let block_storage = Arc::new(BlockStorage::with_db(
+            &db,
+            &block_handle_storage,
+            options.archive_slice_size,
+        )?);
+        let temp_files_path = if is_node {
+            prepare_temp_files_dir(
+                file_db_path,
+                temp_files_path,
+                Duration::from_secs(options.temp_files_ttl_sec),
+            )
+            .await
+            .context("Failed to prepare temp files dir")?
+        } else {
+            file_db_path.join(temp_files_path)
+        };
+
+        let shard_state_storage = ShardStateStorage::with_db(
+            &db,
+            &block_handle_storage,
+            &block_storage,
+            &temp_files_path,
+            Duration::from_secs(options.store_state_wait_timeout_sec),
+            is_node,
+        )
+        .await?;
         let node_state_storage = NodeStateStorage::with_db(&db)?;
-        let block_connection_storage = BlockConnectionStorage::with_db(&db)?;
+        let audit_log_storage = AuditLogStorage::with_db(&db)?;
+        let message_index_storage = MessageIndexStorage::with_db(&db)?;
+        let block_connection_storage =
+            BlockConnectionStorage::with_db(&db, block_handle_storage.key_blocks_index())?;
+
+        if let Some(report) = recovery_report {
+            if report
+                .cleared_columns
+                .contains(&columns::MessageIndex::NAME)
+            {
+                // NOTE: index is rebuilt by the engine in background
+                tracing::warn!("message index was cleared");
+                node_state_storage.store_message_index_rebuild_required(true)?;
+            }
+        }
 
         Ok(Arc::new(Self {
-            file_db_path: file_db_path.as_ref().to_path_buf(),
+            file_db_path: file_db_path.to_path_buf(),
+            temp_files_path,
             block_handle_storage,
             block_storage,
             shard_state_storage,
             block_connection_storage,
             node_state_storage,
+            audit_log_storage,
+            message_index_storage,
             runtime_storage,
             db,
             caches,
@@ -143,6 +279,11 @@ impl Db {
         &self.file_db_path
     }
 
+    #[inline(always)]
+    pub fn temp_files_path(&self) -> &Path {
+        &self.temp_files_path
+    }
+
     #[inline(always)]
     pub fn runtime_storage(&self) -> &RuntimeStorage {
         self.runtime_storage.as_ref()
@@ -173,12 +314,76 @@ impl Db {
         &self.node_state_storage
     }
 
+    #[inline(always)]
+    pub fn audit_log(&self) -> &AuditLogStorage {
+        &self.audit_log_storage
+    }
+
+    #[inline(always)]
+    pub fn message_index(&self) -> &MessageIndexStorage {
+        &self.message_index_storage
+    }
+
     pub fn metrics(&self) -> DbMetrics {
         DbMetrics {
             shard_state_storage: self.shard_state_storage.metrics(),
         }
     }
 
+    /// Names of all column families
+    pub const COLUMNS: [&'static str; 13] = [
+        columns::Archives::NAME,
+        columns::BlockHandles::NAME,
+        columns::KeyBlocks::NAME,
+        columns::ShardStates::NAME,
+        columns::Cells::NAME,
+        columns::NodeStates::NAME,
+        columns::Prev1::NAME,
+        columns::Prev2::NAME,
+        columns::Next1::NAME,
+        columns::Next2::NAME,
+        columns::PackageEntries::NAME,
+        columns::AuditLog::NAME,
+        columns::MessageIndex::NAME,
+    ];
+
+    /// Triggers manual compaction of the whole key range of the specified column families.
+    ///
+    /// NOTE: blocks the current thread until compaction is finished
+    pub fn compact(&self, columns: &[&'static str]) -> Result<Vec<ColumnCompactionStats>> {
+        const SST_FILES_SIZE: &str = "rocksdb.total-sst-files-size";
+
+        let mut result = Vec::with_capacity(columns.len());
+        for &column in columns {
+            let cf = self
+                .db
+                .cf_handle(column)
+                .with_context(|| format!("No cf for {column}"))?;
+
+            let size_before = self
+                .db
+                .property_int_value_cf(&cf, SST_FILES_SIZE)?
+                .unwrap_or_default();
+
+            tracing::info!(column, size_before, "compacting column family");
+            self.db.compact_range_cf(&cf, None::<&[u8]>, None::<&[u8]>);
+
+            let size_after = self
+                .db
+                .property_int_value_cf(&cf, SST_FILES_SIZE)?
+                .unwrap_or_default();
+            tracing::info!(column, size_before, size_after, "compacted column family");
+
+            result.push(ColumnCompactionStats {
+                column,
+                size_before,
+                size_after,
+            });
+        }
+
+        Ok(result)
+    }
+
     pub fn get_memory_usage_stats(&self) -> Result<RocksdbStats> {
         let caches = &[
             &self.caches.block_cache,
@@ -204,7 +409,158 @@ impl Db {
     }
 }
 
+/// Creates temp files dir and removes all leftovers of interrupted
+/// state imports and archive downloads (e.g. when the DB was copied
+/// from another host during the import).
+///
+/// Temp files dir must be inside the file db dir. Only files with the names
+/// produced by the node are removed, all other entries are left as is.
+///
+/// NOTE: files of the state downloads marked to be kept are removed only after `ttl`
+async fn prepare_temp_files_dir(
+    file_db_path: &Path,
+    temp_files_path: &Path,
+    ttl: Duration,
+) -> Result<PathBuf> {
+    tokio::fs::create_dir_all(file_db_path).await?;
+    let file_db_root = tokio::fs::canonicalize(file_db_path).await?;
+
+    // Check the path before creating anything outside the file db
+    let path = file_db_path.join(temp_files_path);
+    let escapes = temp_files_path
+        .components()
+        .any(|component| matches!(component, std::path::Component::ParentDir))
+        || !(path.starts_with(file_db_path) || path.starts_with(&file_db_root));
+    if escapes {
+        return Err(DbError::TempFilesPathOutsideFileDb.into());
+    }
+
+    tokio::fs::create_dir_all(&path).await?;
+
+    // NOTE: symlinks could still point outside the file db
+    let temp_files_root = tokio::fs::canonicalize(&path).await?;
+    if temp_files_root == file_db_root {
+        return Err(DbError::TempFilesPathIsFileDb.into());
+    }
+    if !temp_files_root.starts_with(&file_db_root) {
+        return Err(DbError::TempFilesPathOutsideFileDb.into());
+    }
+
+    // NOTE: there are no downloads in progress during the startup
+    let stats = sweep_temp_files(&path, &[], ttl).await?;
+    let mut removed = stats.removed_files;
+
+    // Archive downloads were previously stored in the root of the file db
+    removed += remove_dir_files(file_db_path, |name| name.starts_with(TEMP_ARCHIVE_PREFIX)).await?;
+
+    if removed > 0 {
+        tracing::warn!(
+            path = %path.display(),
+            removed,
+            reclaimed_bytes = stats.reclaimed_bytes,
+            "removed stale temp files"
+        );
+    }
+
+    Ok(path)
+}
+
+/// Removes files (but not directories) with the matching names
+async fn remove_dir_files<F>(path: &Path, mut filter: F) -> Result<usize>
+where
+    F: FnMut(&str) -> bool,
+{
+    let mut removed = 0;
+
+    let mut entries = tokio::fs::read_dir(path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let file_name = entry.file_name();
+        if !filter(&file_name.to_string_lossy()) {
+            continue;
+        }
+
+        if !entry.file_type().await?.is_file() {
+            continue;
+        }
+
+        let entry_path = entry.path();
+        tokio::fs::remove_file(&entry_path)
+            .await
+            .with_context(|| format!("Failed to remove {}", entry_path.display()))?;
+
+        removed += 1;
+    }
+
+    Ok(removed)
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct DbMetrics {
     pub shard_state_storage: ShardStateStorageMetrics,
 }
+
+#[derive(Debug, Copy, Clone)]
+pub struct ColumnCompactionStats {
+    pub column: &'static str,
+    /// Total size of SST files before compaction in bytes
+    pub size_before: u64,
+    /// Total size of SST files after compaction in bytes
+    pub size_after: u64,
+}
+
+/// Name prefix of the temp files of large archive downloads
+pub(crate) const TEMP_ARCHIVE_PREFIX: &str = "temp_archive";
+
+#[derive(thiserror::Error, Debug)]
+enum DbError {
+    #[error("Temp files dir must be inside the file db dir")]
+    TempFilesPathOutsideFileDb,
+    #[error("Temp files dir must differ from the file db dir")]
+    TempFilesPathIsFileDb,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::*;
+
+    #[tokio::test]
+    async fn temp_files_dir_inside_file_db() {
+        let root = TempDir::new("temp_files_dir");
+        let file_db_path = root.join("file");
+        let ttl = Duration::from_secs(3600);
+
+        // Paths outside the file db are rejected before anything is created
+        for temp_files_path in [root.join("outside"), "../outside".into(), ".".into()] {
+            assert!(prepare_temp_files_dir(&file_db_path, &temp_files_path, ttl)
+                .await
+                .is_err());
+        }
+        assert!(!root.join("outside").exists());
+
+        let path = prepare_temp_files_dir(&file_db_path, Path::new("downloads"), ttl)
+            .await
+            .unwrap();
+        assert_eq!(path, file_db_path.join("downloads"));
+
+        // Only own files are removed
+        for name in ["temp_archive0001", "state_cells_1", "user_file"] {
+            tokio::fs::write(path.join(name), [0; 1]).await.unwrap();
+        }
+        tokio::fs::create_dir(path.join("state_cells_dir"))
+            .await
+            .unwrap();
+        tokio::fs::write(file_db_path.join("temp_archive0002"), [0; 1])
+            .await
+            .unwrap();
+
+        prepare_temp_files_dir(&file_db_path, &file_db_path.join("downloads"), ttl)
+            .await
+            .unwrap();
+        assert!(!path.join("temp_archive0001").exists());
+        assert!(!path.join("state_cells_1").exists());
+        assert!(!file_db_path.join("temp_archive0002").exists());
+        assert!(path.join("user_file").exists());
+        assert!(path.join("state_cells_dir").exists());
+    }
+}
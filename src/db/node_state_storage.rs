@@ -1,9 +1,10 @@
 use std::sync::Arc;
 
 use anyhow::Result;
-use parking_lot::Mutex;
+use arc_swap::ArcSwapOption;
 
 use super::{columns, read_block_id_le, write_block_id_le, StoredValue, Tree};
+use crate::utils::TopBlocks;
 
 pub struct NodeStateStorage {
     db: Tree<columns::NodeStates>,
@@ -22,6 +23,20 @@ impl NodeStateStorage {
         })
     }
 
+    /// Stores the zero state of the network this DB belongs to
+    pub fn store_zero_state_id(&self, id: &ton_block::BlockIdExt) -> Result<()> {
+        self.db.insert(ZERO_STATE_ID, write_block_id_le(id))
+    }
+
+    pub fn load_zero_state_id(&self) -> Result<Option<ton_block::BlockIdExt>> {
+        Ok(match self.db.get(ZERO_STATE_ID)? {
+            Some(data) => {
+                Some(read_block_id_le(&data).ok_or(NodeStateStorageError::InvalidBlockId)?)
+            }
+            None => None,
+        })
+    }
+
     pub fn store_historical_sync_start(&self, id: &ton_block::BlockIdExt) -> Result<()> {
         self.db.insert(HISTORICAL_SYNC_LOW, id.to_vec())
     }
@@ -33,16 +48,144 @@ impl NodeStateStorage {
         })
     }
 
+    /// Stores the highest block up to which the historical sync was completed
+    pub fn store_historical_sync_completed(&self, id: &ton_block::BlockIdExt) -> Result<()> {
+        self.db.insert(HISTORICAL_SYNC_COMPLETED, id.to_vec())
+    }
+
+    pub fn load_historical_sync_completed(&self) -> Result<Option<ton_block::BlockIdExt>> {
+        Ok(match self.db.get(HISTORICAL_SYNC_COMPLETED)? {
+            Some(data) => Some(ton_block::BlockIdExt::from_slice(data.as_ref())?),
+            None => None,
+        })
+    }
+
+    /// Stores the position of the last delivered historical sync notifications
+    pub fn store_historical_sync_notified(&self, top_blocks: &TopBlocks) -> Result<()> {
+        self.db
+            .insert(HISTORICAL_SYNC_NOTIFIED, top_blocks.to_vec())
+    }
+
+    pub fn load_historical_sync_notified(&self) -> Result<Option<TopBlocks>> {
+        Ok(match self.db.get(HISTORICAL_SYNC_NOTIFIED)? {
+            Some(data) => Some(TopBlocks::from_slice(data.as_ref())?),
+            None => None,
+        })
+    }
+
+    /// Stores the position of the last delivered subscriber notifications
+    pub fn store_notified_top_blocks(&self, top_blocks: &TopBlocks) -> Result<()> {
+        self.db.insert(NOTIFIED_TOP_BLOCKS, top_blocks.to_vec())
+    }
+
+    pub fn load_notified_top_blocks(&self) -> Result<Option<TopBlocks>> {
+        Ok(match self.db.get(NOTIFIED_TOP_BLOCKS)? {
+            Some(data) => Some(TopBlocks::from_slice(data.as_ref())?),
+            None => None,
+        })
+    }
+
     pub fn store_historical_sync_end(&self, id: &ton_block::BlockIdExt) -> Result<()> {
         self.db.insert(HISTORICAL_SYNC_HIGH, id.to_vec())
     }
 
     pub fn load_historical_sync_end(&self) -> Result<ton_block::BlockIdExt> {
-        let data = self
-            .db
-            .get(HISTORICAL_SYNC_HIGH)?
-            .ok_or(NodeStateStorageError::HighBlockNotFound)?;
-        ton_block::BlockIdExt::from_slice(data.as_ref())
+        self.find_historical_sync_end()?
+            .ok_or_else(|| NodeStateStorageError::HighBlockNotFound.into())
+    }
+
+    /// Same as [`NodeStateStorage::load_historical_sync_end`], but the value
+    /// is absent for the DBs which were not initialized with the cold boot
+    pub fn find_historical_sync_end(&self) -> Result<Option<ton_block::BlockIdExt>> {
+        Ok(match self.db.get(HISTORICAL_SYNC_HIGH)? {
+            Some(data) => Some(ton_block::BlockIdExt::from_slice(data.as_ref())?),
+            None => None,
+        })
+    }
+
+    /// Stores the block from which the subscribers were told the indexing started
+    pub fn store_indexing_started_at(&self, id: &ton_block::BlockIdExt) -> Result<()> {
+        self.db.insert(INDEXING_STARTED_AT, id.to_vec())
+    }
+
+    pub fn load_indexing_started_at(&self) -> Result<Option<ton_block::BlockIdExt>> {
+        Ok(match self.db.get(INDEXING_STARTED_AT)? {
+            Some(data) => Some(ton_block::BlockIdExt::from_slice(data.as_ref())?),
+            None => None,
+        })
+    }
+
+    /// Stores the masterchain seqno from which the indexing was started
+    pub fn store_start_from_seqno(&self, seqno: u32) -> Result<()> {
+        self.db.insert(START_FROM_SEQNO, seqno.to_le_bytes())
+    }
+
+    pub fn load_start_from_seqno(&self) -> Result<Option<u32>> {
+        Ok(match self.db.get(START_FROM_SEQNO)? {
+            Some(data) if data.len() >= 4 => {
+                Some(u32::from_le_bytes(data[..4].try_into().unwrap()))
+            }
+            _ => None,
+        })
+    }
+
+    /// Stores the masterchain seqno from which the applied blocks are delivered to subscribers
+    pub fn store_notify_from_seqno(&self, seqno: u32) -> Result<()> {
+        self.db.insert(NOTIFY_FROM_SEQNO, seqno.to_le_bytes())
+    }
+
+    pub fn load_notify_from_seqno(&self) -> Result<Option<u32>> {
+        Ok(match self.db.get(NOTIFY_FROM_SEQNO)? {
+            Some(data) if data.len() >= 4 => {
+                Some(u32::from_le_bytes(data[..4].try_into().unwrap()))
+            }
+            _ => None,
+        })
+    }
+
+    /// Stores hard fork blocks which were accepted and already reported
+    pub fn store_accepted_hard_forks<'a, I>(&self, ids: I) -> Result<()>
+    where
+        I: IntoIterator<Item = &'a ton_block::BlockIdExt>,
+    {
+        let mut data = Vec::new();
+        for id in ids {
+            id.serialize(&mut data);
+        }
+        self.db.insert(ACCEPTED_HARD_FORKS, data)
+    }
+
+    pub fn load_accepted_hard_forks(&self) -> Result<Vec<ton_block::BlockIdExt>> {
+        let mut ids = Vec::new();
+        if let Some(data) = self.db.get(ACCEPTED_HARD_FORKS)? {
+            let mut reader = data.as_ref();
+            while !reader.is_empty() {
+                ids.push(ton_block::BlockIdExt::deserialize(&mut reader)?);
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Marks the message index as incomplete (e.g. after it was cleared by the repair)
+    pub fn store_message_index_rebuild_required(&self, required: bool) -> Result<()> {
+        if required {
+            self.db.insert(MESSAGE_INDEX_REBUILD, b"")
+        } else {
+            self.db.remove(MESSAGE_INDEX_REBUILD)
+        }
+    }
+
+    pub fn load_message_index_rebuild_required(&self) -> Result<bool> {
+        Ok(self.db.get(MESSAGE_INDEX_REBUILD)?.is_some())
+    }
+
+    /// Marks that masterchain refs of the orphaned shard blocks were already backfilled
+    pub fn store_mc_refs_backfilled(&self) -> Result<()> {
+        self.db.insert(MC_REFS_BACKFILLED, b"")
+    }
+
+    pub fn load_mc_refs_backfilled(&self) -> Result<bool> {
+        Ok(self.db.get(MC_REFS_BACKFILLED)?.is_some())
     }
 
     #[allow(unused)]
@@ -69,6 +212,12 @@ impl NodeStateStorage {
         self.load_block_id(&self.last_mc_block_id)
     }
 
+    /// Same as [`NodeStateStorage::load_last_mc_block_id`], but returns `None`
+    /// if the pointer was not stored yet
+    pub fn find_last_mc_block_id(&self) -> Result<Option<ton_block::BlockIdExt>> {
+        self.find_block_id(&self.last_mc_block_id)
+    }
+
     pub fn store_init_mc_block_id(&self, id: &ton_block::BlockIdExt) -> Result<()> {
         self.store_block_id(&self.init_mc_block_id, id)
     }
@@ -85,29 +234,45 @@ impl NodeStateStorage {
         self.load_block_id(&self.shards_client_mc_block_id)
     }
 
+    /// Same as [`NodeStateStorage::load_shards_client_mc_block_id`], but returns `None`
+    /// if the pointer was not stored yet
+    pub fn find_shards_client_mc_block_id(&self) -> Result<Option<ton_block::BlockIdExt>> {
+        self.find_block_id(&self.shards_client_mc_block_id)
+    }
+
     #[inline(always)]
     fn store_block_id(
         &self,
         (cache, key): &BlockIdCache,
         block_id: &ton_block::BlockIdExt,
     ) -> Result<()> {
+        // NOTE: the cache is updated without locks, so concurrent readers
+        // are never blocked by the db write
         self.db.insert(key, write_block_id_le(block_id))?;
-        *cache.lock() = Some(block_id.clone());
+        cache.store(Some(Arc::new(block_id.clone())));
         Ok(())
     }
 
     #[inline(always)]
-    fn load_block_id(&self, (cache, key): &BlockIdCache) -> Result<ton_block::BlockIdExt> {
-        if let Some(a) = &*cache.lock() {
-            return Ok(a.clone());
+    fn load_block_id(&self, cache: &BlockIdCache) -> Result<ton_block::BlockIdExt> {
+        match self.find_block_id(cache)? {
+            Some(block_id) => Ok(block_id),
+            None => Err(NodeStateStorageError::ParamNotFound.into()),
+        }
+    }
+
+    #[inline(always)]
+    fn find_block_id(&self, (cache, key): &BlockIdCache) -> Result<Option<ton_block::BlockIdExt>> {
+        if let Some(cached) = &*cache.load() {
+            return Ok(Some(cached.as_ref().clone()));
         }
 
         let value = match self.db.get(key)? {
             Some(data) => read_block_id_le(&data).ok_or(NodeStateStorageError::InvalidBlockId)?,
-            None => return Err(NodeStateStorageError::ParamNotFound.into()),
+            None => return Ok(None),
         };
-        *cache.lock() = Some(value.clone());
-        Ok(value)
+        cache.store(Some(Arc::new(value.clone())));
+        Ok(Some(value))
     }
 }
 
@@ -121,13 +286,127 @@ pub enum NodeStateStorageError {
     InvalidBlockId,
 }
 
-type BlockIdCache = (Mutex<Option<ton_block::BlockIdExt>>, &'static [u8]);
+type BlockIdCache = (ArcSwapOption<ton_block::BlockIdExt>, &'static [u8]);
 
 const HISTORICAL_SYNC_LOW: &[u8] = b"background_sync_low";
 const HISTORICAL_SYNC_HIGH: &[u8] = b"background_sync_high";
+const HISTORICAL_SYNC_COMPLETED: &[u8] = b"background_sync_completed";
+const HISTORICAL_SYNC_NOTIFIED: &[u8] = b"background_sync_notified";
 
 const LAST_UPLOADED_ARCHIVE: &[u8] = b"last_uploaded_archive";
+const START_FROM_SEQNO: &[u8] = b"start_from_seqno";
+const NOTIFY_FROM_SEQNO: &[u8] = b"notify_from_seqno";
+const INDEXING_STARTED_AT: &[u8] = b"indexing_started_at";
+const NOTIFIED_TOP_BLOCKS: &[u8] = b"notified_top_blocks";
+const MESSAGE_INDEX_REBUILD: &[u8] = b"message_index_rebuild";
+const ACCEPTED_HARD_FORKS: &[u8] = b"accepted_hard_forks";
+const MC_REFS_BACKFILLED: &[u8] = b"mc_refs_backfilled";
 
+const ZERO_STATE_ID: &[u8] = b"ZeroStateId";
 const LAST_MC_BLOCK_ID: &[u8] = b"LastMcBlockId";
 const INIT_MC_BLOCK_ID: &[u8] = b"InitMcBlockId";
 const SHARDS_CLIENT_MC_BLOCK_ID: &[u8] = b"ShardsClientMcBlockId";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::tree::DbCaches;
+    use crate::db::{BlockHandleStorage, BlockMetaData};
+    use crate::test_helpers::*;
+
+    #[test]
+    fn parallel_shard_blocks_apply() {
+        const SHARD_BLOCK_COUNT: u32 = 256;
+
+        let dir = TempDir::new("node_state_storage");
+        let caches = DbCaches::with_capacity(0).unwrap();
+        let db = test_db_builder(&dir, &caches)
+            .column::<columns::BlockHandles>()
+            .column::<columns::KeyBlocks>()
+            .column::<columns::NodeStates>()
+            .build()
+            .unwrap();
+
+        let handles = Arc::new(BlockHandleStorage::with_db(&db).unwrap());
+        let node_state = Arc::new(NodeStateStorage::with_db(&db).unwrap());
+
+        node_state
+            .store_shards_client_mc_block_id(&mc_block_id(0))
+            .unwrap();
+
+        // Apply synthetic shard blocks while reading and moving the shard client pointer
+        let threads = (0..SHARD_BLOCK_COUNT)
+            .map(|i| {
+                let handles = handles.clone();
+                let node_state = node_state.clone();
+                std::thread::spawn(move || {
+                    let shard_id = ton_block::ShardIdent::with_tagged_prefix(
+                        0,
+                        ((i as u64) << 56) | (1 << 55),
+                    )
+                    .unwrap();
+                    let block_id = block_id(shard_id, i + 1);
+                    let meta_data = BlockMetaData {
+                        is_key_block: false,
+                        gen_utime: i,
+                        mc_ref_seqno: Some(i + 1),
+                    };
+
+                    let (handle, _) = handles.create_or_load_handle(&block_id, meta_data)?;
+                    if handle.meta().set_is_applied() {
+                        handles.store_handle(&handle)?;
+                    }
+
+                    let last = node_state.load_shards_client_mc_block_id()?;
+                    assert!(last.seq_no <= SHARD_BLOCK_COUNT);
+
+                    node_state.store_shards_client_mc_block_id(&mc_block_id(i + 1))?;
+                    Ok::<_, anyhow::Error>(block_id)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for thread in threads {
+            let block_id = thread.join().unwrap().unwrap();
+            let handle = handles.load_handle(&block_id).unwrap().unwrap();
+            assert!(handle.meta().is_applied());
+        }
+
+        // Cached pointer must be the same as the stored one
+        node_state
+            .store_shards_client_mc_block_id(&mc_block_id(SHARD_BLOCK_COUNT + 1))
+            .unwrap();
+        let cached = node_state.load_shards_client_mc_block_id().unwrap();
+        let stored = NodeStateStorage::with_db(&db)
+            .unwrap()
+            .load_shards_client_mc_block_id()
+            .unwrap();
+        assert_eq!(cached, stored);
+    }
+
+    #[test]
+    fn missing_and_invalid_pointers() {
+        let dir = TempDir::new("node_state_pointers");
+        let caches = DbCaches::with_capacity(0).unwrap();
+        let db = test_db_builder(&dir, &caches)
+            .column::<columns::NodeStates>()
+            .build()
+            .unwrap();
+
+        let node_state = NodeStateStorage::with_db(&db).unwrap();
+        assert_eq!(node_state.find_last_mc_block_id().unwrap(), None);
+        assert!(node_state.load_last_mc_block_id().is_err());
+
+        // Invalid value is an error rather than a missing pointer
+        node_state.db.insert(LAST_MC_BLOCK_ID, [1, 2, 3]).unwrap();
+        assert!(node_state.find_last_mc_block_id().is_err());
+
+        node_state
+            .store_shards_client_mc_block_id(&mc_block_id(10))
+            .unwrap();
+        assert_eq!(
+            node_state.find_shards_client_mc_block_id().unwrap(),
+            Some(mc_block_id(10))
+        );
+    }
+}
@@ -130,6 +130,62 @@ impl CellStorage {
         Ok(cell)
     }
 
+    /// Loads the cells tree up to the specified depth into the RocksDB block cache
+    /// using batched reads (one `multi_get` per tree level).
+    ///
+    /// Returns the number of prefetched cells
+    pub fn prefetch(&self, root: UInt256, depth: usize) -> Result<usize> {
+        let cf = self.cells.get_cf();
+        let read_config = self.cells.read_config();
+        let db = self.cells.raw_db_handle();
+
+        let mut visited = FxHashSet::default();
+        visited.insert(root);
+
+        let mut level = vec![root];
+        let mut total = 0;
+
+        for current_depth in 0..=depth {
+            if level.is_empty() {
+                break;
+            }
+
+            let values = db.multi_get_cf_opt(
+                level.iter().map(|cell_id| (&cf, cell_id.as_slice())),
+                read_config,
+            );
+            total += level.len();
+
+            // Don't parse references of the last level
+            if current_depth == depth {
+                break;
+            }
+
+            let mut next_level = Vec::with_capacity(level.len() * 2);
+            for value in values {
+                let value = match value? {
+                    Some(value) => value,
+                    None => {
+                        return Err(CellStorageError::CellNotFound)
+                            .with_context(|| format!("Depth: {current_depth}"))
+                    }
+                };
+
+                let (_, references) = StorageCell::deserialize_marker_and_references(&value)?;
+                for reference in references {
+                    let cell_id = reference.hash();
+                    if visited.insert(cell_id) {
+                        next_level.push(cell_id);
+                    }
+                }
+            }
+
+            level = next_level;
+        }
+
+        Ok(total)
+    }
+
     pub async fn sweep_cells(&self, target_marker: u8) -> Result<usize> {
         let total = Arc::new(AtomicUsize::new(0));
         let mut tasks = FuturesUnordered::new();
@@ -1,15 +1,28 @@
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
 use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
 use tokio::fs::File;
-use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt, BufWriter};
 
+use super::parser::BocHeader;
+use crate::db::TEMP_ARCHIVE_PREFIX;
 use crate::utils::MappedFile;
 
+/// Temp files of the state download.
+///
+/// File names are `state_{kind}_{id}`, where `id` is built from the target block id
+/// (see [`FilesContext::files_id`]). The keep marker contains the resume point
+/// of the interrupted download (if any), it is replaced atomically with the
+/// resume file after each saved chunk.
 pub struct FilesContext {
     cells_path: PathBuf,
     cells_file: Option<BufWriter<File>>,
     hashes_path: PathBuf,
+    keep_path: PathBuf,
+    resume_path: PathBuf,
+    resume_point: Option<StateResumePoint>,
 }
 
 impl FilesContext {
@@ -17,44 +30,109 @@ impl FilesContext {
     where
         P: AsRef<Path>,
     {
-        let block_id = format!(
-            "({},{:016x},{})",
-            block_id.shard_id.workchain_id(),
-            block_id.shard_id.shard_prefix_with_tag(),
-            block_id.seq_no
-        );
+        let id = Self::files_id(block_id);
+        let [cells_path, hashes_path, keep_path, resume_path] =
+            [CELLS_PREFIX, HASHES_PREFIX, KEEP_PREFIX, RESUME_PREFIX]
+                .map(|prefix| downloads_dir.as_ref().join(format!("{prefix}{id}")));
+
+        let resume_point = load_resume_point(&keep_path, &cells_path).await;
+
+        let mut cells_file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(resume_point.is_none())
+            .read(true)
+            .open(&cells_path)
+            .await
+            .context("Failed to create cells file")?;
 
-        let cells_path = downloads_dir
-            .as_ref()
-            .join(format!("state_cells_{block_id}"));
-        let hashes_path = downloads_dir
-            .as_ref()
-            .join(format!("state_hashes_{block_id}"));
-
-        let cells_file = Some(BufWriter::new(
-            tokio::fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .truncate(true)
-                .read(true)
-                .open(&cells_path)
+        if let Some(point) = &resume_point {
+            // Remove the incomplete chunk written after the resume point
+            cells_file
+                .set_len(point.cells_file_len)
                 .await
-                .context("Failed to create cells file")?,
-        ));
+                .context("Failed to truncate cells file")?;
+            cells_file.seek(std::io::SeekFrom::End(0)).await?;
+        }
 
         Ok(Self {
             cells_path,
-            cells_file,
+            cells_file: Some(BufWriter::new(cells_file)),
             hashes_path,
+            keep_path,
+            resume_path,
+            resume_point,
         })
     }
 
-    pub async fn clear(self) -> Result<()> {
-        tokio::fs::remove_file(self.cells_path).await?;
-        tokio::fs::remove_file(self.hashes_path).await?;
+    /// Deterministic part of the temp file names for the specified block
+    pub fn files_id(block_id: &ton_block::BlockIdExt) -> String {
+        format!(
+            "{}_{:016x}_{}_{}",
+            block_id.shard_id.workchain_id(),
+            block_id.shard_id.shard_prefix_with_tag(),
+            block_id.seq_no,
+            block_id.root_hash.to_hex_string()
+        )
+    }
+
+    /// Returns the progress of the previous interrupted download
+    pub fn take_resume_point(&mut self) -> Option<StateResumePoint> {
+        self.resume_point.take()
+    }
+
+    /// Syncs the cells file and stores the point from which
+    /// the download could be resumed. Files are kept by the startup sweep
+    /// until the TTL is reached.
+    ///
+    /// NOTE: the point is written to the resume file first and then renamed,
+    /// so the keep marker always contains either the previous or the new point
+    pub async fn save_resume_point(&mut self, point: &StateResumePoint) -> Result<()> {
+        let cells_file = self.cells_file()?;
+        cells_file.flush().await?;
+        // Cells must be on disk before the point which refers to them
+        cells_file.get_ref().sync_data().await?;
+
+        let mut file = File::create(&self.resume_path)
+            .await
+            .context("Failed to create resume file")?;
+        file.write_all(&serde_json::to_vec(point)?).await?;
+        file.sync_all().await?;
+        drop(file);
+
+        tokio::fs::rename(&self.resume_path, &self.keep_path)
+            .await
+            .context("Failed to save resume point")
+    }
+
+    /// Removes all temp files (including partially written ones)
+    pub async fn clear(mut self) -> Result<()> {
+        // Close the file before removing it
+        drop(self.cells_file.take());
+
+        for path in [
+            &self.cells_path,
+            &self.hashes_path,
+            &self.keep_path,
+            &self.resume_path,
+        ] {
+            match tokio::fs::remove_file(path).await {
+                Ok(()) => {}
+                // Hashes file is created only during finalization
+                // and resume files only for resumable downloads
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to remove {}", path.display()))
+                }
+            }
+        }
         Ok(())
     }
 
+    pub fn cells_path(&self) -> &Path {
+        &self.cells_path
+    }
+
     pub fn cells_file(&mut self) -> Result<&mut BufWriter<File>> {
         match &mut self.cells_file {
             Some(file) => Ok(file),
@@ -81,8 +159,237 @@ impl FilesContext {
     }
 }
 
+/// Removes temp files of the downloads which are neither in progress nor marked to be kept,
+/// and all files older than `ttl`.
+///
+/// NOTE: only files with the names produced by the node are removed
+pub async fn sweep_temp_files(
+    dir: &Path,
+    in_progress: &[ton_block::BlockIdExt],
+    ttl: Duration,
+) -> Result<TempFilesSweepStats> {
+    let in_progress = in_progress
+        .iter()
+        .map(FilesContext::files_id)
+        .collect::<Vec<_>>();
+    let now = SystemTime::now();
+    let is_fresh = |metadata: &std::fs::Metadata| {
+        metadata
+            .modified()
+            .ok()
+            .and_then(|modified| now.duration_since(modified).ok())
+            .map(|age| age < ttl)
+            // Files from the future are treated as fresh
+            .unwrap_or(true)
+    };
+
+    let mut stats = TempFilesSweepStats::default();
+
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let metadata = entry.metadata().await?;
+        if !metadata.is_file() {
+            continue;
+        }
+
+        let file_name = entry.file_name();
+        let file_name = file_name.to_string_lossy();
+        let files_id = parse_files_id(&file_name).map(str::to_owned);
+        let keep = match &files_id {
+            Some(id) if in_progress.contains(id) => is_fresh(&metadata),
+            Some(id) => match tokio::fs::metadata(dir.join(format!("{KEEP_PREFIX}{id}"))).await {
+                Ok(marker) => is_fresh(&metadata) && is_fresh(&marker),
+                Err(_) => false,
+            },
+            // Archive downloads are never resumed
+            None if file_name.starts_with(TEMP_ARCHIVE_PREFIX) => false,
+            None => true,
+        };
+        if keep {
+            continue;
+        }
+
+        tokio::fs::remove_file(&path)
+            .await
+            .with_context(|| format!("Failed to remove {}", path.display()))?;
+
+        stats.removed_files += 1;
+        stats.reclaimed_bytes += metadata.len();
+    }
+
+    Ok(stats)
+}
+
+/// Progress of the state download which could be continued
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateResumePoint {
+    /// Position in the state BOC from which the download must be continued
+    pub position: u64,
+    pub cells_read: u64,
+    /// Length of the cells file with all complete chunks
+    pub cells_file_len: u64,
+    pub header: BocHeader,
+    /// CRC of the state BOC up to the `position` (see [`super::parser::ShardStatePacketReader::crc`])
+    #[serde(default)]
+    pub crc: Option<u32>,
+}
+
+async fn load_resume_point(keep_path: &Path, cells_path: &Path) -> Option<StateResumePoint> {
+    let data = tokio::fs::read(keep_path).await.ok()?;
+    if data.is_empty() {
+        return None;
+    }
+
+    let point = match serde_json::from_slice::<StateResumePoint>(&data) {
+        Ok(point) => point,
+        Err(e) => {
+            tracing::warn!(path = %keep_path.display(), "invalid state resume point: {e:?}");
+            return None;
+        }
+    };
+
+    // NOTE: points without CRC were saved by the older versions,
+    // such downloads are restarted to keep the CRC check
+    if point.header.has_crc && point.crc.is_none() {
+        tracing::warn!(path = %keep_path.display(), "state resume point without CRC");
+        return None;
+    }
+
+    match tokio::fs::metadata(cells_path).await {
+        Ok(metadata) if metadata.len() >= point.cells_file_len => Some(point),
+        _ => {
+            tracing::warn!(path = %cells_path.display(), "cells file is shorter than expected");
+            None
+        }
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct TempFilesSweepStats {
+    pub removed_files: usize,
+    pub reclaimed_bytes: u64,
+}
+
+fn parse_files_id(file_name: &str) -> Option<&str> {
+    [CELLS_PREFIX, HASHES_PREFIX, KEEP_PREFIX, RESUME_PREFIX]
+        .into_iter()
+        .find_map(|prefix| file_name.strip_prefix(prefix))
+}
+
+const CELLS_PREFIX: &str = "state_cells_";
+const HASHES_PREFIX: &str = "state_hashes_";
+const KEEP_PREFIX: &str = "state_keep_";
+const RESUME_PREFIX: &str = "state_resume_";
+
 #[derive(thiserror::Error, Debug)]
 enum FilesContextError {
     #[error("Already finalized")]
     AlreadyFinalized,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::*;
+
+    #[tokio::test]
+    async fn sweep_stale_temp_files() {
+        let temp_dir = TempDir::new("temp_files_sweep");
+        let dir = temp_dir.path();
+
+        let stale = FilesContext::new(dir, &mc_block_id(1)).await.unwrap();
+        // Interrupted download with the stored resume point
+        let kept = FilesContext::new(dir, &mc_block_id(2)).await.unwrap();
+        tokio::fs::write(&kept.keep_path, []).await.unwrap();
+        let active = FilesContext::new(dir, &mc_block_id(3)).await.unwrap();
+
+        tokio::fs::write(stale.cells_path(), [0; 100])
+            .await
+            .unwrap();
+        tokio::fs::write(&stale.hashes_path, [0; 10]).await.unwrap();
+        tokio::fs::write(dir.join("temp_archive0001"), [0; 1])
+            .await
+            .unwrap();
+        tokio::fs::write(dir.join("state_cells_(0,8000000000000000,1)"), [])
+            .await
+            .unwrap();
+
+        // Unknown entries are left as is
+        tokio::fs::write(dir.join("unknown"), [0; 1000])
+            .await
+            .unwrap();
+        tokio::fs::create_dir(dir.join("state_cells_dir"))
+            .await
+            .unwrap();
+
+        let exists = |path: &Path| path.exists();
+
+        let ttl = Duration::from_secs(3600);
+        let stats = sweep_temp_files(dir, &[mc_block_id(3)], ttl).await.unwrap();
+        assert_eq!(
+            stats,
+            TempFilesSweepStats {
+                removed_files: 4,
+                reclaimed_bytes: 111,
+            }
+        );
+        assert!(!exists(stale.cells_path()));
+        assert!(!exists(&stale.hashes_path));
+        assert!(exists(kept.cells_path()));
+        assert!(exists(&kept.keep_path));
+        assert!(exists(active.cells_path()));
+
+        // Kept files are removed after the TTL
+        let stats = sweep_temp_files(dir, &[mc_block_id(3)], Duration::ZERO)
+            .await
+            .unwrap();
+        assert_eq!(stats.removed_files, 3);
+        assert!(!exists(kept.cells_path()));
+        assert!(!exists(&kept.keep_path));
+        assert!(!exists(active.cells_path()));
+        assert!(exists(&dir.join("unknown")));
+        assert!(exists(&dir.join("state_cells_dir")));
+    }
+
+    #[tokio::test]
+    async fn resume_point_is_replaced_atomically() {
+        let temp_dir = TempDir::new("state_resume_point");
+        let dir = temp_dir.path();
+
+        let header = BocHeader {
+            root_index: 0,
+            index_included: false,
+            has_crc: true,
+            ref_size: 2,
+            offset_size: 2,
+            cell_count: 100,
+            total_size: 1000,
+        };
+        let point = |position: u64, crc: Option<u32>| StateResumePoint {
+            position,
+            cells_read: position / 10,
+            cells_file_len: 0,
+            header: header.clone(),
+            crc,
+        };
+
+        let mut ctx = FilesContext::new(dir, &mc_block_id(1)).await.unwrap();
+        ctx.save_resume_point(&point(100, Some(1))).await.unwrap();
+        ctx.save_resume_point(&point(200, Some(2))).await.unwrap();
+        assert!(!ctx.resume_path.exists());
+        drop(ctx);
+
+        let mut ctx = FilesContext::new(dir, &mc_block_id(1)).await.unwrap();
+        let loaded = ctx.take_resume_point().unwrap();
+        assert_eq!((loaded.position, loaded.crc), (200, Some(2)));
+
+        // Points without CRC for the BOC with CRC are not resumed
+        ctx.save_resume_point(&point(300, None)).await.unwrap();
+        drop(ctx);
+
+        let mut ctx = FilesContext::new(dir, &mc_block_id(1)).await.unwrap();
+        assert!(ctx.take_resume_point().is_none());
+        ctx.clear().await.unwrap();
+    }
+}
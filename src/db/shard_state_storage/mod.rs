@@ -2,7 +2,7 @@ use std::ops::Deref;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
 use futures_util::stream::FuturesUnordered;
@@ -14,6 +14,7 @@ use ton_types::UInt256;
 
 use self::cell_storage::*;
 use self::files_context::FilesContext;
+pub use self::files_context::{sweep_temp_files, TempFilesSweepStats};
 use self::gc_state_storage::{GcState, GcStateStorage, LastShardBlockKey, Step};
 use self::replace_transaction::ShardStateReplaceTransaction;
 use super::{
@@ -43,20 +44,23 @@ pub struct ShardStateStorage {
     min_ref_mc_state: Arc<MinRefMcState>,
     max_new_mc_cell_count: AtomicUsize,
     max_new_sc_cell_count: AtomicUsize,
+    store_state_operations: OperationsPool<ton_block::BlockIdExt, ()>,
+    store_state_wait_timeout: Duration,
+    #[cfg(test)]
+    cell_write_passes: AtomicUsize,
 }
 
 impl ShardStateStorage {
-    pub async fn with_db<P>(
+    /// NOTE: interrupted GC is finished only if `resume_gc` is set
+    pub async fn with_db(
         db: &Arc<rocksdb::DB>,
         block_handle_storage: &Arc<BlockHandleStorage>,
         block_storage: &Arc<BlockStorage>,
-        file_db_path: P,
-    ) -> Result<Self>
-    where
-        P: AsRef<Path>,
-    {
-        let downloads_dir = prepare_file_db_dir(&file_db_path, "downloads").await?;
-        // let persistent_dir = prepare_file_db_dir(&file_db_path, "persistent").await?;
+        downloads_dir: &Path,
+        store_state_wait_timeout: Duration,
+        resume_gc: bool,
+    ) -> Result<Self> {
+        let downloads_dir = Arc::new(downloads_dir.to_path_buf());
 
         let res = Self {
             shard_states: Tree::new(db)?,
@@ -69,6 +73,10 @@ impl ShardStateStorage {
             min_ref_mc_state: Arc::new(Default::default()),
             max_new_mc_cell_count: AtomicUsize::new(0),
             max_new_sc_cell_count: AtomicUsize::new(0),
+            store_state_operations: OperationsPool::new("store_state_operations"),
+            store_state_wait_timeout,
+            #[cfg(test)]
+            cell_write_passes: AtomicUsize::new(0),
         };
 
         let gc_state = res.gc_state_storage.load()?;
@@ -77,6 +85,10 @@ impl ShardStateStorage {
                 tracing::info!("shard state GC is pending");
                 *res.current_marker.write().await = gc_state.current_marker;
             }
+            Some(_) if !resume_gc => {
+                tracing::info!("shard state GC was interrupted, leaving it as is");
+                *res.current_marker.write().await = gc_state.current_marker;
+            }
             Some(step) => {
                 let target_marker = gc_state.next_marker();
 
@@ -145,6 +157,32 @@ impl ShardStateStorage {
             return Ok(false);
         }
 
+        // Concurrent callers for the same block wait for the first one
+        let mut stored = false;
+        let timeout_ms = self.store_state_wait_timeout.as_millis() as u64;
+        self.store_state_operations
+            .do_or_wait(handle.id(), Some(timeout_ms), async {
+                stored = self.store_state_impl(handle, state).await?;
+                Ok(())
+            })
+            .await?;
+
+        Ok(stored)
+    }
+
+    async fn store_state_impl(
+        &self,
+        handle: &Arc<BlockHandle>,
+        state: &ShardStateStuff,
+    ) -> Result<bool> {
+        // State could have been stored by the operation which has just finished
+        if handle.meta().has_state() {
+            return Ok(false);
+        }
+
+        #[cfg(test)]
+        self.cell_write_passes.fetch_add(1, Ordering::Relaxed);
+
         let block_id = handle.id();
         let cell_id = state.root_cell().repr_hash();
 
@@ -212,21 +250,55 @@ impl ShardStateStorage {
         }
     }
 
+    /// Warms up the cache for the state cells tree up to the specified depth
+    pub async fn prefetch_state(
+        &self,
+        block_id: &ton_block::BlockIdExt,
+        depth: usize,
+    ) -> Result<usize> {
+        let root = match self
+            .shard_states
+            .get((block_id.shard_id, block_id.seq_no).to_vec())?
+        {
+            Some(root) => UInt256::from_be_bytes(&root),
+            None => return Err(ShardStateStorageError::NotFound.into()),
+        };
+
+        let cell_storage = self.cell_storage.clone();
+        tokio::task::spawn_blocking(move || cell_storage.prefetch(root, depth)).await?
+    }
+
     pub async fn begin_replace(
         &'_ self,
         block_id: &ton_block::BlockIdExt,
     ) -> Result<(ShardStateReplaceTransaction<'_>, FilesContext)> {
-        let ctx = FilesContext::new(self.downloads_dir.as_ref(), block_id).await?;
-
-        Ok((
-            ShardStateReplaceTransaction::new(
+        let mut ctx = FilesContext::new(self.downloads_dir.as_ref(), block_id).await?;
+
+        let transaction = match ctx.take_resume_point() {
+            Some(point) => {
+                tracing::info!(
+                    block_id = %block_id.display(),
+                    position = point.position,
+                    cells_read = point.cells_read,
+                    "resuming state download"
+                );
+                ShardStateReplaceTransaction::resume(
+                    &self.shard_states,
+                    &self.cell_storage,
+                    &self.min_ref_mc_state,
+                    PS_MARKER,
+                    point,
+                )
+            }
+            None => ShardStateReplaceTransaction::new(
                 &self.shard_states,
                 &self.cell_storage,
                 &self.min_ref_mc_state,
                 PS_MARKER,
             ),
-            ctx,
-        ))
+        };
+
+        Ok((transaction, ctx))
     }
 
     /// Searches for an edge with the least referenced masterchain block
@@ -648,9 +720,18 @@ impl ShardStateStorage {
     }
 
     fn find_mc_block_id(&self, mc_seq_no: u32) -> Result<Option<ton_block::BlockIdExt>> {
+        self.find_block_id(&ton_block::ShardIdent::masterchain(), mc_seq_no)
+    }
+
+    /// Returns the id of the block with the stored state
+    pub fn find_block_id(
+        &self,
+        shard_id: &ton_block::ShardIdent,
+        seq_no: u32,
+    ) -> Result<Option<ton_block::BlockIdExt>> {
         Ok(self
             .shard_states
-            .get((ton_block::ShardIdent::masterchain(), mc_seq_no).to_vec())?
+            .get((*shard_id, seq_no).to_vec())?
             .and_then(|value| {
                 let value = value.as_ref();
                 if value.len() < 96 {
@@ -661,8 +742,8 @@ impl ShardStateStorage {
                 let file_hash: [u8; 32] = value[64..96].try_into().unwrap();
 
                 Some(ton_block::BlockIdExt {
-                    shard_id: ton_block::ShardIdent::masterchain(),
-                    seq_no: mc_seq_no,
+                    shard_id: *shard_id,
+                    seq_no,
                     root_hash: UInt256::from(root_hash),
                     file_hash: UInt256::from(file_hash),
                 })
@@ -759,15 +840,6 @@ fn make_block_id_bound(shard_ident: &ton_block::ShardIdent, value: u8) -> [u8; 1
     result
 }
 
-async fn prepare_file_db_dir<P: AsRef<Path>>(
-    file_db_path: P,
-    folder: &str,
-) -> Result<Arc<PathBuf>> {
-    let dir = Arc::new(file_db_path.as_ref().join(folder));
-    tokio::fs::create_dir_all(dir.as_ref()).await?;
-    Ok(dir)
-}
-
 #[derive(thiserror::Error, Debug)]
 enum ShardStateStorageError {
     #[error("Not found")]
@@ -775,3 +847,139 @@ enum ShardStateStorageError {
     #[error("Block handle id mismatch")]
     BlockHandleIdMismatch,
 }
+
+#[cfg(test)]
+mod tests {
+    use ton_block::Serializable;
+
+    use super::*;
+    use crate::db::{BlockMetaData, Db};
+    use crate::test_helpers::*;
+
+    /// Synthetic shard state with its block handle
+    fn make_state(db: &Db) -> (Arc<BlockHandle>, Arc<ShardStateStuff>) {
+        let shard_id = ton_block::ShardIdent::with_tagged_prefix(0, ton_block::SHARD_FULL).unwrap();
+        let mut state = ton_block::ShardStateUnsplit::with_ident(shard_id);
+        state.set_seq_no(1);
+        let root = state.serialize().unwrap();
+
+        let block_id = block_id(shard_id, 1);
+        let state =
+            Arc::new(ShardStateStuff::new(block_id.clone(), root, &MinRefMcState::new()).unwrap());
+        let (handle, _) = db
+            .block_handle_storage()
+            .create_or_load_handle(
+                &block_id,
+                BlockMetaData {
+                    is_key_block: false,
+                    gen_utime: 0,
+                    mc_ref_seqno: Some(1),
+                },
+            )
+            .unwrap();
+        (handle, state)
+    }
+
+    #[tokio::test]
+    async fn concurrent_store_state() {
+        let dir = TempDir::new("shard_state_storage");
+        let db = test_db(&dir).await;
+        let (handle, state) = make_state(&db);
+        let block_id = handle.id().clone();
+
+        let tasks = (0..10).map(|_| {
+            let db = db.clone();
+            let handle = handle.clone();
+            let state = state.clone();
+            tokio::spawn(async move {
+                db.shard_state_storage()
+                    .store_state(&handle, &state)
+                    .await
+                    .unwrap()
+            })
+        });
+
+        let stored = futures_util::future::join_all(tasks)
+            .await
+            .into_iter()
+            .map(|result| result.unwrap())
+            .filter(|stored| *stored)
+            .count();
+        assert_eq!(stored, 1);
+        assert!(handle.meta().has_state());
+
+        let storage = db.shard_state_storage();
+        assert_eq!(storage.cell_write_passes.load(Ordering::Acquire), 1);
+        assert!(storage.load_state(&block_id).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn stuck_store_state_times_out_waiters() {
+        let dir = TempDir::new("store_state_timeout");
+        let db = test_db(&dir).await;
+        let (handle, state) = make_state(&db);
+        let storage = db.shard_state_storage();
+
+        // The first store is stuck until the marker lock is released
+        let marker = storage.current_marker.write().await;
+        let leader = tokio::spawn({
+            let db = db.clone();
+            let handle = handle.clone();
+            let state = state.clone();
+            async move { db.shard_state_storage().store_state(&handle, &state).await }
+        });
+        tokio::task::yield_now().await;
+
+        tokio::time::pause();
+        let error = storage.store_state(&handle, &state).await.unwrap_err();
+        assert!(error.to_string().contains("operation timeout"));
+        assert!(!handle.meta().has_state());
+
+        drop(marker);
+        assert!(leader.await.unwrap().unwrap());
+        assert!(handle.meta().has_state());
+    }
+
+    #[tokio::test]
+    async fn cells_are_prefetched_by_levels() {
+        let dir = TempDir::new("cells_prefetch");
+        let db = test_db(&dir).await;
+        let storage = db.shard_state_storage();
+        let cell_storage = &storage.cell_storage;
+
+        let cell = |data: u32, references: &[&ton_types::Cell]| {
+            let mut builder = ton_types::BuilderData::new();
+            builder.append_u32(data).unwrap();
+            for reference in references {
+                builder
+                    .checked_append_reference((*reference).clone())
+                    .unwrap();
+            }
+            builder.into_cell().unwrap()
+        };
+
+        // Levels: [root], [left, right], [shared], [leaf].
+        // The shared cell is referenced from both cells of the previous level
+        let leaf = cell(4, &[]);
+        let shared = cell(3, &[&leaf]);
+        let left = cell(1, &[&shared]);
+        let right = cell(2, &[&shared]);
+        let root = cell(0, &[&left, &right]);
+
+        let mut batch = rocksdb::WriteBatch::default();
+        cell_storage
+            .store_cell(&mut batch, 0, root.clone())
+            .unwrap();
+        storage.shard_states.raw_db_handle().write(batch).unwrap();
+
+        // Each level is prefetched only after the previous one,
+        // and the depth bounds the number of levels
+        let root_hash = root.repr_hash();
+        for (depth, expected) in [(0, 1), (1, 3), (2, 4), (3, 5), (100, 5)] {
+            assert_eq!(cell_storage.prefetch(root_hash, depth).unwrap(), expected);
+        }
+
+        // Missing cells are reported
+        assert!(cell_storage.prefetch(UInt256::from([0xff; 32]), 1).is_err());
+    }
+}
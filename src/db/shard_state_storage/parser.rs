@@ -2,6 +2,7 @@ use std::io::Read;
 
 use anyhow::{Context, Result};
 use crc::{Crc, CRC_32_ISCSI};
+use serde::{Deserialize, Serialize};
 use smallvec::SmallVec;
 use ton_types::ByteOrderRead;
 
@@ -18,6 +19,8 @@ macro_rules! try_read {
 pub struct ShardStatePacketReader {
     hasher: crc::Digest<'static, u32>,
     has_crc: bool,
+    /// Position of the first byte of the current packet in the BOC
+    current_packet_start: u64,
     offset: usize,
     current_packet: Vec<u8>,
     next_packet: Vec<u8>,
@@ -29,6 +32,7 @@ impl ShardStatePacketReader {
         Self {
             hasher: CRC.digest(),
             has_crc: true,
+            current_packet_start: 0,
             offset: 0,
             current_packet: Default::default(),
             next_packet: Default::default(),
@@ -36,6 +40,44 @@ impl ShardStatePacketReader {
         }
     }
 
+    /// Creates a reader which expects packets starting from the specified position.
+    ///
+    /// `crc` is the CRC of the skipped part (see [`ShardStatePacketReader::crc`]),
+    /// it must be specified for the BOC with CRC
+    pub fn resume_at(position: u64, crc: Option<u32>) -> Self {
+        let (hasher, has_crc) = match crc {
+            // NOTE: the register of the reflected CRC is stored reversed, and
+            // the finalized value is XORed with `xorout`
+            Some(crc) => (
+                CRC.digest_with_initial((crc ^ CRC_32_ISCSI.xorout).reverse_bits()),
+                true,
+            ),
+            None => (CRC.digest(), false),
+        };
+
+        Self {
+            hasher,
+            has_crc,
+            current_packet_start: position,
+            ..Self::new()
+        }
+    }
+
+    /// CRC of the consumed part of the BOC, or `None` if the BOC has no CRC.
+    ///
+    /// NOTE: must be called only when there are no bytes to skip, i.e. after the header
+    /// was read and the index was skipped
+    pub fn crc(&self) -> Option<u32> {
+        debug_assert_eq!(self.bytes_to_skip, 0);
+        self.has_crc.then(|| self.hasher.clone().finalize())
+    }
+
+    /// Position in the BOC of the first byte which is not consumed yet
+    /// (partially read items are not consumed)
+    pub fn position(&self) -> u64 {
+        self.current_packet_start + (self.offset + self.bytes_to_skip) as u64
+    }
+
     pub fn read_header(&mut self) -> Result<Option<BocHeader>> {
         const BOC_INDEXED_TAG: u32 = 0x68ff65f3;
         const BOC_INDEXED_CRC32_TAG: u32 = 0xacc3a728;
@@ -226,6 +268,11 @@ impl ShardStatePacketReader {
         }
     }
 
+    fn switch_to_next_packet(&mut self) {
+        self.current_packet_start += self.current_packet.len() as u64;
+        self.current_packet = std::mem::take(&mut self.next_packet);
+    }
+
     fn set_skip(&mut self, n: usize) {
         self.bytes_to_skip = n;
     }
@@ -248,18 +295,19 @@ impl ShardStatePacketReader {
             std::cmp::Ordering::Equal => {
                 self.hasher.update(&self.current_packet[self.offset..]);
                 self.offset = 0;
-                self.current_packet = std::mem::take(&mut self.next_packet);
+                self.switch_to_next_packet();
                 ReaderAction::Complete
             }
             std::cmp::Ordering::Greater => {
                 n -= remaining;
                 self.hasher.update(&self.current_packet[self.offset..]);
                 self.offset = 0;
-                self.current_packet = std::mem::take(&mut self.next_packet);
+                self.switch_to_next_packet();
 
                 if n > self.current_packet.len() {
                     n -= self.current_packet.len();
                     self.hasher.update(&self.current_packet);
+                    self.current_packet_start += self.current_packet.len() as u64;
                     self.current_packet = Vec::new();
                     self.bytes_to_skip = n;
                     ReaderAction::Incomplete
@@ -275,7 +323,7 @@ impl ShardStatePacketReader {
 
 static CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BocHeader {
     pub root_index: u64,
     pub index_included: bool,
@@ -425,7 +473,7 @@ impl<'a> ShardStatePacketReaderTransaction<'a> {
             }
 
             // Replace current packet
-            self.reader.current_packet = std::mem::take(&mut self.reader.next_packet);
+            self.reader.switch_to_next_packet();
         } else if self.reader.has_crc {
             // Write to the hasher current bytes
             self.reader
@@ -495,3 +543,142 @@ enum ShardStateParserError {
     #[error("Crc mismatch")]
     CrcMismatch,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PACKET_SIZE: usize = 32;
+
+    fn make_boc() -> Vec<u8> {
+        let mut last: Option<ton_types::Cell> = None;
+        for i in 0..100u32 {
+            let mut builder = ton_types::BuilderData::new();
+            builder.append_u32(i).unwrap();
+            if let Some(child) = last.take() {
+                builder.checked_append_reference(child).unwrap();
+            }
+            last = Some(builder.into_cell().unwrap());
+        }
+        ton_types::serialize_toc(&last.unwrap()).unwrap()
+    }
+
+    /// Same as [`make_boc`], but with CRC
+    fn make_boc_with_crc() -> Vec<u8> {
+        let mut boc = make_boc();
+        if boc[4] & 0b0100_0000 == 0 {
+            boc[4] |= 0b0100_0000;
+            let crc = CRC.checksum(&boc);
+            boc.extend_from_slice(&crc.to_le_bytes());
+        }
+        boc
+    }
+
+    /// Returns whether all cells were read
+    fn feed(
+        reader: &mut ShardStatePacketReader,
+        header: &mut Option<BocHeader>,
+        cells: &mut Vec<Vec<u8>>,
+        packet: &[u8],
+    ) -> bool {
+        reader.set_next_packet(packet.to_vec());
+
+        if header.is_none() {
+            match reader.read_header().unwrap() {
+                Some(new_header) => *header = Some(new_header),
+                None => return false,
+            }
+        }
+        let header = header.as_ref().unwrap();
+
+        let mut buffer = [0; 256];
+        while (cells.len() as u64) < header.cell_count {
+            match reader.read_cell(header.ref_size, &mut buffer).unwrap() {
+                Some(size) => cells.push(buffer[..size].to_vec()),
+                None => return false,
+            }
+        }
+
+        !header.has_crc || reader.read_crc().unwrap().is_some()
+    }
+
+    #[test]
+    fn resume_from_position() {
+        for boc in [make_boc(), make_boc_with_crc()] {
+            // Uninterrupted read
+            let mut reader = ShardStatePacketReader::new();
+            let mut header = None;
+            let mut expected = Vec::new();
+            let complete = boc
+                .chunks(PACKET_SIZE)
+                .map(|packet| feed(&mut reader, &mut header, &mut expected, packet))
+                .last();
+            assert_eq!(complete, Some(true));
+            assert_eq!(expected.len(), 100);
+            assert_eq!(reader.position(), boc.len() as u64);
+
+            // Interrupted after several packets
+            let mut reader = ShardStatePacketReader::new();
+            let mut header = None;
+            let mut cells = Vec::new();
+            for packet in boc.chunks(PACKET_SIZE).take(3) {
+                assert!(!feed(&mut reader, &mut header, &mut cells, packet));
+            }
+            let position = reader.position();
+            let crc = reader.crc();
+            assert!(!cells.is_empty());
+            assert!(position <= (PACKET_SIZE * 3) as u64);
+            assert_eq!(crc.is_some(), header.as_ref().unwrap().has_crc);
+
+            // Resumed from the reported position
+            let mut reader = ShardStatePacketReader::resume_at(position, crc);
+            let complete = boc[position as usize..]
+                .chunks(PACKET_SIZE)
+                .map(|packet| feed(&mut reader, &mut header, &mut cells, packet))
+                .last();
+            assert_eq!(complete, Some(true));
+            assert_eq!(cells, expected);
+            assert_eq!(reader.position(), boc.len() as u64);
+        }
+    }
+
+    #[test]
+    fn crc_is_checked_after_resume() {
+        let boc = make_boc_with_crc();
+
+        let mut reader = ShardStatePacketReader::new();
+        let mut header = None;
+        let mut cells = Vec::new();
+        for packet in boc.chunks(PACKET_SIZE).take(3) {
+            feed(&mut reader, &mut header, &mut cells, packet);
+        }
+        let position = reader.position() as usize;
+        let crc = reader.crc();
+        assert!(crc.is_some());
+
+        // Corrupted byte after the resume point
+        let mut corrupted = boc.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+
+        let mut reader = ShardStatePacketReader::resume_at(position as u64, crc);
+        let mut result = Ok(None);
+        for packet in corrupted[position..].chunks(PACKET_SIZE) {
+            reader.set_next_packet(packet.to_vec());
+            let header = header.as_ref().unwrap();
+            let mut buffer = [0; 256];
+            while (cells.len() as u64) < header.cell_count {
+                match reader.read_cell(header.ref_size, &mut buffer).unwrap() {
+                    Some(size) => cells.push(buffer[..size].to_vec()),
+                    None => break,
+                }
+            }
+            if (cells.len() as u64) == header.cell_count {
+                result = reader.read_crc();
+                if !matches!(result, Ok(None)) {
+                    break;
+                }
+            }
+        }
+        assert!(result.is_err());
+    }
+}
@@ -21,6 +21,7 @@ pub struct ShardStateReplaceTransaction<'a> {
     reader: ShardStatePacketReader,
     header: Option<BocHeader>,
     cells_read: u64,
+    cells_file_len: u64,
 }
 
 impl<'a> ShardStateReplaceTransaction<'a> {
@@ -38,6 +39,29 @@ impl<'a> ShardStateReplaceTransaction<'a> {
             reader: ShardStatePacketReader::new(),
             header: None,
             cells_read: 0,
+            cells_file_len: 0,
+        }
+    }
+
+    /// Continues the interrupted transaction.
+    ///
+    /// NOTE: packets must start from the [`StateResumePoint::position`]
+    pub fn resume(
+        shard_state_db: &'a Tree<columns::ShardStates>,
+        cell_storage: &'a Arc<CellStorage>,
+        min_ref_mc_state: &'a Arc<MinRefMcState>,
+        marker: u8,
+        point: StateResumePoint,
+    ) -> Self {
+        Self {
+            shard_state_db,
+            cell_storage,
+            min_ref_mc_state,
+            marker,
+            reader: ShardStatePacketReader::resume_at(point.position, point.crc),
+            header: Some(point.header),
+            cells_read: point.cells_read,
+            cells_file_len: point.cells_file_len,
         }
     }
 
@@ -45,6 +69,16 @@ impl<'a> ShardStateReplaceTransaction<'a> {
         &self.header
     }
 
+    pub fn cells_read(&self) -> u64 {
+        self.cells_read
+    }
+
+    /// Position in the state BOC from which the next packet is expected
+    /// if the download is restarted
+    pub fn position(&self) -> u64 {
+        self.reader.position()
+    }
+
     pub async fn process_packet(
         &mut self,
         ctx: &mut FilesContext,
@@ -53,6 +87,7 @@ impl<'a> ShardStateReplaceTransaction<'a> {
     ) -> Result<bool> {
         use tokio::io::AsyncWriteExt;
 
+        let cells_path = ctx.cells_path().to_owned();
         let cells_file = ctx.cells_file()?;
 
         self.reader.set_next_packet(packet);
@@ -83,7 +118,10 @@ impl<'a> ShardStateReplaceTransaction<'a> {
             };
 
             buffer[cell_size] = cell_size as u8;
-            cells_file.write_all(&buffer[..cell_size + 1]).await?;
+            cells_file
+                .write_all(&buffer[..cell_size + 1])
+                .await
+                .map_err(|e| map_write_error(e, &cells_path))?;
 
             chunk_size += cell_size as u32 + 1;
             self.cells_read += 1;
@@ -93,7 +131,25 @@ impl<'a> ShardStateReplaceTransaction<'a> {
 
         if chunk_size > 0 {
             tracing::debug!(chunk_size, "creating chunk");
-            cells_file.write_u32_le(chunk_size).await?;
+            cells_file
+                .write_u32_le(chunk_size)
+                .await
+                .map_err(|e| map_write_error(e, &cells_path))?;
+            self.cells_file_len += chunk_size as u64 + 4;
+
+            let point = StateResumePoint {
+                position: self.reader.position(),
+                cells_read: self.cells_read,
+                cells_file_len: self.cells_file_len,
+                header: header.clone(),
+                crc: self.reader.crc(),
+            };
+            ctx.save_resume_point(&point).await.map_err(|e| {
+                match e.downcast::<std::io::Error>() {
+                    Ok(e) => map_write_error(e, &cells_path),
+                    Err(e) => e,
+                }
+            })?;
         }
 
         if self.cells_read < header.cell_count {
@@ -128,7 +184,13 @@ impl<'a> ShardStateReplaceTransaction<'a> {
 
         let hashes_file =
             ctx.create_mapped_hashes_file(header.cell_count as usize * HashesEntry::LEN)?;
-        let cells_file = ctx.create_mapped_cells_file().await?;
+        let cells_path = ctx.cells_path().to_owned();
+        let cells_file = ctx.create_mapped_cells_file().await.map_err(|e| match e
+            .downcast::<std::io::Error>()
+        {
+            Ok(e) => map_write_error(e, &cells_path),
+            Err(e) => e,
+        })?;
 
         let db = self.shard_state_db.raw_db_handle();
         let mut write_options = rocksdb::WriteOptions::default();
@@ -211,18 +273,24 @@ impl<'a> ShardStateReplaceTransaction<'a> {
             tokio::task::yield_now().await;
         }
 
-        if batch_len > 0 {
-            db.write_opt(std::mem::take(&mut ctx.write_batch), &write_options)?;
-        }
-
-        progress_bar.complete();
-
         let shard_state_key = (block_id.shard_id, block_id.seq_no).to_vec();
 
         // Current entry contains root cell
         let current_entry = ctx.entries_buffer.split_children(&[]).0;
-        self.shard_state_db
-            .insert(&shard_state_key, current_entry.as_reader().hash(3))?;
+
+        // NOTE: root is written in the same batch as the last cells, so that
+        // it never becomes visible without its children (even after crash)
+        {
+            let shard_states_cf = self.shard_state_db.get_cf();
+            ctx.write_batch.put_cf(
+                &shard_states_cf,
+                &shard_state_key,
+                current_entry.as_reader().hash(3),
+            );
+        }
+        db.write_opt(std::mem::take(&mut ctx.write_batch), &write_options)?;
+
+        progress_bar.complete();
 
         // Load stored shard state
         match self.shard_state_db.get(shard_state_key)? {
@@ -444,8 +512,22 @@ impl FinalizationContext {
     }
 }
 
+/// Converts a full disk error into a readable one
+fn map_write_error(e: std::io::Error, path: &std::path::Path) -> anyhow::Error {
+    if e.raw_os_error() == Some(libc::ENOSPC) {
+        ReplaceTransactionError::OutOfDiskSpace {
+            path: path.to_owned(),
+        }
+        .into()
+    } else {
+        e.into()
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 enum ReplaceTransactionError {
+    #[error("Out of disk space while writing {}", path.display())]
+    OutOfDiskSpace { path: std::path::PathBuf },
     #[error("Not found")]
     NotFound,
     #[error("Invalid shard state packet")]
@@ -453,3 +535,103 @@ enum ReplaceTransactionError {
     #[error("Invalid cell")]
     InvalidCell,
 }
+
+#[cfg(test)]
+mod tests {
+    use ton_block::Serializable;
+
+    use super::*;
+    use crate::db::tree::DbCaches;
+    use crate::test_helpers::*;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn full_disk_error() {
+        // Writes to `/dev/full` always fail with ENOSPC
+        let path = std::path::Path::new("/dev/full");
+        let e = std::fs::OpenOptions::new()
+            .write(true)
+            .open(path)
+            .and_then(|mut file| file.write_all(&[0; 16]).and_then(|_| file.flush()))
+            .unwrap_err();
+
+        let e = map_write_error(e, path);
+        assert!(matches!(
+            e.downcast_ref::<ReplaceTransactionError>(),
+            Some(ReplaceTransactionError::OutOfDiskSpace { path }) if path == std::path::Path::new("/dev/full")
+        ));
+
+        // Other errors are left as is
+        let e = map_write_error(std::io::ErrorKind::BrokenPipe.into(), path);
+        assert!(e.downcast_ref::<std::io::Error>().is_some());
+    }
+
+    #[tokio::test]
+    async fn state_root_is_stored_with_last_cells() {
+        let dir = TempDir::new("replace_transaction");
+        let caches = DbCaches::with_capacity(0).unwrap();
+        let db = test_db_builder(&dir, &caches)
+            .column::<columns::ShardStates>()
+            .column::<columns::Cells>()
+            .build()
+            .unwrap();
+
+        let shard_states = Tree::<columns::ShardStates>::new(&db).unwrap();
+        let cell_storage = Arc::new(CellStorage::new(&db).unwrap());
+        let min_ref_mc_state = MinRefMcState::new();
+
+        let state = ton_block::ShardStateUnsplit::default();
+        let block_id = ton_block::BlockIdExt {
+            shard_id: *state.shard(),
+            seq_no: state.seq_no(),
+            root_hash: Default::default(),
+            file_hash: Default::default(),
+        };
+        let boc = ton_types::serialize_toc(&state.serialize().unwrap()).unwrap();
+        let shard_state_key = (block_id.shard_id, block_id.seq_no).to_vec();
+
+        let import = |boc: Vec<u8>| {
+            let mut transaction = ShardStateReplaceTransaction::new(
+                &shard_states,
+                &cell_storage,
+                &min_ref_mc_state,
+                0,
+            );
+            let (path, block_id) = (dir.path().to_path_buf(), block_id.clone());
+            async move {
+                let mut ctx = FilesContext::new(&path, &block_id).await?;
+                let mut pg = ProgressBar::builder("test").build();
+                assert!(transaction.process_packet(&mut ctx, boc, &mut pg).await?);
+                let result = transaction.finalize(&mut ctx, block_id, &mut pg).await;
+                ctx.clear().await?;
+                result
+            }
+        };
+
+        // Inject fault into the root cell (which is processed last)
+        let mut corrupted = boc.clone();
+        corrupted[root_cell_offset(&boc)] |= 0b0010_0000; // set level mask
+        assert!(import(corrupted).await.is_err());
+        assert!(shard_states.get(&shard_state_key).unwrap().is_none());
+
+        // Import without faults
+        let stored = import(boc).await.unwrap();
+        assert_eq!(
+            stored.root_cell().repr_hash(),
+            state.serialize().unwrap().repr_hash()
+        );
+        assert!(shard_states.get(&shard_state_key).unwrap().is_some());
+    }
+
+    /// Returns offset of the first cell in the generic BOC without index
+    fn root_cell_offset(boc: &[u8]) -> usize {
+        let flags = boc[4];
+        assert_eq!(flags & 0b1100_0000, 0, "index and crc are not supported");
+
+        let ref_size = (flags & 0b111) as usize;
+        let offset_size = boc[5] as usize;
+
+        // magic + flags + offset_size + cells + roots + absent + total_cells_size + root_list
+        4 + 1 + 1 + ref_size * 3 + offset_size + ref_size
+    }
+}
@@ -6,10 +6,15 @@ use rocksdb::{
     BoundColumnFamily, Cache, DBIterator, DBPinnableSlice, DBRawIterator, IteratorMode, Options,
     ReadOptions, WriteOptions, DB,
 };
+use rustc_hash::FxHashMap;
 
 pub trait Column {
     const NAME: &'static str;
 
+    /// Whether the column contains only the data derived from other columns,
+    /// so it could be cleared and rebuilt after the DB corruption
+    const DERIVATIVE: bool = false;
+
     fn options(opts: &mut Options, caches: &DbCaches) {
         let _unused = opts;
         let _unused = caches;
@@ -48,7 +53,18 @@ pub struct DbBuilder<'a> {
     path: PathBuf,
     options: Options,
     caches: &'a DbCaches,
-    descriptors: Vec<rocksdb::ColumnFamilyDescriptor>,
+    column_options: Vec<Options>,
+    column_names: Vec<&'static str>,
+    derivative_columns: Vec<&'static str>,
+    recover_corrupt_columns: bool,
+    read_only: bool,
+    schema_version: Option<SchemaVersion>,
+}
+
+struct SchemaVersion {
+    column: &'static str,
+    key: &'static str,
+    version: [u8; 3],
 }
 
 impl<'a> DbBuilder<'a> {
@@ -60,7 +76,12 @@ impl<'a> DbBuilder<'a> {
             path: path.as_ref().into(),
             options: Default::default(),
             caches,
-            descriptors: Default::default(),
+            column_options: Default::default(),
+            column_names: Default::default(),
+            derivative_columns: Default::default(),
+            recover_corrupt_columns: false,
+            read_only: false,
+            schema_version: None,
         }
     }
 
@@ -78,18 +99,373 @@ impl<'a> DbBuilder<'a> {
     {
         let mut opts = Default::default();
         T::options(&mut opts, self.caches);
-        self.descriptors
-            .push(rocksdb::ColumnFamilyDescriptor::new(T::NAME, opts));
+        self.column_options.push(opts);
+        self.column_names.push(T::NAME);
+        if T::DERIVATIVE {
+            self.derivative_columns.push(T::NAME);
+        }
+        self
+    }
+
+    /// Whether to repair the DB if it is corrupted instead of failing.
+    ///
+    /// NOTE: the DB is repaired only if all corrupted columns are derivative,
+    /// they are cleared after the repair because they could lose some entries.
+    /// Fails without any changes if some primary column is corrupted
+    /// (or if the corrupted columns can't be found)
+    pub fn recover_corrupt_columns(mut self, recover: bool) -> Self {
+        self.recover_corrupt_columns = recover;
+        self
+    }
+
+    /// Whether to open the existing DB in read-only mode.
+    ///
+    /// NOTE: the DB is not locked, so it could be opened while it is used
+    /// by another process. Corrupted DB is never repaired in this mode
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Stores the schema version (major, minor, patch) under the `key` of the
+    /// specified column of the new DB.
+    ///
+    /// The existing DB with a newer schema is refused before any column family
+    /// is created. Older versions are left as is, they must be migrated after
+    /// the DB is opened
+    pub fn schema_version<T>(mut self, key: &'static str, version: [u8; 3]) -> Self
+    where
+        T: Column,
+    {
+        self.schema_version = Some(SchemaVersion {
+            column: T::NAME,
+            key,
+            version,
+        });
         self
     }
 
+    /// Opens the DB creating missing column families.
+    ///
+    /// Fails if the DB contains unknown column families (i.e. it was
+    /// created by a newer version), because it can't be opened without them
     pub fn build(self) -> Result<Arc<DB>> {
-        Ok(Arc::new(DB::open_cf_descriptors(
-            &self.options,
-            &self.path,
-            self.descriptors,
-        )?))
+        self.build_with_report().map(|(db, _)| db)
+    }
+
+    /// Same as [`DbBuilder::build`], but also returns the report
+    /// if the DB was repaired while opening
+    pub fn build_with_report(self) -> Result<(Arc<DB>, Option<DbRecoveryReport>)> {
+        if let Ok(lost_columns) =
+            std::fs::read_to_string(self.path.join(PRIMARY_COLUMNS_LOST_MARKER))
+        {
+            return Err(DbBuilderError::PrimaryColumnsLost(lost_columns).into());
+        }
+
+        // NOTE: column families can't be listed for a new DB
+        let existing = DB::list_cf(&self.options, &self.path).ok();
+        if let Some(existing) = &existing {
+            self.check_schema_version(existing)?;
+
+            let missing = check_columns(existing, &self.column_names)?;
+            if !missing.is_empty() {
+                tracing::info!(?missing, "creating missing column families");
+            }
+        }
+
+        let error = match self.open() {
+            Ok(db) => {
+                if existing.is_none() && !self.read_only {
+                    self.store_schema_version(&db)?;
+                }
+                return Ok((Arc::new(db), None));
+            }
+            Err(e)
+                if self.recover_corrupt_columns
+                    && !self.read_only
+                    && e.kind() == rocksdb::ErrorKind::Corruption =>
+            {
+                e
+            }
+            Err(e) => return Err(e.into()),
+        };
+        tracing::error!("DB is corrupted: {error}");
+
+        // NOTE: repair moves corrupted tables away, so the DB is left as is
+        // unless it is known that only derivative columns are corrupted
+        let corrupted = self
+            .find_corrupted_columns(existing.as_deref().unwrap_or_default())
+            .ok_or(DbBuilderError::CorruptedColumnsUnknown)?;
+        let primary = corrupted
+            .iter()
+            .filter(|name| !self.derivative_columns.contains(name))
+            .copied()
+            .collect::<Vec<_>>();
+        if !primary.is_empty() {
+            return Err(DbBuilderError::PrimaryColumnsCorrupted(primary.join(", ")).into());
+        }
+        tracing::warn!(?corrupted, "trying to repair DB");
+
+        // NOTE: the repair doesn't tell which column the lost files belonged to
+        let table_columns = self.table_columns();
+
+        // Corrupted files are moved into the `lost` directory during the repair
+        let lost_files_dir = self.path.join("lost");
+        let old_lost_files = list_files(&lost_files_dir);
+
+        DB::repair(&self.options, &self.path).context("Failed to repair DB")?;
+
+        let lost_files = list_files(&lost_files_dir)
+            .into_iter()
+            .filter(|path| !old_lost_files.contains(path))
+            .collect::<Vec<_>>();
+
+        // Only tables contain the data, logs are always moved after they are converted
+        let mut lost_columns = Vec::new();
+        for path in &lost_files {
+            if path.extension().and_then(|ext| ext.to_str()) != Some("sst") {
+                continue;
+            }
+            let column = path
+                .file_name()
+                .and_then(|name| table_columns.get(name.to_string_lossy().as_ref()))
+                .map(String::as_str);
+            match column {
+                Some(name) if self.derivative_columns.contains(&name) => {}
+                Some(name) => lost_columns.push(name.to_owned()),
+                None => lost_columns.push(format!("<unknown: {}>", path.display())),
+            }
+        }
+        if !lost_columns.is_empty() {
+            lost_columns.sort_unstable();
+            lost_columns.dedup();
+            let lost_columns = lost_columns.join(", ");
+
+            // NOTE: repaired DB opens without errors, so the failure must be remembered
+            std::fs::write(self.path.join(PRIMARY_COLUMNS_LOST_MARKER), &lost_columns)
+                .context("Failed to save lost columns")?;
+            return Err(DbBuilderError::PrimaryColumnsLost(lost_columns).into());
+        }
+
+        let db = self.open().context("Failed to open repaired DB")?;
+
+        let mut report = DbRecoveryReport {
+            error: error.to_string(),
+            lost_files,
+            cleared_columns: Vec::new(),
+        };
+
+        // Primary columns are only repaired, derivative columns are recreated
+        for (&name, opts) in self.column_names.iter().zip(&self.column_options) {
+            if self.derivative_columns.contains(&name) {
+                db.drop_cf(name)?;
+                db.create_cf(name, opts)?;
+                report.cleared_columns.push(name);
+            }
+        }
+
+        tracing::warn!(
+            lost_files = ?report.lost_files,
+            cleared_columns = ?report.cleared_columns,
+            "DB repaired"
+        );
+        Ok((Arc::new(db), Some(report)))
+    }
+
+    /// Fails if the existing DB has a newer schema version
+    fn check_schema_version(&self, existing: &[String]) -> Result<()> {
+        let schema = match &self.schema_version {
+            Some(schema) if existing.iter().any(|name| name == schema.column) => schema,
+            _ => return Ok(()),
+        };
+
+        // NOTE: the DB is opened read-only to leave it as is if the version is newer
+        let stored = DB::open_cf_for_read_only(&self.options, &self.path, [schema.column], false)
+            .and_then(|db| {
+                let cf = db.cf_handle(schema.column).expect("opened above");
+                db.get_cf(&cf, schema.key)
+            });
+        let stored = match stored {
+            Ok(Some(stored)) => stored,
+            // Legacy DB without the version
+            Ok(None) => return Ok(()),
+            // Corrupted DB is checked after it is repaired
+            Err(e) if e.kind() == rocksdb::ErrorKind::Corruption => {
+                tracing::warn!("failed to check the schema version of the corrupted DB: {e}");
+                return Ok(());
+            }
+            Err(e) => return Err(e).context("Failed to load schema version"),
+        };
+
+        let stored: [u8; 3] = stored
+            .try_into()
+            .map_err(|_| DbBuilderError::InvalidSchemaVersion)?;
+        if stored > schema.version {
+            return Err(DbBuilderError::NewerSchemaVersion {
+                stored,
+                expected: schema.version,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    fn store_schema_version(&self, db: &DB) -> Result<()> {
+        if let Some(schema) = &self.schema_version {
+            let cf = db
+                .cf_handle(schema.column)
+                .context("Schema version column not found")?;
+            db.put_cf(&cf, schema.key, schema.version)
+                .context("Failed to save schema version")?;
+        }
+        Ok(())
+    }
+
+    /// Finds declared columns of the corrupted DB which can't be fully read
+    /// with checksums verification. The DB is opened read-only.
+    ///
+    /// NOTE: returns `None` if even the relaxed read-only open fails
+    fn find_corrupted_columns(&self, existing: &[String]) -> Option<Vec<&'static str>> {
+        let mut options = self.options.clone();
+        options.set_paranoid_checks(false);
+        options.set_skip_checking_sst_file_sizes_on_db_open(true);
+
+        let columns = self
+            .column_names
+            .iter()
+            .copied()
+            .filter(|name| existing.iter().any(|existing| existing == name))
+            .collect::<Vec<_>>();
+
+        let db = match DB::open_cf_for_read_only(&options, &self.path, &columns, false) {
+            Ok(db) => db,
+            Err(e) => {
+                tracing::error!("failed to open the corrupted DB for checks: {e}");
+                return None;
+            }
+        };
+
+        let mut corrupted = Vec::new();
+        for name in columns {
+            let cf = db.cf_handle(name)?;
+
+            let mut read_options = ReadOptions::default();
+            read_options.set_verify_checksums(true);
+            read_options.fill_cache(false);
+
+            let mut iter = db.raw_iterator_cf_opt(&cf, read_options);
+            iter.seek_to_first();
+            while iter.valid() {
+                iter.next();
+            }
+            if let Err(e) = iter.status() {
+                tracing::error!(column = name, "column is corrupted: {e}");
+                corrupted.push(name);
+            }
+        }
+        Some(corrupted)
+    }
+
+    /// Returns the column of each table file (by file name) of the corrupted DB.
+    ///
+    /// NOTE: returns an empty map if even the relaxed read-only open fails
+    fn table_columns(&self) -> FxHashMap<String, String> {
+        let mut options = self.options.clone();
+        options.set_paranoid_checks(false);
+        options.set_skip_checking_sst_file_sizes_on_db_open(true);
+
+        let live_files = DB::open_cf_for_read_only(&options, &self.path, &self.column_names, false)
+            .and_then(|db| db.live_files());
+        match live_files {
+            Ok(files) => files
+                .into_iter()
+                .map(|file| {
+                    let name = file.name.trim_start_matches('/').to_owned();
+                    (name, file.column_family_name)
+                })
+                .collect(),
+            Err(e) => {
+                tracing::error!("failed to list tables of the corrupted DB: {e}");
+                Default::default()
+            }
+        }
+    }
+
+    fn open(&self) -> Result<DB, rocksdb::Error> {
+        let descriptors = self
+            .column_names
+            .iter()
+            .zip(&self.column_options)
+            .map(|(name, opts)| rocksdb::ColumnFamilyDescriptor::new(*name, opts.clone()));
+        if self.read_only {
+            DB::open_cf_descriptors_read_only(&self.options, &self.path, descriptors, false)
+        } else {
+            DB::open_cf_descriptors(&self.options, &self.path, descriptors)
+        }
+    }
+}
+
+/// What was done to open the corrupted DB
+#[derive(Debug, Clone)]
+pub struct DbRecoveryReport {
+    /// Corruption error which was returned on the first attempt
+    pub error: String,
+    /// Files which couldn't be repaired
+    pub lost_files: Vec<PathBuf>,
+    /// Derivative columns which must be rebuilt
+    pub cleared_columns: Vec<&'static str>,
+}
+
+/// File in the DB directory which exists if the repair lost some primary data
+const PRIMARY_COLUMNS_LOST_MARKER: &str = "PRIMARY_COLUMNS_LOST";
+
+fn list_files(dir: &Path) -> Vec<PathBuf> {
+    match std::fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Returns declared column families which don't exist in the DB
+fn check_columns<'a>(
+    existing: &[String],
+    declared: &[&'a str],
+) -> Result<Vec<&'a str>, DbBuilderError> {
+    let unknown = existing
+        .iter()
+        .filter(|name| {
+            name.as_str() != rocksdb::DEFAULT_COLUMN_FAMILY_NAME
+                && !declared.contains(&name.as_str())
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    if !unknown.is_empty() {
+        return Err(DbBuilderError::UnknownColumns(unknown.join(", ")));
     }
+
+    Ok(declared
+        .iter()
+        .filter(|name| !existing.iter().any(|existing| existing == *name))
+        .copied()
+        .collect())
+}
+
+#[derive(thiserror::Error, Debug)]
+enum DbBuilderError {
+    #[error("DB contains unknown column families (probably created by a newer version): {0}")]
+    UnknownColumns(String),
+    #[error("Repair lost data of primary column families, restore the DB from backup: {0}")]
+    PrimaryColumnsLost(String),
+    #[error("Primary column families are corrupted, restore the DB from backup: {0}")]
+    PrimaryColumnsCorrupted(String),
+    #[error("Failed to find corrupted column families, restore the DB from backup")]
+    CorruptedColumnsUnknown,
+    #[error("DB has a newer schema version {stored:?} (expected at most {expected:?})")]
+    NewerSchemaVersion { stored: [u8; 3], expected: [u8; 3] },
+    #[error("Invalid schema version")]
+    InvalidSchemaVersion,
 }
 
 pub struct Tree<T> {
@@ -153,6 +529,17 @@ where
         Ok(self.db.delete_cf_opt(&cf, key, &self.write_config)?)
     }
 
+    /// Removes all keys in the range `[from, to)`
+    ///
+    /// NOTE: a single range tombstone is written instead of a tombstone
+    /// per key, so prefer it for large contiguous ranges
+    pub fn delete_range<K: AsRef<[u8]>>(&self, from: K, to: K) -> Result<()> {
+        let cf = self.get_cf();
+        Ok(self
+            .db
+            .delete_range_cf_opt(&cf, from, to, &self.write_config)?)
+    }
+
     #[allow(dead_code)]
     pub fn contains_key<K: AsRef<[u8]>>(&self, key: K) -> Result<bool> {
         let cf = self.get_cf();
@@ -206,3 +593,190 @@ where
         self.db.raw_iterator_cf_opt(&cf, read_config)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::columns;
+    use crate::test_helpers::*;
+
+    #[test]
+    fn missing_and_unknown_columns() {
+        let existing = ["default", "archive", "block_handles"].map(String::from);
+
+        // Old DB without the new column
+        let missing =
+            check_columns(&existing, &["archive", "block_handles", "new_column"]).unwrap();
+        assert_eq!(missing, ["new_column"]);
+
+        // Same columns
+        let missing = check_columns(&existing, &["archive", "block_handles"]).unwrap();
+        assert!(missing.is_empty());
+
+        // Newer DB
+        assert!(check_columns(&existing, &["archive"]).is_err());
+    }
+
+    #[test]
+    fn delete_range() {
+        let dir = TempDir::new("delete_range");
+        let caches = DbCaches::with_capacity(0).unwrap();
+        let db = test_db_builder(&dir, &caches)
+            .column::<columns::Archives>()
+            .build()
+            .unwrap();
+
+        let tree = Tree::<columns::Archives>::new(&db).unwrap();
+        for id in 0u32..100 {
+            tree.insert(id.to_be_bytes(), [1]).unwrap();
+        }
+
+        // End of the range is exclusive
+        tree.delete_range(10u32.to_be_bytes(), 90u32.to_be_bytes())
+            .unwrap();
+        for id in 0u32..100 {
+            let removed = (10..90).contains(&id);
+            assert_eq!(!tree.contains_key(id.to_be_bytes()).unwrap(), removed);
+        }
+    }
+
+    #[test]
+    fn newer_schema_version_is_refused() {
+        const KEY: &str = "schema_version";
+
+        let dir = TempDir::new("schema_version");
+        let caches = DbCaches::with_capacity(0).unwrap();
+        let builder = |version| {
+            test_db_builder(&dir, &caches)
+                .column::<columns::NodeStates>()
+                .schema_version::<columns::NodeStates>(KEY, version)
+        };
+
+        // Version is stored for the new DB
+        let db = builder([1, 1, 0]).build().unwrap();
+        let states = Tree::<columns::NodeStates>::new(&db).unwrap();
+        assert_eq!(states.get(KEY).unwrap().unwrap().as_ref(), [1, 1, 0]);
+        drop(states);
+        drop(db);
+
+        // Older version is refused before the new column is created
+        let error = builder([1, 0, 0])
+            .column::<columns::Archives>()
+            .build()
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<DbBuilderError>(),
+            Some(DbBuilderError::NewerSchemaVersion { .. })
+        ));
+        let existing = DB::list_cf(&Options::default(), dir.join("rocksdb")).unwrap();
+        assert!(!existing.iter().any(|name| name == columns::Archives::NAME));
+
+        // Newer version is left for migrations
+        let db = builder([1, 2, 0])
+            .column::<columns::Archives>()
+            .build()
+            .unwrap();
+        let states = Tree::<columns::NodeStates>::new(&db).unwrap();
+        assert_eq!(states.get(KEY).unwrap().unwrap().as_ref(), [1, 1, 0]);
+    }
+
+    fn corrupt_test_builder<'a>(dir: &TempDir, caches: &'a DbCaches) -> DbBuilder<'a> {
+        test_db_builder(dir, caches)
+            .column::<columns::BlockHandles>()
+            .column::<columns::MessageIndex>()
+    }
+
+    /// Fills both columns, flushes them into SST files and truncates the file of the specified column
+    fn corrupt_column_table(dir: &TempDir, caches: &DbCaches, column: &str) {
+        let sst_file = {
+            let db = corrupt_test_builder(dir, caches).build().unwrap();
+            let handles = Tree::<columns::BlockHandles>::new(&db).unwrap();
+            let index = Tree::<columns::MessageIndex>::new(&db).unwrap();
+            for id in 0u32..1000 {
+                handles.insert(id.to_be_bytes(), [1; 64]).unwrap();
+                index.insert(id.to_be_bytes(), [2; 64]).unwrap();
+            }
+            db.flush_cf(&handles.get_cf()).unwrap();
+            db.flush_cf(&index.get_cf()).unwrap();
+
+            let file = db
+                .live_files()
+                .unwrap()
+                .into_iter()
+                .find(|file| file.column_family_name == column)
+                .unwrap();
+            dir.join("rocksdb").join(file.name.trim_start_matches('/'))
+        };
+
+        // Simulate partially written file
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&sst_file)
+            .unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len / 2).unwrap();
+    }
+
+    #[test]
+    fn recover_corrupt_derivative_column() {
+        let dir = TempDir::new("recover_corrupt");
+        let caches = DbCaches::with_capacity(0).unwrap();
+        let builder = || corrupt_test_builder(&dir, &caches);
+
+        corrupt_column_table(&dir, &caches, columns::MessageIndex::NAME);
+        assert!(builder().build().is_err());
+
+        let (db, report) = builder()
+            .recover_corrupt_columns(true)
+            .build_with_report()
+            .unwrap();
+        let report = report.unwrap();
+        assert_eq!(report.cleared_columns, [columns::MessageIndex::NAME]);
+
+        // Primary column is kept, derivative column is ready to be rebuilt
+        let handles = Tree::<columns::BlockHandles>::new(&db).unwrap();
+        for id in 0u32..1000 {
+            assert!(handles.contains_key(id.to_be_bytes()).unwrap());
+        }
+        let index = Tree::<columns::MessageIndex>::new(&db).unwrap();
+        assert!(index.iterator(IteratorMode::Start).next().is_none());
+    }
+
+    #[test]
+    fn recover_corrupt_primary_column_fails() {
+        let dir = TempDir::new("recover_corrupt_primary");
+        let caches = DbCaches::with_capacity(0).unwrap();
+        let builder = || corrupt_test_builder(&dir, &caches).recover_corrupt_columns(true);
+
+        corrupt_column_table(&dir, &caches, columns::BlockHandles::NAME);
+        let tables = || {
+            let mut tables = list_files(&dir.join("rocksdb"))
+                .into_iter()
+                .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some("sst"))
+                .map(|path| {
+                    let len = std::fs::metadata(&path).unwrap().len();
+                    (path, len)
+                })
+                .collect::<Vec<_>>();
+            tables.sort();
+            tables
+        };
+        let tables_before = tables();
+
+        for _ in 0..2 {
+            let error = builder().build().unwrap_err();
+            assert!(matches!(
+                error.downcast_ref::<DbBuilderError>(),
+                Some(DbBuilderError::PrimaryColumnsCorrupted(names)) if names == columns::BlockHandles::NAME
+            ));
+
+            // DB is not repaired, so it could still be restored
+            assert!(!dir.join("rocksdb").join("lost").exists());
+            assert!(!dir
+                .join("rocksdb")
+                .join(PRIMARY_COLUMNS_LOST_MARKER)
+                .exists());
+            assert_eq!(tables(), tables_before);
+        }
+    }
+}
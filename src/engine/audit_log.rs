@@ -0,0 +1,134 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::sync::mpsc;
+
+use crate::config::AuditLogOptions;
+use crate::db::{AuditLogEntry, AuditLogEventKind, Db};
+
+/// Max number of entries written in one batch
+const MAX_BATCH_LEN: usize = 256;
+
+/// Non-blocking writer of the audit log.
+///
+/// Entries are written by a separate task, so recording never waits for the DB.
+/// If the queue is full, entries are dropped and counted
+pub struct AuditLog {
+    tx: Option<mpsc::Sender<AuditLogEntry>>,
+    dropped: AtomicU64,
+}
+
+impl AuditLog {
+    pub fn new(db: &Arc<Db>, options: Option<AuditLogOptions>) -> Self {
+        let options = match options {
+            Some(options) => options,
+            None => {
+                return Self {
+                    tx: None,
+                    dropped: Default::default(),
+                }
+            }
+        };
+
+        let (tx, rx) = mpsc::channel(options.queue_size);
+        tokio::spawn(write_entries(db.clone(), rx, options.capacity));
+
+        Self {
+            tx: Some(tx),
+            dropped: Default::default(),
+        }
+    }
+
+    pub fn record<R>(
+        &self,
+        kind: AuditLogEventKind,
+        block_id: Option<&ton_block::BlockIdExt>,
+        reason: R,
+    ) where
+        R: Into<String>,
+    {
+        let tx = match &self.tx {
+            Some(tx) => tx,
+            None => return,
+        };
+
+        let entry = AuditLogEntry {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_millis() as u64)
+                .unwrap_or_default(),
+            kind,
+            block_id: block_id.cloned(),
+            reason: reason.into(),
+        };
+
+        if tx.try_send(entry).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Number of entries dropped because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+async fn write_entries(db: Arc<Db>, mut rx: mpsc::Receiver<AuditLogEntry>, capacity: u64) {
+    let mut entries = Vec::with_capacity(MAX_BATCH_LEN);
+    while let Some(entry) = rx.recv().await {
+        entries.push(entry);
+        while entries.len() < MAX_BATCH_LEN {
+            match rx.try_recv() {
+                Ok(entry) => entries.push(entry),
+                Err(_) => break,
+            }
+        }
+
+        if let Err(e) = db.audit_log().append(&entries, capacity) {
+            tracing::error!("failed to write audit log: {e:?}");
+        }
+        entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::*;
+
+    #[test]
+    fn every_entry_is_recorded_or_counted() {
+        let (tx, mut rx) = mpsc::channel(2);
+        let audit_log = AuditLog {
+            tx: Some(tx),
+            dropped: Default::default(),
+        };
+
+        // Repeated pointer stores with the same reason are all recorded
+        for seq_no in 1..=3 {
+            audit_log.record(
+                AuditLogEventKind::LastAppliedMcBlock,
+                Some(&mc_block_id(seq_no)),
+                "applied",
+            );
+        }
+
+        // Entries which didn't fit into the queue are counted
+        let mut recorded = Vec::new();
+        while let Ok(entry) = rx.try_recv() {
+            recorded.push(entry.block_id.unwrap().seq_no);
+        }
+        assert_eq!(recorded, [1, 2]);
+        assert_eq!(audit_log.dropped(), 1);
+
+        // Queue is released after the entries are written
+        audit_log.record(
+            AuditLogEventKind::LastAppliedMcBlock,
+            Some(&mc_block_id(4)),
+            "applied",
+        );
+        assert_eq!(rx.try_recv().unwrap().block_id.unwrap().seq_no, 4);
+        assert_eq!(audit_log.dropped(), 1);
+    }
+}
@@ -0,0 +1,349 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use once_cell::sync::OnceCell;
+use rustc_hash::FxHashSet;
+use ton_block::HashmapAugType;
+use ton_types::UInt256;
+
+use super::Engine;
+use crate::config::BalanceHistoryOptions;
+use crate::utils::*;
+
+/// Receiver of the balance changes extracted during the historical sync
+#[async_trait::async_trait]
+pub trait BalanceSink: Send + Sync {
+    /// Called for each transaction of the watched account in order of blocks.
+    ///
+    /// `delta` is the change of the account balance (in nanotons) and `fees`
+    /// are the total fees paid by the transaction.
+    ///
+    /// NOTE: records of the masterchain block interrupted by the restart
+    /// could be pushed again, `(account, lt)` identifies the record
+    async fn push(
+        &self,
+        account: &UInt256,
+        block_id: &ton_block::BlockIdExt,
+        lt: u64,
+        delta: i128,
+        fees: u128,
+    ) -> Result<()>;
+}
+
+pub(super) struct BalanceHistory {
+    options: BalanceHistoryOptions,
+    accounts: FxHashSet<(i32, UInt256)>,
+    sink: OnceCell<Arc<dyn BalanceSink>>,
+}
+
+impl BalanceHistory {
+    pub fn new(options: BalanceHistoryOptions) -> Self {
+        Self {
+            accounts: options.accounts.iter().cloned().collect(),
+            options,
+            sink: Default::default(),
+        }
+    }
+}
+
+impl Engine {
+    /// Sets the receiver of balance changes of the accounts
+    /// from `sync_options.balance_history`.
+    ///
+    /// NOTE: must be called before the engine is started
+    pub fn set_balance_sink(&self, sink: Arc<dyn BalanceSink>) -> Result<()> {
+        let history = self
+            .balance_history
+            .as_ref()
+            .ok_or(BalanceHistoryError::NotConfigured)?;
+        history
+            .sink
+            .set(sink)
+            .map_err(|_| BalanceHistoryError::SinkAlreadySet.into())
+    }
+
+    pub(super) fn check_balance_sink(&self) {
+        if matches!(&self.balance_history, Some(history) if history.sink.get().is_none()) {
+            tracing::warn!(
+                target: "sync",
+                "balance history is configured without the sink, records are not extracted"
+            );
+        }
+    }
+
+    /// Pushes balance changes of the watched accounts from the block
+    /// of the historical sync to the sink
+    pub(super) async fn extract_balance_history(
+        &self,
+        block: &BlockStuff,
+        mc_seq_no: u32,
+    ) -> Result<()> {
+        let (history, sink) = match &self.balance_history {
+            Some(history) if history.options.contains_seqno(mc_seq_no) => {
+                match history.sink.get() {
+                    Some(sink) => (history, sink),
+                    None => return Ok(()),
+                }
+            }
+            _ => return Ok(()),
+        };
+
+        let workchain = block.id().shard_id.workchain_id();
+        let changes = collect_balance_changes(block.block(), |account| {
+            history.accounts.contains(&(workchain, *account))
+        })?;
+
+        for (account, change) in changes {
+            sink.push(&account, block.id(), change.lt, change.delta, change.fees)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct BalanceChange {
+    lt: u64,
+    delta: i128,
+    fees: u128,
+}
+
+/// Returns balance changes of all transactions of the matching accounts
+fn collect_balance_changes<F>(
+    block: &ton_block::Block,
+    mut is_watched: F,
+) -> Result<Vec<(UInt256, BalanceChange)>>
+where
+    F: FnMut(&UInt256) -> bool,
+{
+    let mut changes = Vec::new();
+    block
+        .read_extra()?
+        .read_account_blocks()?
+        .iterate_objects(|account_block| {
+            let account = UInt256::from_slice(&account_block.account_id().get_bytestring(0));
+            if !is_watched(&account) {
+                return Ok(true);
+            }
+
+            account_block
+                .transactions()
+                .iterate_objects(|ton_block::InRefValue(tx)| {
+                    changes.push((account, transaction_balance_change(&tx)?));
+                    Ok(true)
+                })?;
+
+            Ok(true)
+        })?;
+
+    // NOTE: account blocks are ordered by account, transactions by lt
+    changes.sort_by_key(|(_, change)| change.lt);
+    Ok(changes)
+}
+
+/// Computes the balance change from the inbound message value, the value
+/// with forwarding fees of outbound messages and the total fees
+fn transaction_balance_change(tx: &ton_block::Transaction) -> Result<BalanceChange> {
+    let fees = tx.total_fees().grams.as_u128();
+
+    let mut delta = 0i128;
+    if let Some(in_msg) = tx.read_in_msg()? {
+        if let Some(header) = in_msg.int_header() {
+            delta += header.value.grams.as_u128() as i128;
+        }
+    }
+
+    tx.iterate_out_msgs(|out_msg| {
+        if let Some(header) = out_msg.int_header() {
+            delta -= (header.value.grams.as_u128()
+                + header.fwd_fee.as_u128()
+                + header.ihr_fee.as_u128()) as i128;
+        }
+        Ok(true)
+    })?;
+
+    delta -= fees as i128;
+
+    Ok(BalanceChange {
+        lt: tx.logical_time(),
+        delta,
+        fees,
+    })
+}
+
+#[derive(thiserror::Error, Debug)]
+enum BalanceHistoryError {
+    #[error("Balance history is not configured")]
+    NotConfigured,
+    #[error("Balance sink is already set")]
+    SinkAlreadySet,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use ton_block::{Deserializable, Serializable};
+
+    use super::*;
+    use crate::test_helpers::*;
+
+    fn address(byte: u8) -> ton_block::MsgAddressInt {
+        ton_block::MsgAddressInt::with_standart(None, 0, [byte; 32].into()).unwrap()
+    }
+
+    fn internal_message(src: u8, dst: u8, value: u64, fwd_fee: u64) -> ton_block::Message {
+        let mut header = ton_block::InternalMessageHeader::with_addresses(
+            address(src),
+            address(dst),
+            ton_block::CurrencyCollection::with_grams(value),
+        );
+        header.fwd_fee = fwd_fee.into();
+        ton_block::Message::with_int_header(header)
+    }
+
+    #[test]
+    fn transfer_balance_change() {
+        // Wallet receives 10 tons and sends 3 tons
+        let mut tx = ton_block::Transaction::with_address_and_status(
+            [1; 32].into(),
+            ton_block::AccountStatus::AccStateActive,
+        );
+        tx.set_logical_time(1000);
+        tx.set_total_fees(ton_block::CurrencyCollection::with_grams(5_000_000));
+        tx.write_in_msg(Some(&internal_message(2, 1, 10_000_000_000, 0)))
+            .unwrap();
+        tx.add_out_message(&internal_message(1, 3, 3_000_000_000, 1_000_000))
+            .unwrap();
+
+        // Transactions are parsed from cells of the block
+        let tx = ton_block::Transaction::construct_from_cell(tx.serialize().unwrap()).unwrap();
+
+        let change = transaction_balance_change(&tx).unwrap();
+        assert_eq!(
+            change,
+            BalanceChange {
+                lt: 1000,
+                delta: 10_000_000_000 - 3_000_000_000 - 1_000_000 - 5_000_000,
+                fees: 5_000_000,
+            }
+        );
+    }
+
+    fn make_transaction(
+        account: u8,
+        lt: u64,
+        fees: u64,
+        in_msg: ton_block::Message,
+        out_msgs: &[ton_block::Message],
+    ) -> ton_block::Transaction {
+        let mut tx = ton_block::Transaction::with_address_and_status(
+            [account; 32].into(),
+            ton_block::AccountStatus::AccStateActive,
+        );
+        tx.set_logical_time(lt);
+        tx.set_total_fees(ton_block::CurrencyCollection::with_grams(fees));
+        tx.write_in_msg(Some(&in_msg)).unwrap();
+        for msg in out_msgs {
+            tx.add_out_message(msg).unwrap();
+        }
+        tx
+    }
+
+    #[test]
+    fn balance_changes_from_block() {
+        // Watched account receives 10 tons, then sends 3 tons and 1 ton in one transaction
+        let watched = vec![
+            make_transaction(
+                1,
+                1000,
+                5_000_000,
+                internal_message(2, 1, 10_000_000_000, 0),
+                &[],
+            ),
+            make_transaction(
+                1,
+                1002,
+                7_000_000,
+                internal_message(2, 1, 0, 0),
+                &[
+                    internal_message(1, 3, 3_000_000_000, 1_000_000),
+                    internal_message(1, 4, 1_000_000_000, 1_000_000),
+                ],
+            ),
+        ];
+        // Transaction of another account between them
+        let other = make_transaction(3, 1001, 1_000_000, internal_message(1, 3, 1, 0), &[]);
+
+        let mut account_blocks = ton_block::ShardAccountBlocks::default();
+        for (account, txs) in [(1, watched), (3, vec![other])] {
+            let mut account_block = ton_block::AccountBlock::with_address([account; 32].into());
+            for tx in &txs {
+                account_block.add_transaction(tx).unwrap();
+            }
+            account_blocks.insert(&account_block).unwrap();
+        }
+        let mut extra = ton_block::BlockExtra::default();
+        extra.write_account_blocks(&account_blocks).unwrap();
+
+        let shard = ton_block::ShardIdent::with_tagged_prefix(0, ton_block::SHARD_FULL).unwrap();
+        let (_, data) = make_block(shard, 1, extra);
+        let block = ton_block::Block::construct_from_bytes(&data).unwrap();
+
+        let account = UInt256::from([1; 32]);
+        let changes = collect_balance_changes(&block, |id| id == &account).unwrap();
+        assert_eq!(
+            changes,
+            [
+                (
+                    account,
+                    BalanceChange {
+                        lt: 1000,
+                        delta: 10_000_000_000 - 5_000_000,
+                        fees: 5_000_000,
+                    }
+                ),
+                (
+                    account,
+                    BalanceChange {
+                        lt: 1002,
+                        delta: -(3_000_000_000 + 1_000_000_000 + 2_000_000 + 7_000_000),
+                        fees: 7_000_000,
+                    }
+                ),
+            ]
+        );
+
+        // Other accounts are not watched
+        let changes = collect_balance_changes(&block, |_| false).unwrap();
+        assert!(changes.is_empty());
+    }
+
+    /// Block BOC from the public network with a known transfer specified by
+    /// the `BALANCE_HISTORY_FIXTURE` env variable. Expected values are taken from
+    /// the explorer: `BALANCE_HISTORY_FIXTURE_ACCOUNT` (hex), `..._LT`, `..._DELTA`
+    /// and `..._FEES`.
+    #[test]
+    #[ignore = "requires a public network block specified by BALANCE_HISTORY_FIXTURE"]
+    fn balance_changes_from_block_fixture() {
+        let path = std::env::var_os("BALANCE_HISTORY_FIXTURE")
+            .expect("BALANCE_HISTORY_FIXTURE must be set for this test");
+        let var = |name: &str| std::env::var(format!("BALANCE_HISTORY_FIXTURE_{name}")).unwrap();
+
+        let data = std::fs::read(path).unwrap();
+        let block = ton_block::Block::construct_from_bytes(&data).unwrap();
+
+        let account = UInt256::from_str(&var("ACCOUNT")).unwrap();
+        let changes = collect_balance_changes(&block, |id| id == &account).unwrap();
+        assert!(changes.iter().all(|(id, _)| id == &account));
+
+        let lt = var("LT").parse::<u64>().unwrap();
+        let (_, change) = changes
+            .iter()
+            .find(|(_, change)| change.lt == lt)
+            .expect("transaction not found");
+        assert_eq!(change.delta, var("DELTA").parse::<i128>().unwrap());
+        assert_eq!(change.fees, var("FEES").parse::<u128>().unwrap());
+    }
+}
@@ -0,0 +1,343 @@
+use std::sync::atomic::Ordering;
+
+use anyhow::Result;
+use broxus_util::now;
+use serde::{Deserialize, Serialize};
+use ton_types::FxDashMap;
+
+use super::Engine;
+
+impl Engine {
+    /// Checks the block generation time against the local clock and the previous blocks.
+    ///
+    /// The block is accepted by the consensus, so anomalies are only reported
+    pub(crate) fn check_block_time(
+        &self,
+        block_id: &ton_block::BlockIdExt,
+        gen_utime: u32,
+        prev1_id: &ton_block::BlockIdExt,
+        prev2_id: &Option<ton_block::BlockIdExt>,
+    ) -> Result<()> {
+        let prev_utime = self.prev_blocks_utime(prev1_id, prev2_id)?;
+        let max_ahead_sec = self.sync_options.max_block_time_ahead_sec;
+
+        let anomaly = check_block_utime(gen_utime, prev_utime, now(), max_ahead_sec);
+        self.block_time_anomalies
+            .observe(block_id, gen_utime, anomaly);
+
+        let anomaly = match anomaly {
+            Some(anomaly) => anomaly,
+            None => return Ok(()),
+        };
+
+        tracing::warn!(
+            target: "block_time",
+            block_id = %block_id.display(),
+            gen_utime,
+            prev_utime,
+            ?anomaly,
+            "block with skewed generation time"
+        );
+        self.metrics.skewed_blocks.fetch_add(1, Ordering::Release);
+        self.block_time_anomalies.record(block_id.shard_id, anomaly);
+        Ok(())
+    }
+
+    /// Returns the number of blocks with skewed generation time per shard
+    pub fn block_time_anomalies(&self) -> Vec<(ton_block::ShardIdent, BlockTimeAnomalyCounters)> {
+        self.block_time_anomalies.snapshot()
+    }
+
+    /// Returns the block time suitable for the lag computation (see [`lag_utime`])
+    pub(crate) fn block_lag_utime(
+        &self,
+        shard: &ton_block::ShardIdent,
+        gen_utime: u32,
+        now: u32,
+    ) -> Option<u32> {
+        lag_utime(
+            &self.block_time_anomalies,
+            shard,
+            gen_utime,
+            now,
+            self.sync_options.max_block_time_ahead_sec,
+        )
+    }
+
+    fn prev_blocks_utime(
+        &self,
+        prev1_id: &ton_block::BlockIdExt,
+        prev2_id: &Option<ton_block::BlockIdExt>,
+    ) -> Result<Option<u32>> {
+        let handles = self.db.block_handle_storage();
+
+        let mut result = None;
+        for id in std::iter::once(prev1_id).chain(prev2_id) {
+            // NOTE: previous block is loaded only after split/merge or restart
+            let utime = match self.block_time_anomalies.last_utime(id) {
+                Some(utime) => Some(utime),
+                // NOTE: zerostate blocks have no meta
+                None => handles.load_meta(id)?.map(|meta| meta.gen_utime()),
+            };
+            result = std::cmp::max(result, utime);
+        }
+        Ok(result)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum BlockTimeAnomaly {
+    /// Block was generated later than the local time
+    AheadOfLocalTime { ahead_sec: u32 },
+    /// Block was generated before its previous block
+    BeforePrevBlock { behind_sec: u32 },
+}
+
+pub(crate) fn check_block_utime(
+    gen_utime: u32,
+    prev_utime: Option<u32>,
+    now: u32,
+    max_ahead_sec: u32,
+) -> Option<BlockTimeAnomaly> {
+    if gen_utime > now.saturating_add(max_ahead_sec) {
+        return Some(BlockTimeAnomaly::AheadOfLocalTime {
+            ahead_sec: gen_utime - now,
+        });
+    }
+
+    match prev_utime {
+        Some(prev_utime) if gen_utime < prev_utime => Some(BlockTimeAnomaly::BeforePrevBlock {
+            behind_sec: prev_utime - gen_utime,
+        }),
+        _ => None,
+    }
+}
+
+/// Returns the block time suitable for the lag computation:
+/// not before the previous block and not after the local time
+pub(crate) fn clamp_block_utime(gen_utime: u32, prev_utime: Option<u32>, now: u32) -> u32 {
+    let utime = match prev_utime {
+        Some(prev_utime) => std::cmp::max(gen_utime, prev_utime),
+        None => gen_utime,
+    };
+    std::cmp::min(utime, now)
+}
+
+/// Returns the block time suitable for the lag computation.
+///
+/// Time of the block far ahead of the local time is replaced with the time
+/// of the last block of the same shard which was not ahead of it. So blocks
+/// from the future don't make the node look synced.
+///
+/// Returns `None` if there were no such blocks since the start
+pub(crate) fn lag_utime(
+    anomalies: &BlockTimeAnomalies,
+    shard: &ton_block::ShardIdent,
+    gen_utime: u32,
+    now: u32,
+    max_ahead_sec: u32,
+) -> Option<u32> {
+    if gen_utime <= now.saturating_add(max_ahead_sec) {
+        Some(std::cmp::min(gen_utime, now))
+    } else {
+        anomalies.last_valid_utime(shard)
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct BlockTimeAnomalies {
+    shards: FxDashMap<ton_block::ShardIdent, BlockTimeAnomalyCounters>,
+    last_blocks: FxDashMap<ton_block::ShardIdent, LastBlockTime>,
+}
+
+/// The last checked block of the shard
+struct LastBlockTime {
+    block_id: ton_block::BlockIdExt,
+    gen_utime: u32,
+    /// Time of the last block which was not ahead of the local time
+    last_valid_utime: Option<u32>,
+}
+
+impl BlockTimeAnomalies {
+    fn observe(
+        &self,
+        block_id: &ton_block::BlockIdExt,
+        gen_utime: u32,
+        anomaly: Option<BlockTimeAnomaly>,
+    ) {
+        let is_valid = !matches!(anomaly, Some(BlockTimeAnomaly::AheadOfLocalTime { .. }));
+
+        let mut last = self
+            .last_blocks
+            .entry(block_id.shard_id)
+            .or_insert_with(|| LastBlockTime {
+                block_id: block_id.clone(),
+                gen_utime,
+                last_valid_utime: None,
+            });
+        if last.block_id.seq_no > block_id.seq_no {
+            return;
+        }
+        last.block_id = block_id.clone();
+        last.gen_utime = gen_utime;
+        if is_valid {
+            last.last_valid_utime = std::cmp::max(last.last_valid_utime, Some(gen_utime));
+        }
+    }
+
+    /// Returns the time of the block if it is the last checked block of its shard
+    fn last_utime(&self, block_id: &ton_block::BlockIdExt) -> Option<u32> {
+        let last = self.last_blocks.get(&block_id.shard_id)?;
+        (last.block_id == *block_id).then(|| last.gen_utime)
+    }
+
+    fn last_valid_utime(&self, shard: &ton_block::ShardIdent) -> Option<u32> {
+        self.last_blocks.get(shard)?.last_valid_utime
+    }
+
+    fn record(&self, shard: ton_block::ShardIdent, anomaly: BlockTimeAnomaly) {
+        let mut counters = self.shards.entry(shard).or_default();
+        match anomaly {
+            BlockTimeAnomaly::AheadOfLocalTime { .. } => counters.ahead_of_local_time += 1,
+            BlockTimeAnomaly::BeforePrevBlock { .. } => counters.before_prev_block += 1,
+        }
+    }
+
+    fn snapshot(&self) -> Vec<(ton_block::ShardIdent, BlockTimeAnomalyCounters)> {
+        let mut result = self
+            .shards
+            .iter()
+            .map(|entry| (*entry.key(), *entry.value()))
+            .collect::<Vec<_>>();
+        result.sort_unstable_by_key(|(shard, _)| *shard);
+        result
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct BlockTimeAnomalyCounters {
+    pub ahead_of_local_time: u64,
+    pub before_prev_block: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::*;
+
+    const NOW: u32 = 1_000_000;
+
+    #[test]
+    fn anomalies_detection() {
+        // Normal block
+        assert_eq!(check_block_utime(NOW - 5, Some(NOW - 10), NOW, 60), None);
+        // Slightly ahead, within the allowed skew
+        assert_eq!(check_block_utime(NOW + 60, Some(NOW), NOW, 60), None);
+        // Same time as the previous block
+        assert_eq!(check_block_utime(NOW, Some(NOW), NOW, 60), None);
+
+        assert_eq!(
+            check_block_utime(NOW + 3600, Some(NOW), NOW, 60),
+            Some(BlockTimeAnomaly::AheadOfLocalTime { ahead_sec: 3600 })
+        );
+        assert_eq!(
+            check_block_utime(NOW - 20, Some(NOW - 10), NOW, 60),
+            Some(BlockTimeAnomaly::BeforePrevBlock { behind_sec: 10 })
+        );
+        assert_eq!(check_block_utime(NOW - 20, None, NOW, 60), None);
+    }
+
+    #[test]
+    fn clamping() {
+        // Valid time is not changed
+        assert_eq!(clamp_block_utime(NOW - 5, Some(NOW - 10), NOW), NOW - 5);
+        assert_eq!(clamp_block_utime(NOW - 5, None, NOW), NOW - 5);
+
+        // Future time is clamped to the local time
+        assert_eq!(clamp_block_utime(NOW + 3600, Some(NOW - 10), NOW), NOW);
+        assert_eq!(clamp_block_utime(NOW + 3600, None, NOW), NOW);
+
+        // Time before the previous block is clamped to the previous block time
+        assert_eq!(clamp_block_utime(NOW - 20, Some(NOW - 10), NOW), NOW - 10);
+
+        // Previous block from the future is clamped too
+        assert_eq!(clamp_block_utime(NOW - 20, Some(NOW + 10), NOW), NOW);
+    }
+
+    #[test]
+    fn future_block_is_not_synced() {
+        const MAX_AHEAD: u32 = 60;
+
+        let anomalies = BlockTimeAnomalies::default();
+        let observe = |seq_no, gen_utime, now| {
+            let prev_utime = match seq_no {
+                1 => None,
+                _ => Some(anomalies.last_utime(&mc_block_id(seq_no - 1)).unwrap()),
+            };
+            let anomaly = check_block_utime(gen_utime, prev_utime, now, MAX_AHEAD);
+            anomalies.observe(&mc_block_id(seq_no), gen_utime, anomaly);
+            anomaly
+        };
+
+        // Unknown time before the first valid block
+        assert_eq!(lag_utime(&anomalies, &mc, NOW + 3600, NOW, MAX_AHEAD), None);
+
+        assert_eq!(observe(1, NOW - 10, NOW), None);
+        assert_eq!(
+            lag_utime(&anomalies, &mc, NOW - 10, NOW, MAX_AHEAD),
+            Some(NOW - 10)
+        );
+
+        // Block from the future
+        assert_eq!(
+            observe(2, NOW + 3600, NOW),
+            Some(BlockTimeAnomaly::AheadOfLocalTime { ahead_sec: 3600 })
+        );
+        assert_eq!(anomalies.last_utime(&block_id(2)), Some(NOW + 3600));
+
+        // Lag is computed from the last valid block and grows with time
+        let now = NOW + 1000;
+        let utime = lag_utime(&anomalies, &mc, NOW + 3600, now, MAX_AHEAD).unwrap();
+        assert_eq!(utime, NOW - 10);
+        assert!(utime + 600 <= now);
+
+        // Next block is compared with the skewed one but it is valid for the lag
+        assert_eq!(
+            observe(3, now, now),
+            Some(BlockTimeAnomaly::BeforePrevBlock { behind_sec: 2600 })
+        );
+        assert_eq!(lag_utime(&anomalies, &mc, now, now, MAX_AHEAD), Some(now));
+        assert_eq!(anomalies.last_valid_utime(&mc), Some(now));
+    }
+
+    #[test]
+    fn counters_per_shard() {
+        let anomalies = BlockTimeAnomalies::default();
+        let left = ton_block::ShardIdent::with_tagged_prefix(0, 0x4000_0000_0000_0000).unwrap();
+        let right = ton_block::ShardIdent::with_tagged_prefix(0, 0xc000_0000_0000_0000).unwrap();
+
+        anomalies.record(right, BlockTimeAnomaly::BeforePrevBlock { behind_sec: 1 });
+        anomalies.record(left, BlockTimeAnomaly::AheadOfLocalTime { ahead_sec: 100 });
+        anomalies.record(left, BlockTimeAnomaly::AheadOfLocalTime { ahead_sec: 100 });
+
+        assert_eq!(
+            anomalies.snapshot(),
+            [
+                (
+                    left,
+                    BlockTimeAnomalyCounters {
+                        ahead_of_local_time: 2,
+                        before_prev_block: 0,
+                    }
+                ),
+                (
+                    right,
+                    BlockTimeAnomalyCounters {
+                        ahead_of_local_time: 0,
+                        before_prev_block: 1,
+                    }
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,446 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use parking_lot::Mutex;
+use rustc_hash::FxHashMap;
+use tokio::sync::oneshot;
+
+use super::Engine;
+use crate::db::{BlockConnection, BlockHandle, Db};
+
+/// Waiters for blocks which are not applied yet
+#[derive(Default)]
+pub(super) struct BlockWaiters {
+    inner: Mutex<BlockWaitersInner>,
+}
+
+#[derive(Default)]
+struct BlockWaitersInner {
+    /// Last applied block of each shard since the start
+    top_blocks: FxHashMap<ton_block::ShardIdent, ton_block::BlockIdExt>,
+    waiters: Vec<BlockWaiter>,
+}
+
+struct BlockWaiter {
+    shard: ton_block::ShardIdent,
+    seq_no: u32,
+    tx: oneshot::Sender<ton_block::BlockIdExt>,
+}
+
+impl BlockWaiters {
+    /// Registers the waiter for the block with the specified seqno in the shard.
+    ///
+    /// Returns the last applied blocks of intersecting shards with seqno not less
+    /// than the specified one, or `None` if no intersecting blocks were applied since
+    /// the start (so that the DB must be checked).
+    fn register(
+        &self,
+        shard: ton_block::ShardIdent,
+        seq_no: u32,
+    ) -> (
+        Option<Vec<ton_block::BlockIdExt>>,
+        oneshot::Receiver<ton_block::BlockIdExt>,
+    ) {
+        let (tx, rx) = oneshot::channel();
+
+        let mut inner = self.inner.lock();
+
+        let mut tops = None;
+        for (top_shard, top_id) in &inner.top_blocks {
+            if shards_intersect(top_shard, &shard) {
+                let tops = tops.get_or_insert_with(Vec::new);
+                if top_id.seq_no >= seq_no {
+                    tops.push(top_id.clone());
+                }
+            }
+        }
+
+        inner.waiters.push(BlockWaiter { shard, seq_no, tx });
+
+        (tops, rx)
+    }
+
+    /// Completes waiters of the applied block
+    pub fn notify_applied(&self, block_id: &ton_block::BlockIdExt) {
+        let mut inner = self.inner.lock();
+
+        let top = inner
+            .top_blocks
+            .entry(block_id.shard_id)
+            .or_insert_with(|| block_id.clone());
+        if top.seq_no < block_id.seq_no {
+            *top = block_id.clone();
+        }
+
+        let waiters = std::mem::take(&mut inner.waiters);
+        for waiter in waiters {
+            if waiter.tx.is_closed() {
+                // Waiter timed out
+                continue;
+            }
+
+            if waiter.seq_no == block_id.seq_no
+                && shards_intersect(&waiter.shard, &block_id.shard_id)
+            {
+                waiter.tx.send(block_id.clone()).ok();
+            } else {
+                inner.waiters.push(waiter);
+            }
+        }
+    }
+}
+
+impl Engine {
+    /// Waits until the block with the specified seqno in the shard is applied.
+    ///
+    /// Returns immediately if the block is already applied. Shards are matched
+    /// with splits and merges taken into account: a waiter on the parent shard is
+    /// satisfied by a block of either child shard and a waiter on the child shard
+    /// is satisfied by a block of the merged shard with the same seqno. The handle
+    /// of the matched block is returned, so its shard could differ from the specified one.
+    ///
+    /// NOTE: blocks applied before the start are looked up by walking back from
+    /// the top blocks of the last processed masterchain block
+    pub async fn wait_for_block(
+        &self,
+        shard: ton_block::ShardIdent,
+        seq_no: u32,
+        timeout: Duration,
+    ) -> Result<Arc<BlockHandle>> {
+        // NOTE: the waiter is registered before the lookup so that
+        // the block applied in between is not missed
+        let (tops, rx) = self.block_waiters.register(shard, seq_no);
+
+        let tops = match tops {
+            Some(tops) => tops,
+            None => self.load_stored_top_blocks(&shard).await?,
+        };
+        for top in tops {
+            if let Some(handle) = self.find_applied_block(&shard, seq_no, top).await? {
+                return Ok(handle);
+            }
+        }
+
+        let block_id = match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(block_id)) => block_id,
+            Ok(Err(_)) => return Err(BlockWaitersError::Cancelled.into()),
+            Err(_) => return Err(BlockWaitersError::Timeout.into()),
+        };
+
+        self.db
+            .block_handle_storage()
+            .load_handle(&block_id)?
+            .ok_or_else(|| BlockWaitersError::HandleNotFound.into())
+    }
+
+    /// Returns applied top blocks of the shards intersecting the specified one
+    async fn load_stored_top_blocks(
+        &self,
+        shard: &ton_block::ShardIdent,
+    ) -> Result<Vec<ton_block::BlockIdExt>> {
+        if shard.is_masterchain() {
+            return Ok(vec![self.load_last_applied_mc_block_id()?]);
+        }
+
+        let mc_block_id = self.load_shards_client_mc_block_id()?;
+        let mc_block = self.load_mc_block_data(&mc_block_id).await?;
+        Ok(mc_block
+            .shard_blocks()?
+            .into_values()
+            .filter(|id| shards_intersect(&id.shard_id, shard))
+            .collect())
+    }
+
+    /// Finds the applied block with the specified seqno in the shard of the top block.
+    ///
+    /// NOTE: DB is read outside the async runtime threads
+    async fn find_applied_block(
+        &self,
+        shard: &ton_block::ShardIdent,
+        seq_no: u32,
+        top: ton_block::BlockIdExt,
+    ) -> Result<Option<Arc<BlockHandle>>> {
+        let db = self.db.clone();
+        let shard = *shard;
+        tokio::task::spawn_blocking(move || find_applied_block(&db, &shard, seq_no, top)).await?
+    }
+}
+
+/// Finds the block by its stored state or walks back from the top block
+/// to the block with the specified seqno
+fn find_applied_block(
+    db: &Db,
+    shard: &ton_block::ShardIdent,
+    seq_no: u32,
+    top: ton_block::BlockIdExt,
+) -> Result<Option<Arc<BlockHandle>>> {
+    let load_applied = |block_id: &ton_block::BlockIdExt| {
+        Ok(db
+            .block_handle_storage()
+            .load_handle(block_id)?
+            .filter(|handle| handle.meta().is_applied()))
+    };
+
+    // States of applied blocks are indexed by the shard and seqno
+    let shard_state_storage = db.shard_state_storage();
+    for shard_id in std::iter::once(&top.shard_id).chain((*shard != top.shard_id).then(|| shard)) {
+        if let Some(block_id) = shard_state_storage.find_block_id(shard_id, seq_no)? {
+            return load_applied(&block_id);
+        }
+    }
+
+    // States could be removed by the gc, so walk back through the previous blocks
+    let mut block_id = top;
+    if block_id.shard_id.is_masterchain() {
+        // Start from the closest key block
+        let key_blocks = db.block_handle_storage().key_blocks_index();
+        if let Some(key_block_id) = key_blocks.next(seq_no) {
+            if key_block_id.seq_no < block_id.seq_no {
+                block_id = key_block_id;
+            }
+        }
+    }
+
+    while block_id.seq_no > seq_no {
+        block_id = match prev_intersecting_block(db, &block_id, shard)? {
+            Some(prev_id) => prev_id,
+            None => return Ok(None),
+        };
+    }
+    if block_id.seq_no != seq_no {
+        return Ok(None);
+    }
+
+    load_applied(&block_id)
+}
+
+fn prev_intersecting_block(
+    db: &Db,
+    block_id: &ton_block::BlockIdExt,
+    shard: &ton_block::ShardIdent,
+) -> Result<Option<ton_block::BlockIdExt>> {
+    let connections = db.block_connection_storage();
+    let prev1 = match connections.find_connection(block_id, BlockConnection::Prev1)? {
+        Some(id) => id,
+        None => return Ok(None),
+    };
+
+    // Previous blocks before merge are stored as `prev1` (left) and `prev2` (right)
+    if shards_intersect(&prev1.shard_id, shard) {
+        Ok(Some(prev1))
+    } else {
+        connections.find_connection(block_id, BlockConnection::Prev2)
+    }
+}
+
+/// Returns `true` if one shard is the same as the other or its ancestor
+fn shards_intersect(left: &ton_block::ShardIdent, right: &ton_block::ShardIdent) -> bool {
+    if left.workchain_id() != right.workchain_id() {
+        return false;
+    }
+
+    let left = left.shard_prefix_with_tag();
+    let right = right.shard_prefix_with_tag();
+
+    // Compare only bits before the tag of the larger shard
+    let tag = std::cmp::max(left & left.wrapping_neg(), right & right.wrapping_neg());
+    let mask = !(tag | (tag - 1));
+    (left ^ right) & mask == 0
+}
+
+#[derive(thiserror::Error, Debug)]
+enum BlockWaitersError {
+    #[error("Timeout while waiting for the block")]
+    Timeout,
+    #[error("Block waiter cancelled")]
+    Cancelled,
+    #[error("Applied block handle not found")]
+    HandleNotFound,
+}
+
+#[cfg(test)]
+mod tests {
+    use ton_block::Serializable;
+
+    use super::*;
+    use crate::db::BlockMetaData;
+    use crate::test_helpers::*;
+    use crate::utils::{MinRefMcState, ShardStateStuff};
+
+    fn shards() -> (
+        ton_block::ShardIdent,
+        ton_block::ShardIdent,
+        ton_block::ShardIdent,
+    ) {
+        let parent = ton_block::ShardIdent::full(0);
+        let (left, right) = parent.split().unwrap();
+        (parent, left, right)
+    }
+
+    #[test]
+    fn already_applied_block() {
+        let waiters = BlockWaiters::default();
+        let (parent, _, _) = shards();
+
+        // Nothing is known since the start
+        let (tops, _) = waiters.register(parent, 10);
+        assert!(tops.is_none());
+
+        waiters.notify_applied(&block_id(parent, 10));
+        waiters.notify_applied(&block_id(parent, 11));
+
+        let (tops, _) = waiters.register(parent, 10);
+        assert_eq!(tops.unwrap(), [block_id(parent, 11)]);
+
+        // Block is not applied yet
+        let (tops, _) = waiters.register(parent, 12);
+        assert!(tops.unwrap().is_empty());
+
+        // Other workchain
+        let (tops, _) = waiters.register(ton_block::ShardIdent::masterchain(), 10);
+        assert!(tops.is_none());
+    }
+
+    #[tokio::test]
+    async fn block_arrives_later() {
+        let waiters = BlockWaiters::default();
+        let (parent, _, _) = shards();
+
+        let (_, rx) = waiters.register(parent, 10);
+
+        waiters.notify_applied(&block_id(parent, 9));
+        waiters.notify_applied(&mc_block_id(10));
+        waiters.notify_applied(&block_id(parent, 10));
+
+        assert_eq!(rx.await.unwrap(), block_id(parent, 10));
+        assert!(waiters.inner.lock().waiters.is_empty());
+    }
+
+    #[tokio::test]
+    async fn block_wait_timeout() {
+        let waiters = BlockWaiters::default();
+        let (parent, _, _) = shards();
+
+        let (_, rx) = waiters.register(parent, 10);
+        let result = tokio::time::timeout(Duration::from_millis(10), rx).await;
+        assert!(result.is_err());
+
+        // Timed out waiters are removed
+        waiters.notify_applied(&block_id(parent, 9));
+        assert!(waiters.inner.lock().waiters.is_empty());
+    }
+
+    #[tokio::test]
+    async fn split_and_merge() {
+        let waiters = BlockWaiters::default();
+        let (parent, left, right) = shards();
+
+        // Waiter on the parent shard is satisfied by either child
+        let (_, parent_rx) = waiters.register(parent, 10);
+        // Waiter on the child shard is satisfied by the merged shard
+        let (_, left_rx) = waiters.register(left, 20);
+
+        waiters.notify_applied(&block_id(right, 10));
+        assert_eq!(parent_rx.await.unwrap(), block_id(right, 10));
+
+        waiters.notify_applied(&block_id(parent, 20));
+        assert_eq!(left_rx.await.unwrap(), block_id(parent, 20));
+
+        assert!(shards_intersect(&parent, &left));
+        assert!(shards_intersect(&right, &parent));
+        assert!(!shards_intersect(&left, &right));
+    }
+
+    /// Stores applied blocks `1..=top` of the shard connected by `prev1`.
+    /// States are stored only for blocks starting from `first_state`
+    async fn store_applied_chain(
+        engine: &Engine,
+        shard: ton_block::ShardIdent,
+        top: u32,
+        first_state: u32,
+    ) {
+        let db = &engine.db;
+        let mut prev: Option<ton_block::BlockIdExt> = None;
+        for seq_no in 1..=top {
+            let id = block_id(shard, seq_no);
+            let (handle, _) = db
+                .block_handle_storage()
+                .create_or_load_handle(
+                    &id,
+                    BlockMetaData {
+                        is_key_block: false,
+                        gen_utime: seq_no,
+                        mc_ref_seqno: Some(seq_no),
+                    },
+                )
+                .unwrap();
+            handle.meta().set_is_applied();
+            db.block_handle_storage().store_handle(&handle).unwrap();
+
+            if let Some(prev) = &prev {
+                db.block_connection_storage()
+                    .store_connection(&handle, BlockConnection::Prev1, prev)
+                    .unwrap();
+            }
+
+            if seq_no >= first_state {
+                let mut state = ton_block::ShardStateUnsplit::with_ident(shard);
+                state.set_seq_no(seq_no);
+                let root = state.serialize().unwrap();
+                let state = ShardStateStuff::new(id.clone(), root, &MinRefMcState::new()).unwrap();
+                db.shard_state_storage()
+                    .store_state(&handle, &state)
+                    .await
+                    .unwrap();
+            }
+
+            prev = Some(id);
+        }
+    }
+
+    #[tokio::test]
+    async fn block_applied_before_start() {
+        const TIMEOUT: Duration = Duration::from_millis(10);
+
+        let dir = TempDir::new("block_applied_before_start");
+        let engine = test_engine(&dir, Vec::new()).await;
+        let mc = ton_block::ShardIdent::masterchain();
+        let (parent, left, _) = shards();
+
+        store_applied_chain(&engine, mc, 5, 3).await;
+        store_applied_chain(&engine, parent, 5, 5).await;
+        engine
+            .db
+            .node_state()
+            .store_last_mc_block_id(&mc_block_id(5))
+            .unwrap();
+
+        // Masterchain block is found by its state
+        let handle = engine.wait_for_block(mc, 4, TIMEOUT).await.unwrap();
+        assert_eq!(handle.id(), &mc_block_id(4));
+
+        // State of the old block was removed, so it is found by the previous blocks
+        let handle = engine.wait_for_block(mc, 1, TIMEOUT).await.unwrap();
+        assert_eq!(handle.id(), &mc_block_id(1));
+
+        // Block is not applied yet
+        assert!(engine.wait_for_block(mc, 6, TIMEOUT).await.is_err());
+
+        // Shard blocks
+        let top = block_id(parent, 5);
+        for (shard, seq_no) in [(parent, 5), (parent, 2), (left, 2)] {
+            let handle = engine
+                .find_applied_block(&shard, seq_no, top.clone())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(handle.id(), &block_id(parent, seq_no));
+        }
+        assert!(engine
+            .find_applied_block(&parent, 6, top)
+            .await
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,661 @@
+use anyhow::Result;
+use ton_types::UInt256;
+
+use super::{shard_contains_account, Engine, EngineError};
+use crate::db::BlockConnection;
+use crate::utils::*;
+
+impl Engine {
+    /// Finds the latest applied masterchain block generated at or before the specified time.
+    ///
+    /// Returns the block id with its exact `gen_utime`, or `None` if the time is
+    /// before the first known masterchain block. The last applied block is returned
+    /// for any time after it.
+    ///
+    /// NOTE: blocks between the closest key blocks are bisected by seqno, so
+    /// only the blocks with the stored data are used. The rest is walked
+    /// block by block from the closest found one
+    pub fn masterchain_block_at(&self, utime: u32) -> Result<Option<(ton_block::BlockIdExt, u32)>> {
+        let key_blocks = self.db.block_handle_storage().key_blocks_index().ids();
+        let last_mc_block_id = self.load_last_applied_mc_block_id()?;
+
+        let key_block = last_at_or_before(&key_blocks, utime, |id| self.block_utime(id))?;
+
+        // Seqno range with all blocks after the specified time excluded
+        let (from_seq_no, to_seq_no) = match key_block {
+            Some(index) => (
+                key_blocks[index].seq_no,
+                key_blocks.get(index + 1).map(|id| id.seq_no),
+            ),
+            None => (0, key_blocks.first().map(|id| id.seq_no)),
+        };
+        let to_seq_no = to_seq_no.unwrap_or(last_mc_block_id.seq_no + 1);
+
+        let mc_shard = ton_block::ShardIdent::masterchain();
+        let block_storage = self.db.block_storage();
+        let closest = bisect_at_or_before(from_seq_no, to_seq_no, utime, |seq_no| {
+            let block_id = match block_storage.find_block_id(&mc_shard, seq_no)? {
+                Some(block_id) => block_id,
+                None => return Ok(None),
+            };
+            let meta = self.db.block_handle_storage().load_meta(&block_id)?;
+            Ok(meta.map(|meta| (block_id, meta.gen_utime())))
+        })?;
+
+        let start = match (closest, key_block) {
+            (Some((block_id, _)), _) => block_id,
+            (None, Some(index)) => key_blocks[index].clone(),
+            (None, None) => match key_blocks.first() {
+                Some(id) => id.clone(),
+                None => last_mc_block_id,
+            },
+        };
+
+        let connections = self.db.block_connection_storage();
+        find_latest_at_or_before(
+            start,
+            utime,
+            |id| self.block_utime(id),
+            |id| connections.find_connection(id, BlockConnection::Next1),
+            |id| connections.find_connection(id, BlockConnection::Prev1),
+        )
+    }
+
+    /// Finds the latest applied block of the account shard generated at or before
+    /// the specified time. Shard splits and merges are followed by the account address.
+    ///
+    /// Returns the block id with its exact `gen_utime`, or `None` if the time is
+    /// before the first known block.
+    ///
+    /// NOTE: data of the masterchain block at the specified time must be stored
+    pub async fn shard_block_at(
+        &self,
+        address: &ton_block::MsgAddressInt,
+        utime: u32,
+    ) -> Result<Option<(ton_block::BlockIdExt, u32)>> {
+        let workchain = address.workchain_id();
+        if workchain == ton_block::MASTERCHAIN_ID {
+            return self.masterchain_block_at(utime);
+        }
+        let account = UInt256::from_slice(&address.address().get_bytestring(0));
+
+        let mc_block_id = match self.masterchain_block_at(utime)? {
+            Some((id, _)) => id,
+            None => return Ok(None),
+        };
+        let mc_block = self.load_mc_block_data(&mc_block_id).await?;
+
+        let start = mc_block
+            .shard_blocks()?
+            .into_values()
+            .find(|id| {
+                id.shard_id.workchain_id() == workchain
+                    && shard_contains_account(&id.shard_id, &account)
+            })
+            .ok_or(EngineError::AccountShardNotFound)?;
+
+        find_latest_at_or_before(
+            start,
+            utime,
+            |id| self.block_utime(id),
+            |id| self.next_account_block(id, &account),
+            |id| self.prev_account_block(id, &account),
+        )
+    }
+
+    /// Resolves the masterchain block live at the specified time along with
+    /// the top blocks of all shards referenced by it.
+    ///
+    /// NOTE: data of the masterchain block at the specified time must be stored
+    pub async fn chain_state_at_utime(
+        &self,
+        utime: u32,
+    ) -> Result<(ton_block::BlockIdExt, TopBlocks)> {
+        let mc_block_id = match self.masterchain_block_at(utime)? {
+            Some((id, _)) => id,
+            None => return Err(EngineError::BlockNotFound.into()),
+        };
+        let mc_block = self.load_mc_block_data(&mc_block_id).await?;
+        let top_blocks = TopBlocks::from_mc_block(&mc_block)?;
+        Ok((mc_block_id, top_blocks))
+    }
+
+    pub(super) async fn load_mc_block_data(
+        &self,
+        block_id: &ton_block::BlockIdExt,
+    ) -> Result<BlockStuff> {
+        let handle = self
+            .db
+            .block_handle_storage()
+            .load_handle(block_id)?
+            .ok_or(EngineError::BlockNotFound)?;
+        self.db.block_storage().load_block_data(&handle).await
+    }
+
+    fn block_utime(&self, block_id: &ton_block::BlockIdExt) -> Result<u32> {
+        match self.db.block_handle_storage().load_meta(block_id)? {
+            Some(meta) => Ok(meta.gen_utime()),
+            None => Err(EngineError::BlockNotFound.into()),
+        }
+    }
+
+    fn next_account_block(
+        &self,
+        block_id: &ton_block::BlockIdExt,
+        account: &UInt256,
+    ) -> Result<Option<ton_block::BlockIdExt>> {
+        let connections = self.db.block_connection_storage();
+        let next1 = match connections.find_connection(block_id, BlockConnection::Next1)? {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+
+        // Next blocks after split are stored as `next1` (left) and `next2` (right)
+        if shard_contains_account(&next1.shard_id, account) {
+            Ok(Some(next1))
+        } else {
+            connections.find_connection(block_id, BlockConnection::Next2)
+        }
+    }
+
+    fn prev_account_block(
+        &self,
+        block_id: &ton_block::BlockIdExt,
+        account: &UInt256,
+    ) -> Result<Option<ton_block::BlockIdExt>> {
+        let connections = self.db.block_connection_storage();
+        let prev1 = match connections.find_connection(block_id, BlockConnection::Prev1)? {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+
+        // Previous blocks before merge are stored as `prev1` (left) and `prev2` (right)
+        let is_merge = prev1.shard_id != block_id.shard_id
+            && matches!(block_id.shard_id.split(), Ok((left, _)) if left == prev1.shard_id);
+        if is_merge && !shard_contains_account(&prev1.shard_id, account) {
+            connections.find_connection(block_id, BlockConnection::Prev2)
+        } else {
+            Ok(Some(prev1))
+        }
+    }
+}
+
+/// Returns the index of the last item with `gen_utime <= utime`.
+///
+/// NOTE: items must be sorted by `gen_utime`
+fn last_at_or_before<T, F>(items: &[T], utime: u32, mut gen_utime: F) -> Result<Option<usize>>
+where
+    F: FnMut(&T) -> Result<u32>,
+{
+    let (mut left, mut right) = (0, items.len());
+    while left < right {
+        let mid = left + (right - left) / 2;
+        if gen_utime(&items[mid])? <= utime {
+            left = mid + 1;
+        } else {
+            right = mid;
+        }
+    }
+    Ok(left.checked_sub(1))
+}
+
+/// Bisects seqnos in `from_seq_no..to_seq_no` and returns the latest found item
+/// with `gen_utime <= utime` along with its `gen_utime`.
+///
+/// `item_at` returns `None` for missing items. Bisection stops at the first
+/// missing item, so the result could be any earlier item before the required one.
+///
+/// NOTE: `gen_utime` must not decrease with seqno
+fn bisect_at_or_before<T, F>(
+    from_seq_no: u32,
+    to_seq_no: u32,
+    utime: u32,
+    mut item_at: F,
+) -> Result<Option<(T, u32)>>
+where
+    F: FnMut(u32) -> Result<Option<(T, u32)>>,
+{
+    let mut result = None;
+
+    let (mut left, mut right) = (from_seq_no, to_seq_no);
+    while left < right {
+        let mid = left + (right - left) / 2;
+        match item_at(mid)? {
+            Some((item, item_utime)) if item_utime <= utime => {
+                result = Some((item, item_utime));
+                left = mid + 1;
+            }
+            Some(_) => right = mid,
+            None => break,
+        }
+    }
+
+    Ok(result)
+}
+
+/// Walks the chain from the `start` item and returns the latest item
+/// with `gen_utime <= utime` along with its `gen_utime`.
+///
+/// NOTE: `gen_utime` must not decrease along the chain
+fn find_latest_at_or_before<T, F, N, P>(
+    start: T,
+    utime: u32,
+    mut gen_utime: F,
+    mut next: N,
+    mut prev: P,
+) -> Result<Option<(T, u32)>>
+where
+    F: FnMut(&T) -> Result<u32>,
+    N: FnMut(&T) -> Result<Option<T>>,
+    P: FnMut(&T) -> Result<Option<T>>,
+{
+    let mut current = start;
+    let mut current_utime = gen_utime(&current)?;
+
+    while current_utime > utime {
+        current = match prev(&current)? {
+            Some(prev) => prev,
+            None => return Ok(None),
+        };
+        current_utime = gen_utime(&current)?;
+    }
+
+    while let Some(next) = next(&current)? {
+        let next_utime = gen_utime(&next)?;
+        if next_utime > utime {
+            break;
+        }
+        current = next;
+        current_utime = next_utime;
+    }
+
+    Ok(Some((current, current_utime)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rustc_hash::FxHashMap;
+
+    use super::*;
+    use crate::db::{BlockHandle, BlockMetaData};
+    use crate::test_helpers::*;
+    use crate::test_util::SyntheticChain;
+
+    /// Chain of blocks with `gen_utime` by index
+    const CHAIN: [u32; 8] = [100, 105, 110, 110, 120, 130, 130, 140];
+    /// Indices of the key blocks
+    const KEY_BLOCKS: [usize; 3] = [0, 3, 6];
+
+    fn find(utime: u32) -> Option<(usize, u32)> {
+        let key_blocks = last_at_or_before(&KEY_BLOCKS, utime, |&i| Ok(CHAIN[i])).unwrap();
+        let start = KEY_BLOCKS[key_blocks.unwrap_or_default()];
+
+        find_latest_at_or_before(
+            start,
+            utime,
+            |&i| Ok(CHAIN[i]),
+            |&i| Ok((i + 1 < CHAIN.len()).then(|| i + 1)),
+            |&i| Ok(i.checked_sub(1)),
+        )
+        .unwrap()
+    }
+
+    /// Stores applied masterchain blocks `(seq_no, gen_utime, shard tops)` connected in order
+    async fn store_applied_mc_blocks(
+        engine: &Engine,
+        blocks: &[(u32, u32, &[ton_block::BlockIdExt])],
+    ) {
+        let connections = engine.db.block_connection_storage();
+
+        let mut prev_mc_handle: Option<Arc<BlockHandle>> = None;
+        for &(seq_no, gen_utime, shard_tops) in blocks {
+            let (id, data) = make_mc_block_with_shards(seq_no, shard_tops);
+            let block = BlockStuff::deserialize_checked(id.clone(), &data).unwrap();
+            let meta_data = BlockMetaData {
+                is_key_block: false,
+                gen_utime,
+                mc_ref_seqno: Some(seq_no),
+            };
+            let mc_handle = engine
+                .db
+                .block_storage()
+                .store_block_data(&BlockStuffAug::new(block, data), meta_data)
+                .await
+                .unwrap()
+                .handle;
+            if let Some(prev_mc_handle) = &prev_mc_handle {
+                connections
+                    .store_connection(prev_mc_handle, BlockConnection::Next1, &id)
+                    .unwrap();
+                connections
+                    .store_connection(&mc_handle, BlockConnection::Prev1, prev_mc_handle.id())
+                    .unwrap();
+            }
+            engine
+                .db
+                .block_handle_storage()
+                .store_block_applied(&mc_handle)
+                .unwrap();
+            engine.db.node_state().store_last_mc_block_id(&id).unwrap();
+            prev_mc_handle = Some(mc_handle);
+        }
+    }
+
+    #[test]
+    fn key_blocks_search() {
+        let find = |utime| last_at_or_before(&KEY_BLOCKS, utime, |&i| Ok(CHAIN[i])).unwrap();
+        assert_eq!(find(0), None);
+        assert_eq!(find(100), Some(0));
+        assert_eq!(find(115), Some(1));
+        assert_eq!(find(130), Some(2));
+        assert_eq!(find(u32::MAX), Some(2));
+        assert_eq!(
+            last_at_or_before(&[] as &[usize], 100, |_| Ok(0)).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn blocks_by_time() {
+        // Before the first block
+        assert_eq!(find(0), None);
+        assert_eq!(find(99), None);
+
+        // Exact match
+        assert_eq!(find(100), Some((0, 100)));
+        assert_eq!(find(105), Some((1, 105)));
+
+        // Between blocks
+        assert_eq!(find(107), Some((1, 105)));
+        assert_eq!(find(125), Some((4, 120)));
+
+        // The latest of blocks with the same time
+        assert_eq!(find(110), Some((3, 110)));
+        assert_eq!(find(135), Some((6, 130)));
+
+        // After the last block
+        assert_eq!(find(140), Some((7, 140)));
+        assert_eq!(find(u32::MAX), Some((7, 140)));
+    }
+
+    #[test]
+    fn walks_back_from_newer_start() {
+        let result = find_latest_at_or_before(
+            6,
+            112,
+            |&i| Ok(CHAIN[i]),
+            |&i| Ok((i + 1 < CHAIN.len()).then(|| i + 1)),
+            |&i| Ok(i.checked_sub(1)),
+        )
+        .unwrap();
+        assert_eq!(result, Some((3, 110)));
+    }
+
+    #[test]
+    fn bisects_seq_nos() {
+        let bisect = |utime, missing: Option<u32>| {
+            bisect_at_or_before(0, CHAIN.len() as u32, utime, |seq_no| {
+                Ok((Some(seq_no) != missing).then(|| (seq_no, CHAIN[seq_no as usize])))
+            })
+            .unwrap()
+        };
+
+        assert_eq!(bisect(99, None), None);
+        assert_eq!(bisect(100, None), Some((0, 100)));
+        assert_eq!(bisect(110, None), Some((3, 110)));
+        assert_eq!(bisect(125, None), Some((4, 120)));
+        assert_eq!(bisect(u32::MAX, None), Some((7, 140)));
+
+        // Bisection stops at the missing item with the closest found one
+        assert_eq!(bisect(125, Some(4)), None);
+        assert_eq!(bisect(135, Some(6)), Some((4, 120)));
+    }
+
+    #[tokio::test]
+    async fn masterchain_blocks_by_time() {
+        const ZERO_STATE_UTIME: u32 = 1000;
+
+        let chain = SyntheticChain::generate(16, ZERO_STATE_UTIME, |seq_no| {
+            ZERO_STATE_UTIME + seq_no * 10
+        })
+        .unwrap();
+
+        let dir = TempDir::new("masterchain_blocks_by_time");
+        chain.create_db(dir.path()).await.unwrap();
+        let engine = test_engine_with_global_config(&dir, chain.global_config(), Vec::new()).await;
+
+        let find = |utime| {
+            engine
+                .masterchain_block_at(utime)
+                .unwrap()
+                .map(|(id, utime)| (id.seq_no, utime))
+        };
+
+        // Before the first block
+        assert_eq!(find(0), None);
+        assert_eq!(find(ZERO_STATE_UTIME - 1), None);
+
+        // Zerostate
+        assert_eq!(find(ZERO_STATE_UTIME), Some((0, ZERO_STATE_UTIME)));
+
+        // Exact match and between blocks
+        assert_eq!(find(1050), Some((5, 1050)));
+        assert_eq!(find(1075), Some((7, 1070)));
+
+        // After the last block
+        assert_eq!(find(1160), Some((16, 1160)));
+        assert_eq!(find(u32::MAX), Some((16, 1160)));
+    }
+
+    #[tokio::test]
+    async fn chain_state_at_utime_on_synthetic_chain() {
+        const ZERO_STATE_UTIME: u32 = 1000;
+
+        let chain = SyntheticChain::generate(16, ZERO_STATE_UTIME, |seq_no| {
+            ZERO_STATE_UTIME + seq_no * 10
+        })
+        .unwrap();
+
+        let dir = TempDir::new("chain_state_at_utime");
+        chain.create_db(dir.path()).await.unwrap();
+        let engine = test_engine_with_global_config(&dir, chain.global_config(), Vec::new()).await;
+
+        for (utime, mc_seq_no) in [
+            // Exact match
+            (1050, 5),
+            // Between blocks
+            (1075, 7),
+            // After the last block
+            (1160, 16),
+            (u32::MAX, 16),
+        ] {
+            let (mc_block_id, top_blocks) = engine.chain_state_at_utime(utime).await.unwrap();
+            assert_eq!(&mc_block_id, &chain.mc_block(mc_seq_no).unwrap().id);
+            assert_eq!(top_blocks.mc_block, mc_block_id);
+
+            // Shard block committed by the masterchain block is the shard top
+            let shard_block_id = &chain.shard_block(mc_seq_no).unwrap().id;
+            assert_eq!(
+                top_blocks.shard_heights,
+                FxHashMap::from_iter([(shard_block_id.shard_id.clone(), shard_block_id.seq_no)])
+            );
+        }
+
+        // Before the first block
+        let error = engine
+            .chain_state_at_utime(ZERO_STATE_UTIME - 1)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error.downcast::<EngineError>().unwrap(),
+            EngineError::BlockNotFound
+        ));
+    }
+
+    #[tokio::test]
+    async fn chain_state_at_utime_lists_all_shard_tops() {
+        let dir = TempDir::new("chain_state_at_utime_shards");
+        let engine = test_engine(&dir, Vec::new()).await;
+
+        let basechain = ton_block::ShardIdent::full(ton_block::BASE_WORKCHAIN_ID);
+        let workchain = ton_block::ShardIdent::full(1);
+
+        // The second workchain appears in the second masterchain block
+        let first_tops = [block_id(basechain.clone(), 1)];
+        let second_tops = [
+            block_id(basechain.clone(), 5),
+            block_id(workchain.clone(), 7),
+        ];
+        store_applied_mc_blocks(
+            &engine,
+            &[(1, 100, &first_tops[..]), (2, 200, &second_tops[..])],
+        )
+        .await;
+
+        let chain_state = |utime| {
+            let engine = engine.clone();
+            async move {
+                let (mc_block_id, top_blocks) = engine.chain_state_at_utime(utime).await.unwrap();
+                assert_eq!(top_blocks.mc_block, mc_block_id);
+                (mc_block_id.seq_no, top_blocks.shard_heights)
+            }
+        };
+
+        assert_eq!(
+            chain_state(150).await,
+            (1, FxHashMap::from_iter([(basechain.clone(), 1)]))
+        );
+        let second = (2, FxHashMap::from_iter([(basechain, 5), (workchain, 7)]));
+        assert_eq!(chain_state(200).await, second);
+        assert_eq!(chain_state(u32::MAX).await, second);
+
+        // Before the first masterchain block
+        assert!(engine.chain_state_at_utime(50).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn shard_blocks_by_time_through_split_and_merge() {
+        let dir = TempDir::new("shard_blocks_by_time");
+        let engine = test_engine(&dir, Vec::new()).await;
+
+        let full = ton_block::ShardIdent::full(ton_block::BASE_WORKCHAIN_ID);
+        let (left, right) = full.split().unwrap();
+
+        // Ids with distinct hashes for the blocks with the same seqno
+        let shard_block =
+            |shard_id: &ton_block::ShardIdent, seq_no: u32, tag: u8| ton_block::BlockIdExt {
+                shard_id: shard_id.clone(),
+                seq_no,
+                root_hash: ton_types::UInt256::from([tag; 32]),
+                file_hash: ton_types::UInt256::from([tag; 32]),
+            };
+
+        // 1 -> split -> (2L, 2R) -> (3L, 3R) -> merge -> 4
+        let before_split = shard_block(&full, 1, 1);
+        let left_2 = shard_block(&left, 2, 2);
+        let right_2 = shard_block(&right, 2, 3);
+        let left_3 = shard_block(&left, 3, 4);
+        let right_3 = shard_block(&right, 3, 5);
+        let after_merge = shard_block(&full, 4, 6);
+
+        let block_handle_storage = engine.db.block_handle_storage();
+        let connections = engine.db.block_connection_storage();
+
+        let mut handles = Vec::new();
+        for (id, gen_utime) in [
+            (&before_split, 100),
+            (&left_2, 110),
+            (&right_2, 110),
+            (&left_3, 120),
+            (&right_3, 120),
+            (&after_merge, 130),
+        ] {
+            let meta_data = BlockMetaData {
+                is_key_block: false,
+                gen_utime,
+                mc_ref_seqno: None,
+            };
+            let (handle, _) = block_handle_storage
+                .create_or_load_handle(id, meta_data)
+                .unwrap();
+            handles.push(handle);
+        }
+        let handle =
+            |id: &ton_block::BlockIdExt| handles.iter().find(|handle| handle.id() == id).unwrap();
+
+        for (from, direction, to) in [
+            (&before_split, BlockConnection::Next1, &left_2),
+            (&before_split, BlockConnection::Next2, &right_2),
+            (&left_2, BlockConnection::Prev1, &before_split),
+            (&right_2, BlockConnection::Prev1, &before_split),
+            (&left_2, BlockConnection::Next1, &left_3),
+            (&right_2, BlockConnection::Next1, &right_3),
+            (&left_3, BlockConnection::Prev1, &left_2),
+            (&right_3, BlockConnection::Prev1, &right_2),
+            (&left_3, BlockConnection::Next1, &after_merge),
+            (&right_3, BlockConnection::Next1, &after_merge),
+            (&after_merge, BlockConnection::Prev1, &left_3),
+            (&after_merge, BlockConnection::Prev2, &right_3),
+        ] {
+            connections
+                .store_connection(handle(from), direction, to)
+                .unwrap();
+        }
+
+        // Masterchain blocks before the split and right before the merge
+        store_applied_mc_blocks(
+            &engine,
+            &[
+                (1, 1, std::slice::from_ref(&before_split)),
+                (2, 125, std::slice::from_ref(&after_merge)),
+            ],
+        )
+        .await;
+
+        let left_account =
+            ton_block::MsgAddressInt::with_standart(None, 0, [0x00; 32].into()).unwrap();
+        let right_account =
+            ton_block::MsgAddressInt::with_standart(None, 0, [0xff; 32].into()).unwrap();
+        let find = |address: &ton_block::MsgAddressInt, utime| {
+            let engine = engine.clone();
+            let address = address.clone();
+            async move { engine.shard_block_at(&address, utime).await.unwrap() }
+        };
+
+        // Before the first masterchain block
+        assert_eq!(find(&left_account, 0).await, None);
+
+        // Before the split
+        assert_eq!(
+            find(&left_account, 105).await,
+            Some((before_split.clone(), 100))
+        );
+        assert_eq!(
+            find(&right_account, 105).await,
+            Some((before_split.clone(), 100))
+        );
+
+        // Forward through the split
+        assert_eq!(find(&left_account, 115).await, Some((left_2.clone(), 110)));
+        assert_eq!(
+            find(&right_account, 115).await,
+            Some((right_2.clone(), 110))
+        );
+
+        // Backward through the merge
+        assert_eq!(find(&left_account, 127).await, Some((left_3.clone(), 120)));
+        assert_eq!(find(&right_account, 127).await, Some((right_3, 120)));
+
+        // After the last block
+        assert_eq!(
+            find(&left_account, u32::MAX).await,
+            Some((after_merge.clone(), 130))
+        );
+        assert_eq!(
+            find(&right_account, u32::MAX).await,
+            Some((after_merge, 130))
+        );
+    }
+}
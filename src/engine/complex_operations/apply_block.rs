@@ -3,13 +3,19 @@
 /// Changes:
 /// - replaced old `failure` crate with `anyhow`
 /// - slightly changed application of blocks
+/// - added timings of application phases
 ///
 use std::sync::Arc;
+#[cfg(feature = "apply-metrics")]
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
 use futures_util::future::{BoxFuture, FutureExt};
 
-use crate::db::{BlockConnection, BlockHandle};
+#[cfg(feature = "apply-metrics")]
+use serde::{Deserialize, Serialize};
+
+use crate::db::{lock_owner_scope, BlockConnection, BlockHandle};
 use crate::engine::Engine;
 use crate::utils::*;
 
@@ -36,24 +42,60 @@ pub fn apply_block<'a>(
         ensure_prev_blocks_downloaded(engine, &prev1_id, &prev2_id, mc_seq_no, pre_apply, depth)
             .await?;
 
+        if !pre_apply {
+            engine.check_block_time(
+                handle.id(),
+                handle.meta().gen_utime(),
+                &prev1_id,
+                &prev2_id,
+            )?;
+        }
+
+        // NOTE: time spent on previous blocks is not included
+        let mut timer = ApplyBlockTimer::new();
+
         let shard_state = if handle.meta().has_state() {
-            engine.load_state(handle.id()).await?
+            let state = engine.load_state(handle.id()).await?;
+            timer.finish_phase(ApplyBlockPhase::StateUpdate);
+            state
         } else {
-            compute_and_store_shard_state(engine, handle, block, &prev1_id, &prev2_id).await?
+            compute_and_store_shard_state(engine, handle, block, &prev1_id, &prev2_id, &mut timer)
+                .await?
         };
 
         if !pre_apply {
             update_block_connections(engine, handle, &prev1_id, &prev2_id)?;
+
+            // NOTE: index entries are written along with the applied flag,
+            // so that the index never references unapplied blocks
+            let mut applied_batch = rocksdb::WriteBatch::default();
+            if engine.index_messages {
+                engine
+                    .db
+                    .message_index()
+                    .index_block_batch(block, &mut applied_batch)?;
+            }
+            timer.finish_phase(ApplyBlockPhase::IndexUpdate);
+
             engine
-                .notify_subscribers_with_block(handle, block, &shard_state)
+                .notify_subscribers_with_block(handle, block, &shard_state, mc_seq_no)
                 .await?;
+            timer.finish_phase(ApplyBlockPhase::Notify);
 
             if block.id().is_masterchain() {
-                engine.store_last_applied_mc_block_id(block.id())?;
+                engine.store_last_applied_mc_block_id(block.id(), "applied")?;
 
                 // TODO: update shard blocks
 
-                engine.set_applied(handle, mc_seq_no).await?;
+                engine
+                    .set_applied_with(handle, mc_seq_no, applied_batch)
+                    .await?;
+
+                if handle.is_key_block() {
+                    engine
+                        .notify_config_changes(handle, block, Some(&shard_state))
+                        .await;
+                }
 
                 let id = handle.id().clone();
                 engine
@@ -61,8 +103,13 @@ pub fn apply_block<'a>(
                     .do_or_wait(&prev1_id, None, async move { Ok(id) })
                     .await?;
             } else {
-                engine.set_applied(handle, mc_seq_no).await?;
+                engine
+                    .set_applied_with(handle, mc_seq_no, applied_batch)
+                    .await?;
             }
+            timer.finish_phase(ApplyBlockPhase::IndexUpdate);
+
+            timer.report(engine, block.id().is_masterchain());
         }
 
         Ok(())
@@ -80,9 +127,20 @@ async fn ensure_prev_blocks_downloaded(
 ) -> Result<()> {
     match prev2_id {
         Some(prev2_id) => {
+            // NOTE: both branches are polled by one task, but hold the locks independently
             let futures = vec![
-                engine.download_and_apply_block(prev1_id, mc_seq_no, pre_apply, depth + 1),
-                engine.download_and_apply_block(prev2_id, mc_seq_no, pre_apply, depth + 1),
+                lock_owner_scope(engine.download_and_apply_block(
+                    prev1_id,
+                    mc_seq_no,
+                    pre_apply,
+                    depth + 1,
+                )),
+                lock_owner_scope(engine.download_and_apply_block(
+                    prev2_id,
+                    mc_seq_no,
+                    pre_apply,
+                    depth + 1,
+                )),
             ];
 
             futures_util::future::join_all(futures)
@@ -146,6 +204,7 @@ async fn compute_and_store_shard_state(
     block: &BlockStuff,
     prev1_id: &ton_block::BlockIdExt,
     prev2_id: &Option<ton_block::BlockIdExt>,
+    timer: &mut ApplyBlockTimer,
 ) -> Result<Arc<ShardStateStuff>> {
     enum RefMcStateHandles {
         Split(Arc<RefMcStateHandle>, Arc<RefMcStateHandle>),
@@ -194,11 +253,161 @@ async fn compute_and_store_shard_state(
         }
     })
     .await??;
+    timer.finish_phase(ApplyBlockPhase::StateUpdate);
 
     engine.store_state(handle, &shard_state).await?;
+    timer.finish_phase(ApplyBlockPhase::CellWrites);
+
     Ok(shard_state)
 }
 
+#[cfg(feature = "apply-metrics")]
+/// Block application timings by phase, collected separately
+/// for the masterchain and shard blocks.
+///
+/// NOTE: proofs are checked before blocks are applied, so they are not included
+#[derive(Default)]
+pub struct ApplyBlockMetrics {
+    masterchain: ApplyBlockPhaseHistograms,
+    shardchain: ApplyBlockPhaseHistograms,
+}
+
+#[cfg(feature = "apply-metrics")]
+impl ApplyBlockMetrics {
+    pub fn snapshot(&self, reset: bool) -> ApplyBlockMetricsSnapshot {
+        ApplyBlockMetricsSnapshot {
+            masterchain: self.masterchain.snapshot(reset),
+            shardchain: self.shardchain.snapshot(reset),
+        }
+    }
+
+    fn record(
+        &self,
+        is_masterchain: bool,
+        phases: &[Duration; ApplyBlockPhase::COUNT],
+        total: Duration,
+    ) {
+        let histograms = if is_masterchain {
+            &self.masterchain
+        } else {
+            &self.shardchain
+        };
+
+        for (histogram, duration) in histograms.phases.iter().zip(phases) {
+            histogram.record(*duration);
+        }
+        histograms.total.record(total);
+    }
+}
+
+#[cfg(feature = "apply-metrics")]
+#[derive(Default)]
+struct ApplyBlockPhaseHistograms {
+    phases: [Histogram; ApplyBlockPhase::COUNT],
+    total: Histogram,
+}
+
+#[cfg(feature = "apply-metrics")]
+impl ApplyBlockPhaseHistograms {
+    fn snapshot(&self, reset: bool) -> ApplyBlockPhasesSnapshot {
+        let phase = |phase: ApplyBlockPhase| self.phases[phase as usize].snapshot(reset);
+        ApplyBlockPhasesSnapshot {
+            state_update: phase(ApplyBlockPhase::StateUpdate),
+            cell_writes: phase(ApplyBlockPhase::CellWrites),
+            index_update: phase(ApplyBlockPhase::IndexUpdate),
+            notify: phase(ApplyBlockPhase::Notify),
+            total: self.total.snapshot(reset),
+        }
+    }
+}
+
+#[cfg(feature = "apply-metrics")]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ApplyBlockMetricsSnapshot {
+    pub masterchain: ApplyBlockPhasesSnapshot,
+    pub shardchain: ApplyBlockPhasesSnapshot,
+}
+
+#[cfg(feature = "apply-metrics")]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ApplyBlockPhasesSnapshot {
+    /// Loading or computing the new shard state
+    pub state_update: HistogramSnapshot,
+    /// Storing the new shard state cells
+    pub cell_writes: HistogramSnapshot,
+    /// Updating block connections and handle flags
+    pub index_update: HistogramSnapshot,
+    /// Notifying subscribers
+    pub notify: HistogramSnapshot,
+    pub total: HistogramSnapshot,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum ApplyBlockPhase {
+    StateUpdate,
+    CellWrites,
+    IndexUpdate,
+    Notify,
+}
+
+#[cfg(feature = "apply-metrics")]
+impl ApplyBlockPhase {
+    const COUNT: usize = 4;
+}
+
+/// Measures consecutive phases of the block application.
+///
+/// NOTE: does nothing without the `apply-metrics` feature
+#[cfg(feature = "apply-metrics")]
+struct ApplyBlockTimer {
+    started_at: Instant,
+    phase_started_at: Instant,
+    phases: [Duration; ApplyBlockPhase::COUNT],
+}
+
+#[cfg(feature = "apply-metrics")]
+impl ApplyBlockTimer {
+    fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            started_at: now,
+            phase_started_at: now,
+            phases: Default::default(),
+        }
+    }
+
+    /// Adds the time since the end of the previous phase to the specified phase
+    fn finish_phase(&mut self, phase: ApplyBlockPhase) {
+        let now = Instant::now();
+        self.phases[phase as usize] += now - self.phase_started_at;
+        self.phase_started_at = now;
+    }
+
+    fn report(self, engine: &Engine, is_masterchain: bool) {
+        let total = self.phase_started_at - self.started_at;
+        engine
+            .apply_block_metrics
+            .record(is_masterchain, &self.phases, total);
+    }
+}
+
+#[cfg(not(feature = "apply-metrics"))]
+struct ApplyBlockTimer;
+
+#[cfg(not(feature = "apply-metrics"))]
+impl ApplyBlockTimer {
+    #[inline(always)]
+    fn new() -> Self {
+        Self
+    }
+
+    #[inline(always)]
+    fn finish_phase(&mut self, _: ApplyBlockPhase) {}
+
+    #[inline(always)]
+    fn report(self, _: &Engine, _: bool) {}
+}
+
 #[derive(thiserror::Error, Debug)]
 enum ApplyBlockError {
     #[error("Block id mismatch")]
@@ -210,3 +419,52 @@ enum ApplyBlockError {
     #[error("Invalid masterchain block sequence")]
     InvalidMasterchainBlockSequence,
 }
+
+#[cfg(all(test, feature = "apply-metrics"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phases_sum_to_total() {
+        let metrics = ApplyBlockMetrics::default();
+
+        for _ in 0..4 {
+            let mut timer = ApplyBlockTimer::new();
+            for (phase, ms) in [
+                (ApplyBlockPhase::StateUpdate, 5),
+                (ApplyBlockPhase::CellWrites, 3),
+                (ApplyBlockPhase::IndexUpdate, 1),
+                (ApplyBlockPhase::Notify, 2),
+                (ApplyBlockPhase::IndexUpdate, 1),
+            ] {
+                std::thread::sleep(Duration::from_millis(ms));
+                timer.finish_phase(phase);
+            }
+            let total = timer.phase_started_at - timer.started_at;
+            metrics.record(false, &timer.phases, total);
+        }
+
+        let snapshot = metrics.snapshot(true);
+        assert_eq!(snapshot.masterchain.total.count, 0);
+
+        let shardchain = &snapshot.shardchain;
+        assert_eq!(shardchain.total.count, 4);
+
+        let phases_sum = [
+            &shardchain.state_update,
+            &shardchain.cell_writes,
+            &shardchain.index_update,
+            &shardchain.notify,
+        ]
+        .iter()
+        .map(|histogram| histogram.sum_us)
+        .sum::<u64>();
+
+        // NOTE: each value is truncated to microseconds
+        let total = shardchain.total.sum_us;
+        assert!(total >= 12 * 4 * 1000);
+        assert!(phases_sum <= total && total - phases_sum <= 4 * ApplyBlockPhase::COUNT as u64);
+
+        assert_eq!(metrics.snapshot(false).shardchain.total.count, 0);
+    }
+}
@@ -1,3 +1,4 @@
+use std::future::Future;
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -6,8 +7,8 @@ use arc_swap::ArcSwapOption;
 use broxus_util::now;
 use futures_util::future::BoxFuture;
 use futures_util::stream::FuturesOrdered;
-use futures_util::{FutureExt, StreamExt};
-use tokio::sync::mpsc;
+use futures_util::{FutureExt, StreamExt, TryStreamExt};
+use tokio::sync::{mpsc, Semaphore};
 use tokio_util::sync::CancellationToken;
 
 use crate::db::*;
@@ -31,6 +32,15 @@ pub async fn cold_boot(engine: &Arc<Engine>) -> Result<ton_block::BlockIdExt> {
     // Choose the latest key block with persistent state
     let last_key_block = choose_key_block(engine)?;
 
+    // NOTE: peers serve states only for persistent key blocks, so the state at
+    // the sync start is built by applying blocks after the chosen key block
+    if let Some(seqno) = engine.sync_options.sync_from_seqno {
+        if seqno > last_key_block.id().seq_no {
+            tracing::info!(seqno, "skipping notifications before the sync start");
+            engine.store_notify_from_seqno(seqno)?;
+        }
+    }
+
     if last_key_block.id().seq_no == 0 {
         // If the last suitable key block is zerostate, we must download all other zerostates
         let zero_state = engine.load_mc_zero_state().await?;
@@ -69,7 +79,7 @@ async fn prepare_prev_key_block(engine: &Arc<Engine>) -> Result<PrevKeyBlock> {
                     return Err(ColdBootError::StartingFromNonKeyBlock.into());
                 }
 
-                let proof = block_storage.load_block_proof(&handle, false).await?;
+                let proof = engine.load_best_block_proof(&handle).await?;
                 return Ok(PrevKeyBlock::KeyBlock {
                     handle,
                     proof: Box::new(proof),
@@ -90,8 +100,8 @@ async fn prepare_prev_key_block(engine: &Arc<Engine>) -> Result<PrevKeyBlock> {
             }
         } else {
             // Previous key block is also a key block so it must have proof
-            let proof = block_storage
-                .load_block_proof(&prev_key_block, false)
+            let proof = engine
+                .load_best_block_proof(&prev_key_block)
                 .await
                 .context("Failed to found prev key block proof")?;
             PrevKeyBlock::KeyBlock {
@@ -151,8 +161,13 @@ async fn download_key_blocks(engine: &Arc<Engine>, mut prev_key_block: PrevKeyBl
 
     let good_peer = Arc::new(ArcSwapOption::empty());
     tokio::spawn({
+        let engine = engine.clone();
         let mc_client = engine.masterchain_client.clone();
         let good_peer = good_peer.clone();
+        let prefetch_limiter = match engine.sync_options.key_block_proofs_prefetch {
+            0 => None,
+            limit => Some(Arc::new(Semaphore::new(limit))),
+        };
         async move {
             tokio::pin! { let dropped = signal.cancelled(); }
 
@@ -179,7 +194,15 @@ async fn download_key_blocks(engine: &Arc<Engine>, mut prev_key_block: PrevKeyBl
                         // Send result back to the main task
                         Ok((ids, neighbour)) => {
                             good_peer.store(Some(neighbour.clone()));
-                            if ids_tx.send((ids, neighbour)).is_err() {
+
+                            // Start downloading proofs while the previous batch is verified
+                            let prefetched = prefetch_key_block_proofs(
+                                &engine,
+                                &ids,
+                                &neighbour,
+                                prefetch_limiter.as_ref(),
+                            );
+                            if ids_tx.send((ids, neighbour, prefetched)).is_err() {
                                 return;
                             }
                             break 'inner;
@@ -208,12 +231,14 @@ async fn download_key_blocks(engine: &Arc<Engine>, mut prev_key_block: PrevKeyBl
     tasks_tx.send(prev_handle.id().clone()).ok();
 
     let node_state = engine.db.node_state();
-    while let Some((ids, neighbour)) = ids_rx.recv().await {
+    let mut empty_responses = 0;
+    while let Some((ids, neighbour, prefetched)) = ids_rx.recv().await {
         match ids.last() {
             // Start downloading next key blocks in background
             Some(block_id) => {
                 tracing::debug!(last_key_block_id = %block_id.display());
                 tasks_tx.send(block_id.clone()).ok();
+                empty_responses = 0;
             }
             // Allow empty response for syncing from zerostate
             None if prev_handle.id().seq_no == 0
@@ -223,6 +248,18 @@ async fn download_key_blocks(engine: &Arc<Engine>, mut prev_key_block: PrevKeyBl
             }
             // Retry request in case of empty response
             None => {
+                // Peers consistently report that there are no newer key blocks
+                empty_responses += 1;
+                if empty_responses >= MAX_EMPTY_KEY_BLOCK_RESPONSES {
+                    // NOTE: the next key block is expected at least every two steps
+                    let expected_utime = prev_handle.meta().gen_utime() + 2 * KEY_BLOCK_UTIME_STEP;
+                    let skew = (now() as i64 - expected_utime as i64).max(0);
+                    if check_clock_skew(engine, skew)? {
+                        // NOTE: there is no point in waiting for the key block which doesn't exist
+                        break;
+                    }
+                }
+
                 // Reset good peer, because empty ids is suspicious
                 good_peer.store(None);
                 tasks_tx.send(prev_handle.id().clone()).ok();
@@ -232,7 +269,8 @@ async fn download_key_blocks(engine: &Arc<Engine>, mut prev_key_block: PrevKeyBl
 
         // Download key block proofs in parallel
         // TODO: Invalidate queue (tasks_*) in case of bad block proof
-        let mut stream = BlockProofStream::new(engine, &mut prev_key_block, &ids, &neighbour);
+        let mut stream =
+            BlockProofStream::new(engine, &mut prev_key_block, &ids, &neighbour, prefetched);
 
         // Process each key block sequentially
         while let Some((handle, proof)) = stream.next().await? {
@@ -241,6 +279,11 @@ async fn download_key_blocks(engine: &Arc<Engine>, mut prev_key_block: PrevKeyBl
             let prev_utime = prev_handle.meta().gen_utime();
             if is_persistent_state(block_utime, prev_utime) {
                 node_state.store_init_mc_block_id(handle.id())?;
+                engine.audit_log.record(
+                    AuditLogEventKind::InitMcBlock,
+                    Some(handle.id()),
+                    "persistent state key block",
+                );
             }
 
             // Update stream context
@@ -254,6 +297,9 @@ async fn download_key_blocks(engine: &Arc<Engine>, mut prev_key_block: PrevKeyBl
         let last_utime = prev_handle.meta().gen_utime();
         let current_utime = now();
 
+        // Key blocks from the future
+        check_clock_skew(engine, (current_utime as i64 - last_utime as i64).min(0))?;
+
         pg.set_progress(last_utime.saturating_sub(sync_start_utime));
 
         tracing::debug!(
@@ -272,12 +318,72 @@ async fn download_key_blocks(engine: &Arc<Engine>, mut prev_key_block: PrevKeyBl
     Ok(())
 }
 
+/// Starts downloading key block proofs in background.
+///
+/// Returns an empty list if prefetch is disabled
+fn prefetch_key_block_proofs(
+    engine: &Arc<Engine>,
+    ids: &[ton_block::BlockIdExt],
+    neighbour: &Arc<Neighbour>,
+    limiter: Option<&Arc<Semaphore>>,
+) -> Vec<Option<PrefetchedBlockProof>> {
+    spawn_prefetch(ids, limiter, |block_id| {
+        let engine = engine.clone();
+        let neighbour = neighbour.clone();
+        async move {
+            engine
+                .download_block_proof(&block_id, true, None, Some(&neighbour))
+                .await
+        }
+    })
+}
+
+/// Spawns downloads in the order of ids, at most `limiter` permits at the same time.
+///
+/// Returns handles in the same order, downloads are cancelled when they are dropped
+fn spawn_prefetch<T, F, R>(
+    ids: &[ton_block::BlockIdExt],
+    limiter: Option<&Arc<Semaphore>>,
+    mut download: F,
+) -> Vec<Option<BoxFuture<'static, Result<T>>>>
+where
+    T: Send + 'static,
+    F: FnMut(ton_block::BlockIdExt) -> R,
+    R: Future<Output = Result<T>> + Send + 'static,
+{
+    let limiter = match limiter {
+        Some(limiter) => limiter,
+        None => return Vec::new(),
+    };
+
+    ids.iter()
+        .map(|block_id| {
+            let limiter = limiter.clone();
+            let download = download(block_id.clone());
+
+            // NOTE: download is cancelled when the handle is dropped
+            let (task, handle) = async move {
+                let _permit = limiter.acquire().await?;
+                download.await
+            }
+            .remote_handle();
+            tokio::spawn(task);
+
+            Some(handle.boxed())
+        })
+        .collect()
+}
+
+type PrefetchedBlockProof = BoxFuture<'static, Result<BlockProofStuffAug>>;
+
 /// Parallel block proof downloader
 struct BlockProofStream<'a> {
     engine: &'a Engine,
     prev_key_block: &'a mut PrevKeyBlock,
     ids: &'a [ton_block::BlockIdExt],
     neighbour: &'a Arc<Neighbour>,
+    /// Proofs downloaded in background, used only for the first attempt
+    prefetched: Vec<Option<PrefetchedBlockProof>>,
     futures: FuturesOrdered<BoxFuture<'a, (usize, Result<BlockProofStuffAug>)>>,
     index: usize,
 }
@@ -288,12 +394,14 @@ impl<'a> BlockProofStream<'a> {
         prev_key_block: &'a mut PrevKeyBlock,
         ids: &'a [ton_block::BlockIdExt],
         neighbour: &'a Arc<Neighbour>,
+        prefetched: Vec<Option<PrefetchedBlockProof>>,
     ) -> Self {
         Self {
             engine,
             prev_key_block,
             ids,
             neighbour,
+            prefetched,
             futures: Default::default(),
             index: 0,
         }
@@ -312,7 +420,7 @@ impl<'a> BlockProofStream<'a> {
 
         // Check whether block proof is already stored locally
         if let Some(handle) = block_handle_storage.load_handle(&block_id)? {
-            if let Ok(proof) = block_storage.load_block_proof(&handle, false).await {
+            if let Ok(proof) = self.engine.load_best_block_proof(&handle).await {
                 // Move index forward
                 self.index += 1;
                 return Ok(Some((handle, proof)));
@@ -368,35 +476,104 @@ impl<'a> BlockProofStream<'a> {
         // must be the same as `self.index` and so on
         let ids = self.ids.iter().enumerate().skip(self.index);
         self.futures = ids
-            .map(|(index, id)| {
-                self.engine
-                    .download_block_proof(id, true, None, Some(self.neighbour))
-                    .map(move |result| (index, result))
-                    .boxed()
-            })
+            .map(
+                |(index, id)| match self.prefetched.get_mut(index).and_then(Option::take) {
+                    Some(proof) => proof.map(move |result| (index, result)).boxed(),
+                    None => self
+                        .engine
+                        .download_block_proof(id, true, None, Some(self.neighbour))
+                        .map(move |result| (index, result))
+                        .boxed(),
+                },
+            )
             .collect();
     }
 }
 
+/// Reports the difference between the local clock and the time derived from
+/// the blocks received from peers (positive if the local clock is ahead).
+///
+/// Returns whether the clock is skewed or an error if the node must not boot with such clock
+fn check_clock_skew(engine: &Engine, skew: i64) -> Result<bool> {
+    if skew.abs() <= engine.sync_options.max_clock_skew_sec as i64 {
+        return Ok(false);
+    }
+
+    tracing::error!(
+        skew,
+        "LOCAL CLOCK IS SKEWED! Key blocks received from peers don't match the local time, \
+        persistent state selection could be wrong. Check the system time synchronization"
+    );
+
+    if engine.sync_options.refuse_boot_on_clock_skew {
+        return Err(ColdBootError::ClockSkew { skew }.into());
+    }
+    Ok(true)
+}
+
 /// Selectes the latest suitable key block with persistent state
+/// (not newer than `sync_from_seqno` if it is specified).
+///
+/// If `start_from_seqno` is specified, selects the oldest suitable key block
+/// with persistent state at or after it instead.
 fn choose_key_block(engine: &Engine) -> Result<Arc<BlockHandle>> {
     let block_handle_storage = engine.db.block_handle_storage();
-    let mut key_blocks = block_handle_storage
+
+    let key_blocks = block_handle_storage
         .key_blocks_iterator(KeyBlocksDirection::Backward)
         .map(|item| {
-            item.and_then(|block_id| {
+            let handle = item.and_then(|block_id| {
                 block_handle_storage
                     .load_handle(&block_id)?
                     .context("Key block handle not found")
-            })
-        })
-        .peekable();
+            })?;
+            let (seq_no, utime) = (handle.id().seq_no, handle.meta().gen_utime());
+            Ok((handle, seq_no, utime))
+        });
+
+    match select_key_block(engine, key_blocks)? {
+        Some(handle) => {
+            tracing::info!(block_id = %handle.id().display(), "found best key block handle");
+            Ok(handle)
+        }
+        None => Err(ColdBootError::PersistentShardStateNotFound.into()),
+    }
+}
+
+/// Selects the key block for the cold boot (see [`choose_key_block`]) from key blocks
+/// in reverse order (from the latest to the oldest) as `(key block, seqno, gen utime)`
+fn select_key_block<T, I>(engine: &Engine, key_blocks: I) -> Result<Option<T>>
+where
+    I: Iterator<Item = Result<(T, u32, u32)>>,
+{
+    let sync_from_seqno = engine.sync_options.sync_from_seqno;
+    if let Some(seqno) = sync_from_seqno {
+        tracing::info!(seqno, "searching key block for the sync start");
+    }
+    let start_from_seqno = engine.start_from_seqno;
+    if let Some(seqno) = start_from_seqno {
+        tracing::info!(seqno, "searching key block for the indexing start");
+    }
+    select_key_block_at(sync_from_seqno, start_from_seqno, now(), key_blocks)
+}
+
+pub(super) fn select_key_block_at<T, I>(
+    sync_from_seqno: Option<u32>,
+    start_from_seqno: Option<u32>,
+    now: u32,
+    key_blocks: I,
+) -> Result<Option<T>>
+where
+    I: Iterator<Item = Result<(T, u32, u32)>>,
+{
+    let mut best = None;
+
+    let mut key_blocks = key_blocks.peekable();
 
     // Iterate all key blocks in reverse order (from the latest to the oldest)
-    while let Some(handle) = key_blocks.next().transpose()? {
-        let handle_utime = handle.meta().gen_utime();
+    while let Some((key_block, seq_no, utime)) = key_blocks.next().transpose()? {
         let prev_utime = match key_blocks.peek() {
-            Some(Ok(prev_block)) => prev_block.meta().gen_utime(),
+            Some(Ok((_, _, prev_utime))) => *prev_utime,
             Some(Err(e)) => {
                 tracing::warn!("failed to load previous key block: {e:?}");
                 return Err(ColdBootError::FailedToLoadKeyBlock.into());
@@ -404,28 +581,37 @@ fn choose_key_block(engine: &Engine) -> Result<Arc<BlockHandle>> {
             None => 0,
         };
 
-        let is_persistent = prev_utime == 0 || is_persistent_state(handle_utime, prev_utime);
-        tracing::debug!(
-            seq_no = handle.id().seq_no,
-            is_persistent,
-            "new key block candidate",
-        );
+        let is_persistent = prev_utime == 0 || is_persistent_state(utime, prev_utime);
+        tracing::debug!(seq_no, is_persistent, "new key block candidate");
+
+        // All remaining key blocks are before the indexing start
+        if matches!(start_from_seqno, Some(seqno) if seq_no < seqno) {
+            break;
+        }
 
         // Skip not persistent or too new key blocks
-        if !is_persistent {
+        if matches!(sync_from_seqno, Some(seqno) if seq_no > seqno) {
+            tracing::debug!("ignoring state: after the sync start");
+            continue;
+        } else if !is_persistent {
             tracing::debug!("ignoring state: not persistent");
             continue;
-        } else if handle_utime + INTITAL_SYNC_TIME_SECONDS > now() {
+        } else if utime + INTITAL_SYNC_TIME_SECONDS > now {
             tracing::debug!("ignoring state: too new");
             continue;
         }
 
+        if start_from_seqno.is_some() {
+            // Continue searching for the oldest suitable key block
+            best = Some(key_block);
+            continue;
+        }
+
         // Use first suitable key block
-        tracing::info!(block_id = %handle.id().display(), "found best key block handle");
-        return Ok(handle);
+        return Ok(Some(key_block));
     }
 
-    Err(ColdBootError::PersistentShardStateNotFound.into())
+    Ok(best)
 }
 
 enum PrevKeyBlock {
@@ -452,37 +638,71 @@ impl PrevKeyBlock {
         engine: &Engine,
         next_proof: &BlockProofStuff,
     ) -> Result<BriefBlockInfo> {
-        let block_id = next_proof.id();
+        let anchor = match self {
+            Self::ZeroState { state, .. } => ProofAnchor::ZeroState(state),
+            Self::KeyBlock { proof, .. } => ProofAnchor::KeyBlock(proof),
+        };
+        check_key_block_proof(engine, anchor, next_proof)
+    }
+}
+
+/// Trusted data against which the next key block proof is checked
+pub(super) enum ProofAnchor<'a> {
+    ZeroState(&'a ShardStateStuff),
+    KeyBlock(&'a BlockProofStuff),
+}
 
-        let (virt_block, virt_block_info) = next_proof
-            .pre_check_block_proof()
-            .context("Failed to pre check block proof")?;
-        let res = BriefBlockInfo::from(&virt_block_info);
+pub(super) fn check_key_block_proof(
+    engine: &Engine,
+    anchor: ProofAnchor<'_>,
+    next_proof: &BlockProofStuff,
+) -> Result<BriefBlockInfo> {
+    check_key_block_proof_with(anchor, next_proof, |block_id, e| {
+        if engine.is_hard_fork(block_id) {
+            engine.on_hard_fork_accepted(block_id, "cold boot key block proof check", e);
+            true
+        } else {
+            false
+        }
+    })
+}
 
-        match self {
-            // Check block proof with zero state
-            PrevKeyBlock::ZeroState { state, .. } => {
-                check_with_master_state(next_proof, state, &virt_block, &virt_block_info)
-            }
-            // Check block proof with previous key block
-            PrevKeyBlock::KeyBlock { proof, .. } => {
-                check_with_prev_key_block_proof(next_proof, proof, &virt_block, &virt_block_info)
-            }
+/// Same as [`check_key_block_proof`], but the invalid proof is accepted
+/// only if `accept_invalid` returns `true` for it (e.g. for hard forks)
+pub(super) fn check_key_block_proof_with<F>(
+    anchor: ProofAnchor<'_>,
+    next_proof: &BlockProofStuff,
+    accept_invalid: F,
+) -> Result<BriefBlockInfo>
+where
+    F: FnOnce(&ton_block::BlockIdExt, &anyhow::Error) -> bool,
+{
+    let block_id = next_proof.id();
+
+    let (virt_block, virt_block_info) = next_proof
+        .pre_check_block_proof()
+        .context("Failed to pre check block proof")?;
+    let res = BriefBlockInfo::from(&virt_block_info);
+
+    match anchor {
+        // Check block proof with zero state
+        ProofAnchor::ZeroState(state) => {
+            check_with_master_state(next_proof, state, &virt_block, &virt_block_info)
+        }
+        // Check block proof with previous key block
+        ProofAnchor::KeyBlock(proof) => {
+            check_with_prev_key_block_proof(next_proof, proof, &virt_block, &virt_block_info)
         }
-        .or_else(|e| {
-            // Allow invalid proofs for hard forks
-            if engine.is_hard_fork(block_id) {
-                tracing::warn!(
-                    block_id = %block_id.display(),
-                    "received hard fork key block, ignoring proof"
-                );
-                Ok(())
-            } else {
-                Err(e)
-            }
-        })
-        .map(move |_| res)
     }
+    .or_else(|e| {
+        // Allow invalid proofs for hard forks
+        if accept_invalid(block_id, &e) {
+            Ok(())
+        } else {
+            Err(e)
+        }
+    })
+    .map(move |_| res)
 }
 
 async fn download_workchain_zero_state(
@@ -529,22 +749,25 @@ async fn download_start_blocks_and_states(
     );
 
     // Download and save blocks and states from other shards
-    for (_, block_id) in init_mc_block.shard_blocks()? {
-        if block_id.seq_no == 0 {
-            engine.download_zero_state(&block_id).await?;
-        } else {
-            download_block_with_state(
-                engine,
-                FullStateId {
-                    mc_block_id: mc_block_id.clone(),
-                    block_id,
-                },
-            )
-            .await?;
-        };
-    }
-
-    Ok(())
+    futures_util::stream::iter(init_mc_block.shard_blocks()?.into_values())
+        .map(|block_id| async move {
+            if block_id.seq_no == 0 {
+                engine.download_zero_state(&block_id).await?;
+            } else {
+                download_block_with_state(
+                    engine,
+                    FullStateId {
+                        mc_block_id: mc_block_id.clone(),
+                        block_id,
+                    },
+                )
+                .await?;
+            };
+            Ok::<_, anyhow::Error>(())
+        })
+        .buffer_unordered(engine.sync_options.parallel_state_downloads)
+        .try_collect::<()>()
+        .await
 }
 
 async fn download_block_with_state(
@@ -565,7 +788,9 @@ async fn download_block_with_state(
         Some(handle) => (block_storage.load_block_data(&handle).await?, handle),
         None => {
             let (block, proof, meta_data) = loop {
-                let (block, proof) = engine.download_block(&full_state_id.block_id, None).await?;
+                let (block, proof) = engine
+                    .download_block(&full_state_id.block_id, None, None)
+                    .await?;
                 match proof.pre_check_block_proof() {
                     Ok((_, block_info)) => {
                         let meta_data = BriefBlockInfo::from(&block_info).with_mc_seq_no(mc_seq_no);
@@ -599,16 +824,17 @@ async fn download_block_with_state(
 
     // Download block state
     if !handle.meta().has_state() {
-        let state_update = block.block().read_state_update()?;
+        let state_update = read_state_update(handle.id(), block.block())?;
 
         tracing::info!(block_id = %handle.id().display(), "downloading state");
         let shard_state = download_state(engine, full_state_id).await?;
         tracing::info!(block_id = %handle.id().display(), "downloaded state");
 
-        let state_hash = shard_state.root_cell().repr_hash();
-        if state_update.new_hash != state_hash {
-            return Err(ColdBootError::ShardStateHashMismatch.into());
-        }
+        check_state_hash(
+            handle.id(),
+            &state_update.new_hash,
+            &shard_state.root_cell().repr_hash(),
+        )?;
 
         engine.store_state(&handle, &shard_state).await?;
         engine
@@ -620,8 +846,38 @@ async fn download_block_with_state(
     Ok((handle, block))
 }
 
-const KEY_BLOCK_UTIME_STEP: u32 = 86400;
+/// Reads the state update of the block, treating a failure as a structurally invalid block
+fn read_state_update(
+    block_id: &ton_block::BlockIdExt,
+    block: &ton_block::Block,
+) -> Result<ton_block::MerkleUpdate, ColdBootError> {
+    block
+        .read_state_update()
+        .map_err(|e| ColdBootError::InvalidBlockStructure {
+            block_id: block_id.display().to_string(),
+            reason: e.to_string(),
+        })
+}
+
+fn check_state_hash(
+    block_id: &ton_block::BlockIdExt,
+    expected: &ton_types::UInt256,
+    actual: &ton_types::UInt256,
+) -> Result<(), ColdBootError> {
+    if expected != actual {
+        return Err(ColdBootError::ShardStateHashMismatch {
+            block_id: block_id.display().to_string(),
+            expected: expected.to_hex_string(),
+            actual: actual.to_hex_string(),
+        });
+    }
+    Ok(())
+}
+
+pub(super) const KEY_BLOCK_UTIME_STEP: u32 = 86400;
 const INTITAL_SYNC_TIME_SECONDS: u32 = 300;
+/// Number of empty responses for the next key blocks after which the local clock is checked
+const MAX_EMPTY_KEY_BLOCK_RESPONSES: usize = 5;
 
 #[derive(thiserror::Error, Debug)]
 enum ColdBootError {
@@ -631,8 +887,149 @@ enum ColdBootError {
     FailedToLoadKeyBlock,
     #[error("Base workchain info not found")]
     BaseWorkchainInfoNotFound,
-    #[error("Downloaded shard state hash mismatch")]
-    ShardStateHashMismatch,
+    #[error("Block {block_id} is structurally invalid: failed to read state update: {reason}")]
+    InvalidBlockStructure { block_id: String, reason: String },
+    #[error(
+        "Downloaded shard state hash mismatch for block {block_id} (expected: {expected}, actual: {actual})"
+    )]
+    ShardStateHashMismatch {
+        block_id: String,
+        expected: String,
+        actual: String,
+    },
     #[error("Persistent shard state not found")]
     PersistentShardStateNotFound,
+    #[error("Local clock is skewed by {skew} seconds")]
+    ClockSkew { skew: i64 },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_block_id() -> ton_block::BlockIdExt {
+        ton_block::BlockIdExt {
+            shard_id: ton_block::ShardIdent::masterchain(),
+            seq_no: 123,
+            root_hash: ton_types::UInt256::from([1; 32]),
+            file_hash: ton_types::UInt256::from([2; 32]),
+        }
+    }
+
+    #[test]
+    fn absent_state_update_is_invalid_block() {
+        let block_id = test_block_id();
+        let block = ton_block::Block::default();
+
+        let err = read_state_update(&block_id, &block).unwrap_err();
+        assert!(matches!(err, ColdBootError::InvalidBlockStructure { .. }));
+        assert!(err.to_string().contains(&block_id.display().to_string()));
+    }
+
+    #[test]
+    fn state_hash_mismatch_is_reported() {
+        let block_id = test_block_id();
+        let expected = ton_types::UInt256::from([3; 32]);
+        let actual = ton_types::UInt256::from([4; 32]);
+
+        assert!(check_state_hash(&block_id, &expected, &expected).is_ok());
+
+        let err = check_state_hash(&block_id, &expected, &actual).unwrap_err();
+        assert!(matches!(err, ColdBootError::ShardStateHashMismatch { .. }));
+        assert!(err.to_string().contains(&block_id.display().to_string()));
+    }
+
+    #[test]
+    fn select_oldest_key_block_after_start_seqno() {
+        const PERIOD: u32 = 1 << 17;
+
+        // Key blocks from the latest to the oldest as `(seqno, utime)`.
+        // Each block starts a new persistent state period except for 300
+        let key_blocks = [
+            (500, 5 * PERIOD),
+            (400, 4 * PERIOD),
+            (300, 3 * PERIOD + 1),
+            (200, 3 * PERIOD),
+            (100, 2 * PERIOD),
+            (1, PERIOD),
+        ];
+        let now = 10 * PERIOD;
+
+        let select = |sync_from_seqno: Option<u32>, start_from_seqno: Option<u32>| {
+            let key_blocks = key_blocks
+                .iter()
+                .map(|&(seq_no, utime)| Ok((seq_no, seq_no, utime)));
+            select_key_block_at(sync_from_seqno, start_from_seqno, now, key_blocks).unwrap()
+        };
+
+        // Latest persistent key block
+        assert_eq!(select(None, None), Some(500));
+        assert_eq!(select(Some(450), None), Some(400));
+
+        // Oldest persistent key block at or after the indexing start
+        assert_eq!(select(None, Some(150)), Some(200));
+        assert_eq!(select(None, Some(250)), Some(400));
+        assert_eq!(select(None, Some(100)), Some(100));
+        assert_eq!(select(None, Some(600)), None);
+
+        // Too new key blocks are skipped
+        let key_blocks = [(500, now - 1), (400, 4 * PERIOD)];
+        let key_blocks = key_blocks
+            .iter()
+            .map(|&(seq_no, utime)| Ok((seq_no, seq_no, utime)));
+        assert_eq!(
+            select_key_block_at(None, Some(100), now, key_blocks).unwrap(),
+            Some(400)
+        );
+    }
+
+    #[tokio::test]
+    async fn key_block_proofs_prefetch_is_ordered_and_bounded() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        const LIMIT: usize = 2;
+
+        let ids = (1..=5)
+            .map(crate::test_helpers::mc_block_id)
+            .collect::<Vec<_>>();
+
+        #[derive(Default)]
+        struct State {
+            started: parking_lot::Mutex<Vec<u32>>,
+            in_flight: AtomicUsize,
+            max_in_flight: AtomicUsize,
+        }
+        let state = Arc::new(State::default());
+
+        let limiter = Arc::new(Semaphore::new(LIMIT));
+        let prefetched = spawn_prefetch(&ids, Some(&limiter), |block_id| {
+            let state = state.clone();
+            async move {
+                state.started.lock().push(block_id.seq_no);
+                let in_flight = state.in_flight.fetch_add(1, Ordering::AcqRel) + 1;
+                state.max_in_flight.fetch_max(in_flight, Ordering::AcqRel);
+                for _ in 0..10 {
+                    tokio::task::yield_now().await;
+                }
+                state.in_flight.fetch_sub(1, Ordering::AcqRel);
+                Ok(block_id.seq_no)
+            }
+        });
+        assert_eq!(prefetched.len(), ids.len());
+
+        // Results are returned in the order of ids
+        let mut results = Vec::new();
+        for handle in prefetched {
+            results.push(handle.unwrap().await.unwrap());
+        }
+        assert_eq!(results, [1, 2, 3, 4, 5]);
+
+        // Downloads are started in the order of ids and never exceed the limit
+        assert_eq!(*state.started.lock(), [1, 2, 3, 4, 5]);
+        assert_eq!(state.max_in_flight.load(Ordering::Acquire), LIMIT);
+
+        // Prefetch is disabled without the limiter
+        let prefetched = spawn_prefetch(&ids, None, |block_id| async move { Ok(block_id) });
+        assert!(prefetched.is_empty());
+    }
 }
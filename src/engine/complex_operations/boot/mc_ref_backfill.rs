@@ -0,0 +1,197 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use crate::db::{BlockConnection, BlockHandle};
+use crate::engine::Engine;
+use crate::utils::*;
+
+/// How many masterchain blocks below the shards client block are checked
+const MAX_BACKFILL_DEPTH: u32 = 1000;
+
+/// Assigns masterchain ref seqno to the applied shard blocks which were left without it.
+///
+/// Older versions applied shard blocks before the masterchain ref was persisted, so an
+/// interrupted sync could leave them orphaned. Masterchain blocks are checked backwards
+/// from the shards client block until the one with all top shard blocks committed, and then
+/// the refs are assigned in the forward order, so that each shard block gets the seqno
+/// of the first masterchain block which references it.
+///
+/// NOTE: runs only once per DB, because the ref is now assigned when the block is applied
+///
+/// Returns the number of updated shard blocks
+pub async fn backfill_mc_refs(engine: &Arc<Engine>) -> Result<usize> {
+    let node_state = engine.db.node_state();
+    if node_state.load_mc_refs_backfilled()? {
+        return Ok(0);
+    }
+
+    let total_updated = backfill_mc_refs_impl(engine).await?;
+    node_state.store_mc_refs_backfilled()?;
+    Ok(total_updated)
+}
+
+async fn backfill_mc_refs_impl(engine: &Arc<Engine>) -> Result<usize> {
+    let handles = engine.db.block_handle_storage();
+    let connections = engine.db.block_connection_storage();
+
+    let mut mc_block_id = engine.load_shards_client_mc_block_id()?;
+
+    // Find masterchain blocks with orphaned top shard blocks
+    let mut mc_blocks = Vec::new();
+    while mc_block_id.seq_no > 0 && mc_blocks.len() < MAX_BACKFILL_DEPTH as usize {
+        let block = match load_mc_block(engine, &mc_block_id).await? {
+            Some(block) => block,
+            None => break,
+        };
+
+        let mut has_orphans = false;
+        for (_, shard_block_id) in block.shard_blocks()? {
+            if let Some(handle) = handles.load_handle(&shard_block_id)? {
+                has_orphans |= is_orphan(&handle);
+            }
+        }
+        if !has_orphans {
+            break;
+        }
+
+        let prev_block_id = connections.find_connection(&mc_block_id, BlockConnection::Prev1)?;
+        mc_blocks.push(block);
+
+        match prev_block_id {
+            Some(prev_block_id) => mc_block_id = prev_block_id,
+            None => break,
+        }
+    }
+
+    let mut total_updated = 0;
+    for block in mc_blocks.into_iter().rev() {
+        let mc_seq_no = block.id().seq_no;
+
+        let mut queue = block
+            .shard_blocks()?
+            .into_values()
+            .collect::<Vec<ton_block::BlockIdExt>>();
+        while let Some(block_id) = queue.pop() {
+            let handle = match handles.load_handle(&block_id)? {
+                Some(handle) if is_orphan(&handle) => handle,
+                _ => continue,
+            };
+
+            handles.assign_mc_ref_seq_no(&handle, mc_seq_no)?;
+            total_updated += 1;
+
+            for direction in [BlockConnection::Prev1, BlockConnection::Prev2] {
+                if let Some(prev_id) = connections.find_connection(&block_id, direction)? {
+                    queue.push(prev_id);
+                }
+            }
+        }
+    }
+
+    if total_updated > 0 {
+        tracing::warn!(total_updated, "backfilled masterchain refs of shard blocks");
+    }
+    Ok(total_updated)
+}
+
+fn is_orphan(handle: &BlockHandle) -> bool {
+    let meta = handle.meta();
+    meta.is_applied() && meta.masterchain_ref_seqno() == 0 && handle.id().seq_no != 0
+}
+
+async fn load_mc_block(
+    engine: &Engine,
+    block_id: &ton_block::BlockIdExt,
+) -> Result<Option<BlockStuff>> {
+    let handle = match engine.db.block_handle_storage().load_handle(block_id)? {
+        Some(handle) if handle.meta().has_data() => handle,
+        _ => return Ok(None),
+    };
+    let block = engine.db.block_storage().load_block_data(&handle).await?;
+    Ok(Some(block))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::BlockMetaData;
+    use crate::test_helpers::*;
+
+    async fn store_block(
+        engine: &Engine,
+        (id, data): &(ton_block::BlockIdExt, Vec<u8>),
+        prev_id: Option<&ton_block::BlockIdExt>,
+    ) -> Arc<BlockHandle> {
+        let block = BlockStuff::deserialize_checked(id.clone(), data).unwrap();
+        let meta_data = BlockMetaData {
+            is_key_block: false,
+            gen_utime: id.seq_no,
+            mc_ref_seqno: None,
+        };
+        let handle = engine
+            .db
+            .block_storage()
+            .store_block_data(&BlockStuffAug::new(block, data.clone()), meta_data)
+            .await
+            .unwrap()
+            .handle;
+        engine
+            .db
+            .block_handle_storage()
+            .store_block_applied(&handle)
+            .unwrap();
+
+        if let Some(prev_id) = prev_id {
+            engine
+                .db
+                .block_connection_storage()
+                .store_connection(&handle, BlockConnection::Prev1, prev_id)
+                .unwrap();
+        }
+        handle
+    }
+
+    #[tokio::test]
+    async fn mc_refs_are_backfilled_once() {
+        let dir = TempDir::new("mc_ref_backfill");
+        let engine = test_engine(&dir, Vec::new()).await;
+
+        let shard = ton_block::ShardIdent::full(ton_block::BASE_WORKCHAIN_ID);
+        let shard_blocks = (1..=4)
+            .map(|seq_no| make_block(shard, seq_no, Default::default()))
+            .collect::<Vec<_>>();
+
+        let mut shard_handles = Vec::new();
+        let mut prev_id = None;
+        for block in &shard_blocks {
+            shard_handles.push(store_block(&engine, block, prev_id).await);
+            prev_id = Some(&block.0);
+        }
+
+        // The first masterchain block commits three shard blocks,
+        // the second one commits the last shard block
+        let mc_block_1 = make_mc_block_with_shards(1, &[shard_blocks[2].0.clone()]);
+        let mc_block_2 = make_mc_block_with_shards(2, &[shard_blocks[3].0.clone()]);
+        store_block(&engine, &mc_block_1, None).await;
+        store_block(&engine, &mc_block_2, Some(&mc_block_1.0)).await;
+
+        engine
+            .store_shards_client_mc_block_id(&mc_block_1.0, "test")
+            .unwrap();
+
+        // Orphaned shard blocks get the seqno of the first referencing masterchain block
+        assert_eq!(backfill_mc_refs(&engine).await.unwrap(), 3);
+        for handle in &shard_handles[..3] {
+            assert_eq!(handle.masterchain_ref_seqno(), 1);
+        }
+        assert!(engine.db.node_state().load_mc_refs_backfilled().unwrap());
+
+        // Backfill is not repeated on the next boot
+        engine
+            .store_shards_client_mc_block_id(&mc_block_2.0, "test")
+            .unwrap();
+        assert_eq!(backfill_mc_refs(&engine).await.unwrap(), 0);
+        assert_eq!(shard_handles[3].masterchain_ref_seqno(), 0);
+    }
+}
@@ -2,13 +2,18 @@ use std::sync::Arc;
 
 use anyhow::Result;
 
+use crate::db::AuditLogEventKind;
 use crate::engine::Engine;
 use crate::utils::*;
 
 use self::cold_boot::*;
+use self::mc_ref_backfill::*;
+pub use self::verify_boot::*;
 use self::warm_boot::*;
 
 mod cold_boot;
+mod mc_ref_backfill;
+mod verify_boot;
 mod warm_boot;
 
 /// Ensures that all shard states are downloaded.
@@ -18,12 +23,28 @@ pub async fn boot(engine: &Arc<Engine>) -> Result<()> {
     tracing::info!("starting boot");
 
     let last_key_block_id = match engine.load_last_applied_mc_block_id() {
-        Ok(block_id) => warm_boot(engine, block_id).await?,
+        Ok(block_id) => {
+            let last_key_block_id = warm_boot(engine, block_id).await?;
+            engine.audit_log.record(
+                AuditLogEventKind::BootAnchor,
+                Some(&last_key_block_id),
+                "warm boot",
+            );
+            last_key_block_id
+        }
+        Err(e) if engine.require_preseeded => {
+            return Err(BootError::DbNotPreseeded(e.to_string()).into());
+        }
         Err(e) => {
             tracing::warn!("failed to load last masterchain block id: {e}. node is not synced yet");
             let last_mc_block_id = cold_boot(engine).await?;
+            engine.audit_log.record(
+                AuditLogEventKind::BootAnchor,
+                Some(&last_mc_block_id),
+                "cold boot",
+            );
 
-            engine.store_last_applied_mc_block_id(&last_mc_block_id)?;
+            engine.store_last_applied_mc_block_id(&last_mc_block_id, "cold boot")?;
 
             engine
                 .db
@@ -37,11 +58,14 @@ pub async fn boot(engine: &Arc<Engine>) -> Result<()> {
     let shards_client_mc_block_id = match engine.load_shards_client_mc_block_id() {
         Ok(block_id) => block_id,
         Err(_) => {
-            engine.store_shards_client_mc_block_id(&last_key_block_id)?;
+            engine.store_shards_client_mc_block_id(&last_key_block_id, "boot")?;
             last_key_block_id.clone()
         }
     };
 
+    // Fix shard blocks applied by the interrupted sync
+    backfill_mc_refs(engine).await?;
+
     tracing::info!(
         last_key_block_id = %last_key_block_id.display(),
         shards_client_mc_block_id = %shards_client_mc_block_id.display(),
@@ -49,3 +73,44 @@ pub async fn boot(engine: &Arc<Engine>) -> Result<()> {
     );
     Ok(())
 }
+
+#[derive(thiserror::Error, Debug)]
+enum BootError {
+    #[error("Cold boot is disabled and DB has no last applied masterchain block: {0}")]
+    DbNotPreseeded(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::NodeConfig;
+    use crate::test_helpers::*;
+
+    #[tokio::test]
+    async fn empty_db_is_rejected_when_preseeded_is_required() {
+        let dir = TempDir::new("boot_require_preseeded");
+        let node_config = NodeConfig {
+            require_preseeded: true,
+            ..test_node_config(&dir)
+        };
+        let engine = Engine::new(node_config, test_global_config(), Vec::new())
+            .await
+            .unwrap();
+
+        let error = boot(&engine).await.unwrap_err();
+        assert!(matches!(
+            error.downcast::<BootError>().unwrap(),
+            BootError::DbNotPreseeded(_)
+        ));
+
+        // Cold boot didn't start, so the DB is left empty
+        assert!(engine.load_last_applied_mc_block_id().is_err());
+        assert!(engine.load_shards_client_mc_block_id().is_err());
+        assert!(engine
+            .db
+            .node_state()
+            .find_historical_sync_end()
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,493 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use broxus_util::now;
+use global_config::GlobalConfig;
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use super::cold_boot::*;
+use crate::config::NodeConfig;
+use crate::engine::hard_forks::HardForks;
+use crate::engine::{start_network, NodeRpcClient};
+use crate::network::Neighbour;
+use crate::utils::*;
+
+/// Checks the key block proofs chain from the init block the same way as the cold
+/// boot does, but keeps all downloaded data in memory and stops before downloading
+/// any states (except the zero state when starting from it).
+///
+/// NOTE: only the network is started, the DB is neither opened nor created.
+/// So the init block is taken from the global config and all data is requested from peers
+pub async fn verify_boot(
+    config: NodeConfig,
+    global_config: GlobalConfig,
+) -> Result<BootVerificationReport> {
+    tracing::info!("starting boot verification");
+
+    config.validate()?;
+
+    let selection = KeyBlockSelection {
+        sync_from_seqno: config.sync_options.sync_from_seqno,
+        start_from_seqno: config.start_from_seqno,
+    };
+    let hard_forks = HardForks::new(global_config.hard_forks.clone())
+        .context("Invalid hard forks in the global config")?;
+    let init_block_id = match &global_config.init_block {
+        Some(block_id) if block_id.seq_no > global_config.zero_state.seq_no => block_id.clone(),
+        _ => global_config.zero_state.clone(),
+    };
+
+    let (_network, masterchain_client, _) = start_network(config, global_config).await?;
+
+    let source = NetworkKeyBlocks {
+        client: &masterchain_client,
+        hard_forks: &hard_forks,
+        accepted_hard_forks: Default::default(),
+    };
+
+    // Init block is trusted, so only its own consistency is checked
+    let anchor = source.download_init_anchor(&init_block_id).await?;
+    let init_utime = anchor.gen_utime()?;
+
+    let mut report =
+        verify_key_blocks_chain(&source, init_block_id, anchor, init_utime, selection, now())
+            .await?;
+    report.accepted_hard_forks = source.accepted_hard_forks.into_inner();
+
+    tracing::info!(
+        verified = report.proof_chain_verified,
+        verified_key_blocks = report.verified_key_blocks,
+        chosen_key_block_id = ?report.chosen_key_block_id,
+        "finished boot verification"
+    );
+    Ok(report)
+}
+
+/// Config params which affect the key block chosen by the cold boot
+#[derive(Debug, Default, Copy, Clone)]
+struct KeyBlockSelection {
+    sync_from_seqno: Option<u32>,
+    start_from_seqno: Option<u32>,
+}
+
+/// Walks the key blocks after the init block and fills the report
+async fn verify_key_blocks_chain<S: KeyBlocksSource>(
+    source: &S,
+    init_block_id: ton_block::BlockIdExt,
+    mut anchor: S::Anchor,
+    init_utime: u32,
+    selection: KeyBlockSelection,
+    now: u32,
+) -> Result<BootVerificationReport> {
+    let mut report = BootVerificationReport {
+        init_block_id: init_block_id.to_string(),
+        ..Default::default()
+    };
+
+    let mut key_blocks = vec![(init_block_id, init_utime)];
+    match verify_key_blocks(source, &mut anchor, &mut key_blocks, &mut report, now).await {
+        Ok(()) => report.proof_chain_verified = true,
+        Err(e) => {
+            tracing::error!("key blocks proof chain verification failed: {e:?}");
+            report.error = Some(format!("{e:?}"));
+        }
+    }
+
+    report.verified_key_blocks = key_blocks.len() - 1;
+    report.last_key_block_id = key_blocks.last().map(|(id, _)| id.to_string());
+
+    // NOTE: only the key blocks from the init block are candidates,
+    // so the result could differ from the cold boot with a non-empty DB
+    let candidates = key_blocks
+        .iter()
+        .rev()
+        .map(|(id, utime)| Ok((id, id.seq_no, *utime)));
+    report.chosen_key_block_id = select_key_block_at(
+        selection.sync_from_seqno,
+        selection.start_from_seqno,
+        now,
+        candidates,
+    )?
+    .map(|id| id.to_string());
+
+    Ok(report)
+}
+
+/// Downloads and checks all key blocks after the last one until now
+async fn verify_key_blocks<S: KeyBlocksSource>(
+    source: &S,
+    anchor: &mut S::Anchor,
+    key_blocks: &mut Vec<(ton_block::BlockIdExt, u32)>,
+    report: &mut BootVerificationReport,
+    now: u32,
+) -> Result<()> {
+    let mut empty_responses = 0;
+    loop {
+        let (prev_id, prev_utime) = key_blocks.last().cloned().expect("never empty");
+
+        let (ids, peer) = source
+            .next_key_block_ids(&prev_id)
+            .await
+            .context("Failed to download next key block ids")?;
+        report.peers.insert(source.peer_id(&peer));
+
+        if ids.is_empty() {
+            // There could be no newer key blocks
+            empty_responses += 1;
+            if empty_responses >= MAX_DOWNLOAD_ATTEMPTS {
+                break;
+            }
+            continue;
+        }
+        empty_responses = 0;
+
+        for id in ids {
+            let (next_anchor, gen_utime) = source
+                .download_checked_proof(anchor, &id, &peer)
+                .await
+                .with_context(|| {
+                    format!(
+                        "Invalid key block proof {} from {}",
+                        id.display(),
+                        source.peer_id(&peer)
+                    )
+                })?;
+
+            key_blocks.push((id, gen_utime));
+            *anchor = next_anchor;
+        }
+
+        // Stop at the latest key block
+        let (_, last_utime) = key_blocks.last().cloned().expect("never empty");
+        if last_utime == prev_utime || last_utime + 2 * KEY_BLOCK_UTIME_STEP > now {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+/// Key blocks with proofs which are checked during the verification
+#[async_trait::async_trait]
+trait KeyBlocksSource {
+    type Peer: Send + Sync;
+    /// The last checked key block against which the next proof is checked
+    type Anchor: Send + Sync;
+
+    async fn next_key_block_ids(
+        &self,
+        prev_id: &ton_block::BlockIdExt,
+    ) -> Result<(Vec<ton_block::BlockIdExt>, Self::Peer)>;
+
+    fn peer_id(&self, peer: &Self::Peer) -> String;
+
+    /// Downloads the key block proof from the peer and checks it with the anchor.
+    /// Returns the new anchor and the gen utime of the key block
+    async fn download_checked_proof(
+        &self,
+        anchor: &Self::Anchor,
+        block_id: &ton_block::BlockIdExt,
+        peer: &Self::Peer,
+    ) -> Result<(Self::Anchor, u32)>;
+}
+
+/// Key blocks from the masterchain overlay.
+///
+/// NOTE: invalid proofs of the hard fork blocks are accepted, but only reported in memory
+struct NetworkKeyBlocks<'a> {
+    client: &'a NodeRpcClient,
+    hard_forks: &'a HardForks,
+    accepted_hard_forks: Mutex<Vec<String>>,
+}
+
+impl NetworkKeyBlocks<'_> {
+    async fn download_init_anchor(
+        &self,
+        init_block_id: &ton_block::BlockIdExt,
+    ) -> Result<VerifiedAnchor> {
+        if init_block_id.seq_no == 0 {
+            let state = download_with_retries(|| self.client.download_zero_state(init_block_id))
+                .await
+                .context("Failed to download zero state")?;
+            Ok(VerifiedAnchor::ZeroState(state))
+        } else {
+            let proof = download_with_retries(|| {
+                self.client.download_block_proof(init_block_id, true, None)
+            })
+            .await
+            .context("Failed to download init block proof")?;
+            proof
+                .pre_check_block_proof()
+                .context("Invalid init block proof")?;
+            Ok(VerifiedAnchor::KeyBlock(Box::new(proof.data)))
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl KeyBlocksSource for NetworkKeyBlocks<'_> {
+    type Peer = Arc<Neighbour>;
+    type Anchor = VerifiedAnchor;
+
+    async fn next_key_block_ids(
+        &self,
+        prev_id: &ton_block::BlockIdExt,
+    ) -> Result<(Vec<ton_block::BlockIdExt>, Self::Peer)> {
+        const BLOCKS_PER_BATCH: u16 = 5;
+
+        self.client
+            .download_next_key_blocks_ids(prev_id, BLOCKS_PER_BATCH, None)
+            .await
+    }
+
+    fn peer_id(&self, peer: &Self::Peer) -> String {
+        peer.peer_id().to_string()
+    }
+
+    async fn download_checked_proof(
+        &self,
+        anchor: &Self::Anchor,
+        block_id: &ton_block::BlockIdExt,
+        peer: &Self::Peer,
+    ) -> Result<(Self::Anchor, u32)> {
+        let proof =
+            download_with_retries(|| self.client.download_block_proof(block_id, true, Some(peer)))
+                .await
+                .context("Failed to download key block proof")?;
+
+        let info = check_key_block_proof_with(anchor.as_proof_anchor(), &proof, |block_id, e| {
+            if !self.hard_forks.contains(block_id) {
+                return false;
+            }
+            tracing::warn!(
+                block_id = %block_id.display(),
+                "accepted hard fork block with invalid proof: {e:?}"
+            );
+            self.accepted_hard_forks.lock().push(block_id.to_string());
+            true
+        })?;
+
+        Ok((
+            VerifiedAnchor::KeyBlock(Box::new(proof.data)),
+            info.gen_utime,
+        ))
+    }
+}
+
+async fn download_with_retries<T, F, FT>(mut f: F) -> Result<T>
+where
+    F: FnMut() -> FT,
+    FT: Future<Output = Result<Option<T>>>,
+{
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        match f().await {
+            Ok(Some(item)) => return Ok(item),
+            Ok(None) if attempt >= MAX_DOWNLOAD_ATTEMPTS => {
+                return Err(VerifyBootError::NotFound.into())
+            }
+            Err(e) if attempt >= MAX_DOWNLOAD_ATTEMPTS => return Err(e),
+            Ok(None) => tracing::debug!(attempt, "data not found"),
+            Err(e) => tracing::debug!(attempt, "failed to download data: {e:?}"),
+        }
+        tokio::time::sleep(Duration::from_millis(100 * attempt as u64)).await;
+    }
+}
+
+enum VerifiedAnchor {
+    ZeroState(Arc<ShardStateStuff>),
+    KeyBlock(Box<BlockProofStuff>),
+}
+
+impl VerifiedAnchor {
+    fn as_proof_anchor(&self) -> ProofAnchor<'_> {
+        match self {
+            Self::ZeroState(state) => ProofAnchor::ZeroState(state),
+            Self::KeyBlock(proof) => ProofAnchor::KeyBlock(proof),
+        }
+    }
+
+    fn gen_utime(&self) -> Result<u32> {
+        Ok(match self {
+            Self::ZeroState(state) => state.state().gen_time(),
+            Self::KeyBlock(proof) => proof.pre_check_block_proof()?.1.gen_utime().as_u32(),
+        })
+    }
+}
+
+/// Result of the boot verification
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct BootVerificationReport {
+    /// Trusted block from which the proofs chain is checked
+    pub init_block_id: String,
+    /// Whether all received key block proofs are valid
+    pub proof_chain_verified: bool,
+    /// Number of key blocks after the init block with valid proofs
+    pub verified_key_blocks: usize,
+    /// The latest key block with the valid proof
+    pub last_key_block_id: Option<String>,
+    /// Key block with persistent state which would be used by the cold boot
+    pub chosen_key_block_id: Option<String>,
+    /// Peers which served key block ids
+    pub peers: std::collections::BTreeSet<String>,
+    /// Hard fork blocks which were accepted with invalid proofs
+    pub accepted_hard_forks: Vec<String>,
+    /// Reason why the verification failed
+    pub error: Option<String>,
+}
+
+#[derive(thiserror::Error, Debug)]
+enum VerifyBootError {
+    #[error("Data was not found on peers")]
+    NotFound,
+}
+
+const MAX_DOWNLOAD_ATTEMPTS: u32 = 5;
+
+#[cfg(test)]
+mod tests {
+    use rustc_hash::FxHashMap;
+
+    use super::*;
+    use crate::test_helpers::*;
+
+    /// Key blocks of the synthetic chain. Anchor is the last checked key block
+    #[derive(Default)]
+    struct MockKeyBlocks {
+        /// Gen utime of each key block
+        utimes: FxHashMap<u32, u32>,
+        /// Key blocks with invalid proofs
+        invalid: Vec<u32>,
+        requests: Mutex<usize>,
+    }
+
+    #[async_trait::async_trait]
+    impl KeyBlocksSource for MockKeyBlocks {
+        type Peer = u32;
+        type Anchor = ton_block::BlockIdExt;
+
+        async fn next_key_block_ids(
+            &self,
+            prev_id: &ton_block::BlockIdExt,
+        ) -> Result<(Vec<ton_block::BlockIdExt>, Self::Peer)> {
+            let peer = {
+                let mut requests = self.requests.lock();
+                *requests += 1;
+                *requests as u32 % 2
+            };
+
+            let mut ids = self
+                .utimes
+                .keys()
+                .copied()
+                .filter(|&seq_no| seq_no > prev_id.seq_no)
+                .collect::<Vec<_>>();
+            ids.sort_unstable();
+            ids.truncate(2);
+            Ok((ids.into_iter().map(mc_block_id).collect(), peer))
+        }
+
+        fn peer_id(&self, peer: &Self::Peer) -> String {
+            format!("peer{peer}")
+        }
+
+        async fn download_checked_proof(
+            &self,
+            anchor: &Self::Anchor,
+            block_id: &ton_block::BlockIdExt,
+            _: &Self::Peer,
+        ) -> Result<(Self::Anchor, u32)> {
+            // Proofs are checked only with the previous key block
+            let prev = self
+                .utimes
+                .keys()
+                .copied()
+                .filter(|&seq_no| seq_no < block_id.seq_no)
+                .max()
+                .unwrap_or_default();
+            anyhow::ensure!(anchor.seq_no == prev, "unexpected anchor");
+            anyhow::ensure!(!self.invalid.contains(&block_id.seq_no), "invalid proof");
+
+            Ok((block_id.clone(), self.utimes[&block_id.seq_no]))
+        }
+    }
+
+    #[tokio::test]
+    async fn walk_key_blocks_proof_chain() {
+        const PERIOD: u32 = 1 << 17;
+        let now = 10 * PERIOD;
+
+        // Key block in each persistent state period
+        let mut source = MockKeyBlocks {
+            utimes: (1..=8).map(|i| (i * 100, i * PERIOD)).collect(),
+            ..Default::default()
+        };
+
+        let report = verify_key_blocks_chain(
+            &source,
+            mc_block_id(0),
+            mc_block_id(0),
+            0,
+            Default::default(),
+            now,
+        )
+        .await
+        .unwrap();
+        assert!(report.proof_chain_verified);
+        assert!(report.error.is_none());
+        assert_eq!(report.verified_key_blocks, 8);
+        assert_eq!(report.last_key_block_id, Some(mc_block_id(800).to_string()));
+        assert_eq!(
+            report.chosen_key_block_id,
+            Some(mc_block_id(800).to_string())
+        );
+        assert_eq!(
+            report.peers.iter().map(String::as_str).collect::<Vec<_>>(),
+            ["peer0", "peer1"]
+        );
+
+        // Oldest suitable key block for the indexing start
+        let report = verify_key_blocks_chain(
+            &source,
+            mc_block_id(0),
+            mc_block_id(0),
+            0,
+            KeyBlockSelection {
+                sync_from_seqno: None,
+                start_from_seqno: Some(250),
+            },
+            now,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            report.chosen_key_block_id,
+            Some(mc_block_id(300).to_string())
+        );
+
+        // Chain is checked up to the invalid proof
+        source.invalid.push(500);
+        let report = verify_key_blocks_chain(
+            &source,
+            mc_block_id(0),
+            mc_block_id(0),
+            0,
+            Default::default(),
+            now,
+        )
+        .await
+        .unwrap();
+        assert!(!report.proof_chain_verified);
+        assert!(report.error.unwrap().contains("invalid proof"));
+        assert_eq!(report.verified_key_blocks, 4);
+        assert_eq!(report.last_key_block_id, Some(mc_block_id(400).to_string()));
+        assert_eq!(
+            report.chosen_key_block_id,
+            Some(mc_block_id(400).to_string())
+        );
+    }
+}
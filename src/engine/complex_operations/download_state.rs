@@ -45,6 +45,7 @@ pub async fn download_state(
     };
 
     let (result_tx, result_rx) = oneshot::channel();
+    let (start_offset_tx, start_offset_rx) = oneshot::channel();
     let (packets_tx, packets_rx) = mpsc::channel(PROCESSING_QUEUE_LEN);
 
     let completion_signal = CancellationToken::new();
@@ -57,11 +58,18 @@ pub async fn download_state(
         let block_id = full_state_id.block_id.clone();
         let total_size = total_size.clone();
         async move {
-            result_tx.send(background_process(&engine, block_id, total_size, packets_rx).await)
+            result_tx.send(
+                background_process(&engine, block_id, total_size, start_offset_tx, packets_rx)
+                    .await,
+            )
         }
     });
 
+    let memory_budget = engine.memory_budget.clone();
     let downloader = async move {
+        // Wait until the previous progress is loaded
+        let start_offset = start_offset_rx.await?;
+
         let mut scheduler = Scheduler::with_slots(
             mc_client,
             full_state_id,
@@ -69,13 +77,19 @@ pub async fn download_state(
             total_size,
             DOWNLOADING_QUEUE_LEN,
             PACKET_SIZE,
+            start_offset as usize,
         )
         .await?;
 
         let mut total_bytes = 0;
         while let Some(packet) = scheduler.wait_next_packet().await? {
             total_bytes += packet.len();
-            if packets_tx.send(packet).await.is_err() {
+
+            // Wait until processed packets are released
+            let memory = memory_budget
+                .acquire(MemoryCategory::StatePackets, packet.len())
+                .await;
+            if packets_tx.send((packet, memory)).await.is_err() {
                 break;
             }
         }
@@ -107,6 +121,7 @@ async fn background_process(
     engine: &Arc<Engine>,
     block_id: ton_block::BlockIdExt,
     total_size: Arc<AtomicU64>,
+    start_offset_tx: oneshot::Sender<u64>,
     mut packets_rx: PacketsRx,
 ) -> Result<Arc<ShardStateStuff>> {
     let (mut transaction, mut ctx) = engine
@@ -118,11 +133,21 @@ async fn background_process(
     let mut pg = ProgressBar::builder("downloading state")
         .exact_unit("cells")
         .build();
+    if let Some(header) = transaction.header() {
+        // Download is resumed
+        pg.set_total(header.cell_count);
+        pg.set_progress(transaction.cells_read());
+    }
+
+    start_offset_tx
+        .send(transaction.position())
+        .map_err(|_| DownloadStateError::SchedulerError)
+        .context("Downloader closed")?;
 
     let mut full = false;
     let mut total_size_known = false;
 
-    while let Some(packet) = packets_rx.recv().await {
+    while let Some((packet, _memory)) = packets_rx.recv().await {
         if !total_size_known {
             if let Some(header) = transaction.header() {
                 total_size.store(header.total_size, Ordering::Release);
@@ -137,7 +162,10 @@ async fn background_process(
             }
             Ok(false) => continue,
             Err(e) => {
-                ctx.clear().await?;
+                // NOTE: keep the original error (e.g. out of disk space)
+                if let Err(e) = ctx.clear().await {
+                    tracing::error!("failed to remove temp state files: {e:?}");
+                }
                 return Err(e);
             }
         }
@@ -147,7 +175,12 @@ async fn background_process(
     while packets_rx.recv().await.is_some() {}
 
     if !full {
-        ctx.clear().await?;
+        // NOTE: files are kept so that the next attempt could continue from this point
+        tracing::warn!(
+            block_id = %block_id.display(),
+            position = transaction.position(),
+            "state download interrupted, progress is kept"
+        );
         return Err(DownloadStateError::UnexpectedEof.into());
     }
 
@@ -178,6 +211,7 @@ impl Scheduler {
         total_size: Arc<AtomicU64>,
         worker_count: usize,
         packet_size: usize,
+        start_offset: usize,
     ) -> Result<Self> {
         let (response_tx, response_rx) = mpsc::channel(worker_count);
 
@@ -199,7 +233,7 @@ impl Scheduler {
         let mut offset_txs = Vec::with_capacity(worker_count);
         let mut pending_packets = Vec::with_capacity(worker_count);
 
-        let mut offset = 0;
+        let mut offset = start_offset;
         for _ in 0..worker_count {
             let (offsets_tx, offsets_rx) = mpsc::channel(1);
             tokio::spawn(download_packet_worker(ctx.clone(), offsets_rx));
@@ -216,7 +250,7 @@ impl Scheduler {
             pending_packets,
             response_rx,
             packet_size,
-            current_offset: 0,
+            current_offset: start_offset,
             complete,
             cancellation_token,
         })
@@ -378,7 +412,7 @@ type ResponseTx = mpsc::Sender<(usize, Result<Vec<u8>>)>;
 type OffsetsRx = mpsc::Receiver<usize>;
 type OffsetsTx = mpsc::Sender<usize>;
 
-type PacketsRx = mpsc::Receiver<Vec<u8>>;
+type PacketsRx = mpsc::Receiver<(Vec<u8>, MemoryBudgetGuard)>;
 
 #[derive(thiserror::Error, Debug)]
 enum DownloadStateError {
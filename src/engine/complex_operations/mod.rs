@@ -1,11 +1,15 @@
 pub use self::apply_block::*;
 pub use self::boot::*;
 pub use self::download_state::*;
+pub use self::replay::*;
 pub use self::shard_client::*;
 pub use self::sync::*;
+pub use self::warmup::*;
 
 mod apply_block;
 mod boot;
 mod download_state;
+mod replay;
 mod shard_client;
 mod sync;
+mod warmup;
@@ -0,0 +1,487 @@
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use rustc_hash::FxHashSet;
+use tokio::sync::watch;
+
+use crate::db::*;
+use crate::engine::{shard_notification_order, Engine, ProcessBlockContext, SubscriberEntry};
+use crate::utils::BlockStuff;
+
+/// Progress of the shards client for the subscribers added after the engine was started.
+///
+/// NOTE: late subscribers are notified from the stored blocks of each
+/// masterchain block processed by the shards client, so the same path is used
+/// both for replay and for live notifications
+pub struct LateSubscribers {
+    /// The last masterchain block seqno processed by the shards client
+    processed_tx: watch::Sender<u32>,
+}
+
+impl Default for LateSubscribers {
+    fn default() -> Self {
+        Self {
+            processed_tx: watch::channel(0).0,
+        }
+    }
+}
+
+/// Replays all stored blocks starting from the specified masterchain block
+/// and then switches the subscriber to the live notifications.
+///
+/// Live notifications are delivered in background, so a slow or failing subscriber
+/// doesn't block the shards client. The subscriber is stopped if some block was not
+/// delivered after several attempts.
+///
+/// NOTE: each masterchain block is delivered after its shard blocks
+/// in the increasing seqno order
+pub async fn subscribe_from(
+    engine: &Arc<Engine>,
+    from_mc_seq_no: u32,
+    entry: SubscriberEntry,
+) -> Result<()> {
+    let source = EngineReplaySource {
+        engine: Arc::downgrade(engine),
+        entry,
+    };
+    let mut cursor = ReplayCursor::new(from_mc_seq_no);
+    let mut processed_rx = engine.late_subscribers.processed_tx.subscribe();
+
+    let last_mc_seq_no = engine.load_shards_client_mc_block_id()?.seq_no;
+    catch_up(&source, &mut cursor, &mut processed_rx, last_mc_seq_no).await?;
+    tracing::info!(
+        next_mc_seq_no = cursor.next_seq_no,
+        "replay finished, switched subscriber to live notifications"
+    );
+
+    tokio::spawn(async move {
+        if let Err(e) = follow(&source, &mut cursor, &mut processed_rx).await {
+            tracing::error!(
+                next_mc_seq_no = cursor.next_seq_no,
+                "late subscriber stopped: {e:?}"
+            );
+        }
+    });
+    Ok(())
+}
+
+/// Notifies late subscribers that all blocks up to the specified masterchain block are stored.
+///
+/// NOTE: must be called after the shards client processed this block
+pub fn notify_late_subscribers(engine: &Engine, mc_block_id: &ton_block::BlockIdExt) {
+    engine
+        .late_subscribers
+        .processed_tx
+        .send_replace(mc_block_id.seq_no);
+}
+
+/// Delivers all blocks until the last processed masterchain block
+async fn catch_up<S: ReplaySource>(
+    source: &S,
+    cursor: &mut ReplayCursor,
+    processed_rx: &mut watch::Receiver<u32>,
+    mut last_mc_seq_no: u32,
+) -> Result<()> {
+    loop {
+        cursor.replay_with_retries(source, last_mc_seq_no).await?;
+
+        // Blocks could have been processed during the replay
+        last_mc_seq_no = std::cmp::max(last_mc_seq_no, *processed_rx.borrow_and_update());
+        if cursor.next_seq_no > last_mc_seq_no || !source.is_working() {
+            return Ok(());
+        }
+    }
+}
+
+/// Delivers new blocks as soon as they are processed
+async fn follow<S: ReplaySource>(
+    source: &S,
+    cursor: &mut ReplayCursor,
+    processed_rx: &mut watch::Receiver<u32>,
+) -> Result<()> {
+    while processed_rx.changed().await.is_ok() {
+        let last_mc_seq_no = *processed_rx.borrow_and_update();
+        catch_up(source, cursor, processed_rx, last_mc_seq_no).await?;
+    }
+    Ok(())
+}
+
+/// Stored blocks of the masterchain blocks for the replay
+#[async_trait::async_trait]
+trait ReplaySource: Send + Sync {
+    type Item: Send + Sync;
+
+    fn is_working(&self) -> bool;
+
+    /// Finds the masterchain block with the specified seqno, starting from
+    /// the last delivered one if it is known
+    fn find_mc_block_id(
+        &self,
+        last_id: Option<&ton_block::BlockIdExt>,
+        seq_no: u32,
+    ) -> Result<ton_block::BlockIdExt>;
+
+    /// Loads all blocks of the masterchain block in the notification order:
+    /// committed shard blocks and then the masterchain block itself
+    async fn load_mc_block_items(
+        &self,
+        mc_block_id: &ton_block::BlockIdExt,
+    ) -> Result<Vec<Self::Item>>;
+
+    async fn deliver(&self, item: &Self::Item) -> Result<()>;
+}
+
+struct EngineReplaySource {
+    engine: Weak<Engine>,
+    entry: SubscriberEntry,
+}
+
+impl EngineReplaySource {
+    fn engine(&self) -> Result<Arc<Engine>> {
+        self.engine
+            .upgrade()
+            .ok_or_else(|| ReplayError::EngineStopped.into())
+    }
+}
+
+#[async_trait::async_trait]
+impl ReplaySource for EngineReplaySource {
+    type Item = (Arc<BlockHandle>, BlockStuff);
+
+    fn is_working(&self) -> bool {
+        matches!(self.engine.upgrade(), Some(engine) if engine.is_working())
+    }
+
+    fn find_mc_block_id(
+        &self,
+        last_id: Option<&ton_block::BlockIdExt>,
+        seq_no: u32,
+    ) -> Result<ton_block::BlockIdExt> {
+        let engine = self.engine()?;
+        let block_handle_storage = engine.db.block_handle_storage();
+        let block_connection_storage = engine.db.block_connection_storage();
+
+        let mut block_id = match last_id {
+            Some(last_id) => last_id.clone(),
+            // Start from the closest key block
+            None => block_handle_storage
+                .find_prev_key_block(seq_no + 1)?
+                .context("No key block found before the replay start")?
+                .id()
+                .clone(),
+        };
+
+        while block_id.seq_no < seq_no {
+            block_id =
+                block_connection_storage.load_connection(&block_id, BlockConnection::Next1)?;
+        }
+        Ok(block_id)
+    }
+
+    async fn load_mc_block_items(
+        &self,
+        mc_block_id: &ton_block::BlockIdExt,
+    ) -> Result<Vec<Self::Item>> {
+        let engine = self.engine()?;
+        let block_storage = engine.db.block_storage();
+
+        let mc_handle = engine
+            .db
+            .block_handle_storage()
+            .load_handle(mc_block_id)?
+            .context("Masterchain block handle not found")?;
+        let mc_block = block_storage.load_block_data(&mc_handle).await?;
+
+        let shard_handles = load_committed_shard_blocks(&engine.db, &mc_block)?;
+        let mut items = Vec::with_capacity(shard_handles.len() + 1);
+        for handle in shard_handles {
+            let block = block_storage.load_block_data(&handle).await?;
+            items.push((handle, block));
+        }
+        items.push((mc_handle, mc_block));
+        Ok(items)
+    }
+
+    async fn deliver(&self, (handle, block): &Self::Item) -> Result<()> {
+        let engine = self.engine()?;
+        let ctx = ProcessBlockContext {
+            engine: &engine,
+            meta: handle.meta().brief(),
+            handle,
+            block,
+            shard_state: None,
+            block_data: None,
+            block_proof_data: None,
+        };
+        self.entry
+            .call(|subscriber| subscriber.process_block(ctx))
+            .await
+    }
+}
+
+struct ReplayCursor {
+    /// Next masterchain block seqno to deliver
+    next_seq_no: u32,
+    /// The last delivered masterchain block
+    last_id: Option<ton_block::BlockIdExt>,
+    /// Number of already delivered blocks of the next masterchain block
+    delivered_items: usize,
+}
+
+impl ReplayCursor {
+    fn new(next_seq_no: u32) -> Self {
+        Self {
+            next_seq_no,
+            last_id: None,
+            delivered_items: 0,
+        }
+    }
+
+    /// Retries the replay with an increasing delay.
+    ///
+    /// NOTE: already delivered blocks are not delivered again
+    async fn replay_with_retries<S: ReplaySource>(
+        &mut self,
+        source: &S,
+        last_mc_seq_no: u32,
+    ) -> Result<()> {
+        let mut attempt = 0;
+        loop {
+            match self.replay_until(source, last_mc_seq_no).await {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt + 1 >= MAX_REPLAY_ATTEMPTS => return Err(e),
+                Err(e) => {
+                    attempt += 1;
+                    tracing::warn!(
+                        attempt,
+                        next_mc_seq_no = self.next_seq_no,
+                        "failed to notify late subscriber, retrying: {e:?}"
+                    );
+                    tokio::time::sleep(Duration::from_millis(100 << attempt)).await;
+                }
+            }
+        }
+    }
+
+    async fn replay_until<S: ReplaySource>(
+        &mut self,
+        source: &S,
+        last_mc_seq_no: u32,
+    ) -> Result<()> {
+        while self.next_seq_no <= last_mc_seq_no && source.is_working() {
+            let mc_block_id = source.find_mc_block_id(self.last_id.as_ref(), self.next_seq_no)?;
+
+            let items = source.load_mc_block_items(&mc_block_id).await?;
+            for item in items.iter().skip(self.delivered_items) {
+                source.deliver(item).await?;
+                self.delivered_items += 1;
+            }
+
+            self.next_seq_no += 1;
+            self.last_id = Some(mc_block_id);
+            self.delivered_items = 0;
+        }
+        Ok(())
+    }
+}
+
+/// Loads handles of all shard blocks committed by the masterchain block
+/// in the notification order
+pub fn load_committed_shard_blocks(
+    db: &Db,
+    mc_block: &BlockStuff,
+) -> Result<Vec<Arc<BlockHandle>>> {
+    let block_handle_storage = db.block_handle_storage();
+    let block_connection_storage = db.block_connection_storage();
+    let mc_seq_no = mc_block.id().seq_no;
+
+    let mut shard_handles = Vec::new();
+    let mut visited = FxHashSet::default();
+    let mut stack = mc_block.shard_blocks()?.into_values().collect::<Vec<_>>();
+    while let Some(block_id) = stack.pop() {
+        if block_id.seq_no == 0 || !visited.insert(block_id.clone()) {
+            continue;
+        }
+
+        let handle = match block_handle_storage.load_handle(&block_id)? {
+            Some(handle) if handle.masterchain_ref_seqno() == mc_seq_no => handle,
+            _ => continue,
+        };
+
+        // NOTE: prev2 exists only after merge
+        for direction in [BlockConnection::Prev1, BlockConnection::Prev2] {
+            if let Ok(prev_id) = block_connection_storage.load_connection(&block_id, direction) {
+                stack.push(prev_id);
+            }
+        }
+        shard_handles.push(handle);
+    }
+    shard_handles.sort_unstable_by_key(|handle| shard_notification_order(handle.id()));
+
+    Ok(shard_handles)
+}
+
+#[derive(thiserror::Error, Debug)]
+enum ReplayError {
+    #[error("Engine stopped")]
+    EngineStopped,
+}
+
+const MAX_REPLAY_ATTEMPTS: u32 = 5;
+
+#[cfg(test)]
+mod tests {
+    use parking_lot::Mutex;
+
+    use super::*;
+    use crate::test_helpers::*;
+
+    /// Masterchain blocks with the specified number of shard blocks.
+    /// Items are `(mc seqno, index in the masterchain block)`
+    #[derive(Default)]
+    struct MockSource {
+        shard_blocks: usize,
+        delivered: Mutex<Vec<(u32, usize)>>,
+        /// Items which fail once
+        fail_once: Mutex<Vec<(u32, usize)>>,
+        /// Item which always fails
+        fail_always: Option<(u32, usize)>,
+        attempts: Mutex<usize>,
+    }
+
+    impl MockSource {
+        fn expected(&self, mc_seq_nos: std::ops::RangeInclusive<u32>) -> Vec<(u32, usize)> {
+            mc_seq_nos
+                .flat_map(|seq_no| (0..=self.shard_blocks).map(move |i| (seq_no, i)))
+                .collect()
+        }
+
+        fn delivered(&self) -> Vec<(u32, usize)> {
+            self.delivered.lock().clone()
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ReplaySource for MockSource {
+        type Item = (u32, usize);
+
+        fn is_working(&self) -> bool {
+            true
+        }
+
+        fn find_mc_block_id(
+            &self,
+            last_id: Option<&ton_block::BlockIdExt>,
+            seq_no: u32,
+        ) -> Result<ton_block::BlockIdExt> {
+            if let Some(last_id) = last_id {
+                assert_eq!(last_id.seq_no + 1, seq_no);
+            }
+            Ok(mc_block_id(seq_no))
+        }
+
+        async fn load_mc_block_items(
+            &self,
+            mc_block_id: &ton_block::BlockIdExt,
+        ) -> Result<Vec<Self::Item>> {
+            Ok((0..=self.shard_blocks)
+                .map(|i| (mc_block_id.seq_no, i))
+                .collect())
+        }
+
+        async fn deliver(&self, item: &Self::Item) -> Result<()> {
+            if Some(*item) == self.fail_always {
+                *self.attempts.lock() += 1;
+                anyhow::bail!("subscriber error");
+            }
+
+            let mut fail_once = self.fail_once.lock();
+            if let Some(i) = fail_once.iter().position(|failed| failed == item) {
+                fail_once.remove(i);
+                anyhow::bail!("subscriber error");
+            }
+
+            self.delivered.lock().push(*item);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn catch_up_and_switch_to_live() {
+        let source = Arc::new(MockSource {
+            shard_blocks: 2,
+            ..Default::default()
+        });
+        let (processed_tx, mut processed_rx) = watch::channel(5);
+
+        // Blocks were processed during the replay
+        processed_tx.send_replace(7);
+
+        let mut cursor = ReplayCursor::new(3);
+        catch_up(source.as_ref(), &mut cursor, &mut processed_rx, 5)
+            .await
+            .unwrap();
+        assert_eq!(cursor.next_seq_no, 8);
+        assert_eq!(source.delivered(), source.expected(3..=7));
+
+        let live = tokio::spawn({
+            let source = source.clone();
+            async move { follow(source.as_ref(), &mut cursor, &mut processed_rx).await }
+        });
+        processed_tx.send_replace(8);
+        processed_tx.send_replace(10);
+        while source.delivered().len() < source.expected(3..=10).len() {
+            tokio::task::yield_now().await;
+        }
+
+        // Live notifications stop with the engine
+        drop(processed_tx);
+        live.await.unwrap().unwrap();
+        assert_eq!(source.delivered(), source.expected(3..=10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_blocks_are_not_delivered_again() {
+        let source = MockSource {
+            shard_blocks: 2,
+            fail_once: Mutex::new(vec![(2, 1), (4, 2), (4, 2)]),
+            ..Default::default()
+        };
+        let (_processed_tx, mut processed_rx) = watch::channel(0);
+
+        let mut cursor = ReplayCursor::new(1);
+        catch_up(&source, &mut cursor, &mut processed_rx, 5)
+            .await
+            .unwrap();
+        assert_eq!(source.delivered(), source.expected(1..=5));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn subscriber_is_stopped_after_retries() {
+        let source = MockSource {
+            shard_blocks: 2,
+            fail_always: Some((4, 1)),
+            ..Default::default()
+        };
+        let (processed_tx, mut processed_rx) = watch::channel(0);
+
+        let mut cursor = ReplayCursor::new(1);
+        catch_up(&source, &mut cursor, &mut processed_rx, 3)
+            .await
+            .unwrap();
+
+        processed_tx.send_replace(5);
+        follow(&source, &mut cursor, &mut processed_rx)
+            .await
+            .unwrap_err();
+        assert_eq!(*source.attempts.lock(), MAX_REPLAY_ATTEMPTS as usize);
+
+        // Only the failed block is left
+        assert_eq!(cursor.next_seq_no, 4);
+        assert_eq!(cursor.delivered_items, 1);
+        let mut expected = source.expected(1..=3);
+        expected.push((4, 0));
+        assert_eq!(source.delivered(), expected);
+    }
+}
@@ -4,13 +4,20 @@
 /// - replaced old `failure` crate with `anyhow`
 /// - simplified block walking
 ///
+use std::collections::VecDeque;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 use anyhow::{anyhow, Result};
-use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use parking_lot::Mutex;
+use rustc_hash::FxHashMap;
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
 
-use crate::db::BlockConnection;
-use crate::engine::Engine;
+use super::replay::notify_late_subscribers;
+use super::sync::{catch_up_shard_blocks, shard_client_far_behind};
+use crate::config::BroadcastStorePolicy;
+use crate::db::{lock_owner_scope, BlockConnection, BlockHandle};
+use crate::engine::{DiskSpaceLevel, Engine};
 use crate::proto;
 use crate::utils::*;
 
@@ -19,6 +26,8 @@ pub async fn walk_masterchain_blocks(
     mut block_id: ton_block::BlockIdExt,
 ) -> Result<()> {
     while engine.is_working() {
+        engine.wait_for_disk_space(DiskSpaceLevel::Critical).await;
+
         tracing::info!(
             block_id = %block_id.display(),
             "walking through masterchain blocks"
@@ -41,30 +50,212 @@ pub async fn walk_shard_blocks(
     engine: &Arc<Engine>,
     mc_block_id: ton_block::BlockIdExt,
 ) -> Result<()> {
+    walk_shard_blocks_impl(&EngineShardClient { engine }, mc_block_id).await
+}
+
+async fn walk_shard_blocks_impl<S>(source: &S, mc_block_id: ton_block::BlockIdExt) -> Result<()>
+where
+    S: ShardClientSource,
+{
     let semaphore = Arc::new(Semaphore::new(1));
 
-    let block_handle_storage = engine.db.block_handle_storage();
-    let mut handle = block_handle_storage
-        .load_handle(&mc_block_id)?
-        .ok_or(ShardClientError::ShardchainBlockHandleNotFound)?;
+    let mut block_id = match source.take_reset() {
+        Some(block_id) => source.reset(&block_id)?,
+        None => source.check_mc_block(mc_block_id)?,
+    };
+
+    while source.is_working() {
+        source.wait_for_disk_space().await;
+
+        if let Some(target) = source.take_reset() {
+            // Wait until the last scheduled masterchain block is processed
+            let _permit = semaphore.acquire().await?;
+            block_id = source.reset(&target)?;
+        }
+
+        let last_mc_seq_no = source.last_mc_seq_no()?;
+        if shard_client_far_behind(block_id.seq_no, last_mc_seq_no) {
+            // Wait until the last scheduled masterchain block is processed
+            let permit = semaphore.clone().acquire_owned().await?;
+
+            source.set_archives_mode(true);
+            let result = source.catch_up_with_archives().await;
+            source.set_archives_mode(false);
+            drop(permit);
+
+            // Continue walking from the last processed masterchain block
+            block_id = source.check_mc_block(result?)?;
+            continue;
+        }
 
-    while engine.is_working() {
         tracing::info!(
-            block_id = %handle.id().display(),
+            block_id = %block_id.display(),
             "walking through shard blocks"
         );
-        let (next_handle, next_block) = engine.wait_next_applied_mc_block(&handle, None).await?;
-        handle = next_handle;
+        let (next_block_id, next_block) = match source.wait_next_mc_block(&block_id).await? {
+            Some(next) => next,
+            None => continue,
+        };
+        block_id = next_block_id;
 
-        let engine = engine.clone();
         let permit = semaphore.clone().acquire_owned().await?;
+        source.spawn_load_shard_blocks(permit, next_block);
+    }
+    Ok(())
+}
+
+/// Operations of the shard blocks walker
+#[async_trait::async_trait]
+trait ShardClientSource: Send + Sync {
+    type McBlock: Send;
+
+    fn is_working(&self) -> bool;
+
+    async fn wait_for_disk_space(&self);
+
+    fn take_reset(&self) -> Option<ton_block::BlockIdExt>;
+
+    /// Moves the shard client to the specified masterchain block
+    fn reset(&self, mc_block_id: &ton_block::BlockIdExt) -> Result<ton_block::BlockIdExt>;
+
+    /// Ensures that the masterchain block is known
+    fn check_mc_block(&self, mc_block_id: ton_block::BlockIdExt) -> Result<ton_block::BlockIdExt>;
+
+    fn last_mc_seq_no(&self) -> Result<u32>;
+
+    fn set_archives_mode(&self, active: bool);
+
+    /// Imports shard blocks from archives until the shard client catches up.
+    /// Returns the last processed masterchain block
+    async fn catch_up_with_archives(&self) -> Result<ton_block::BlockIdExt>;
+
+    /// Waits for the next applied masterchain block.
+    /// Returns `None` if the shard client was reset meanwhile
+    async fn wait_next_mc_block(
+        &self,
+        prev_block_id: &ton_block::BlockIdExt,
+    ) -> Result<Option<(ton_block::BlockIdExt, Self::McBlock)>>;
+
+    /// Applies shard blocks of the masterchain block in background.
+    /// The permit is released when all of them are processed
+    fn spawn_load_shard_blocks(&self, permit: OwnedSemaphorePermit, mc_block: Self::McBlock);
+}
+
+struct EngineShardClient<'a> {
+    engine: &'a Arc<Engine>,
+}
+
+impl EngineShardClient<'_> {
+    fn load_handle(&self, mc_block_id: &ton_block::BlockIdExt) -> Result<Arc<BlockHandle>> {
+        self.engine
+            .db
+            .block_handle_storage()
+            .load_handle(mc_block_id)?
+            .ok_or_else(|| ShardClientError::ShardchainBlockHandleNotFound.into())
+    }
+}
+
+#[async_trait::async_trait]
+impl ShardClientSource for EngineShardClient<'_> {
+    type McBlock = BlockStuff;
+
+    fn is_working(&self) -> bool {
+        self.engine.is_working()
+    }
+
+    async fn wait_for_disk_space(&self) {
+        self.engine
+            .wait_for_disk_space(DiskSpaceLevel::Critical)
+            .await
+    }
+
+    fn take_reset(&self) -> Option<ton_block::BlockIdExt> {
+        self.engine.shards_client_reset.take()
+    }
+
+    fn reset(&self, mc_block_id: &ton_block::BlockIdExt) -> Result<ton_block::BlockIdExt> {
+        reset_shards_client(self.engine, mc_block_id).map(|handle| handle.id().clone())
+    }
+
+    fn check_mc_block(&self, mc_block_id: ton_block::BlockIdExt) -> Result<ton_block::BlockIdExt> {
+        self.load_handle(&mc_block_id)?;
+        Ok(mc_block_id)
+    }
+
+    fn last_mc_seq_no(&self) -> Result<u32> {
+        Ok(self.engine.load_last_applied_mc_block_id()?.seq_no)
+    }
+
+    fn set_archives_mode(&self, active: bool) {
+        self.engine
+            .metrics
+            .shard_client_archives_mode
+            .store(active, Ordering::Release);
+    }
+
+    async fn catch_up_with_archives(&self) -> Result<ton_block::BlockIdExt> {
+        catch_up_shard_blocks(self.engine).await?;
+        self.engine.load_shards_client_mc_block_id()
+    }
+
+    async fn wait_next_mc_block(
+        &self,
+        prev_block_id: &ton_block::BlockIdExt,
+    ) -> Result<Option<(ton_block::BlockIdExt, BlockStuff)>> {
+        let handle = self.load_handle(prev_block_id)?;
+        tokio::select! {
+            result = self.engine.wait_next_applied_mc_block(&handle, None) => {
+                let (next_handle, next_block) = result?;
+                Ok(Some((next_handle.id().clone(), next_block)))
+            },
+            _ = self.engine.shards_client_reset.notify.notified() => Ok(None),
+        }
+    }
+
+    fn spawn_load_shard_blocks(&self, permit: OwnedSemaphorePermit, mc_block: BlockStuff) {
+        let engine = self.engine.clone();
         tokio::spawn(async move {
-            if let Err(e) = load_shard_blocks(&engine, permit, next_block).await {
+            if let Err(e) = load_shard_blocks(&engine, permit, mc_block).await {
                 tracing::error!("failed to load shard blocks: {e:?}");
             }
         });
     }
-    Ok(())
+}
+
+fn reset_shards_client(
+    engine: &Arc<Engine>,
+    mc_block_id: &ton_block::BlockIdExt,
+) -> Result<Arc<BlockHandle>> {
+    let handle = engine
+        .db
+        .block_handle_storage()
+        .load_handle(mc_block_id)?
+        .ok_or(ShardClientError::MasterchainBlockNotFound)?;
+    engine.rewind_shards_client_mc_block_id(mc_block_id, "reset")?;
+
+    tracing::warn!(
+        block_id = %mc_block_id.display(),
+        "shards client was reset"
+    );
+    Ok(handle)
+}
+
+/// Pending request to restart shard blocks walking from the specified masterchain block
+#[derive(Default)]
+pub struct ShardsClientReset {
+    target: Mutex<Option<ton_block::BlockIdExt>>,
+    notify: Notify,
+}
+
+impl ShardsClientReset {
+    pub fn request(&self, mc_block_id: ton_block::BlockIdExt) {
+        *self.target.lock() = Some(mc_block_id);
+        self.notify.notify_one();
+    }
+
+    fn take(&self) -> Option<ton_block::BlockIdExt> {
+        self.target.lock().take()
+    }
 }
 
 async fn load_next_masterchain_block(
@@ -99,12 +290,26 @@ async fn load_next_masterchain_block(
         return Err(ShardClientError::InvalidBlockProof.into());
     }
 
-    let (virt_block, virt_block_info) = block_proof.pre_check_block_proof()?;
+    let (virt_block, virt_block_info) = {
+        let block_proof = block_proof.data.clone();
+        engine
+            .blocking_pool
+            .run(move || block_proof.pre_check_block_proof())
+            .await?
+    };
     let brief_info = BriefBlockInfo::from(&virt_block_info);
 
     // TODO: use key block proof
     let prev_state = engine.wait_state(prev_block_id, None, true).await?;
-    check_with_master_state(&block_proof, &prev_state, &virt_block, &virt_block_info)?;
+    {
+        let block_proof = block_proof.data.clone();
+        engine
+            .blocking_pool
+            .run(move || {
+                check_with_master_state(&block_proof, &prev_state, &virt_block, &virt_block_info)
+            })
+            .await?;
+    }
 
     let mut handle = match block_handle_storage.load_handle(block_id)? {
         // Handle exists and it has block data specified
@@ -156,26 +361,30 @@ async fn load_shard_blocks(
         }
 
         let engine = engine.clone();
-        tasks.push(tokio::spawn(async move {
-            while let Err(e) = engine
-                .download_and_apply_block(&shard_block_id, mc_seq_no, false, 0)
-                .await
-            {
-                tracing::error!(
-                    block_id = %shard_block_id.display(),
-                    "failed to apply shard block: {e:?}"
-                );
+        tasks.push(tokio::spawn(lock_owner_scope(async move {
+            loop {
+                let _permit = engine.shard_apply_limiter.acquire().await?;
+                match engine
+                    .download_and_apply_block(&shard_block_id, mc_seq_no, false, 0)
+                    .await
+                {
+                    Ok(()) => break Ok::<_, anyhow::Error>(()),
+                    Err(e) => tracing::error!(
+                        block_id = %shard_block_id.display(),
+                        "failed to apply shard block: {e:?}"
+                    ),
+                }
             }
-        }));
+        })));
     }
 
-    futures_util::future::join_all(tasks)
-        .await
-        .into_iter()
-        .find(|item| item.is_err())
-        .unwrap_or(Ok(()))?;
+    for result in futures_util::future::join_all(tasks).await {
+        result??;
+    }
 
-    engine.store_shards_client_mc_block_id(masterchain_block.id())?;
+    engine.flush_shard_notifications(mc_seq_no).await?;
+    engine.store_shards_client_mc_block_id(masterchain_block.id(), "shard blocks applied")?;
+    notify_late_subscribers(engine, masterchain_block.id());
 
     drop(permit);
     Ok(())
@@ -224,28 +433,69 @@ pub async fn process_block_broadcast(
             let catchain_config = config_params.catchain_config()?;
             (CheckWith::State(zerostate), validator_set, catchain_config)
         } else {
-            let proof = block_storage.load_block_proof(&handle, false).await?;
+            let proof = engine.load_best_block_proof(&handle).await?;
             let (validator_set, catchain_config) = proof.get_cur_validators_set()?;
             (CheckWith::KeyBlock(proof), validator_set, catchain_config)
         }
     };
 
-    validate_broadcast(&mut broadcast, &validator_set, &catchain_config)?;
+    let (broadcast, proof, block) = engine
+        .blocking_pool
+        .run(move || {
+            validate_broadcast(&mut broadcast, &validator_set, &catchain_config)?;
+
+            let block_id = &broadcast.id;
+            if block_id.shard_id.is_masterchain() {
+                match key_block_proof {
+                    CheckWith::KeyBlock(key_block_proof) => {
+                        proof.check_with_prev_key_block_proof(&key_block_proof)?
+                    }
+                    CheckWith::State(state) => proof.check_with_master_state(&state)?,
+                }
+            } else {
+                proof.check_proof_link()?;
+            }
+
+            let block = BlockStuff::deserialize_checked(block_id.clone(), &broadcast.data)?;
+            Ok((broadcast, proof, block))
+        })
+        .await?;
 
     let block_id = &broadcast.id;
-    if block_id.shard_id.is_masterchain() {
-        match key_block_proof {
-            CheckWith::KeyBlock(key_block_proof) => {
-                proof.check_with_prev_key_block_proof(&key_block_proof)?
+    let block = BlockStuffAug::new(block, broadcast.data);
+    let proof = BlockProofStuffAug::new(proof, broadcast.proof);
+
+    // Check whether the block must be written into the storage
+    let store_policy = engine.broadcast_options.store_policy;
+    if store_policy != BroadcastStorePolicy::StoreAll {
+        let position = BroadcastPosition::new(engine, &block)?;
+        match broadcast_store_action(store_policy, &position) {
+            BroadcastStoreAction::Store => {}
+            BroadcastStoreAction::Buffer => {
+                if let Some(buffer) = &engine.broadcast_blocks_buffer {
+                    engine
+                        .metrics
+                        .buffered_broadcasts
+                        .fetch_add(1, Ordering::Relaxed);
+                    if buffer.insert(block, proof).is_some() {
+                        engine
+                            .metrics
+                            .skipped_broadcasts
+                            .fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                return Ok(());
+            }
+            BroadcastStoreAction::Skip => {
+                engine
+                    .metrics
+                    .skipped_broadcasts
+                    .fetch_add(1, Ordering::Relaxed);
+                return Ok(());
             }
-            CheckWith::State(state) => proof.check_with_master_state(&state)?,
         }
-    } else {
-        proof.check_proof_link()?;
     }
 
-    let block = BlockStuff::deserialize_checked(block_id.clone(), &broadcast.data)?;
-    let block = BlockStuffAug::new(block, broadcast.data);
     let mut handle = match block_storage
         .store_block_data(&block, meta_data.with_mc_seq_no(0))
         .await?
@@ -257,10 +507,7 @@ pub async fn process_block_broadcast(
 
     if !handle.meta().has_proof() {
         handle = match block_storage
-            .store_block_proof(
-                &BlockProofStuffAug::new(proof, broadcast.proof),
-                handle.into(),
-            )
+            .store_block_proof(&proof, handle.into())
             .await?
         {
             result if result.updated => result.handle,
@@ -270,7 +517,13 @@ pub async fn process_block_broadcast(
     }
 
     if block_id.shard_id.is_masterchain() {
-        if block_id.seq_no == last_applied_mc_block_id.seq_no + 1 {
+        // Apply masterchain block immediately if it is contiguous with the tip.
+        // Otherwise, the gap will be filled by sync or masterchain blocks walker
+        let (prev1, _) = block.construct_prev_id()?;
+        if matches!(
+            block_handle_storage.load_handle(&prev1)?,
+            Some(handle) if handle.meta().is_applied()
+        ) {
             engine
                 .apply_block_ext(&handle, &block, block.id().seq_no, false, 0)
                 .await?;
@@ -283,7 +536,9 @@ pub async fn process_block_broadcast(
             .ok_or(ShardClientError::InvalidBlockExtra)?;
 
         let shards_client_mc_block_id = engine.load_shards_client_mc_block_id()?;
-        if shards_client_mc_block_id.seq_no + 8 >= master_ref.master.seq_no {
+        if shards_client_mc_block_id.seq_no + SHARD_BROADCAST_APPLY_DISTANCE
+            >= master_ref.master.seq_no
+        {
             engine
                 .apply_block_ext(&handle, &block, shards_client_mc_block_id.seq_no, true, 0)
                 .await?;
@@ -293,6 +548,147 @@ pub async fn process_block_broadcast(
     Ok(())
 }
 
+/// Broadcast blocks which are too far ahead of the clients to be stored.
+///
+/// NOTE: the oldest blocks are dropped when the buffer is full
+pub struct BroadcastBlocksBuffer {
+    capacity: usize,
+    blocks: Mutex<BufferedBroadcasts>,
+}
+
+#[derive(Default)]
+struct BufferedBroadcasts {
+    order: VecDeque<ton_block::BlockIdExt>,
+    items: FxHashMap<ton_block::BlockIdExt, (BlockStuffAug, BlockProofStuffAug)>,
+}
+
+impl BroadcastBlocksBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: std::cmp::max(capacity, 1),
+            blocks: Default::default(),
+        }
+    }
+
+    /// Returns the id of the dropped block if the buffer was full
+    pub fn insert(
+        &self,
+        block: BlockStuffAug,
+        proof: BlockProofStuffAug,
+    ) -> Option<ton_block::BlockIdExt> {
+        let mut blocks = self.blocks.lock();
+        let block_id = block.id().clone();
+        if blocks.items.contains_key(&block_id) {
+            return None;
+        }
+
+        let dropped = if blocks.items.len() >= self.capacity {
+            let oldest = blocks.order.pop_front()?;
+            blocks.items.remove(&oldest);
+            Some(oldest)
+        } else {
+            None
+        };
+
+        blocks.order.push_back(block_id.clone());
+        blocks.items.insert(block_id, (block, proof));
+        dropped
+    }
+
+    /// Removes the block from the buffer
+    pub fn take(
+        &self,
+        block_id: &ton_block::BlockIdExt,
+    ) -> Option<(BlockStuffAug, BlockProofStuffAug)> {
+        let mut blocks = self.blocks.lock();
+        let item = blocks.items.remove(block_id)?;
+        blocks.order.retain(|id| id != block_id);
+        Some(item)
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.lock().items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.lock().items.is_empty()
+    }
+}
+
+/// Position of the broadcast block relative to the corresponding client
+#[derive(Debug, Copy, Clone)]
+struct BroadcastPosition {
+    is_masterchain: bool,
+    /// Masterchain block seqno referenced by the block
+    /// (seqno of the block itself for masterchain blocks)
+    ref_mc_seq_no: u32,
+    /// Last processed masterchain block seqno of the corresponding client
+    client_mc_seq_no: u32,
+}
+
+impl BroadcastPosition {
+    fn new(engine: &Engine, block: &BlockStuff) -> Result<Self> {
+        let block_id = block.id();
+        if block_id.shard_id.is_masterchain() {
+            Ok(Self {
+                is_masterchain: true,
+                ref_mc_seq_no: block_id.seq_no,
+                client_mc_seq_no: engine.load_last_applied_mc_block_id()?.seq_no,
+            })
+        } else {
+            let master_ref = block
+                .block()
+                .read_info()
+                .and_then(|info| info.read_master_ref())?
+                .ok_or(ShardClientError::InvalidBlockExtra)?;
+
+            Ok(Self {
+                is_masterchain: false,
+                ref_mc_seq_no: master_ref.master.seq_no,
+                client_mc_seq_no: engine.load_shards_client_mc_block_id()?.seq_no,
+            })
+        }
+    }
+
+    /// Max distance at which the block is applied immediately
+    fn apply_distance(&self) -> u32 {
+        if self.is_masterchain {
+            // Masterchain block must be contiguous with the tip
+            1
+        } else {
+            SHARD_BROADCAST_APPLY_DISTANCE
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum BroadcastStoreAction {
+    Store,
+    Buffer,
+    Skip,
+}
+
+fn broadcast_store_action(
+    policy: BroadcastStorePolicy,
+    position: &BroadcastPosition,
+) -> BroadcastStoreAction {
+    let distance = position
+        .ref_mc_seq_no
+        .saturating_sub(position.client_mc_seq_no);
+
+    match policy {
+        BroadcastStorePolicy::StoreAll => BroadcastStoreAction::Store,
+        BroadcastStorePolicy::StoreWithinHorizon { horizon, .. } if distance <= horizon => {
+            BroadcastStoreAction::Store
+        }
+        BroadcastStorePolicy::StoreWithinHorizon { .. } => BroadcastStoreAction::Buffer,
+        BroadcastStorePolicy::ApplyOnly if distance <= position.apply_distance() => {
+            BroadcastStoreAction::Store
+        }
+        BroadcastStorePolicy::ApplyOnly => BroadcastStoreAction::Skip,
+    }
+}
+
 fn validate_broadcast(
     broadcast: &mut proto::BlockBroadcast,
     validator_set: &ton_block::ValidatorSet,
@@ -338,6 +734,10 @@ fn validate_broadcast(
     Ok(())
 }
 
+/// Max number of masterchain blocks between the shard client and the block
+/// referenced by the shard broadcast to apply it immediately
+const SHARD_BROADCAST_APPLY_DISTANCE: u32 = 8;
+
 #[derive(thiserror::Error, Debug)]
 enum ShardClientError {
     #[error("Masterchain block not found")]
@@ -351,3 +751,310 @@ enum ShardClientError {
     #[error("Invalid block extra")]
     InvalidBlockExtra,
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicU32};
+
+    use super::super::sync::shard_client_caught_up;
+    use super::*;
+    use crate::db::BlockMetaData;
+    use crate::engine::EngineError;
+    use crate::test_helpers::*;
+
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    enum Step {
+        /// Shard blocks of the masterchain blocks range imported from the archive
+        Archive(u32, u32),
+        /// Shard blocks of the masterchain block applied one by one
+        Block(u32),
+        /// Shard client moved to the masterchain block
+        Reset(u32),
+    }
+
+    /// Shard client which lags behind the masterchain head
+    struct MockShardClient {
+        last_mc_seq_no: AtomicU32,
+        shards_client_mc_seq_no: AtomicU32,
+        archives_mode: AtomicBool,
+        steps: Mutex<Vec<Step>>,
+        /// Reset target requested after the masterchain block is processed
+        reset_after: Mutex<Option<(u32, u32)>>,
+        pending_reset: Mutex<Option<ton_block::BlockIdExt>>,
+    }
+
+    impl MockShardClient {
+        const ARCHIVE_LEN: u32 = 100;
+
+        fn new(shards_client_mc_seq_no: u32, last_mc_seq_no: u32) -> Self {
+            Self {
+                last_mc_seq_no: AtomicU32::new(last_mc_seq_no),
+                shards_client_mc_seq_no: AtomicU32::new(shards_client_mc_seq_no),
+                archives_mode: Default::default(),
+                steps: Default::default(),
+                reset_after: Default::default(),
+                pending_reset: Default::default(),
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ShardClientSource for MockShardClient {
+        type McBlock = u32;
+
+        fn is_working(&self) -> bool {
+            self.shards_client_mc_seq_no.load(Ordering::Acquire)
+                < self.last_mc_seq_no.load(Ordering::Acquire)
+        }
+
+        async fn wait_for_disk_space(&self) {}
+
+        fn take_reset(&self) -> Option<ton_block::BlockIdExt> {
+            self.pending_reset.lock().take()
+        }
+
+        fn reset(&self, mc_block_id: &ton_block::BlockIdExt) -> Result<ton_block::BlockIdExt> {
+            self.steps.lock().push(Step::Reset(mc_block_id.seq_no));
+            self.shards_client_mc_seq_no
+                .store(mc_block_id.seq_no, Ordering::Release);
+            Ok(mc_block_id.clone())
+        }
+
+        fn check_mc_block(
+            &self,
+            mc_block_id: ton_block::BlockIdExt,
+        ) -> Result<ton_block::BlockIdExt> {
+            Ok(mc_block_id)
+        }
+
+        fn last_mc_seq_no(&self) -> Result<u32> {
+            Ok(self.last_mc_seq_no.load(Ordering::Acquire))
+        }
+
+        fn set_archives_mode(&self, active: bool) {
+            self.archives_mode.store(active, Ordering::Release);
+        }
+
+        async fn catch_up_with_archives(&self) -> Result<ton_block::BlockIdExt> {
+            assert!(self.archives_mode.load(Ordering::Acquire));
+
+            let last_mc_seq_no = self.last_mc_seq_no.load(Ordering::Acquire);
+            loop {
+                let from = self.shards_client_mc_seq_no.load(Ordering::Acquire) + 1;
+                let to = std::cmp::min(from + Self::ARCHIVE_LEN - 1, last_mc_seq_no);
+                self.steps.lock().push(Step::Archive(from, to));
+                self.shards_client_mc_seq_no.store(to, Ordering::Release);
+
+                if shard_client_caught_up(to, last_mc_seq_no) {
+                    break Ok(mc_block_id(to));
+                }
+            }
+        }
+
+        async fn wait_next_mc_block(
+            &self,
+            prev_block_id: &ton_block::BlockIdExt,
+        ) -> Result<Option<(ton_block::BlockIdExt, u32)>> {
+            assert!(!self.archives_mode.load(Ordering::Acquire));
+
+            let seq_no = prev_block_id.seq_no + 1;
+            assert!(seq_no <= self.last_mc_seq_no.load(Ordering::Acquire));
+            Ok(Some((mc_block_id(seq_no), seq_no)))
+        }
+
+        fn spawn_load_shard_blocks(&self, permit: OwnedSemaphorePermit, seq_no: u32) {
+            self.steps.lock().push(Step::Block(seq_no));
+            self.shards_client_mc_seq_no
+                .store(seq_no, Ordering::Release);
+
+            let mut reset_after = self.reset_after.lock();
+            if let Some((after, target)) = *reset_after {
+                if after == seq_no {
+                    *reset_after = None;
+                    *self.pending_reset.lock() = Some(mc_block_id(target));
+                }
+            }
+            drop(permit);
+        }
+    }
+
+    #[tokio::test]
+    async fn catch_up_large_gap_with_archives() {
+        const LAST_MC_SEQ_NO: u32 = 10_500;
+        const SHARDS_CLIENT_MC_SEQ_NO: u32 = LAST_MC_SEQ_NO - 500;
+
+        let client = MockShardClient::new(SHARDS_CLIENT_MC_SEQ_NO, LAST_MC_SEQ_NO);
+        walk_shard_blocks_impl(&client, mc_block_id(SHARDS_CLIENT_MC_SEQ_NO))
+            .await
+            .unwrap();
+
+        // Archives are imported until the gap is small enough,
+        // then the shard client returns to the per-block walking
+        let expected = [
+            Step::Archive(10_001, 10_100),
+            Step::Archive(10_101, 10_200),
+            Step::Archive(10_201, 10_300),
+            Step::Archive(10_301, 10_400),
+        ]
+        .into_iter()
+        .chain((10_401..=LAST_MC_SEQ_NO).map(Step::Block))
+        .collect::<Vec<_>>();
+        assert_eq!(*client.steps.lock(), expected);
+        assert!(!client.archives_mode.load(Ordering::Acquire));
+    }
+
+    #[tokio::test]
+    async fn small_gap_is_walked_block_by_block() {
+        let client = MockShardClient::new(1000, 1150);
+        walk_shard_blocks_impl(&client, mc_block_id(1000))
+            .await
+            .unwrap();
+
+        let expected = (1001..=1150).map(Step::Block).collect::<Vec<_>>();
+        assert_eq!(*client.steps.lock(), expected);
+    }
+
+    #[tokio::test]
+    async fn walking_restarts_from_reset_block() {
+        let client = MockShardClient::new(1000, 1010);
+        *client.reset_after.lock() = Some((1005, 1002));
+        walk_shard_blocks_impl(&client, mc_block_id(1000))
+            .await
+            .unwrap();
+
+        // Shard blocks after the reset target are walked again
+        let expected = (1001..=1005)
+            .map(Step::Block)
+            .chain(std::iter::once(Step::Reset(1002)))
+            .chain((1003..=1010).map(Step::Block))
+            .collect::<Vec<_>>();
+        assert_eq!(*client.steps.lock(), expected);
+    }
+
+    #[tokio::test]
+    async fn reset_shards_client_to_applied_mc_block() {
+        let dir = TempDir::new("reset_shards_client_to");
+        let engine = test_engine(&dir, Vec::new()).await;
+        let handles = engine.db.block_handle_storage();
+
+        for seq_no in 1..=10 {
+            let (handle, _) = handles
+                .create_or_load_handle(
+                    &mc_block_id(seq_no),
+                    BlockMetaData {
+                        is_key_block: false,
+                        gen_utime: seq_no,
+                        mc_ref_seqno: Some(seq_no),
+                    },
+                )
+                .unwrap();
+            handle.meta().set_is_applied();
+            // Block 5 has no state
+            if seq_no != 5 {
+                handle.meta().set_has_state();
+            }
+            handles.store_handle(&handle).unwrap();
+        }
+        let node_state = engine.db.node_state();
+        node_state.store_last_mc_block_id(&mc_block_id(10)).unwrap();
+        node_state
+            .store_shards_client_mc_block_id(&mc_block_id(10))
+            .unwrap();
+
+        let reset_error = |block_id: &ton_block::BlockIdExt| {
+            engine
+                .reset_shards_client_to(block_id)
+                .unwrap_err()
+                .downcast::<EngineError>()
+                .unwrap()
+        };
+        let shard = ton_block::ShardIdent::with_tagged_prefix(0, ton_block::SHARD_FULL).unwrap();
+        assert!(matches!(
+            reset_error(&block_id(shard, 3)),
+            EngineError::NonMasterchainShardsClientBlock
+        ));
+        // Not applied yet
+        assert!(matches!(
+            reset_error(&mc_block_id(11)),
+            EngineError::InvalidShardsClientBlock
+        ));
+        // Shard blocks can't be applied without the masterchain state
+        assert!(matches!(
+            reset_error(&mc_block_id(5)),
+            EngineError::InvalidShardsClientBlock
+        ));
+
+        let client = EngineShardClient { engine: &engine };
+        assert!(client.take_reset().is_none());
+
+        // The request itself doesn't move the pointer
+        engine.reset_shards_client_to(&mc_block_id(3)).unwrap();
+        assert_eq!(
+            engine.load_shards_client_mc_block_id().unwrap(),
+            mc_block_id(10)
+        );
+
+        // The walker moves the pointer back when it takes the request
+        let target = client.take_reset().unwrap();
+        assert_eq!(target, mc_block_id(3));
+        assert_eq!(client.reset(&target).unwrap(), mc_block_id(3));
+        assert_eq!(
+            engine.load_shards_client_mc_block_id().unwrap(),
+            mc_block_id(3)
+        );
+        assert!(client.take_reset().is_none());
+    }
+
+    #[test]
+    fn broadcast_store_horizon() {
+        const HORIZON: BroadcastStorePolicy = BroadcastStorePolicy::StoreWithinHorizon {
+            horizon: 100,
+            buffer_size: 10,
+        };
+
+        let position = |is_masterchain: bool, ref_mc_seq_no: u32| BroadcastPosition {
+            is_masterchain,
+            ref_mc_seq_no,
+            client_mc_seq_no: 1000,
+        };
+
+        for is_masterchain in [true, false] {
+            // Store all blocks by default
+            for ref_mc_seq_no in [900, 1001, 1100, 5000] {
+                assert_eq!(
+                    broadcast_store_action(
+                        BroadcastStorePolicy::StoreAll,
+                        &position(is_masterchain, ref_mc_seq_no)
+                    ),
+                    BroadcastStoreAction::Store
+                );
+            }
+
+            // Blocks within horizon are stored, others are buffered
+            for (ref_mc_seq_no, action) in [
+                (900, BroadcastStoreAction::Store),
+                (1001, BroadcastStoreAction::Store),
+                (1100, BroadcastStoreAction::Store),
+                (1101, BroadcastStoreAction::Buffer),
+                (5000, BroadcastStoreAction::Buffer),
+            ] {
+                assert_eq!(
+                    broadcast_store_action(HORIZON, &position(is_masterchain, ref_mc_seq_no)),
+                    action
+                );
+            }
+        }
+
+        // Only applicable blocks are stored
+        let apply_only = |is_masterchain: bool, ref_mc_seq_no: u32| {
+            broadcast_store_action(
+                BroadcastStorePolicy::ApplyOnly,
+                &position(is_masterchain, ref_mc_seq_no),
+            )
+        };
+        assert_eq!(apply_only(true, 1001), BroadcastStoreAction::Store);
+        assert_eq!(apply_only(true, 1002), BroadcastStoreAction::Skip);
+        assert_eq!(apply_only(false, 1008), BroadcastStoreAction::Store);
+        assert_eq!(apply_only(false, 1009), BroadcastStoreAction::Skip);
+    }
+}
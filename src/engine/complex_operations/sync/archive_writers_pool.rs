@@ -8,6 +8,7 @@ use anyhow::{Context, Result};
 use parking_lot::Mutex;
 
 use super::block_maps::*;
+use crate::db::TEMP_ARCHIVE_PREFIX;
 
 #[derive(Clone)]
 pub struct ArchiveWritersPool {
@@ -15,10 +16,15 @@ pub struct ArchiveWritersPool {
 }
 
 impl ArchiveWritersPool {
-    pub fn new(base_path: impl AsRef<Path>, save_to_disk_threshold: usize) -> Self {
+    pub fn new(
+        base_path: impl AsRef<Path>,
+        save_to_disk_threshold: usize,
+        large_archive_threshold: usize,
+    ) -> Self {
         Self {
             state: Arc::new(ArchiveWritersPoolState {
                 save_to_disk_threshold,
+                large_archive_threshold,
                 acquired_memory: Default::default(),
                 temp_file_index: Default::default(),
                 base_path: base_path.as_ref().to_path_buf(),
@@ -36,6 +42,8 @@ impl ArchiveWritersPool {
 
 struct ArchiveWritersPoolState {
     save_to_disk_threshold: usize,
+    /// Max size of a single archive kept in memory
+    large_archive_threshold: usize,
     // NOTE: `AtomicUsize` is not used here because there is a complex
     // InMemory-to-File transition
     acquired_memory: Mutex<usize>,
@@ -48,7 +56,7 @@ impl ArchiveWritersPoolState {
         let temp_file_index = self.temp_file_index.fetch_add(1, Ordering::AcqRel);
         let path = self
             .base_path
-            .join(format!("temp_archive{temp_file_index:04}"));
+            .join(format!("{TEMP_ARCHIVE_PREFIX}{temp_file_index:04}"));
 
         let file = std::fs::OpenOptions::new()
             .write(true)
@@ -67,9 +75,15 @@ pub struct ArchiveWriter {
 }
 
 impl ArchiveWriter {
-    pub fn parse_block_maps(&self) -> Result<Arc<BlockMaps>> {
-        match &self.state {
-            ArchiveWriterState::InMemory(buffer) => BlockMaps::new(buffer),
+    pub fn parse_block_maps(mut self) -> Result<Arc<BlockMaps>> {
+        match &mut self.state {
+            ArchiveWriterState::InMemory(buffer) => {
+                // NOTE: buffer is moved into block maps, so it is not tracked by the pool anymore
+                let buffer = std::mem::take(buffer);
+                *self.pool_state.acquired_memory.lock() -= buffer.len();
+
+                BlockMaps::from_bytes(buffer.into())
+            }
             ArchiveWriterState::File { file, .. } => {
                 let mapped_file =
                     FileWriterView::new(file).context("Failed to map temp archive file")?;
@@ -83,7 +97,9 @@ impl ArchiveWriter {
         if let ArchiveWriterState::InMemory(buffer) = &self.state {
             let move_to_file = {
                 let mut acquired_memory = self.pool_state.acquired_memory.lock();
-                if *acquired_memory + additional > self.pool_state.save_to_disk_threshold {
+                if *acquired_memory + additional > self.pool_state.save_to_disk_threshold
+                    || buffer.len() + additional > self.pool_state.large_archive_threshold
+                {
                     *acquired_memory -= buffer.len();
                     true
                 } else {
@@ -216,3 +232,42 @@ impl Drop for FileWriterView<'_> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::TempDir;
+
+    #[test]
+    fn large_archive_is_written_to_file() {
+        let dir = TempDir::new("archive_writers");
+        let pool = ArchiveWritersPool::new(dir.path(), 1 << 20, 256);
+        let chunk = [1u8; 100];
+
+        // Small archive stays in memory
+        let mut small = pool.acquire();
+        small.write_all(&chunk).unwrap();
+        small.write_all(&chunk).unwrap();
+        assert!(matches!(small.state, ArchiveWriterState::InMemory(_)));
+
+        // Large archive is moved into the file with all previous chunks
+        let mut large = pool.acquire();
+        for _ in 0..3 {
+            large.write_all(&chunk).unwrap();
+        }
+        large.flush().unwrap();
+        match &large.state {
+            ArchiveWriterState::File { file, .. } => {
+                let view = FileWriterView::new(file).unwrap();
+                assert_eq!(view.as_slice(), [1u8; 300]);
+            }
+            ArchiveWriterState::InMemory(_) => panic!("large archive must be written to file"),
+        }
+
+        // Memory of the moved archive is released
+        assert_eq!(*pool.state.acquired_memory.lock(), 200);
+
+        drop((small, large));
+        assert_eq!(*pool.state.acquired_memory.lock(), 0);
+    }
+}
@@ -1,19 +1,24 @@
 use std::collections::binary_heap::PeekMut;
-use std::collections::BinaryHeap;
+use std::collections::{BTreeSet, BinaryHeap};
+use std::io::Write;
 use std::ops::{Bound, Deref, DerefMut, RangeBounds};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{Context, Result};
 use broxus_util::now;
 use parking_lot::Mutex;
+use rustc_hash::FxHashSet;
 use tokio::sync::Notify;
 use tokio_util::sync::CancellationToken;
 
 use super::archive_writers_pool::*;
 use super::block_maps::*;
+use crate::config::{SyncOptions, SyncSource};
 use crate::engine::{ArchiveDownloadStatus, Engine};
 use crate::network::Neighbour;
+use crate::utils::*;
 
 pub struct ArchivesStream {
     ctx: Arc<DownloaderContext>,
@@ -24,6 +29,9 @@ pub struct ArchivesStream {
     /// The seq_no of the last archive that started downloading
     max_mc_seq_no: u32,
     to: Option<u32>,
+    /// Archive which failed to import and will be yielded again
+    retried: Option<RetriedBlockMaps>,
+    stride: ArchiveStride,
 }
 
 impl ArchivesStream {
@@ -60,23 +68,22 @@ impl ArchivesStream {
         // Enable prefetch only for historical sync when range end is known
         let prefetch_enabled = to.is_some();
 
+        let key_blocks = engine
+            .db
+            .block_handle_storage()
+            .key_blocks_index()
+            .seq_nos(from..=to.unwrap_or(u32::MAX));
+
         let mut stream = ArchivesStream {
-            ctx: Arc::new(DownloaderContext {
-                engine: engine.clone(),
-                writers_pool: ArchiveWritersPool::new(
-                    engine.db.file_db_path(),
-                    engine.sync_options.save_to_disk_threshold,
-                ),
-                new_archive_notification: Default::default(),
-                cancellation_token: Default::default(),
-                good_peers: Default::default(),
-            }),
+            ctx: Arc::new(DownloaderContext::new(engine)),
             pending_archives: Default::default(),
             prefetch_enabled,
             next_mc_seq_no: from,
             last_blocks,
             max_mc_seq_no: 0,
             to,
+            retried: None,
+            stride: ArchiveStride::new(&engine.sync_options, key_blocks),
         };
 
         // Start with only the first archive
@@ -88,12 +95,30 @@ impl ArchivesStream {
     /// Wait next archive
     #[tracing::instrument(skip(self))]
     pub async fn recv(&'_ mut self) -> ReceivedBlockMaps<'_> {
-        const STEP: u32 = BlockMaps::MAX_MC_BLOCK_COUNT as u32;
-
         let next_index = self.next_mc_seq_no;
         let mut has_gap = false;
+        let mut is_outdated = false;
 
-        let (block_maps, neighbour) = loop {
+        // NOTE: `next_mc_seq_no` is not changed until the archive is accepted,
+        // so the retried archive is always yielded before the next ones
+        if let Some(retried) = take_retried(&mut self.retried, next_index).await {
+            tracing::info!(
+                target: "sync",
+                next_index,
+                attempt = retried.attempts + 1,
+                "retrying archive import"
+            );
+            return ReceivedBlockMaps::new(
+                self,
+                next_index,
+                retried.neighbour,
+                retried.block_maps,
+                retried.memory,
+                retried.attempts,
+            );
+        }
+
+        let (block_maps, neighbour, memory) = loop {
             // Force fill gap
             if has_gap {
                 self.start_downloading(next_index);
@@ -104,23 +129,26 @@ impl ArchivesStream {
             // Get pending archive with max priority
             let notified = match self.pending_archives.peek_mut() {
                 // Process if this is an archive with required seq_no
-                Some(item) if item.index < next_index + STEP => {
+                Some(item) if self.stride.is_required(item.index, next_index) => {
                     let data = {
                         let mut data = item.block_maps.lock();
 
                         // Check lowest id without taking inner data
                         if let Some(maps) = &mut *data {
                             match maps.preload(next_index, &self.last_blocks) {
-                                Ok(block_maps) => {
-                                    if matches!(
-                                        block_maps.lowest_mc_id(),
-                                        Some(id) if id.seq_no > next_index
-                                    ) {
+                                Ok(block_maps) => match block_maps.mc_seqno_range() {
+                                    Some(range) if *range.start() > next_index => {
                                         has_gap = true;
                                         // Drop acquired lock and `PeekMut` object
                                         continue;
                                     }
-                                }
+                                    // Archive was requested before the actual range of
+                                    // the previous one was known and has nothing new
+                                    Some(range) if *range.end() < next_index => {
+                                        is_outdated = true;
+                                    }
+                                    _ => {}
+                                },
                                 Err(e) => {
                                     tracing::warn!(target: "sync", next_index, "failed to preload archive: {e:?}");
                                 }
@@ -136,13 +164,29 @@ impl ArchivesStream {
                     if let Some(data) = data {
                         // Remove this item from the queue
                         PeekMut::pop(item);
+                        self.ctx
+                            .engine
+                            .metrics
+                            .pending_archives
+                            .fetch_sub(1, Ordering::Release);
+
+                        if is_outdated {
+                            is_outdated = false;
+                            tracing::info!(target: "sync", next_index, "skipping outdated archive");
+                            continue;
+                        }
 
                         match data.loaded {
                             Some(block_maps) => {
                                 // Result item was found
-                                break (block_maps, data.neighbour);
+                                break (block_maps, data.neighbour, data.decoded_memory);
                             }
                             None => {
+                                // Don't use the peer which sent an invalid archive
+                                if let Some(neighbour) = &data.neighbour {
+                                    self.ctx.good_peers.remove(neighbour);
+                                }
+
                                 tracing::error!(target: "sync", next_index, "retrying invalid archive");
                                 continue;
                             }
@@ -164,40 +208,87 @@ impl ArchivesStream {
             notified.await;
         };
 
-        // NOTE: when `to` is Some, then we need to prefetch until
-        // `max_mc_seq_no` will be at least `STEP` greater than `to`.
-        // That's because archives must overlap:
-        //
-        //                  to -.          / discarded \
-        // |--------*-----|-----*---*----|---------*----|
-        //       mS ^       mS+STEP ^    mS+2*STEP ^
-        //
-        // > where mS is `max_mc_seq_no`
-        //
+        // Some peers serve archives which are not aligned to the stride,
+        // so make sure that the block right after this archive is requested
+        if let Some(range) = block_maps.mc_seqno_range() {
+            // NOTE: archives near the head of the chain could be incomplete
+            let is_last =
+                !self.prefetch_enabled || matches!(self.to, Some(to) if *range.end() >= to);
+            self.stride
+                .observe(&range, block_maps.starts_with_key_block(), is_last);
+            self.ensure_next_requested(range);
+        }
+
         while self.prefetch_enabled
             && self.pending_archives.len() < self.ctx.engine.sync_options.parallel_archive_downloads
-            && !matches!(self.to, Some(to) if self.max_mc_seq_no + 2 * STEP > to)
+            && !self
+                .stride
+                .is_prefetch_complete(self.max_mc_seq_no, self.to)
         {
-            self.start_downloading(self.max_mc_seq_no + STEP);
+            self.start_downloading(self.stride.next_prefetch_index(self.max_mc_seq_no));
         }
 
-        ReceivedBlockMaps {
-            stream: self,
-            index: next_index,
-            neighbour,
-            block_maps,
-            accepted: false,
+        ReceivedBlockMaps::new(self, next_index, neighbour, block_maps, memory, 0)
+    }
+
+    /// Starts downloading the archive right after the received range
+    /// if there is no pending archive which is expected to contain it
+    fn ensure_next_requested(&mut self, range: std::ops::RangeInclusive<u32>) {
+        if let Some(next) = self.next_request_after(&range) {
+            tracing::info!(
+                target: "sync",
+                next,
+                "requesting archive after the misaligned one",
+            );
+            self.start_downloading(next);
         }
     }
 
+    /// Returns the seqno of the archive which must be requested after the received range
+    /// or `None` if it is out of the sync range or is expected in one of the pending archives
+    fn next_request_after(&self, range: &std::ops::RangeInclusive<u32>) -> Option<u32> {
+        let next = *range.end() + 1;
+        if !self.prefetch_enabled || matches!(self.to, Some(to) if next > to) {
+            return None;
+        }
+
+        let expected = next..self.stride.archive_end(next);
+        if self
+            .pending_archives
+            .iter()
+            .any(|item| expected.contains(&item.index))
+        {
+            return None;
+        }
+
+        Some(next)
+    }
+
     fn start_downloading(&mut self, mc_block_seq_no: u32) {
+        // NOTE: the next required archive must not wait for the memory budget,
+        // because prefetched archives are released only after it is processed
+        let required = mc_block_seq_no <= self.next_mc_seq_no;
+
+        if self.pending_archives.len() >= self.ctx.engine.sync_options.max_pending_archives
+            && !self.evict_pending(mc_block_seq_no, required)
+        {
+            return;
+        }
+
         let block_maps = Arc::new(Mutex::new(None));
+        let cancellation_token = self.ctx.cancellation_token.child_token();
 
         // Add pending archive
         self.pending_archives.push(PendingBlockMaps {
             index: mc_block_seq_no,
             block_maps: block_maps.clone(),
+            cancellation_token: cancellation_token.clone(),
         });
+        self.ctx
+            .engine
+            .metrics
+            .pending_archives
+            .fetch_add(1, Ordering::Release);
         self.max_mc_seq_no = std::cmp::max(self.max_mc_seq_no, mc_block_seq_no);
 
         // Prepare context
@@ -205,21 +296,129 @@ impl ArchivesStream {
 
         // Spawn downloader
         tokio::spawn(async move {
-            if let Some((writer, neighbour)) = download_archive(&ctx, mc_block_seq_no).await {
-                *block_maps.lock() = Some(BlockMapsData {
-                    neighbour: Some(neighbour),
-                    writer: Some(writer),
-                    loaded: None,
-                });
+            if let Some((writer, neighbour, raw_memory)) =
+                download_archive(&ctx, &cancellation_token, mc_block_seq_no, required).await
+            {
+                // NOTE: the archive is parsed before the lock is acquired,
+                // the lock is only held to publish the result
+                let data = BlockMapsData::parse(
+                    &ctx.engine,
+                    mc_block_seq_no,
+                    neighbour,
+                    writer,
+                    raw_memory,
+                )
+                .await;
+                *block_maps.lock() = Some(data);
                 ctx.new_archive_notification.notify_waiters();
             }
         });
     }
 }
 
+impl ArchivesStream {
+    /// Makes room for the new archive when the queue is full by cancelling
+    /// one of the pending archives (see [`select_eviction`]).
+    ///
+    /// Returns `false` if the new archive itself must not be downloaded
+    fn evict_pending(&mut self, mc_block_seq_no: u32, required: bool) -> bool {
+        let metrics = &self.ctx.engine.metrics;
+
+        let evicted = match select_eviction(
+            &self.pending_archives,
+            self.next_mc_seq_no,
+            mc_block_seq_no,
+            required,
+        ) {
+            Eviction::Evict(index) => index,
+            Eviction::Skip => {
+                tracing::debug!(
+                    target: "sync",
+                    mc_block_seq_no,
+                    len = self.pending_archives.len(),
+                    "archives queue is full, skipping prefetch"
+                );
+                metrics.evicted_archives.fetch_add(1, Ordering::Release);
+                return false;
+            }
+        };
+
+        let mut items = std::mem::take(&mut self.pending_archives).into_vec();
+        if let Some(index) = items.iter().position(|item| item.index == evicted) {
+            let evicted = items.swap_remove(index);
+            evicted.cancellation_token.cancel();
+            metrics.pending_archives.fetch_sub(1, Ordering::Release);
+            metrics.evicted_archives.fetch_add(1, Ordering::Release);
+        }
+        self.pending_archives = items.into();
+
+        if evicted > self.next_mc_seq_no {
+            tracing::info!(target: "sync", index = evicted, "archives queue is full, evicted prefetched archive");
+        } else {
+            tracing::warn!(
+                target: "sync",
+                index = evicted,
+                mc_block_seq_no,
+                "archives queue is full of required archives, evicted the lowest one"
+            );
+        }
+
+        // Prefetch will continue right after the remaining archives
+        self.max_mc_seq_no = self
+            .pending_archives
+            .iter()
+            .map(|item| item.index)
+            .max()
+            .unwrap_or(self.next_mc_seq_no);
+        true
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Eviction {
+    /// Cancel the pending archive with the specified index
+    Evict(u32),
+    /// Don't download the new archive
+    Skip,
+}
+
+/// Selects the archive to cancel when the pending queue is full.
+///
+/// Prefetched archives with the highest index are evicted first. Archives which
+/// are required for the next import are evicted only for the new required archive,
+/// starting from the lowest (most likely outdated) one, so the queue never
+/// grows past the limit
+fn select_eviction(
+    pending_archives: &BinaryHeap<PendingBlockMaps>,
+    next_mc_seq_no: u32,
+    mc_block_seq_no: u32,
+    required: bool,
+) -> Eviction {
+    match pending_archives.iter().map(|item| item.index).max() {
+        Some(farthest) if farthest > next_mc_seq_no => {
+            if !required && mc_block_seq_no >= farthest {
+                Eviction::Skip
+            } else {
+                Eviction::Evict(farthest)
+            }
+        }
+        // NOTE: `BinaryHeap` is reversed, so the lowest index is at the top
+        _ if required => match pending_archives.peek() {
+            Some(lowest) => Eviction::Evict(lowest.index),
+            None => Eviction::Skip,
+        },
+        _ => Eviction::Skip,
+    }
+}
+
 impl Drop for ArchivesStream {
     fn drop(&mut self) {
         self.ctx.cancellation_token.cancel();
+        self.ctx
+            .engine
+            .metrics
+            .pending_archives
+            .fetch_sub(self.pending_archives.len() as u64, Ordering::Release);
     }
 }
 
@@ -229,6 +428,27 @@ struct DownloaderContext {
     new_archive_notification: Notify,
     cancellation_token: CancellationToken,
     good_peers: GoodPeers,
+    local_first: bool,
+    /// Indices of the archives which were already read from the local DB
+    local_archives_used: Mutex<FxHashSet<u32>>,
+}
+
+impl DownloaderContext {
+    fn new(engine: &Arc<Engine>) -> Self {
+        Self {
+            engine: engine.clone(),
+            writers_pool: ArchiveWritersPool::new(
+                engine.db.temp_files_path(),
+                engine.sync_options.save_to_disk_threshold,
+                engine.sync_options.large_archive_threshold,
+            ),
+            new_archive_notification: Default::default(),
+            cancellation_token: Default::default(),
+            good_peers: Default::default(),
+            local_first: engine.sync_options.source == SyncSource::LocalFirst,
+            local_archives_used: Default::default(),
+        }
+    }
 }
 
 #[derive(Default)]
@@ -277,9 +497,135 @@ type GoodPeerSlot = Option<Arc<Neighbour>>;
 
 const GOOD_PEER_COUNT: usize = 4;
 
+/// Expected number of masterchain blocks in archives served by peers.
+///
+/// Starts from the configured values and follows the size of received archives
+/// when two consecutive ones have the same size (single archives could be cut
+/// by key blocks or by the head of the chain)
+struct ArchiveStride {
+    stride: u32,
+    key_block_stride: Option<u32>,
+    /// Learned strides are never less than this value
+    min_stride: u32,
+    /// Known key blocks of the synced range. Each key block starts a new archive
+    key_blocks: BTreeSet<u32>,
+    /// Size of the previous received archive and whether it started with a key block
+    last_len: Option<(u32, bool)>,
+}
+
+impl ArchiveStride {
+    fn new(options: &SyncOptions, key_blocks: BTreeSet<u32>) -> Self {
+        let min_configured = match options.key_block_archive_stride {
+            Some(stride) => std::cmp::min(stride, options.archive_stride),
+            None => options.archive_stride,
+        };
+
+        Self {
+            stride: options.archive_stride,
+            key_block_stride: options.key_block_archive_stride,
+            min_stride: std::cmp::max(min_configured / MIN_LEARNED_STRIDE_DIVISOR, 1),
+            key_blocks,
+            last_len: None,
+        }
+    }
+
+    /// Expected number of masterchain blocks in the archive
+    fn archive_len(&self, starts_with_key_block: bool) -> u32 {
+        match self.key_block_stride {
+            Some(stride) if starts_with_key_block => stride,
+            _ => self.stride,
+        }
+    }
+
+    /// Expected seqno of the first block after the archive which starts at `index`
+    fn archive_end(&self, index: u32) -> u32 {
+        let len = self.archive_len(self.key_blocks.contains(&index));
+        let end = index.saturating_add(len);
+
+        // Archive is cut by the next key block
+        match self.key_blocks.range(index.saturating_add(1)..end).next() {
+            Some(&key_block) => key_block,
+            None => end,
+        }
+    }
+
+    /// Whether the pending archive could contain the next required block.
+    ///
+    /// NOTE: pending archives are yielded from the lowest index, so the lowest
+    /// required archive is awaited even if higher ones were downloaded earlier
+    fn is_required(&self, index: u32, next_index: u32) -> bool {
+        index < self.archive_end(next_index)
+    }
+
+    fn next_prefetch_index(&self, max_index: u32) -> u32 {
+        self.archive_end(max_index)
+    }
+
+    /// NOTE: when `to` is Some, then we need to prefetch until
+    /// the archive after `max_index` will end after `to`.
+    /// That's because archives must overlap:
+    ///
+    /// ```text
+    ///                  to -.             / discarded \
+    /// |--------*-----|-----*---*-------|---------*----|
+    ///       mS ^       mS+stride ^   mS+2*stride ^
+    /// ```
+    ///
+    /// > where mS is `max_index`
+    fn is_prefetch_complete(&self, max_index: u32, to: Option<u32>) -> bool {
+        matches!(to, Some(to) if self.archive_end(self.archive_end(max_index)) > to)
+    }
+
+    /// Updates the stride from the received archive.
+    ///
+    /// NOTE: the last archive of the range could be incomplete, so it is ignored.
+    /// Archives cut by the known key block and too short archives are ignored too
+    fn observe(
+        &mut self,
+        range: &std::ops::RangeInclusive<u32>,
+        starts_with_key_block: bool,
+        is_last: bool,
+    ) {
+        if starts_with_key_block {
+            self.key_blocks.insert(*range.start());
+        }
+
+        let len = range.end() - range.start() + 1;
+        if is_last
+            || len < self.min_stride
+            || self.key_blocks.contains(&range.end().saturating_add(1))
+        {
+            return;
+        }
+
+        let confirmed = self.last_len == Some((len, starts_with_key_block));
+        self.last_len = Some((len, starts_with_key_block));
+        if !confirmed {
+            return;
+        }
+
+        let stride = match &mut self.key_block_stride {
+            Some(stride) if starts_with_key_block => stride,
+            _ => &mut self.stride,
+        };
+        if *stride != len {
+            tracing::info!(
+                target: "sync",
+                prev = *stride,
+                new = len,
+                starts_with_key_block,
+                "archive stride changed"
+            );
+            *stride = len;
+        }
+    }
+}
+
 struct PendingBlockMaps {
     index: u32,
     block_maps: Arc<Mutex<Option<BlockMapsData>>>,
+    /// Cancels the download of this archive
+    cancellation_token: CancellationToken,
 }
 
 impl PartialEq for PendingBlockMaps {
@@ -305,23 +651,65 @@ impl Ord for PendingBlockMaps {
 
 struct BlockMapsData {
     neighbour: Option<Arc<Neighbour>>,
+    /// Parsed archive which was not checked yet, `None` if the archive is invalid
+    parsed: Option<Arc<BlockMaps>>,
+    /// Parsed and checked archive
     loaded: Option<Arc<BlockMaps>>,
-    writer: Option<ArchiveWriter>,
+    decoded_memory: Option<MemoryBudgetGuard>,
 }
 
 impl BlockMapsData {
+    /// Parses the downloaded archive in the blocking pool
+    async fn parse(
+        engine: &Engine,
+        mc_seq_no: u32,
+        neighbour: Option<Arc<Neighbour>>,
+        writer: ArchiveWriter,
+        raw_memory: MemoryBudgetGuard,
+    ) -> Self {
+        // NOTE: in-memory archive is shared by the parsed entries,
+        // so it is accounted as a part of the decoded size
+        let decoded_size = raw_memory.bytes() * DECODED_ARCHIVE_SIZE_FACTOR;
+
+        let parsed = match engine
+            .blocking_pool
+            .run(move || {
+                writer
+                    .parse_block_maps()
+                    .context("Failed to load block maps")
+            })
+            .await
+        {
+            Ok(block_maps) => Some(block_maps),
+            Err(e) => {
+                tracing::warn!(target: "sync", mc_seq_no, "failed to parse archive: {e:?}");
+                None
+            }
+        };
+
+        let decoded_memory = parsed.as_ref().map(|_| {
+            engine
+                .memory_budget
+                .force_acquire(MemoryCategory::BlockMaps, decoded_size)
+        });
+        drop(raw_memory);
+
+        Self {
+            neighbour,
+            parsed,
+            loaded: None,
+            decoded_memory,
+        }
+    }
+
     fn preload(
         &'_ mut self,
         next_index: u32,
         edge: &Option<BlockMapsEdge>,
     ) -> Result<&'_ Arc<BlockMaps>> {
         if self.loaded.is_none() {
-            if let Some(writer) = self.writer.take() {
-                let block_maps = writer
-                    .parse_block_maps()
-                    .context("Failed to load block maps")?;
+            if let Some(block_maps) = self.parsed.take() {
                 block_maps.check(next_index, edge)?;
-
                 self.loaded = Some(block_maps);
             }
         }
@@ -338,36 +726,239 @@ pub struct ReceivedBlockMaps<'a> {
     stream: &'a mut ArchivesStream,
     index: u32,
     neighbour: Option<Arc<Neighbour>>,
-    block_maps: Arc<BlockMaps>,
+    /// `None` if the maps were taken by [`ReceivedBlockMaps::take_block_maps`]
+    block_maps: Option<Arc<BlockMaps>>,
+    memory: Option<MemoryBudgetGuard>,
+    highest_mc_seq_no: Option<u32>,
+    mc_block_count: usize,
+    /// Number of failed import attempts of this archive
+    attempts: u32,
     accepted: bool,
 }
 
+impl<'a> ReceivedBlockMaps<'a> {
+    fn new(
+        stream: &'a mut ArchivesStream,
+        index: u32,
+        neighbour: Option<Arc<Neighbour>>,
+        block_maps: Arc<BlockMaps>,
+        memory: Option<MemoryBudgetGuard>,
+        attempts: u32,
+    ) -> Self {
+        Self {
+            stream,
+            index,
+            neighbour,
+            highest_mc_seq_no: block_maps.highest_mc_id().map(|id| id.seq_no),
+            mc_block_count: block_maps.mc_block_ids.len(),
+            block_maps: Some(block_maps),
+            memory,
+            attempts,
+            accepted: false,
+        }
+    }
+}
+
 impl ReceivedBlockMaps<'_> {
     pub fn accept(mut self, edge: Option<BlockMapsEdge>) {
         self.accepted = true;
-        if let Some(highest_mc_id) = self.block_maps.highest_mc_id() {
+        if let Some(highest_mc_seq_no) = self.highest_mc_seq_no {
             self.stream.last_blocks = edge;
-            self.stream.next_mc_seq_no = highest_mc_id.seq_no + 1;
+            self.stream.next_mc_seq_no = highest_mc_seq_no + 1;
         }
     }
 
+    /// Number of masterchain blocks in the archive
+    pub fn mc_block_count(&self) -> usize {
+        self.mc_block_count
+    }
+
+    /// Takes the maps with their memory reservation, so that they
+    /// are released as soon as the caller no longer needs them.
+    ///
+    /// NOTE: the archive can't be retried without a new download afterwards
+    pub fn take_block_maps(&mut self) -> Option<TakenBlockMaps> {
+        let maps = self.block_maps.take()?;
+        Some(TakenBlockMaps {
+            maps,
+            memory: self.memory.take(),
+        })
+    }
+
     pub fn accept_with_time(self, time: u32, edge: Option<BlockMapsEdge>) {
         self.stream.prefetch_enabled = time + ARCHIVE_EXISTENCE_THRESHOLD <= now();
         self.accept(edge);
     }
+
+    /// Drops the invalid archive and penalizes the peer it was downloaded from.
+    ///
+    /// NOTE: archive will be downloaded again on drop
+    pub fn reject(self) {
+        if let Some(neighbour) = &self.neighbour {
+            tracing::warn!(target: "sync", index = self.index, "archive rejected");
+            neighbour.penalize();
+        }
+    }
+
+    /// Keeps the archive to yield it again after a backoff.
+    ///
+    /// Should be used when the import failed not because of the archive itself
+    /// (e.g. DB stall). Falls back to a new download after `max_import_attempts`
+    pub fn retry(mut self) {
+        let block_maps = match self.block_maps.take() {
+            Some(block_maps) => block_maps,
+            None => {
+                tracing::warn!(
+                    target: "sync",
+                    index = self.index,
+                    "archive was already released, downloading it again"
+                );
+                // NOTE: archive will be downloaded again on drop
+                return;
+            }
+        };
+
+        let attempts = self.attempts + 1;
+        let options = &self.stream.ctx.engine.sync_options;
+        let delay = match import_retry_delay(
+            attempts,
+            options.max_import_attempts,
+            Duration::from_millis(options.import_retry_interval_ms),
+        ) {
+            Some(delay) => delay,
+            None => {
+                tracing::warn!(
+                    target: "sync",
+                    index = self.index,
+                    attempts,
+                    "archive import attempts exhausted"
+                );
+                // NOTE: archive will be downloaded again on drop
+                return;
+            }
+        };
+
+        self.accepted = true;
+        self.stream.retried = Some(RetriedBlockMaps {
+            index: self.index,
+            attempts,
+            retry_at: tokio::time::Instant::now() + delay,
+            neighbour: self.neighbour.take(),
+            block_maps,
+            memory: self.memory.take(),
+        });
+    }
+}
+
+/// Archive which failed to import
+struct RetriedBlockMaps {
+    index: u32,
+    attempts: u32,
+    retry_at: tokio::time::Instant,
+    neighbour: Option<Arc<Neighbour>>,
+    block_maps: Arc<BlockMaps>,
+    memory: Option<MemoryBudgetGuard>,
+}
+
+/// Waits until the retried archive with the specified index can be imported again
+async fn take_retried(
+    retried: &mut Option<RetriedBlockMaps>,
+    next_index: u32,
+) -> Option<RetriedBlockMaps> {
+    match retried {
+        Some(item) if item.index == next_index => {
+            // NOTE: archive is taken only after the sleep so that
+            // it is not lost if the future is cancelled
+            tokio::time::sleep_until(item.retry_at).await;
+            retried.take()
+        }
+        Some(item) => {
+            // NOTE: `next_mc_seq_no` is not changed while the archive is retried,
+            // so this means that the stream state is broken
+            tracing::error!(
+                target: "sync",
+                retried_index = item.index,
+                next_index,
+                "retried archive doesn't match the next index, dropping it"
+            );
+            *retried = None;
+            None
+        }
+        None => None,
+    }
+}
+
+/// Returns the delay before the next import attempt
+/// or `None` if all attempts are exhausted
+fn import_retry_delay(attempts: u32, max_attempts: u32, interval: Duration) -> Option<Duration> {
+    const MAX_BACKOFF_SHIFT: u32 = 6;
+
+    (attempts < max_attempts)
+        .then(|| interval * (1 << std::cmp::min(attempts.saturating_sub(1), MAX_BACKOFF_SHIFT)))
+}
+
+#[cfg(test)]
+impl ArchivesStream {
+    /// Stream over the already downloaded archives without prefetch
+    pub(super) fn with_loaded_archives(
+        engine: &Arc<Engine>,
+        from: u32,
+        archives: &[(u32, Arc<BlockMaps>)],
+    ) -> Self {
+        let pending_archives = archives
+            .iter()
+            .map(|(index, block_maps)| PendingBlockMaps {
+                index: *index,
+                block_maps: Arc::new(Mutex::new(Some(BlockMapsData {
+                    neighbour: None,
+                    parsed: None,
+                    loaded: Some(block_maps.clone()),
+                    decoded_memory: None,
+                }))),
+                cancellation_token: Default::default(),
+            })
+            .collect();
+        engine
+            .metrics
+            .pending_archives
+            .fetch_add(archives.len() as u64, Ordering::Release);
+
+        Self {
+            ctx: Arc::new(DownloaderContext::new(engine)),
+            pending_archives,
+            prefetch_enabled: false,
+            next_mc_seq_no: from,
+            last_blocks: None,
+            max_mc_seq_no: archives
+                .iter()
+                .map(|(index, _)| *index)
+                .max()
+                .unwrap_or_default(),
+            to: None,
+            retried: None,
+            stride: ArchiveStride::new(&engine.sync_options, Default::default()),
+        }
+    }
+}
+
+/// Archive maps with the memory reserved for them
+pub struct TakenBlockMaps {
+    pub maps: Arc<BlockMaps>,
+    pub memory: Option<MemoryBudgetGuard>,
 }
 
+/// NOTE: panics if the maps were taken by [`ReceivedBlockMaps::take_block_maps`]
 impl Deref for ReceivedBlockMaps<'_> {
     type Target = Arc<BlockMaps>;
 
     fn deref(&self) -> &Self::Target {
-        &self.block_maps
+        self.block_maps.as_ref().expect("block maps were taken")
     }
 }
 
 impl DerefMut for ReceivedBlockMaps<'_> {
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.block_maps
+        self.block_maps.as_mut().expect("block maps were taken")
     }
 }
 
@@ -387,12 +978,40 @@ impl Drop for ReceivedBlockMaps<'_> {
 
 async fn download_archive(
     ctx: &DownloaderContext,
+    cancellation_token: &CancellationToken,
     mc_seq_no: u32,
-) -> Option<(ArchiveWriter, Arc<Neighbour>)> {
+    required: bool,
+) -> Option<(ArchiveWriter, Option<Arc<Neighbour>>, MemoryBudgetGuard)> {
     tokio::pin!(
-        let signal = ctx.cancellation_token.cancelled();
+        let signal = cancellation_token.cancelled();
     );
 
+    let memory_budget = &ctx.engine.memory_budget;
+    let mut memory = if required {
+        memory_budget.force_acquire(MemoryCategory::Archives, ARCHIVE_SIZE_ESTIMATE)
+    } else {
+        tokio::select! {
+            guard = memory_budget.acquire(MemoryCategory::Archives, ARCHIVE_SIZE_ESTIMATE) => guard,
+            _ = (&mut signal) => return None,
+        }
+    };
+
+    if ctx.local_first && ctx.local_archives_used.lock().insert(mc_seq_no) {
+        match load_local_archive(ctx, mc_seq_no).await {
+            Ok(Some((writer, len))) => {
+                tracing::debug!(target: "sync", mc_seq_no, bytes_len = len, "using local archive");
+                memory.resize(len);
+                return Some((writer, None, memory));
+            }
+            Ok(None) => {
+                tracing::debug!(target: "sync", mc_seq_no, "no local archive found");
+            }
+            Err(e) => {
+                tracing::warn!(target: "sync", mc_seq_no, "failed to read local archive: {e:?}");
+            }
+        }
+    }
+
     tracing::info!(target: "sync", mc_seq_no, "downloading archive");
 
     loop {
@@ -416,7 +1035,8 @@ async fn download_archive(
                     elapsed_ms = start.elapsed().as_millis(),
                     "downloaded archive",
                 );
-                break Some((writer, neighbour));
+                memory.resize(len);
+                break Some((writer, Some(neighbour), memory));
             }
             Ok(ArchiveDownloadStatus::NotFound) => {
                 if let Some(neighbour) = &good_peer {
@@ -434,6 +1054,37 @@ async fn download_archive(
     }
 }
 
+/// Copies the stored archive with the specified masterchain block into the new writer.
+///
+/// NOTE: the archive could be large and written to the temp file,
+/// so it is copied outside the async runtime threads
+async fn load_local_archive(
+    ctx: &DownloaderContext,
+    mc_seq_no: u32,
+) -> Result<Option<(ArchiveWriter, usize)>> {
+    let engine = ctx.engine.clone();
+    let mut writer = ctx.writers_pool.acquire();
+
+    tokio::task::spawn_blocking(move || {
+        let block_storage = engine.db.block_storage();
+
+        let archive = match block_storage.get_archive_id(mc_seq_no) {
+            Some(archive_id) => block_storage.get_archive(archive_id)?,
+            None => None,
+        };
+        let archive = match archive {
+            Some(archive) => archive,
+            None => return Ok(None),
+        };
+
+        writer
+            .write_all(&archive)
+            .context("Failed to write local archive")?;
+        Ok(Some((writer, archive.len())))
+    })
+    .await?
+}
+
 #[derive(Debug, thiserror::Error)]
 enum ArchivesStreamError {
     #[error("Empty block maps data")]
@@ -441,3 +1092,424 @@ enum ArchivesStreamError {
 }
 
 const ARCHIVE_EXISTENCE_THRESHOLD: u32 = 1800;
+
+/// Learned strides can't be less than the configured ones divided by this value
+const MIN_LEARNED_STRIDE_DIVISOR: u32 = 4;
+
+/// Memory reserved for the archive before its size is known
+const ARCHIVE_SIZE_ESTIMATE: usize = 32 * 1024 * 1024;
+/// Approximate ratio between decoded block maps and the raw archive
+pub(super) const DECODED_ARCHIVE_SIZE_FACTOR: usize = 2;
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    fn pending(index: u32) -> PendingBlockMaps {
+        PendingBlockMaps {
+            index,
+            block_maps: Default::default(),
+            cancellation_token: Default::default(),
+        }
+    }
+
+    fn retried(index: u32, attempts: u32, delay: Duration) -> RetriedBlockMaps {
+        RetriedBlockMaps {
+            index,
+            attempts,
+            retry_at: tokio::time::Instant::now() + delay,
+            neighbour: None,
+            block_maps: Arc::new(BlockMaps {
+                mc_block_ids: BTreeMap::new(),
+                blocks: BTreeMap::new(),
+            }),
+            memory: None,
+        }
+    }
+
+    #[test]
+    fn lowest_required_archive_is_selected() {
+        let stride = stride(100, None);
+
+        // Several archives are downloaded in arbitrary order
+        let mut pending_archives = BinaryHeap::new();
+        for index in [301, 101, 401, 1, 201] {
+            pending_archives.push(pending(index));
+        }
+
+        let next_index = 101;
+        let item = pending_archives.peek().unwrap();
+        assert_eq!(item.index, 1);
+        assert!(stride.is_required(item.index, next_index));
+
+        // Outdated archive is removed, the next one starts exactly at the next block
+        pending_archives.pop();
+        let item = pending_archives.peek().unwrap();
+        assert_eq!(item.index, next_index);
+        assert!(stride.is_required(item.index, next_index));
+
+        // Archives after the stride are not required yet
+        assert!(!stride.is_required(201, next_index));
+
+        let indices = std::iter::from_fn(|| pending_archives.pop().map(|item| item.index))
+            .collect::<Vec<_>>();
+        assert_eq!(indices, [101, 201, 301, 401]);
+    }
+
+    #[test]
+    fn pending_archives_eviction() {
+        let queue = |indices: &[u32]| {
+            indices
+                .iter()
+                .copied()
+                .map(pending)
+                .collect::<BinaryHeap<_>>()
+        };
+
+        // The farthest prefetched archive is evicted for the closer one
+        let pending_archives = queue(&[101, 201, 301, 401]);
+        assert_eq!(
+            select_eviction(&pending_archives, 101, 251, false),
+            Eviction::Evict(401)
+        );
+        assert_eq!(
+            select_eviction(&pending_archives, 101, 101, true),
+            Eviction::Evict(401)
+        );
+
+        // Prefetch after the farthest archive is skipped
+        assert_eq!(
+            select_eviction(&pending_archives, 101, 401, false),
+            Eviction::Skip
+        );
+        assert_eq!(
+            select_eviction(&pending_archives, 101, 501, false),
+            Eviction::Skip
+        );
+
+        // Queue is full of required archives
+        let pending_archives = queue(&[51, 1, 101]);
+        assert_eq!(
+            select_eviction(&pending_archives, 101, 201, false),
+            Eviction::Skip
+        );
+        assert_eq!(
+            select_eviction(&pending_archives, 101, 101, true),
+            Eviction::Evict(1)
+        );
+    }
+
+    #[test]
+    fn import_retry_backoff() {
+        let interval = Duration::from_secs(1);
+        assert_eq!(import_retry_delay(1, 3, interval), Some(interval));
+        assert_eq!(import_retry_delay(2, 3, interval), Some(interval * 2));
+        assert_eq!(import_retry_delay(3, 3, interval), None);
+        assert_eq!(import_retry_delay(1, 1, interval), None);
+        assert_eq!(import_retry_delay(100, 1000, interval), Some(interval * 64));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_import_is_retried_without_download() {
+        let delay = Duration::from_secs(1);
+
+        // First import attempt failed
+        let item = retried(100, 1, delay);
+        let block_maps = item.block_maps.clone();
+        let mut slot = Some(item);
+
+        // Retried archive is yielded only after the backoff
+        let start = tokio::time::Instant::now();
+        let item = take_retried(&mut slot, 100).await.unwrap();
+        assert!(start.elapsed() >= delay);
+        assert!(slot.is_none());
+
+        // The same parsed archive is reused for the second attempt
+        assert!(Arc::ptr_eq(&item.block_maps, &block_maps));
+        assert_eq!(item.attempts, 1);
+
+        // Retried archive is not yielded for other indices
+        let mut slot = Some(retried(100, 1, delay));
+        assert!(take_retried(&mut slot, 200).await.is_none());
+        assert!(slot.is_none());
+    }
+
+    #[tokio::test]
+    async fn local_archive_is_copied() {
+        let now = broxus_util::now();
+        let chain =
+            crate::test_util::SyntheticChain::generate(4, now - 100, |seq_no| now - 100 + seq_no)
+                .unwrap();
+
+        let dir = crate::test_helpers::TempDir::new("archives_stream_local");
+        chain.create_db(dir.path()).await.unwrap();
+        let engine = crate::test_helpers::test_engine_with_global_config(
+            &dir,
+            chain.global_config(),
+            Vec::new(),
+        )
+        .await;
+        let ctx = DownloaderContext::new(&engine);
+
+        let block_storage = engine.db.block_storage();
+        let archive_id = block_storage.get_archive_id(1).unwrap();
+        let stored = block_storage
+            .get_archive(archive_id)
+            .unwrap()
+            .unwrap()
+            .to_vec();
+
+        let (writer, len) = load_local_archive(&ctx, 1).await.unwrap().unwrap();
+        assert_eq!(len, stored.len());
+
+        let block_maps = writer.parse_block_maps().unwrap();
+        assert_eq!(
+            block_maps.mc_block_ids.keys().copied().collect::<Vec<_>>(),
+            [1, 2, 3, 4]
+        );
+
+        // Seqno without the stored archive
+        assert!(load_local_archive(&ctx, u32::MAX).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn stream_yields_retried_archive_again() {
+        let dir = crate::test_helpers::TempDir::new("archives_stream_retry");
+        let engine = crate::test_helpers::test_engine(&dir, Vec::new()).await;
+
+        let first = mc_archive(1..=3);
+        let second = mc_archive(4..=6);
+
+        let mut stream = ArchivesStream::with_loaded_archives(
+            &engine,
+            1,
+            &[(1, first.clone()), (4, second.clone())],
+        );
+
+        // First import attempt fails
+        let received = stream.recv().await;
+        assert!(Arc::ptr_eq(&*received, &first));
+        received.retry();
+        assert!(stream.retried.is_some());
+
+        // The same archive is yielded again without a new download
+        let received = stream.recv().await;
+        assert!(Arc::ptr_eq(&*received, &first));
+        assert_eq!(received.attempts, 1);
+        received.accept(None);
+        assert!(stream.retried.is_none());
+        assert_eq!(stream.pending_archives.len(), 1);
+
+        // The stream continues with the next archive
+        let received = stream.recv().await;
+        assert!(Arc::ptr_eq(&*received, &second));
+        received.accept(None);
+        assert_eq!(stream.next_mc_seq_no, 7);
+        assert!(stream.pending_archives.is_empty());
+    }
+
+    #[tokio::test]
+    async fn outdated_archive_is_skipped() {
+        let dir = crate::test_helpers::TempDir::new("archives_stream_outdated");
+        let engine = crate::test_helpers::test_engine(&dir, Vec::new()).await;
+
+        // Archive at 3 was requested before the actual range of the first one was known
+        let first = mc_archive(1..=5);
+        let outdated = mc_archive(3..=4);
+        let next = mc_archive(6..=8);
+        let mut stream = ArchivesStream::with_loaded_archives(
+            &engine,
+            1,
+            &[(1, first.clone()), (3, outdated), (6, next.clone())],
+        );
+
+        let received = stream.recv().await;
+        assert!(Arc::ptr_eq(&*received, &first));
+        received.accept(None);
+        assert_eq!(stream.next_mc_seq_no, 6);
+
+        // Outdated archive has nothing new, so it is dropped
+        let received = stream.recv().await;
+        assert!(Arc::ptr_eq(&*received, &next));
+        received.accept(None);
+        assert_eq!(stream.next_mc_seq_no, 9);
+        assert!(stream.pending_archives.is_empty());
+    }
+
+    #[tokio::test]
+    async fn next_archive_is_requested_after_misaligned_one() {
+        let dir = crate::test_helpers::TempDir::new("archives_stream_next_request");
+        let engine = crate::test_helpers::test_engine(&dir, Vec::new()).await;
+
+        let mut stream =
+            ArchivesStream::with_loaded_archives(&engine, 1, &[(101, mc_archive(101..=110))]);
+        stream.stride = stride(100, None);
+        stream.to = Some(1000);
+
+        // Nothing is requested without prefetch
+        assert_eq!(stream.next_request_after(&(1..=50)), None);
+        stream.prefetch_enabled = true;
+
+        // Pending archive is expected to contain the next block
+        assert_eq!(stream.next_request_after(&(1..=100)), None);
+        assert_eq!(stream.next_request_after(&(1..=50)), None);
+
+        // Next block is after the pending archive
+        assert_eq!(stream.next_request_after(&(101..=150)), Some(151));
+        assert_eq!(stream.next_request_after(&(1..=250)), Some(251));
+
+        // Next block is out of the sync range
+        assert_eq!(stream.next_request_after(&(901..=999)), Some(1000));
+        assert_eq!(stream.next_request_after(&(901..=1000)), None);
+    }
+
+    /// Parsed archive with empty masterchain blocks in the specified range
+    fn mc_archive(range: std::ops::RangeInclusive<u32>) -> Arc<BlockMaps> {
+        let blocks = range
+            .map(crate::test_helpers::make_mc_block)
+            .collect::<Vec<_>>();
+        BlockMaps::new(&crate::test_helpers::make_archive(&blocks)).unwrap()
+    }
+
+    fn stride(stride: u32, key_block_stride: Option<u32>) -> ArchiveStride {
+        stride_with_key_blocks(stride, key_block_stride, &[])
+    }
+
+    fn stride_with_key_blocks(
+        stride: u32,
+        key_block_stride: Option<u32>,
+        key_blocks: &[u32],
+    ) -> ArchiveStride {
+        ArchiveStride::new(
+            &SyncOptions {
+                archive_stride: stride,
+                key_block_archive_stride: key_block_stride,
+                ..Default::default()
+            },
+            key_blocks.iter().copied().collect(),
+        )
+    }
+
+    /// Simulates the prefetch of the historical sync and returns requested indices
+    fn prefetch_indices(stride: &ArchiveStride, from: u32, to: u32) -> Vec<u32> {
+        let mut indices = vec![from];
+        let mut max_index = from;
+        while !stride.is_prefetch_complete(max_index, Some(to)) {
+            max_index = stride.next_prefetch_index(max_index);
+            indices.push(max_index);
+        }
+        indices
+    }
+
+    #[test]
+    fn archive_scheduling_math() {
+        for (archive_stride, expected) in [
+            (100, vec![1, 101, 201]),
+            (20, (0..19).map(|i| 1 + i * 20).collect()),
+            (500, vec![1]),
+            (1, (1..=399).collect()),
+        ] {
+            let stride = stride(archive_stride, None);
+            let indices = prefetch_indices(&stride, 1, 400);
+            assert_eq!(indices, expected, "stride {archive_stride}");
+
+            // The last archive overlaps the end of the range
+            let last = *indices.last().unwrap();
+            assert!(last + 2 * archive_stride > 400);
+
+            // Only archives before the next stride are required
+            assert!(stride.is_required(1, 1));
+            assert!(stride.is_required(archive_stride, 1));
+            assert!(!stride.is_required(archive_stride + 1, 1));
+        }
+
+        // No overflow near the end of the seqno range
+        let stride = stride(100, None);
+        assert!(stride.is_required(u32::MAX - 1, u32::MAX - 50));
+        assert!(stride.is_prefetch_complete(u32::MAX - 50, Some(u32::MAX - 1)));
+        assert!(!stride.is_prefetch_complete(u32::MAX - 50, None));
+    }
+
+    #[test]
+    fn archive_stride_is_learned() {
+        let mut stride = stride(100, Some(50));
+        assert_eq!(stride.archive_len(false), 100);
+        assert_eq!(stride.archive_len(true), 50);
+
+        // Single archive cut by the key block doesn't change the stride
+        stride.observe(&(1..=40), false, false);
+        stride.observe(&(41..=60), true, false);
+        assert_eq!(stride.archive_len(false), 100);
+        assert_eq!(stride.archive_len(true), 50);
+
+        // Two consecutive archives of the same size
+        stride.observe(&(61..=80), false, false);
+        stride.observe(&(81..=100), false, false);
+        assert_eq!(stride.archive_len(false), 20);
+        assert_eq!(stride.archive_len(true), 50);
+
+        stride.observe(&(101..=130), true, false);
+        stride.observe(&(131..=160), true, false);
+        assert_eq!(stride.archive_len(true), 30);
+
+        // The last archive of the range could be incomplete
+        stride.observe(&(161..=165), false, true);
+        stride.observe(&(161..=165), false, true);
+        assert_eq!(stride.archive_len(false), 20);
+    }
+
+    #[test]
+    fn learned_stride_is_bounded() {
+        let mut stride = stride(100, None);
+
+        // Archives near the head are short, but they don't shrink the stride
+        for start in [1, 11, 21] {
+            stride.observe(&(start..=start + 9), false, false);
+        }
+        assert_eq!(stride.archive_len(false), 100);
+
+        // Archives of the minimal size are still accepted
+        stride.observe(&(31..=55), false, false);
+        stride.observe(&(56..=80), false, false);
+        assert_eq!(stride.archive_len(false), 25);
+    }
+
+    #[test]
+    fn archive_cut_by_key_block_is_ignored() {
+        let mut stride = stride_with_key_blocks(100, None, &[161]);
+
+        // Both archives end right before key blocks
+        stride.observe(&(1..=60), false, false);
+        stride.observe(&(61..=120), true, false);
+        stride.observe(&(121..=160), false, false);
+        stride.observe(&(121..=160), false, false);
+        assert_eq!(stride.archive_len(false), 100);
+
+        // Key block is learned from the received archive
+        assert!(stride.key_blocks.contains(&61));
+        assert_eq!(stride.archive_end(1), 61);
+    }
+
+    #[test]
+    fn key_block_scheduling_math() {
+        let stride = stride_with_key_blocks(100, Some(50), &[151, 301]);
+
+        // Archives are cut by key blocks which start archives of their own size
+        let indices = prefetch_indices(&stride, 1, 500);
+        assert_eq!(indices, [1, 101, 151, 201, 251, 301, 351]);
+
+        // Only the archive before the key block is required
+        assert!(stride.is_required(150, 101));
+        assert!(!stride.is_required(151, 101));
+
+        // Archives which start with the key block are shorter
+        assert!(stride.is_required(200, 151));
+        assert!(!stride.is_required(201, 151));
+
+        // Key block stride is used only for known key blocks
+        let stride = stride_with_key_blocks(100, Some(50), &[]);
+        assert_eq!(prefetch_indices(&stride, 1, 400), [1, 101, 201]);
+    }
+}
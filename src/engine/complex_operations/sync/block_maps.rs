@@ -3,7 +3,8 @@ use std::hash::BuildHasherDefault;
 use std::sync::Arc;
 
 use anyhow::Result;
-use rustc_hash::FxHashMap;
+use bytes::Bytes;
+use rustc_hash::{FxHashMap, FxHashSet};
 
 use crate::utils::*;
 
@@ -13,9 +14,20 @@ pub struct BlockMaps {
 }
 
 impl BlockMaps {
-    pub const MAX_MC_BLOCK_COUNT: usize = 100;
-
+    /// Parses archive and copies raw data of each entry
     pub fn new(data: &[u8]) -> Result<Arc<Self>> {
+        Self::parse(data, Bytes::copy_from_slice)
+    }
+
+    /// Parses archive and shares its buffer with all entries
+    pub fn from_bytes(data: Bytes) -> Result<Arc<Self>> {
+        Self::parse(&data, |entry| data.slice_ref(entry))
+    }
+
+    fn parse<F>(data: &[u8], mut raw_data: F) -> Result<Arc<Self>>
+    where
+        F: FnMut(&[u8]) -> Bytes,
+    {
         let mut reader = ArchivePackageViewReader::new(data)?;
 
         let mut maps = BlockMaps {
@@ -31,9 +43,9 @@ impl BlockMaps {
                     maps.blocks
                         .entry(id.clone())
                         .or_insert_with(BlockMapsEntry::default)
-                        .block = Some(BlockStuffAug::new(block, entry.data.to_vec()));
+                        .block = Some(BlockStuffAug::new(block, raw_data(entry.data)));
                     if id.is_masterchain() {
-                        maps.mc_block_ids.insert(id.seq_no, id);
+                        maps.insert_mc_block_id(id)?;
                     }
                 }
                 PackageEntryId::Proof(id) if id.is_masterchain() => {
@@ -42,8 +54,8 @@ impl BlockMaps {
                     maps.blocks
                         .entry(id.clone())
                         .or_insert_with(BlockMapsEntry::default)
-                        .proof = Some(BlockProofStuffAug::new(proof, entry.data.to_vec()));
-                    maps.mc_block_ids.insert(id.seq_no, id);
+                        .proof = Some(BlockProofStuffAug::new(proof, raw_data(entry.data)));
+                    maps.insert_mc_block_id(id)?;
                 }
                 PackageEntryId::ProofLink(id) if !id.is_masterchain() => {
                     let proof = BlockProofStuff::deserialize(id.clone(), entry.data, true)?;
@@ -51,7 +63,7 @@ impl BlockMaps {
                     maps.blocks
                         .entry(id.clone())
                         .or_insert_with(BlockMapsEntry::default)
-                        .proof = Some(BlockProofStuffAug::new(proof, entry.data.to_vec()));
+                        .proof = Some(BlockProofStuffAug::new(proof, raw_data(entry.data)));
                 }
                 _ => continue,
             }
@@ -60,6 +72,26 @@ impl BlockMaps {
         Ok(Arc::new(maps))
     }
 
+    /// Block and proof entries of the same masterchain block must have the same id
+    fn insert_mc_block_id(&mut self, id: ton_block::BlockIdExt) -> Result<(), BlockMapsError> {
+        use std::collections::btree_map::Entry;
+
+        match self.mc_block_ids.entry(id.seq_no) {
+            Entry::Vacant(entry) => {
+                entry.insert(id);
+            }
+            Entry::Occupied(entry) if entry.get() != &id => {
+                return Err(BlockMapsError::MasterchainBlockIdMismatch {
+                    seqno: id.seq_no,
+                    first: entry.get().clone(),
+                    second: id,
+                })
+            }
+            Entry::Occupied(_) => {}
+        }
+        Ok(())
+    }
+
     pub fn lowest_mc_id(&self) -> Option<&ton_block::BlockIdExt> {
         self.mc_block_ids.values().next()
     }
@@ -68,6 +100,28 @@ impl BlockMaps {
         self.mc_block_ids.values().rev().next()
     }
 
+    /// Whether the lowest masterchain block in this archive is a key block
+    pub fn starts_with_key_block(&self) -> bool {
+        let entry = match self.lowest_mc_id() {
+            Some(id) => self.blocks.get(id),
+            None => None,
+        };
+        match entry.and_then(|entry| entry.block.as_ref()) {
+            Some(block) => matches!(block.block().read_info(), Ok(info) if info.key_block()),
+            None => false,
+        }
+    }
+
+    /// Actual range of masterchain blocks in this archive.
+    ///
+    /// NOTE: could differ from the requested one, because some peers serve archives
+    /// which are not aligned to the expected archive stride
+    pub fn mc_seqno_range(&self) -> Option<std::ops::RangeInclusive<u32>> {
+        let lowest = self.mc_block_ids.keys().next()?;
+        let highest = self.mc_block_ids.keys().next_back()?;
+        Some(*lowest..=*highest)
+    }
+
     pub fn check(&self, index: u32, edge: &Option<BlockMapsEdge>) -> Result<(), BlockMapsError> {
         let mc_block_count = self.mc_block_ids.len();
 
@@ -142,6 +196,66 @@ impl BlockMaps {
         // Archive is not empty and all blocks are contiguous
         Ok(())
     }
+
+    /// Ensures that shard blocks in this archive are the ones committed by the masterchain.
+    ///
+    /// `committed` are the shard block ids from the masterchain blocks (e.g. top shard
+    /// blocks of each masterchain block). Previous blocks of the committed blocks
+    /// from this archive are also considered committed.
+    ///
+    /// Returns the committed shard blocks of this archive. Other shard blocks
+    /// are not verified by the masterchain and must not be used.
+    pub fn check_committed_shard_blocks<I>(
+        &self,
+        committed: I,
+    ) -> Result<FxHashSet<ton_block::BlockIdExt>>
+    where
+        I: IntoIterator<Item = ton_block::BlockIdExt>,
+    {
+        use std::collections::hash_map::Entry;
+
+        let mut known = FxHashMap::<(ton_block::ShardIdent, u32), ton_block::BlockIdExt>::default();
+
+        let mut queue = committed.into_iter().collect::<Vec<_>>();
+        while let Some(id) = queue.pop() {
+            match known.entry((id.shard_id, id.seq_no)) {
+                Entry::Vacant(entry) => {
+                    entry.insert(id.clone());
+                }
+                Entry::Occupied(_) => continue,
+            }
+
+            // NOTE: block data is checked against its id during parsing,
+            // so references of the committed block can be trusted
+            if let Some(block) = self.blocks.get(&id).and_then(|entry| entry.block.as_ref()) {
+                let (prev1, prev2) = block.data.construct_prev_id()?;
+                queue.push(prev1);
+                queue.extend(prev2);
+            }
+        }
+
+        let mut verified = FxHashSet::default();
+        for id in self.blocks.keys() {
+            if id.is_masterchain() {
+                continue;
+            }
+            match known.get(&(id.shard_id, id.seq_no)) {
+                Some(committed) if committed != id => {
+                    return Err(BlockMapsError::UncommittedShardBlock {
+                        block_id: id.clone(),
+                        committed: committed.clone(),
+                    }
+                    .into())
+                }
+                Some(_) => {
+                    verified.insert(id.clone());
+                }
+                None => {}
+            }
+        }
+
+        Ok(verified)
+    }
 }
 
 #[derive(Default)]
@@ -636,11 +750,24 @@ pub enum BlockMapsError {
     EmptyArchive,
     #[error("Inconsistent masterchain blocks")]
     InconsistentMasterchainBlocks,
+    #[error("Different masterchain block ids for seqno {seqno}: {first} and {second}")]
+    MasterchainBlockIdMismatch {
+        seqno: u32,
+        first: ton_block::BlockIdExt,
+        second: ton_block::BlockIdExt,
+    },
     #[error("Inconsistent masterchain block {shard_ident}:{seqno}")]
     InconsistentShardchainBlock {
         shard_ident: ton_block::ShardIdent,
         seqno: u32,
     },
+    #[error(
+        "Shard block {block_id} differs from the one committed by the masterchain: {committed}"
+    )]
+    UncommittedShardBlock {
+        block_id: ton_block::BlockIdExt,
+        committed: ton_block::BlockIdExt,
+    },
     #[error("Block not found in archive")]
     BlockDataNotFound,
     #[error("Block proof not found in archive")]
@@ -669,6 +796,53 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn committed_shard_blocks() {
+        let mc = ton_block::ShardIdent::masterchain();
+        let shard = ton_block::ShardIdent::with_tagged_prefix(0, 0b1000 << 60).unwrap();
+        let other_shard = ton_block::ShardIdent::with_tagged_prefix(0, 0b0100 << 60).unwrap();
+
+        let committed = [
+            make_block_id(shard, 10, 1),
+            make_block_id(other_shard, 5, 1),
+        ];
+
+        // Archive with the committed blocks and the uncommitted ones (e.g. for the next archive)
+        let maps = make_block_maps([
+            make_block_id(mc, 100, 1),
+            make_block_id(mc, 101, 2),
+            make_block_id(shard, 10, 1),
+            make_block_id(shard, 11, 2),
+            make_block_id(other_shard, 5, 1),
+        ]);
+        let verified = maps
+            .check_committed_shard_blocks(committed.clone())
+            .unwrap();
+
+        // Blocks which are not committed by the masterchain are not verified
+        assert_eq!(
+            verified,
+            committed.iter().cloned().collect::<FxHashSet<_>>()
+        );
+
+        // Tampered archive with a different block for the committed seqno
+        let tampered = make_block_maps([
+            make_block_id(mc, 100, 1),
+            make_block_id(shard, 10, 3),
+            make_block_id(other_shard, 5, 1),
+        ]);
+        let err = tampered
+            .check_committed_shard_blocks(committed)
+            .unwrap_err()
+            .downcast::<BlockMapsError>()
+            .unwrap();
+        assert!(matches!(
+            err,
+            BlockMapsError::UncommittedShardBlock { block_id, committed }
+                if block_id == make_block_id(shard, 10, 3) && committed == make_block_id(shard, 10, 1)
+        ));
+    }
+
     #[test]
     fn correct_block_maps_edge() {
         let edge = Some(make_edge(5, [(0b0_100, 5), (0b1_100, 5)]));
@@ -839,6 +1013,94 @@ mod tests {
         ));
     }
 
+    /// NOTE: allocations are checked in the `archive_alloc` integration test
+    #[test]
+    fn archive_parse_shares_buffer() {
+        use ton_block::Serializable;
+
+        const ENTRY_COUNT: usize = 100;
+
+        // Prepare fixture archive
+        let root = ton_block::Block::default().serialize().unwrap();
+        let block_data = ton_types::serialize_toc(&root).unwrap();
+        let block_id = ton_block::BlockIdExt {
+            shard_id: ton_block::ShardIdent::masterchain(),
+            seq_no: 1,
+            root_hash: root.repr_hash(),
+            file_hash: ton_types::UInt256::calc_file_hash(&block_data),
+        };
+
+        let mut archive = ARCHIVE_PREFIX.to_vec();
+        let segment =
+            make_archive_segment(&PackageEntryId::Block(&block_id).filename(), &block_data);
+        for _ in 0..ENTRY_COUNT {
+            archive.extend_from_slice(&segment);
+        }
+        let archive = Bytes::from(archive);
+
+        let copied = BlockMaps::new(&archive).unwrap();
+        let shared = BlockMaps::from_bytes(archive.clone()).unwrap();
+
+        assert_eq!(copied.blocks.len(), 1);
+        assert_eq!(shared.blocks.len(), 1);
+
+        // Shared entry data points into the archive buffer
+        let data = shared.blocks[&block_id]
+            .block
+            .as_ref()
+            .unwrap()
+            .new_archive_data()
+            .unwrap();
+        assert!(archive.as_ptr_range().contains(&data.as_ptr()));
+    }
+
+    #[test]
+    fn conflicting_mc_block_ids() {
+        use ton_block::Serializable;
+
+        let root = ton_block::Block::default().serialize().unwrap();
+        let block_data = ton_types::serialize_toc(&root).unwrap();
+        let block_id = ton_block::BlockIdExt {
+            shard_id: ton_block::ShardIdent::masterchain(),
+            seq_no: 1,
+            root_hash: root.repr_hash(),
+            file_hash: ton_types::UInt256::calc_file_hash(&block_data),
+        };
+
+        // Proof for another block with the same seqno
+        let other_block_id = ton_block::BlockIdExt {
+            root_hash: ton_types::UInt256::from_slice(&[1; 32]),
+            ..block_id.clone()
+        };
+        let proof_data = ton_block::BlockProof {
+            proof_for: other_block_id.clone(),
+            root: Default::default(),
+            signatures: None,
+        }
+        .write_to_bytes()
+        .unwrap();
+
+        let mut archive = ARCHIVE_PREFIX.to_vec();
+        archive.extend_from_slice(&make_archive_segment(
+            &PackageEntryId::Block(&block_id).filename(),
+            &block_data,
+        ));
+        archive.extend_from_slice(&make_archive_segment(
+            &PackageEntryId::Proof(&other_block_id).filename(),
+            &proof_data,
+        ));
+
+        let error = match BlockMaps::new(&archive) {
+            Ok(_) => panic!("archive with conflicting ids must be rejected"),
+            Err(e) => e,
+        };
+        assert!(matches!(
+            error.downcast_ref::<BlockMapsError>(),
+            Some(BlockMapsError::MasterchainBlockIdMismatch { seqno: 1, first, second })
+                if first == &block_id && second == &other_block_id
+        ));
+    }
+
     fn make_masterchain(
         seqnos: impl IntoIterator<Item = u32>,
     ) -> (ton_block::ShardIdent, BTreeSet<u32>) {
@@ -871,6 +1133,29 @@ mod tests {
         }
     }
 
+    fn make_block_id(
+        shard_ident: ton_block::ShardIdent,
+        seq_no: u32,
+        hash: u8,
+    ) -> ton_block::BlockIdExt {
+        ton_block::BlockIdExt {
+            shard_id: shard_ident,
+            seq_no,
+            root_hash: ton_types::UInt256::from([hash; 32]),
+            file_hash: ton_types::UInt256::from([hash; 32]),
+        }
+    }
+
+    fn make_block_maps(ids: impl IntoIterator<Item = ton_block::BlockIdExt>) -> BlockMaps {
+        BlockMaps {
+            mc_block_ids: Default::default(),
+            blocks: ids
+                .into_iter()
+                .map(|id| (id, BlockMapsEntry::default()))
+                .collect(),
+        }
+    }
+
     fn check_block_maps(
         shards: impl IntoIterator<Item = (ton_block::ShardIdent, BTreeSet<u32>)>,
         edge: &Option<BlockMapsEdge>,
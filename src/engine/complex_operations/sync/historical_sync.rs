@@ -1,19 +1,30 @@
+use std::future::Future;
 use std::ops::ControlFlow;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 use anyhow::Result;
+use futures_util::stream::FuturesUnordered;
+use futures_util::{FutureExt, StreamExt};
 use ton_types::FxDashSet;
 
 use super::archives_stream::*;
 use super::block_maps::*;
-use crate::engine::Engine;
+use super::import_stats::*;
+use super::progress_log::*;
+use super::SyncError;
+use crate::db::NodeStateStorage;
+use crate::engine::notification_sequencer::NotificationSequencer;
+use crate::engine::{
+    DiskSpaceLevel, Engine, PendingBlockNotification, NOTIFICATION_SEQUENCER_WINDOW,
+};
 use crate::utils::*;
 
 pub async fn historical_sync(engine: &Arc<Engine>, from_seqno: u32) -> Result<()> {
-    let (from, to) = engine.historical_sync_range(from_seqno)?;
-    if from + 1 >= to {
-        return Ok(());
-    }
+    let (from, to) = match engine.historical_sync_range(from_seqno)? {
+        Some(range) => range,
+        None => return Ok(()),
+    };
 
     tracing::info!(
         target: "sync",
@@ -22,47 +33,96 @@ pub async fn historical_sync(engine: &Arc<Engine>, from_seqno: u32) -> Result<()
         "started historical sync"
     );
 
-    let mut ctx = HistoricalSyncContext::new(engine, from, to);
+    engine.check_balance_sink();
+
+    let mut ctx = HistoricalSyncContext::new(engine, from, to)?;
 
     let mut archives = ArchivesStream::new(engine, from..=to, None);
+    let mut progress_log = SyncProgressLogger::new(engine.sync_options.progress_log);
+    let mut pg = ProgressBar::builder("historical sync")
+        .total(to - from)
+        .exact_unit("mc blocks")
+        .with_eta()
+        .build();
     loop {
         let archive = archives.recv().await;
-        match ctx.handle(archive.clone()).await {
-            Ok(ControlFlow::Break(())) => break,
+        engine.wait_for_disk_space(DiskSpaceLevel::Low).await;
+
+        let import_start = std::time::Instant::now();
+        let mut stats = ImportStats::default();
+        let result = ctx.handle(archive.clone(), &mut stats).await;
+        {
+            let mut sync_stats = engine.sync_stats.lock();
+            match &result {
+                Ok(_) => sync_stats.record_imported(&stats),
+                Err(_) => sync_stats.record_failed(),
+            }
+        }
+
+        if let (Ok(_), Some(highest_id)) = (&result, archive.highest_mc_id()) {
+            progress_log.record(
+                highest_id,
+                archive.mc_block_ids.len(),
+                import_start.elapsed(),
+            );
+        }
+
+        match result {
+            Ok(ControlFlow::Break(())) => {
+                let node_state = engine.db.node_state();
+                if let Some(last_id) = node_state.load_historical_sync_start()? {
+                    node_state.store_historical_sync_completed(&last_id)?;
+                }
+                pg.complete();
+                break;
+            }
             Ok(_) => {
+                if let Some(highest_id) = archive.highest_mc_id() {
+                    pg.set_progress(highest_id.seq_no.clamp(from, to) - from);
+                }
                 archive.accept(ctx.last_archive_edge.clone());
             }
             Err(e) => {
                 tracing::error!(target: "sync", "failed to save archive: {e:?}");
+                archive.retry();
             }
         }
     }
+    progress_log.finish();
 
-    tracing::info!(target: "sync", "historical sync complete");
+    tracing::info!(target: "sync", stats = ?engine.sync_stats(), "historical sync complete");
     Ok(())
 }
 
 struct HistoricalSyncContext<'a> {
     engine: &'a Arc<Engine>,
     last_archive_edge: Option<BlockMapsEdge>,
+    /// Orders notifications of the concurrently saved shard chains
+    notifications: Arc<HistoricalNotifications<PendingBlockNotification>>,
     from: u32,
     to: u32,
 }
 
 impl<'a> HistoricalSyncContext<'a> {
-    fn new(engine: &'a Arc<Engine>, from: u32, to: u32) -> Self {
-        Self {
+    fn new(engine: &'a Arc<Engine>, from: u32, to: u32) -> Result<Self> {
+        let notifications = HistoricalNotifications::restore(engine.db.node_state(), from)?;
+        Ok(Self {
             engine,
             last_archive_edge: None,
+            notifications: Arc::new(notifications),
             from,
             to,
-        }
+        })
     }
 
-    async fn handle(&mut self, maps: Arc<BlockMaps>) -> Result<ControlFlow<()>> {
+    async fn handle(
+        &mut self,
+        maps: Arc<BlockMaps>,
+        stats: &mut ImportStats,
+    ) -> Result<ControlFlow<()>> {
         let (lowest_id, highest_id) = match (maps.lowest_mc_id(), maps.highest_mc_id()) {
             (Some(lowest), Some(highest)) => (lowest, highest),
-            _ => return Err(HistoricalSyncError::EmptyArchivePackage.into()),
+            _ => return Err(SyncError::EmptyArchivePackage.into()),
         };
         tracing::debug!(
             target: "sync",
@@ -73,11 +133,12 @@ impl<'a> HistoricalSyncContext<'a> {
 
         let mut block_edge = self.last_archive_edge.clone();
 
-        self.process_blocks(&maps, &mut block_edge).await?;
+        self.process_blocks(&maps, &mut block_edge, stats).await?;
         tracing::info!(
             target: "sync",
             lowest_id = %lowest_id.display(),
             highest_id = %highest_id.display(),
+            ?stats,
             "saved archive"
         );
 
@@ -95,10 +156,12 @@ impl<'a> HistoricalSyncContext<'a> {
         &mut self,
         maps: &Arc<BlockMaps>,
         edge: &mut Option<BlockMapsEdge>,
+        stats: &mut ImportStats,
     ) -> Result<()> {
         let node_state = self.engine.db.node_state();
 
         let splits = Arc::new(FxDashSet::default());
+        let counters = Arc::new(ImportCounters::default());
 
         for mc_block_id in maps.mc_block_ids.values() {
             let mc_seq_no = mc_block_id.seq_no;
@@ -107,8 +170,9 @@ impl<'a> HistoricalSyncContext<'a> {
                 break;
             }
 
-            // Save block
+            // Prepare block
             let (info, block, proof) = self.engine.prepare_archive_block(maps, mc_block_id).await?;
+            counters.proofs_verified.fetch_add(1, Ordering::Release);
 
             let shard_blocks = block.shard_blocks()?;
             let new_edge = BlockMapsEdge {
@@ -121,47 +185,42 @@ impl<'a> HistoricalSyncContext<'a> {
 
             // Skip already saved blocks
             if mc_seq_no <= self.from {
+                if mc_seq_no == self.from {
+                    self.notifications
+                        .seed(&TopBlocks {
+                            mc_block: mc_block_id.clone(),
+                            shard_heights: new_edge.top_shard_blocks.clone(),
+                        })
+                        .await;
+                }
+                counters.skipped.fetch_add(1, Ordering::Release);
                 *edge = Some(new_edge);
                 continue;
             }
 
-            self.engine
-                .save_archive_block(info, block, proof, mc_seq_no)
-                .await?;
+            // Skip blocks which were referenced in previous mc block (no new blocks were
+            // produced in this shard)
+            let top_blocks = shard_blocks
+                .into_values()
+                .filter(|id| should_process(maps, edge, id))
+                .collect::<Vec<_>>();
 
             splits.clear();
-            let mut tasks = Vec::with_capacity(shard_blocks.len());
-            for (_, id) in shard_blocks {
-                fn should_process(
-                    maps: &BlockMaps,
-                    edge: &Option<BlockMapsEdge>,
-                    id: &ton_block::BlockIdExt,
-                ) -> bool {
-                    match edge {
-                        // Process blocks only if they are after the current edge
-                        Some(edge) => edge.is_before(id),
-                        // Always process all blocks in archive when block edge is not specified
-                        None => maps.blocks.contains_key(id),
-                    }
-                }
-
-                // Skip blocks which were referenced in previous mc block (no new blocks were
-                // produced in this shard)
-                if !should_process(maps, edge, &id) {
-                    continue;
-                }
-
+            let save_chain = |id: ton_block::BlockIdExt| {
                 let engine = self.engine.clone();
                 let splits = splits.clone();
                 let maps = maps.clone();
                 let edge = edge.clone();
-                tasks.push(tokio::spawn(async move {
+                let notifications = self.notifications.clone();
+                let counters = counters.clone();
+                tokio::spawn(async move {
                     let mut blocks_to_add = Vec::new();
 
                     // For each block starting from the latest one (which was referenced by the mc block)
                     let mut stack = Vec::from([id]);
                     while let Some(id) = stack.pop() {
                         let (info, block, proof) = engine.prepare_archive_block(&maps, &id).await?;
+                        counters.proofs_verified.fetch_add(1, Ordering::Release);
                         let (prev1, prev2) = block.data.construct_prev_id()?;
 
                         if info.after_split && !splits.insert(prev1.clone()) {
@@ -195,49 +254,188 @@ impl<'a> HistoricalSyncContext<'a> {
                     // Apply blocks
                     for (info, block, block_proof) in blocks_to_add {
                         engine
-                            .save_archive_block(info, block, block_proof, mc_seq_no)
+                            .save_archive_block(
+                                info,
+                                block,
+                                block_proof,
+                                mc_seq_no,
+                                &notifications,
+                                &counters,
+                            )
                             .await?;
                     }
 
                     Ok::<_, anyhow::Error>(())
-                }));
-            }
+                })
+                .map(|result| result?)
+            };
 
-            futures_util::future::try_join_all(tasks)
-                .await?
-                .into_iter()
-                .find(|item| item.is_err())
-                .unwrap_or(Ok(()))?;
+            let shard_blocks_start = std::time::Instant::now();
+            let mut shard_blocks_duration = Default::default();
+            save_mc_block_with_shards(top_blocks, save_chain, || {
+                shard_blocks_duration = shard_blocks_start.elapsed();
+                self.engine.save_archive_block(
+                    info,
+                    block,
+                    proof,
+                    mc_seq_no,
+                    &self.notifications,
+                    &counters,
+                )
+            })
+            .await?;
+            stats.shard_blocks_duration += shard_blocks_duration;
+            stats.mc_blocks_duration += shard_blocks_start.elapsed() - shard_blocks_duration;
 
             *edge = Some(new_edge);
 
-            node_state.store_historical_sync_start(mc_block_id)?;
+            self.notifications
+                .commit_mc_block(node_state, mc_block_id)
+                .await?;
         }
 
+        counters.add_to(stats);
         Ok(())
     }
 }
 
-impl Engine {
-    async fn prepare_archive_block<'a>(
+fn should_process(
+    maps: &BlockMaps,
+    edge: &Option<BlockMapsEdge>,
+    id: &ton_block::BlockIdExt,
+) -> bool {
+    match edge {
+        // Process blocks only if they are after the current edge
+        Some(edge) => edge.is_before(id),
+        // Always process all blocks in archive when block edge is not specified
+        None => maps.blocks.contains_key(id),
+    }
+}
+
+/// Saves shard chains of the masterchain block and then the masterchain block itself.
+///
+/// NOTE: the masterchain block is saved strictly after all its shard blocks
+async fn save_mc_block_with_shards<S, F, M, FM>(
+    top_blocks: Vec<ton_block::BlockIdExt>,
+    save_chain: S,
+    save_mc_block: M,
+) -> Result<()>
+where
+    S: Fn(ton_block::BlockIdExt) -> F,
+    F: Future<Output = Result<()>>,
+    M: FnOnce() -> FM,
+    FM: Future<Output = Result<()>>,
+{
+    let mut tasks = top_blocks
+        .into_iter()
+        .map(save_chain)
+        .collect::<FuturesUnordered<_>>();
+
+    // NOTE: wait for all tasks even if some of them failed,
+    // so that no blocks are notified after the error is returned
+    let mut result = Ok(());
+    while let Some(item) = tasks.next().await {
+        match item {
+            Ok(()) => {}
+            Err(e) if result.is_ok() => result = Err(e),
+            Err(e) => tracing::error!(target: "sync", "failed to save shard blocks: {e:?}"),
+        }
+    }
+    result?;
+
+    save_mc_block().await
+}
+
+/// Notifications of the historical sync.
+///
+/// Blocks are delivered in the same order as the live ones (see [`NotificationSequencer`])
+/// and the position is stored after each delivered block, so that after the restart
+/// the sync resumes right after the last notified block of each shard.
+struct HistoricalNotifications<T> {
+    sequencer: tokio::sync::Mutex<NotificationSequencer<T>>,
+    /// Whether the position was restored from the DB
+    restored: bool,
+}
+
+impl<T: Clone> HistoricalNotifications<T> {
+    /// Restores the position of the sync which continues after the specified masterchain block
+    fn restore(node_state: &NodeStateStorage, from: u32) -> Result<Self> {
+        let mut sequencer = NotificationSequencer::new(NOTIFICATION_SEQUENCER_WINDOW);
+
+        // NOTE: masterchain block is committed after it is delivered, so the stored
+        // position could be one block ahead. Other positions belong to another range
+        let position = node_state
+            .load_historical_sync_notified()?
+            .filter(|position| matches!(position.mc_block.seq_no.checked_sub(from), Some(0 | 1)));
+        if let Some(position) = &position {
+            sequencer.reset(position);
+        }
+
+        Ok(Self {
+            sequencer: tokio::sync::Mutex::new(sequencer),
+            restored: position.is_some(),
+        })
+    }
+
+    /// Starts from the top blocks of the last saved masterchain block
+    /// if the position was not restored
+    async fn seed(&self, top_blocks: &TopBlocks) {
+        if !self.restored {
+            self.sequencer.lock().await.reset(top_blocks);
+        }
+    }
+
+    async fn is_delivered(&self, block_id: &ton_block::BlockIdExt) -> bool {
+        self.sequencer.lock().await.is_delivered(block_id)
+    }
+
+    /// Adds the notification and delivers all notifications which are ready
+    async fn notify<D, F>(
         &self,
-        maps: &'a BlockMaps,
+        node_state: &NodeStateStorage,
         block_id: &ton_block::BlockIdExt,
-    ) -> Result<(BriefBlockInfo, &'a BlockStuffAug, &'a BlockProofStuffAug)> {
-        let entry = maps.blocks.get(block_id);
-        let (block, proof) = entry
-            .ok_or(HistoricalSyncError::IncompleteBlockData)?
-            .get_data()?;
-        let info = self.check_block_proof(proof).await?;
-        Ok((info, block, proof))
+        top_blocks: Option<TopBlocks>,
+        item: T,
+        deliver: D,
+    ) -> Result<()>
+    where
+        D: FnMut(T) -> F,
+        F: Future<Output = Result<()>>,
+    {
+        let mut sequencer = self.sequencer.lock().await;
+        match top_blocks {
+            Some(top_blocks) => sequencer.push_mc_block(top_blocks, item)?,
+            None => sequencer.push_shard_block(block_id.clone(), item)?,
+        }
+        sequencer
+            .deliver_ready(deliver, |position| {
+                node_state.store_historical_sync_notified(position)
+            })
+            .await
+    }
+
+    /// Moves the low pointer of the sync to the delivered masterchain block
+    async fn commit_mc_block(
+        &self,
+        node_state: &NodeStateStorage,
+        mc_block_id: &ton_block::BlockIdExt,
+    ) -> Result<()> {
+        if !self.is_delivered(mc_block_id).await {
+            return Err(SyncError::UndeliveredMasterchainBlock.into());
+        }
+        node_state.store_historical_sync_start(mc_block_id)
     }
+}
 
+impl Engine {
     async fn save_archive_block(
         &self,
         info: BriefBlockInfo,
         block: &BlockStuffAug,
         proof: &BlockProofStuffAug,
         mc_seq_no: u32,
+        notifications: &HistoricalNotifications<PendingBlockNotification>,
+        counters: &ImportCounters,
     ) -> Result<()> {
         let block_handle_storage = self.db.block_handle_storage();
         let block_storage = self.db.block_storage();
@@ -245,24 +443,54 @@ impl Engine {
         let (handle, _) = block_handle_storage
             .create_or_load_handle(block.id(), info.with_mc_seq_no(mc_seq_no))?;
 
-        // Archive block
-        if self.archive_options.is_some() {
-            block_storage.move_into_archive_with_data(
-                &handle,
-                proof.is_link(),
-                block.new_archive_data()?,
-                proof.new_archive_data()?,
-            )?;
-        }
+        // Blocks which were notified before the restart are already saved
+        if notifications.is_delivered(block.id()).await {
+            counters.skipped.fetch_add(1, Ordering::Release);
+        } else {
+            // Archive block
+            if self.archive_options.is_some() && !handle.meta().is_archived() {
+                let block_data = block.new_archive_data()?;
+                let proof_data = proof.new_archive_data()?;
+                block_storage.move_into_archive_with_data(
+                    &handle,
+                    proof.is_link(),
+                    block_data,
+                    proof_data,
+                )?;
+                counters.bytes_written.fetch_add(
+                    (block_data.len() + proof_data.len()) as u64,
+                    Ordering::Release,
+                );
+            }
 
-        // Notify subscribers
-        self.notify_subscribers_with_archive_block(
-            &handle,
-            &block.data,
-            block.new_archive_data()?,
-            proof.new_archive_data()?,
-        )
-        .await?;
+            self.extract_balance_history(&block.data, mc_seq_no).await?;
+
+            // Notify subscribers
+            let top_blocks = match handle.id().shard_id.is_masterchain() {
+                true => Some(TopBlocks::from_mc_block(&block.data)?),
+                false => None,
+            };
+            let notification = PendingBlockNotification {
+                handle: handle.clone(),
+                block: block.data.clone(),
+                shard_state: None,
+                archive_data: Some((block.new_archive_bytes()?, proof.new_archive_bytes()?)),
+            };
+            notifications
+                .notify(
+                    self.db.node_state(),
+                    handle.id(),
+                    top_blocks,
+                    notification,
+                    |item| self.notify_subscribers_with_pending(item),
+                )
+                .await?;
+
+            if handle.is_key_block() {
+                self.notify_config_changes(&handle, &block.data, None).await;
+            }
+            counters.applied.fetch_add(1, Ordering::Release);
+        }
 
         if handle.id().shard_id.is_masterchain() {
             self.on_masterchain_block(&handle).await?;
@@ -271,24 +499,547 @@ impl Engine {
         Ok(())
     }
 
-    fn historical_sync_range(&self, from_seqno: u32) -> Result<(u32, u32)> {
+    fn historical_sync_range(&self, from_seqno: u32) -> Result<Option<(u32, u32)>> {
         let state = self.db.node_state();
 
-        let low = match state.load_historical_sync_start()? {
-            Some(low) => low.seq_no,
-            None => from_seqno.saturating_sub(1),
+        let progress = HistoricalSyncProgress {
+            low: state.load_historical_sync_start()?.map(|id| id.seq_no),
+            completed: state.load_historical_sync_completed()?.map(|id| id.seq_no),
+            high: state.load_historical_sync_end()?.seq_no,
+        };
+
+        Ok(progress.compute_range(from_seqno))
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct HistoricalSyncProgress {
+    /// The last saved masterchain block
+    low: Option<u32>,
+    /// The masterchain block up to which the sync was completed
+    completed: Option<u32>,
+    /// Target masterchain block
+    high: u32,
+}
+
+impl HistoricalSyncProgress {
+    /// Returns the range of masterchain blocks `(from, to]` to sync
+    /// or `None` if there is nothing to sync
+    fn compute_range(&self, from_seqno: u32) -> Option<(u32, u32)> {
+        let high = self.high;
+
+        // Saved low block could be greater than the target when it was
+        // changed (e.g. after the cold boot from the different init block)
+        let low = match self.low {
+            Some(low) if low < high => Some(low),
+            Some(low) => {
+                tracing::warn!(target: "sync", low, high, "ignoring stale historical sync progress");
+                None
+            }
+            None => None,
+        };
+
+        let from = match (low, self.completed) {
+            (Some(low), Some(completed)) => std::cmp::max(low, completed),
+            (Some(low), None) => low,
+            // Continue from the previously completed sync when target was raised
+            (None, Some(completed)) => completed,
+            (None, None) => from_seqno.saturating_sub(1),
         };
 
-        let high = state.load_historical_sync_end()?.seq_no;
+        if from + 1 >= high {
+            tracing::info!(target: "sync", from, high, "historical sync is already complete");
+            return None;
+        }
 
-        Ok((low, high))
+        Some((from, high))
     }
 }
 
-#[derive(Debug, thiserror::Error)]
-enum HistoricalSyncError {
-    #[error("Empty archive package")]
-    EmptyArchivePackage,
-    #[error("Incomplete block data")]
-    IncompleteBlockData,
+#[cfg(test)]
+mod tests {
+    use rustc_hash::FxHashMap;
+
+    use super::*;
+    use crate::test_helpers::*;
+
+    #[test]
+    fn fresh_historical_sync_range() {
+        let progress = HistoricalSyncProgress {
+            low: None,
+            completed: None,
+            high: 1000,
+        };
+        assert_eq!(progress.compute_range(100), Some((99, 1000)));
+        assert_eq!(progress.compute_range(2000), None);
+    }
+
+    #[test]
+    fn resume_after_completed_sync_with_raised_target() {
+        // Previous sync was completed up to 1000, new target is 2000
+        let progress = HistoricalSyncProgress {
+            low: Some(1000),
+            completed: Some(1000),
+            high: 2000,
+        };
+        assert_eq!(progress.compute_range(100), Some((1000, 2000)));
+
+        // Interrupted while syncing to the new target
+        let progress = HistoricalSyncProgress {
+            low: Some(1500),
+            completed: Some(1000),
+            high: 2000,
+        };
+        assert_eq!(progress.compute_range(100), Some((1500, 2000)));
+
+        // Nothing to do after completion
+        let progress = HistoricalSyncProgress {
+            low: Some(2000),
+            completed: Some(2000),
+            high: 2000,
+        };
+        assert_eq!(progress.compute_range(100), None);
+    }
+
+    #[test]
+    fn resume_with_stale_low_block() {
+        // Low block is greater than the target
+        let progress = HistoricalSyncProgress {
+            low: Some(3000),
+            completed: None,
+            high: 2000,
+        };
+        assert_eq!(progress.compute_range(100), Some((99, 2000)));
+
+        let progress = HistoricalSyncProgress {
+            low: Some(3000),
+            completed: Some(1000),
+            high: 2000,
+        };
+        assert_eq!(progress.compute_range(100), Some((1000, 2000)));
+
+        let progress = HistoricalSyncProgress {
+            low: Some(3000),
+            completed: Some(3000),
+            high: 2000,
+        };
+        assert_eq!(progress.compute_range(100), None);
+    }
+
+    /// Masterchain block with the shard chains it commits
+    struct McBlock {
+        top_blocks: TopBlocks,
+        /// Blocks of each shard chain in the order of the archive walk
+        chains: Vec<Vec<ton_block::BlockIdExt>>,
+    }
+
+    /// Chain with several blocks of each shard per masterchain block
+    /// and the split of the full shard at the specified masterchain block.
+    ///
+    /// NOTE: the first element is the initial masterchain block without chains
+    fn make_chain(mc_blocks: u32, split_at: u32) -> Vec<McBlock> {
+        let full = ton_block::ShardIdent::with_tagged_prefix(0, ton_block::SHARD_FULL).unwrap();
+        let (left, right) = full.split().unwrap();
+
+        let mut heights = FxHashMap::default();
+        heights.insert(full, 0u32);
+        let mut result = vec![McBlock {
+            top_blocks: TopBlocks {
+                mc_block: block_id(ton_block::ShardIdent::masterchain(), 0),
+                shard_heights: heights.clone(),
+            },
+            chains: Vec::new(),
+        }];
+
+        for mc_seq_no in 1..=mc_blocks {
+            let mut chains = Vec::new();
+            if mc_seq_no == split_at {
+                // Both children chains are walked back to the same parent blocks
+                let parent = heights.remove(&full).unwrap();
+                let ancestors = (parent + 1..=parent + 2)
+                    .map(|seq_no| block_id(full, seq_no))
+                    .collect::<Vec<_>>();
+                for child in [left, right] {
+                    let mut chain = ancestors.clone();
+                    chain.extend((parent + 3..=parent + 4).map(|seq_no| block_id(child, seq_no)));
+                    chains.push(chain);
+                    heights.insert(child, parent + 4);
+                }
+            } else {
+                for (shard, height) in heights.iter_mut() {
+                    let count = 2 + mc_seq_no % 2;
+                    chains.push(
+                        (*height + 1..=*height + count)
+                            .map(|seq_no| block_id(*shard, seq_no))
+                            .collect(),
+                    );
+                    *height += count;
+                }
+            }
+
+            result.push(McBlock {
+                top_blocks: TopBlocks {
+                    mc_block: block_id(ton_block::ShardIdent::masterchain(), mc_seq_no),
+                    shard_heights: heights.clone(),
+                },
+                chains,
+            });
+        }
+        result
+    }
+
+    struct CrashSimulation {
+        notified: Vec<ton_block::BlockIdExt>,
+        /// Number of notifications before the crash
+        remaining: usize,
+    }
+
+    fn notify(sim: &std::cell::RefCell<CrashSimulation>, id: ton_block::BlockIdExt) -> Result<()> {
+        let mut sim = sim.borrow_mut();
+        if sim.remaining == 0 {
+            anyhow::bail!("simulated crash");
+        }
+        sim.remaining -= 1;
+        sim.notified.push(id);
+        Ok(())
+    }
+
+    /// Same steps as [`HistoricalSyncContext::process_blocks`] over the synthetic chain
+    async fn run_sync(
+        node_state: &NodeStateStorage,
+        chain: &[McBlock],
+        sim: &std::cell::RefCell<CrashSimulation>,
+    ) -> Result<()> {
+        let from = match node_state.load_historical_sync_start()? {
+            Some(id) => id.seq_no,
+            None => 0,
+        };
+
+        let notifications = HistoricalNotifications::restore(node_state, from)?;
+        notifications.seed(&chain[from as usize].top_blocks).await;
+
+        let save = |id: ton_block::BlockIdExt, top_blocks: Option<TopBlocks>| {
+            let notifications = &notifications;
+            async move {
+                if notifications.is_delivered(&id).await {
+                    return Ok(());
+                }
+                notifications
+                    .notify(node_state, &id.clone(), top_blocks, id, |id| {
+                        futures_util::future::ready(notify(sim, id))
+                    })
+                    .await
+            }
+        };
+
+        for mc_block in &chain[from as usize + 1..] {
+            let top_blocks = mc_block.top_blocks.clone();
+            let mc_block_id = top_blocks.mc_block.clone();
+
+            let tops = mc_block
+                .chains
+                .iter()
+                .filter_map(|chain| chain.last().cloned())
+                .collect();
+            save_mc_block_with_shards(
+                tops,
+                |top| {
+                    let chain = mc_block
+                        .chains
+                        .iter()
+                        .find(|chain| chain.last() == Some(&top))
+                        .cloned()
+                        .unwrap_or_default();
+                    async move {
+                        for id in chain {
+                            save(id, None).await?;
+                        }
+                        Ok(())
+                    }
+                },
+                || save(mc_block_id.clone(), Some(top_blocks)),
+            )
+            .await?;
+
+            notifications
+                .commit_mc_block(node_state, &mc_block_id)
+                .await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn no_notification_gaps_after_restart() {
+        let chain = make_chain(6, 3);
+        let total = chain
+            .iter()
+            .map(|mc_block| {
+                let mut ids = mc_block.chains.iter().flatten().collect::<Vec<_>>();
+                ids.sort_unstable_by_key(|id| (id.shard_id, id.seq_no));
+                ids.dedup();
+                ids.len() + 1
+            })
+            .sum::<usize>()
+            - 1;
+
+        for crash_at in 0..=total {
+            let dir = TempDir::new(&format!("historical_sync_{crash_at}"));
+            let db = test_db(&dir).await;
+            let node_state = db.node_state();
+
+            let sim = std::cell::RefCell::new(CrashSimulation {
+                notified: Vec::new(),
+                remaining: crash_at,
+            });
+
+            let result = run_sync(node_state, &chain, &sim).await;
+            assert_eq!(result.is_err(), crash_at < total);
+
+            // Restart
+            sim.borrow_mut().remaining = usize::MAX;
+            run_sync(node_state, &chain, &sim).await.unwrap();
+            assert_eq!(
+                node_state.load_historical_sync_start().unwrap(),
+                Some(chain.last().unwrap().top_blocks.mc_block.clone())
+            );
+
+            let notified = sim.into_inner().notified;
+            assert_eq!(notified.len(), total, "crash at {crash_at}");
+
+            // Blocks of each shard are notified in order without gaps
+            let mut heights = chain[0].top_blocks.shard_heights.clone();
+            for id in &notified {
+                if id.shard_id.is_masterchain() {
+                    let mc_block = &chain[id.seq_no as usize];
+                    for (shard, top) in &mc_block.top_blocks.shard_heights {
+                        assert_eq!(heights.get(shard), Some(top), "crash at {crash_at}");
+                    }
+                    continue;
+                }
+
+                let prev = match heights.get(&id.shard_id) {
+                    Some(prev) => *prev,
+                    // First block after split continues the parent chain
+                    None => heights[&id.shard_id.merge().unwrap()],
+                };
+                assert_eq!(id.seq_no, prev + 1, "crash at {crash_at}");
+                heights.insert(id.shard_id, id.seq_no);
+            }
+        }
+    }
+
+    /// Records raw data of the delivered blocks
+    #[derive(Default)]
+    struct RecordingSubscriber {
+        delivered: parking_lot::Mutex<Vec<(ton_block::BlockIdExt, Vec<u8>, Vec<u8>)>>,
+    }
+
+    #[async_trait::async_trait]
+    impl crate::engine::Subscriber for RecordingSubscriber {
+        async fn process_block(&self, ctx: crate::engine::ProcessBlockContext<'_>) -> Result<()> {
+            let data = ctx.load_block_data().await?.to_vec();
+            let proof = ctx.load_block_proof_data().await?;
+            self.delivered.lock().push((ctx.id().clone(), data, proof));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn historical_and_normal_import_are_equivalent() {
+        const MC_SEQ_NO: u32 = 10;
+
+        let shard = ton_block::ShardIdent::with_tagged_prefix(0, ton_block::SHARD_FULL).unwrap();
+        let blocks = (1..=3)
+            .map(|seq_no| make_block(shard, seq_no, Default::default()))
+            .collect::<Vec<_>>();
+        let maps = BlockMaps::new(&make_archive(&blocks)).unwrap();
+
+        let normal_dir = TempDir::new("import_equivalence_normal");
+        let normal = test_engine(&normal_dir, Vec::new()).await;
+
+        let historical_dir = TempDir::new("import_equivalence_historical");
+        let subscriber = Arc::new(RecordingSubscriber::default());
+        let historical = test_engine(
+            &historical_dir,
+            vec![subscriber.clone() as Arc<dyn crate::engine::Subscriber>],
+        )
+        .await;
+        let notifications =
+            HistoricalNotifications::restore(historical.db.node_state(), 0).unwrap();
+        let counters = ImportCounters::default();
+
+        for (id, data) in &blocks {
+            // Normal sync
+            let (info, block, proof) = normal.prepare_archive_block(&maps, id).await.unwrap();
+            let (handle, bytes_written) = normal
+                .save_block(info, block, proof, MC_SEQ_NO)
+                .await
+                .unwrap();
+            assert!(bytes_written > 0);
+
+            // Historical sync
+            let (historical_info, block, proof) =
+                historical.prepare_archive_block(&maps, id).await.unwrap();
+            historical
+                .save_archive_block(
+                    historical_info,
+                    block,
+                    proof,
+                    MC_SEQ_NO,
+                    &notifications,
+                    &counters,
+                )
+                .await
+                .unwrap();
+            let historical_handle = historical
+                .db
+                .block_handle_storage()
+                .load_handle(id)
+                .unwrap()
+                .unwrap();
+
+            // Both paths derive the same info from the proof
+            assert_eq!(info.is_key_block, historical_info.is_key_block);
+            assert_eq!(info.gen_utime, id.seq_no);
+            assert_eq!(info.gen_utime, historical_info.gen_utime);
+            assert_eq!(info.after_split, historical_info.after_split);
+            assert_eq!(info.proof_key_block_seqno, None);
+            assert_eq!(historical_info.proof_key_block_seqno, None);
+
+            // Both paths store the same handle meta
+            let (meta, historical_meta) = (handle.meta(), historical_handle.meta());
+            assert_eq!(meta.is_key_block(), historical_meta.is_key_block());
+            assert_eq!(meta.gen_utime(), historical_meta.gen_utime());
+            assert_eq!(meta.masterchain_ref_seqno(), MC_SEQ_NO);
+            assert_eq!(historical_meta.masterchain_ref_seqno(), MC_SEQ_NO);
+
+            // Normal sync stores the archive entries unchanged
+            let block_storage = normal.db.block_storage();
+            assert!(meta.has_data() && meta.has_proof_link());
+            assert_eq!(
+                &block_storage.load_block_data_raw(&handle).await.unwrap(),
+                data
+            );
+            assert_eq!(
+                block_storage
+                    .load_block_proof_raw(&handle, true)
+                    .await
+                    .unwrap(),
+                make_block_proof(id, data)
+            );
+        }
+
+        // Historical sync passes the same archive entries to subscribers
+        let delivered = std::mem::take(&mut *subscriber.delivered.lock());
+        assert_eq!(delivered.len(), blocks.len());
+        for ((id, data), (delivered_id, delivered_data, delivered_proof)) in
+            blocks.iter().zip(delivered)
+        {
+            assert_eq!(&delivered_id, id);
+            assert_eq!(&delivered_data, data);
+            assert_eq!(delivered_proof, make_block_proof(id, data));
+        }
+
+        let mut stats = ImportStats::default();
+        counters.add_to(&mut stats);
+        assert_eq!(stats.blocks_applied, blocks.len() as u64);
+        assert_eq!(stats.blocks_skipped, 0);
+    }
+
+    /// Records changed config params, optionally failing on each of them
+    #[derive(Default)]
+    struct ConfigSubscriber {
+        fail: bool,
+        changed: parking_lot::Mutex<Vec<u32>>,
+    }
+
+    #[async_trait::async_trait]
+    impl crate::engine::Subscriber for ConfigSubscriber {
+        async fn on_config_param_changed(
+            &self,
+            index: u32,
+            _: Option<&ton_block::ConfigParamEnum>,
+            _: Option<&ton_block::ConfigParamEnum>,
+        ) -> Result<()> {
+            self.changed.lock().push(index);
+            if self.fail {
+                anyhow::bail!("config subscriber failed");
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn historical_key_blocks_notify_config_changes() {
+        fn make_config(minter: u8) -> ton_block::ConfigParams {
+            let mut config = ton_block::ConfigParams::default();
+            config
+                .set_config(ton_block::ConfigParamEnum::ConfigParam1(
+                    ton_block::ConfigParam1 {
+                        elector_addr: ton_types::UInt256::from([1; 32]),
+                    },
+                ))
+                .unwrap();
+            config
+                .set_config(ton_block::ConfigParamEnum::ConfigParam2(
+                    ton_block::ConfigParam2 {
+                        minter_addr: ton_types::UInt256::from([minter; 32]),
+                    },
+                ))
+                .unwrap();
+            config
+        }
+
+        let failing = Arc::new(ConfigSubscriber {
+            fail: true,
+            ..Default::default()
+        });
+        let subscriber = Arc::new(ConfigSubscriber::default());
+
+        let dir = TempDir::new("historical_config_changes");
+        let engine = test_engine(
+            &dir,
+            vec![
+                failing.clone() as Arc<dyn crate::engine::Subscriber>,
+                subscriber.clone() as Arc<dyn crate::engine::Subscriber>,
+            ],
+        )
+        .await;
+        let notifications = HistoricalNotifications::restore(engine.db.node_state(), 0).unwrap();
+        let counters = ImportCounters::default();
+
+        let key_blocks = [
+            make_key_block_with_config(1, 0, make_config(1)),
+            make_key_block_with_config(2, 1, make_config(2)),
+        ];
+        for (id, data) in &key_blocks {
+            let block = BlockStuff::deserialize_checked(id.clone(), data).unwrap();
+            let info = BriefBlockInfo::from(&block.block().read_info().unwrap());
+            assert!(info.is_key_block);
+
+            let proof_data = make_block_proof(id, data);
+            let proof = BlockProofStuff::deserialize(id.clone(), &proof_data, false).unwrap();
+
+            // Subscriber errors don't interrupt the sync
+            engine
+                .save_archive_block(
+                    info,
+                    &BlockStuffAug::new(block, data.clone()),
+                    &BlockProofStuffAug::new(proof, proof_data),
+                    id.seq_no,
+                    &notifications,
+                    &counters,
+                )
+                .await
+                .unwrap();
+        }
+
+        // Only the minter address was changed in the second key block
+        assert_eq!(*subscriber.changed.lock(), [2]);
+        assert_eq!(*failing.changed.lock(), [2]);
+
+        let mut stats = ImportStats::default();
+        counters.add_to(&mut stats);
+        assert_eq!(stats.blocks_applied, key_blocks.len() as u64);
+    }
 }
@@ -0,0 +1,67 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Statistics of the archive import
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct ImportStats {
+    /// Masterchain blocks and top shard blocks applied from the archive
+    pub blocks_applied: u64,
+    /// Blocks which were already applied before the import
+    pub blocks_skipped: u64,
+    pub proofs_verified: u64,
+    /// Size of the newly stored block data and proofs
+    pub bytes_written: u64,
+    pub mc_blocks_duration: Duration,
+    pub shard_blocks_duration: Duration,
+    /// Number of shard block application retries
+    pub shard_block_retries: u64,
+}
+
+impl ImportStats {
+    pub fn merge(&mut self, other: &Self) {
+        self.blocks_applied += other.blocks_applied;
+        self.blocks_skipped += other.blocks_skipped;
+        self.proofs_verified += other.proofs_verified;
+        self.bytes_written += other.bytes_written;
+        self.mc_blocks_duration += other.mc_blocks_duration;
+        self.shard_blocks_duration += other.shard_blocks_duration;
+        self.shard_block_retries += other.shard_block_retries;
+    }
+}
+
+/// Statistics of all archives imported since the engine start
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub struct SyncStats {
+    pub archives_imported: u64,
+    pub archives_failed: u64,
+    pub import: ImportStats,
+}
+
+impl SyncStats {
+    pub(crate) fn record_imported(&mut self, stats: &ImportStats) {
+        self.archives_imported += 1;
+        self.import.merge(stats);
+    }
+
+    pub(crate) fn record_failed(&mut self) {
+        self.archives_failed += 1;
+    }
+}
+
+/// Counters updated by the concurrent block tasks
+#[derive(Default)]
+pub(super) struct ImportCounters {
+    pub applied: AtomicU64,
+    pub skipped: AtomicU64,
+    pub proofs_verified: AtomicU64,
+    pub bytes_written: AtomicU64,
+}
+
+impl ImportCounters {
+    pub fn add_to(&self, stats: &mut ImportStats) {
+        stats.blocks_applied += self.applied.load(Ordering::Acquire);
+        stats.blocks_skipped += self.skipped.load(Ordering::Acquire);
+        stats.proofs_verified += self.proofs_verified.load(Ordering::Acquire);
+        stats.bytes_written += self.bytes_written.load(Ordering::Acquire);
+    }
+}
@@ -1,20 +1,33 @@
-use std::borrow::Cow;
+use std::future::Future;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
-use anyhow::{Context, Result};
+use anyhow::Result;
+use rustc_hash::FxHashMap;
 
+use super::replay::notify_late_subscribers;
+use crate::config::ArchiveProofMode;
 use crate::db::*;
-use crate::engine::Engine;
+use crate::engine::downloader::DownloaderTimeouts;
+use crate::engine::{DiskSpaceLevel, Engine};
 use crate::utils::*;
 
 use self::archives_stream::*;
+#[cfg(any(test, feature = "test-util"))]
+pub use self::block_maps::BlockMaps;
 use self::block_maps::*;
 pub use self::historical_sync::*;
+pub use self::import_stats::*;
+use self::new_shards::*;
+use self::progress_log::*;
 
 mod archive_writers_pool;
 mod archives_stream;
 mod block_maps;
 mod historical_sync;
+mod import_stats;
+mod new_shards;
+mod progress_log;
 
 pub async fn sync(engine: &Arc<Engine>) -> Result<()> {
     tracing::info!(target: "sync", "started normal sync");
@@ -27,13 +40,31 @@ pub async fn sync(engine: &Arc<Engine>) -> Result<()> {
     );
 
     let mut archives = ArchivesStream::new(engine, last_mc_block_id.seq_no + 1.., None);
+    let new_shard_zero_states =
+        NewShardZeroStates::new(NEW_SHARD_ZEROSTATE_TIMEOUT, NEW_SHARD_ZEROSTATE_RETRY_AFTER);
+    let mut progress_log = SyncProgressLogger::new(engine.sync_options.progress_log);
 
     let mut last_gen_utime = 0;
     loop {
-        let archive = archives.recv().await;
+        // NOTE: masterchain blocks from broadcasts are applied as soon as they
+        // are contiguous with the tip, so the node could become synced before
+        // the archive with the next blocks is available
+        let mut archive = tokio::select! {
+            archive = archives.recv() => archive,
+            result = wait_synced_by_broadcasts(engine) => {
+                result?;
+                tracing::info!(target: "sync", "synced by block broadcasts");
+                break;
+            }
+        };
+
+        engine.wait_for_disk_space(DiskSpaceLevel::Critical).await;
+
+        let import_start = std::time::Instant::now();
         if let Err(e) = import_package_with_apply(
             engine,
-            archive.clone(),
+            &mut archive,
+            &new_shard_zero_states,
             &last_mc_block_id,
             &mut last_gen_utime,
         )
@@ -44,101 +75,240 @@ pub async fn sync(engine: &Arc<Engine>) -> Result<()> {
                 block_id = %last_mc_block_id.display(),
                 "failed to apply queued archive: {e:?}"
             );
+            if is_invalid_archive(&e) {
+                archive.reject();
+            } else {
+                archive.retry();
+            }
             continue;
         }
 
+        last_mc_block_id = engine.last_applied_block()?;
+        progress_log.record(
+            &last_mc_block_id,
+            archive.mc_block_count(),
+            import_start.elapsed(),
+        );
+
         if engine.is_synced()? {
             break;
         }
 
-        last_mc_block_id = engine.last_applied_block()?;
         archive.accept_with_time(last_gen_utime, None); // TODO
     }
+    progress_log.finish();
+
+    tracing::info!(target: "sync", stats = ?engine.sync_stats(), "normal sync finished");
+    Ok(())
+}
+
+/// Imports shard blocks from archives until the shard client
+/// is close enough to the last applied masterchain block.
+pub async fn catch_up_shard_blocks(engine: &Arc<Engine>) -> Result<()> {
+    let shards_client_mc_block_id = engine.load_shards_client_mc_block_id()?;
+    tracing::info!(
+        target: "sync",
+        shards_client_mc_block_id = %shards_client_mc_block_id.display(),
+        last_mc_block_id = %engine.load_last_applied_mc_block_id()?.display(),
+        "started shard blocks catch up"
+    );
+
+    let mut archives = ArchivesStream::new(engine, shards_client_mc_block_id.seq_no + 1.., None);
+    let new_shard_zero_states =
+        NewShardZeroStates::new(NEW_SHARD_ZEROSTATE_TIMEOUT, NEW_SHARD_ZEROSTATE_RETRY_AFTER);
+    let mut progress_log = SyncProgressLogger::new(engine.sync_options.progress_log);
+
+    let mut last_gen_utime = 0;
+    while engine.is_working() {
+        let mut archive = archives.recv().await;
+        engine.wait_for_disk_space(DiskSpaceLevel::Critical).await;
+
+        // NOTE: masterchain blocks are applied concurrently by the masterchain walker,
+        // so the archive could contain only already applied ones
+        let last_mc_block_id = engine.load_last_applied_mc_block_id()?;
+        let import_start = std::time::Instant::now();
+        if let Err(e) = import_package_with_apply(
+            engine,
+            &mut archive,
+            &new_shard_zero_states,
+            &last_mc_block_id,
+            &mut last_gen_utime,
+        )
+        .await
+        {
+            tracing::error!(
+                target: "sync",
+                block_id = %last_mc_block_id.display(),
+                "failed to import shard blocks from archive: {e:?}"
+            );
+            if is_invalid_archive(&e) {
+                archive.reject();
+            } else {
+                archive.retry();
+            }
+            continue;
+        }
+
+        let shards_client_mc_block_id = engine.load_shards_client_mc_block_id()?;
+        progress_log.record(
+            &shards_client_mc_block_id,
+            archive.mc_block_count(),
+            import_start.elapsed(),
+        );
+
+        let last_mc_seq_no = engine.load_last_applied_mc_block_id()?.seq_no;
+        if shard_client_caught_up(shards_client_mc_block_id.seq_no, last_mc_seq_no) {
+            break;
+        }
+
+        archive.accept(None);
+    }
+    progress_log.finish();
 
+    tracing::info!(
+        target: "sync",
+        stats = ?engine.sync_stats(),
+        "finished shard blocks catch up"
+    );
     Ok(())
 }
 
+/// Whether the shard client lags so much that importing archives
+/// is faster than downloading shard blocks one by one
+pub fn shard_client_far_behind(shards_client_mc_seq_no: u32, last_mc_seq_no: u32) -> bool {
+    last_mc_seq_no.saturating_sub(shards_client_mc_seq_no) > SHARD_CLIENT_ARCHIVES_THRESHOLD
+}
+
+/// Whether the shard client can return to the per-block walking
+///
+/// NOTE: the threshold is lower than the one used to start the catch up
+/// to not switch modes back and forth on the edge
+pub(super) fn shard_client_caught_up(shards_client_mc_seq_no: u32, last_mc_seq_no: u32) -> bool {
+    last_mc_seq_no.saturating_sub(shards_client_mc_seq_no) <= SHARD_CLIENT_ARCHIVES_THRESHOLD / 2
+}
+
+async fn wait_synced_by_broadcasts(engine: &Arc<Engine>) -> Result<()> {
+    const CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        if engine.is_synced()? {
+            return Ok(());
+        }
+    }
+}
+
 #[tracing::instrument(
-    skip(engine, maps, last_mc_block_id),
+    skip(engine, archive, new_shard_zero_states, last_mc_block_id),
     fields(last_mc_block_id = %last_mc_block_id.display())
 )]
 async fn import_package_with_apply(
     engine: &Arc<Engine>,
-    maps: Arc<BlockMaps>,
+    archive: &mut ReceivedBlockMaps<'_>,
+    new_shard_zero_states: &NewShardZeroStates,
     last_mc_block_id: &ton_block::BlockIdExt,
     last_gen_utime: &mut u32,
-) -> Result<()> {
-    if maps.mc_block_ids.is_empty() {
+) -> Result<ImportStats> {
+    let result = import_package_with_apply_impl(
+        engine,
+        archive,
+        new_shard_zero_states,
+        last_mc_block_id,
+        last_gen_utime,
+    )
+    .await;
+
+    let mut sync_stats = engine.sync_stats.lock();
+    match &result {
+        Ok(stats) => sync_stats.record_imported(stats),
+        Err(_) => sync_stats.record_failed(),
+    }
+    result
+}
+
+/// NOTE: the archive maps are released during the import,
+/// so the archive can't be retried without a new download after the shard blocks are scheduled
+async fn import_package_with_apply_impl(
+    engine: &Arc<Engine>,
+    archive: &mut ReceivedBlockMaps<'_>,
+    new_shard_zero_states: &NewShardZeroStates,
+    last_mc_block_id: &ton_block::BlockIdExt,
+    last_gen_utime: &mut u32,
+) -> Result<ImportStats> {
+    if archive.mc_block_count() == 0 {
         return Err(SyncError::EmptyArchivePackage.into());
     }
 
+    let mut stats = ImportStats::default();
+
     let import_start = std::time::Instant::now();
+    import_mc_blocks_with_apply(
+        engine,
+        &***archive,
+        last_mc_block_id,
+        last_gen_utime,
+        &mut stats,
+    )
+    .await?;
+    stats.mc_blocks_duration = import_start.elapsed();
 
-    import_mc_blocks_with_apply(engine, &maps, last_mc_block_id, last_gen_utime).await?;
-    import_shard_blocks_with_apply(engine, &maps).await?;
+    let shard_blocks_start = std::time::Instant::now();
+    import_shard_blocks_with_apply(engine, archive, new_shard_zero_states, &mut stats).await?;
+    stats.shard_blocks_duration = shard_blocks_start.elapsed();
 
     let elapsed_ms = import_start.elapsed().as_millis();
     tracing::info!(
         target: "sync",
         block_id = %last_mc_block_id.display(),
         elapsed_ms,
+        ?stats,
         "imported archive package"
     );
-    Ok(())
+    Ok(stats)
 }
 
 async fn import_mc_blocks_with_apply(
     engine: &Arc<Engine>,
     maps: &BlockMaps,
-    mut last_mc_block_id: &ton_block::BlockIdExt,
+    last_mc_block_id: &ton_block::BlockIdExt,
     last_gen_utime: &mut u32,
+    stats: &mut ImportStats,
 ) -> Result<()> {
     let db = &engine.db;
 
-    for id in maps.mc_block_ids.values() {
-        // Skip already processed blocks
-        if id.seq_no <= last_mc_block_id.seq_no {
-            if id.seq_no == last_mc_block_id.seq_no && last_mc_block_id != id {
-                return Err(SyncError::MasterchainBlockIdMismatch.into());
-            }
-            continue;
-        }
-
-        // Ensure that we have all previous blocks
-        if id.seq_no != last_mc_block_id.seq_no + 1 {
-            tracing::error!(
-                target: "sync",
-                seq_no = id.seq_no,
-                expected = last_mc_block_id.seq_no + 1,
-                "masterchain block seqno mismatch",
-            );
-            return Err(SyncError::BlocksSkippedInArchive.into());
-        }
+    let (processed, new_ids) =
+        split_processed_mc_blocks(maps.mc_block_ids.values(), last_mc_block_id)?;
+    stats.blocks_skipped += processed as u64;
 
+    let mut last_mc_block_id = last_mc_block_id;
+    for id in new_ids {
         // Move last applied mc block
         last_mc_block_id = id;
 
         // Skip already applied blocks
         if let Some(handle) = db.block_handle_storage().load_handle(id)? {
             if handle.meta().is_applied() {
+                stats.blocks_skipped += 1;
                 continue;
             }
         }
 
         // Save block
-        let entry = maps
-            .blocks
-            .get(id)
-            .context("Failed to get masterchain block entry")?;
-
-        let (block, block_proof) = entry.get_data()?;
-        let handle = engine.save_block(block, block_proof, id.seq_no).await?;
+        let (info, block, block_proof) = engine.prepare_archive_block(maps, id).await?;
+        stats.proofs_verified += 1;
+        let (handle, bytes_written) = engine
+            .save_block(info, block, block_proof, id.seq_no)
+            .await?;
+        stats.bytes_written += bytes_written;
         *last_gen_utime = handle.meta().gen_utime();
 
         // Apply block
         engine
             .apply_block_ext(&handle, block, id.seq_no, false, 0)
             .await?;
+        stats.blocks_applied += 1;
     }
 
     tracing::info!(
@@ -149,42 +319,187 @@ async fn import_mc_blocks_with_apply(
     Ok(())
 }
 
-async fn import_shard_blocks_with_apply(engine: &Arc<Engine>, maps: &Arc<BlockMaps>) -> Result<()> {
-    let db = &engine.db;
+/// Returns top shardchain blocks from the archive or loads them from db
+/// (masterchain block could have been applied before this archive)
+async fn load_shard_blocks(
+    engine: &Engine,
+    maps: &BlockMaps,
+    mc_block_id: &ton_block::BlockIdExt,
+) -> Result<FxHashMap<ton_block::ShardIdent, ton_block::BlockIdExt>> {
+    match maps.blocks.get(mc_block_id).and_then(|e| e.block.as_ref()) {
+        Some(block) => block.data.shard_blocks(),
+        None => {
+            let db = &engine.db;
+            let masterchain_handle = db
+                .block_handle_storage()
+                .load_handle(mc_block_id)?
+                .ok_or(SyncError::MasterchainBlockNotFound)?;
+            db.block_storage()
+                .load_block_data(&masterchain_handle)
+                .await?
+                .shard_blocks()
+        }
+    }
+}
+
+/// Whether the import failed because of the archive contents,
+/// so it must not be imported again
+fn is_invalid_archive(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<BlockMapsError>(),
+        Some(BlockMapsError::UncommittedShardBlock { .. })
+    )
+}
 
-    // Save all shardchain blocks
-    for (id, entry) in &maps.blocks {
-        if !id.shard_id.is_masterchain() {
-            let (block, block_proof) = entry.get_data()?;
-            engine.save_block(block, block_proof, 0).await?;
+/// Returns the number of masterchain blocks up to the last applied one
+/// and the remaining blocks, which must continue the last applied one without gaps
+fn split_processed_mc_blocks<'a, I>(
+    mc_block_ids: I,
+    last_mc_block_id: &ton_block::BlockIdExt,
+) -> Result<(usize, Vec<&'a ton_block::BlockIdExt>), SyncError>
+where
+    I: IntoIterator<Item = &'a ton_block::BlockIdExt>,
+{
+    let mut processed = 0;
+    let mut new_ids = Vec::new();
+    let mut expected_seq_no = last_mc_block_id.seq_no + 1;
+
+    for id in mc_block_ids {
+        // Skip already processed blocks
+        if id.seq_no <= last_mc_block_id.seq_no {
+            if id.seq_no == last_mc_block_id.seq_no && last_mc_block_id != id {
+                return Err(SyncError::MasterchainBlockIdMismatch);
+            }
+            processed += 1;
+            continue;
         }
+
+        // Ensure that we have all previous blocks
+        if id.seq_no != expected_seq_no {
+            tracing::error!(
+                target: "sync",
+                seq_no = id.seq_no,
+                expected = expected_seq_no,
+                "masterchain block seqno mismatch",
+            );
+            return Err(SyncError::BlocksSkippedInArchive);
+        }
+        expected_seq_no += 1;
+        new_ids.push(id);
     }
 
+    Ok((processed, new_ids))
+}
+
+async fn import_shard_blocks_with_apply(
+    engine: &Arc<Engine>,
+    archive: &mut ReceivedBlockMaps<'_>,
+    new_shard_zero_states: &NewShardZeroStates,
+    stats: &mut ImportStats,
+) -> Result<()> {
+    // NOTE: masterchain blocks are loaded once for both the check and the schedule
+    let top_blocks = {
+        let maps = &***archive;
+
+        let mut top_blocks = McTopBlocks::with_capacity(maps.mc_block_ids.len());
+        for mc_block_id in maps.mc_block_ids.values() {
+            let shard_blocks = load_shard_blocks(engine, maps, mc_block_id).await?;
+            top_blocks.push((mc_block_id.clone(), shard_blocks.into_values().collect()));
+        }
+
+        // Ensure that shard blocks are the ones committed by the masterchain
+        let verified = match engine.sync_options.proof_mode {
+            ArchiveProofMode::Full => {
+                let committed = top_blocks.iter().flat_map(|(_, ids)| ids.iter().cloned());
+                Some(maps.check_committed_shard_blocks(committed)?)
+            }
+            ArchiveProofMode::LinkOnly => None,
+        };
+
+        // Save shardchain blocks
+        let mut uncommitted = 0usize;
+        for id in maps.blocks.keys() {
+            if id.shard_id.is_masterchain() {
+                continue;
+            }
+            if matches!(&verified, Some(verified) if !verified.contains(id)) {
+                tracing::debug!(
+                    target: "sync",
+                    block_id = %id.display(),
+                    "skipping shard block which is not committed by the masterchain"
+                );
+                uncommitted += 1;
+                continue;
+            }
+
+            let (info, block, block_proof) = engine.prepare_archive_block(maps, id).await?;
+            stats.proofs_verified += 1;
+            let (_, bytes_written) = engine.save_block(info, block, block_proof, 0).await?;
+            stats.bytes_written += bytes_written;
+        }
+        if uncommitted > 0 {
+            tracing::warn!(target: "sync", uncommitted, "skipped uncommitted shard blocks");
+        }
+
+        top_blocks
+    };
+
+    // Take only the needed blocks from the archive and release it
+    // before applying, so that the memory is freed as blocks are applied
+    let last_applied_mc_block_id = engine.load_shards_client_mc_block_id()?;
+    let TakenBlockMaps { maps, memory } = archive
+        .take_block_maps()
+        .ok_or(SyncError::BlockMapsReleased)?;
+    let schedule = schedule_shard_blocks(&maps, top_blocks, last_applied_mc_block_id.seq_no)?;
+    drop(maps);
+    let mut memory = ScheduledMemory::new(memory, &schedule);
+
+    let counters = Arc::new(ImportCounters::default());
+
     // Iterate through all masterchain blocks in archive
-    let mut last_applied_mc_block_id = engine.load_shards_client_mc_block_id()?;
-    for mc_block_id in maps.mc_block_ids.values() {
+    for scheduled in schedule {
+        let ScheduledMcBlock {
+            mc_block_id,
+            shard_block_ids,
+            archive_blocks,
+            ..
+        } = &scheduled;
         let mc_seq_no = mc_block_id.seq_no;
-        if mc_seq_no <= last_applied_mc_block_id.seq_no {
-            continue;
-        }
 
-        // Load masterchain block data and top shardchain blocks
-        let masterchain_handle = db
-            .block_handle_storage()
-            .load_handle(mc_block_id)?
-            .ok_or(SyncError::MasterchainBlockNotFound)?;
-        let masterchain_block = db
-            .block_storage()
-            .load_block_data(&masterchain_handle)
+        // Download zerostates of the new shards before applying blocks
+        new_shard_zero_states
+            .fetch(shard_block_ids, |id| {
+                let engine = engine.clone();
+                async move {
+                    engine
+                        .download_zero_state_ext(&id, NEW_SHARD_ZEROSTATE_TIMEOUTS)
+                        .await
+                        .map(|_| ())
+                }
+            })
             .await?;
-        let shard_blocks = masterchain_block.shard_blocks()?;
 
         // Start applying blocks for each shard
-        let mut tasks = Vec::with_capacity(shard_blocks.len());
-        for (_, id) in shard_blocks {
+        let retries = apply_mc_block_shards(engine, mc_block_id, shard_block_ids.clone(), |id, attempt| {
             let engine = engine.clone();
-            let maps = maps.clone();
-            tasks.push(tokio::spawn(async move {
+            let archive_block = archive_blocks.get(&id).cloned();
+            let counters = counters.clone();
+            async move {
+                let _permit = engine.shard_apply_limiter.acquire().await?;
+
+                if attempt > 0 {
+                    // Retry with fresh downloads
+                    tracing::info!(
+                        target: "sync",
+                        block_id = %id.display(),
+                        attempt,
+                        "retrying shard block application"
+                    );
+                    engine.download_and_apply_block(&id, mc_seq_no, false, 0).await?;
+                    counters.applied.fetch_add(1, Ordering::Release);
+                    return Ok(());
+                }
+
                 let db = &engine.db;
 
                 let handle = db
@@ -194,6 +509,7 @@ async fn import_shard_blocks_with_apply(engine: &Arc<Engine>, maps: &Arc<BlockMa
 
                 // Skip applied blocks
                 if handle.meta().is_applied() {
+                    counters.skipped.fetch_add(1, Ordering::Release);
                     return Ok(());
                 }
 
@@ -204,21 +520,9 @@ async fn import_shard_blocks_with_apply(engine: &Arc<Engine>, maps: &Arc<BlockMa
                 }
 
                 // Get block data or load it from db
-                let block_storage = db.block_storage();
-                let block = match maps.blocks.get(&id) {
-                    Some(entry) => match &entry.block {
-                        Some(block) => Some(Cow::Borrowed(&block.data)),
-                        None => block_storage
-                            .load_block_data(&handle)
-                            .await
-                            .ok()
-                            .map(Cow::Owned),
-                    },
-                    None => block_storage
-                        .load_block_data(&handle)
-                        .await
-                        .ok()
-                        .map(Cow::Owned),
+                let block = match archive_block {
+                    Some(block) => Some(block),
+                    None => db.block_storage().load_block_data(&handle).await.ok(),
                 };
 
                 // TODO:
@@ -229,63 +533,288 @@ async fn import_shard_blocks_with_apply(engine: &Arc<Engine>, maps: &Arc<BlockMa
                 match block {
                     Some(block) => {
                         engine
-                            .apply_block_ext(&handle, block.as_ref(), mc_seq_no, false, 0)
-                            .await
+                            .apply_block_ext(&handle, &block, mc_seq_no, false, 0)
+                            .await?
                     }
                     None => {
                         tracing::info!(target: "sync", mc_seq_no, "downloading shardchain block");
                         engine
                             .download_and_apply_block(handle.id(), mc_seq_no, false, 0)
-                            .await
+                            .await?
                     }
                 }
-            }));
+                counters.applied.fetch_add(1, Ordering::Release);
+                Ok(())
+            }
+        })
+        .await?;
+        stats.shard_block_retries += retries as u64;
+        memory.release(&scheduled);
+    }
+
+    counters.add_to(stats);
+    Ok(())
+}
+
+/// Top shard blocks of each masterchain block of the archive
+type McTopBlocks = Vec<(ton_block::BlockIdExt, Vec<ton_block::BlockIdExt>)>;
+
+/// Top shard blocks of the masterchain block with their data from the archive
+struct ScheduledMcBlock {
+    mc_block_id: ton_block::BlockIdExt,
+    shard_block_ids: Vec<ton_block::BlockIdExt>,
+    /// Top shard blocks which are present in the archive
+    archive_blocks: FxHashMap<ton_block::BlockIdExt, BlockStuff>,
+    /// Size of the raw data of `archive_blocks`
+    data_size: usize,
+}
+
+/// Collects the top shard blocks of the masterchain blocks which are not processed
+/// by the shard client yet, so that the archive could be dropped before applying them.
+///
+/// `top_blocks` are the top shard blocks of each masterchain block of the archive
+fn schedule_shard_blocks(
+    maps: &BlockMaps,
+    top_blocks: McTopBlocks,
+    last_applied_mc_seq_no: u32,
+) -> Result<Vec<ScheduledMcBlock>> {
+    let mut schedule = Vec::new();
+    for (mc_block_id, shard_block_ids) in top_blocks {
+        if mc_block_id.seq_no <= last_applied_mc_seq_no {
+            continue;
+        }
+
+        let mut archive_blocks = FxHashMap::default();
+        let mut data_size = 0;
+        for id in &shard_block_ids {
+            if let Some(block) = maps.blocks.get(id).and_then(|entry| entry.block.as_ref()) {
+                data_size += block.new_archive_data()?.len();
+                archive_blocks.insert(id.clone(), block.data.clone());
+            }
+        }
+
+        schedule.push(ScheduledMcBlock {
+            mc_block_id,
+            shard_block_ids,
+            archive_blocks,
+            data_size,
+        });
+    }
+    Ok(schedule)
+}
+
+/// Memory reservation of the scheduled blocks which are not applied yet
+struct ScheduledMemory(Option<MemoryBudgetGuard>);
+
+impl ScheduledMemory {
+    /// Shrinks the reservation of the whole archive to the scheduled blocks
+    fn new(mut memory: Option<MemoryBudgetGuard>, schedule: &[ScheduledMcBlock]) -> Self {
+        if let Some(memory) = &mut memory {
+            let scheduled = schedule
+                .iter()
+                .map(|scheduled| scheduled.data_size * DECODED_ARCHIVE_SIZE_FACTOR)
+                .sum::<usize>();
+            memory.resize(std::cmp::min(memory.bytes(), scheduled));
         }
+        Self(memory)
+    }
+
+    fn release(&mut self, applied: &ScheduledMcBlock) {
+        if let Some(memory) = &mut self.0 {
+            memory.release(applied.data_size * DECODED_ARCHIVE_SIZE_FACTOR);
+        }
+    }
+}
 
-        futures_util::future::try_join_all(tasks)
-            .await?
+/// Applies the top shard blocks of the masterchain block (see [`apply_shard_blocks`])
+/// and advances the shards client pointer to it.
+///
+/// Returns the total number of retries
+async fn apply_mc_block_shards<F, R>(
+    engine: &Arc<Engine>,
+    mc_block_id: &ton_block::BlockIdExt,
+    shard_block_ids: Vec<ton_block::BlockIdExt>,
+    apply: F,
+) -> Result<usize>
+where
+    F: FnMut(ton_block::BlockIdExt, usize) -> R,
+    R: Future<Output = Result<()>> + Send + 'static,
+{
+    let retries =
+        apply_shard_blocks(shard_block_ids, MAX_SHARD_BLOCK_APPLY_ATTEMPTS, apply).await?;
+
+    engine.flush_shard_notifications(mc_block_id.seq_no).await?;
+    engine.store_shards_client_mc_block_id(mc_block_id, "shard blocks applied from archive")?;
+    notify_late_subscribers(engine, mc_block_id);
+    Ok(retries)
+}
+
+/// Applies shard blocks concurrently, retrying only failed ones.
+///
+/// `apply` receives the block id and the attempt number (starting from zero).
+/// Returns the total number of retries
+async fn apply_shard_blocks<F, R>(
+    mut block_ids: Vec<ton_block::BlockIdExt>,
+    max_attempts: usize,
+    mut apply: F,
+) -> Result<usize, SyncError>
+where
+    F: FnMut(ton_block::BlockIdExt, usize) -> R,
+    R: Future<Output = Result<()>> + Send + 'static,
+{
+    let mut retries = 0;
+    for attempt in 0..max_attempts {
+        if attempt > 0 {
+            retries += block_ids.len();
+        }
+
+        let tasks = block_ids.iter().map(|id| {
+            let id = id.clone();
+            let task = tokio::spawn(lock_owner_scope(apply(id.clone(), attempt)));
+            async move {
+                match task.await {
+                    Ok(Ok(())) => None,
+                    Ok(Err(e)) => {
+                        tracing::warn!(
+                            target: "sync",
+                            block_id = %id.display(),
+                            attempt,
+                            "failed to apply shard block: {e:?}"
+                        );
+                        Some(id)
+                    }
+                    Err(e) => {
+                        tracing::warn!(
+                            target: "sync",
+                            block_id = %id.display(),
+                            attempt,
+                            "shard block task failed: {e:?}"
+                        );
+                        Some(id)
+                    }
+                }
+            }
+        });
+
+        block_ids = futures_util::future::join_all(tasks)
+            .await
             .into_iter()
-            .find(|item| item.is_err())
-            .unwrap_or(Ok(()))?;
+            .flatten()
+            .collect();
 
-        engine.store_shards_client_mc_block_id(mc_block_id)?;
-        last_applied_mc_block_id = mc_block_id.clone();
+        if block_ids.is_empty() {
+            return Ok(retries);
+        }
     }
 
-    Ok(())
+    Err(SyncError::ShardBlocksNotApplied(FailedShardBlocks(
+        block_ids,
+    )))
 }
 
 impl Engine {
+    /// Saves and applies the block from an external source the same way
+    /// as blocks from archives. Shard blocks are only saved, they are applied
+    /// with the masterchain block which references them.
+    pub async fn submit_block(
+        self: &Arc<Self>,
+        block: &BlockStuffAug,
+        block_proof: &BlockProofStuffAug,
+    ) -> Result<()> {
+        let block_id = block.id();
+        if block_proof.id() != block_id {
+            return Err(SyncError::BlockProofMismatch.into());
+        }
+
+        let is_masterchain = block_id.shard_id.is_masterchain();
+        if is_masterchain && block_proof.is_link() {
+            return Err(SyncError::MasterchainProofLink.into());
+        }
+
+        // Skip already applied blocks
+        if let Some(handle) = self.db.block_handle_storage().load_handle(block_id)? {
+            if handle.meta().is_applied() {
+                return Ok(());
+            }
+        }
+
+        let info = self.check_block_proof(block_proof).await?;
+        if !is_masterchain {
+            self.save_block(info, block, block_proof, 0).await?;
+            return Ok(());
+        }
+
+        let (handle, _) = self
+            .save_block(info, block, block_proof, block_id.seq_no)
+            .await?;
+        self.apply_block_ext(&handle, block, block_id.seq_no, false, 0)
+            .await
+    }
+
     fn last_applied_block(&self) -> Result<ton_block::BlockIdExt> {
         let mc_block_id = self.load_last_applied_mc_block_id()?;
         let sc_block_id = self.load_shards_client_mc_block_id()?;
 
         Ok(match (mc_block_id, sc_block_id) {
-            (mc_block_id, sc_block_id) if mc_block_id.seq_no > sc_block_id.seq_no => sc_block_id,
+            (mc_block_id, sc_block_id) if mc_block_id.seq_no > sc_block_id.seq_no => {
+                self.audit_log.record(
+                    AuditLogEventKind::PointerRewind,
+                    Some(&sc_block_id),
+                    format!(
+                        "sync continues from shards client block, last applied is {}",
+                        mc_block_id.seq_no
+                    ),
+                );
+                sc_block_id
+            }
             (mc_block_id, _) => mc_block_id,
         })
     }
 
+    /// Finds block data and proof in the archive and checks the proof.
+    ///
+    /// Shared by the normal and historical sync
+    async fn prepare_archive_block<'a>(
+        &self,
+        maps: &'a BlockMaps,
+        block_id: &ton_block::BlockIdExt,
+    ) -> Result<(BriefBlockInfo, &'a BlockStuffAug, &'a BlockProofStuffAug)> {
+        let entry = maps.blocks.get(block_id);
+        let (block, proof) = entry.ok_or(SyncError::IncompleteBlockData)?.get_data()?;
+        let info = self.check_block_proof(proof).await?;
+        Ok((info, block, proof))
+    }
+
+    /// Returns the block handle and the size of the newly stored data
     async fn save_block(
         &self,
+        info: BriefBlockInfo,
         block: &BlockStuffAug,
         block_proof: &BlockProofStuffAug,
         mc_seq_no: u32,
-    ) -> Result<Arc<BlockHandle>> {
+    ) -> Result<(Arc<BlockHandle>, u64)> {
         let block_storage = self.db.block_storage();
 
-        let info = self.check_block_proof(block_proof).await?;
+        let new_data_len = |new: bool, data: Result<&[u8], _>| match (new, data) {
+            (true, Ok(data)) => data.len() as u64,
+            _ => 0,
+        };
 
-        let handle = block_storage
+        let result = block_storage
             .store_block_data(block, info.with_mc_seq_no(mc_seq_no))
-            .await?
-            .handle;
-        let handle = block_storage
-            .store_block_proof(block_proof, handle.into())
-            .await?
-            .handle;
-
-        Ok(handle)
+            .await?;
+        let mut bytes_written = new_data_len(result.new, block.new_archive_data());
+
+        let result = block_storage
+            .store_checked_block_proof(
+                block_proof,
+                result.handle.into(),
+                info.proof_key_block_seqno,
+            )
+            .await?;
+        bytes_written += new_data_len(result.new, block_proof.new_archive_data());
+
+        Ok((result.handle, bytes_written))
     }
 }
 
@@ -301,4 +830,376 @@ enum SyncError {
     MasterchainBlockNotFound,
     #[error("Shardchain block handle not found")]
     ShardchainBlockHandleNotFound,
+    #[error("Incomplete block data")]
+    IncompleteBlockData,
+    #[error("Block proof is for another block")]
+    BlockProofMismatch,
+    #[error("Masterchain block must have a full proof")]
+    MasterchainProofLink,
+    #[error("Failed to apply shard blocks: {0}")]
+    ShardBlocksNotApplied(FailedShardBlocks),
+    #[error("Masterchain block was not notified after its shard blocks")]
+    UndeliveredMasterchainBlock,
+    #[error("Archive block maps were already released")]
+    BlockMapsReleased,
+}
+
+#[derive(Debug)]
+struct FailedShardBlocks(Vec<ton_block::BlockIdExt>);
+
+impl std::fmt::Display for FailedShardBlocks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, id) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            std::fmt::Display::fmt(&id.display(), f)?;
+        }
+        Ok(())
+    }
+}
+
+const MAX_SHARD_BLOCK_APPLY_ATTEMPTS: usize = 3;
+
+/// Max number of masterchain blocks the shard client could lag behind
+/// before switching to the archives import
+const SHARD_CLIENT_ARCHIVES_THRESHOLD: u32 = 200;
+
+const NEW_SHARD_ZEROSTATE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+const NEW_SHARD_ZEROSTATE_RETRY_AFTER: std::time::Duration = std::time::Duration::from_secs(30);
+const NEW_SHARD_ZEROSTATE_TIMEOUTS: DownloaderTimeouts = DownloaderTimeouts {
+    initial: 200,
+    max: 5000,
+    multiplier: 1.5,
+};
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::test_helpers::*;
+
+    fn shard() -> ton_block::ShardIdent {
+        ton_block::ShardIdent::with_tagged_prefix(0, 0x8000_0000_0000_0000).unwrap()
+    }
+
+    #[tokio::test]
+    async fn retry_only_failed_shard_blocks() {
+        let dir = TempDir::new("retry_only_failed_shard_blocks");
+        let engine = test_engine(&dir, Vec::new()).await;
+        engine
+            .db
+            .node_state()
+            .store_shards_client_mc_block_id(&mc_block_id(1))
+            .unwrap();
+
+        let ids = (1..=4)
+            .map(|seq_no| block_id(shard(), seq_no))
+            .collect::<Vec<_>>();
+        let failing_id = ids[2].clone();
+
+        let applied = Arc::new(parking_lot::Mutex::new(FxHashMap::default()));
+        let failures = Arc::new(AtomicUsize::new(0));
+
+        let retries = apply_mc_block_shards(&engine, &mc_block_id(2), ids.clone(), |id, _| {
+            let applied = applied.clone();
+            let failures = failures.clone();
+            let failing = id == failing_id;
+            async move {
+                // Fail transiently once
+                if failing && failures.fetch_add(1, Ordering::AcqRel) == 0 {
+                    anyhow::bail!("transient error");
+                }
+                *applied.lock().entry(id).or_insert(0usize) += 1;
+                Ok(())
+            }
+        })
+        .await
+        .unwrap();
+
+        let applied = applied.lock();
+        assert_eq!(failures.load(Ordering::Acquire), 2);
+        assert_eq!(retries, 1);
+        for id in &ids {
+            assert_eq!(applied.get(id), Some(&1));
+        }
+
+        // Masterchain pointer advances after the retry
+        assert_eq!(
+            engine.load_shards_client_mc_block_id().unwrap(),
+            mc_block_id(2)
+        );
+    }
+
+    #[tokio::test]
+    async fn report_permanently_failed_shard_blocks() {
+        let ids = (0..4)
+            .map(|seq_no| block_id(shard(), seq_no))
+            .collect::<Vec<_>>();
+        let failing_id = ids[1].clone();
+
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let result = apply_shard_blocks(ids, 3, |id, _| {
+            let attempts = attempts.clone();
+            let failing = id == failing_id;
+            async move {
+                attempts.fetch_add(1, Ordering::AcqRel);
+                if failing {
+                    anyhow::bail!("permanent error");
+                }
+                Ok(())
+            }
+        })
+        .await;
+
+        // 4 blocks on the first attempt and 2 retries of the failed one
+        assert_eq!(attempts.load(Ordering::Acquire), 6);
+        match result {
+            Err(SyncError::ShardBlocksNotApplied(FailedShardBlocks(ids))) => {
+                assert_eq!(ids, [failing_id]);
+            }
+            _ => panic!("unexpected result"),
+        }
+    }
+
+    #[test]
+    fn skip_already_applied_mc_blocks() {
+        let ids = (10..20).map(mc_block_id).collect::<Vec<_>>();
+
+        // Re-importing an already applied archive
+        let (processed, new_ids) = split_processed_mc_blocks(&ids, &mc_block_id(25)).unwrap();
+        assert_eq!(processed, 10);
+        assert!(new_ids.is_empty());
+
+        // Archive overlaps the last applied block
+        let (processed, new_ids) = split_processed_mc_blocks(&ids, &mc_block_id(14)).unwrap();
+        assert_eq!(processed, 5);
+        assert_eq!(new_ids, ids[5..].iter().collect::<Vec<_>>());
+
+        // Archive continues the last applied block
+        let (processed, new_ids) = split_processed_mc_blocks(&ids, &mc_block_id(9)).unwrap();
+        assert_eq!(processed, 0);
+        assert_eq!(new_ids.len(), 10);
+
+        // Gap after the last applied block
+        assert!(matches!(
+            split_processed_mc_blocks(&ids, &mc_block_id(5)),
+            Err(SyncError::BlocksSkippedInArchive)
+        ));
+
+        // Different block with the same seqno
+        let mut other = mc_block_id(14);
+        other.root_hash = ton_types::UInt256::from([1; 32]);
+        assert!(matches!(
+            split_processed_mc_blocks(&ids, &other),
+            Err(SyncError::MasterchainBlockIdMismatch)
+        ));
+    }
+
+    #[tokio::test]
+    async fn reimport_applied_archive() {
+        let dir = TempDir::new("reimport_applied_archive");
+        let engine = test_engine(&dir, Vec::new()).await;
+
+        // Masterchain blocks which were already applied from the same archive
+        let blocks = (1..=3).map(make_mc_block).collect::<Vec<_>>();
+        let block_handle_storage = engine.db.block_handle_storage();
+        for (id, _) in &blocks {
+            let meta_data = BlockMetaData {
+                is_key_block: false,
+                gen_utime: id.seq_no,
+                mc_ref_seqno: Some(id.seq_no),
+            };
+            let (handle, _) = block_handle_storage
+                .create_or_load_handle(id, meta_data)
+                .unwrap();
+            handle.meta().set_is_applied();
+            block_handle_storage.store_handle(&handle).unwrap();
+        }
+
+        let last_id = &blocks[2].0;
+        let node_state = engine.db.node_state();
+        node_state.store_last_mc_block_id(last_id).unwrap();
+        node_state.store_shards_client_mc_block_id(last_id).unwrap();
+
+        // Import starts from the second block, the third one
+        // was applied concurrently by the masterchain walker
+        let maps = BlockMaps::new(&make_archive(&blocks)).unwrap();
+        let mut archives = ArchivesStream::with_loaded_archives(&engine, 1, &[(1, maps)]);
+        let mut archive = archives.recv().await;
+        let new_shard_zero_states =
+            NewShardZeroStates::new(NEW_SHARD_ZEROSTATE_TIMEOUT, NEW_SHARD_ZEROSTATE_RETRY_AFTER);
+        let mut last_gen_utime = 0;
+        let stats = import_package_with_apply(
+            &engine,
+            &mut archive,
+            &new_shard_zero_states,
+            &blocks[1].0,
+            &mut last_gen_utime,
+        )
+        .await
+        .unwrap();
+        archive.accept(None);
+
+        assert_eq!(stats.blocks_skipped, 3);
+        assert_eq!(stats.blocks_applied, 0);
+        assert_eq!(stats.proofs_verified, 0);
+        assert_eq!(stats.bytes_written, 0);
+
+        let sync_stats = engine.sync_stats();
+        assert_eq!(sync_stats.archives_imported, 1);
+        assert_eq!(sync_stats.archives_failed, 0);
+        assert_eq!(sync_stats.import, stats);
+    }
+
+    #[tokio::test]
+    async fn reimport_skips_signature_checks() {
+        use crate::test_util::SyntheticChain;
+
+        let now = broxus_util::now();
+        let chain = SyntheticChain::generate(3, now - 100, |seq_no| now - 100 + seq_no).unwrap();
+
+        let dir = TempDir::new("reimport_signature_checks");
+        let engine = test_engine_with_global_config(&dir, chain.global_config(), Vec::new()).await;
+
+        // Proofs of the first key range are checked against the zero state
+        let zero_state = &chain.mc_zero_state;
+        let (handle, _) = engine
+            .db
+            .block_handle_storage()
+            .create_or_load_handle(
+                &zero_state.id,
+                BlockMetaData::zero_state(zero_state.gen_utime),
+            )
+            .unwrap();
+        let state = ShardStateStuff::deserialize_zerostate(zero_state.id.clone(), &zero_state.data)
+            .unwrap();
+        engine
+            .db
+            .shard_state_storage()
+            .store_state(&handle, &state)
+            .await
+            .unwrap();
+
+        let maps = BlockMaps::new(&chain.archive(1..=3)).unwrap();
+        let mc_block_id = |seq_no: u32| chain.mc_block(seq_no).unwrap().id.clone();
+        let signature_checks = || {
+            engine
+                .metrics
+                .proof_signature_checks
+                .load(Ordering::Acquire)
+        };
+
+        // First import of the first two blocks checks the signatures
+        // and records the key block of the check
+        for seq_no in 1..=2 {
+            let id = mc_block_id(seq_no);
+            let (info, block, proof) = engine.prepare_archive_block(&maps, &id).await.unwrap();
+            assert_eq!(info.proof_key_block_seqno, Some(0));
+            let (handle, _) = engine.save_block(info, block, proof, seq_no).await.unwrap();
+            assert_eq!(handle.meta().proof_checked_by(), Some(0));
+        }
+        assert_eq!(signature_checks(), 2);
+
+        // Second import of the same archive
+        for seq_no in 1..=2 {
+            let id = mc_block_id(seq_no);
+            let (info, _, _) = engine.prepare_archive_block(&maps, &id).await.unwrap();
+            assert_eq!(info.proof_key_block_seqno, Some(0));
+        }
+        assert_eq!(signature_checks(), 2);
+
+        // New block is checked
+        let (info, _, _) = engine
+            .prepare_archive_block(&maps, &mc_block_id(3))
+            .await
+            .unwrap();
+        assert_eq!(info.proof_key_block_seqno, Some(0));
+        assert_eq!(signature_checks(), 3);
+    }
+
+    #[test]
+    fn shard_client_switches_to_archives() {
+        const LAST_MC_SEQ_NO: u32 = 10_500;
+
+        // Shard client fell 500 blocks behind after restart
+        let mut shards_client_mc_seq_no = LAST_MC_SEQ_NO - 500;
+        assert!(shard_client_far_behind(
+            shards_client_mc_seq_no,
+            LAST_MC_SEQ_NO
+        ));
+
+        // Archives (100 masterchain blocks each) are imported until the gap is small enough
+        let mut archives = 0;
+        while !shard_client_caught_up(shards_client_mc_seq_no, LAST_MC_SEQ_NO) {
+            shards_client_mc_seq_no += 100;
+            archives += 1;
+        }
+        assert_eq!(archives, 4);
+        assert!(!shard_client_far_behind(
+            shards_client_mc_seq_no,
+            LAST_MC_SEQ_NO
+        ));
+
+        // Small lag is handled by the per-block walker
+        assert!(!shard_client_far_behind(
+            LAST_MC_SEQ_NO - 150,
+            LAST_MC_SEQ_NO
+        ));
+        assert!(!shard_client_far_behind(LAST_MC_SEQ_NO, LAST_MC_SEQ_NO - 1));
+    }
+
+    #[tokio::test]
+    async fn archive_memory_is_released_before_applying_shard_blocks() {
+        let dir = TempDir::new("archive_memory_is_released");
+        let engine = test_engine(&dir, Vec::new()).await;
+
+        let shard = ton_block::ShardIdent::full(ton_block::BASE_WORKCHAIN_ID);
+        let shard_blocks = (1..=4)
+            .map(|seq_no| make_block(shard, seq_no, Default::default()))
+            .collect::<Vec<_>>();
+        let mc_blocks = vec![
+            make_mc_block_with_shards(1, &[shard_blocks[1].0.clone()]),
+            make_mc_block_with_shards(2, &[shard_blocks[3].0.clone()]),
+        ];
+
+        let mut blocks = shard_blocks.clone();
+        blocks.extend(mc_blocks.iter().cloned());
+        let data = make_archive(&blocks);
+        let maps = BlockMaps::new(&data).unwrap();
+
+        let archive_memory = data.len() * DECODED_ARCHIVE_SIZE_FACTOR;
+        let memory = engine
+            .memory_budget
+            .force_acquire(MemoryCategory::BlockMaps, archive_memory);
+        assert_eq!(engine.memory_budget.metrics().block_maps, archive_memory);
+
+        // Only the top shard blocks are kept after the archive is released
+        let mut top_blocks = McTopBlocks::new();
+        for mc_block_id in maps.mc_block_ids.values() {
+            let shard_blocks = load_shard_blocks(&engine, &maps, mc_block_id)
+                .await
+                .unwrap();
+            top_blocks.push((mc_block_id.clone(), shard_blocks.into_values().collect()));
+        }
+        let schedule = schedule_shard_blocks(&maps, top_blocks, 0).unwrap();
+        drop(maps);
+        let mut memory = ScheduledMemory::new(Some(memory), &schedule);
+
+        assert_eq!(schedule.len(), 2);
+        let scheduled_memory =
+            (shard_blocks[1].1.len() + shard_blocks[3].1.len()) * DECODED_ARCHIVE_SIZE_FACTOR;
+        assert_eq!(engine.memory_budget.metrics().block_maps, scheduled_memory);
+        assert!(scheduled_memory < archive_memory);
+
+        // Memory is released as masterchain blocks are processed
+        memory.release(&schedule[0]);
+        assert_eq!(
+            engine.memory_budget.metrics().block_maps,
+            shard_blocks[3].1.len() * DECODED_ARCHIVE_SIZE_FACTOR
+        );
+        memory.release(&schedule[1]);
+        assert_eq!(engine.memory_budget.metrics().block_maps, 0);
+    }
 }
@@ -0,0 +1,175 @@
+use std::future::Future;
+use std::time::Duration;
+
+use anyhow::Result;
+use parking_lot::Mutex;
+use rustc_hash::FxHashMap;
+use tokio::time::Instant;
+
+use crate::utils::*;
+
+/// Fetches zerostates of the shards which first appear in the masterchain block.
+///
+/// NOTE: failed zerostates are remembered for some time so that
+/// the following archives with the same new shard don't hammer peers
+pub struct NewShardZeroStates {
+    timeout: Duration,
+    retry_after: Duration,
+    failed: Mutex<FxHashMap<ton_block::BlockIdExt, Instant>>,
+}
+
+impl NewShardZeroStates {
+    pub fn new(timeout: Duration, retry_after: Duration) -> Self {
+        Self {
+            timeout,
+            retry_after,
+            failed: Default::default(),
+        }
+    }
+
+    /// Downloads all zerostates among the top shard blocks.
+    ///
+    /// Fails if any zerostate was not downloaded within the timeout
+    /// or failed recently
+    pub async fn fetch<'a, I, F, R>(&self, shard_block_ids: I, mut download: F) -> Result<()>
+    where
+        I: IntoIterator<Item = &'a ton_block::BlockIdExt>,
+        F: FnMut(ton_block::BlockIdExt) -> R,
+        R: Future<Output = Result<()>>,
+    {
+        let mut tasks = Vec::new();
+        for id in shard_block_ids {
+            if id.seq_no != 0 {
+                continue;
+            }
+
+            if self.failed_recently(id) {
+                return Err(NewShardError::ZeroStateUnavailable(id.display().to_string()).into());
+            }
+
+            let id = id.clone();
+            let task = tokio::time::timeout(self.timeout, download(id.clone()));
+            tasks.push(async move {
+                let result = match task.await {
+                    Ok(result) => result,
+                    Err(_) => Err(NewShardError::ZeroStateTimeout.into()),
+                };
+                (id, result)
+            });
+        }
+
+        let mut result = Ok(());
+        for (id, task_result) in futures_util::future::join_all(tasks).await {
+            match task_result {
+                Ok(()) => {
+                    self.failed.lock().remove(&id);
+                }
+                Err(e) => {
+                    tracing::warn!(
+                        target: "sync",
+                        block_id = %id.display(),
+                        "failed to download zerostate of the new shard: {e:?}"
+                    );
+                    self.failed.lock().insert(id, Instant::now());
+                    result = Err(e);
+                }
+            }
+        }
+
+        result
+    }
+
+    fn failed_recently(&self, id: &ton_block::BlockIdExt) -> bool {
+        let mut failed = self.failed.lock();
+        match failed.get(id) {
+            Some(failed_at) if failed_at.elapsed() < self.retry_after => true,
+            Some(_) => {
+                failed.remove(id);
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+enum NewShardError {
+    #[error("Zerostate download timeout")]
+    ZeroStateTimeout,
+    #[error("Zerostate {0} is unavailable, retry later")]
+    ZeroStateUnavailable(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::test_helpers::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+    const RETRY_AFTER: Duration = Duration::from_secs(30);
+
+    fn shard_block_id(shard: u64, seq_no: u32) -> ton_block::BlockIdExt {
+        block_id(
+            ton_block::ShardIdent::with_tagged_prefix(0, shard).unwrap(),
+            seq_no,
+        )
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shard_appears_with_delayed_zerostate() {
+        // Top shard blocks of the masterchain block N: the old shard
+        // and the new one which still has only its zerostate
+        let shard_blocks = [
+            shard_block_id(0x8000000000000000, 100),
+            shard_block_id(0x4000000000000000, 0),
+        ];
+
+        let zerostates = NewShardZeroStates::new(TIMEOUT, RETRY_AFTER);
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let download = |delay: Duration| {
+            let attempts = attempts.clone();
+            move |id: ton_block::BlockIdExt| {
+                assert_eq!(id.seq_no, 0);
+                attempts.fetch_add(1, Ordering::AcqRel);
+                async move {
+                    tokio::time::sleep(delay).await;
+                    Ok::<_, anyhow::Error>(())
+                }
+            }
+        };
+
+        // Peers don't serve the zerostate yet
+        let started_at = tokio::time::Instant::now();
+        zerostates
+            .fetch(&shard_blocks, download(TIMEOUT * 2))
+            .await
+            .unwrap_err();
+        assert!(started_at.elapsed() < TIMEOUT * 2);
+        assert_eq!(attempts.load(Ordering::Acquire), 1);
+
+        // Next archive with the same shard fails immediately
+        zerostates
+            .fetch(&shard_blocks, download(Duration::ZERO))
+            .await
+            .unwrap_err();
+        assert_eq!(attempts.load(Ordering::Acquire), 1);
+
+        // Zerostate becomes available after a while
+        tokio::time::advance(RETRY_AFTER).await;
+        zerostates
+            .fetch(&shard_blocks, download(TIMEOUT / 2))
+            .await
+            .unwrap();
+        assert_eq!(attempts.load(Ordering::Acquire), 2);
+
+        // Blocks without new shards don't require downloads
+        zerostates
+            .fetch(&shard_blocks[..1], download(TIMEOUT * 2))
+            .await
+            .unwrap();
+        assert_eq!(attempts.load(Ordering::Acquire), 2);
+    }
+}
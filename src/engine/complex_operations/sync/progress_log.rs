@@ -0,0 +1,177 @@
+use std::time::{Duration, Instant};
+
+use crate::config::SyncProgressLog;
+use crate::utils::*;
+
+/// Aggregates stats of imported archives to log them
+/// according to the configured sampling.
+///
+/// NOTE: only the high-frequency progress messages are sampled,
+/// errors and milestones are always logged. The stats which were not
+/// logged yet are flushed by [`SyncProgressLogger::finish`]
+pub struct SyncProgressLogger {
+    mode: SyncProgressLog,
+    progress: SyncProgress,
+    last_mc_block_id: Option<ton_block::BlockIdExt>,
+    last_logged_at: Instant,
+}
+
+impl SyncProgressLogger {
+    pub fn new(mode: SyncProgressLog) -> Self {
+        Self::with_start_time(mode, Instant::now())
+    }
+
+    fn with_start_time(mode: SyncProgressLog, now: Instant) -> Self {
+        Self {
+            mode,
+            progress: Default::default(),
+            last_mc_block_id: None,
+            last_logged_at: now,
+        }
+    }
+
+    /// Records the imported archive and logs the progress if needed
+    pub fn record(
+        &mut self,
+        last_mc_block_id: &ton_block::BlockIdExt,
+        mc_blocks: usize,
+        elapsed: Duration,
+    ) {
+        self.last_mc_block_id = Some(last_mc_block_id.clone());
+        if let Some(progress) = self.record_at(Instant::now(), mc_blocks, elapsed) {
+            log_progress(last_mc_block_id, &progress);
+        }
+    }
+
+    /// Logs the stats of archives which were imported since the last message
+    pub fn finish(mut self) {
+        if let (Some(progress), Some(last_mc_block_id)) =
+            (self.take_pending(), &self.last_mc_block_id)
+        {
+            log_progress(last_mc_block_id, &progress);
+        }
+    }
+
+    fn take_pending(&mut self) -> Option<SyncProgress> {
+        if self.progress.archives > 0 {
+            Some(std::mem::take(&mut self.progress))
+        } else {
+            None
+        }
+    }
+
+    fn record_at(
+        &mut self,
+        now: Instant,
+        mc_blocks: usize,
+        elapsed: Duration,
+    ) -> Option<SyncProgress> {
+        self.progress.archives += 1;
+        self.progress.mc_blocks += mc_blocks;
+        self.progress.elapsed += elapsed;
+
+        let ready = match self.mode {
+            SyncProgressLog::EachArchive => true,
+            SyncProgressLog::EveryNthArchive { n } => self.progress.archives >= n as usize,
+            SyncProgressLog::Summary { interval_sec } => {
+                now.duration_since(self.last_logged_at) >= Duration::from_secs(interval_sec)
+            }
+        };
+
+        if ready {
+            self.last_logged_at = now;
+            Some(std::mem::take(&mut self.progress))
+        } else {
+            None
+        }
+    }
+}
+
+fn log_progress(last_mc_block_id: &ton_block::BlockIdExt, progress: &SyncProgress) {
+    tracing::info!(
+        target: "sync",
+        last_mc_block_id = %last_mc_block_id.display(),
+        archives = progress.archives,
+        mc_blocks = progress.mc_blocks,
+        elapsed_ms = progress.elapsed.as_millis(),
+        "imported archive packages"
+    );
+}
+
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+struct SyncProgress {
+    archives: usize,
+    mc_blocks: usize,
+    /// Total time spent on importing
+    elapsed: Duration,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ELAPSED: Duration = Duration::from_millis(100);
+
+    #[test]
+    fn sampling_modes() {
+        let start = Instant::now();
+
+        // Each archive
+        let mut logger = SyncProgressLogger::with_start_time(SyncProgressLog::EachArchive, start);
+        for _ in 0..3 {
+            assert!(logger.record_at(start, 10, ELAPSED).is_some());
+        }
+
+        // Every Nth archive
+        let mut logger =
+            SyncProgressLogger::with_start_time(SyncProgressLog::EveryNthArchive { n: 3 }, start);
+        assert_eq!(logger.record_at(start, 10, ELAPSED), None);
+        assert_eq!(logger.record_at(start, 10, ELAPSED), None);
+        assert_eq!(
+            logger.record_at(start, 10, ELAPSED),
+            Some(SyncProgress {
+                archives: 3,
+                mc_blocks: 30,
+                elapsed: ELAPSED * 3,
+            })
+        );
+        assert_eq!(logger.record_at(start, 10, ELAPSED), None);
+
+        // Summary per interval
+        let mut logger = SyncProgressLogger::with_start_time(
+            SyncProgressLog::Summary { interval_sec: 60 },
+            start,
+        );
+        assert_eq!(
+            logger.record_at(start + Duration::from_secs(20), 5, ELAPSED),
+            None
+        );
+        assert_eq!(
+            logger.record_at(start + Duration::from_secs(40), 5, ELAPSED),
+            None
+        );
+        assert_eq!(
+            logger.record_at(start + Duration::from_secs(60), 5, ELAPSED),
+            Some(SyncProgress {
+                archives: 3,
+                mc_blocks: 15,
+                elapsed: ELAPSED * 3,
+            })
+        );
+        assert_eq!(
+            logger.record_at(start + Duration::from_secs(100), 5, ELAPSED),
+            None
+        );
+
+        // The last partial summary is flushed
+        assert_eq!(
+            logger.take_pending(),
+            Some(SyncProgress {
+                archives: 1,
+                mc_blocks: 5,
+                elapsed: ELAPSED,
+            })
+        );
+        assert_eq!(logger.take_pending(), None);
+    }
+}
@@ -0,0 +1,222 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+
+use crate::config::WarmupOptions;
+use crate::db::*;
+use crate::engine::Engine;
+
+/// Preloads recently used key block proofs, block handles, per-shard block lookup
+/// entries and top shard states so that the first blocks after boot are not applied
+/// on a cold DB.
+///
+/// The latency of the same lookup query is measured before and after the warm-up.
+///
+/// NOTE: handles are kept alive for [`WARM_HANDLES_TTL`] to stay in the handles cache
+pub async fn warm_up_caches(engine: &Arc<Engine>, options: WarmupOptions) -> Result<()> {
+    let started_at = Instant::now();
+    let mut stats = WarmupStats::default();
+
+    let first_query = measure_lookup_query(engine);
+    let result = warm_up_caches_impl(engine, &options, &mut stats).await;
+    let warm_query = measure_lookup_query(engine);
+
+    tracing::info!(
+        key_blocks = stats.key_blocks,
+        failed_key_blocks = stats.failed_key_blocks,
+        handles = stats.handles,
+        lookup_entries = stats.lookup_entries,
+        prefetched_cells = stats.prefetched_cells,
+        first_query_us = first_query.map(|elapsed| elapsed.as_micros() as u64),
+        warm_query_us = warm_query.map(|elapsed| elapsed.as_micros() as u64),
+        interrupted = result.is_err() || !engine.is_working(),
+        elapsed_ms = started_at.elapsed().as_millis(),
+        "warmed up caches"
+    );
+
+    // Release handles after a while
+    let engine = Arc::downgrade(engine);
+    tokio::spawn(async move {
+        tokio::time::sleep(WARM_HANDLES_TTL).await;
+        if let Some(engine) = engine.upgrade() {
+            engine.warm_handles.lock().clear();
+        }
+    });
+
+    result
+}
+
+async fn warm_up_caches_impl(
+    engine: &Arc<Engine>,
+    options: &WarmupOptions,
+    stats: &mut WarmupStats,
+) -> Result<()> {
+    let block_handle_storage = engine.db.block_handle_storage();
+    let block_connection_storage = engine.db.block_connection_storage();
+    let shard_state_storage = engine.db.shard_state_storage();
+
+    // Key blocks with proofs, which are used to check next block proofs
+    let key_block_ids = block_handle_storage
+        .key_blocks_iterator(KeyBlocksDirection::Backward)
+        .take(options.key_blocks)
+        .collect::<Result<Vec<_>>>()?;
+
+    // NOTE: oldest key blocks are inserted first to keep the latest ones in the cache
+    for block_id in key_block_ids.into_iter().rev() {
+        if !engine.is_working() {
+            return Ok(());
+        }
+
+        let handle = match block_handle_storage.load_handle(&block_id)? {
+            Some(handle) if block_id.seq_no > 0 => handle,
+            _ => continue,
+        };
+
+        // NOTE: missing proof only makes the first check slower, so it is not fatal
+        let proof = match engine.load_best_block_proof(&handle).await {
+            Ok(proof) => proof,
+            Err(e) => {
+                tracing::warn!(
+                    block_id = %block_id.display(),
+                    "failed to load key block proof: {e:?}"
+                );
+                stats.failed_key_blocks += 1;
+                continue;
+            }
+        };
+        engine
+            .key_block_proofs_cache
+            .insert(block_id, Arc::new(proof));
+        engine.warm_handles.lock().push(handle);
+        stats.key_blocks += 1;
+    }
+
+    // Last handles and states of the current shards
+    let last_mc_block_id = engine.load_shards_client_mc_block_id()?;
+    let last_mc_handle = block_handle_storage
+        .load_handle(&last_mc_block_id)?
+        .context("Shards client masterchain block handle not found")?;
+    let last_mc_block = engine
+        .db
+        .block_storage()
+        .load_block_data(&last_mc_handle)
+        .await?;
+
+    for (_, top_block_id) in last_mc_block.shard_blocks()? {
+        if !engine.is_working() {
+            return Ok(());
+        }
+
+        match engine
+            .prefetch_state(&top_block_id, options.state_depth)
+            .await
+        {
+            Ok(cells) => stats.prefetched_cells += cells,
+            Err(e) => tracing::debug!(
+                block_id = %top_block_id.display(),
+                "failed to prefetch top shard state: {e:?}"
+            ),
+        }
+
+        let mut block_id = top_block_id;
+        for _ in 0..options.shard_blocks {
+            let handle = match block_handle_storage.load_handle(&block_id)? {
+                Some(handle) => handle,
+                None => break,
+            };
+            engine.warm_handles.lock().push(handle);
+            stats.handles += 1;
+
+            // Entry of the (shard, seqno) lookup which is used to find applied
+            // blocks by seqno (e.g. by the block waiters)
+            if shard_state_storage
+                .find_block_id(&block_id.shard_id, block_id.seq_no)?
+                .is_some()
+            {
+                stats.lookup_entries += 1;
+            }
+
+            block_id =
+                match block_connection_storage.load_connection(&block_id, BlockConnection::Prev1) {
+                    Ok(prev_id) => prev_id,
+                    Err(_) => break,
+                };
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the latency of the lookups of the last masterchain block
+/// by seqno and of its previous block (or `None` if they failed)
+fn measure_lookup_query(engine: &Engine) -> Option<Duration> {
+    let started_at = Instant::now();
+    let block_id = engine.load_shards_client_mc_block_id().ok()?;
+    engine
+        .db
+        .shard_state_storage()
+        .find_block_id(&block_id.shard_id, block_id.seq_no)
+        .ok()?;
+    engine
+        .db
+        .block_connection_storage()
+        .load_connection(&block_id, BlockConnection::Prev1)
+        .ok()?;
+    Some(started_at.elapsed())
+}
+
+#[derive(Default)]
+struct WarmupStats {
+    key_blocks: usize,
+    failed_key_blocks: usize,
+    handles: usize,
+    lookup_entries: usize,
+    prefetched_cells: usize,
+}
+
+/// How long preloaded handles are kept alive
+const WARM_HANDLES_TTL: Duration = Duration::from_secs(60);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::*;
+    use crate::test_util::SyntheticChain;
+    use crate::utils::*;
+
+    #[tokio::test]
+    async fn missing_key_block_proof_is_skipped() {
+        let now = broxus_util::now();
+        let chain = SyntheticChain::generate(4, now - 100, |seq_no| now - 100 + seq_no).unwrap();
+
+        let dir = TempDir::new("warmup_missing_proof");
+        chain.create_db(dir.path()).await.unwrap();
+        let engine = test_engine_with_global_config(&dir, chain.global_config(), Vec::new()).await;
+
+        // Key block without the stored proof
+        let (id, data) = make_key_block_with_config(1000, 0, Default::default());
+        let block = BlockStuff::deserialize_checked(id, &data).unwrap();
+        let meta_data = BlockMetaData {
+            is_key_block: true,
+            gen_utime: now,
+            mc_ref_seqno: Some(1000),
+        };
+        engine
+            .db
+            .block_storage()
+            .store_block_data(&BlockStuffAug::new(block, data), meta_data)
+            .await
+            .unwrap();
+
+        let mut stats = WarmupStats::default();
+        warm_up_caches_impl(&engine, &Default::default(), &mut stats)
+            .await
+            .unwrap();
+
+        // Other caches are still warmed up
+        assert_eq!(stats.failed_key_blocks, 1);
+        assert!(stats.handles > 0);
+        assert!(measure_lookup_query(&engine).is_some());
+    }
+}
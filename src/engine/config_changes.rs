@@ -0,0 +1,211 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result};
+
+use super::Engine;
+use crate::db::BlockHandle;
+use crate::utils::{BlockStuff, ShardStateStuff};
+
+impl Engine {
+    /// Loads config params of the key block from its stored state
+    /// or from the key block itself if the state is not stored
+    pub(super) async fn load_key_block_config(
+        &self,
+        handle: &BlockHandle,
+    ) -> Result<ton_block::ConfigParams> {
+        let seqno = handle.id().seq_no;
+        if handle.meta().has_state() {
+            let state = self.load_state(handle.id()).await?;
+            return Ok(state.config_params()?.clone());
+        }
+
+        let block = self.db.block_storage().load_block_data(handle).await?;
+        block_config(block.block(), seqno)
+    }
+
+    /// Notifies subscribers about config params which differ from the previous key block.
+    ///
+    /// NOTE: must be called after the key block is committed. Errors are only logged,
+    /// so that a failed notification doesn't interrupt block processing
+    pub(super) async fn notify_config_changes(
+        &self,
+        handle: &BlockHandle,
+        block: &BlockStuff,
+        shard_state: Option<&ShardStateStuff>,
+    ) {
+        if let Err(e) = self
+            .notify_config_changes_impl(handle, block, shard_state)
+            .await
+        {
+            tracing::error!(
+                seqno = handle.id().seq_no,
+                "failed to notify config changes: {e:?}"
+            );
+        }
+    }
+
+    async fn notify_config_changes_impl(
+        &self,
+        handle: &BlockHandle,
+        block: &BlockStuff,
+        shard_state: Option<&ShardStateStuff>,
+    ) -> Result<()> {
+        let seqno = handle.id().seq_no;
+        let prev_seqno = block.block().read_info()?.prev_key_block_seqno();
+
+        let config = match shard_state {
+            Some(state) => state.config_params()?.clone(),
+            None => block_config(block.block(), seqno)?,
+        };
+        let config = &config;
+
+        let cached = self.last_key_block_config.lock().clone();
+        let prev_config = match cached {
+            Some((cached_seqno, config)) if cached_seqno == prev_seqno => Some(config),
+            _ => match self.load_prev_key_block_config(prev_seqno).await {
+                Ok(config) => Some(config),
+                Err(e) => {
+                    tracing::warn!(
+                        seqno,
+                        prev_seqno,
+                        "failed to load previous key block config, skipping config diff: {e:?}"
+                    );
+                    None
+                }
+            },
+        };
+        *self.last_key_block_config.lock() = Some((seqno, config.clone()));
+
+        let prev_config = match prev_config {
+            Some(config) => config,
+            None => return Ok(()),
+        };
+
+        for index in changed_config_params(&prev_config, config)? {
+            let old = prev_config
+                .config(index)
+                .with_context(|| format!("Failed to parse old config param {index}"))?;
+            let new = config
+                .config(index)
+                .with_context(|| format!("Failed to parse new config param {index}"))?;
+
+            tracing::debug!(seqno, index, "config param changed");
+            for entry in &self.subscribers {
+                let result = entry
+                    .call(|subscriber| {
+                        subscriber.on_config_param_changed(index, old.as_ref(), new.as_ref())
+                    })
+                    .await;
+                if let Err(e) = result {
+                    tracing::error!(seqno, index, "failed to handle config param change: {e:?}");
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn load_prev_key_block_config(&self, seqno: u32) -> Result<ton_block::ConfigParams> {
+        let handle = self
+            .db
+            .block_handle_storage()
+            .load_key_block_handle(seqno)?;
+        self.load_key_block_config(&handle).await
+    }
+}
+
+/// Reads config params from the extra of the masterchain key block
+fn block_config(block: &ton_block::Block, seqno: u32) -> Result<ton_block::ConfigParams> {
+    let extra = block
+        .read_extra()?
+        .read_custom()?
+        .ok_or(ConfigChangesError::ConfigNotFound(seqno))?;
+    extra
+        .config()
+        .cloned()
+        .ok_or_else(|| ConfigChangesError::ConfigNotFound(seqno).into())
+}
+
+/// Returns indices of the config params which were added, removed or changed
+fn changed_config_params(
+    old: &ton_block::ConfigParams,
+    new: &ton_block::ConfigParams,
+) -> Result<Vec<u32>> {
+    let old = collect_config_params(old)?;
+    let mut new = collect_config_params(new)?;
+
+    let mut changed = Vec::new();
+    for (index, old_hash) in old {
+        match new.remove(&index) {
+            Some(new_hash) if new_hash == old_hash => {}
+            _ => changed.push(index),
+        }
+    }
+    changed.extend(new.into_keys());
+    changed.sort_unstable();
+
+    Ok(changed)
+}
+
+/// Returns hashes of the config params by their indices
+fn collect_config_params(
+    config: &ton_block::ConfigParams,
+) -> Result<BTreeMap<u32, ton_types::UInt256>> {
+    let mut params = BTreeMap::new();
+    config.config_params.iterate_slices(|mut key, value| {
+        let index = key.get_next_u32()?;
+        // NOTE: params are stored as references
+        params.insert(index, value.reference(0)?.repr_hash());
+        Ok(true)
+    })?;
+    Ok(params)
+}
+
+#[derive(thiserror::Error, Debug)]
+enum ConfigChangesError {
+    #[error("Config not found in key block {0}")]
+    ConfigNotFound(u32),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_config<I>(params: I) -> ton_block::ConfigParams
+    where
+        I: IntoIterator<Item = ton_block::ConfigParamEnum>,
+    {
+        let mut config = ton_block::ConfigParams::default();
+        for param in params {
+            config.set_config(param).unwrap();
+        }
+        config
+    }
+
+    fn elector(addr: u8) -> ton_block::ConfigParamEnum {
+        ton_block::ConfigParamEnum::ConfigParam1(ton_block::ConfigParam1 {
+            elector_addr: ton_types::UInt256::from([addr; 32]),
+        })
+    }
+
+    fn minter(addr: u8) -> ton_block::ConfigParamEnum {
+        ton_block::ConfigParamEnum::ConfigParam2(ton_block::ConfigParam2 {
+            minter_addr: ton_types::UInt256::from([addr; 32]),
+        })
+    }
+
+    #[test]
+    fn only_changed_params_are_reported() {
+        let old = make_config([elector(1), minter(1)]);
+
+        assert!(changed_config_params(&old, &old).unwrap().is_empty());
+
+        let new = make_config([elector(1), minter(2)]);
+        assert_eq!(changed_config_params(&old, &new).unwrap(), [2]);
+
+        // Added and removed params
+        let new = make_config([minter(1)]);
+        assert_eq!(changed_config_params(&old, &new).unwrap(), [1]);
+        assert_eq!(changed_config_params(&new, &old).unwrap(), [1]);
+    }
+}
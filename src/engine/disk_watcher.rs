@@ -0,0 +1,210 @@
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::sync::watch;
+
+use crate::config::DiskWatermarksOptions;
+
+/// Available space level of the DB volume
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum DiskSpaceLevel {
+    Normal,
+    /// Below the soft threshold: GC is triggered and historical sync is paused
+    Low,
+    /// Below the hard threshold: all downloads and block application are paused
+    Critical,
+}
+
+impl DiskSpaceLevel {
+    fn compute(available: u64, options: &DiskWatermarksOptions) -> Self {
+        if available < options.hard_threshold {
+            Self::Critical
+        } else if available < options.soft_threshold {
+            Self::Low
+        } else {
+            Self::Normal
+        }
+    }
+}
+
+/// Tracks available space of the DB volumes against the configured thresholds
+pub struct DiskWatcher {
+    paths: Vec<PathBuf>,
+    options: DiskWatermarksOptions,
+    level_tx: watch::Sender<DiskSpaceLevel>,
+    level_rx: watch::Receiver<DiskSpaceLevel>,
+}
+
+impl DiskWatcher {
+    /// Creates a watcher for the volumes of all specified paths
+    /// (e.g. RocksDB and file DB directories)
+    pub fn new<I, P>(paths: I, options: DiskWatermarksOptions) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        let mut paths = paths
+            .into_iter()
+            .map(|path| path.as_ref().to_path_buf())
+            .collect::<Vec<_>>();
+        paths.dedup();
+
+        let (level_tx, level_rx) = watch::channel(DiskSpaceLevel::Normal);
+        Self {
+            paths,
+            options,
+            level_tx,
+            level_rx,
+        }
+    }
+
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    pub fn options(&self) -> &DiskWatermarksOptions {
+        &self.options
+    }
+
+    pub fn check_interval(&self) -> Duration {
+        Duration::from_secs(self.options.check_interval_sec)
+    }
+
+    pub fn level(&self) -> DiskSpaceLevel {
+        *self.level_rx.borrow()
+    }
+
+    /// Updates the level with the measured available space.
+    ///
+    /// Returns the new level if it was changed
+    pub fn update(&self, available: u64) -> Option<DiskSpaceLevel> {
+        let level = DiskSpaceLevel::compute(available, &self.options);
+        let prev = self.level_tx.send_replace(level);
+        (prev != level).then(|| level)
+    }
+
+    /// Waits until the level is lower than `pause_at`
+    pub async fn wait_below(&self, pause_at: DiskSpaceLevel) {
+        let mut level_rx = self.level_rx.clone();
+        while *level_rx.borrow_and_update() >= pause_at {
+            if level_rx.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Returns the path with the least space available to unprivileged users
+    /// on its volume, and this space
+    pub fn available_space(&self) -> Result<(&Path, u64)> {
+        let mut result = None;
+        for path in &self.paths {
+            let available = available_space(path)?;
+            if !matches!(result, Some((_, min)) if min <= available) {
+                result = Some((path.as_path(), available));
+            }
+        }
+        result.ok_or_else(|| DiskWatcherError::NoPaths.into())
+    }
+}
+
+fn available_space(path: &Path) -> Result<u64> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: path is a valid C string and stat is a valid pointer
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    // SAFETY: stat is initialized by the successful call
+    let stat = unsafe { stat.assume_init() };
+
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[derive(thiserror::Error, Debug)]
+enum DiskWatcherError {
+    #[error("No paths to watch")]
+    NoPaths,
+}
+
+/// Reason why the node stopped processing blocks
+#[derive(Debug, Clone)]
+pub struct StallReport {
+    pub reason: StallReason,
+}
+
+#[derive(Debug, Clone)]
+pub enum StallReason {
+    /// Available space on one of the DB volumes is below the hard threshold
+    LowDiskSpace {
+        path: PathBuf,
+        available: u64,
+        hard_threshold: u64,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    const GB: u64 = 1 << 30;
+
+    #[tokio::test]
+    async fn disk_space_levels() {
+        let watcher = Arc::new(DiskWatcher::new(
+            ["db"],
+            DiskWatermarksOptions {
+                check_interval_sec: 1,
+                soft_threshold: 20 * GB,
+                hard_threshold: 5 * GB,
+            },
+        ));
+        assert_eq!(watcher.level(), DiskSpaceLevel::Normal);
+
+        // Nothing changes while there is enough space
+        assert_eq!(watcher.update(100 * GB), None);
+        watcher.wait_below(DiskSpaceLevel::Low).await;
+
+        // Soft threshold pauses only background tasks
+        assert_eq!(watcher.update(10 * GB), Some(DiskSpaceLevel::Low));
+        assert_eq!(watcher.update(9 * GB), None);
+        watcher.wait_below(DiskSpaceLevel::Critical).await;
+
+        // Hard threshold pauses everything
+        assert_eq!(watcher.update(GB), Some(DiskSpaceLevel::Critical));
+
+        let paused = {
+            let watcher = watcher.clone();
+            tokio::spawn(async move { watcher.wait_below(DiskSpaceLevel::Critical).await })
+        };
+        let paused_background = {
+            let watcher = watcher.clone();
+            tokio::spawn(async move { watcher.wait_below(DiskSpaceLevel::Low).await })
+        };
+        tokio::task::yield_now().await;
+        assert!(!paused.is_finished());
+
+        // GC freed some space
+        assert_eq!(watcher.update(6 * GB), Some(DiskSpaceLevel::Low));
+        paused.await.unwrap();
+        assert!(!paused_background.is_finished());
+
+        // Space is freed
+        assert_eq!(watcher.update(30 * GB), Some(DiskSpaceLevel::Normal));
+        paused_background.await.unwrap();
+    }
+
+    #[test]
+    fn measure_available_space() {
+        let dir = std::env::temp_dir();
+        let watcher = DiskWatcher::new([Path::new("."), &dir], Default::default());
+        let (path, available) = watcher.available_space().unwrap();
+        assert!(available > 0);
+        assert!(watcher.paths().iter().any(|item| item == path));
+    }
+}
@@ -19,23 +19,20 @@ impl<'a, T> DownloadContext<'a, T> {
         block_id: &ton_block::BlockIdExt,
     ) -> Result<Option<(BlockStuffAug, BlockProofStuffAug)>> {
         match self.db.block_handle_storage().load_handle(block_id)? {
-            Some(handle) => {
-                let mut is_link = false;
-                if handle.meta().has_data() && handle.has_proof_or_link(&mut is_link) {
-                    let block = self.db.block_storage().load_block_data(&handle).await?;
-                    let block_proof = self
-                        .db
-                        .block_storage()
-                        .load_block_proof(&handle, is_link)
-                        .await?;
-                    Ok(Some((
-                        BlockStuffAug::loaded(block),
-                        BlockProofStuffAug::loaded(block_proof),
-                    )))
-                } else {
-                    Ok(None)
+            Some(handle) if handle.meta().has_data() => {
+                let block_storage = self.db.block_storage();
+                match block_storage.load_best_block_proof(&handle).await? {
+                    Some(block_proof) => {
+                        let block = block_storage.load_block_data(&handle).await?;
+                        Ok(Some((
+                            BlockStuffAug::loaded(block),
+                            BlockProofStuffAug::loaded(block_proof),
+                        )))
+                    }
+                    None => Ok(None),
                 }
             }
+            Some(_) => Ok(None),
             None => Ok(None),
         }
     }
@@ -51,11 +48,17 @@ impl Downloader for BlockDownloader {
         &self,
         context: &DownloadContext<'_, Self::Item>,
     ) -> Result<Option<Self::Item>> {
-        if let Some(full_block) = context.load_full_block(context.block_id).await? {
-            return Ok(Some(full_block));
+        // NOTE: the pinned neighbour is always queried, even if the block is stored
+        if !context.pin_neighbour {
+            if let Some(full_block) = context.load_full_block(context.block_id).await? {
+                return Ok(Some(full_block));
+            }
         }
 
-        context.client.download_block_full(context.block_id).await
+        context
+            .client
+            .download_block_full(context.block_id, context.explicit_neighbour)
+            .await
     }
 }
 
@@ -76,13 +79,12 @@ impl Downloader for BlockProofDownloader {
             .block_handle_storage()
             .load_handle(context.block_id)?
         {
-            let mut is_link = false;
-            if handle.has_proof_or_link(&mut is_link) {
-                let proof = context
-                    .db
-                    .block_storage()
-                    .load_block_proof(&handle, is_link)
-                    .await?;
+            if let Some(proof) = context
+                .db
+                .block_storage()
+                .load_best_block_proof(&handle)
+                .await?
+            {
                 return Ok(Some(Self::Item::loaded(proof)));
             }
         }
@@ -167,6 +169,8 @@ pub struct DownloadContext<'a, T> {
 
     pub downloader: Arc<dyn Downloader<Item = T>>,
     pub explicit_neighbour: Option<&'a Arc<Neighbour>>,
+    /// Whether to keep querying the explicit neighbour after errors
+    pub pin_neighbour: bool,
 }
 
 impl<'a, T> DownloadContext<'a, T> {
@@ -178,6 +182,18 @@ impl<'a, T> DownloadContext<'a, T> {
         self
     }
 
+    /// Sends all queries only to the specified neighbour of the client overlay
+    pub fn with_pinned_neighbour(
+        mut self,
+        client: &'a NodeRpcClient,
+        neighbour: &'a Arc<Neighbour>,
+    ) -> Self {
+        self.client = client;
+        self.explicit_neighbour = Some(neighbour);
+        self.pin_neighbour = true;
+        self
+    }
+
     pub async fn download(&mut self) -> Result<T> {
         let mut attempt = 1;
         loop {
@@ -185,7 +201,9 @@ impl<'a, T> DownloadContext<'a, T> {
                 Ok(Some(result)) => break Ok(result),
                 Ok(None) => tracing::debug!("got no data for {}", self.name),
                 Err(e) => {
-                    self.explicit_neighbour = None;
+                    if !self.pin_neighbour {
+                        self.explicit_neighbour = None;
+                    }
                     tracing::debug!("error in {}: {e:?}", self.name)
                 }
             }
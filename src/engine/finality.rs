@@ -0,0 +1,134 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use super::Engine;
+use crate::db::BriefBlockMeta;
+
+impl Engine {
+    /// Returns the number of applied masterchain blocks built on top of
+    /// the masterchain block which committed the specified block.
+    ///
+    /// Unknown blocks and shard blocks which are not applied yet
+    /// are reported as not committed
+    pub fn finality_of(&self, block_id: &ton_block::BlockIdExt) -> Result<Finality> {
+        // NOTE: load head first so that the block is never committed after it
+        let mc_head = self.load_last_applied_mc_block_id()?.seq_no;
+        let meta = self.db.block_handle_storage().load_meta(block_id)?;
+        Ok(compute_finality(block_id, meta.as_ref(), mc_head))
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Finality {
+    /// Seqno of the applied masterchain block which committed the block
+    pub committed_in_mc: Option<u32>,
+    /// Seqno of the last applied masterchain block
+    pub mc_head: u32,
+    /// Number of masterchain blocks after the committing one
+    pub depth: Option<u32>,
+}
+
+impl Finality {
+    /// Whether the block is buried under at least `depth` masterchain blocks
+    pub fn is_final(&self, depth: u32) -> bool {
+        matches!(self.depth, Some(current) if current >= depth)
+    }
+}
+
+fn compute_finality(
+    block_id: &ton_block::BlockIdExt,
+    meta: Option<&BriefBlockMeta>,
+    mc_head: u32,
+) -> Finality {
+    let committed_in_mc = match meta {
+        Some(meta) if meta.is_applied() => {
+            let mc_seqno = if block_id.shard_id.is_masterchain() {
+                block_id.seq_no
+            } else {
+                meta.masterchain_ref_seqno()
+            };
+            // Legacy handles could have no masterchain ref
+            (mc_seqno != 0 && mc_seqno <= mc_head).then(|| mc_seqno)
+        }
+        _ => None,
+    };
+
+    Finality {
+        committed_in_mc,
+        mc_head,
+        depth: committed_in_mc.map(|mc_seqno| mc_head - mc_seqno),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::BlockMeta;
+    use crate::test_helpers::*;
+    use crate::utils::BriefBlockInfo;
+
+    fn new_meta(mc_seq_no: u32) -> BlockMeta {
+        BlockMeta::with_data(
+            BriefBlockInfo {
+                is_key_block: false,
+                gen_utime: 0,
+                after_split: false,
+                proof_key_block_seqno: None,
+            }
+            .with_mc_seq_no(mc_seq_no),
+        )
+    }
+
+    #[test]
+    fn shard_block_commit() {
+        let id = block_id(ton_block::ShardIdent::full(0), 100);
+
+        // Unknown block
+        let finality = compute_finality(&id, None, 10);
+        assert_eq!(finality.committed_in_mc, None);
+        assert_eq!(finality.depth, None);
+
+        // Stored, but not committed yet
+        let meta = new_meta(0);
+        let finality = compute_finality(&id, Some(&meta.brief()), 10);
+        assert_eq!(finality.committed_in_mc, None);
+        assert!(!finality.is_final(0));
+
+        // Committed by the next masterchain block
+        meta.set_masterchain_ref_seqno(11).unwrap();
+        meta.set_is_applied();
+        let finality = compute_finality(&id, Some(&meta.brief()), 11);
+        assert_eq!(
+            finality,
+            Finality {
+                committed_in_mc: Some(11),
+                mc_head: 11,
+                depth: Some(0),
+            }
+        );
+        assert!(finality.is_final(0));
+        assert!(!finality.is_final(1));
+
+        // Buried under new masterchain blocks
+        let finality = compute_finality(&id, Some(&meta.brief()), 15);
+        assert_eq!(finality.depth, Some(4));
+        assert!(finality.is_final(4));
+    }
+
+    #[test]
+    fn masterchain_block() {
+        let id = mc_block_id(20);
+
+        let meta = new_meta(20);
+        assert_eq!(
+            compute_finality(&id, Some(&meta.brief()), 20).committed_in_mc,
+            None
+        );
+
+        meta.set_is_applied();
+        assert_eq!(
+            compute_finality(&id, Some(&meta.brief()), 25).depth,
+            Some(5)
+        );
+    }
+}
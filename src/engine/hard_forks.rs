@@ -0,0 +1,228 @@
+use std::sync::atomic::Ordering;
+
+use anyhow::Result;
+use parking_lot::Mutex;
+use rustc_hash::FxHashSet;
+
+use super::Engine;
+use crate::db::{AuditLogEventKind, NodeStateStorage};
+use crate::utils::*;
+
+/// Hard fork blocks from the global config
+pub struct HardForks {
+    configured: Vec<ton_block::BlockIdExt>,
+    /// Hard fork blocks which were already reported (persisted across restarts)
+    accepted: Mutex<FxHashSet<ton_block::BlockIdExt>>,
+}
+
+impl HardForks {
+    /// Ensures that all configured hard fork blocks are plausible
+    pub fn new(configured: Vec<ton_block::BlockIdExt>) -> Result<Self, HardForksError> {
+        let mut prev_seqno = None;
+        for id in &configured {
+            if !id.shard_id.is_masterchain() {
+                return Err(HardForksError::NonMasterchainBlock(
+                    id.display().to_string(),
+                ));
+            }
+            if id.seq_no == 0 {
+                return Err(HardForksError::ZeroState);
+            }
+            if matches!(prev_seqno, Some(prev) if prev >= id.seq_no) {
+                return Err(HardForksError::InvalidOrder(id.display().to_string()));
+            }
+            prev_seqno = Some(id.seq_no);
+        }
+
+        Ok(Self {
+            configured,
+            accepted: Default::default(),
+        })
+    }
+
+    /// Restores hard fork blocks which were reported before the restart
+    pub fn with_accepted(self, accepted: Vec<ton_block::BlockIdExt>) -> Self {
+        self.accepted.lock().extend(accepted);
+        self
+    }
+
+    pub fn contains(&self, block_id: &ton_block::BlockIdExt) -> bool {
+        self.position(block_id).is_some()
+    }
+
+    /// Index of the hard fork in the global config
+    fn position(&self, block_id: &ton_block::BlockIdExt) -> Option<usize> {
+        self.configured.iter().position(|id| id == block_id)
+    }
+
+    fn is_accepted(&self, block_id: &ton_block::BlockIdExt) -> bool {
+        self.accepted.lock().contains(block_id)
+    }
+
+    /// Returns `true` only for the first acceptance of the hard fork block.
+    ///
+    /// NOTE: the accepted set is stored under the lock, so concurrent
+    /// acceptances are never lost
+    fn mark_accepted(
+        &self,
+        block_id: &ton_block::BlockIdExt,
+        node_state: &NodeStateStorage,
+    ) -> Result<bool> {
+        let mut accepted = self.accepted.lock();
+        if accepted.contains(block_id) {
+            return Ok(false);
+        }
+
+        let mut ids = accepted.iter().collect::<Vec<_>>();
+        ids.push(block_id);
+        ids.sort_by_key(|id| id.seq_no);
+        node_state.store_accepted_hard_forks(ids)?;
+
+        accepted.insert(block_id.clone());
+        Ok(true)
+    }
+}
+
+impl Engine {
+    pub(super) fn is_hard_fork(&self, block_id: &ton_block::BlockIdExt) -> bool {
+        self.hard_forks.contains(block_id)
+    }
+
+    /// Reports the hard fork block accepted despite the failed check.
+    ///
+    /// NOTE: each hard fork block is reported only once, even after restarts
+    pub(super) fn on_hard_fork_accepted(
+        &self,
+        block_id: &ton_block::BlockIdExt,
+        failed_check: &str,
+        error: &anyhow::Error,
+    ) {
+        match self
+            .hard_forks
+            .mark_accepted(block_id, self.db.node_state())
+        {
+            Ok(true) => {}
+            Ok(false) => {
+                tracing::debug!(block_id = %block_id.display(), failed_check, "hard fork block accepted again");
+                return;
+            }
+            // NOTE: the fork is still reported, but could be reported again after restart
+            Err(e) => tracing::error!(
+                block_id = %block_id.display(),
+                "failed to store accepted hard fork: {e:?}"
+            ),
+        }
+
+        let fork_index = self.hard_forks.position(block_id);
+        tracing::warn!(
+            block_id = %block_id.display(),
+            fork_index,
+            failed_check,
+            "accepted hard fork block: {error:?}",
+        );
+        self.audit_log.record(
+            AuditLogEventKind::HardFork,
+            Some(block_id),
+            format!("{failed_check} ignored: {error}"),
+        );
+        self.metrics
+            .accepted_hard_forks
+            .fetch_add(1, Ordering::Release);
+    }
+
+    /// Warns at startup about configured hard forks within the already applied
+    /// range of masterchain blocks which were never encountered (usually a typo
+    /// in the config)
+    pub(super) fn check_hard_forks_encountered(&self) -> Result<()> {
+        let block_handle_storage = self.db.block_handle_storage();
+        let last_mc_seqno = self.load_last_applied_mc_block_id()?.seq_no;
+
+        for id in &self.hard_forks.configured {
+            if id.seq_no <= self.init_mc_block_id.seq_no || id.seq_no > last_mc_seqno {
+                continue;
+            }
+
+            // NOTE: handles of old blocks could be removed by the blocks gc
+            if self.hard_forks.is_accepted(id) || block_handle_storage.load_handle(id)?.is_some() {
+                continue;
+            }
+
+            match block_handle_storage.key_blocks_index().get(id.seq_no) {
+                Some(actual) => tracing::warn!(
+                    configured = %id.display(),
+                    actual = %actual.display(),
+                    "configured hard fork differs from the key block with the same seqno",
+                ),
+                None => tracing::warn!(
+                    configured = %id.display(),
+                    "configured hard fork was never encountered",
+                ),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum HardForksError {
+    #[error("Hard fork must be a masterchain block: {0}")]
+    NonMasterchainBlock(String),
+    #[error("Hard fork can't be a zero state")]
+    ZeroState,
+    #[error("Hard forks must be ordered by seqno without duplicates: {0}")]
+    InvalidOrder(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::*;
+
+    #[tokio::test]
+    async fn hard_fork_is_reported_once() {
+        let dir = TempDir::new("hard_fork_is_reported_once");
+        let db = test_db(&dir).await;
+        let node_state = db.node_state();
+
+        let configured = vec![mc_block_id(10), mc_block_id(20)];
+        let forks = HardForks::new(configured.clone()).unwrap();
+        assert!(forks.contains(&mc_block_id(10)));
+        assert!(!forks.contains(&mc_block_id(15)));
+
+        assert!(forks.mark_accepted(&mc_block_id(20), node_state).unwrap());
+        assert!(!forks.mark_accepted(&mc_block_id(20), node_state).unwrap());
+        assert!(forks.mark_accepted(&mc_block_id(10), node_state).unwrap());
+        assert!(!forks.mark_accepted(&mc_block_id(10), node_state).unwrap());
+
+        // Accepted forks are not reported again after restart
+        let accepted = node_state.load_accepted_hard_forks().unwrap();
+        assert_eq!(accepted, configured);
+
+        let forks = HardForks::new(configured).unwrap().with_accepted(accepted);
+        assert!(forks.is_accepted(&mc_block_id(10)));
+        assert!(!forks.mark_accepted(&mc_block_id(10), node_state).unwrap());
+        assert!(!forks.mark_accepted(&mc_block_id(20), node_state).unwrap());
+    }
+
+    #[test]
+    fn implausible_hard_forks() {
+        let shard = ton_block::ShardIdent::with_tagged_prefix(0, 0x8000_0000_0000_0000).unwrap();
+        assert!(matches!(
+            HardForks::new(vec![block_id(shard, 10)]),
+            Err(HardForksError::NonMasterchainBlock(_))
+        ));
+        assert!(matches!(
+            HardForks::new(vec![mc_block_id(0)]),
+            Err(HardForksError::ZeroState)
+        ));
+        assert!(matches!(
+            HardForks::new(vec![mc_block_id(20), mc_block_id(10)]),
+            Err(HardForksError::InvalidOrder(_))
+        ));
+        assert!(matches!(
+            HardForks::new(vec![mc_block_id(10), mc_block_id(10)]),
+            Err(HardForksError::InvalidOrder(_))
+        ));
+    }
+}
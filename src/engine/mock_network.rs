@@ -0,0 +1,420 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use everscale_network::adnl;
+use parking_lot::Mutex;
+use rustc_hash::FxHashMap;
+
+use super::{Engine, NodeRpcServer};
+use crate::network::{MockTransport, NodeNetwork, OverlayClient};
+
+/// In-process network which routes overlay queries directly to the query
+/// handlers of the connected engines.
+///
+/// Connected engines are added to the overlay neighbours of each other,
+/// so they can be used without the peers discovery.
+///
+/// NOTE: only queries are delivered, broadcasts are not supported
+#[derive(Default)]
+pub struct MockNetwork {
+    nodes: Mutex<FxHashMap<adnl::NodeIdShort, MockNode>>,
+    /// Number of sent queries by the workchain of the overlay
+    queries: Mutex<FxHashMap<i32, usize>>,
+}
+
+impl MockNetwork {
+    /// Number of queries sent through the overlay of the specified workchain
+    pub fn query_count(&self, workchain: i32) -> usize {
+        self.queries
+            .lock()
+            .get(&workchain)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    async fn query(
+        &self,
+        workchain: i32,
+        peer_id: &adnl::NodeIdShort,
+        query: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>> {
+        *self.queries.lock().entry(workchain).or_default() += 1;
+
+        let server = match self.nodes.lock().get(peer_id) {
+            Some(node) => node.server.clone(),
+            None => return Ok(None),
+        };
+        server.handle_query(&query).await
+    }
+}
+
+struct MockNode {
+    server: Arc<NodeRpcServer>,
+    /// Masterchain and basechain overlay clients
+    clients: [Arc<OverlayClient>; 2],
+}
+
+/// Transport of the overlay client of the specified workchain
+struct MockOverlay {
+    network: Arc<MockNetwork>,
+    workchain: i32,
+}
+
+#[async_trait::async_trait]
+impl MockTransport for MockOverlay {
+    async fn query(&self, peer_id: &adnl::NodeIdShort, query: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.network.query(self.workchain, peer_id, query).await
+    }
+}
+
+impl Engine {
+    /// Routes all overlay queries of the engine through the in-process network
+    /// and makes the engine reachable from it.
+    ///
+    /// Returns the peer id of the engine in the network
+    pub fn connect_mock_network(
+        self: &Arc<Self>,
+        network: &Arc<MockNetwork>,
+    ) -> Result<adnl::NodeIdShort> {
+        let peer_id = *self
+            .network
+            .adnl()
+            .key_by_tag(NodeNetwork::TAG_OVERLAY_KEY)?
+            .id();
+
+        for (workchain, client) in [
+            (ton_block::MASTERCHAIN_ID, &self.masterchain_client),
+            (ton_block::BASE_WORKCHAIN_ID, &self.basechain_client),
+        ] {
+            let transport = Arc::new(MockOverlay {
+                network: network.clone(),
+                workchain,
+            });
+            if !client.0.set_mock_transport(transport) {
+                return Err(MockNetworkError::AlreadyConnected.into());
+            }
+        }
+
+        let clients = [
+            self.masterchain_client.0.clone(),
+            self.basechain_client.0.clone(),
+        ];
+
+        let mut nodes = network.nodes.lock();
+        for (other_id, other) in nodes.iter() {
+            for (client, other_client) in clients.iter().zip(&other.clients) {
+                client.neighbours().add(*other_id);
+                other_client.neighbours().add(peer_id);
+            }
+        }
+        nodes.insert(
+            peer_id,
+            MockNode {
+                server: NodeRpcServer::new(self),
+                clients,
+            },
+        );
+        Ok(peer_id)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+enum MockNetworkError {
+    #[error("Engine is already connected to the mock network")]
+    AlreadyConnected,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::db::{BlockConnection, BlockMetaData};
+    use crate::engine::{ProcessBlockContext, Subscriber};
+    use crate::test_helpers::*;
+    use crate::test_util::SyntheticChain;
+    use crate::utils::{BlockProofStuff, BlockProofStuffAug, BlockStuff, BlockStuffAug};
+
+    async fn store_block(engine: &Engine, id: &ton_block::BlockIdExt, data: &[u8]) {
+        let block = BlockStuff::deserialize_checked(id.clone(), data).unwrap();
+        let proof_data = make_block_proof(id, data);
+        let proof = BlockProofStuff::deserialize(id.clone(), &proof_data, true).unwrap();
+
+        let meta_data = BlockMetaData {
+            is_key_block: false,
+            gen_utime: id.seq_no,
+            mc_ref_seqno: Some(0),
+        };
+        let block_storage = engine.db.block_storage();
+        let result = block_storage
+            .store_block_data(&BlockStuffAug::new(block, data.to_vec()), meta_data)
+            .await
+            .unwrap();
+        block_storage
+            .store_block_proof(
+                &BlockProofStuffAug::new(proof, proof_data),
+                result.handle.into(),
+            )
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn engine_downloads_blocks_from_mock_peer() {
+        let dir_a = TempDir::new("mock_network_a");
+        let dir_b = TempDir::new("mock_network_b");
+        let node_a = test_engine(&dir_a, Vec::new()).await;
+        let node_b = test_engine(&dir_b, Vec::new()).await;
+
+        let network = Arc::new(MockNetwork::default());
+        let peer_a = node_a.connect_mock_network(&network).unwrap();
+        node_b.connect_mock_network(&network).unwrap();
+        assert!(node_b.connect_mock_network(&network).is_err());
+
+        let shard = ton_block::ShardIdent::full(ton_block::BASE_WORKCHAIN_ID);
+        let blocks = (1..=3)
+            .map(|seq_no| make_block(shard, seq_no, Default::default()))
+            .collect::<Vec<_>>();
+        for (id, data) in &blocks {
+            store_block(&node_a, id, data).await;
+        }
+
+        for (id, data) in &blocks {
+            let (block, proof) = node_b
+                .download_block(id, Some(1), Some(&peer_a))
+                .await
+                .unwrap();
+            assert_eq!(block.id(), id);
+            assert_eq!(block.new_archive_data().unwrap(), data.as_slice());
+            assert!(proof.is_link());
+            assert_eq!(
+                proof.new_archive_data().unwrap(),
+                make_block_proof(id, data).as_slice()
+            );
+        }
+
+        // Shard blocks are downloaded through the overlay of their workchain
+        let workchain = ton_block::BASE_WORKCHAIN_ID;
+        assert_eq!(network.query_count(ton_block::MASTERCHAIN_ID), 0);
+        assert_eq!(network.query_count(workchain), 2 * blocks.len());
+
+        // Unknown block is requested only the specified number of times
+        let (unknown_id, _) = make_block(shard, 4, Default::default());
+        assert!(node_b
+            .download_block(&unknown_id, Some(2), Some(&peer_a))
+            .await
+            .is_err());
+        assert_eq!(network.query_count(workchain), 2 * blocks.len() + 2);
+
+        // Attempts are limited by default when the peer is specified
+        assert!(node_b
+            .download_block(&unknown_id, None, Some(&peer_a))
+            .await
+            .is_err());
+        assert_eq!(
+            network.query_count(workchain),
+            2 * blocks.len() + 2 + crate::engine::PINNED_PEER_DOWNLOAD_ATTEMPTS as usize
+        );
+
+        // Node A has no archives
+        let mut archive = Vec::new();
+        assert_eq!(
+            node_b
+                .download_archive_from_peer(1, &peer_a, &mut archive)
+                .await
+                .unwrap(),
+            None
+        );
+        assert!(archive.is_empty());
+    }
+
+    #[derive(Default)]
+    struct RecordingSubscriber {
+        delivered: Mutex<Vec<ton_block::BlockIdExt>>,
+    }
+
+    #[async_trait::async_trait]
+    impl Subscriber for RecordingSubscriber {
+        async fn process_block(&self, ctx: ProcessBlockContext<'_>) -> Result<()> {
+            self.delivered.lock().push(ctx.id().clone());
+            Ok(())
+        }
+    }
+
+    const CHAIN_LEN: u32 = 250;
+
+    /// Node B which has cold-booted and synced from node A
+    /// with the generated chain over the mock network
+    struct MockSync {
+        chain: SyntheticChain,
+        node_a: Arc<Engine>,
+        node_b: Arc<Engine>,
+        subscriber_b: Arc<RecordingSubscriber>,
+        network: Arc<MockNetwork>,
+        _dirs: [TempDir; 2],
+    }
+
+    impl MockSync {
+        async fn run(sync_from_seqno: Option<u32>) -> Self {
+            const CHAIN_AGE: u32 = 1000;
+            const PERSISTENT_STATE_PERIOD: u32 = 1 << 17;
+
+            // Cold boot starts from the zerostate only if it was generated
+            // in the current persistent state period
+            let mut now = broxus_util::now();
+            let elapsed = now % PERSISTENT_STATE_PERIOD;
+            if elapsed <= CHAIN_AGE {
+                tokio::time::sleep(Duration::from_secs((CHAIN_AGE + 1 - elapsed) as u64)).await;
+                now = broxus_util::now();
+            }
+
+            // All blocks except the last one are too old for the node to be synced,
+            // so all archives must be imported
+            let zero_state_utime = now - CHAIN_AGE;
+            let chain = SyntheticChain::generate(CHAIN_LEN, zero_state_utime, |seq_no| {
+                if seq_no == CHAIN_LEN {
+                    now
+                } else {
+                    zero_state_utime + seq_no
+                }
+            })
+            .unwrap();
+
+            // Node A has already applied the whole chain
+            let dir_a = TempDir::new("mock_sync_a");
+            chain.create_db(dir_a.path()).await.unwrap();
+            let node_a =
+                test_engine_with_global_config(&dir_a, chain.global_config(), Vec::new()).await;
+
+            // Node B cold-boots with node A as the only DHT node
+            let dir_b = TempDir::new("mock_sync_b");
+            let mut config_b = test_node_config(&dir_b);
+            config_b.sync_options.sync_from_seqno = sync_from_seqno;
+            let mut global_config_b = chain.global_config();
+            global_config_b.dht_nodes = vec![node_a.network().dht().make_signed_node()];
+            let subscriber_b = Arc::new(RecordingSubscriber::default());
+            let node_b = Engine::new(
+                config_b,
+                global_config_b,
+                vec![subscriber_b.clone() as Arc<dyn Subscriber>],
+            )
+            .await
+            .unwrap();
+
+            let network = Arc::new(MockNetwork::default());
+            node_a.connect_mock_network(&network).unwrap();
+            node_b.connect_mock_network(&network).unwrap();
+
+            tokio::time::timeout(Duration::from_secs(120), node_b.start())
+                .await
+                .expect("node B didn't sync in time")
+                .unwrap();
+
+            Self {
+                chain,
+                node_a,
+                node_b,
+                subscriber_b,
+                network,
+                _dirs: [dir_a, dir_b],
+            }
+        }
+
+        /// Delivered seqnos of masterchain or shard blocks
+        fn delivered(&self, is_masterchain: bool) -> Vec<u32> {
+            self.subscriber_b
+                .delivered
+                .lock()
+                .iter()
+                .filter(|id| id.shard_id.is_masterchain() == is_masterchain)
+                .map(|id| id.seq_no)
+                .collect()
+        }
+    }
+
+    impl Drop for MockSync {
+        fn drop(&mut self) {
+            self.node_b.shutdown();
+            self.node_a.shutdown();
+        }
+    }
+
+    #[tokio::test]
+    async fn engine_syncs_from_mock_peer() {
+        let sync = MockSync::run(None).await;
+        let (chain, node_b) = (&sync.chain, &sync.node_b);
+
+        // Applied head
+        let head = chain.head();
+        assert_eq!(&node_b.load_last_applied_mc_block_id().unwrap(), head);
+        assert_eq!(&node_b.load_shards_client_mc_block_id().unwrap(), head);
+
+        // Blocks are applied with the same data and connected
+        let block_handle_storage = node_b.db.block_handle_storage();
+        let block_connection_storage = node_b.db.block_connection_storage();
+        let block_storage = node_b.db.block_storage();
+        for seq_no in 1..=CHAIN_LEN {
+            for block in [chain.shard_block(seq_no), chain.mc_block(seq_no)] {
+                let block = block.unwrap();
+                let handle = block_handle_storage
+                    .load_handle(&block.id)
+                    .unwrap()
+                    .expect("block handle not found");
+                assert!(handle.meta().is_applied());
+                assert_eq!(handle.masterchain_ref_seqno(), seq_no);
+                assert_eq!(
+                    block_storage.load_block_data_raw(&handle).await.unwrap(),
+                    block.data
+                );
+                assert_eq!(
+                    block_connection_storage
+                        .load_connection(&block.id, BlockConnection::Prev1)
+                        .unwrap()
+                        .seq_no,
+                    seq_no - 1
+                );
+            }
+        }
+
+        // Key blocks index contains only the masterchain zerostate
+        assert_eq!(
+            block_handle_storage.key_blocks_index().ids(),
+            vec![chain.mc_zero_state.id.clone()]
+        );
+
+        // Each block is delivered exactly once and in order
+        let all = (1..=CHAIN_LEN).collect::<Vec<_>>();
+        assert_eq!(sync.delivered(true), all);
+        assert_eq!(sync.delivered(false), all);
+
+        // Blocks were served from archives
+        assert_eq!(node_b.sync_stats().archives_imported, 3);
+        assert_eq!(node_b.sync_stats().archives_failed, 0);
+        assert!(sync.network.query_count(ton_block::MASTERCHAIN_ID) > 0);
+    }
+
+    #[tokio::test]
+    async fn engine_syncs_from_mock_peer_since_seqno() {
+        const SYNC_FROM_SEQNO: u32 = 150;
+
+        let sync = MockSync::run(Some(SYNC_FROM_SEQNO)).await;
+        let node_b = &sync.node_b;
+
+        // The state is built from the zerostate (the only persistent key block)...
+        assert_eq!(
+            node_b.load_last_applied_mc_block_id().unwrap(),
+            *sync.chain.head()
+        );
+        assert_eq!(
+            node_b.db.node_state().load_notify_from_seqno().unwrap(),
+            Some(SYNC_FROM_SEQNO)
+        );
+
+        // ...but the blocks before the sync start are not delivered
+        let since = (SYNC_FROM_SEQNO..=CHAIN_LEN).collect::<Vec<_>>();
+        assert_eq!(sync.delivered(true), since);
+        assert_eq!(sync.delivered(false), since);
+        assert!(!node_b.is_notified(SYNC_FROM_SEQNO - 1));
+        assert!(node_b.is_notified(SYNC_FROM_SEQNO));
+    }
+}
@@ -6,15 +6,17 @@
 /// - slightly changed application of blocks
 ///
 use std::io::Write;
-use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU32, Ordering};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::{Context, Result};
 use broxus_util::now;
-use everscale_network::overlay;
+use everscale_network::{adnl, overlay};
+use parking_lot::Mutex;
 pub use rocksdb::perf::MemoryUsageStats;
-use rustc_hash::FxHashSet;
+use rustc_hash::{FxHashMap, FxHashSet};
 use serde::{Deserialize, Serialize};
 use tokio::sync::Notify;
 
@@ -25,13 +27,38 @@ use crate::db::*;
 use crate::network::*;
 use crate::utils::*;
 
+use self::audit_log::*;
+use self::balance_history::BalanceHistory;
+pub use self::balance_history::BalanceSink;
+pub use self::block_time::*;
+use self::block_waiters::BlockWaiters;
 use self::complex_operations::*;
+pub use self::disk_watcher::*;
 use self::downloader::*;
+pub use self::finality::Finality;
+use self::hard_forks::HardForks;
+#[cfg(feature = "mock-network")]
+pub use self::mock_network::MockNetwork;
 pub use self::node_rpc::*;
-
+use self::notification_sequencer::*;
+pub use self::validator_sets::{ValidatorInfo, ValidatorSetInfo, ValidatorSets};
+
+mod audit_log;
+mod balance_history;
+mod block_time;
+mod block_waiters;
+mod blocks_by_time;
 pub mod complex_operations;
+mod config_changes;
+mod disk_watcher;
 mod downloader;
+mod finality;
+mod hard_forks;
+#[cfg(any(test, feature = "mock-network"))]
+mod mock_network;
 mod node_rpc;
+mod notification_sequencer;
+mod validator_sets;
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum EngineStatus {
@@ -43,8 +70,14 @@ pub struct Engine {
     is_working: AtomicBool,
     db: Arc<Db>,
     states_gc_options: Option<StateGcOptions>,
+    states_gc_trigger: Arc<Notify>,
     blocks_gc_state: Option<BlocksGcState>,
-    subscribers: Vec<Arc<dyn Subscriber>>,
+    disk_watcher: Option<Arc<DiskWatcher>>,
+    subscribers: Vec<SubscriberEntry>,
+    subscriber_error_policy: Option<SubscriberErrorPolicy>,
+    late_subscribers: LateSubscribers,
+    pending_shard_notifications: Option<Mutex<PendingShardNotifications>>,
+    notification_sequencer: Option<BlockNotificationSequencer>,
     network: Arc<NodeNetwork>,
 
     masterchain_client: NodeRpcClient,
@@ -53,18 +86,50 @@ pub struct Engine {
     old_blocks_policy: OldBlocksPolicy,
     zero_state_id: ton_block::BlockIdExt,
     init_mc_block_id: ton_block::BlockIdExt,
-    hard_forks: FxHashSet<ton_block::BlockIdExt>,
+    hard_forks: HardForks,
 
     archive_options: Option<ArchiveOptions>,
     sync_options: SyncOptions,
+    network_options: NetworkOptions,
+    broadcast_options: BroadcastOptions,
+    broadcast_blocks_buffer: Option<BroadcastBlocksBuffer>,
 
     shard_states_operations: ShardStatesOperationsPool,
     block_applying_operations: BlockApplyingOperationsPool,
     next_block_applying_operations: NextBlockApplyingOperationsPool,
     download_block_operations: DownloadBlockOperationsPool,
+    block_waiters: BlockWaiters,
     shard_states_cache: ShardStateCache,
+    warmup_options: Option<WarmupOptions>,
+    shards_client_reset: ShardsClientReset,
+    /// Serializes moves of the last applied and shards client pointers
+    node_state_pointers_lock: Mutex<()>,
+    audit_log: AuditLog,
+    index_messages: bool,
+    require_preseeded: bool,
+    /// Masterchain seqno from which the indexing was started
+    start_from_seqno: Option<u32>,
+    /// Masterchain seqno before which the applied blocks are not delivered
+    /// to subscribers (see `sync_options.sync_from_seqno`)
+    notify_from_seqno: AtomicU32,
+    /// Handles preloaded after boot, kept alive to stay in the handles cache
+    warm_handles: Mutex<Vec<Arc<BlockHandle>>>,
+    sync_stats: Mutex<SyncStats>,
+    key_block_proofs_cache: SmallLruCache<ton_block::BlockIdExt, Arc<BlockProofStuff>>,
+    /// Parsed validator sets by key block seqno
+    validator_sets_cache: SmallLruCache<u32, ValidatorSets>,
+    /// Config of the last notified key block
+    last_key_block_config: Mutex<Option<(u32, ton_block::ConfigParams)>>,
+    balance_history: Option<BalanceHistory>,
+    memory_budget: Arc<MemoryBudget>,
+    blocking_pool: BlockingPool,
+    /// Shared limit of the concurrently applied shard blocks
+    shard_apply_limiter: ConcurrencyLimiter,
+    block_time_anomalies: BlockTimeAnomalies,
 
     metrics: Arc<EngineMetrics>,
+    #[cfg(feature = "apply-metrics")]
+    apply_block_metrics: ApplyBlockMetrics,
 }
 
 type ShardStatesOperationsPool = OperationsPool<ton_block::BlockIdExt, Arc<ShardStateStuff>>;
@@ -73,10 +138,234 @@ type NextBlockApplyingOperationsPool = OperationsPool<ton_block::BlockIdExt, ton
 type DownloadBlockOperationsPool =
     OperationsPool<ton_block::BlockIdExt, (BlockStuffAug, BlockProofStuffAug)>;
 
+/// Number of recently used key block proofs kept deserialized
+const KEY_BLOCK_PROOFS_CACHE_CAPACITY: usize = 4;
+const VALIDATOR_SETS_CACHE_CAPACITY: usize = 8;
+
+/// Max number of out-of-order notifications buffered by the sequencer
+const NOTIFICATION_SEQUENCER_WINDOW: usize = 4096;
+/// Default number of attempts to download the block from the specified peer
+const PINNED_PEER_DOWNLOAD_ATTEMPTS: u32 = 10;
+
+/// Applied shard blocks grouped by the masterchain block seqno
+type PendingShardNotifications = FxHashMap<u32, Vec<PendingBlockNotification>>;
+
+type BlockNotificationSequencer =
+    tokio::sync::Mutex<NotificationSequencer<PendingBlockNotification>>;
+
+#[derive(Clone)]
+struct PendingBlockNotification {
+    handle: Arc<BlockHandle>,
+    block: BlockStuff,
+    shard_state: Option<Arc<ShardStateStuff>>,
+    /// Raw block and proof data of the block from the archive
+    archive_data: Option<(bytes::Bytes, bytes::Bytes)>,
+}
+
+impl PendingBlockNotification {
+    fn applied(
+        handle: &Arc<BlockHandle>,
+        block: &BlockStuff,
+        shard_state: &Arc<ShardStateStuff>,
+    ) -> Self {
+        Self {
+            handle: handle.clone(),
+            block: block.clone(),
+            shard_state: Some(shard_state.clone()),
+            archive_data: None,
+        }
+    }
+
+    fn context<'a>(&'a self, engine: &'a Engine) -> ProcessBlockContext<'a> {
+        ProcessBlockContext {
+            engine,
+            meta: self.handle.meta().brief(),
+            handle: &self.handle,
+            block: &self.block,
+            shard_state: self.shard_state.as_deref(),
+            block_data: self.archive_data.as_ref().map(|(data, _)| data.as_ref()),
+            block_proof_data: self.archive_data.as_ref().map(|(_, data)| data.as_ref()),
+        }
+    }
+}
+
+/// Applied blocks after the delivered position which were not delivered
+/// before the restart. Shard blocks are found by walking forward from the
+/// top blocks of the position, masterchain blocks are loaded up to the last
+/// applied one.
+///
+/// NOTE: shard blocks go first, so that most masterchain blocks could be
+/// delivered right after they are pushed into the sequencer
+struct UndeliveredNotifications {
+    shard_queue: Vec<ton_block::BlockIdExt>,
+    visited: FxHashSet<ton_block::BlockIdExt>,
+    next_mc_seq_no: u32,
+    last_applied_mc_seq_no: u32,
+}
+
+impl UndeliveredNotifications {
+    fn new(engine: &Engine, position: &TopBlocks) -> Result<Self> {
+        let shard_state_storage = engine.db.shard_state_storage();
+
+        let mut shard_queue = Vec::new();
+        for (shard, seq_no) in &position.shard_heights {
+            if let Some(block_id) = shard_state_storage.find_block_id(shard, *seq_no)? {
+                shard_queue.push(block_id);
+            }
+        }
+
+        Ok(Self {
+            shard_queue,
+            visited: Default::default(),
+            next_mc_seq_no: position.mc_block.seq_no + 1,
+            last_applied_mc_seq_no: engine.load_last_applied_mc_block_id()?.seq_no,
+        })
+    }
+
+    /// Loads at most `limit` next blocks. Returns an empty window when all blocks are loaded
+    async fn load_window(
+        &mut self,
+        engine: &Engine,
+        limit: usize,
+    ) -> Result<Vec<PendingBlockNotification>> {
+        let block_connection_storage = engine.db.block_connection_storage();
+
+        let mut result = Vec::new();
+
+        // NOTE: each block has at most two next blocks (after split)
+        while result.len() + 2 <= limit {
+            let block_id = match self.shard_queue.pop() {
+                Some(block_id) => block_id,
+                None => break,
+            };
+
+            // NOTE: after merge both parents point to the same next block
+            for direction in [BlockConnection::Next1, BlockConnection::Next2] {
+                let next_id =
+                    match block_connection_storage.find_connection(&block_id, direction)? {
+                        Some(next_id) if self.visited.insert(next_id.clone()) => next_id,
+                        _ => continue,
+                    };
+                if let Some(item) = engine.load_applied_notification(&next_id).await? {
+                    result.push(item);
+                    self.shard_queue.push(next_id);
+                }
+            }
+        }
+
+        if !self.shard_queue.is_empty() {
+            return Ok(result);
+        }
+
+        let shard_state_storage = engine.db.shard_state_storage();
+        while result.len() < limit && self.next_mc_seq_no <= self.last_applied_mc_seq_no {
+            let item = match shard_state_storage
+                .find_block_id(&ton_block::ShardIdent::masterchain(), self.next_mc_seq_no)?
+            {
+                Some(block_id) => engine.load_applied_notification(&block_id).await?,
+                None => None,
+            };
+
+            match item {
+                Some(item) => {
+                    result.push(item);
+                    self.next_mc_seq_no += 1;
+                }
+                None => {
+                    // Stop at the first missing block
+                    self.next_mc_seq_no = u32::MAX;
+                    break;
+                }
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+/// Ensures that the DB was synced against the same zero state as in the global config.
+///
+/// NOTE: DBs created before the zero state was stored are assigned to the current network
+fn check_network(db: &Db, zero_state_id: &ton_block::BlockIdExt) -> Result<()> {
+    let node_state = db.node_state();
+    match node_state.load_zero_state_id()? {
+        Some(stored) if &stored != zero_state_id => Err(EngineError::NetworkMismatch {
+            stored: stored.root_hash.to_hex_string(),
+            expected: zero_state_id.root_hash.to_hex_string(),
+        }
+        .into()),
+        Some(_) => Ok(()),
+        None => node_state.store_zero_state_id(zero_state_id),
+    }
+}
+
+/// Ensures that the indexing start is never moved backwards for the existing DB
+fn check_start_from_seqno(db: &Db, start_from_seqno: Option<u32>) -> Result<()> {
+    let node_state = db.node_state();
+    let stored = node_state.load_start_from_seqno()?;
+    let is_empty = node_state.load_last_mc_block_id().is_err();
+
+    match (stored, start_from_seqno) {
+        (Some(stored), new) if !is_empty && new.unwrap_or_default() < stored => {
+            Err(EngineError::StartSeqnoLowered { stored, new }.into())
+        }
+        (stored, Some(new)) if stored != Some(new) => node_state.store_start_from_seqno(new),
+        _ => Ok(()),
+    }
+}
+
+/// Starts the network and creates overlay clients for the masterchain and the basechain
+async fn start_network(
+    config: NodeConfig,
+    global_config: GlobalConfig,
+) -> Result<(Arc<NodeNetwork>, NodeRpcClient, NodeRpcClient)> {
+    let network = NodeNetwork::new(
+        config.ip_address,
+        config.adnl_keys.build_keystore()?,
+        config.adnl_options,
+        config.rldp_options,
+        config.dht_options,
+        config.neighbours_options,
+        config.overlay_shard_options,
+        global_config,
+    )
+    .await
+    .context("Failed to init network")?;
+
+    let (masterchain, basechain) = futures_util::future::join(
+        network.create_overlay_client(ton_block::MASTERCHAIN_ID),
+        network.create_overlay_client(ton_block::BASE_WORKCHAIN_ID),
+    )
+    .await;
+    let masterchain_client = masterchain
+        .map(NodeRpcClient)
+        .context("Failed to create masterchain overlay")?;
+    let basechain_client = basechain
+        .map(NodeRpcClient)
+        .context("Failed to create basechain overlay")?;
+
+    tracing::info!("network started");
+    Ok((network, masterchain_client, basechain_client))
+}
+
+/// Sort key for buffered shard block notifications.
+///
+/// NOTE: seqno goes before the shard so that blocks after split/merge
+/// are always notified after their parents
+fn shard_notification_order(id: &ton_block::BlockIdExt) -> (i32, u32, u64) {
+    (
+        id.shard_id.workchain_id(),
+        id.seq_no,
+        id.shard_id.shard_prefix_with_tag(),
+    )
+}
+
 struct BlocksGcState {
     ty: BlocksGcKind,
     max_blocks_per_batch: Option<usize>,
     enabled: AtomicBool,
+    /// Whether the GC triggered by the low disk space is in progress
+    triggered: AtomicBool,
 }
 
 impl Drop for Engine {
@@ -91,16 +380,25 @@ impl Engine {
         global_config: GlobalConfig,
         subscribers: Vec<Arc<dyn Subscriber>>,
     ) -> Result<Arc<Self>> {
+        config.validate()?;
+
         let old_blocks_policy = config.sync_options.old_blocks_policy;
+        let blocking_pool = BlockingPool::new(config.sync_options.verification_threads);
+        let shard_apply_limiter =
+            ConcurrencyLimiter::new(config.sync_options.shard_apply_concurrency);
         let db = Db::new(
             &config.rocks_db_path,
             &config.file_db_path,
+            &config.temp_files_path,
             config.max_db_memory_usage,
+            config.db_options,
         )
         .await
         .context("Failed to create DB")?;
 
         let zero_state_id = global_config.zero_state.clone();
+        check_network(&db, &zero_state_id)?;
+        check_start_from_seqno(&db, config.start_from_seqno)?;
 
         let mut init_mc_block_id = zero_state_id.clone();
         if let Ok(block_id) = db.node_state().load_init_mc_block_id() {
@@ -117,50 +415,40 @@ impl Engine {
             "selected init block"
         );
 
-        let hard_forks = global_config.hard_forks.clone().into_iter().collect();
-
-        let network = NodeNetwork::new(
-            config.ip_address,
-            config.adnl_keys.build_keystore()?,
-            config.adnl_options,
-            config.rldp_options,
-            config.dht_options,
-            config.neighbours_options,
-            config.overlay_shard_options,
-            global_config,
-        )
-        .await
-        .context("Failed to init network")?;
-
-        let (masterchain_client, basechain_client) = {
-            let (masterchain, basechain) = futures_util::future::join(
-                network.create_overlay_client(ton_block::MASTERCHAIN_ID),
-                network.create_overlay_client(ton_block::BASE_WORKCHAIN_ID),
-            )
-            .await;
-
-            (
-                masterchain
-                    .map(NodeRpcClient)
-                    .context("Failed to create masterchain overlay")?,
-                basechain
-                    .map(NodeRpcClient)
-                    .context("Failed to create basechain overlay")?,
-            )
-        };
+        let hard_forks = HardForks::new(global_config.hard_forks.clone())
+            .context("Invalid hard forks in the global config")?
+            .with_accepted(db.node_state().load_accepted_hard_forks()?);
 
-        tracing::info!("network started");
+        let (network, masterchain_client, basechain_client) =
+            start_network(config.clone(), global_config).await?;
 
         Ok(Arc::new(Self {
             is_working: AtomicBool::new(true),
             db: db.clone(),
             states_gc_options: config.state_gc_options,
+            states_gc_trigger: Default::default(),
             blocks_gc_state: config.blocks_gc_options.map(|options| BlocksGcState {
                 ty: options.kind,
                 max_blocks_per_batch: options.max_blocks_per_batch,
                 enabled: AtomicBool::new(options.enable_for_sync),
+                triggered: AtomicBool::new(false),
+            }),
+            disk_watcher: config.disk_watermarks.map(|options| {
+                Arc::new(DiskWatcher::new(
+                    [&config.rocks_db_path, &config.file_db_path],
+                    options,
+                ))
+            }),
+            subscribers: subscribers
+                .into_iter()
+                .map(|subscriber| SubscriberEntry::new(subscriber, config.subscriber_error_policy))
+                .collect(),
+            subscriber_error_policy: config.subscriber_error_policy,
+            late_subscribers: Default::default(),
+            pending_shard_notifications: config.ordered_shard_notifications.then(Default::default),
+            notification_sequencer: config.ordered_shard_notifications.then(|| {
+                tokio::sync::Mutex::new(NotificationSequencer::new(NOTIFICATION_SEQUENCER_WINDOW))
             }),
-            subscribers,
             network,
             masterchain_client,
             basechain_client,
@@ -170,16 +458,67 @@ impl Engine {
             hard_forks,
             archive_options: config.archive_options,
             sync_options: config.sync_options,
+            network_options: config.network_options,
+            broadcast_options: config.broadcast_options,
+            broadcast_blocks_buffer: match config.broadcast_options.store_policy {
+                BroadcastStorePolicy::StoreWithinHorizon { buffer_size, .. } => {
+                    Some(BroadcastBlocksBuffer::new(buffer_size))
+                }
+                _ => None,
+            },
             shard_states_operations: OperationsPool::new("shard_states_operations"),
             block_applying_operations: OperationsPool::new("block_applying_operations"),
             next_block_applying_operations: OperationsPool::new("next_block_applying_operations"),
             download_block_operations: OperationsPool::new("download_block_operations"),
+            block_waiters: Default::default(),
             shard_states_cache: ShardStateCache::new(config.shard_state_cache_options),
+            warmup_options: config.warmup_options,
+            shards_client_reset: Default::default(),
+            node_state_pointers_lock: Default::default(),
+            audit_log: AuditLog::new(&db, config.audit_log_options),
+            index_messages: config.index_messages,
+            require_preseeded: config.require_preseeded,
+            start_from_seqno: config.start_from_seqno,
+            notify_from_seqno: AtomicU32::new(
+                db.node_state()
+                    .load_notify_from_seqno()?
+                    .unwrap_or_default(),
+            ),
+            warm_handles: Default::default(),
+            sync_stats: Default::default(),
+            key_block_proofs_cache: SmallLruCache::new(KEY_BLOCK_PROOFS_CACHE_CAPACITY),
+            validator_sets_cache: SmallLruCache::new(VALIDATOR_SETS_CACHE_CAPACITY),
+            last_key_block_config: Default::default(),
+            balance_history: config
+                .sync_options
+                .balance_history
+                .clone()
+                .map(BalanceHistory::new),
+            memory_budget: MemoryBudget::new(config.max_sync_memory_usage),
+            blocking_pool,
+            shard_apply_limiter,
+            block_time_anomalies: Default::default(),
             metrics: Arc::new(Default::default()),
+            #[cfg(feature = "apply-metrics")]
+            apply_block_metrics: Default::default(),
         }))
     }
 
+    /// Checks the key block proofs chain which would be used by the cold boot
+    /// without downloading states and storing anything.
+    ///
+    /// NOTE: only the network is started, the DB is not opened
+    pub async fn verify_boot(
+        config: NodeConfig,
+        global_config: GlobalConfig,
+    ) -> Result<BootVerificationReport> {
+        verify_boot(config, global_config).await
+    }
+
     pub async fn start(self: &Arc<Self>) -> Result<()> {
+        // Start watching disk space before any blocks are stored
+        self.start_disk_watcher();
+
         // Start full node overlay service
         let service = NodeRpcServer::new(self);
 
@@ -190,6 +529,11 @@ impl Engine {
 
         // Boot
         boot(self).await?;
+        self.check_hard_forks_encountered()?;
+        self.init_notification_sequencer().await?;
+        if self.start_from_seqno.is_some() {
+            self.notify_indexing_started().await?;
+        }
         self.notify_subscribers_with_status(EngineStatus::Booted)
             .await;
 
@@ -197,20 +541,40 @@ impl Engine {
         self.listen_broadcasts(&self.masterchain_client);
         self.listen_broadcasts(&self.basechain_client);
 
+        // Warm up caches in background
+        if let Some(options) = self.warmup_options {
+            let engine = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = warm_up_caches(&engine, options).await {
+                    tracing::error!("failed to warm up caches: {e:?}");
+                }
+            });
+        }
+
         // Start archives gc
         self.start_archives_gc().await?;
 
+        self.start_message_index_rebuild()?;
+
         // Synchronize
         match self.old_blocks_policy {
             OldBlocksPolicy::Ignore => { /* do nothing */ }
             OldBlocksPolicy::Sync { from_seqno } => {
+                // Blocks before the indexing start are never downloaded
+                let from_seqno =
+                    std::cmp::max(from_seqno, self.start_from_seqno.unwrap_or_default());
                 historical_sync(self, from_seqno).await?;
             }
         }
         if !self.is_synced()? {
             sync(self).await?;
         }
-        tracing::info!("node synced");
+        let sync_stats = self.sync_stats();
+        tracing::info!(stats = ?sync_stats, "node synced");
+
+        for entry in &self.subscribers {
+            entry.subscriber.sync_completed(&sync_stats).await;
+        }
 
         self.notify_subscribers_with_status(EngineStatus::Synced)
             .await;
@@ -223,10 +587,59 @@ impl Engine {
         Ok(())
     }
 
+    /// Replays stored blocks starting from the specified masterchain block
+    /// and then notifies the subscriber about new blocks without gaps and duplicates.
+    ///
+    /// NOTE: blocks are delivered after they are processed by the shards client,
+    /// and only `process_block` is called for such subscribers. New blocks are
+    /// delivered in background, and the subscriber is stopped if it fails to
+    /// process some block after several attempts
+    pub async fn subscribe_from(
+        self: &Arc<Self>,
+        from_mc_seq_no: u32,
+        subscriber: Arc<dyn Subscriber>,
+    ) -> Result<()> {
+        let subscriber = SubscriberEntry::new(subscriber, self.subscriber_error_policy);
+        subscribe_from(self, from_mc_seq_no, subscriber).await
+    }
+
+    /// Tells subscribers the block from which the indexing started (only once per DB)
+    async fn notify_indexing_started(&self) -> Result<()> {
+        let node_state = self.db.node_state();
+        if node_state.load_indexing_started_at()?.is_some() {
+            return Ok(());
+        }
+
+        // NOTE: preseeded DBs were not initialized with the cold boot,
+        // so the indexing is treated as started from the current block
+        let block_id = match node_state.find_historical_sync_end()? {
+            Some(block_id) => block_id,
+            None => self.load_last_applied_mc_block_id()?,
+        };
+
+        for entry in &self.subscribers {
+            entry.subscriber.indexing_started_at(&block_id).await;
+        }
+        node_state.store_indexing_started_at(&block_id)
+    }
+
     pub fn network(&self) -> &Arc<NodeNetwork> {
         &self.network
     }
 
+    /// Skips notifications about the blocks applied before the specified masterchain seqno
+    fn store_notify_from_seqno(&self, seqno: u32) -> Result<()> {
+        self.db.node_state().store_notify_from_seqno(seqno)?;
+        self.notify_from_seqno.store(seqno, Ordering::Release);
+        Ok(())
+    }
+
+    /// Whether subscribers must be notified about the block committed
+    /// in the specified masterchain block
+    fn is_notified(&self, mc_seq_no: u32) -> bool {
+        mc_seq_no >= self.notify_from_seqno.load(Ordering::Acquire)
+    }
+
     async fn prepare_blocks_gc(self: &Arc<Self>) -> Result<()> {
         let blocks_gc_state = match &self.blocks_gc_state {
             Some(state) => state,
@@ -234,18 +647,140 @@ impl Engine {
         };
 
         blocks_gc_state.enabled.store(true, Ordering::Release);
+        self.remove_outdated_blocks(blocks_gc_state).await
+    }
 
+    async fn remove_outdated_blocks(&self, blocks_gc_state: &BlocksGcState) -> Result<()> {
         let handle = self.db.block_handle_storage().find_last_key_block()?;
+        self.audit_log.record(
+            AuditLogEventKind::BlocksGc,
+            Some(handle.id()),
+            format!("{:?}", blocks_gc_state.ty),
+        );
         self.db
             .block_storage()
             .remove_outdated_blocks(
                 handle.id(),
                 blocks_gc_state.max_blocks_per_batch,
                 blocks_gc_state.ty,
+                self.start_from_seqno,
             )
             .await
     }
 
+    fn start_disk_watcher(self: &Arc<Self>) {
+        let watcher = match &self.disk_watcher {
+            Some(watcher) => watcher.clone(),
+            None => return,
+        };
+
+        let engine = Arc::downgrade(self);
+        tokio::spawn(async move {
+            loop {
+                let engine = match engine.upgrade() {
+                    Some(engine) if engine.is_working() => engine,
+                    _ => return,
+                };
+
+                match watcher.available_space() {
+                    Ok((path, available)) => {
+                        engine
+                            .metrics
+                            .available_disk_space
+                            .store(available, Ordering::Relaxed);
+
+                        if let Some(level) = watcher.update(available) {
+                            engine
+                                .on_disk_space_level_changed(&watcher, path, level, available)
+                                .await;
+                        }
+                    }
+                    Err(e) => tracing::error!("failed to check available disk space: {e:?}"),
+                }
+
+                drop(engine);
+                tokio::time::sleep(watcher.check_interval()).await;
+            }
+        });
+    }
+
+    async fn on_disk_space_level_changed(
+        self: &Arc<Self>,
+        watcher: &DiskWatcher,
+        path: &Path,
+        level: DiskSpaceLevel,
+        available: u64,
+    ) {
+        match level {
+            DiskSpaceLevel::Normal => {
+                tracing::info!(available, "disk space freed, resuming sync");
+                return;
+            }
+            DiskSpaceLevel::Low => {
+                tracing::warn!(
+                    path = %path.display(),
+                    available,
+                    "low disk space, pausing background sync"
+                );
+            }
+            DiskSpaceLevel::Critical => {
+                tracing::error!(
+                    path = %path.display(),
+                    available,
+                    "critically low disk space, pausing downloads and block application"
+                );
+
+                let report = StallReport {
+                    reason: StallReason::LowDiskSpace {
+                        path: path.to_path_buf(),
+                        available,
+                        hard_threshold: watcher.options().hard_threshold,
+                    },
+                };
+                for entry in &self.subscribers {
+                    entry.subscriber.stall_detected(&report).await;
+                }
+            }
+        }
+
+        // Try to free some space as soon as possible.
+        // NOTE: the permit is stored if the states GC is running now
+        self.states_gc_trigger.notify_one();
+        self.trigger_blocks_gc();
+    }
+
+    /// Removes outdated blocks in background unless it is already in progress
+    fn trigger_blocks_gc(self: &Arc<Self>) {
+        let blocks_gc_state = match &self.blocks_gc_state {
+            Some(state) if state.enabled.load(Ordering::Acquire) => state,
+            _ => return,
+        };
+        if blocks_gc_state.triggered.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let engine = self.clone();
+        tokio::spawn(async move {
+            if let Some(blocks_gc_state) = &engine.blocks_gc_state {
+                if let Err(e) = engine.remove_outdated_blocks(blocks_gc_state).await {
+                    tracing::error!("failed to remove outdated blocks: {e:?}");
+                }
+                blocks_gc_state.triggered.store(false, Ordering::Release);
+            }
+        });
+    }
+
+    /// Waits while the disk space level is at `pause_at` or worse
+    async fn wait_for_disk_space(&self, pause_at: DiskSpaceLevel) {
+        if let Some(watcher) = &self.disk_watcher {
+            watcher.wait_below(pause_at).await;
+        }
+    }
+
+    fn is_disk_space_critical(&self) -> bool {
+        matches!(&self.disk_watcher, Some(watcher) if watcher.level() == DiskSpaceLevel::Critical)
+    }
+
     async fn start_archives_gc(self: &Arc<Self>) -> Result<()> {
         let options = match &self.archive_options {
             Some(options) => options,
@@ -396,6 +931,11 @@ impl Engine {
                             }
                         }
 
+                        engine.audit_log.record(
+                            AuditLogEventKind::ArchivesGc,
+                            None,
+                            format!("until archive {until_id}"),
+                        );
                         if let Err(e) = engine.db.block_storage().remove_outdated_archives(until_id)
                         {
                             tracing::error!("failed to remove outdated archives: {e:?}");
@@ -433,6 +973,31 @@ impl Engine {
         Ok(())
     }
 
+    /// Rebuilds the message index in background if it was cleared
+    fn start_message_index_rebuild(self: &Arc<Self>) -> Result<()> {
+        if !self.index_messages || !self.db.node_state().load_message_index_rebuild_required()? {
+            return Ok(());
+        }
+
+        let db = self.db.clone();
+        tokio::task::spawn_blocking(move || {
+            tracing::warn!("rebuilding message index");
+            let result = db
+                .message_index()
+                .rebuild(db.block_storage())
+                .and_then(|count| {
+                    db.node_state()
+                        .store_message_index_rebuild_required(false)?;
+                    Ok(count)
+                });
+            match result {
+                Ok(count) => tracing::warn!(count, "message index rebuilt"),
+                Err(e) => tracing::error!("failed to rebuild message index: {e:?}"),
+            }
+        });
+        Ok(())
+    }
+
     fn start_states_gc(self: &Arc<Self>) {
         let options = match self.states_gc_options {
             Some(options) => options,
@@ -440,6 +1005,7 @@ impl Engine {
         };
 
         let engine = Arc::downgrade(self);
+        let trigger = self.states_gc_trigger.clone();
 
         if let Some(ttl) = self.shard_states_cache.ttl() {
             let engine = engine.clone();
@@ -459,12 +1025,19 @@ impl Engine {
         gc_at = (gc_at - gc_at % options.interval_sec) + options.offset_sec;
 
         tokio::spawn(async move {
+            let mut triggered = false;
             loop {
-                // Shift gc timestamp one iteration further
-                gc_at += options.interval_sec;
+                // Shift gc timestamp one iteration further unless the previous GC
+                // was triggered before it
+                if !triggered {
+                    gc_at += options.interval_sec;
+                }
                 // Check if there is some time left before the GC
                 if let Some(interval) = gc_at.checked_sub(broxus_util::now_sec_u64()) {
-                    tokio::time::sleep(Duration::from_secs(interval)).await;
+                    triggered = tokio::select! {
+                        _ = tokio::time::sleep(Duration::from_secs(interval)) => false,
+                        _ = trigger.notified() => true,
+                    };
                 }
 
                 let engine = match engine.upgrade() {
@@ -480,6 +1053,12 @@ impl Engine {
                     }
                 };
 
+                engine.audit_log.record(
+                    AuditLogEventKind::StatesGc,
+                    Some(&block_id),
+                    if triggered { "triggered" } else { "scheduled" },
+                );
+
                 let shard_state_storage = engine.db.shard_state_storage();
                 match shard_state_storage
                     .remove_outdated_states(block_id.seq_no)
@@ -514,6 +1093,12 @@ impl Engine {
         &self.metrics
     }
 
+    /// Block application timings by phase. Values are reset if `reset` is true
+    #[cfg(feature = "apply-metrics")]
+    pub fn apply_block_metrics(&self, reset: bool) -> ApplyBlockMetricsSnapshot {
+        self.apply_block_metrics.snapshot(reset)
+    }
+
     pub fn internal_metrics(&self) -> InternalEngineMetrics {
         InternalEngineMetrics {
             shard_states_cache_len: self.shard_states_cache.len(),
@@ -521,9 +1106,47 @@ impl Engine {
             block_applying_operations_len: self.block_applying_operations.len(),
             next_block_applying_operations_len: self.next_block_applying_operations.len(),
             download_block_operations_len: self.download_block_operations.len(),
+            buffered_broadcasts_len: self
+                .broadcast_blocks_buffer
+                .as_ref()
+                .map(BroadcastBlocksBuffer::len)
+                .unwrap_or_default(),
+            memory_budget: self.memory_budget.metrics(),
+            shard_blocks_applying: self.shard_apply_limiter.in_flight(),
         }
     }
 
+    /// Number of errors returned by each subscriber (in the registration order)
+    pub fn subscriber_errors(&self) -> Vec<u64> {
+        self.subscribers
+            .iter()
+            .map(|entry| entry.error_count.load(Ordering::Acquire))
+            .collect()
+    }
+
+    /// Compacts the specified (or all) column families to reclaim the space
+    /// occupied by deleted entries. Better be called during low traffic.
+    pub async fn compact_database(
+        &self,
+        columns: Option<Vec<&str>>,
+    ) -> Result<Vec<ColumnCompactionStats>> {
+        let columns = match columns {
+            Some(columns) => columns
+                .into_iter()
+                .map(|name| {
+                    Db::COLUMNS
+                        .into_iter()
+                        .find(|column| *column == name)
+                        .ok_or_else(|| EngineError::UnknownColumn(name.to_owned()))
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            None => Db::COLUMNS.to_vec(),
+        };
+
+        let db = self.db.clone();
+        tokio::task::spawn_blocking(move || db.compact(&columns)).await?
+    }
+
     pub fn network_metrics(&self) -> NetworkMetrics {
         self.network.metrics()
     }
@@ -546,10 +1169,6 @@ impl Engine {
         Ok(())
     }
 
-    fn is_hard_fork(&self, block_id: &ton_block::BlockIdExt) -> bool {
-        self.hard_forks.contains(block_id)
-    }
-
     fn get_rpc_client(&self, workchain: i32) -> Result<&NodeRpcClient> {
         match workchain {
             ton_block::MASTERCHAIN_ID => Ok(&self.masterchain_client),
@@ -562,6 +1181,9 @@ impl Engine {
         let engine = self.clone();
         let client = client.clone();
 
+        let options = self.broadcast_options;
+        let buffer = Arc::new(tokio::sync::Semaphore::new(options.buffer_size));
+
         tokio::spawn(async move {
             loop {
                 let block = match client.wait_broadcast().await {
@@ -569,11 +1191,43 @@ impl Engine {
                     Err(_) => continue,
                 };
 
+                if engine.is_disk_space_critical() {
+                    tracing::debug!(
+                        block_id = %block.id.display(),
+                        "critically low disk space, skipping block broadcast"
+                    );
+                    continue;
+                }
+
+                let permit = match buffer.clone().try_acquire_owned() {
+                    Ok(permit) => permit,
+                    Err(_) => match options.overflow_policy {
+                        BroadcastOverflowPolicy::Drop => {
+                            engine
+                                .metrics
+                                .dropped_broadcasts
+                                .fetch_add(1, Ordering::Relaxed);
+                            tracing::debug!(
+                                block_id = %block.id.display(),
+                                "broadcasts buffer is full, dropping block broadcast"
+                            );
+                            continue;
+                        }
+                        BroadcastOverflowPolicy::Wait => {
+                            match buffer.clone().acquire_owned().await {
+                                Ok(permit) => permit,
+                                Err(_) => break,
+                            }
+                        }
+                    },
+                };
+
                 let engine = engine.clone();
                 tokio::spawn(async move {
                     if let Err(e) = process_block_broadcast(&engine, block).await {
                         tracing::error!("failed to process block broadcast: {e:?}");
                     }
+                    drop(permit);
                 });
             }
         });
@@ -582,6 +1236,22 @@ impl Engine {
     async fn download_zero_state(
         &self,
         block_id: &ton_block::BlockIdExt,
+    ) -> Result<(Arc<BlockHandle>, Arc<ShardStateStuff>)> {
+        self.download_zero_state_ext(
+            block_id,
+            DownloaderTimeouts {
+                initial: 10,
+                max: 3000,
+                multiplier: 1.2,
+            },
+        )
+        .await
+    }
+
+    async fn download_zero_state_ext(
+        &self,
+        block_id: &ton_block::BlockIdExt,
+        timeouts: DownloaderTimeouts,
     ) -> Result<(Arc<BlockHandle>, Arc<ShardStateStuff>)> {
         // Check if zero state was already downloaded
         let block_handle_storage = self.db.block_handle_storage();
@@ -606,11 +1276,7 @@ impl Engine {
                 Arc::new(ZeroStateDownloader),
                 block_id,
                 None,
-                Some(DownloaderTimeouts {
-                    initial: 10,
-                    max: 3000,
-                    multiplier: 1.2,
-                }),
+                Some(timeouts),
             )
             .download()
             .await?;
@@ -651,21 +1317,42 @@ impl Engine {
         .await
     }
 
-    async fn download_block(
+    /// Downloads the block with its proof or loads it from the storage.
+    ///
+    /// If `peer` is specified, the block is always requested from that peer
+    /// (useful for reproducing issues with the data served by a specific peer).
+    /// The peer could lack the block, so at most `PINNED_PEER_DOWNLOAD_ATTEMPTS`
+    /// attempts are made by default in that case
+    pub async fn download_block(
         &self,
         block_id: &ton_block::BlockIdExt,
         max_attempts: Option<u32>,
+        peer: Option<&adnl::NodeIdShort>,
     ) -> Result<(BlockStuffAug, BlockProofStuffAug)> {
+        if let Some(peer) = peer {
+            let client = self.get_rpc_client(block_id.shard_id.workchain_id())?;
+            let neighbour = client.neighbour(peer);
+            let max_attempts = max_attempts.unwrap_or(PINNED_PEER_DOWNLOAD_ATTEMPTS);
+            tracing::info!(block_id = %block_id.display(), peer_id = %peer, "downloading block from peer");
+            return self
+                .download_block_worker(
+                    block_id,
+                    Some(max_attempts),
+                    None,
+                    Some((client, &neighbour)),
+                )
+                .await;
+        }
+
         let db = &self.db;
 
         loop {
+            self.wait_for_disk_space(DiskSpaceLevel::Critical).await;
+
             if let Some(handle) = db.block_handle_storage().load_handle(block_id)? {
                 if handle.meta().has_data() {
                     let block = db.block_storage().load_block_data(&handle).await?;
-                    let block_proof = db
-                        .block_storage()
-                        .load_block_proof(&handle, !block_id.shard().is_masterchain())
-                        .await?;
+                    let block_proof = self.load_best_block_proof(&handle).await?;
 
                     return Ok((
                         WithArchiveData::loaded(block),
@@ -674,12 +1361,21 @@ impl Engine {
                 }
             }
 
+            // Use the block from broadcast if it was not stored
+            if let Some(data) = self
+                .broadcast_blocks_buffer
+                .as_ref()
+                .and_then(|buffer| buffer.take(block_id))
+            {
+                return Ok(data);
+            }
+
             if let Some(data) = self
                 .download_block_operations
                 .do_or_wait(
                     block_id,
                     None,
-                    self.download_block_worker(block_id, max_attempts, None),
+                    self.download_block_worker(block_id, max_attempts, None, None),
                 )
                 .await?
             {
@@ -714,8 +1410,55 @@ impl Engine {
         output: &mut (dyn Write + Send),
     ) -> Result<ArchiveDownloadStatus> {
         self.masterchain_client
-            .download_archive(mc_block_seq_no, neighbour, output)
+            .download_archive(
+                mc_block_seq_no,
+                neighbour,
+                self.network_options.max_archive_size,
+                output,
+            )
+            .await
+    }
+
+    /// Downloads the archive for the specified masterchain block from the peer.
+    ///
+    /// Returns the archive size or `None` if the peer doesn't have it
+    pub async fn download_archive_from_peer(
+        &self,
+        mc_block_seq_no: u32,
+        peer: &adnl::NodeIdShort,
+        output: &mut (dyn Write + Send),
+    ) -> Result<Option<usize>> {
+        let neighbour = self.masterchain_client.neighbour(peer);
+        tracing::info!(mc_block_seq_no, peer_id = %peer, "downloading archive from peer");
+        match self
+            .download_archive(mc_block_seq_no, Some(&neighbour), output)
+            .await?
+        {
+            ArchiveDownloadStatus::Downloaded { len, .. } => Ok(Some(len)),
+            ArchiveDownloadStatus::NotFound => Ok(None),
+        }
+    }
+
+    /// Returns stored block BOC without deserializing it
+    pub async fn get_raw_block_data(
+        &self,
+        block_id: &ton_block::BlockIdExt,
+    ) -> Result<Option<Vec<u8>>> {
+        let handle = match self.db.block_handle_storage().load_handle(block_id)? {
+            Some(handle) if handle.meta().has_data() => handle,
+            _ => return Ok(None),
+        };
+
+        self.db
+            .block_storage()
+            .load_block_data_raw(&handle)
             .await
+            .map(Some)
+    }
+
+    /// Returns block meta without creating the block handle
+    pub fn block_meta(&self, block_id: &ton_block::BlockIdExt) -> Result<Option<BriefBlockMeta>> {
+        self.db.block_handle_storage().load_meta(block_id)
     }
 
     pub async fn load_last_key_block(&self) -> Result<BlockStuff> {
@@ -837,10 +1580,46 @@ impl Engine {
         }
     }
 
+    /// Loads the full proof for masterchain blocks or the proof link for shard blocks
+    pub async fn load_best_block_proof(&self, handle: &BlockHandle) -> Result<BlockProofStuff> {
+        match self
+            .db
+            .block_storage()
+            .load_best_block_proof(handle)
+            .await?
+        {
+            Some(proof) => Ok(proof),
+            None => Err(EngineError::BlockProofNotFound.into()),
+        }
+    }
+
     pub async fn load_mc_zero_state(&self) -> Result<Arc<ShardStateStuff>> {
         self.load_state(&self.zero_state_id).await
     }
 
+    /// Returns the stored masterchain zero state from the global config
+    pub async fn mc_zero_state(&self) -> Result<Arc<ShardStateStuff>> {
+        self.zero_state(&self.zero_state_id).await
+    }
+
+    /// Returns the stored zero state of the masterchain or some workchain.
+    ///
+    /// NOTE: unlike `load_state`, fails for non-zero states and doesn't
+    /// try to download the missing state
+    pub async fn zero_state(
+        &self,
+        block_id: &ton_block::BlockIdExt,
+    ) -> Result<Arc<ShardStateStuff>> {
+        if block_id.seq_no != 0 {
+            return Err(EngineError::NotZeroState.into());
+        }
+
+        match self.db.block_handle_storage().load_meta(block_id)? {
+            Some(meta) if meta.flags().has_state => self.load_state(block_id).await,
+            _ => Err(EngineError::ZeroStateNotFound.into()),
+        }
+    }
+
     pub async fn load_state(
         &self,
         block_id: &ton_block::BlockIdExt,
@@ -855,20 +1634,75 @@ impl Engine {
         Ok(state)
     }
 
-    async fn store_state(
+    /// Returns the account state at the specified masterchain block along with
+    /// the Merkle proof of it against the root of the shard state.
+    ///
+    /// NOTE: the state of the shard block referenced by the masterchain block must be stored
+    pub async fn account_with_proof(
         &self,
-        handle: &Arc<BlockHandle>,
-        state: &Arc<ShardStateStuff>,
-    ) -> Result<()> {
-        self.shard_states_cache.set(handle.id(), || state.clone());
+        address: &ton_block::MsgAddressInt,
+        mc_block_id: &ton_block::BlockIdExt,
+    ) -> Result<(ton_block::ShardAccount, ton_block::MerkleProof)> {
+        if !mc_block_id.shard_id.is_masterchain() {
+            return Err(EngineError::NonMasterchainAccountProofBlock.into());
+        }
 
-        self.db
-            .shard_state_storage()
-            .store_state(handle, state.as_ref())
-            .await?;
+        let workchain = address.workchain_id();
+        let account = ton_types::UInt256::from_slice(&address.address().get_bytestring(0));
 
-        self.shard_states_operations
-            .do_or_wait(
+        let mc_state = self.load_state(mc_block_id).await?;
+        let state = if workchain == ton_block::MASTERCHAIN_ID {
+            mc_state
+        } else {
+            let mut shard_block_id = None;
+            mc_state.shards()?.iterate_shards(|ident, descr| {
+                if ident.workchain_id() != workchain || !shard_contains_account(&ident, &account) {
+                    return Ok(true);
+                }
+                shard_block_id = Some(ton_block::BlockIdExt {
+                    shard_id: ident,
+                    seq_no: descr.seq_no,
+                    root_hash: descr.root_hash,
+                    file_hash: descr.file_hash,
+                });
+                Ok(false)
+            })?;
+
+            let shard_block_id = shard_block_id.ok_or(EngineError::AccountShardNotFound)?;
+            self.load_state(&shard_block_id).await?
+        };
+
+        state.account_with_proof(&account)
+    }
+
+    /// Prefetches state cells up to the specified depth before the traversal.
+    ///
+    /// Returns the number of prefetched cells
+    pub async fn prefetch_state(
+        &self,
+        block_id: &ton_block::BlockIdExt,
+        depth: usize,
+    ) -> Result<usize> {
+        self.db
+            .shard_state_storage()
+            .prefetch_state(block_id, depth)
+            .await
+    }
+
+    async fn store_state(
+        &self,
+        handle: &Arc<BlockHandle>,
+        state: &Arc<ShardStateStuff>,
+    ) -> Result<()> {
+        self.shard_states_cache.set(handle.id(), || state.clone());
+
+        self.db
+            .shard_state_storage()
+            .store_state(handle, state.as_ref())
+            .await?;
+
+        self.shard_states_operations
+            .do_or_wait(
                 state.block_id(),
                 None,
                 futures_util::future::ok(state.clone()),
@@ -893,26 +1727,75 @@ impl Engine {
             .load_handle(&last_applied_mc_block_id)?
             .ok_or(EngineError::FailedToLoadLastMasterchainBlockHandle)?;
 
-        if last_mc_block_handle.meta().gen_utime() + 600 > now() {
-            return Ok(true);
-        }
-
-        Ok(false)
+        // NOTE: blocks from the future must not make the node look synced forever
+        let now = now();
+        let gen_utime = self.block_lag_utime(
+            &last_applied_mc_block_id.shard_id,
+            last_mc_block_handle.meta().gen_utime(),
+            now,
+        );
+        Ok(matches!(gen_utime, Some(gen_utime) if gen_utime + 600 > now))
     }
 
+    /// Marks the block as applied within the specified masterchain block.
+    ///
+    /// Idempotent: returns `Ok(false)` if the block was already applied with the same
+    /// masterchain ref seqno, and an error if it was applied with a different one
     async fn set_applied(&self, handle: &Arc<BlockHandle>, mc_seq_no: u32) -> Result<bool> {
+        self.set_applied_with(handle, mc_seq_no, Default::default())
+            .await
+    }
+
+    /// Marks the block as applied and writes the batch along with its handle
+    async fn set_applied_with(
+        &self,
+        handle: &Arc<BlockHandle>,
+        mc_seq_no: u32,
+        batch: rocksdb::WriteBatch,
+    ) -> Result<bool> {
+        // NOTE: the same block could be applied concurrently from several paths,
+        // so the ref seqno is checked even if the block is already applied
+        let check_mc_ref = || match handle.meta().masterchain_ref_seqno() {
+            prev if prev != 0 && prev != mc_seq_no => Err(EngineError::MasterchainRefMismatch {
+                block_id: handle.id().display().to_string(),
+                prev,
+                new: mc_seq_no,
+            }),
+            _ => Ok(()),
+        };
+
+        check_mc_ref()?;
         if handle.meta().is_applied() {
+            if handle.meta().masterchain_ref_seqno() == 0 && mc_seq_no != 0 {
+                // Handles applied by the previous versions could have no ref seqno
+                self.db
+                    .block_handle_storage()
+                    .assign_mc_ref_seq_no(handle, mc_seq_no)?;
+            }
             return Ok(false);
         }
-        self.db
+
+        if let Err(e) = self
+            .db
             .block_handle_storage()
-            .assign_mc_ref_seq_no(handle, mc_seq_no)?;
+            .assign_mc_ref_seq_no(handle, mc_seq_no)
+        {
+            // Different ref seqno was assigned concurrently
+            check_mc_ref()?;
+            return Err(e);
+        }
 
         if self.archive_options.is_some() {
             self.db.block_storage().move_into_archive(handle).await?;
         }
 
-        let applied = self.db.block_handle_storage().store_block_applied(handle)?;
+        let applied = self
+            .db
+            .block_handle_storage()
+            .store_block_applied_with(handle, batch)?;
+        if applied {
+            self.block_waiters.notify_applied(handle.id());
+        }
 
         if handle.id().shard_id.is_masterchain() {
             self.on_masterchain_block(handle).await?;
@@ -925,12 +1808,18 @@ impl Engine {
         if handle.is_key_block() {
             if let Some(blocks_gc) = &self.blocks_gc_state {
                 if blocks_gc.enabled.load(Ordering::Acquire) {
+                    self.audit_log.record(
+                        AuditLogEventKind::BlocksGc,
+                        Some(handle.id()),
+                        format!("{:?} on new key block", blocks_gc.ty),
+                    );
                     self.db
                         .block_storage()
                         .remove_outdated_blocks(
                             handle.id(),
                             blocks_gc.max_blocks_per_batch,
                             blocks_gc.ty,
+                            self.start_from_seqno,
                         )
                         .await?
                 }
@@ -947,22 +1836,140 @@ impl Engine {
         self.db.node_state().load_last_mc_block_id()
     }
 
-    fn store_last_applied_mc_block_id(&self, block_id: &ton_block::BlockIdExt) -> Result<()> {
-        self.db.node_state().store_last_mc_block_id(block_id)?;
+    /// Moves the last applied masterchain block pointer.
+    ///
+    /// NOTE: fails if the pointer moves back
+    fn store_last_applied_mc_block_id(
+        &self,
+        block_id: &ton_block::BlockIdExt,
+        reason: &str,
+    ) -> Result<()> {
+        let _guard = self.node_state_pointers_lock.lock();
+
+        let node_state = self.db.node_state();
+        let current = node_state.find_last_mc_block_id()?;
+        check_pointer_advance("last applied", current.as_ref(), block_id, reason)?;
+
+        node_state.store_last_mc_block_id(block_id)?;
+        self.audit_log.record(
+            AuditLogEventKind::LastAppliedMcBlock,
+            Some(block_id),
+            reason,
+        );
         self.metrics
             .last_mc_block_seqno
             .store(block_id.seq_no, Ordering::Release);
         Ok(())
     }
 
+    /// Loads at most `limit` latest chain progress decisions, newest first.
+    ///
+    /// NOTE: entries are written in background, so the latest ones could be missing
+    pub fn audit_log(&self, limit: usize) -> Result<Vec<AuditLogEntry>> {
+        self.db.audit_log().load(limit)
+    }
+
+    /// Number of audit log entries dropped because the write queue was full
+    pub fn audit_log_dropped(&self) -> u64 {
+        self.audit_log.dropped()
+    }
+
+    /// Statistics of all archives imported since the engine start
+    pub fn sync_stats(&self) -> SyncStats {
+        *self.sync_stats.lock()
+    }
+
+    /// Finds the transaction triggered by the inbound message with the specified hash.
+    ///
+    /// NOTE: returns an error if `index_messages` is disabled
+    pub fn find_transaction_by_message(
+        &self,
+        msg_hash: &ton_types::UInt256,
+    ) -> Result<Option<TransactionLocation>> {
+        if !self.index_messages {
+            return Err(EngineError::MessageIndexDisabled.into());
+        }
+        self.db.message_index().find(msg_hash)
+    }
+
     pub fn load_shards_client_mc_block_id(&self) -> Result<ton_block::BlockIdExt> {
         self.db.node_state().load_shards_client_mc_block_id()
     }
 
-    fn store_shards_client_mc_block_id(&self, block_id: &ton_block::BlockIdExt) -> Result<()> {
+    /// Moves the shards client back to the specified applied masterchain block.
+    ///
+    /// Shard blocks of all subsequent masterchain blocks are walked again,
+    /// missing ones are downloaded and applied. Used to recover from an inconsistent
+    /// shards client state.
+    pub fn reset_shards_client_to(&self, mc_block_id: &ton_block::BlockIdExt) -> Result<()> {
+        if !mc_block_id.shard().is_masterchain() {
+            return Err(EngineError::NonMasterchainShardsClientBlock.into());
+        }
+
+        let last_mc_seq_no = self.load_last_applied_mc_block_id()?.seq_no;
+        if mc_block_id.seq_no > last_mc_seq_no {
+            return Err(EngineError::InvalidShardsClientBlock.into());
+        }
+
+        // Shard blocks are applied on top of the states referenced by this block
+        match self.db.block_handle_storage().load_handle(mc_block_id)? {
+            Some(handle) if handle.meta().is_applied() && handle.meta().has_state() => {}
+            _ => return Err(EngineError::InvalidShardsClientBlock.into()),
+        }
+
+        tracing::warn!(
+            block_id = %mc_block_id.display(),
+            "requested shards client reset"
+        );
+        self.audit_log.record(
+            AuditLogEventKind::PointerRewind,
+            Some(mc_block_id),
+            "shards client reset requested",
+        );
+        self.shards_client_reset.request(mc_block_id.clone());
+        Ok(())
+    }
+
+    /// Moves the shards client pointer.
+    ///
+    /// NOTE: fails if the pointer moves back, use [`Engine::rewind_shards_client_mc_block_id`]
+    /// for the explicit reset
+    fn store_shards_client_mc_block_id(
+        &self,
+        block_id: &ton_block::BlockIdExt,
+        reason: &str,
+    ) -> Result<()> {
+        let _guard = self.node_state_pointers_lock.lock();
+
+        let current = self.db.node_state().find_shards_client_mc_block_id()?;
+        check_pointer_advance("shards client", current.as_ref(), block_id, reason)?;
+
+        self.write_shards_client_mc_block_id(block_id, reason)
+    }
+
+    /// Moves the shards client pointer to any masterchain block
+    fn rewind_shards_client_mc_block_id(
+        &self,
+        block_id: &ton_block::BlockIdExt,
+        reason: &str,
+    ) -> Result<()> {
+        let _guard = self.node_state_pointers_lock.lock();
+        self.write_shards_client_mc_block_id(block_id, reason)
+    }
+
+    fn write_shards_client_mc_block_id(
+        &self,
+        block_id: &ton_block::BlockIdExt,
+        reason: &str,
+    ) -> Result<()> {
         self.db
             .node_state()
             .store_shards_client_mc_block_id(block_id)?;
+        self.audit_log.record(
+            AuditLogEventKind::ShardsClientMcBlock,
+            Some(block_id),
+            reason,
+        );
         self.metrics
             .last_shard_client_mc_block_seqno
             .store(block_id.seq_no, Ordering::Release);
@@ -1049,7 +2056,7 @@ impl Engine {
                 .do_or_wait(
                     block_id,
                     None,
-                    self.download_block_worker(block_id, max_attempts, timeouts),
+                    self.download_block_worker(block_id, max_attempts, timeouts, None),
                 )
                 .await?
             {
@@ -1065,7 +2072,11 @@ impl Engine {
                     .handle;
                 let handle = db
                     .block_storage()
-                    .store_block_proof(&block_proof, handle.into())
+                    .store_checked_block_proof(
+                        &block_proof,
+                        handle.into(),
+                        info.proof_key_block_seqno,
+                    )
                     .await?
                     .handle;
 
@@ -1101,23 +2112,103 @@ impl Engine {
     }
 
     async fn notify_subscribers_with_status(&self, status: EngineStatus) {
-        for subscriber in &self.subscribers {
-            subscriber.engine_status_changed(status).await;
+        for entry in &self.subscribers {
+            entry.subscriber.engine_status_changed(status).await;
+        }
+    }
+
+    /// Starts notifications sequencing right after the last delivered blocks and
+    /// pushes the already applied blocks which were not delivered before the restart.
+    ///
+    /// NOTE: DBs without the stored position continue from the last block
+    /// processed by the shards client
+    async fn init_notification_sequencer(&self) -> Result<()> {
+        let sequencer = match &self.notification_sequencer {
+            Some(sequencer) => sequencer,
+            None => return Ok(()),
+        };
+
+        let position = match self.db.node_state().load_notified_top_blocks()? {
+            Some(position) => position,
+            None => {
+                let mc_block_id = self.load_shards_client_mc_block_id()?;
+                let block = self.load_mc_block_data(&mc_block_id).await?;
+                TopBlocks::from_mc_block(&block)?
+            }
+        };
+
+        let mut sequencer = sequencer.lock().await;
+        sequencer.reset(&position);
+
+        // NOTE: blocks are loaded in windows, so that only a bounded number
+        // of blocks and states is kept in memory
+        let mut undelivered = UndeliveredNotifications::new(self, &position)?;
+        let mut total = 0;
+        loop {
+            let window = undelivered
+                .load_window(self, NOTIFICATION_SEQUENCER_WINDOW)
+                .await?;
+            if window.is_empty() {
+                break;
+            }
+            total += window.len();
+
+            for item in window {
+                let result = if item.handle.id().shard_id.is_masterchain() {
+                    let top_blocks = TopBlocks::from_mc_block(&item.block)?;
+                    sequencer.push_mc_block(top_blocks, item)
+                } else {
+                    let block_id = item.handle.id().clone();
+                    sequencer.push_shard_block(block_id, item)
+                };
+                result.map_err(|e| self.on_notification_sequencer_error(e))?;
+                self.deliver_ready_notifications(&mut sequencer).await?;
+            }
         }
+
+        tracing::info!(
+            position = %position.mc_block.display(),
+            undelivered = total,
+            "restored notifications sequencer"
+        );
+        Ok(())
+    }
+
+    /// Loads the block with its state if it is applied
+    async fn load_applied_notification(
+        &self,
+        block_id: &ton_block::BlockIdExt,
+    ) -> Result<Option<PendingBlockNotification>> {
+        let handle = match self.db.block_handle_storage().load_handle(block_id)? {
+            Some(handle) if handle.meta().is_applied() => handle,
+            _ => return Ok(None),
+        };
+        let block = self.db.block_storage().load_block_data(&handle).await?;
+        let shard_state = self.load_state(block_id).await?;
+        Ok(Some(PendingBlockNotification::applied(
+            &handle,
+            &block,
+            &shard_state,
+        )))
     }
 
     async fn notify_subscribers_with_block(
         &self,
         handle: &Arc<BlockHandle>,
         block: &BlockStuff,
-        shard_state: &ShardStateStuff,
+        shard_state: &Arc<ShardStateStuff>,
+        mc_seq_no: u32,
     ) -> Result<()> {
         if self.subscribers.is_empty() {
             return Ok(());
         }
 
         let meta = handle.meta().brief();
-        let time_diff = now() as i64 - meta.gen_utime() as i64;
+        let now = now();
+        let lag_utime = self
+            .block_lag_utime(&handle.id().shard_id, meta.gen_utime(), now)
+            .unwrap_or_else(|| clamp_block_utime(meta.gen_utime(), None, now));
+        let time_diff = now as i64 - lag_utime as i64;
 
         let ctx = ProcessBlockContext {
             engine: self,
@@ -1137,67 +2228,154 @@ impl Engine {
                 .last_mc_utime
                 .store(meta.gen_utime(), Ordering::Release);
 
-            for subscriber in &self.subscribers {
-                subscriber.process_block(ctx).await?;
+            // Delay notification until all committed shard blocks are delivered
+            if let Some(sequencer) = &self.notification_sequencer {
+                let mut sequencer = sequencer.lock().await;
+                let top_blocks = TopBlocks::from_mc_block(block)?;
+                sequencer
+                    .push_mc_block(
+                        top_blocks,
+                        PendingBlockNotification::applied(handle, block, shard_state),
+                    )
+                    .map_err(|e| self.on_notification_sequencer_error(e))?;
+                return self.deliver_ready_notifications(&mut sequencer).await;
+            }
+
+            if !self.is_notified(mc_seq_no) {
+                return Ok(());
+            }
+            for entry in &self.subscribers {
+                entry
+                    .call(|subscriber| subscriber.process_block(ctx))
+                    .await?;
             }
         } else {
             self.metrics
                 .shard_client_time_diff
                 .store(time_diff, Ordering::Release);
 
-            for subscriber in &self.subscribers {
-                subscriber.process_block(ctx).await?;
+            // Delay notification until all shard blocks are applied
+            if let Some(pending) = &self.pending_shard_notifications {
+                pending.lock().entry(mc_seq_no).or_default().push(
+                    PendingBlockNotification::applied(handle, block, shard_state),
+                );
+                return Ok(());
+            }
+
+            if !self.is_notified(mc_seq_no) {
+                return Ok(());
+            }
+            for entry in &self.subscribers {
+                entry
+                    .call(|subscriber| subscriber.process_block(ctx))
+                    .await?;
             }
         }
 
         Ok(())
     }
 
-    async fn notify_subscribers_with_archive_block(
-        &self,
-        handle: &Arc<BlockHandle>,
-        block: &BlockStuff,
-        block_data: &[u8],
-        block_proof_data: &[u8],
-    ) -> Result<()> {
-        let meta = handle.meta().brief();
+    /// Notifies subscribers about the buffered shard blocks of the specified
+    /// masterchain block. Does nothing if `ordered_shard_notifications` is disabled.
+    ///
+    /// NOTE: must be called after all shard blocks of this masterchain block are applied
+    async fn flush_shard_notifications(&self, mc_seq_no: u32) -> Result<()> {
+        let pending = match &self.pending_shard_notifications {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
 
-        let ctx = ProcessBlockContext {
-            engine: self,
-            meta,
-            handle,
-            block,
-            shard_state: None,
-            block_data: Some(block_data),
-            block_proof_data: Some(block_proof_data),
+        let mut items = match pending.lock().remove(&mc_seq_no) {
+            Some(items) => items,
+            None => return Ok(()),
         };
+        items.sort_unstable_by_key(|item| shard_notification_order(item.handle.id()));
 
-        if handle.id().shard().is_masterchain() {
-            for subscriber in &self.subscribers {
-                subscriber.process_block(ctx).await?;
-            }
-        } else {
-            for subscriber in &self.subscribers {
-                subscriber.process_block(ctx).await?;
+        // NOTE: sequencer is always enabled together with the pending notifications
+        let sequencer = match &self.notification_sequencer {
+            Some(sequencer) => sequencer,
+            None => return Ok(()),
+        };
+        let mut sequencer = sequencer.lock().await;
+
+        let mut items = items.into_iter();
+        while let Some(item) = items.next() {
+            let block_id = item.handle.id().clone();
+            if let Err(e) = sequencer.push_shard_block(block_id, item) {
+                // Keep the rest of notifications for the next attempt
+                pending.lock().entry(mc_seq_no).or_default().extend(items);
+                return Err(self.on_notification_sequencer_error(e));
             }
         }
 
+        // NOTE: notifications which were not delivered stay in the sequencer
+        self.deliver_ready_notifications(&mut sequencer).await
+    }
+
+    /// Delivers all notifications which are allowed by the ordering contract.
+    ///
+    /// NOTE: the position of the delivered notifications is stored
+    /// so that the sequencer could be restored after the restart
+    async fn deliver_ready_notifications(
+        &self,
+        sequencer: &mut NotificationSequencer<PendingBlockNotification>,
+    ) -> Result<()> {
+        let node_state = self.db.node_state();
+        sequencer
+            .deliver_ready(
+                |item| self.notify_subscribers_with_pending(item),
+                |position| node_state.store_notified_top_blocks(position),
+            )
+            .await?;
+
+        if sequencer.pending_len() > 0 {
+            tracing::debug!(
+                pending = sequencer.pending_len(),
+                "waiting for out-of-order notifications"
+            );
+        }
+        Ok(())
+    }
+
+    async fn notify_subscribers_with_pending(&self, item: PendingBlockNotification) -> Result<()> {
+        // NOTE: blocks saved by the historical sync have no state and are always delivered
+        if item.shard_state.is_some() && !self.is_notified(item.handle.masterchain_ref_seqno()) {
+            return Ok(());
+        }
+
+        let ctx = item.context(self);
+        for entry in &self.subscribers {
+            entry
+                .call(|subscriber| subscriber.process_block(ctx))
+                .await?;
+        }
         Ok(())
     }
 
+    fn on_notification_sequencer_error(&self, e: NotificationSequencerError) -> anyhow::Error {
+        tracing::error!("subscriber notifications ordering violated: {e}");
+        e.into()
+    }
+
     async fn notify_subscribers_with_full_state(&self, state: &ShardStateStuff) -> Result<()> {
-        for subscriber in &self.subscribers {
-            subscriber.process_full_state(state).await?;
+        for entry in &self.subscribers {
+            entry
+                .call(|subscriber| subscriber.process_full_state(state))
+                .await?;
         }
         Ok(())
     }
 
     async fn check_block_proof(&self, block_proof: &BlockProofStuff) -> Result<BriefBlockInfo> {
         let block_handle_storage = self.db.block_handle_storage();
-        let block_storage = self.db.block_storage();
 
-        let (virt_block, virt_block_info) = block_proof.pre_check_block_proof()?;
-        let res = BriefBlockInfo::from(&virt_block_info);
+        let (virt_block, virt_block_info) = {
+            let block_proof = block_proof.clone();
+            self.blocking_pool
+                .run(move || block_proof.pre_check_block_proof())
+                .await?
+        };
+        let mut res = BriefBlockInfo::from(&virt_block_info);
 
         if block_proof.is_link() {
             // Nothing else to check for proof link
@@ -1211,33 +2389,70 @@ impl Engine {
                 .context("Failed to load prev key block handle")?
         };
 
+        // Skip signatures check if the proof was already checked against the same key block
+        let key_block_seqno = handle.id().seq_no;
+        if key_block_seqno == 0 || handle.meta().has_proof() {
+            if let Some(block_handle) = block_handle_storage.load_handle(block_proof.id())? {
+                if block_handle.meta().proof_checked_by() == Some(key_block_seqno) {
+                    res.proof_key_block_seqno = Some(key_block_seqno);
+                    return Ok(res);
+                }
+            }
+        }
+
+        self.metrics
+            .proof_signature_checks
+            .fetch_add(1, Ordering::Relaxed);
+
         if handle.id().seq_no == 0 {
             let zero_state = self
                 .load_mc_zero_state()
                 .await
                 .context("Failed to load mc zero state")?;
-            block_proof.check_with_master_state(&zero_state)?
+
+            let block_proof = block_proof.clone();
+            self.blocking_pool
+                .run(move || block_proof.check_with_master_state(&zero_state))
+                .await?;
+            res.proof_key_block_seqno = Some(key_block_seqno);
         } else {
-            let prev_key_block_proof = block_storage
-                .load_block_proof(&handle, false)
-                .await
-                .context("Failed to load prev key block proof")?;
-
-            if let Err(e) = check_with_prev_key_block_proof(
-                block_proof,
-                &prev_key_block_proof,
-                &virt_block,
-                &virt_block_info,
-            ) {
-                if !self.is_hard_fork(handle.id()) {
-                    return Err(e);
+            // NOTE: consecutive blocks are usually checked against the same key block
+            let prev_key_block_proof = match self.key_block_proofs_cache.get(handle.id()) {
+                Some(proof) => proof,
+                None => {
+                    self.metrics
+                        .key_block_proof_loads
+                        .fetch_add(1, Ordering::Relaxed);
+
+                    let proof = self
+                        .load_best_block_proof(&handle)
+                        .await
+                        .context("Failed to load prev key block proof")?;
+                    let proof = Arc::new(proof);
+                    self.key_block_proofs_cache
+                        .insert(handle.id().clone(), proof.clone());
+                    proof
                 }
+            };
+
+            let block_proof = block_proof.clone();
+            let result = self
+                .blocking_pool
+                .run(move || {
+                    check_with_prev_key_block_proof(
+                        &block_proof,
+                        &prev_key_block_proof,
+                        &virt_block,
+                        &virt_block_info,
+                    )
+                })
+                .await;
 
+            match result {
+                Ok(()) => res.proof_key_block_seqno = Some(key_block_seqno),
+                Err(e) if !self.is_hard_fork(handle.id()) => return Err(e),
                 // Allow invalid proofs for hard forks
-                tracing::warn!(
-                    block_id = %handle.id().display(),
-                    "received hard fork key block, ignoring proof",
-                );
+                Err(e) => self.on_hard_fork_accepted(handle.id(), "block proof check", &e),
             }
         }
 
@@ -1249,16 +2464,19 @@ impl Engine {
         block_id: &ton_block::BlockIdExt,
         max_attempts: Option<u32>,
         timeouts: Option<DownloaderTimeouts>,
+        pinned_neighbour: Option<(&NodeRpcClient, &Arc<Neighbour>)>,
     ) -> Result<(BlockStuffAug, BlockProofStuffAug)> {
-        self.create_download_context(
+        let mut context = self.create_download_context(
             "download_block",
             Arc::new(BlockDownloader),
             block_id,
             max_attempts,
             timeouts,
-        )
-        .download()
-        .await
+        );
+        if let Some((client, neighbour)) = pinned_neighbour {
+            context = context.with_pinned_neighbour(client, neighbour);
+        }
+        context.download().await
     }
 
     fn create_download_context<'a, T>(
@@ -1278,12 +2496,20 @@ impl Engine {
             db: self.db.as_ref(),
             downloader,
             explicit_neighbour: None,
+            pin_neighbour: false,
         }
     }
 }
 
 #[async_trait::async_trait]
 pub trait Subscriber: Send + Sync {
+    /// How to handle errors returned by this subscriber.
+    ///
+    /// NOTE: overridden by `subscriber_error_policy` in the node config. Default: `Fail`
+    fn error_policy(&self) -> SubscriberErrorPolicy {
+        SubscriberErrorPolicy::Fail
+    }
+
     async fn engine_status_changed(&self, status: EngineStatus) {
         let _unused_by_default = status;
     }
@@ -1297,6 +2523,96 @@ pub trait Subscriber: Send + Sync {
         let _unused_by_default = state;
         Ok(())
     }
+
+    /// Called after boot with the masterchain block from which the indexing started.
+    ///
+    /// NOTE: only called when `start_from_seqno` is specified in the config
+    async fn indexing_started_at(&self, block_id: &ton_block::BlockIdExt) {
+        let _unused_by_default = block_id;
+    }
+
+    /// Called for each config param which differs from the one in the previous key block.
+    /// `None` means that the param is absent in the config.
+    ///
+    /// NOTE: called after the key block is committed as applied (also for key blocks
+    /// saved by the historical sync). Errors are logged and don't stop block processing
+    async fn on_config_param_changed(
+        &self,
+        index: u32,
+        old: Option<&ton_block::ConfigParamEnum>,
+        new: Option<&ton_block::ConfigParamEnum>,
+    ) -> Result<()> {
+        let _unused_by_default = (index, old, new);
+        Ok(())
+    }
+
+    /// Called when the node stops processing blocks until the problem is resolved
+    async fn stall_detected(&self, report: &StallReport) {
+        let _unused_by_default = report;
+    }
+
+    /// Called once after the initial sync with the stats of all imported archives.
+    ///
+    /// NOTE: called before the `Synced` status is reported
+    async fn sync_completed(&self, stats: &SyncStats) {
+        let _unused_by_default = stats;
+    }
+}
+
+struct SubscriberEntry {
+    subscriber: Arc<dyn Subscriber>,
+    error_policy: SubscriberErrorPolicy,
+    error_count: AtomicU64,
+}
+
+impl SubscriberEntry {
+    /// `error_policy` overrides the policy of the subscriber (see [`Subscriber::error_policy`])
+    fn new(subscriber: Arc<dyn Subscriber>, error_policy: Option<SubscriberErrorPolicy>) -> Self {
+        Self {
+            error_policy: error_policy.unwrap_or_else(|| subscriber.error_policy()),
+            subscriber,
+            error_count: Default::default(),
+        }
+    }
+
+    async fn call<'a, F, R>(&'a self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&'a dyn Subscriber) -> R,
+        R: std::future::Future<Output = Result<()>> + 'a,
+    {
+        let mut attempt = 0;
+        loop {
+            let error = match f(self.subscriber.as_ref()).await {
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            };
+            self.error_count.fetch_add(1, Ordering::Release);
+
+            match self.error_policy {
+                SubscriberErrorPolicy::LogAndContinue => {
+                    tracing::error!("subscriber failed: {error:?}");
+                    return Ok(());
+                }
+                SubscriberErrorPolicy::Retry {
+                    retries,
+                    backoff_ms,
+                } if attempt < retries => {
+                    const MAX_BACKOFF_SHIFT: usize = 6;
+
+                    let backoff = Duration::from_millis(backoff_ms)
+                        * (1 << std::cmp::min(attempt, MAX_BACKOFF_SHIFT));
+                    attempt += 1;
+                    tracing::warn!(
+                        attempt,
+                        backoff_ms = backoff.as_millis() as u64,
+                        "subscriber failed, retrying: {error:?}"
+                    );
+                    tokio::time::sleep(backoff).await;
+                }
+                _ => return Err(error),
+            }
+        }
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -1310,7 +2626,7 @@ pub struct ProcessBlockContext<'a> {
     block_proof_data: Option<&'a [u8]>,
 }
 
-impl ProcessBlockContext<'_> {
+impl<'a> ProcessBlockContext<'a> {
     #[inline(always)]
     pub fn engine(&self) -> &Engine {
         self.engine
@@ -1356,14 +2672,17 @@ impl ProcessBlockContext<'_> {
         self.shard_state.is_none()
     }
 
-    pub async fn load_block_data(&self) -> Result<Vec<u8>> {
-        match self.block_data {
-            Some(data) => Ok(data.to_vec()),
+    /// Loads raw block data without copying it.
+    ///
+    /// NOTE: see [`BlockDataRef`] for the restrictions
+    pub async fn load_block_data(&self) -> Result<BlockDataRef<'a>> {
+        Ok(BlockDataRef(match self.block_data {
+            Some(data) => BlockDataRefInner::Archive(data),
             None => {
                 let block_storage = self.engine.db.block_storage();
-                block_storage.load_block_data_raw(self.handle).await
+                BlockDataRefInner::Stored(block_storage.load_block_data_raw_ref(self.handle).await?)
             }
-        }
+        }))
     }
 
     pub async fn load_block_proof(&self) -> Result<BlockProofStuff> {
@@ -1371,12 +2690,7 @@ impl ProcessBlockContext<'_> {
             Some(data) => {
                 BlockProofStuff::deserialize(self.handle.id().clone(), data, !self.is_masterchain())
             }
-            None => {
-                let block_storage = self.engine.db.block_storage();
-                block_storage
-                    .load_block_proof(self.handle, !self.is_masterchain())
-                    .await
-            }
+            None => self.engine.load_best_block_proof(self.handle).await,
         }
     }
 
@@ -1384,15 +2698,86 @@ impl ProcessBlockContext<'_> {
         match self.block_proof_data {
             Some(data) => Ok(data.to_vec()),
             None => {
+                let mut is_link = false;
+                self.handle.has_proof_or_link(&mut is_link);
+
                 let block_storage = self.engine.db.block_storage();
                 block_storage
-                    .load_block_proof_raw(self.handle, !self.is_masterchain())
+                    .load_block_proof_raw(self.handle, is_link)
                     .await
             }
         }
     }
 }
 
+/// Raw block data which is borrowed from the archive or pinned in the storage.
+///
+/// NOTE: the block data lock is held while the stored data is alive, so it must
+/// not be kept longer than the block processing, copy it with `to_vec` for that
+pub struct BlockDataRef<'a>(BlockDataRefInner<'a>);
+
+enum BlockDataRefInner<'a> {
+    Archive(&'a [u8]),
+    Stored(PinnedBlockData<'a>),
+}
+
+impl std::ops::Deref for BlockDataRef<'_> {
+    type Target = [u8];
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        match &self.0 {
+            BlockDataRefInner::Archive(data) => data,
+            BlockDataRefInner::Stored(data) => data.as_ref(),
+        }
+    }
+}
+
+impl AsRef<[u8]> for BlockDataRef<'_> {
+    #[inline]
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+/// Ensures that the node state pointer doesn't move back
+fn check_pointer_advance(
+    pointer: &'static str,
+    current: Option<&ton_block::BlockIdExt>,
+    new: &ton_block::BlockIdExt,
+    reason: &str,
+) -> Result<(), EngineError> {
+    match current {
+        Some(current) if current.seq_no > new.seq_no => {
+            tracing::error!(
+                pointer,
+                current = %current.display(),
+                new = %new.display(),
+                reason,
+                "rejected node state pointer regression"
+            );
+            Err(EngineError::PointerRegression {
+                pointer,
+                current: current.seq_no,
+                new: new.seq_no,
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+fn shard_contains_account(shard: &ton_block::ShardIdent, account: &ton_types::UInt256) -> bool {
+    let mut account_prefix = [0; 8];
+    account_prefix.copy_from_slice(&account.as_slice()[..8]);
+    let account_prefix = u64::from_be_bytes(account_prefix);
+
+    // Compare only bits before the shard tag
+    let prefix = shard.shard_prefix_with_tag();
+    let tag = prefix & prefix.wrapping_neg();
+    let mask = !(tag | (tag - 1));
+    (prefix ^ account_prefix) & mask == 0
+}
+
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct EngineMetrics {
     pub last_mc_block_seqno: AtomicU32,
@@ -1400,6 +2785,31 @@ pub struct EngineMetrics {
     pub last_mc_utime: AtomicU32,
     pub mc_time_diff: AtomicI64,
     pub shard_client_time_diff: AtomicI64,
+    /// Number of block broadcasts dropped due to the full buffer
+    pub dropped_broadcasts: AtomicU64,
+    /// Number of block broadcasts kept in memory instead of the storage
+    pub buffered_broadcasts: AtomicU64,
+    /// Number of block broadcasts skipped (or dropped from the memory)
+    /// due to the store policy
+    pub skipped_broadcasts: AtomicU64,
+    /// Number of block proofs checked against key block signatures
+    pub proof_signature_checks: AtomicU64,
+    /// Number of key block proofs loaded from disk to check block proofs
+    pub key_block_proof_loads: AtomicU64,
+    /// Whether the shard client imports blocks from archives instead of
+    /// downloading them one by one
+    pub shard_client_archives_mode: AtomicBool,
+    /// Available space on the DB volume (only when disk watermarks are configured)
+    pub available_disk_space: AtomicU64,
+    /// Number of applied blocks with generation time far ahead of the local time
+    /// or before the previous block
+    pub skewed_blocks: AtomicU64,
+    /// Number of archives which are being downloaded or wait for the import
+    pub pending_archives: AtomicU64,
+    /// Number of prefetched archives dropped due to the full archives queue
+    pub evicted_archives: AtomicU64,
+    /// Number of hard fork blocks accepted despite the failed proof check
+    pub accepted_hard_forks: AtomicU64,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone, Copy)]
@@ -1409,6 +2819,11 @@ pub struct InternalEngineMetrics {
     pub block_applying_operations_len: usize,
     pub next_block_applying_operations_len: usize,
     pub download_block_operations_len: usize,
+    /// Number of block broadcasts kept in memory due to the store policy
+    pub buffered_broadcasts_len: usize,
+    pub memory_budget: MemoryBudgetMetrics,
+    /// Number of shard blocks being applied by the shard client or the archives import
+    pub shard_blocks_applying: usize,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -1421,4 +2836,417 @@ enum EngineError {
     TooDeepRecursion,
     #[error("Overlay not found")]
     OverlayNotFound,
+    #[error("Unknown column family: {0}")]
+    UnknownColumn(String),
+    #[error("Account proof must be requested for the masterchain block")]
+    NonMasterchainAccountProofBlock,
+    #[error("Shard for the account not found")]
+    AccountShardNotFound,
+    #[error("Block proof not found")]
+    BlockProofNotFound,
+    #[error("Block not found")]
+    BlockNotFound,
+    #[error("Block is not a zero state")]
+    NotZeroState,
+    #[error("Zero state not found")]
+    ZeroStateNotFound,
+    #[error(
+        "Block {block_id} is already applied within a different masterchain block (prev: {prev}, new: {new})"
+    )]
+    MasterchainRefMismatch {
+        block_id: String,
+        prev: u32,
+        new: u32,
+    },
+    #[error("Message index is disabled")]
+    MessageIndexDisabled,
+    #[error("Shards client can only be reset to the masterchain block")]
+    NonMasterchainShardsClientBlock,
+    #[error("Shards client can only be reset to the applied masterchain block with state")]
+    InvalidShardsClientBlock,
+    #[error(
+        "DB belongs to a different network (stored zero state root hash: {stored}, global config zero state root hash: {expected})"
+    )]
+    NetworkMismatch { stored: String, expected: String },
+    #[error(
+        "Indexing start can't be moved backwards for the existing DB (stored: {stored}, new: {new:?}). Use a fresh DB to index older blocks"
+    )]
+    StartSeqnoLowered { stored: u32, new: Option<u32> },
+    #[error("The {pointer} pointer can't move back (current seqno: {current}, new seqno: {new})")]
+    PointerRegression {
+        pointer: &'static str,
+        current: u32,
+        new: u32,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::*;
+
+    #[test]
+    fn node_state_pointers_only_advance() {
+        let id = mc_block_id;
+
+        // Initial store
+        assert!(check_pointer_advance("test", None, &id(10), "boot").is_ok());
+        // Same or next block
+        assert!(check_pointer_advance("test", Some(&id(10)), &id(10), "applied").is_ok());
+        assert!(check_pointer_advance("test", Some(&id(10)), &id(11), "applied").is_ok());
+        // Regression
+        assert!(matches!(
+            check_pointer_advance("test", Some(&id(10)), &id(9), "applied"),
+            Err(EngineError::PointerRegression {
+                current: 10,
+                new: 9,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn engine_rejects_pointer_regression() {
+        let dir = TempDir::new("engine_pointer_regression");
+        let engine = test_engine(&dir, Vec::new()).await;
+        let node_state = engine.db.node_state();
+
+        node_state.store_last_mc_block_id(&mc_block_id(10)).unwrap();
+        node_state
+            .store_shards_client_mc_block_id(&mc_block_id(10))
+            .unwrap();
+
+        let is_regression = |result: Result<()>| {
+            matches!(
+                result.unwrap_err().downcast::<EngineError>().unwrap(),
+                EngineError::PointerRegression {
+                    current: 10,
+                    new: 9,
+                    ..
+                }
+            )
+        };
+
+        assert!(is_regression(
+            engine.store_last_applied_mc_block_id(&mc_block_id(9), "test")
+        ));
+        assert!(is_regression(
+            engine.store_shards_client_mc_block_id(&mc_block_id(9), "test")
+        ));
+        assert_eq!(node_state.load_last_mc_block_id().unwrap(), mc_block_id(10));
+        assert_eq!(
+            node_state.load_shards_client_mc_block_id().unwrap(),
+            mc_block_id(10)
+        );
+
+        // Pointers still advance
+        engine
+            .store_last_applied_mc_block_id(&mc_block_id(11), "test")
+            .unwrap();
+        engine
+            .store_shards_client_mc_block_id(&mc_block_id(11), "test")
+            .unwrap();
+        assert_eq!(node_state.load_last_mc_block_id().unwrap(), mc_block_id(11));
+        assert_eq!(
+            node_state.load_shards_client_mc_block_id().unwrap(),
+            mc_block_id(11)
+        );
+    }
+
+    #[tokio::test]
+    async fn start_from_seqno_is_never_lowered() {
+        let dir = TempDir::new("start_from_seqno");
+        let db = test_db(&dir).await;
+        let node_state = db.node_state();
+
+        // Empty DB accepts any value
+        check_start_from_seqno(&db, Some(1000)).unwrap();
+        check_start_from_seqno(&db, Some(500)).unwrap();
+        assert_eq!(node_state.load_start_from_seqno().unwrap(), Some(500));
+        check_start_from_seqno(&db, None).unwrap();
+        assert_eq!(node_state.load_start_from_seqno().unwrap(), Some(500));
+
+        // Synced DB
+        node_state
+            .store_last_mc_block_id(&mc_block_id(2000))
+            .unwrap();
+
+        check_start_from_seqno(&db, Some(500)).unwrap();
+        check_start_from_seqno(&db, Some(600)).unwrap();
+        assert_eq!(node_state.load_start_from_seqno().unwrap(), Some(600));
+
+        for new in [Some(599), None] {
+            assert!(matches!(
+                check_start_from_seqno(&db, new)
+                    .unwrap_err()
+                    .downcast::<EngineError>()
+                    .unwrap(),
+                EngineError::StartSeqnoLowered { stored: 600, .. }
+            ));
+        }
+        assert_eq!(node_state.load_start_from_seqno().unwrap(), Some(600));
+    }
+
+    #[test]
+    fn shard_notifications_order() {
+        let id = |shard: u64, seq_no: u32| {
+            block_id(
+                ton_block::ShardIdent::with_tagged_prefix(0, shard).unwrap(),
+                seq_no,
+            )
+        };
+
+        // Parent shard at seqno 10 was split into two shards
+        let mut ids = vec![
+            id(0xc000000000000000, 12),
+            id(0x4000000000000000, 11),
+            id(0x8000000000000000, 10),
+            id(0xc000000000000000, 11),
+            id(0x4000000000000000, 12),
+        ];
+        ids.sort_unstable_by_key(shard_notification_order);
+
+        let ids = ids
+            .iter()
+            .map(|id| (id.shard_id.shard_prefix_with_tag(), id.seq_no))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ids,
+            [
+                (0x8000000000000000, 10),
+                (0x4000000000000000, 11),
+                (0xc000000000000000, 11),
+                (0x4000000000000000, 12),
+                (0xc000000000000000, 12),
+            ]
+        );
+    }
+
+    #[test]
+    fn account_shards() {
+        let shard = |prefix: u64| ton_block::ShardIdent::with_tagged_prefix(0, prefix).unwrap();
+        let account = |first_byte: u8| {
+            let mut account = [0x55; 32];
+            account[0] = first_byte;
+            ton_types::UInt256::from_slice(&account)
+        };
+
+        // Full shard contains all accounts
+        assert!(shard_contains_account(
+            &shard(0x8000000000000000),
+            &account(0x00)
+        ));
+        assert!(shard_contains_account(
+            &shard(0x8000000000000000),
+            &account(0xff)
+        ));
+
+        // Split shards
+        assert!(shard_contains_account(
+            &shard(0x4000000000000000),
+            &account(0x7f)
+        ));
+        assert!(!shard_contains_account(
+            &shard(0x4000000000000000),
+            &account(0x80)
+        ));
+        assert!(shard_contains_account(
+            &shard(0xc000000000000000),
+            &account(0x80)
+        ));
+        assert!(!shard_contains_account(
+            &shard(0xc000000000000000),
+            &account(0x7f)
+        ));
+
+        // Deeper split
+        assert!(shard_contains_account(
+            &shard(0xa000000000000000),
+            &account(0xb0)
+        ));
+        assert!(!shard_contains_account(
+            &shard(0xa000000000000000),
+            &account(0xc0)
+        ));
+        assert!(shard_contains_account(
+            &shard(0xa000000000000000),
+            &account(0x80)
+        ));
+        assert!(!shard_contains_account(
+            &shard(0xa000000000000000),
+            &account(0x70)
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn subscriber_error_policies() {
+        use std::sync::atomic::AtomicUsize;
+
+        struct FlakySubscriber {
+            failures: usize,
+            calls: AtomicUsize,
+        }
+
+        #[async_trait::async_trait]
+        impl Subscriber for FlakySubscriber {
+            async fn on_config_param_changed(
+                &self,
+                _: u32,
+                _: Option<&ton_block::ConfigParamEnum>,
+                _: Option<&ton_block::ConfigParamEnum>,
+            ) -> Result<()> {
+                if self.calls.fetch_add(1, Ordering::Relaxed) < self.failures {
+                    anyhow::bail!("flaky subscriber");
+                }
+                Ok(())
+            }
+        }
+
+        let call = |failures: usize, error_policy: SubscriberErrorPolicy| async move {
+            let subscriber = Arc::new(FlakySubscriber {
+                failures,
+                calls: Default::default(),
+            });
+            let entry = SubscriberEntry::new(subscriber.clone(), Some(error_policy));
+
+            let started_at = tokio::time::Instant::now();
+            let result = entry
+                .call(|subscriber| subscriber.on_config_param_changed(0, None, None))
+                .await;
+
+            assert_eq!(
+                entry.error_count.load(Ordering::Acquire) as usize,
+                std::cmp::min(failures, subscriber.calls.load(Ordering::Relaxed))
+            );
+            (
+                result.is_ok(),
+                subscriber.calls.load(Ordering::Relaxed),
+                started_at.elapsed(),
+            )
+        };
+
+        // Errors are propagated by default
+        let (ok, calls, _) = call(1, SubscriberErrorPolicy::Fail).await;
+        assert!(!ok);
+        assert_eq!(calls, 1);
+
+        // Errors are only counted
+        let (ok, calls, elapsed) = call(1, SubscriberErrorPolicy::LogAndContinue).await;
+        assert!(ok);
+        assert_eq!(calls, 1);
+        assert_eq!(elapsed, Duration::ZERO);
+
+        // Subscriber is called again after the doubled backoff
+        let retry = SubscriberErrorPolicy::Retry {
+            retries: 2,
+            backoff_ms: 100,
+        };
+        let (ok, calls, elapsed) = call(2, retry).await;
+        assert!(ok);
+        assert_eq!(calls, 3);
+        assert_eq!(elapsed, Duration::from_millis(100 + 200));
+
+        // Error is propagated when retries are exhausted
+        let (ok, calls, _) = call(3, retry).await;
+        assert!(!ok);
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn subscriber_error_policy_override() {
+        struct DefaultSubscriber;
+
+        #[async_trait::async_trait]
+        impl Subscriber for DefaultSubscriber {}
+
+        let entry = SubscriberEntry::new(Arc::new(DefaultSubscriber), None);
+        assert_eq!(entry.error_policy, SubscriberErrorPolicy::Fail);
+
+        let entry = SubscriberEntry::new(
+            Arc::new(DefaultSubscriber),
+            Some(SubscriberErrorPolicy::LogAndContinue),
+        );
+        assert_eq!(entry.error_policy, SubscriberErrorPolicy::LogAndContinue);
+
+        // Config representation
+        let policy: SubscriberErrorPolicy =
+            serde_json::from_str(r#"{"type":"retry","retries":3,"backoff_ms":500}"#).unwrap();
+        assert_eq!(
+            policy,
+            SubscriberErrorPolicy::Retry {
+                retries: 3,
+                backoff_ms: 500
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn undelivered_notifications_are_loaded_in_windows() {
+        use ton_block::Serializable;
+
+        use crate::test_util::SyntheticChain;
+
+        const CHAIN_LEN: u32 = 10;
+        const WINDOW: usize = 4;
+
+        let now = broxus_util::now();
+        let zero_state_utime = now - 100;
+        let chain = SyntheticChain::generate(CHAIN_LEN, zero_state_utime, |seq_no| {
+            zero_state_utime + seq_no
+        })
+        .unwrap();
+
+        let dir = TempDir::new("undelivered_notifications");
+        chain.create_db(dir.path()).await.unwrap();
+        let engine = test_engine_with_global_config(&dir, chain.global_config(), Vec::new()).await;
+
+        // Synthetic states of all applied blocks
+        let shard_state_storage = engine.db.shard_state_storage();
+        for block in chain.shard_blocks.iter().chain(&chain.mc_blocks) {
+            let mut state = ton_block::ShardStateUnsplit::with_ident(block.id.shard_id);
+            state.set_seq_no(block.id.seq_no);
+            let state = ShardStateStuff::new(
+                block.id.clone(),
+                state.serialize().unwrap(),
+                shard_state_storage.min_ref_mc_state(),
+            )
+            .unwrap();
+            let handle = engine
+                .db
+                .block_handle_storage()
+                .load_handle(&block.id)
+                .unwrap()
+                .unwrap();
+            shard_state_storage
+                .store_state(&handle, &state)
+                .await
+                .unwrap();
+        }
+
+        // Nothing was delivered after the zerostate
+        let position = TopBlocks {
+            mc_block: chain.mc_zero_state.id.clone(),
+            shard_heights: [(chain.wc_zero_state.id.shard_id, 0)].into_iter().collect(),
+        };
+        let mut undelivered = UndeliveredNotifications::new(&engine, &position).unwrap();
+
+        let mut loaded = Vec::new();
+        loop {
+            let window = undelivered.load_window(&engine, WINDOW).await.unwrap();
+            if window.is_empty() {
+                break;
+            }
+            assert!(window.len() <= WINDOW);
+            loaded.extend(window.into_iter().map(|item| item.handle.id().clone()));
+        }
+
+        // Shard blocks go first, all blocks are loaded once in order
+        let expected = chain
+            .shard_blocks
+            .iter()
+            .chain(&chain.mc_blocks)
+            .map(|block| block.id.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(loaded, expected);
+    }
 }
@@ -3,11 +3,13 @@
 /// Changes:
 /// - replaced old `failure` crate with `anyhow`
 ///
+use std::future::Future;
 use std::io::Write;
 use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::{Context, Result};
+use everscale_network::adnl;
 
 use crate::network::{Neighbour, OverlayClient};
 use crate::proto;
@@ -17,6 +19,10 @@ use crate::utils::*;
 pub struct NodeRpcClient(pub Arc<OverlayClient>);
 
 impl NodeRpcClient {
+    pub fn neighbour(&self, peer_id: &adnl::NodeIdShort) -> Arc<Neighbour> {
+        self.0.neighbours().get_or_create(peer_id)
+    }
+
     pub fn broadcast_external_message(&self, message: &[u8]) {
         let this = &self.0;
 
@@ -203,6 +209,7 @@ impl NodeRpcClient {
     pub async fn download_block_full(
         &self,
         block_id: &ton_block::BlockIdExt,
+        neighbour: Option<&Arc<Neighbour>>,
     ) -> Result<Option<(BlockStuffAug, BlockProofStuffAug)>> {
         let this = &self.0;
 
@@ -214,7 +221,7 @@ impl NodeRpcClient {
                 },
                 Some(1),
                 None,
-                None,
+                neighbour,
             )
             .await?;
 
@@ -328,10 +335,14 @@ impl NodeRpcClient {
             })
     }
 
+    /// Downloads the archive with the specified masterchain block.
+    ///
+    /// Fails and penalizes the neighbour if the archive is larger than `max_archive_size`
     pub async fn download_archive(
         &self,
         masterchain_seqno: u32,
         neighbour: Option<&Arc<Neighbour>>,
+        max_archive_size: u64,
         output: &mut (dyn Write + Send),
     ) -> Result<ArchiveDownloadStatus> {
         const CHUNK_SIZE: u32 = 1 << 21; // 2 MB
@@ -356,71 +367,38 @@ impl NodeRpcClient {
             }
         };
 
-        let mut verifier = ArchivePackageVerifier::Start;
-
-        let mut offset = 0;
-        let mut part_attempt = 0;
-        let mut peer_attempt = 0;
-        loop {
-            match tokio::time::timeout(
-                Duration::from_secs(10),
+        let slice_neighbour = neighbour.clone();
+        let result = download_archive_slices(
+            archive_id,
+            CHUNK_SIZE,
+            max_archive_size,
+            output,
+            move |offset, peer_attempt| {
                 this.send_rldp_query_raw(
-                    neighbour.clone(),
+                    slice_neighbour.clone(),
                     proto::RpcGetArchiveSlice {
                         archive_id,
                         offset,
                         max_size: CHUNK_SIZE,
                     },
                     peer_attempt,
-                ),
-            )
-            .await
-            {
-                Ok(Ok(chunk)) => {
-                    let is_last = chunk.len() < CHUNK_SIZE as usize;
-
-                    verifier
-                        .verify(&chunk)
-                        .context("Received invalid archive chunk")?;
-                    if is_last {
-                        verifier.final_check().context("Received invalid archive")?;
-                    }
-
-                    output
-                        .write_all(&chunk)
-                        .context("Failed to write archive chunk")?;
-
-                    if is_last {
-                        return Ok(ArchiveDownloadStatus::Downloaded {
-                            neighbour,
-                            len: chunk.len(),
-                        });
-                    }
-
-                    offset += chunk.len() as u64;
-                    part_attempt = 0;
-                }
-                Ok(Err(e)) => {
-                    peer_attempt += 1;
-                    part_attempt += 1;
-                    tracing::error!(
+                )
+            },
+        )
+        .await;
+
+        match result {
+            Ok(len) => Ok(ArchiveDownloadStatus::Downloaded { neighbour, len }),
+            Err(e) => {
+                if let Some(NodeRpcClientError::ArchiveTooLarge { .. }) = e.downcast_ref() {
+                    tracing::warn!(
                         archive_id,
-                        offset,
-                        part_attempt,
-                        "Failed to download archive: {e:?}",
+                        peer_id = %neighbour.peer_id(),
+                        "neighbour sent too large archive"
                     );
-
-                    if part_attempt > 2 {
-                        return Err(NodeRpcClientError::TooManyFailedAttempts.into());
-                    }
-                }
-                Err(_) => {
-                    peer_attempt += 1;
-                    part_attempt += 1;
-                    if part_attempt > 2 {
-                        return Err(NodeRpcClientError::RequestTimeout.into());
-                    }
+                    neighbour.penalize_fully();
                 }
+                Err(e)
             }
         }
     }
@@ -432,6 +410,82 @@ impl NodeRpcClient {
     }
 }
 
+/// Downloads archive slices and writes them into the output while checking the archive.
+///
+/// Returns the total size of the archive
+async fn download_archive_slices<F, R>(
+    archive_id: u64,
+    chunk_size: u32,
+    max_archive_size: u64,
+    output: &mut (dyn Write + Send),
+    mut fetch_slice: F,
+) -> Result<usize>
+where
+    F: FnMut(u64, u32) -> R,
+    R: Future<Output = Result<Vec<u8>>>,
+{
+    let mut verifier = ArchivePackageVerifier::Start;
+
+    let mut offset = 0;
+    let mut part_attempt = 0;
+    let mut peer_attempt = 0;
+    loop {
+        match tokio::time::timeout(Duration::from_secs(10), fetch_slice(offset, peer_attempt)).await
+        {
+            Ok(Ok(chunk)) => {
+                let is_last = chunk.len() < chunk_size as usize;
+
+                // NOTE: size is checked before the data is written anywhere
+                if offset + chunk.len() as u64 > max_archive_size {
+                    return Err(NodeRpcClientError::ArchiveTooLarge {
+                        max_size: max_archive_size,
+                    }
+                    .into());
+                }
+
+                verifier
+                    .verify(&chunk)
+                    .context("Received invalid archive chunk")?;
+                if is_last {
+                    verifier.final_check().context("Received invalid archive")?;
+                }
+
+                output
+                    .write_all(&chunk)
+                    .context("Failed to write archive chunk")?;
+
+                offset += chunk.len() as u64;
+                if is_last {
+                    return Ok(offset as usize);
+                }
+
+                part_attempt = 0;
+            }
+            Ok(Err(e)) => {
+                peer_attempt += 1;
+                part_attempt += 1;
+                tracing::error!(
+                    archive_id,
+                    offset,
+                    part_attempt,
+                    "Failed to download archive: {e:?}",
+                );
+
+                if part_attempt > 2 {
+                    return Err(NodeRpcClientError::TooManyFailedAttempts.into());
+                }
+            }
+            Err(_) => {
+                peer_attempt += 1;
+                part_attempt += 1;
+                if part_attempt > 2 {
+                    return Err(NodeRpcClientError::RequestTimeout.into());
+                }
+            }
+        }
+    }
+}
+
 #[derive(Clone)]
 pub enum ArchiveDownloadStatus {
     Downloaded {
@@ -456,4 +510,77 @@ enum NodeRpcClientError {
     RequestTimeout,
     #[error("Failed to get key blocks")]
     KeyBlocksError,
+    #[error("Archive is larger than {max_size} bytes")]
+    ArchiveTooLarge { max_size: u64 },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::*;
+
+    fn make_archive() -> Vec<u8> {
+        let mut archive = ARCHIVE_PREFIX.to_vec();
+        for seq_no in 1..10u32 {
+            let id = PackageEntryId::Block(mc_block_id(seq_no));
+            archive.extend(make_archive_segment(
+                &id.to_filename(),
+                &[seq_no as u8; 100],
+            ));
+        }
+        archive
+    }
+
+    fn mock_peer(
+        archive: &[u8],
+        chunk_size: u32,
+    ) -> impl FnMut(u64, u32) -> futures_util::future::Ready<Result<Vec<u8>>> + '_ {
+        move |offset, _| {
+            let offset = std::cmp::min(offset as usize, archive.len());
+            let end = std::cmp::min(offset + chunk_size as usize, archive.len());
+            futures_util::future::ready(Ok(archive[offset..end].to_vec()))
+        }
+    }
+
+    #[tokio::test]
+    async fn archive_within_limit() {
+        let archive = make_archive();
+
+        let mut output = Vec::new();
+        let len = download_archive_slices(
+            1,
+            128,
+            archive.len() as u64,
+            &mut output,
+            mock_peer(&archive, 128),
+        )
+        .await
+        .unwrap();
+        assert_eq!(len, archive.len());
+        assert_eq!(output, archive);
+    }
+
+    #[tokio::test]
+    async fn oversized_archive_is_rejected() {
+        let archive = make_archive();
+        let max_archive_size = archive.len() as u64 / 2;
+
+        let mut output = Vec::new();
+        let e = download_archive_slices(
+            1,
+            128,
+            max_archive_size,
+            &mut output,
+            mock_peer(&archive, 128),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(NodeRpcClientError::ArchiveTooLarge { .. })
+        ));
+
+        // Data after the limit is not written
+        assert!(output.len() as u64 <= max_archive_size);
+    }
 }
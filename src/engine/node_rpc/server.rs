@@ -43,13 +43,26 @@ impl NodeRpcServer {
             None => Err(NodeRpcServerError::EngineDropped.into()),
         }
     }
-}
 
-#[async_trait::async_trait]
-impl QuerySubscriber for NodeRpcServer {
-    async fn try_consume_query<'a>(
+    /// Handles the serialized boxed query without the overlay context
+    #[cfg(any(test, feature = "mock-network"))]
+    pub async fn handle_query(&self, query: &[u8]) -> Result<Option<Vec<u8>>> {
+        let constructor = match query.get(..4) {
+            Some(constructor) => u32::from_le_bytes(constructor.try_into().unwrap()),
+            None => return Ok(None),
+        };
+
+        match self
+            .consume_query(constructor, Cow::Borrowed(query))
+            .await?
+        {
+            QueryConsumingResult::Consumed(answer) => Ok(answer),
+            QueryConsumingResult::Rejected(_) => Ok(None),
+        }
+    }
+
+    async fn consume_query<'a>(
         &self,
-        _: SubscriberContext<'a>,
         constructor: u32,
         query: Cow<'a, [u8]>,
     ) -> Result<QueryConsumingResult<'a>> {
@@ -91,6 +104,7 @@ impl QuerySubscriber for NodeRpcServer {
             RpcDownloadKeyBlockProofLink => download_key_block_proof_link => answer_raw,
             RpcGetArchiveInfo => get_archive_info => answer,
             RpcGetArchiveSlice => get_archive_slice => answer_raw,
+            RpcDownloadZeroState => download_zero_state => answer_raw,
             _ => {
                 proto::RpcGetCapabilities::TL_ID => {
                     Ok(QueryConsumingResult::Consumed(Some(tl_proto::serialize(
@@ -106,6 +120,18 @@ impl QuerySubscriber for NodeRpcServer {
     }
 }
 
+#[async_trait::async_trait]
+impl QuerySubscriber for NodeRpcServer {
+    async fn try_consume_query<'a>(
+        &self,
+        _: SubscriberContext<'a>,
+        constructor: u32,
+        query: Cow<'a, [u8]>,
+    ) -> Result<QueryConsumingResult<'a>> {
+        self.consume_query(constructor, query).await
+    }
+}
+
 struct QueryHandler(Arc<Engine>);
 
 impl QueryHandler {
@@ -154,10 +180,16 @@ impl QueryHandler {
 
     async fn prepare_zero_state(
         self,
-        _: proto::RpcPrepareZeroState,
+        query: proto::RpcPrepareZeroState,
     ) -> Result<proto::PreparedState> {
-        // TODO: implement
-        Ok(proto::PreparedState::NotFound)
+        let block_handle_storage = self.0.db.block_handle_storage();
+
+        Ok(match block_handle_storage.load_handle(&query.block)? {
+            Some(handle) if query.block.seq_no == 0 && handle.meta().has_state() => {
+                proto::PreparedState::Found
+            }
+            _ => proto::PreparedState::NotFound,
+        })
     }
 
     async fn get_next_key_block_ids(
@@ -178,24 +210,26 @@ impl QueryHandler {
 
             let mut iterator = block_handle_storage
                 .key_blocks_iterator(KeyBlocksDirection::ForwardFrom(start_block_id.seq_no))
-                .take(limit)
                 .peekable();
 
             if let Some(Ok(id)) = iterator.peek() {
-                if id.root_hash != start_block_id.root_hash() {
-                    return Err(NodeRpcServerError::InvalidRootHash.into());
-                }
-                if id.file_hash != start_block_id.file_hash() {
-                    return Err(NodeRpcServerError::InvalidFileHash.into());
+                if id.seq_no == start_block_id.seq_no {
+                    if id.root_hash != start_block_id.root_hash() {
+                        return Err(NodeRpcServerError::InvalidRootHash.into());
+                    }
+                    if id.file_hash != start_block_id.file_hash() {
+                        return Err(NodeRpcServerError::InvalidFileHash.into());
+                    }
+
+                    // NOTE: the iterator starts from the specified block,
+                    // but only the next key blocks are requested
+                    iterator.next();
                 }
             }
 
             let mut ids = Vec::with_capacity(limit);
-            while let Some(id) = iterator.next().transpose()? {
-                ids.push(id);
-                if ids.len() >= limit {
-                    break;
-                }
+            for id in iterator.take(limit) {
+                ids.push(id?);
             }
 
             Ok::<_, anyhow::Error>(ids)
@@ -324,6 +358,20 @@ impl QueryHandler {
         })
     }
 
+    async fn download_zero_state(self, query: proto::RpcDownloadZeroState) -> Result<Vec<u8>> {
+        match self.0.db.block_handle_storage().load_handle(&query.block)? {
+            Some(handle) if query.block.seq_no == 0 && handle.meta().has_state() => {
+                let state = self.0.load_state(&query.block).await?;
+                let data = tokio::task::spawn_blocking(move || {
+                    ton_types::serialize_toc(state.root_cell())
+                })
+                .await??;
+                Ok(data)
+            }
+            _ => Err(NodeRpcServerError::StateNotFound.into()),
+        }
+    }
+
     async fn get_archive_slice(self, query: proto::RpcGetArchiveSlice) -> Result<Vec<u8>> {
         Ok(
             match self.0.db.block_storage().get_archive_slice(
@@ -354,6 +402,7 @@ fn find_block_proof(
         return Err(NodeRpcServerError::NotKeyBlock.into());
     }
 
+    // NOTE: proof link could be built from the full proof, see `load_block_proof`
     if meta.has_proof() {
         Ok(proto::PreparedProof::Found)
     } else if allow_partial && meta.has_proof_link() {
@@ -378,11 +427,25 @@ async fn load_block_proof(
         {
             block_storage.load_block_proof_raw(&handle, is_link).await
         }
+        // NOTE: proof link is not stored when the full proof is present
+        Some(handle) if is_link && handle.meta().has_proof() => {
+            let proof = block_storage.load_block_proof_raw(&handle, false).await?;
+            make_proof_link(&proof)
+        }
         _ if is_link => Err(NodeRpcServerError::BlockProofLinkNotFound.into()),
         _ => Err(NodeRpcServerError::BlockProofNotFound.into()),
     }
 }
 
+/// Removes signatures from the full proof
+fn make_proof_link(proof: &[u8]) -> Result<Vec<u8>> {
+    use ton_block::{Deserializable, Serializable};
+
+    let mut proof = ton_block::BlockProof::construct_from_bytes(proof)?;
+    proof.signatures = None;
+    proof.write_to_bytes()
+}
+
 #[derive(Debug, thiserror::Error)]
 enum NodeRpcServerError {
     #[error("Engine is already dropped")]
@@ -403,4 +466,33 @@ enum NodeRpcServerError {
     InvalidFileHash,
     #[error("Archive not found")]
     ArchiveNotFound,
+    #[error("State not found")]
+    StateNotFound,
+}
+
+#[cfg(test)]
+mod tests {
+    use ton_block::{Deserializable, Serializable};
+
+    use super::*;
+
+    #[test]
+    fn proof_link_from_full_proof() {
+        let proof = ton_block::BlockProof {
+            proof_for: ton_block::BlockIdExt {
+                shard_id: ton_block::ShardIdent::masterchain(),
+                seq_no: 123,
+                root_hash: ton_types::UInt256::from_slice(&[1; 32]),
+                file_hash: ton_types::UInt256::from_slice(&[2; 32]),
+            },
+            root: Default::default(),
+            signatures: Some(Default::default()),
+        };
+
+        let link = make_proof_link(&proof.write_to_bytes().unwrap()).unwrap();
+        let link = ton_block::BlockProof::construct_from_bytes(&link).unwrap();
+        assert_eq!(link.proof_for, proof.proof_for);
+        assert_eq!(link.root, proof.root);
+        assert!(link.signatures.is_none());
+    }
 }
@@ -0,0 +1,522 @@
+use std::future::Future;
+
+use anyhow::Result;
+use rustc_hash::FxHashMap;
+
+use crate::utils::TopBlocks;
+
+/// Enforces the order of subscriber notifications:
+/// - blocks of each shard are delivered in strictly increasing seqno
+///   (split and merge are tracked through the parent and children shards);
+/// - masterchain block is delivered only after all shard blocks it commits.
+///
+/// Out-of-order items are buffered up to the specified window and
+/// already delivered items are dropped.
+///
+/// NOTE: item is considered delivered only after [`NotificationSequencer::pop_ready`],
+/// so that it could be retried after [`NotificationSequencer::peek_ready`] if delivery failed
+pub struct NotificationSequencer<T> {
+    window: usize,
+    last_mc_block: Option<ton_block::BlockIdExt>,
+    next_mc_seq_no: Option<u32>,
+    next_shard_seq_no: FxHashMap<ton_block::ShardIdent, u32>,
+    pending: Vec<PendingItem<T>>,
+}
+
+impl<T> NotificationSequencer<T> {
+    /// Creates an empty sequencer which accepts the first seen block of each shard
+    pub fn new(window: usize) -> Self {
+        Self {
+            window,
+            last_mc_block: None,
+            next_mc_seq_no: None,
+            next_shard_seq_no: Default::default(),
+            pending: Default::default(),
+        }
+    }
+
+    /// Resets the sequencer to expect blocks right after the specified ones
+    pub fn reset(&mut self, top_blocks: &TopBlocks) {
+        self.last_mc_block = Some(top_blocks.mc_block.clone());
+        self.next_mc_seq_no = Some(top_blocks.mc_block.seq_no + 1);
+        self.next_shard_seq_no = top_blocks
+            .shard_heights
+            .iter()
+            .map(|(shard, seq_no)| (*shard, seq_no + 1))
+            .collect();
+        self.pending.clear();
+    }
+
+    pub fn pending_len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Returns the last delivered masterchain block and the last delivered seqno
+    /// of each shard, or `None` if nothing was delivered since the creation.
+    ///
+    /// NOTE: [`NotificationSequencer::reset`] with the returned value restores
+    /// the same delivery position
+    pub fn position(&self) -> Option<TopBlocks> {
+        Some(TopBlocks {
+            mc_block: self.last_mc_block.clone()?,
+            shard_heights: self
+                .next_shard_seq_no
+                .iter()
+                .map(|(shard, next)| (*shard, next.saturating_sub(1)))
+                .collect(),
+        })
+    }
+
+    /// Returns `true` if the block was already delivered
+    pub fn is_delivered(&self, block_id: &ton_block::BlockIdExt) -> bool {
+        let next = if block_id.shard_id.is_masterchain() {
+            self.next_mc_seq_no
+        } else {
+            self.next_shard_seq_no(&block_id.shard_id)
+        };
+        matches!(next, Some(next) if block_id.seq_no < next)
+    }
+
+    /// Adds masterchain block with its top shard blocks
+    pub fn push_mc_block(
+        &mut self,
+        top_blocks: TopBlocks,
+        item: T,
+    ) -> std::result::Result<(), NotificationSequencerError> {
+        if self.is_delivered(&top_blocks.mc_block) {
+            return Ok(());
+        }
+        self.push(PendingItem::Masterchain { top_blocks, item })
+    }
+
+    /// Adds shard block
+    pub fn push_shard_block(
+        &mut self,
+        block_id: ton_block::BlockIdExt,
+        item: T,
+    ) -> std::result::Result<(), NotificationSequencerError> {
+        if self.is_delivered(&block_id) {
+            return Ok(());
+        }
+        self.push(PendingItem::Shard { block_id, item })
+    }
+
+    /// Returns the next item which could be delivered now
+    pub fn peek_ready(&self) -> Option<&T> {
+        let index = self.next_ready()?;
+        Some(self.pending[index].item())
+    }
+
+    /// Removes the next item which could be delivered now and marks it as delivered
+    pub fn pop_ready(&mut self) -> Option<T> {
+        let index = self.next_ready()?;
+        let item = self.pending.swap_remove(index);
+        Some(self.advance(item))
+    }
+
+    /// Delivers all items which could be delivered now.
+    ///
+    /// `on_delivered` is called with the new position after each delivered item.
+    /// Stops at the first error, so that the failed item stays in the sequencer
+    pub async fn deliver_ready<D, F, P>(
+        &mut self,
+        mut deliver: D,
+        mut on_delivered: P,
+    ) -> Result<()>
+    where
+        T: Clone,
+        D: FnMut(T) -> F,
+        F: Future<Output = Result<()>>,
+        P: FnMut(&TopBlocks) -> Result<()>,
+    {
+        while let Some(item) = self.peek_ready() {
+            deliver(item.clone()).await?;
+            self.pop_ready();
+
+            if let Some(position) = self.position() {
+                on_delivered(&position)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn push(
+        &mut self,
+        item: PendingItem<T>,
+    ) -> std::result::Result<(), NotificationSequencerError> {
+        if self.pending.iter().any(|pending| pending.id() == item.id()) {
+            return Ok(());
+        }
+        if self.pending.len() >= self.window {
+            return Err(NotificationSequencerError::WindowExceeded {
+                block_id: item.id().display().to_string(),
+                pending: self.pending.len(),
+            });
+        }
+        self.pending.push(item);
+        Ok(())
+    }
+
+    fn next_ready(&self) -> Option<usize> {
+        self.pending
+            .iter()
+            .enumerate()
+            .filter(|(_, item)| self.is_ready(item))
+            .min_by_key(|(_, item)| item.order())
+            .map(|(index, _)| index)
+    }
+
+    fn is_ready(&self, item: &PendingItem<T>) -> bool {
+        match item {
+            PendingItem::Masterchain { top_blocks, .. } => {
+                let seq_no = top_blocks.mc_block.seq_no;
+                self.next_mc_seq_no.map_or(true, |next| seq_no == next)
+                    && top_blocks.shard_heights.iter().all(|(shard, &top)| {
+                        top == 0
+                            || self
+                                .next_shard_seq_no(shard)
+                                .map_or(true, |next| next > top)
+                    })
+            }
+            PendingItem::Shard { block_id, .. } => self
+                .next_shard_seq_no(&block_id.shard_id)
+                .map_or(true, |next| block_id.seq_no == next),
+        }
+    }
+
+    fn advance(&mut self, item: PendingItem<T>) -> T {
+        match item {
+            PendingItem::Masterchain { top_blocks, item } => {
+                self.next_mc_seq_no = Some(top_blocks.mc_block.seq_no + 1);
+                self.last_mc_block = Some(top_blocks.mc_block);
+                item
+            }
+            PendingItem::Shard { block_id, item } => {
+                let shard = block_id.shard_id;
+
+                // After split both children start from the same seqno
+                if let Ok(parent) = shard.merge() {
+                    if let Some(next) = self.next_shard_seq_no.remove(&parent) {
+                        if let Ok((left, right)) = parent.split() {
+                            let sibling = if left == shard { right } else { left };
+                            self.next_shard_seq_no.entry(sibling).or_insert(next);
+                        }
+                    }
+                }
+                // After merge children are no longer used
+                if let Ok((left, right)) = shard.split() {
+                    self.next_shard_seq_no.remove(&left);
+                    self.next_shard_seq_no.remove(&right);
+                }
+
+                self.next_shard_seq_no.insert(shard, block_id.seq_no + 1);
+                item
+            }
+        }
+    }
+
+    fn next_shard_seq_no(&self, shard: &ton_block::ShardIdent) -> Option<u32> {
+        if let Some(next) = self.next_shard_seq_no.get(shard) {
+            return Some(*next);
+        }
+
+        // First block after split
+        if let Ok(parent) = shard.merge() {
+            if let Some(next) = self.next_shard_seq_no.get(&parent) {
+                return Some(*next);
+            }
+        }
+
+        // First block after merge
+        if let Ok((left, right)) = shard.split() {
+            if let (Some(left), Some(right)) = (
+                self.next_shard_seq_no.get(&left),
+                self.next_shard_seq_no.get(&right),
+            ) {
+                return Some(std::cmp::max(*left, *right));
+            }
+        }
+
+        None
+    }
+}
+
+enum PendingItem<T> {
+    Masterchain {
+        top_blocks: TopBlocks,
+        item: T,
+    },
+    Shard {
+        block_id: ton_block::BlockIdExt,
+        item: T,
+    },
+}
+
+impl<T> PendingItem<T> {
+    fn id(&self) -> &ton_block::BlockIdExt {
+        match self {
+            Self::Masterchain { top_blocks, .. } => &top_blocks.mc_block,
+            Self::Shard { block_id, .. } => block_id,
+        }
+    }
+
+    fn item(&self) -> &T {
+        match self {
+            Self::Masterchain { item, .. } | Self::Shard { item, .. } => item,
+        }
+    }
+
+    /// Shard blocks go first in the same order as the buffered shard notifications
+    fn order(&self) -> (bool, (i32, u32, u64)) {
+        let id = self.id();
+        (
+            id.shard_id.is_masterchain(),
+            super::shard_notification_order(id),
+        )
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum NotificationSequencerError {
+    #[error("Too many out-of-order notifications ({pending}) while adding block {block_id}")]
+    WindowExceeded { block_id: String, pending: usize },
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::seq::SliceRandom;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::test_helpers::*;
+
+    const WINDOW: usize = 64;
+
+    fn shard(prefix: u64) -> ton_block::ShardIdent {
+        ton_block::ShardIdent::with_tagged_prefix(0, prefix).unwrap()
+    }
+
+    fn initial_top_blocks() -> TopBlocks {
+        TopBlocks {
+            mc_block: mc_block_id(0),
+            shard_heights: FxHashMap::from_iter([(shard(ton_block::SHARD_FULL), 0)]),
+        }
+    }
+
+    #[derive(Clone)]
+    enum Event {
+        Masterchain(TopBlocks),
+        Shard(ton_block::BlockIdExt),
+    }
+
+    impl Event {
+        fn id(&self) -> &ton_block::BlockIdExt {
+            match self {
+                Self::Masterchain(top_blocks) => &top_blocks.mc_block,
+                Self::Shard(id) => id,
+            }
+        }
+    }
+
+    /// Fixture chain with a split of the full shard in the middle.
+    ///
+    /// Returns events for each masterchain block: its shard blocks and itself
+    fn make_chain(rng: &mut impl Rng, mc_blocks: u32) -> Vec<Vec<Event>> {
+        let full = shard(ton_block::SHARD_FULL);
+        let (left, right) = full.split().unwrap();
+        let split_at = mc_blocks / 2;
+
+        let mut heights = FxHashMap::from_iter([(full, 0u32)]);
+        let mut chain = Vec::new();
+        for mc_seq_no in 1..=mc_blocks {
+            let mut events = Vec::new();
+
+            if mc_seq_no == split_at {
+                let parent_seq_no = heights.remove(&full).unwrap();
+                for child in [left, right] {
+                    heights.insert(child, parent_seq_no + 1);
+                    events.push(Event::Shard(block_id(child, parent_seq_no + 1)));
+                }
+            }
+
+            for (shard, height) in heights.iter_mut() {
+                for _ in 0..rng.gen_range(0..3) {
+                    *height += 1;
+                    events.push(Event::Shard(block_id(*shard, *height)));
+                }
+            }
+
+            events.push(Event::Masterchain(TopBlocks {
+                mc_block: mc_block_id(mc_seq_no),
+                shard_heights: heights.clone(),
+            }));
+            chain.push(events);
+        }
+        chain
+    }
+
+    fn check_contract(chain: &[Vec<Event>], delivered: &[Event]) {
+        let expected = chain.iter().flatten().count();
+        assert_eq!(
+            delivered.len(),
+            expected,
+            "all blocks delivered exactly once"
+        );
+
+        let mut last_seq_no = FxHashMap::default();
+        let mut delivered_ids = std::collections::HashSet::new();
+        for event in delivered {
+            let id = event.id();
+            assert!(delivered_ids.insert(id.clone()), "duplicate delivery");
+
+            if let Some(last) = last_seq_no.insert(id.shard_id, id.seq_no) {
+                assert!(id.seq_no > last, "shard blocks out of order");
+            }
+
+            if let Event::Masterchain(top_blocks) = event {
+                for (shard, top) in &top_blocks.shard_heights {
+                    if *top == 0 {
+                        continue;
+                    }
+                    assert!(
+                        delivered_ids.contains(&block_id(*shard, *top)),
+                        "mc block delivered before its shard blocks"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn interleaved_walker_and_importer() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(123);
+
+        for _ in 0..2000 {
+            let chain = make_chain(&mut rng, 20);
+
+            // Walker notifies masterchain block before its shard blocks,
+            // importer notifies in the chain order
+            let walker = chain.iter().flat_map(|events| {
+                let (mc, shards) = events.split_last().unwrap();
+                std::iter::once(mc.clone()).chain(shards.iter().cloned())
+            });
+            let mut walker = walker.collect::<Vec<_>>().into_iter().peekable();
+            let mut importer = chain.iter().flatten().cloned().peekable();
+
+            let mut sequencer = NotificationSequencer::new(WINDOW);
+            sequencer.reset(&initial_top_blocks());
+            let mut delivered = Vec::new();
+            loop {
+                let event = match (walker.peek().is_some(), importer.peek().is_some()) {
+                    (true, true) if rng.gen_bool(0.5) => walker.next().unwrap(),
+                    (_, true) => importer.next().unwrap(),
+                    (true, false) => walker.next().unwrap(),
+                    (false, false) => break,
+                };
+
+                match event.clone() {
+                    Event::Masterchain(top_blocks) => {
+                        sequencer.push_mc_block(top_blocks, event).unwrap()
+                    }
+                    Event::Shard(id) => sequencer.push_shard_block(id, event).unwrap(),
+                };
+                while let Some(event) = sequencer.pop_ready() {
+                    delivered.push(event);
+                }
+            }
+
+            assert_eq!(sequencer.pending_len(), 0);
+            check_contract(&chain, &delivered);
+        }
+    }
+
+    #[test]
+    fn restart_from_persisted_position() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(123);
+
+        for crash_at in 1..20 {
+            let chain = make_chain(&mut rng, 20);
+            let push = |sequencer: &mut NotificationSequencer<Event>, event: &Event| {
+                let result = match event.clone() {
+                    Event::Masterchain(top_blocks) => {
+                        sequencer.push_mc_block(top_blocks, event.clone())
+                    }
+                    Event::Shard(id) => sequencer.push_shard_block(id, event.clone()),
+                };
+                result.unwrap();
+            };
+
+            let mut sequencer = NotificationSequencer::new(WINDOW);
+            sequencer.reset(&initial_top_blocks());
+            let mut delivered = Vec::new();
+
+            // Masterchain blocks are applied ahead of the shards client
+            let last_applied = std::cmp::min(crash_at + 3, chain.len());
+            for events in &chain[crash_at..last_applied] {
+                push(&mut sequencer, events.last().unwrap());
+            }
+            // Shards client has processed some blocks of the next masterchain block
+            let next_shard_blocks = &chain[crash_at][..chain[crash_at].len() - 1];
+            let applied_shard_blocks =
+                &next_shard_blocks[..rng.gen_range(0..=next_shard_blocks.len())];
+            for event in chain[..crash_at]
+                .iter()
+                .flatten()
+                .chain(applied_shard_blocks)
+            {
+                push(&mut sequencer, event);
+                while let Some(event) = sequencer.pop_ready() {
+                    delivered.push(event);
+                }
+            }
+
+            // Restart with the persisted position
+            let position = sequencer.position().unwrap();
+            let mut sequencer = NotificationSequencer::new(WINDOW);
+            sequencer.reset(&position);
+
+            // Already applied blocks are pushed again, then the shards client continues
+            let replayed = chain[..last_applied]
+                .iter()
+                .map(|events| events.last().unwrap())
+                .chain(applied_shard_blocks);
+            for event in replayed.chain(chain.iter().flatten()) {
+                push(&mut sequencer, event);
+                while let Some(event) = sequencer.pop_ready() {
+                    delivered.push(event);
+                }
+            }
+
+            assert_eq!(sequencer.pending_len(), 0);
+            check_contract(&chain, &delivered);
+        }
+    }
+
+    #[test]
+    fn window_is_bounded() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(123);
+        let chain = make_chain(&mut rng, 100);
+
+        // Shuffled blocks without the first masterchain block can't be delivered
+        let mut events = chain[1..].iter().flatten().cloned().collect::<Vec<_>>();
+        events.shuffle(&mut rng);
+
+        let mut sequencer = NotificationSequencer::new(4);
+        sequencer.reset(&initial_top_blocks());
+
+        let mut result = Ok(());
+        for event in events.into_iter().filter(|event| event.id().seq_no > 10) {
+            result = match event.clone() {
+                Event::Masterchain(top_blocks) => sequencer.push_mc_block(top_blocks, event),
+                Event::Shard(id) => sequencer.push_shard_block(id, event),
+            };
+            if result.is_err() {
+                break;
+            }
+        }
+        assert!(matches!(
+            result,
+            Err(NotificationSequencerError::WindowExceeded { pending: 4, .. })
+        ));
+    }
+}
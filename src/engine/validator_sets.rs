@@ -0,0 +1,271 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use super::Engine;
+
+impl Engine {
+    /// Returns validator sets from the config of the specified key block
+    /// (or the last known key block if `None`).
+    ///
+    /// Sets are read from the stored masterchain state of the key block
+    /// or from the key block itself if the state is not stored
+    pub async fn validator_sets_at(&self, key_block_seqno: Option<u32>) -> Result<ValidatorSets> {
+        let block_handle_storage = self.db.block_handle_storage();
+        let handle = match key_block_seqno {
+            Some(seqno) => block_handle_storage.load_key_block_handle(seqno)?,
+            None => block_handle_storage.find_last_key_block()?,
+        };
+
+        let seqno = handle.id().seq_no;
+        if let Some(sets) = self.validator_sets_cache.get(&seqno) {
+            return Ok(sets);
+        }
+
+        let config = self.load_key_block_config(&handle).await?;
+        let sets = ValidatorSets::from_config(seqno, &config)?;
+
+        self.validator_sets_cache.insert(seqno, sets.clone());
+        Ok(sets)
+    }
+}
+
+/// Validator sets from the config params 32, 34 and 36
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ValidatorSets {
+    /// Seqno of the key block with the config
+    pub key_block_seqno: u32,
+    pub prev: Option<ValidatorSetInfo>,
+    pub current: Option<ValidatorSetInfo>,
+    pub next: Option<ValidatorSetInfo>,
+}
+
+impl ValidatorSets {
+    fn from_config(key_block_seqno: u32, config: &ton_block::ConfigParams) -> Result<Self> {
+        let read = |index| -> Result<Option<ValidatorSetInfo>> {
+            Ok(match config.config(index)? {
+                Some(ton_block::ConfigParamEnum::ConfigParam32(param)) => {
+                    Some(ValidatorSetInfo::from(&param.prev_validators))
+                }
+                Some(ton_block::ConfigParamEnum::ConfigParam34(param)) => {
+                    Some(ValidatorSetInfo::from(&param.cur_validators))
+                }
+                Some(ton_block::ConfigParamEnum::ConfigParam36(param)) => {
+                    Some(ValidatorSetInfo::from(&param.next_validators))
+                }
+                _ => None,
+            })
+        };
+
+        Ok(Self {
+            key_block_seqno,
+            prev: read(32)?,
+            current: read(34)?,
+            next: read(36)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ValidatorSetInfo {
+    pub utime_since: u32,
+    pub utime_until: u32,
+    pub total_weight: u64,
+    pub validators: Vec<ValidatorInfo>,
+}
+
+impl From<&ton_block::ValidatorSet> for ValidatorSetInfo {
+    fn from(set: &ton_block::ValidatorSet) -> Self {
+        Self {
+            utime_since: set.utime_since(),
+            utime_until: set.utime_until(),
+            total_weight: set.total_weight(),
+            validators: set.list().iter().map(ValidatorInfo::from).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ValidatorInfo {
+    /// Hex encoded ed25519 public key
+    pub public_key: String,
+    pub weight: u64,
+    /// Hex encoded ADNL address
+    pub adnl_addr: Option<String>,
+}
+
+impl From<&ton_block::ValidatorDescr> for ValidatorInfo {
+    fn from(descr: &ton_block::ValidatorDescr) -> Self {
+        Self {
+            public_key: hex::encode(descr.public_key.key_bytes()),
+            weight: descr.weight,
+            adnl_addr: descr.adnl_addr.as_ref().map(|addr| addr.to_hex_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ton_block::Deserializable;
+
+    use super::*;
+    use crate::db::BlockMetaData;
+    use crate::test_helpers::*;
+    use crate::utils::{BlockStuff, BlockStuffAug};
+
+    fn make_validator(key: u8, weight: u64) -> ton_block::ValidatorDescr {
+        ton_block::ValidatorDescr::with_params(
+            ton_block::SigPubKey::from_bytes(&[key; 32]).unwrap(),
+            weight,
+            Some(ton_types::UInt256::from([key; 32])),
+        )
+    }
+
+    #[test]
+    fn validator_set_conversion() {
+        let set = ton_block::ValidatorSet::new(
+            1000,
+            2000,
+            1,
+            vec![make_validator(1, 10), make_validator(2, 20)],
+        )
+        .unwrap();
+
+        let info = ValidatorSetInfo::from(&set);
+        assert_eq!(info.utime_since, 1000);
+        assert_eq!(info.utime_until, 2000);
+        assert_eq!(info.total_weight, 30);
+        assert_eq!(info.validators.len(), 2);
+        assert_eq!(info.validators[0].public_key, hex::encode([1; 32]));
+        assert_eq!(info.validators[1].weight, 20);
+        assert_eq!(
+            info.validators[1].adnl_addr.as_deref(),
+            Some(hex::encode([2; 32]).as_str())
+        );
+    }
+
+    fn make_validator_set(since: u32, keys: std::ops::Range<u8>) -> ton_block::ValidatorSet {
+        let list = keys.map(|key| make_validator(key, key as u64)).collect();
+        ton_block::ValidatorSet::new(since, since + 1000, 1, list).unwrap()
+    }
+
+    /// Serialized key block with the prev, current and next validator sets in its config
+    fn make_key_block(seq_no: u32) -> (ton_block::BlockIdExt, Vec<u8>) {
+        let mut config = ton_block::ConfigParams::default();
+        config
+            .set_config(ton_block::ConfigParamEnum::ConfigParam32(
+                ton_block::ConfigParam32 {
+                    prev_validators: make_validator_set(1000, 1..3),
+                },
+            ))
+            .unwrap();
+        config
+            .set_config(ton_block::ConfigParamEnum::ConfigParam34(
+                ton_block::ConfigParam34 {
+                    cur_validators: make_validator_set(2000, 3..6),
+                },
+            ))
+            .unwrap();
+        config
+            .set_config(ton_block::ConfigParamEnum::ConfigParam36(
+                ton_block::ConfigParam36 {
+                    next_validators: make_validator_set(3000, 6..10),
+                },
+            ))
+            .unwrap();
+
+        let mut mc_extra = ton_block::McBlockExtra::default();
+        *mc_extra.config_mut() = Some(config);
+        let mut extra = ton_block::BlockExtra::default();
+        extra.write_custom(Some(&mc_extra)).unwrap();
+
+        make_block(ton_block::ShardIdent::masterchain(), seq_no, extra)
+    }
+
+    fn check_key_block_sets(sets: &ValidatorSets) {
+        let expected = [
+            (&sets.prev, 1000, 2, 1 + 2),
+            (&sets.current, 2000, 3, 3 + 4 + 5),
+            (&sets.next, 3000, 4, 6 + 7 + 8 + 9),
+        ];
+        for (set, since, count, total_weight) in expected {
+            let set = set.as_ref().unwrap();
+            assert_eq!(set.utime_since, since);
+            assert_eq!(set.utime_until, since + 1000);
+            assert_eq!(set.validators.len(), count);
+            assert_eq!(set.total_weight, total_weight);
+        }
+    }
+
+    #[test]
+    fn validator_sets_from_key_block() {
+        let (_, data) = make_key_block(10);
+        let block = ton_block::Block::construct_from_bytes(&data).unwrap();
+
+        let extra = block.read_extra().unwrap().read_custom().unwrap().unwrap();
+        let sets = ValidatorSets::from_config(10, extra.config().unwrap()).unwrap();
+        assert_eq!(sets.key_block_seqno, 10);
+        check_key_block_sets(&sets);
+
+        // Sets are absent in the config without them
+        let sets = ValidatorSets::from_config(10, &Default::default()).unwrap();
+        assert_eq!(sets.prev, None);
+        assert_eq!(sets.current, None);
+        assert_eq!(sets.next, None);
+    }
+
+    #[tokio::test]
+    async fn validator_sets_from_stored_key_block() {
+        let dir = TempDir::new("validator_sets_from_stored_key_block");
+        let engine = test_engine(&dir, Vec::new()).await;
+
+        // Key block without the stored state
+        let (id, data) = make_key_block(10);
+        let block = BlockStuff::deserialize_checked(id.clone(), &data).unwrap();
+        let meta_data = BlockMetaData {
+            is_key_block: true,
+            gen_utime: 10,
+            mc_ref_seqno: Some(10),
+        };
+        engine
+            .db
+            .block_storage()
+            .store_block_data(&BlockStuffAug::new(block, data), meta_data)
+            .await
+            .unwrap();
+
+        let sets = engine.validator_sets_at(Some(10)).await.unwrap();
+        assert_eq!(sets.key_block_seqno, 10);
+        check_key_block_sets(&sets);
+
+        // Last key block is used by default
+        assert_eq!(engine.validator_sets_at(None).await.unwrap(), sets);
+
+        assert!(engine.validator_sets_at(Some(5)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn validator_sets_from_signed_chain() {
+        use crate::test_util::SyntheticChain;
+
+        let chain = SyntheticChain::generate(2, 1000, |seq_no| 1000 + seq_no).unwrap();
+        let dir = TempDir::new("validator_sets_from_signed_chain");
+        chain.create_db(dir.path()).await.unwrap();
+        let engine = test_engine_with_global_config(&dir, chain.global_config(), Vec::new()).await;
+
+        // Blocks of the chain are signed by the current set from the zero state config
+        let sets = engine.validator_sets_at(Some(0)).await.unwrap();
+        assert_eq!(sets.key_block_seqno, 0);
+        assert_eq!(sets.prev, None);
+        assert_eq!(sets.next, None);
+
+        let current = sets.current.as_ref().unwrap();
+        assert_eq!(current.utime_since, 0);
+        assert_eq!(current.utime_until, u32::MAX);
+        assert_eq!(current.validators.len(), 3);
+        assert!(current.validators.iter().all(|v| v.weight == 1));
+        assert_eq!(current.total_weight, 3);
+
+        // Zero state is the only key block
+        assert_eq!(engine.validator_sets_at(None).await.unwrap(), sets);
+    }
+}
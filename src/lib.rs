@@ -1,9 +1,20 @@
 pub use crate::config::*;
-pub use crate::db::{BriefBlockMeta, DbMetrics, RocksdbStats};
+pub use crate::db::{
+    AuditLogEntry, AuditLogEventKind, BlockFlags, BriefBlockMeta, ColumnCompactionStats, DbMetrics,
+    RocksdbStats, TransactionLocation,
+};
+#[cfg(feature = "apply-metrics")]
+pub use crate::engine::complex_operations::{ApplyBlockMetricsSnapshot, ApplyBlockPhasesSnapshot};
+pub use crate::engine::complex_operations::{BootVerificationReport, ImportStats, SyncStats};
+#[cfg(feature = "mock-network")]
+pub use crate::engine::MockNetwork;
 pub use crate::engine::{
-    Engine, EngineMetrics, EngineStatus, InternalEngineMetrics, ProcessBlockContext, Subscriber,
+    BalanceSink, BlockDataRef, BlockTimeAnomalyCounters, Engine, EngineMetrics, EngineStatus,
+    Finality, InternalEngineMetrics, ProcessBlockContext, Subscriber, ValidatorInfo,
+    ValidatorSetInfo, ValidatorSets,
 };
 pub use crate::network::{NeighboursOptions, NetworkMetrics, NodeNetwork};
+pub use crate::utils::{parse_block_id, PackageEntryId};
 
 #[cfg(feature = "archive-uploader")]
 pub use archive_uploader;
@@ -12,8 +23,14 @@ pub use global_config::*;
 mod config;
 mod db;
 mod engine;
+#[cfg(feature = "tool")]
+pub mod maintenance;
 mod network;
 mod proto;
+#[cfg(test)]
+mod test_helpers;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
 pub mod utils;
 
 pub mod alloc {
@@ -0,0 +1,406 @@
+//! Offline maintenance of the node DB.
+//!
+//! NOTE: RocksDB holds an exclusive lock on the DB directory, so modifying
+//! operations can't be performed while the node is running. Read-only access
+//! is allowed, but it only sees the data flushed before the DB was opened
+
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::db::*;
+use crate::engine::complex_operations::load_committed_shard_blocks;
+use crate::utils::*;
+
+pub struct MaintenanceDb {
+    db: Arc<Db>,
+}
+
+impl MaintenanceDb {
+    /// Opens the existing DB as is.
+    ///
+    /// Read-write access requires the node to be stopped, read-only access
+    /// doesn't lock the DB and could be used on the running node.
+    ///
+    /// NOTE: the DB is neither migrated nor repaired, temp files and
+    /// interrupted operations are left as is
+    pub async fn open<PS, PF>(
+        rocks_db_path: PS,
+        file_db_path: PF,
+        mem_limit: usize,
+        read_only: bool,
+    ) -> Result<Self>
+    where
+        PS: AsRef<Path>,
+        PF: AsRef<Path>,
+    {
+        let rocks_db_path = rocks_db_path.as_ref();
+
+        if !read_only && is_db_locked(rocks_db_path)? {
+            return Err(MaintenanceError::DbLocked.into());
+        }
+
+        match Db::open_existing(rocks_db_path, file_db_path, mem_limit, read_only).await {
+            Ok(db) => Ok(Self { db }),
+            // NOTE: the lock could have been acquired after the check
+            Err(e) if !read_only && is_lock_error(&e) && is_db_locked(rocks_db_path)? => {
+                Err(MaintenanceError::DbLocked.into())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns the node state pointers
+    pub fn status(&self) -> Result<StatusReport> {
+        let node_state = self.db.node_state();
+        let seq_no = |id: Result<ton_block::BlockIdExt>| id.ok().map(|id| id.seq_no);
+
+        Ok(StatusReport {
+            init_mc_block_seqno: seq_no(node_state.load_init_mc_block_id()),
+            last_mc_block_seqno: seq_no(node_state.load_last_mc_block_id()),
+            shards_client_mc_block_seqno: seq_no(node_state.load_shards_client_mc_block_id()),
+            last_key_block_seqno: self
+                .db
+                .block_handle_storage()
+                .find_last_key_block()
+                .ok()
+                .map(|handle| handle.id().seq_no),
+        })
+    }
+
+    /// Loads at most `limit` latest chain progress decisions, newest first
+    pub fn audit_log(&self, limit: usize) -> Result<Vec<AuditLogEntry>> {
+        self.db.audit_log().load(limit)
+    }
+
+    /// Checks that all masterchain blocks in the range (and shard blocks committed by them)
+    /// have valid data and proofs
+    pub async fn verify_storage(&self, from: u32, to: u32) -> Result<VerifyStorageReport> {
+        let mut report = VerifyStorageReport::default();
+        for mc_block_id in self.mc_block_ids(from, to)? {
+            let mc_handle = match self.db.block_handle_storage().load_handle(&mc_block_id)? {
+                Some(handle) => handle,
+                None => {
+                    report.missing.push(mc_block_id.display().to_string());
+                    continue;
+                }
+            };
+
+            let mc_block = match self.verify_block(&mc_handle, &mut report).await {
+                Some(block) => block,
+                None => continue,
+            };
+            report.mc_blocks += 1;
+
+            for handle in load_committed_shard_blocks(&self.db, &mc_block)? {
+                if self.verify_block(&handle, &mut report).await.is_some() {
+                    report.shard_blocks += 1;
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    async fn verify_block(
+        &self,
+        handle: &BlockHandle,
+        report: &mut VerifyStorageReport,
+    ) -> Option<BlockStuff> {
+        let block_storage = self.db.block_storage();
+        let id = handle.id();
+
+        if !handle.meta().has_data() || !handle.has_proof_or_link(&mut false) {
+            report.missing.push(id.display().to_string());
+            return None;
+        }
+
+        let block = match block_storage.load_block_data_raw_ref(handle).await {
+            Ok(data) => BlockStuff::deserialize_checked(id.clone(), data.as_ref()),
+            Err(e) => Err(e),
+        };
+        let proof = block_storage.load_best_block_proof(handle).await;
+
+        match (block, proof) {
+            (Ok(block), Ok(Some(_))) => Some(block),
+            (block, proof) => {
+                let error = match (block, proof) {
+                    (Err(e), _) | (_, Err(e)) => e.to_string(),
+                    _ => "Block proof not found".to_owned(),
+                };
+                report.corrupted.push(CorruptedBlock {
+                    block_id: id.display().to_string(),
+                    error,
+                });
+                None
+            }
+        }
+    }
+
+    /// Writes all masterchain blocks in the range with their shard blocks
+    /// and proofs into the archive package
+    pub async fn export_archive<P: AsRef<Path>>(
+        &self,
+        from: u32,
+        to: u32,
+        out: P,
+    ) -> Result<ExportArchiveReport> {
+        let block_storage = self.db.block_storage();
+
+        let mut file = std::io::BufWriter::new(
+            std::fs::File::create(out).context("Failed to create archive file")?,
+        );
+        file.write_all(&ARCHIVE_PREFIX)?;
+
+        let mut report = ExportArchiveReport {
+            bytes: ARCHIVE_PREFIX.len() as u64,
+            ..Default::default()
+        };
+
+        for mc_block_id in self.mc_block_ids(from, to)? {
+            let mc_handle = self
+                .db
+                .block_handle_storage()
+                .load_handle(&mc_block_id)?
+                .ok_or(MaintenanceError::BlockNotFound)?;
+            let mc_block = block_storage.load_block_data(&mc_handle).await?;
+
+            let shard_handles = load_committed_shard_blocks(&self.db, &mc_block)?;
+            for handle in shard_handles.iter().chain(std::iter::once(&mc_handle)) {
+                let mut is_link = false;
+                if !handle.has_proof_or_link(&mut is_link) {
+                    return Err(MaintenanceError::BlockProofNotFound.into());
+                }
+
+                let id = handle.id();
+                let proof_id = match is_link {
+                    true => PackageEntryId::ProofLink(id),
+                    false => PackageEntryId::Proof(id),
+                };
+
+                let data = block_storage.load_block_data_raw(handle).await?;
+                let proof = block_storage.load_block_proof_raw(handle, is_link).await?;
+                for segment in [
+                    make_archive_segment(&PackageEntryId::Block(id).filename(), &data),
+                    make_archive_segment(&proof_id.filename(), &proof),
+                ] {
+                    file.write_all(&segment)?;
+                    report.bytes += segment.len() as u64;
+                }
+            }
+
+            report.mc_blocks += 1;
+            report.shard_blocks += shard_handles.len();
+        }
+
+        file.flush()?;
+        Ok(report)
+    }
+
+    /// Writes the shard state of the specified block as a BOC
+    pub async fn export_state<P: AsRef<Path>>(
+        &self,
+        block_id: &ton_block::BlockIdExt,
+        out: P,
+    ) -> Result<ExportStateReport> {
+        let state = self.db.shard_state_storage().load_state(block_id).await?;
+        let boc = ton_types::serialize_toc(state.root_cell())?;
+        std::fs::write(out, &boc).context("Failed to write state")?;
+
+        Ok(ExportStateReport {
+            block_id: block_id.display().to_string(),
+            bytes: boc.len() as u64,
+        })
+    }
+
+    /// Removes blocks which are not needed for the last key block
+    /// with seqno not greater than the specified one
+    pub async fn gc_blocks(
+        &self,
+        before_seqno: u32,
+        max_blocks_per_batch: Option<usize>,
+    ) -> Result<GcBlocksReport> {
+        let target_block = self
+            .db
+            .block_handle_storage()
+            .find_prev_key_block(before_seqno.saturating_add(1))?
+            .filter(|handle| handle.meta().has_data())
+            .ok_or(MaintenanceError::BlockNotFound)?;
+
+        let stats = self
+            .db
+            .block_storage()
+            .remove_blocks_before(&target_block, max_blocks_per_batch)
+            .await?;
+
+        Ok(GcBlocksReport {
+            target_block_id: target_block.id().display().to_string(),
+            mc_package_entries_removed: stats.mc_package_entries_removed,
+            total_package_entries_removed: stats.total_package_entries_removed,
+            total_handles_removed: stats.total_handles_removed,
+            message_index_entries_removed: stats.message_index_entries_removed,
+        })
+    }
+
+    /// Restores the key blocks index by walking the masterchain
+    /// from the oldest known key block
+    pub fn rebuild_index(&self) -> Result<RebuildIndexReport> {
+        let block_handle_storage = self.db.block_handle_storage();
+        let block_connection_storage = self.db.block_connection_storage();
+
+        let mut block_id = match block_handle_storage
+            .key_blocks_iterator(KeyBlocksDirection::ForwardFrom(0))
+            .next()
+        {
+            Some(block_id) => block_id?,
+            None => self.db.node_state().load_init_mc_block_id()?,
+        };
+
+        let mut report = RebuildIndexReport::default();
+        loop {
+            let handle = block_handle_storage
+                .load_handle(&block_id)?
+                .ok_or(MaintenanceError::BlockNotFound)?;
+            if handle.is_key_block() {
+                block_handle_storage.store_handle(&handle)?;
+                report.key_blocks += 1;
+            }
+            report.mc_blocks += 1;
+
+            block_id =
+                match block_connection_storage.load_connection(&block_id, BlockConnection::Next1) {
+                    Ok(next_id) => next_id,
+                    Err(_) => break,
+                };
+        }
+
+        Ok(report)
+    }
+
+    /// Returns ids of the masterchain blocks in the specified range (inclusive)
+    fn mc_block_ids(&self, from: u32, to: u32) -> Result<Vec<ton_block::BlockIdExt>> {
+        let block_connection_storage = self.db.block_connection_storage();
+
+        let mut block_id = self
+            .db
+            .block_handle_storage()
+            .find_prev_key_block(from.saturating_add(1))?
+            .ok_or(MaintenanceError::BlockNotFound)?
+            .id()
+            .clone();
+
+        let mut result = Vec::with_capacity(to.saturating_sub(from) as usize + 1);
+        while block_id.seq_no <= to {
+            if block_id.seq_no >= from {
+                result.push(block_id.clone());
+            }
+            block_id =
+                match block_connection_storage.load_connection(&block_id, BlockConnection::Next1) {
+                    Ok(next_id) => next_id,
+                    Err(_) if block_id.seq_no == to => break,
+                    Err(_) => return Err(MaintenanceError::BlockNotFound.into()),
+                };
+        }
+
+        Ok(result)
+    }
+}
+
+/// Checks whether the RocksDB `LOCK` file is locked by another process
+fn is_db_locked(rocks_db_path: &Path) -> Result<bool> {
+    let file = match std::fs::File::open(rocks_db_path.join("LOCK")) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e).context("Failed to open DB lock file"),
+    };
+
+    // NOTE: RocksDB uses POSIX record locks for the `LOCK` file
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = libc::F_WRLCK as _;
+    lock.l_whence = libc::SEEK_SET as _;
+
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETLK, &mut lock) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("Failed to check DB lock");
+    }
+    Ok(lock.l_type != libc::F_UNLCK as _)
+}
+
+fn is_lock_error(error: &anyhow::Error) -> bool {
+    error.chain().any(|e| {
+        matches!(
+            e.downcast_ref::<rocksdb::Error>(),
+            Some(e) if e.kind() == rocksdb::ErrorKind::IOError
+        )
+    })
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct StatusReport {
+    pub init_mc_block_seqno: Option<u32>,
+    pub last_mc_block_seqno: Option<u32>,
+    pub shards_client_mc_block_seqno: Option<u32>,
+    pub last_key_block_seqno: Option<u32>,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct VerifyStorageReport {
+    pub mc_blocks: usize,
+    pub shard_blocks: usize,
+    /// Blocks without data or proofs
+    pub missing: Vec<String>,
+    pub corrupted: Vec<CorruptedBlock>,
+}
+
+impl VerifyStorageReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.corrupted.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CorruptedBlock {
+    pub block_id: String,
+    pub error: String,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ExportArchiveReport {
+    pub mc_blocks: usize,
+    pub shard_blocks: usize,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExportStateReport {
+    pub block_id: String,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GcBlocksReport {
+    pub target_block_id: String,
+    pub mc_package_entries_removed: usize,
+    pub total_package_entries_removed: usize,
+    pub total_handles_removed: usize,
+    pub message_index_entries_removed: usize,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct RebuildIndexReport {
+    pub mc_blocks: usize,
+    pub key_blocks: usize,
+}
+
+#[derive(thiserror::Error, Debug)]
+enum MaintenanceError {
+    #[error("DB is locked by another process (is the node still running?)")]
+    DbLocked,
+    #[error("Block not found")]
+    BlockNotFound,
+    #[error("Block proof not found")]
+    BlockProofNotFound,
+}
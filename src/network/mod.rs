@@ -15,6 +15,8 @@ use global_config::*;
 use tokio_util::sync::CancellationToken;
 use ton_types::FxDashMap;
 
+#[cfg(any(test, feature = "mock-network"))]
+pub use self::overlay_client::MockTransport;
 pub use self::overlay_client::OverlayClient;
 pub use neighbour::Neighbour;
 use neighbours::Neighbours;
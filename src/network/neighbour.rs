@@ -192,6 +192,19 @@ impl Neighbour {
         set_roundtrip(&self.roundtrip_rldp, roundtrip)
     }
 
+    /// Makes the neighbour much less likely to be selected after the invalid response
+    pub fn penalize(&self) {
+        self.unreliability
+            .fetch_add(MISBEHAVIOUR_UNRELIABILITY, Ordering::Release);
+    }
+
+    /// Excludes the neighbour from the selection until it answers enough queries
+    /// successfully. Used for the responses which are harmful (e.g. too large)
+    pub fn penalize_fully(&self) {
+        self.unreliability
+            .fetch_add(FAIL_UNRELIABILITY, Ordering::Release);
+    }
+
     pub fn unreliability(&self) -> u32 {
         self.unreliability.load(Ordering::Acquire)
     }
@@ -219,3 +232,35 @@ fn set_roundtrip(storage: &AtomicU64, roundtrip: u64) {
 const PROTO_VERSION: u32 = 2;
 const PROTO_CAPABILITIES: u64 = 1;
 const FAIL_UNRELIABILITY: u32 = 10;
+const MISBEHAVIOUR_UNRELIABILITY: u32 = FAIL_UNRELIABILITY / 2;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn penalties() {
+        let neighbour = Neighbour::new(adnl::NodeIdShort::new([1; 32]), Default::default());
+        neighbour.update_proto_version(proto::Capabilities {
+            version: PROTO_VERSION,
+            capabilities: PROTO_CAPABILITIES,
+        });
+        let can_be_selected = |neighbour: &Neighbour| {
+            let mut total_weight = 0;
+            neighbour.try_select(&mut rand::thread_rng(), &mut total_weight, 1.0);
+            total_weight > 0
+        };
+        assert!(can_be_selected(&neighbour));
+
+        neighbour.penalize();
+        assert_eq!(neighbour.unreliability(), MISBEHAVIOUR_UNRELIABILITY);
+        assert!(can_be_selected(&neighbour));
+
+        neighbour.penalize_fully();
+        assert_eq!(
+            neighbour.unreliability(),
+            MISBEHAVIOUR_UNRELIABILITY + FAIL_UNRELIABILITY
+        );
+        assert!(!can_be_selected(&neighbour));
+    }
+}
@@ -8,7 +8,7 @@ use tokio::sync::Semaphore;
 
 use super::neighbour::*;
 use super::neighbours_cache::*;
-use crate::utils::FxDashSet;
+use crate::utils::{ExpiringSet, ExpiringSetMetrics};
 
 pub struct Neighbours {
     dht: Arc<dht::Node>,
@@ -16,7 +16,7 @@ pub struct Neighbours {
     options: NeighboursOptions,
 
     cache: Arc<NeighboursCache>,
-    overlay_peers: FxDashSet<adnl::NodeIdShort>,
+    overlay_peers: ExpiringSet<adnl::NodeIdShort>,
 
     failed_attempts: AtomicU64,
     all_attempts: AtomicU64,
@@ -49,6 +49,12 @@ pub struct NeighboursOptions {
     pub max_ping_tasks: usize,
     /// Default: 6
     pub max_exchange_tasks: usize,
+    /// Known overlay peers which were not seen during this time are forgotten.
+    ///
+    /// Default: 1800
+    pub overlay_peers_ttl_sec: u64,
+    /// Default: 4096
+    pub max_overlay_peers: usize,
 }
 
 impl Default for NeighboursOptions {
@@ -64,6 +70,8 @@ impl Default for NeighboursOptions {
             default_rldp_roundtrip_ms: 2000,
             max_ping_tasks: 6,
             max_exchange_tasks: 6,
+            overlay_peers_ttl_sec: 1800,
+            max_overlay_peers: 4096,
         }
     }
 }
@@ -88,7 +96,10 @@ impl Neighbours {
             overlay: overlay.clone(),
             options,
             cache,
-            overlay_peers: Default::default(),
+            overlay_peers: ExpiringSet::new(
+                Duration::from_secs(options.overlay_peers_ttl_sec),
+                options.max_overlay_peers,
+            ),
             failed_attempts: Default::default(),
             all_attempts: Default::default(),
             start: Instant::now(),
@@ -172,7 +183,12 @@ impl Neighbours {
 
                     let new_peers = match neighbours
                         .overlay
-                        .exchange_random_peers(&adnl, &peer_id, &neighbours.overlay_peers, None)
+                        .exchange_random_peers(
+                            &adnl,
+                            &peer_id,
+                            neighbours.overlay_peers.current_generation(),
+                            None,
+                        )
                         .await
                     {
                         Ok(Some(new_peers)) if !new_peers.is_empty() => new_peers,
@@ -209,6 +225,7 @@ impl Neighbours {
     pub fn metrics(&self) -> NeighboursMetrics {
         NeighboursMetrics {
             peer_search_task_count: self.peer_search_task_count.load(Ordering::Acquire),
+            overlay_peers: self.overlay_peers.metrics(),
         }
     }
 
@@ -224,6 +241,19 @@ impl Neighbours {
         self.overlay_peers.insert(peer_id);
     }
 
+    /// Returns the neighbour with the specified id. Unknown peers are not
+    /// added to the cache so they don't affect neighbour selection
+    pub fn get_or_create(&self, peer_id: &adnl::NodeIdShort) -> Arc<Neighbour> {
+        self.cache.get(peer_id).unwrap_or_else(|| {
+            Arc::new(Neighbour::new(
+                *peer_id,
+                NeighbourOptions {
+                    default_rldp_roundtrip_ms: self.options.default_rldp_roundtrip_ms,
+                },
+            ))
+        })
+    }
+
     pub fn choose_neighbour(&self) -> Option<Arc<Neighbour>> {
         self.cache
             .choose_neighbour(&mut rand::thread_rng(), self.average_failures())
@@ -389,6 +419,7 @@ impl Neighbours {
 #[derive(Debug, Copy, Clone)]
 pub struct NeighboursMetrics {
     pub peer_search_task_count: usize,
+    pub overlay_peers: ExpiringSetMetrics,
 }
 
 fn ordered_boundaries<T>(min: T, max: T) -> (T, T)
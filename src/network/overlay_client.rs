@@ -13,6 +13,8 @@ pub struct OverlayClient {
     rldp: Arc<rldp::Node>,
     overlay: Arc<overlay::Overlay>,
     neighbours: Arc<Neighbours>,
+    #[cfg(any(test, feature = "mock-network"))]
+    mock_transport: once_cell::sync::OnceCell<Arc<dyn MockTransport>>,
 }
 
 impl OverlayClient {
@@ -25,9 +27,19 @@ impl OverlayClient {
             rldp,
             overlay,
             neighbours,
+            #[cfg(any(test, feature = "mock-network"))]
+            mock_transport: Default::default(),
         }
     }
 
+    /// Sends all queries through the in-process transport instead of ADNL and RLDP.
+    ///
+    /// Returns `false` if the transport was already set
+    #[cfg(any(test, feature = "mock-network"))]
+    pub fn set_mock_transport(&self, transport: Arc<dyn MockTransport>) -> bool {
+        self.mock_transport.set(transport).is_ok()
+    }
+
     pub fn overlay(&self) -> &Arc<overlay::Overlay> {
         &self.overlay
     }
@@ -162,6 +174,12 @@ impl OverlayClient {
             timeout.or_else(|| Some(adnl.compute_query_timeout(neighbour.roundtrip_adnl())));
         let peer_id = neighbour.peer_id();
 
+        #[cfg(any(test, feature = "mock-network"))]
+        if let Some(transport) = self.mock_transport.get() {
+            let answer = transport.query(peer_id, tl_proto::serialize(query)).await?;
+            return Ok(answer.and_then(|answer| tl_proto::deserialize::<A>(&answer).ok()));
+        }
+
         let now = Instant::now();
         let answer = self
             .overlay
@@ -206,6 +224,17 @@ impl OverlayClient {
     {
         const ATTEMPT_INTERVAL: u64 = 50; // Milliseconds
 
+        #[cfg(any(test, feature = "mock-network"))]
+        if let Some(transport) = self.mock_transport.get() {
+            let answer = transport
+                .query(neighbour.peer_id(), tl_proto::serialize(query))
+                .await?;
+            return match answer {
+                Some(answer) => Ok((answer, neighbour, 0)),
+                None => Err(OverlayClientError::NoRldpQueryAnswer(*neighbour.peer_id()).into()),
+            };
+        }
+
         let (answer, roundtrip) = self
             .overlay
             .rldp_query(
@@ -234,6 +263,14 @@ impl OverlayClient {
     }
 }
 
+/// In-process replacement of the ADNL and RLDP transports of the overlay client
+#[cfg(any(test, feature = "mock-network"))]
+#[async_trait::async_trait]
+pub trait MockTransport: Send + Sync {
+    /// Returns the answer of the peer or `None` if it didn't reply
+    async fn query(&self, peer_id: &adnl::NodeIdShort, query: Vec<u8>) -> Result<Option<Vec<u8>>>;
+}
+
 const DEFAULT_ADNL_ATTEMPTS: u32 = 50;
 
 struct ResolvedAddress(Option<SocketAddrV4>);
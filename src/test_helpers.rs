@@ -0,0 +1,290 @@
+//! Helpers shared by unit tests
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use global_config::GlobalConfig;
+use ton_block::Serializable;
+
+use crate::config::NodeConfig;
+use crate::db::{Db, DbBuilder, DbCaches};
+use crate::engine::{Engine, Subscriber};
+use crate::utils::{make_archive_segment, BlockStuff, PackageEntryId, ARCHIVE_PREFIX};
+
+/// Directory which is removed on drop
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates an empty directory with a name unique for the test process
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("ton_indexer_{name}_{}", std::process::id()));
+        std::fs::remove_dir_all(&path).ok();
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.0.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.0).ok();
+    }
+}
+
+/// Opens a fresh DB with all columns
+pub async fn test_db(dir: &TempDir) -> Arc<Db> {
+    Db::new(
+        dir.join("rocksdb"),
+        dir.join("file"),
+        "temp",
+        0,
+        Default::default(),
+    )
+    .await
+    .unwrap()
+}
+
+/// Builder of a fresh DB with only the declared columns
+pub fn test_db_builder<'a>(dir: &TempDir, caches: &'a DbCaches) -> DbBuilder<'a> {
+    DbBuilder::new(dir.join("rocksdb"), caches).options(|opts, _| {
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+    })
+}
+
+/// Block id with hashes derived from the seqno
+pub fn block_id(shard_id: ton_block::ShardIdent, seq_no: u32) -> ton_block::BlockIdExt {
+    ton_block::BlockIdExt {
+        shard_id,
+        seq_no,
+        root_hash: ton_types::UInt256::from([seq_no as u8; 32]),
+        file_hash: ton_types::UInt256::from([seq_no as u8; 32]),
+    }
+}
+
+pub fn mc_block_id(seq_no: u32) -> ton_block::BlockIdExt {
+    block_id(ton_block::ShardIdent::masterchain(), seq_no)
+}
+
+/// Engine over a fresh DB without any DHT nodes.
+///
+/// NOTE: the engine is not started
+pub async fn test_engine(dir: &TempDir, subscribers: Vec<Arc<dyn Subscriber>>) -> Arc<Engine> {
+    test_engine_with_global_config(dir, test_global_config(), subscribers).await
+}
+
+/// Global config of the test network without any DHT nodes
+pub fn test_global_config() -> GlobalConfig {
+    GlobalConfig {
+        dht_nodes: Vec::new(),
+        zero_state: mc_block_id(0),
+        init_block: None,
+        hard_forks: Vec::new(),
+    }
+}
+
+/// Same as [`test_engine`], but for the network from the specified global config
+pub async fn test_engine_with_global_config(
+    dir: &TempDir,
+    global_config: GlobalConfig,
+    subscribers: Vec<Arc<dyn Subscriber>>,
+) -> Arc<Engine> {
+    Engine::new(test_node_config(dir), global_config, subscribers)
+        .await
+        .unwrap()
+}
+
+/// Default node config with a free port and all paths inside the specified dir
+pub fn test_node_config(dir: &TempDir) -> NodeConfig {
+    // Take a free port for the ADNL socket
+    let port = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+        .and_then(|socket| socket.local_addr())
+        .unwrap()
+        .port();
+
+    NodeConfig {
+        ip_address: SocketAddrV4::new(Ipv4Addr::LOCALHOST, port),
+        rocks_db_path: dir.join("rocksdb"),
+        file_db_path: dir.join("file"),
+        temp_files_path: dir.join("temp"),
+        ..Default::default()
+    }
+}
+
+/// Serialized empty masterchain block with its id
+pub fn make_mc_block(seq_no: u32) -> (ton_block::BlockIdExt, Vec<u8>) {
+    let mut extra = ton_block::BlockExtra::default();
+    extra
+        .write_custom(Some(&ton_block::McBlockExtra::default()))
+        .unwrap();
+
+    make_block(ton_block::ShardIdent::masterchain(), seq_no, extra)
+}
+
+/// Serialized block with the specified extra and its id.
+///
+/// NOTE: gen utime is equal to the seqno, shard blocks refer to the default masterchain block
+pub fn make_block(
+    shard_id: ton_block::ShardIdent,
+    seq_no: u32,
+    extra: ton_block::BlockExtra,
+) -> (ton_block::BlockIdExt, Vec<u8>) {
+    make_block_with_info(shard_id, seq_no, extra, |_| {})
+}
+
+/// Serialized masterchain block which commits the specified top shard blocks and its id
+pub fn make_mc_block_with_shards(
+    seq_no: u32,
+    top_blocks: &[ton_block::BlockIdExt],
+) -> (ton_block::BlockIdExt, Vec<u8>) {
+    let mut mc_extra = ton_block::McBlockExtra::default();
+    for top_block in top_blocks {
+        let descr = ton_block::ShardDescr {
+            seq_no: top_block.seq_no,
+            root_hash: top_block.root_hash,
+            file_hash: top_block.file_hash,
+            ..Default::default()
+        };
+        mc_extra
+            .shards_mut()
+            .set(
+                &top_block.shard_id.workchain_id(),
+                &ton_block::InRefValue(ton_block::BinTree::with_item(&descr).unwrap()),
+            )
+            .unwrap();
+    }
+    let mut extra = ton_block::BlockExtra::default();
+    extra.write_custom(Some(&mc_extra)).unwrap();
+
+    make_block(ton_block::ShardIdent::masterchain(), seq_no, extra)
+}
+
+/// Serialized masterchain key block with the specified config and its id
+pub fn make_key_block_with_config(
+    seq_no: u32,
+    prev_key_block_seqno: u32,
+    config: ton_block::ConfigParams,
+) -> (ton_block::BlockIdExt, Vec<u8>) {
+    let mut mc_extra = ton_block::McBlockExtra::default();
+    *mc_extra.config_mut() = Some(config);
+    let mut extra = ton_block::BlockExtra::default();
+    extra.write_custom(Some(&mc_extra)).unwrap();
+
+    make_block_with_info(
+        ton_block::ShardIdent::masterchain(),
+        seq_no,
+        extra,
+        |info| {
+            info.set_key_block(true);
+            info.set_prev_key_block_seqno(prev_key_block_seqno);
+        },
+    )
+}
+
+fn make_block_with_info(
+    shard_id: ton_block::ShardIdent,
+    seq_no: u32,
+    extra: ton_block::BlockExtra,
+    update_info: impl FnOnce(&mut ton_block::BlockInfo),
+) -> (ton_block::BlockIdExt, Vec<u8>) {
+    let mut info = ton_block::BlockInfo::default();
+    info.set_shard(shard_id);
+    info.set_seq_no(seq_no).unwrap();
+    info.set_gen_utime(seq_no.into());
+    if !shard_id.is_masterchain() {
+        info.write_master_ref(Some(&ton_block::BlkMasterInfo::default()))
+            .unwrap();
+    }
+    update_info(&mut info);
+
+    let block =
+        ton_block::Block::with_params(0, info, Default::default(), Default::default(), extra)
+            .unwrap();
+
+    let root = block.serialize().unwrap();
+    let data = ton_types::serialize_toc(&root).unwrap();
+    let id = ton_block::BlockIdExt {
+        shard_id,
+        seq_no,
+        root_hash: root.repr_hash(),
+        file_hash: ton_types::UInt256::calc_file_hash(&data),
+    };
+    (id, data)
+}
+
+/// Unsigned proof which contains the whole block
+pub fn make_block_proof(id: &ton_block::BlockIdExt, data: &[u8]) -> Vec<u8> {
+    let root = ton_types::deserialize_tree_of_cells(&mut std::convert::identity(data)).unwrap();
+    let proof = ton_block::BlockProof {
+        proof_for: id.clone(),
+        root: ton_block::MerkleProof::create(&root, |_| true)
+            .and_then(|proof| proof.serialize())
+            .unwrap(),
+        signatures: None,
+    };
+    ton_types::serialize_toc(&proof.serialize().unwrap()).unwrap()
+}
+
+/// Archive package with the data and unsigned proofs of the specified blocks
+pub fn make_archive(blocks: &[(ton_block::BlockIdExt, Vec<u8>)]) -> Vec<u8> {
+    let mut archive = ARCHIVE_PREFIX.to_vec();
+    for (id, data) in blocks {
+        let proof_id = match id.is_masterchain() {
+            true => PackageEntryId::Proof(id),
+            false => PackageEntryId::ProofLink(id),
+        };
+        archive.extend_from_slice(&make_archive_segment(
+            &PackageEntryId::Block(id).to_filename(),
+            data,
+        ));
+        archive.extend_from_slice(&make_archive_segment(
+            &proof_id.to_filename(),
+            &make_block_proof(id, data),
+        ));
+    }
+    archive
+}
+
+/// Block with a transaction for each account triggered by an external message.
+/// Returns the block and the hashes of the messages
+pub fn make_block_with_messages(
+    shard_id: ton_block::ShardIdent,
+    seq_no: u32,
+    accounts: &[ton_types::UInt256],
+) -> (BlockStuff, Vec<ton_types::UInt256>) {
+    let mut account_blocks = ton_block::ShardAccountBlocks::default();
+    let mut msg_hashes = Vec::new();
+    for (i, account) in accounts.iter().enumerate() {
+        let msg = ton_block::Message::with_ext_in_header(ton_block::ExternalInboundMessageHeader {
+            dst: ton_block::MsgAddressInt::with_standart(None, 0, account.clone().into()).unwrap(),
+            ..Default::default()
+        });
+
+        let mut tx = ton_block::Transaction::with_address_and_status(
+            account.clone().into(),
+            ton_block::AccountStatus::AccStateActive,
+        );
+        tx.set_logical_time(i as u64 + 1);
+        tx.write_in_msg(Some(&msg)).unwrap();
+        msg_hashes.push(tx.in_msg_cell().unwrap().repr_hash());
+
+        let mut account_block = ton_block::AccountBlock::with_address(account.clone().into());
+        account_block.add_transaction(&tx).unwrap();
+        account_blocks.insert(&account_block).unwrap();
+    }
+
+    let mut extra = ton_block::BlockExtra::default();
+    extra.write_account_blocks(&account_blocks).unwrap();
+
+    let (id, data) = make_block(shard_id, seq_no, extra);
+    let block = BlockStuff::deserialize_checked(id, &data).unwrap();
+    (block, msg_hashes)
+}
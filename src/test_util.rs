@@ -0,0 +1,310 @@
+//! Helpers to benchmark internal components on recorded data
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use ton_block::Deserializable;
+
+use crate::db::{BlockHandle, Db};
+use crate::engine::complex_operations::BlockMaps;
+use crate::utils::*;
+
+pub use self::synthetic_chain::*;
+
+mod synthetic_chain;
+
+/// Parses and deserializes all blocks and proofs of the archive package
+/// the same way as it is done during sync.
+///
+/// Returns the number of blocks in the archive
+pub fn import_package(data: bytes::Bytes) -> Result<usize> {
+    let maps = BlockMaps::from_bytes(data)?;
+    Ok(maps.blocks.len())
+}
+
+/// Same as [`import_package`], but all entries are copied out of the archive
+/// (as it was done before sharing the archive buffer)
+pub fn import_package_copied(data: &[u8]) -> Result<usize> {
+    let maps = BlockMaps::new(data)?;
+    Ok(maps.blocks.len())
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct ArchiveImportStats {
+    pub blocks: usize,
+    /// Size of the stored block data and proofs
+    pub bytes_written: u64,
+    /// Time spent on the import (without opening the DB)
+    pub import_time: Duration,
+}
+
+/// Imports all blocks and proofs of the archive package into a fresh DB in the
+/// specified directory the same way as it is done during sync: entries are parsed,
+/// proofs are pre-checked and both are stored with the handles.
+///
+/// NOTE: signatures are not checked since there are no key blocks in the DB.
+/// The directory is removed afterwards
+pub async fn import_archive<P: AsRef<Path>>(
+    path: P,
+    data: bytes::Bytes,
+) -> Result<ArchiveImportStats> {
+    let path = TempDir(path.as_ref().to_path_buf());
+    let db = open_db(&path).await?;
+
+    let result = import_archive_into(&db, data).await;
+    drop(db);
+    result
+}
+
+async fn import_archive_into(db: &Arc<Db>, data: bytes::Bytes) -> Result<ArchiveImportStats> {
+    let block_storage = db.block_storage();
+
+    let started_at = Instant::now();
+
+    let maps = BlockMaps::from_bytes(data)?;
+    let mut blocks = 0;
+    let mut bytes_written = 0;
+    for (id, entry) in &maps.blocks {
+        let (block, proof) = entry.get_data()?;
+        let (_, info) = proof.pre_check_block_proof()?;
+
+        // NOTE: shard blocks are saved without the masterchain ref as in the normal sync
+        let mc_seq_no = if id.is_masterchain() { id.seq_no } else { 0 };
+        let info = BriefBlockInfo::from(&info);
+        let result = block_storage
+            .store_block_data(block, info.with_mc_seq_no(mc_seq_no))
+            .await?;
+        if result.new {
+            bytes_written += block.new_archive_data()?.len() as u64;
+        }
+
+        let result = block_storage
+            .store_block_proof(proof, result.handle.into())
+            .await?;
+        if result.new {
+            bytes_written += proof.new_archive_data()?.len() as u64;
+        }
+
+        blocks += 1;
+    }
+
+    Ok(ArchiveImportStats {
+        blocks,
+        bytes_written,
+        import_time: started_at.elapsed(),
+    })
+}
+
+/// Persistent state BOC with its block id
+pub struct RecordedState {
+    pub block_id: ton_block::BlockIdExt,
+    pub data: Vec<u8>,
+}
+
+impl RecordedState {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let data = std::fs::read(path).context("Failed to read state")?;
+
+        let root = ton_types::deserialize_tree_of_cells(&mut data.as_slice())?;
+        let state = ton_block::ShardStateUnsplit::construct_from(&mut root.clone().into())?;
+        let block_id = ton_block::BlockIdExt {
+            shard_id: *state.shard(),
+            seq_no: state.seq_no(),
+            root_hash: root.repr_hash(),
+            file_hash: ton_types::UInt256::calc_file_hash(&data),
+        };
+
+        Ok(Self { block_id, data })
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct StateImportStats {
+    pub cells: u64,
+    /// Time spent on writing cells to the DB
+    pub finalize_time: Duration,
+}
+
+/// Imports the state into a fresh DB in the specified directory.
+///
+/// NOTE: the directory is removed afterwards
+pub async fn import_state<P: AsRef<Path>>(
+    path: P,
+    state: &RecordedState,
+    packet_size: usize,
+) -> Result<StateImportStats> {
+    let path = TempDir(path.as_ref().to_path_buf());
+    let db = open_db(&path).await?;
+
+    let result = import_state_into(&db, state, packet_size).await;
+    drop(db);
+    result
+}
+
+async fn import_state_into(
+    db: &Arc<Db>,
+    state: &RecordedState,
+    packet_size: usize,
+) -> Result<StateImportStats> {
+    let (mut transaction, mut ctx) = db
+        .shard_state_storage()
+        .begin_replace(&state.block_id)
+        .await?;
+
+    let mut pg = ProgressBar::builder("importing state")
+        .exact_unit("cells")
+        .build();
+
+    let mut full = false;
+    for packet in state.data.chunks(packet_size.max(1)) {
+        if transaction
+            .process_packet(&mut ctx, packet.to_vec(), &mut pg)
+            .await?
+        {
+            full = true;
+            break;
+        }
+    }
+    anyhow::ensure!(full, "Unexpected end of state");
+
+    let cells = transaction
+        .header()
+        .as_ref()
+        .map(|header| header.cell_count)
+        .unwrap_or_default();
+
+    let mut pg = ProgressBar::builder("processing state")
+        .exact_unit("bytes")
+        .build();
+
+    let started_at = Instant::now();
+    let result = transaction
+        .finalize(&mut ctx, state.block_id.clone(), &mut pg)
+        .await;
+    let finalize_time = started_at.elapsed();
+
+    ctx.clear().await?;
+    result?;
+
+    Ok(StateImportStats {
+        cells,
+        finalize_time,
+    })
+}
+
+/// Way of reading stored block data
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BlockReadMode {
+    /// Value is copied out of RocksDB before parsing
+    Owned,
+    /// Value is parsed directly from the pinned RocksDB slice
+    Pinned,
+}
+
+/// Blocks of the archive package stored in a fresh DB.
+///
+/// NOTE: the directory is removed on drop
+pub struct StoredBlocks {
+    db: Arc<Db>,
+    handles: Vec<Arc<BlockHandle>>,
+    _path: TempDir,
+}
+
+impl StoredBlocks {
+    /// Stores all blocks of the archive package into a fresh DB in the specified directory
+    pub async fn new<P: AsRef<Path>>(path: P, data: bytes::Bytes) -> Result<Self> {
+        let path = TempDir(path.as_ref().to_path_buf());
+        let db = open_db(&path).await?;
+
+        let maps = BlockMaps::from_bytes(data)?;
+        let mut handles = Vec::with_capacity(maps.blocks.len());
+        for entry in maps.blocks.values() {
+            if let Some(block) = &entry.block {
+                let info = BriefBlockInfo::from(&block.data.block().read_info()?);
+                let result = db
+                    .block_storage()
+                    .store_block_data(block, info.with_mc_seq_no(0))
+                    .await?;
+                handles.push(result.handle);
+            }
+        }
+
+        Ok(Self {
+            db,
+            handles,
+            _path: path,
+        })
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.handles.len()
+    }
+
+    /// Loads and parses all stored blocks, as it is done during replay
+    pub async fn load_all(&self, mode: BlockReadMode) -> Result<()> {
+        let block_storage = self.db.block_storage();
+        for handle in &self.handles {
+            match mode {
+                BlockReadMode::Owned => {
+                    let data = block_storage.load_block_data_raw(handle).await?;
+                    BlockStuff::deserialize_unchecked(handle.id().clone(), &data)?;
+                }
+                BlockReadMode::Pinned => {
+                    block_storage.load_block_data(handle).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Creates a fresh node DB in the specified directories with the masterchain
+/// pointers set to `mc_block_id` (as if the node has been stopped right after
+/// the boot from it)
+pub async fn create_db<PS, PF>(
+    rocksdb_path: PS,
+    file_db_path: PF,
+    mc_block_id: &ton_block::BlockIdExt,
+) -> Result<()>
+where
+    PS: AsRef<Path>,
+    PF: AsRef<Path>,
+{
+    let db = Db::new(
+        rocksdb_path,
+        file_db_path,
+        "temp",
+        64 << 20,
+        Default::default(),
+    )
+    .await?;
+
+    let node_state = db.node_state();
+    node_state.store_init_mc_block_id(mc_block_id)?;
+    node_state.store_last_mc_block_id(mc_block_id)?;
+    node_state.store_shards_client_mc_block_id(mc_block_id)?;
+    Ok(())
+}
+
+async fn open_db(path: &TempDir) -> Result<Arc<Db>> {
+    Db::new(
+        path.0.join("rocksdb"),
+        path.0.join("file"),
+        path.0.join("temp"),
+        1 << 30,
+        Default::default(),
+    )
+    .await
+}
+
+struct TempDir(PathBuf);
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_dir_all(&self.0) {
+            tracing::warn!(path = %self.0.display(), "failed to remove temp dir: {e:?}");
+        }
+    }
+}
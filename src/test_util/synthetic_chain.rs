@@ -0,0 +1,485 @@
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use everscale_crypto::ed25519;
+use global_config::GlobalConfig;
+use ton_block::Serializable;
+use ton_types::{Cell, UInt256};
+
+use crate::db::{BlockConnection, BlockMetaData, Db};
+use crate::utils::*;
+
+/// Number of validators which sign all masterchain blocks
+const VALIDATOR_COUNT: u8 = 3;
+
+/// Deterministic chain with one basechain shard, where each masterchain block
+/// commits the next shard block.
+///
+/// Masterchain blocks have full proofs signed by the validators from the zerostate
+/// config, shard blocks have proof links. States are updated by real merkle updates,
+/// so blocks could be applied on top of the zerostates.
+///
+/// NOTE: only the block times depend on the arguments, so the chain is
+/// regenerated instead of being stored (the cold boot depends on the current time)
+pub struct SyntheticChain {
+    pub mc_zero_state: SyntheticState,
+    pub wc_zero_state: SyntheticState,
+    /// Masterchain blocks starting from seqno 1
+    pub mc_blocks: Vec<SyntheticBlock>,
+    /// Shard blocks in the same order, the shard block `n` is committed
+    /// by the masterchain block `n`
+    pub shard_blocks: Vec<SyntheticBlock>,
+}
+
+pub struct SyntheticState {
+    pub id: ton_block::BlockIdExt,
+    pub gen_utime: u32,
+    /// Serialized state BOC
+    pub data: Vec<u8>,
+}
+
+pub struct SyntheticBlock {
+    pub id: ton_block::BlockIdExt,
+    pub gen_utime: u32,
+    pub data: Vec<u8>,
+    /// Full proof for masterchain blocks and proof link for shard blocks
+    pub proof: Vec<u8>,
+}
+
+impl SyntheticBlock {
+    /// Seqno of the masterchain block which commits this block
+    pub fn mc_seq_no(&self) -> u32 {
+        self.id.seq_no
+    }
+}
+
+impl SyntheticChain {
+    /// Generates the chain of `len` masterchain blocks.
+    ///
+    /// `block_utime` returns the gen utime of the blocks with the specified seqno
+    pub fn generate<F>(len: u32, zero_state_utime: u32, block_utime: F) -> Result<Self>
+    where
+        F: Fn(u32) -> u32,
+    {
+        let validators = Validators::new()?;
+
+        let mc_shard = ton_block::ShardIdent::masterchain();
+        let wc_shard = ton_block::ShardIdent::full(ton_block::BASE_WORKCHAIN_ID);
+
+        // Basechain zerostate
+        let mut wc_state = ton_block::ShardStateUnsplit::with_ident(wc_shard);
+        wc_state.set_gen_time(zero_state_utime);
+        let (wc_zero_state, mut wc_root) = make_state(&wc_state, zero_state_utime)?;
+
+        // Masterchain zerostate with the config
+        let mut config = ton_block::ConfigParams::default();
+        config.set_config(ton_block::ConfigParamEnum::ConfigParam34(
+            ton_block::ConfigParam34 {
+                cur_validators: validators.set.clone(),
+            },
+        ))?;
+        config.set_config(ton_block::ConfigParamEnum::ConfigParam28(
+            validators.catchain_config.clone(),
+        ))?;
+        let mut workchains = ton_block::ConfigParam12::new();
+        workchains.insert(
+            ton_block::BASE_WORKCHAIN_ID,
+            &ton_block::WorkchainDescr {
+                zerostate_root_hash: wc_zero_state.id.root_hash,
+                zerostate_file_hash: wc_zero_state.id.file_hash,
+                ..Default::default()
+            },
+        )?;
+        config.set_config(ton_block::ConfigParamEnum::ConfigParam12(workchains))?;
+
+        let mut mc_state_extra = ton_block::McStateExtra::default();
+        mc_state_extra.config = config;
+
+        let mut mc_state = ton_block::ShardStateUnsplit::with_ident(mc_shard);
+        mc_state.set_gen_time(zero_state_utime);
+        mc_state.write_custom(Some(&mc_state_extra))?;
+        let (mc_zero_state, mut mc_root) = make_state(&mc_state, zero_state_utime)?;
+
+        let mut prev_mc_id = mc_zero_state.id.clone();
+        let mut prev_wc_id = wc_zero_state.id.clone();
+        let mut mc_blocks = Vec::with_capacity(len as usize);
+        let mut shard_blocks = Vec::with_capacity(len as usize);
+
+        for seq_no in 1..=len {
+            let gen_utime = block_utime(seq_no);
+
+            // Shard block which refers to the previous masterchain block
+            wc_state.set_seq_no(seq_no);
+            wc_state.set_gen_time(gen_utime);
+            let new_wc_root = wc_state.serialize()?;
+
+            let mut info = make_block_info(wc_shard, seq_no, gen_utime, &prev_wc_id)?;
+            info.write_master_ref(Some(&ton_block::BlkMasterInfo {
+                master: make_ext_ref(&prev_mc_id),
+            }))?;
+            let shard_block = make_block(
+                info,
+                ton_block::MerkleUpdate::create(&wc_root, &new_wc_root)?,
+                Default::default(),
+                gen_utime,
+                None,
+            )?;
+            wc_root = new_wc_root;
+
+            // Masterchain block which commits the shard block
+            let descr = ton_block::ShardDescr {
+                seq_no,
+                root_hash: shard_block.id.root_hash,
+                file_hash: shard_block.id.file_hash,
+                gen_utime,
+                ..Default::default()
+            };
+            let mut shards = ton_block::ShardHashes::default();
+            shards.set(
+                &ton_block::BASE_WORKCHAIN_ID,
+                &ton_block::InRefValue(ton_block::BinTree::with_item(&descr)?),
+            )?;
+
+            mc_state_extra.shards = shards.clone();
+            mc_state.set_seq_no(seq_no);
+            mc_state.set_gen_time(gen_utime);
+            mc_state.write_custom(Some(&mc_state_extra))?;
+            let new_mc_root = mc_state.serialize()?;
+
+            let mut mc_extra = ton_block::McBlockExtra::default();
+            *mc_extra.shards_mut() = shards;
+            let mut extra = ton_block::BlockExtra::default();
+            extra.write_custom(Some(&mc_extra))?;
+
+            let mc_block = make_block(
+                make_block_info(mc_shard, seq_no, gen_utime, &prev_mc_id)?,
+                ton_block::MerkleUpdate::create(&mc_root, &new_mc_root)?,
+                extra,
+                gen_utime,
+                Some(&validators),
+            )?;
+            mc_root = new_mc_root;
+
+            prev_wc_id = shard_block.id.clone();
+            prev_mc_id = mc_block.id.clone();
+            shard_blocks.push(shard_block);
+            mc_blocks.push(mc_block);
+        }
+
+        Ok(Self {
+            mc_zero_state,
+            wc_zero_state,
+            mc_blocks,
+            shard_blocks,
+        })
+    }
+
+    /// Global config of the chain without DHT nodes
+    pub fn global_config(&self) -> GlobalConfig {
+        GlobalConfig {
+            dht_nodes: Vec::new(),
+            zero_state: self.mc_zero_state.id.clone(),
+            init_block: None,
+            hard_forks: Vec::new(),
+        }
+    }
+
+    /// The last masterchain block id (or the zerostate id for the empty chain)
+    pub fn head(&self) -> &ton_block::BlockIdExt {
+        match self.mc_blocks.last() {
+            Some(block) => &block.id,
+            None => &self.mc_zero_state.id,
+        }
+    }
+
+    /// Masterchain block with the specified seqno
+    pub fn mc_block(&self, seq_no: u32) -> Option<&SyntheticBlock> {
+        self.mc_blocks.get(seq_no.checked_sub(1)? as usize)
+    }
+
+    /// Shard block which is committed by the masterchain block with the specified seqno
+    pub fn shard_block(&self, mc_seq_no: u32) -> Option<&SyntheticBlock> {
+        self.shard_blocks.get(mc_seq_no.checked_sub(1)? as usize)
+    }
+
+    /// Archive package with the masterchain blocks in the specified range
+    /// and the shard blocks committed by them
+    pub fn archive(&self, mc_seq_nos: RangeInclusive<u32>) -> Vec<u8> {
+        let mut archive = ARCHIVE_PREFIX.to_vec();
+        for seq_no in mc_seq_nos {
+            let blocks = [
+                (self.shard_block(seq_no), true),
+                (self.mc_block(seq_no), false),
+            ];
+            for (block, is_link) in blocks {
+                let block = match block {
+                    Some(block) => block,
+                    None => continue,
+                };
+                let proof_id = match is_link {
+                    true => PackageEntryId::ProofLink(&block.id),
+                    false => PackageEntryId::Proof(&block.id),
+                };
+                archive.extend_from_slice(&make_archive_segment(
+                    &PackageEntryId::Block(&block.id).to_filename(),
+                    &block.data,
+                ));
+                archive.extend_from_slice(&make_archive_segment(
+                    &proof_id.to_filename(),
+                    &block.proof,
+                ));
+            }
+        }
+        archive
+    }
+
+    /// Creates the DB of the node which has applied the whole chain
+    /// and moved all blocks into archives (see [`SyntheticChain::store`]).
+    ///
+    /// NOTE: uses the `rocksdb`, `file` and `temp` subdirectories of the specified path
+    pub async fn create_db<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let db = Db::new(
+            path.join("rocksdb"),
+            path.join("file"),
+            path.join("temp"),
+            64 << 20,
+            Default::default(),
+        )
+        .await?;
+        self.store(&db).await
+    }
+
+    /// Stores zerostates, blocks with proofs and connections as applied
+    /// and moves blocks into archives. Masterchain pointers are set to the head.
+    ///
+    /// NOTE: archive ids are loaded on the DB open, so the DB must be reopened
+    /// to serve the archives
+    pub async fn store(&self, db: &Db) -> Result<()> {
+        let block_handle_storage = db.block_handle_storage();
+        let block_connection_storage = db.block_connection_storage();
+        let block_storage = db.block_storage();
+
+        let mut prev_handles = Vec::with_capacity(2);
+        for state in [&self.mc_zero_state, &self.wc_zero_state] {
+            let (handle, _) = block_handle_storage
+                .create_or_load_handle(&state.id, BlockMetaData::zero_state(state.gen_utime))?;
+            let stuff = ShardStateStuff::deserialize_zerostate(state.id.clone(), &state.data)?;
+            db.shard_state_storage()
+                .store_state(&handle, &stuff)
+                .await?;
+            block_handle_storage.store_block_applied(&handle)?;
+            prev_handles.push(handle);
+        }
+
+        for (mc_block, shard_block) in self.mc_blocks.iter().zip(&self.shard_blocks) {
+            // NOTE: shard block goes first as it is committed by the masterchain block
+            for (prev_handle, block) in prev_handles.iter_mut().rev().zip([shard_block, mc_block]) {
+                let is_link = !block.id.is_masterchain();
+                let stuff = BlockStuff::deserialize_checked(block.id.clone(), &block.data)?;
+                let proof = BlockProofStuff::deserialize(block.id.clone(), &block.proof, is_link)?;
+
+                let meta_data = BlockMetaData {
+                    is_key_block: false,
+                    gen_utime: block.gen_utime,
+                    mc_ref_seqno: Some(block.mc_seq_no()),
+                };
+                let handle = block_storage
+                    .store_block_data(&BlockStuffAug::new(stuff, block.data.clone()), meta_data)
+                    .await?
+                    .handle;
+                block_storage
+                    .store_block_proof(
+                        &BlockProofStuffAug::new(proof, block.proof.clone()),
+                        handle.clone().into(),
+                    )
+                    .await?;
+
+                block_connection_storage.store_connection(
+                    prev_handle,
+                    BlockConnection::Next1,
+                    &block.id,
+                )?;
+                block_connection_storage.store_connection(
+                    &handle,
+                    BlockConnection::Prev1,
+                    prev_handle.id(),
+                )?;
+
+                block_handle_storage.store_block_applied(&handle)?;
+                block_storage.move_into_archive(&handle).await?;
+                *prev_handle = handle;
+            }
+        }
+
+        let node_state = db.node_state();
+        node_state.store_zero_state_id(&self.mc_zero_state.id)?;
+        node_state.store_init_mc_block_id(&self.mc_zero_state.id)?;
+        node_state.store_last_mc_block_id(self.head())?;
+        node_state.store_shards_client_mc_block_id(self.head())?;
+        Ok(())
+    }
+}
+
+/// Validators from the masterchain zerostate config
+struct Validators {
+    keys: Vec<ed25519::KeyPair>,
+    set: ton_block::ValidatorSet,
+    catchain_config: ton_block::CatchainConfig,
+}
+
+impl Validators {
+    fn new() -> Result<Self> {
+        let keys = (1..=VALIDATOR_COUNT)
+            .map(|i| ed25519::KeyPair::from(&ed25519::SecretKey::from_bytes([i; 32])))
+            .collect::<Vec<_>>();
+
+        let list = keys
+            .iter()
+            .map(|key| {
+                Ok(ton_block::ValidatorDescr::with_params(
+                    ton_block::SigPubKey::from_bytes(key.public_key.as_bytes())?,
+                    1,
+                    None,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let set = ton_block::ValidatorSet::new(0, u32::MAX, VALIDATOR_COUNT as u16, list)?;
+
+        Ok(Self {
+            keys,
+            set,
+            catchain_config: Default::default(),
+        })
+    }
+
+    /// Signs the masterchain block by all validators of the subset
+    fn sign(
+        &self,
+        id: &ton_block::BlockIdExt,
+        gen_utime: u32,
+    ) -> Result<ton_block::BlockSignatures> {
+        let (subset, validator_list_hash_short) = self.set.calc_subset(
+            &self.catchain_config,
+            id.shard_id.shard_prefix_with_tag(),
+            id.shard_id.workchain_id(),
+            0,
+            ton_block::UnixTime32(gen_utime),
+        )?;
+
+        let data = ton_block::Block::build_data_for_sign(&id.root_hash, &id.file_hash);
+        let total_weight = subset.iter().map(|descr| descr.weight).sum();
+
+        let mut pure_signatures = ton_block::BlockSignaturesPure::with_weight(total_weight);
+        for descr in &subset {
+            let key = self
+                .keys
+                .iter()
+                .find(|key| key.public_key.as_bytes() == descr.public_key.key_bytes())
+                .context("Unknown validator")?;
+            pure_signatures.add_sigpair(ton_block::CryptoSignaturePair {
+                node_id_short: descr.compute_node_id_short(),
+                sign: ton_block::CryptoSignature::from_bytes(&key.sign_raw(&data))?,
+            });
+        }
+
+        Ok(ton_block::BlockSignatures {
+            validator_info: ton_block::ValidatorBaseInfo {
+                validator_list_hash_short,
+                catchain_seqno: 0,
+            },
+            pure_signatures,
+        })
+    }
+}
+
+fn make_state(
+    state: &ton_block::ShardStateUnsplit,
+    gen_utime: u32,
+) -> Result<(SyntheticState, Cell)> {
+    let root = state.serialize()?;
+    let data = ton_types::serialize_toc(&root)?;
+    let id = ton_block::BlockIdExt {
+        shard_id: *state.shard(),
+        seq_no: 0,
+        root_hash: root.repr_hash(),
+        file_hash: UInt256::calc_file_hash(&data),
+    };
+    Ok((
+        SyntheticState {
+            id,
+            gen_utime,
+            data,
+        },
+        root,
+    ))
+}
+
+fn make_block_info(
+    shard_id: ton_block::ShardIdent,
+    seq_no: u32,
+    gen_utime: u32,
+    prev_id: &ton_block::BlockIdExt,
+) -> Result<ton_block::BlockInfo> {
+    let mut info = ton_block::BlockInfo::default();
+    info.set_shard(shard_id);
+    info.set_seq_no(seq_no)?;
+    info.set_gen_utime(gen_utime.into());
+    info.set_prev_stuff(
+        false,
+        &ton_block::BlkPrevInfo::Block {
+            prev: make_ext_ref(prev_id),
+        },
+    )?;
+    Ok(info)
+}
+
+fn make_ext_ref(id: &ton_block::BlockIdExt) -> ton_block::ExtBlkRef {
+    ton_block::ExtBlkRef {
+        end_lt: 0,
+        seq_no: id.seq_no,
+        root_hash: id.root_hash,
+        file_hash: id.file_hash,
+    }
+}
+
+/// Serializes the block and builds its proof (signed if the validators are specified)
+fn make_block(
+    info: ton_block::BlockInfo,
+    state_update: ton_block::MerkleUpdate,
+    extra: ton_block::BlockExtra,
+    gen_utime: u32,
+    validators: Option<&Validators>,
+) -> Result<SyntheticBlock> {
+    let shard_id = *info.shard();
+    let seq_no = info.seq_no();
+
+    let block = ton_block::Block::with_params(0, info, Default::default(), state_update, extra)?;
+    let root = block.serialize()?;
+    let data = ton_types::serialize_toc(&root)?;
+    let id = ton_block::BlockIdExt {
+        shard_id,
+        seq_no,
+        root_hash: root.repr_hash(),
+        file_hash: UInt256::calc_file_hash(&data),
+    };
+
+    let signatures = match validators {
+        Some(validators) => Some(validators.sign(&id, gen_utime)?),
+        None => None,
+    };
+    let proof = ton_block::BlockProof {
+        proof_for: id.clone(),
+        root: ton_block::MerkleProof::create(&root, |_| true)?.serialize()?,
+        signatures,
+    };
+    let proof = ton_types::serialize_toc(&proof.serialize()?)?;
+
+    Ok(SyntheticBlock {
+        id,
+        gen_utime,
+        data,
+        proof,
+    })
+}
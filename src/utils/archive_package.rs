@@ -1,3 +1,7 @@
+use std::io::Read;
+
+use super::PackageEntryId;
+
 /// Encodes archive package segment
 pub fn make_archive_segment(filename: &str, data: &[u8]) -> Vec<u8> {
     let mut vec = Vec::with_capacity(2 + 2 + 4 + filename.len() + data.len());
@@ -31,6 +35,112 @@ impl<'a> ArchivePackageViewReader<'a> {
     }
 }
 
+/// Archive package reader which doesn't require the whole archive in memory
+pub struct ArchivePackageStreamReader<R> {
+    reader: R,
+    /// Number of bytes which could still be read without exceeding the max archive size
+    remaining: u64,
+}
+
+impl<R: Read> ArchivePackageStreamReader<R> {
+    /// Starts reading archive package which is not larger than `max_archive_size`
+    pub fn new(mut reader: R, max_archive_size: u64) -> Result<Self, ArchivePackageError> {
+        let mut header = [0; ARCHIVE_PREFIX.len()];
+        read_exact_or_eof(
+            &mut reader,
+            &mut header,
+            ArchivePackageError::UnexpectedArchiveEof,
+        )?;
+        read_package_header(&header, &mut 0)?;
+        Ok(Self {
+            reader,
+            remaining: max_archive_size.saturating_sub(header.len() as u64),
+        })
+    }
+
+    /// Reads next archive package entry
+    pub fn read_next(
+        &mut self,
+    ) -> Result<Option<(PackageEntryId<ton_block::BlockIdExt>, Vec<u8>)>, ArchivePackageError> {
+        let mut header = [0; ARCHIVE_ENTRY_HEADER_LEN];
+
+        // Archive could only end between entries
+        let filled = read_full(&mut self.reader, &mut header)?;
+        if filled == 0 {
+            return Ok(None);
+        } else if filled < header.len() {
+            return Err(ArchivePackageError::UnexpectedArchiveEof);
+        }
+
+        if header[..2] != ARCHIVE_ENTRY_PREFIX {
+            return Err(ArchivePackageError::InvalidArchiveEntryHeader);
+        }
+        let filename_size = u16::from_le_bytes([header[2], header[3]]) as u64;
+        let data_size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as u64;
+
+        // NOTE: sizes are not trusted, so they are checked before allocating anything
+        let entry_size = header.len() as u64 + filename_size + data_size;
+        if entry_size > self.remaining {
+            return Err(ArchivePackageError::TooLargeEntry);
+        }
+        self.remaining -= entry_size;
+
+        let name = read_entry_part(&mut self.reader, filename_size)?;
+        let entry_id = std::str::from_utf8(&name)
+            .ok()
+            .and_then(|name| PackageEntryId::from_filename(name).ok())
+            .ok_or(ArchivePackageError::InvalidArchiveEntryName)?;
+
+        let data = read_entry_part(&mut self.reader, data_size)?;
+
+        Ok(Some((entry_id, data)))
+    }
+}
+
+/// Reads exactly `len` bytes growing the buffer only as the data arrives,
+/// so that a truncated stream doesn't cause a large allocation
+fn read_entry_part<R: Read>(reader: &mut R, len: u64) -> Result<Vec<u8>, ArchivePackageError> {
+    const MAX_INITIAL_CAPACITY: u64 = 1 << 20;
+
+    let mut data = Vec::with_capacity(std::cmp::min(len, MAX_INITIAL_CAPACITY) as usize);
+    reader
+        .take(len)
+        .read_to_end(&mut data)
+        .map_err(ArchivePackageError::Io)?;
+
+    if data.len() as u64 == len {
+        Ok(data)
+    } else {
+        Err(ArchivePackageError::UnexpectedEntryEof)
+    }
+}
+
+/// Fills the buffer until the end of the stream. Returns the number of bytes read
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize, ArchivePackageError> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(ArchivePackageError::Io(e)),
+        }
+    }
+    Ok(filled)
+}
+
+fn read_exact_or_eof<R: Read>(
+    reader: &mut R,
+    buf: &mut [u8],
+    eof_error: ArchivePackageError,
+) -> Result<(), ArchivePackageError> {
+    if read_full(reader, buf)? == buf.len() {
+        Ok(())
+    } else {
+        Err(eof_error)
+    }
+}
+
 fn read_package_header(buf: &[u8], offset: &mut usize) -> Result<(), ArchivePackageError> {
     let end = *offset;
 
@@ -222,8 +332,104 @@ pub enum ArchivePackageError {
     UnexpectedEntryEof,
     #[error("Too small initial batch")]
     TooSmallInitialBatch,
+    #[error("Archive entry exceeds the max archive size")]
+    TooLargeEntry,
+    #[error("Failed to read archive")]
+    Io(#[source] std::io::Error),
 }
 
 pub const ARCHIVE_PREFIX: [u8; 4] = u32::to_le_bytes(0xae8fdd01);
 const ARCHIVE_ENTRY_PREFIX: [u8; 2] = u16::to_le_bytes(0x1e8b);
 const ARCHIVE_ENTRY_HEADER_LEN: usize = ARCHIVE_ENTRY_PREFIX.len() + 2 + 4; // magic + filename len + data len
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::GetFileName;
+
+    #[test]
+    fn stream_reader_matches_view_reader() {
+        let block_id = |seq_no: u32| ton_block::BlockIdExt {
+            shard_id: ton_block::ShardIdent::masterchain(),
+            seq_no,
+            root_hash: ton_types::UInt256::from_slice(&[seq_no as u8; 32]),
+            file_hash: ton_types::UInt256::from_slice(&[!seq_no as u8; 32]),
+        };
+
+        let mut archive = ARCHIVE_PREFIX.to_vec();
+        for seq_no in 1..10 {
+            let id = PackageEntryId::Block(block_id(seq_no));
+            archive.extend(make_archive_segment(&id.filename(), &[seq_no as u8; 100]));
+            let id = PackageEntryId::Proof(block_id(seq_no));
+            archive.extend(make_archive_segment(&id.filename(), &[]));
+        }
+
+        let mut view_reader = ArchivePackageViewReader::new(&archive).unwrap();
+        // NOTE: slice reader returns small chunks to check partial reads
+        let mut stream_reader = ArchivePackageStreamReader::new(
+            std::io::BufReader::with_capacity(3, &archive[..]),
+            archive.len() as u64,
+        )
+        .unwrap();
+
+        while let Some(expected) = view_reader.read_next().unwrap() {
+            let (id, data) = stream_reader.read_next().unwrap().unwrap();
+            assert_eq!(id, PackageEntryId::from_filename(expected.name).unwrap());
+            assert_eq!(data, expected.data);
+        }
+        assert!(stream_reader.read_next().unwrap().is_none());
+
+        // Truncated archive
+        let truncated = &archive[..archive.len() - 1];
+        let mut stream_reader =
+            ArchivePackageStreamReader::new(truncated, archive.len() as u64).unwrap();
+        let result = loop {
+            match stream_reader.read_next() {
+                Ok(Some(_)) => continue,
+                result => break result,
+            }
+        };
+        assert!(matches!(
+            result,
+            Err(ArchivePackageError::UnexpectedEntryEof)
+        ));
+    }
+
+    #[test]
+    fn untrusted_entry_sizes() {
+        let id = PackageEntryId::Block(ton_block::BlockIdExt {
+            shard_id: ton_block::ShardIdent::masterchain(),
+            seq_no: 1,
+            root_hash: Default::default(),
+            file_hash: Default::default(),
+        });
+        let mut archive = ARCHIVE_PREFIX.to_vec();
+        archive.extend(make_archive_segment(&id.filename(), &[1; 100]));
+
+        // Entry which is larger than the max archive size
+        let mut stream_reader =
+            ArchivePackageStreamReader::new(&archive[..], archive.len() as u64 - 1).unwrap();
+        assert!(matches!(
+            stream_reader.read_next(),
+            Err(ArchivePackageError::TooLargeEntry)
+        ));
+
+        // Entry header with a huge size but without the data
+        let mut archive = ARCHIVE_PREFIX.to_vec();
+        archive.extend_from_slice(&ARCHIVE_ENTRY_PREFIX);
+        archive.extend_from_slice(&u16::MAX.to_le_bytes());
+        archive.extend_from_slice(&u32::MAX.to_le_bytes());
+
+        let mut stream_reader = ArchivePackageStreamReader::new(&archive[..], u64::MAX).unwrap();
+        assert!(matches!(
+            stream_reader.read_next(),
+            Err(ArchivePackageError::UnexpectedEntryEof)
+        ));
+
+        let mut view_reader = ArchivePackageViewReader::new(&archive).unwrap();
+        assert!(matches!(
+            view_reader.read_next(),
+            Err(ArchivePackageError::UnexpectedEntryEof)
+        ));
+    }
+}
@@ -64,6 +64,15 @@ impl BlockStuff {
         Ok(Self { id, block })
     }
 
+    /// Deserializes block without checking that data corresponds to the id.
+    ///
+    /// NOTE: must only be used for the data from the trusted storage
+    pub fn deserialize_unchecked(id: ton_block::BlockIdExt, mut data: &[u8]) -> Result<Self> {
+        let root = ton_types::deserialize_tree_of_cells(&mut data)?;
+        let block = ton_block::Block::construct_from(&mut root.into())?;
+        Ok(Self { id, block })
+    }
+
     #[inline(always)]
     pub fn block(&self) -> &ton_block::Block {
         &self.block
@@ -165,6 +174,9 @@ pub struct BriefBlockInfo {
     pub is_key_block: bool,
     pub gen_utime: u32,
     pub after_split: bool,
+    /// Seqno of the key block against which the block proof was checked.
+    /// `None` for proof links and unchecked proofs
+    pub proof_key_block_seqno: Option<u32>,
 }
 
 impl From<&ton_block::BlockInfo> for BriefBlockInfo {
@@ -173,6 +185,7 @@ impl From<&ton_block::BlockInfo> for BriefBlockInfo {
             is_key_block: info.key_block(),
             gen_utime: info.gen_utime().0,
             after_split: info.after_split(),
+            proof_key_block_seqno: None,
         }
     }
 }
@@ -186,3 +199,65 @@ impl BlockIdExtExtension for ton_block::BlockIdExt {
         self.shard().is_masterchain()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use ton_block::Serializable;
+
+    use super::*;
+
+    fn make_block() -> (ton_block::BlockIdExt, Vec<u8>) {
+        let root = ton_block::Block::default().serialize().unwrap();
+        let data = ton_types::serialize_toc(&root).unwrap();
+
+        let id = ton_block::BlockIdExt {
+            shard_id: ton_block::ShardIdent::masterchain(),
+            seq_no: 1,
+            root_hash: root.repr_hash(),
+            file_hash: UInt256::calc_file_hash(&data),
+        };
+        (id, data)
+    }
+
+    #[test]
+    fn checked_accepts_valid_data() {
+        let (id, data) = make_block();
+        let block = BlockStuff::deserialize_checked(id.clone(), &data).unwrap();
+        assert_eq!(block.id(), &id);
+    }
+
+    #[test]
+    fn checked_rejects_tampered_data() {
+        let (id, mut data) = make_block();
+        *data.last_mut().unwrap() ^= 0xff;
+        assert!(BlockStuff::deserialize_checked(id.clone(), &data).is_err());
+
+        // Even with the matching file hash
+        let id = ton_block::BlockIdExt {
+            file_hash: UInt256::calc_file_hash(&data),
+            ..id
+        };
+        assert!(BlockStuff::deserialize_checked(id, &data).is_err());
+    }
+
+    #[test]
+    fn checked_rejects_wrong_id() {
+        let (id, data) = make_block();
+
+        let wrong_file_hash = ton_block::BlockIdExt {
+            file_hash: UInt256::default(),
+            ..id.clone()
+        };
+        assert!(BlockStuff::deserialize_checked(wrong_file_hash, &data).is_err());
+
+        let wrong_root_hash = ton_block::BlockIdExt {
+            root_hash: UInt256::default(),
+            ..id
+        };
+        assert!(BlockStuff::deserialize_checked(wrong_root_hash.clone(), &data).is_err());
+
+        // Unchecked variant trusts the id
+        let block = BlockStuff::deserialize_unchecked(wrong_root_hash.clone(), &data).unwrap();
+        assert_eq!(block.id(), &wrong_root_hash);
+    }
+}
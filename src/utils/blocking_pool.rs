@@ -0,0 +1,93 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use tokio::sync::Semaphore;
+
+/// Runs CPU-heavy tasks (proof checks, BOC deserialization) outside the async runtime threads.
+///
+/// NOTE: the number of concurrent tasks is limited to not oversubscribe
+/// the shared blocking threads pool
+#[derive(Clone)]
+pub struct BlockingPool {
+    permits: Arc<Semaphore>,
+}
+
+impl BlockingPool {
+    pub fn new(max_tasks: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(std::cmp::max(max_tasks, 1))),
+        }
+    }
+
+    pub async fn run<F, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce() -> Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let _permit = self.permits.acquire().await?;
+        tokio::task::spawn_blocking(f).await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn heavy_tasks_do_not_block_runtime() {
+        const TICK: Duration = Duration::from_millis(10);
+        const TASK_DURATION: Duration = Duration::from_millis(50);
+
+        let pool = BlockingPool::new(2);
+        let active = Arc::new(AtomicUsize::new(0));
+        let max_active = Arc::new(AtomicUsize::new(0));
+
+        // Measure event loop latency while heavy tasks are running
+        let ticker = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(TICK);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            interval.tick().await;
+
+            let mut max_latency = Duration::ZERO;
+            for _ in 0..20 {
+                let started_at = Instant::now();
+                interval.tick().await;
+                max_latency = std::cmp::max(max_latency, started_at.elapsed());
+            }
+            max_latency
+        });
+
+        let tasks = (0..8).map(|_| {
+            let pool = pool.clone();
+            let active = active.clone();
+            let max_active = max_active.clone();
+            async move {
+                pool.run(move || {
+                    let current = active.fetch_add(1, Ordering::AcqRel) + 1;
+                    max_active.fetch_max(current, Ordering::AcqRel);
+
+                    // Busy loop to simulate CPU-heavy work
+                    let started_at = Instant::now();
+                    while started_at.elapsed() < TASK_DURATION {
+                        std::hint::spin_loop();
+                    }
+
+                    active.fetch_sub(1, Ordering::AcqRel);
+                    Ok(())
+                })
+                .await
+            }
+        });
+
+        for result in futures_util::future::join_all(tasks).await {
+            result.unwrap();
+        }
+
+        let max_latency = ticker.await.unwrap();
+        assert!(max_latency < TASK_DURATION, "max latency: {max_latency:?}");
+        assert_eq!(max_active.load(Ordering::Acquire), 2);
+    }
+}
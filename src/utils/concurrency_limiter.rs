@@ -0,0 +1,77 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use anyhow::Result;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Bounds the combined number of concurrent operations started from
+/// different places and tracks how many of them are in flight.
+#[derive(Clone)]
+pub struct ConcurrencyLimiter {
+    permits: Arc<Semaphore>,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(max_tasks: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(std::cmp::max(max_tasks, 1))),
+            in_flight: Default::default(),
+        }
+    }
+
+    pub async fn acquire(&self) -> Result<ConcurrencyPermit> {
+        let permit = self.permits.clone().acquire_owned().await?;
+        self.in_flight.fetch_add(1, Ordering::AcqRel);
+        Ok(ConcurrencyPermit {
+            _permit: permit,
+            in_flight: self.in_flight.clone(),
+        })
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Acquire)
+    }
+}
+
+pub struct ConcurrencyPermit {
+    _permit: OwnedSemaphorePermit,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl Drop for ConcurrencyPermit {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn bound_is_respected_under_load() {
+        let limiter = ConcurrencyLimiter::new(3);
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+
+        // Two producers share the same limiter
+        let tasks = (0..64).map(|i| {
+            let limiter = limiter.clone();
+            let max_in_flight = max_in_flight.clone();
+            tokio::spawn(async move {
+                let _permit = limiter.acquire().await.unwrap();
+                max_in_flight.fetch_max(limiter.in_flight(), Ordering::AcqRel);
+                tokio::time::sleep(Duration::from_millis(1 + i % 2)).await;
+            })
+        });
+
+        for result in futures_util::future::join_all(tasks).await {
+            result.unwrap();
+        }
+
+        assert_eq!(max_in_flight.load(Ordering::Acquire), 3);
+        assert_eq!(limiter.in_flight(), 0);
+    }
+}
@@ -0,0 +1,169 @@
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use super::FxDashSet;
+
+/// Set with entries which expire after some time.
+///
+/// Entries are stored in two generations, the older one is dropped on rotation.
+/// So each entry lives from `ttl` to `2 * ttl` since the last insertion.
+///
+/// NOTE: generations are also rotated when the current one reaches the capacity,
+/// so the set never contains more than `2 * capacity` entries
+pub struct ExpiringSet<K> {
+    generations: [FxDashSet<K>; 2],
+    current: AtomicUsize,
+    ttl: Duration,
+    capacity: usize,
+    last_rotation: Mutex<Instant>,
+    rotations: AtomicU64,
+}
+
+impl<K> ExpiringSet<K>
+where
+    K: Eq + Hash,
+{
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            generations: Default::default(),
+            current: AtomicUsize::new(0),
+            ttl,
+            capacity,
+            last_rotation: Mutex::new(Instant::now()),
+            rotations: Default::default(),
+        }
+    }
+
+    /// Inserts or refreshes the entry. Returns `true` if it was not present
+    pub fn insert(&self, key: K) -> bool {
+        self.rotate_if_needed();
+
+        let current = self.current.load(Ordering::Acquire);
+        let previous = &self.generations[current ^ 1];
+        let existed = previous.remove(&key).is_some();
+        self.generations[current].insert(key) && !existed
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.generations.iter().any(|set| set.contains(key))
+    }
+
+    pub fn remove(&self, key: &K) {
+        for set in &self.generations {
+            set.remove(key);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.generations.iter().map(FxDashSet::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.generations.iter().all(FxDashSet::is_empty)
+    }
+
+    /// Entries which were inserted (or refreshed) during the current generation.
+    ///
+    /// NOTE: used by external APIs which require a plain set
+    pub fn current_generation(&self) -> &FxDashSet<K> {
+        self.rotate_if_needed();
+        &self.generations[self.current.load(Ordering::Acquire)]
+    }
+
+    pub fn metrics(&self) -> ExpiringSetMetrics {
+        ExpiringSetMetrics {
+            len: self.len(),
+            capacity: self.capacity,
+            rotations: self.rotations.load(Ordering::Acquire),
+        }
+    }
+
+    fn rotate_if_needed(&self) {
+        let current = self.current.load(Ordering::Acquire);
+        let expired = || self.last_rotation.lock().elapsed() >= self.ttl;
+        if self.generations[current].len() < self.capacity && !expired() {
+            return;
+        }
+
+        let mut last_rotation = self.last_rotation.lock();
+
+        // Check again under the lock (generation could have been rotated by another thread)
+        let current = self.current.load(Ordering::Acquire);
+        if self.generations[current].len() < self.capacity && last_rotation.elapsed() < self.ttl {
+            return;
+        }
+
+        let next = current ^ 1;
+        self.generations[next].clear();
+        self.current.store(next, Ordering::Release);
+
+        *last_rotation = Instant::now();
+        self.rotations.fetch_add(1, Ordering::Release);
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize)]
+pub struct ExpiringSetMetrics {
+    pub len: usize,
+    pub capacity: usize,
+    pub rotations: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn entries_expire() {
+        let set = ExpiringSet::new(Duration::from_secs(60), 1000);
+        assert!(set.insert(1));
+        assert!(!set.insert(1));
+
+        // Refreshed entries survive rotations
+        for _ in 0..10 {
+            tokio::time::advance(Duration::from_secs(61)).await;
+            assert!(set.contains(&1));
+            assert!(!set.insert(1));
+        }
+
+        // Stale entries are removed after two rotations
+        set.insert(2);
+        tokio::time::advance(Duration::from_secs(61)).await;
+        set.insert(3);
+        assert!(set.contains(&2));
+        tokio::time::advance(Duration::from_secs(61)).await;
+        set.insert(3);
+        assert!(!set.contains(&2));
+        assert!(set.contains(&3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stays_bounded_under_churn() {
+        const CAPACITY: usize = 512;
+
+        let set = ExpiringSet::new(Duration::from_secs(600), CAPACITY);
+
+        // A week of new entries every second
+        let mut max_len = 0;
+        for i in 0..7 * 24 * 3600u64 {
+            set.insert(i);
+            if i % 7 == 0 {
+                // Some entries are refreshed
+                set.insert(i / 2);
+            }
+            max_len = std::cmp::max(max_len, set.len());
+
+            if i % 60 == 0 {
+                tokio::time::advance(Duration::from_secs(60)).await;
+            }
+        }
+
+        assert!(max_len <= 2 * CAPACITY);
+        assert!(set.metrics().rotations > 0);
+    }
+}
@@ -0,0 +1,82 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Lock-free histogram of durations with fixed buckets
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; HISTOGRAM_BUCKETS_US.len() + 1],
+    count: AtomicU64,
+    sum_us: AtomicU64,
+}
+
+impl Histogram {
+    pub fn record(&self, duration: Duration) {
+        let us = duration.as_micros() as u64;
+        let bucket = HISTOGRAM_BUCKETS_US.partition_point(|&bound| bound < us);
+
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_us.fetch_add(us, Ordering::Relaxed);
+    }
+
+    /// Returns current values and optionally resets them.
+    ///
+    /// NOTE: values are not read atomically, so the snapshot could be
+    /// slightly inconsistent under load
+    pub fn snapshot(&self, reset: bool) -> HistogramSnapshot {
+        let read = |value: &AtomicU64| {
+            if reset {
+                value.swap(0, Ordering::Relaxed)
+            } else {
+                value.load(Ordering::Relaxed)
+            }
+        };
+
+        HistogramSnapshot {
+            buckets: self.buckets.iter().map(read).collect(),
+            count: read(&self.count),
+            sum_us: read(&self.sum_us),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct HistogramSnapshot {
+    /// Number of values in each bucket of [`HISTOGRAM_BUCKETS_US`].
+    /// The last item contains values above all bounds
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    pub sum_us: u64,
+}
+
+/// Inclusive upper bounds of histogram buckets in microseconds
+pub const HISTOGRAM_BUCKETS_US: [u64; 14] = [
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000,
+    1_000_000, 2_500_000,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_into_buckets() {
+        let histogram = Histogram::default();
+        histogram.record(Duration::from_micros(100));
+        histogram.record(Duration::from_micros(101));
+        histogram.record(Duration::from_secs(10));
+
+        let snapshot = histogram.snapshot(true);
+        assert_eq!(snapshot.count, 3);
+        assert_eq!(snapshot.sum_us, 10_000_201);
+        assert_eq!(snapshot.buckets[0], 1);
+        assert_eq!(snapshot.buckets[1], 1);
+        assert_eq!(snapshot.buckets[HISTOGRAM_BUCKETS_US.len()], 1);
+
+        let snapshot = histogram.snapshot(false);
+        assert_eq!(snapshot.count, 0);
+        assert!(snapshot.buckets.iter().all(|&count| count == 0));
+    }
+}
@@ -0,0 +1,227 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+/// Global limit for the memory occupied by in-flight sync data
+pub struct MemoryBudget {
+    capacity: usize,
+    used: Mutex<usize>,
+    released: Notify,
+    usage: [AtomicUsize; MemoryCategory::COUNT],
+}
+
+impl MemoryBudget {
+    pub fn new(capacity: usize) -> Arc<Self> {
+        Arc::new(Self {
+            capacity,
+            used: Mutex::new(0),
+            released: Notify::new(),
+            usage: Default::default(),
+        })
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Waits until there is enough free memory and reserves it.
+    ///
+    /// NOTE: request which is larger than the whole budget is granted
+    /// when nothing else is reserved
+    pub async fn acquire(
+        self: &Arc<Self>,
+        category: MemoryCategory,
+        bytes: usize,
+    ) -> MemoryBudgetGuard {
+        loop {
+            // Create `Notified` future before the check to not miss the release
+            let released = self.released.notified();
+            if self.try_reserve(bytes) {
+                break;
+            }
+            released.await;
+        }
+
+        self.usage[category as usize].fetch_add(bytes, Ordering::Release);
+        MemoryBudgetGuard {
+            budget: self.clone(),
+            category,
+            bytes,
+        }
+    }
+
+    /// Reserves memory without waiting. Could exceed the budget.
+    ///
+    /// Used for the data which is required to make progress
+    /// (otherwise pending reservations may never be released)
+    pub fn force_acquire(
+        self: &Arc<Self>,
+        category: MemoryCategory,
+        bytes: usize,
+    ) -> MemoryBudgetGuard {
+        *self.used.lock() += bytes;
+        self.usage[category as usize].fetch_add(bytes, Ordering::Release);
+        MemoryBudgetGuard {
+            budget: self.clone(),
+            category,
+            bytes,
+        }
+    }
+
+    pub fn metrics(&self) -> MemoryBudgetMetrics {
+        let usage =
+            |category: MemoryCategory| self.usage[category as usize].load(Ordering::Acquire);
+        MemoryBudgetMetrics {
+            capacity: self.capacity,
+            total: *self.used.lock(),
+            archives: usage(MemoryCategory::Archives),
+            block_maps: usage(MemoryCategory::BlockMaps),
+            state_packets: usage(MemoryCategory::StatePackets),
+        }
+    }
+
+    fn try_reserve(&self, bytes: usize) -> bool {
+        let mut used = self.used.lock();
+        if *used == 0 || *used + bytes <= self.capacity {
+            *used += bytes;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn release(&self, category: MemoryCategory, bytes: usize) {
+        if bytes == 0 {
+            return;
+        }
+
+        {
+            let mut used = self.used.lock();
+            *used = used.saturating_sub(bytes);
+        }
+        self.usage[category as usize].fetch_sub(bytes, Ordering::Release);
+        self.released.notify_waiters();
+    }
+}
+
+/// Memory reservation which is returned to the budget on drop
+#[must_use]
+pub struct MemoryBudgetGuard {
+    budget: Arc<MemoryBudget>,
+    category: MemoryCategory,
+    bytes: usize,
+}
+
+impl MemoryBudgetGuard {
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Updates reservation to the exact size of the data (without waiting)
+    pub fn resize(&mut self, bytes: usize) {
+        match bytes.cmp(&self.bytes) {
+            std::cmp::Ordering::Greater => {
+                let additional = bytes - self.bytes;
+                *self.budget.used.lock() += additional;
+                self.budget.usage[self.category as usize].fetch_add(additional, Ordering::Release);
+            }
+            std::cmp::Ordering::Less => self.budget.release(self.category, self.bytes - bytes),
+            std::cmp::Ordering::Equal => return,
+        }
+        self.bytes = bytes;
+    }
+
+    /// Returns part of the reserved memory to the budget
+    pub fn release(&mut self, bytes: usize) {
+        let bytes = std::cmp::min(bytes, self.bytes);
+        self.budget.release(self.category, bytes);
+        self.bytes -= bytes;
+    }
+}
+
+impl Drop for MemoryBudgetGuard {
+    fn drop(&mut self) {
+        self.budget.release(self.category, self.bytes);
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MemoryCategory {
+    /// Raw archives received from peers
+    Archives,
+    /// Decoded blocks and proofs of the parsed archives
+    BlockMaps,
+    /// Persistent state packets waiting to be processed
+    StatePackets,
+}
+
+impl MemoryCategory {
+    const COUNT: usize = 3;
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, Copy)]
+pub struct MemoryBudgetMetrics {
+    pub capacity: usize,
+    pub total: usize,
+    pub archives: usize,
+    pub block_maps: usize,
+    pub state_packets: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn tiny_budget_serializes_downloads() {
+        const ARCHIVE_SIZE: usize = 100;
+
+        let budget = MemoryBudget::new(ARCHIVE_SIZE);
+        let active = Arc::new(AtomicUsize::new(0));
+        let max_active = Arc::new(AtomicUsize::new(0));
+
+        let downloads = (0..4).map(|_| {
+            let budget = budget.clone();
+            let active = active.clone();
+            let max_active = max_active.clone();
+            tokio::spawn(async move {
+                let _guard = budget.acquire(MemoryCategory::Archives, ARCHIVE_SIZE).await;
+
+                let current = active.fetch_add(1, Ordering::AcqRel) + 1;
+                max_active.fetch_max(current, Ordering::AcqRel);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                active.fetch_sub(1, Ordering::AcqRel);
+            })
+        });
+
+        for result in futures_util::future::join_all(downloads).await {
+            result.unwrap();
+        }
+
+        assert_eq!(max_active.load(Ordering::Acquire), 1);
+
+        let metrics = budget.metrics();
+        assert_eq!(metrics.total, 0);
+        assert_eq!(metrics.archives, 0);
+    }
+
+    #[tokio::test]
+    async fn guard_resize() {
+        let budget = MemoryBudget::new(100);
+
+        let mut guard = budget.acquire(MemoryCategory::BlockMaps, 10).await;
+        guard.resize(150);
+        assert_eq!(budget.metrics().block_maps, 150);
+
+        guard.release(100);
+        assert_eq!(budget.metrics().total, 50);
+
+        drop(guard);
+        assert_eq!(budget.metrics().total, 0);
+    }
+}
@@ -5,12 +5,18 @@ use rustc_hash::FxHasher;
 pub use archive_package::*;
 pub use block::*;
 pub use block_proof::*;
+pub use blocking_pool::*;
+pub use concurrency_limiter::*;
+pub use expiring_set::*;
+pub use histogram::*;
 pub use mapped_file::*;
+pub use memory_budget::*;
 pub use operations_pool::*;
 pub use package_entry_id::*;
 pub use progress_bar::*;
 pub use shard_state::*;
 pub use shard_state_cache::*;
+pub use small_lru_cache::*;
 pub use stored_value::*;
 pub use top_blocks::*;
 pub use with_archive_data::*;
@@ -18,12 +24,18 @@ pub use with_archive_data::*;
 mod archive_package;
 mod block;
 mod block_proof;
+mod blocking_pool;
+mod concurrency_limiter;
+mod expiring_set;
+mod histogram;
 mod mapped_file;
+mod memory_budget;
 mod operations_pool;
 mod package_entry_id;
 mod progress_bar;
 mod shard_state;
 mod shard_state_cache;
+mod small_lru_cache;
 mod stored_value;
 mod top_blocks;
 mod with_archive_data;
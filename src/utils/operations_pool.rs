@@ -90,8 +90,17 @@ where
             .or_insert_with(|| Operation::new(false))
             .clone();
 
-        self.wait_operation(id, timeout_ms, &operation, check_complete)
-            .await
+        let result = self
+            .wait_operation(id, timeout_ms, &operation, check_complete)
+            .await;
+
+        // Remove the placeholder if nobody started the operation and nobody else waits for it
+        drop(operation);
+        self.operations.remove_if(id, |_, operation| {
+            !operation.stared.load(Ordering::Acquire) && Arc::strong_count(operation) == 1
+        });
+
+        result
     }
 
     async fn wait_operation<F>(
@@ -157,3 +166,40 @@ impl<R> Operation<R> {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn completed_waits_are_removed() {
+        let pool = OperationsPool::<u32, u32>::new("test");
+
+        // Check succeeds without the operation being started
+        for id in 0..1000 {
+            let result = pool.wait(&id, None, || Ok(true)).await.unwrap();
+            assert!(result.is_none());
+        }
+        assert!(pool.is_empty());
+
+        // Started operation is not removed by waiters
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let operation = pool.do_or_wait(&0, None, async move {
+            rx.await.ok();
+            Ok(123)
+        });
+        let wait = async {
+            tokio::task::yield_now().await;
+            assert!(pool
+                .wait(&0, Some(10), || Ok(true))
+                .await
+                .unwrap()
+                .is_none());
+            assert_eq!(pool.len(), 1);
+            tx.send(()).unwrap();
+        };
+        let (result, _) = futures_util::future::join(operation, wait).await;
+        assert_eq!(result.unwrap(), Some(123));
+        assert!(pool.is_empty());
+    }
+}
@@ -14,8 +14,19 @@ use smallvec::SmallVec;
 
 use super::{BlockIdShort, StoredValue};
 
-/// Package entry id
-#[derive(Debug, Hash, Eq, PartialEq)]
+/// Package entry id.
+///
+/// Entries in the archive packages are named as `{prefix}{block_id}`, where prefix is
+/// `block_`, `proof_` or `prooflink_` for the block data, proof and proof link respectively,
+/// and block id is `({workchain},{shard:016x},{seqno}):{ROOT_HASH}:{FILE_HASH}`
+/// (see [`parse_block_id`]). E.g.:
+///
+/// ```text
+/// block_(-1,8000000000000000,100):2E3F...C0A1:6B1D...97F2
+/// proof_(-1,8000000000000000,100):2E3F...C0A1:6B1D...97F2
+/// prooflink_(0,a000000000000000,200):0F5C...D4E8:A4B2...1C39
+/// ```
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
 pub enum PackageEntryId<I> {
     /// Block data entry
     Block(I),
@@ -26,7 +37,7 @@ pub enum PackageEntryId<I> {
 }
 
 impl PackageEntryId<ton_block::BlockIdExt> {
-    /// Parses package entry id from string
+    /// Parses package entry id from the archive entry filename
     pub fn from_filename(filename: &str) -> Result<Self> {
         let block_id_pos = match filename.find('(') {
             Some(pos) => pos,
@@ -48,6 +59,15 @@ impl<I> PackageEntryId<I>
 where
     I: Borrow<ton_block::BlockIdExt>,
 {
+    /// Returns the archive entry filename, the inverse of [`PackageEntryId::from_filename`]
+    pub fn to_filename(&self) -> String {
+        match self {
+            Self::Block(block_id) | Self::Proof(block_id) | Self::ProofLink(block_id) => {
+                format!("{}{}", self.filename_prefix(), block_id.borrow().filename())
+            }
+        }
+    }
+
     /// Returns package entry prefix
     fn filename_prefix(&self) -> &'static str {
         match self {
@@ -100,15 +120,15 @@ where
     I: Borrow<ton_block::BlockIdExt> + Hash,
 {
     fn filename(&self) -> String {
-        match self {
-            Self::Block(block_id) | Self::Proof(block_id) | Self::ProofLink(block_id) => {
-                format!("{}{}", self.filename_prefix(), block_id.borrow().filename())
-            }
-        }
+        self.to_filename()
     }
 }
 
-fn parse_block_id(filename: &str) -> Result<ton_block::BlockIdExt> {
+/// Parses block id from the archive entry filename format:
+/// `({workchain},{shard:016x},{seqno}):{ROOT_HASH}:{FILE_HASH}`.
+///
+/// Shard is a tagged prefix in hex, hashes are in hex of any case
+pub fn parse_block_id(filename: &str) -> Result<ton_block::BlockIdExt> {
     let mut parts = filename.split(':');
 
     let shard_id = match parts.next() {
@@ -204,4 +224,58 @@ mod tests {
         check_package_id(PackageEntryId::Proof(block_id.clone()));
         check_package_id(PackageEntryId::ProofLink(block_id));
     }
+
+    #[test]
+    fn filename_format() {
+        let block_id = ton_block::BlockIdExt {
+            shard_id: ton_block::ShardIdent::with_tagged_prefix(0, 0xa000000000000000).unwrap(),
+            seq_no: 200,
+            root_hash: ton_types::UInt256::from_slice(&[0xab; 32]),
+            file_hash: ton_types::UInt256::from_slice(&[0x01; 32]),
+        };
+        let block_id_str = format!(
+            "(0,a000000000000000,200):{}:{}",
+            "AB".repeat(32),
+            "01".repeat(32)
+        );
+
+        for (id, prefix) in [
+            (PackageEntryId::Block(&block_id), "block_"),
+            (PackageEntryId::Proof(&block_id), "proof_"),
+            (PackageEntryId::ProofLink(&block_id), "prooflink_"),
+        ] {
+            let filename = id.to_filename();
+            assert_eq!(filename, format!("{prefix}{block_id_str}"));
+            assert_eq!(filename, id.filename());
+
+            let parsed = PackageEntryId::from_filename(&filename).unwrap();
+            assert_eq!(parsed.to_filename(), filename);
+        }
+
+        // Hashes are case insensitive
+        assert_eq!(
+            parse_block_id(&block_id_str.to_lowercase()).unwrap(),
+            block_id
+        );
+    }
+
+    #[test]
+    fn invalid_filenames() {
+        let hashes = format!("{}:{}", "AB".repeat(32), "01".repeat(32));
+        for filename in [
+            String::new(),
+            format!("(0,a000000000000000,200):{hashes}"),
+            format!("blocks_(0,a000000000000000,200):{hashes}"),
+            format!("block_0,a000000000000000,200):{hashes}"),
+            format!("block_(0,a000000000000000):{hashes}"),
+            format!("block_(0,a000000000000000,200:{hashes}"),
+            format!("block_(0,a000000000000000,200):{}", "AB".repeat(32)),
+            format!("block_(x,a000000000000000,200):{hashes}"),
+        ] {
+            assert!(
+                PackageEntryId::from_filename(&filename).is_err(),
+                "{filename}"
+            );
+        }
+    }
 }
@@ -1,9 +1,12 @@
+use std::time::{Duration, Instant};
+
 pub struct ProgressBar {
     name: &'static str,
     percentage_step: u64,
     current: u64,
     total: Option<u64>,
     exact_unit: Option<&'static str>,
+    started_at: Option<Instant>,
 }
 
 impl ProgressBar {
@@ -40,15 +43,25 @@ impl ProgressBar {
         };
 
         let percent = self.current * 100 / total;
+        let eta = match self.eta() {
+            Some(eta) => format!(", ETA {}s", eta.as_secs()),
+            None => String::new(),
+        };
         match self.exact_unit {
             Some(exact_unit) => self.message(format_args!(
-                "{percent}% ({} / {total} {exact_unit})",
+                "{percent}% ({} / {total} {exact_unit}){eta}",
                 self.current
             )),
-            None => self.message(format_args!("{percent}%")),
+            None => self.message(format_args!("{percent}%{eta}")),
         }
     }
 
+    /// Estimates the remaining time based on the average speed since the start
+    fn eta(&self) -> Option<Duration> {
+        let started_at = self.started_at?;
+        compute_eta(started_at.elapsed(), self.current, self.total?)
+    }
+
     #[inline(always)]
     fn message(&self, text: impl std::fmt::Display) {
         tracing::info!("{}... {text}", self.name);
@@ -67,6 +80,7 @@ pub struct ProgressBarBuilder {
     percentage_step: u64,
     total: Option<u64>,
     exact_unit: Option<&'static str>,
+    eta: bool,
 }
 
 impl ProgressBarBuilder {
@@ -76,6 +90,7 @@ impl ProgressBarBuilder {
             percentage_step: PERCENTAGE_STEP,
             total: None,
             exact_unit: None,
+            eta: false,
         }
     }
 
@@ -94,6 +109,12 @@ impl ProgressBarBuilder {
         self
     }
 
+    /// Appends the estimated remaining time to the progress messages
+    pub fn with_eta(mut self) -> Self {
+        self.eta = true;
+        self
+    }
+
     pub fn build(self) -> ProgressBar {
         let pg = ProgressBar {
             name: self.name,
@@ -101,6 +122,7 @@ impl ProgressBarBuilder {
             current: 0,
             total: self.total,
             exact_unit: self.exact_unit,
+            started_at: self.eta.then(Instant::now),
         };
 
         if self.total.is_some() {
@@ -114,3 +136,28 @@ impl ProgressBarBuilder {
 }
 
 const PERCENTAGE_STEP: u64 = 5;
+
+fn compute_eta(elapsed: Duration, current: u64, total: u64) -> Option<Duration> {
+    if current == 0 || current > total {
+        return None;
+    }
+    let remaining = (total - current) as f64 / current as f64;
+    Some(elapsed.mul_f64(remaining))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eta_estimation() {
+        let elapsed = Duration::from_secs(60);
+        assert_eq!(compute_eta(elapsed, 0, 100), None);
+        assert_eq!(
+            compute_eta(elapsed, 25, 100),
+            Some(Duration::from_secs(180))
+        );
+        assert_eq!(compute_eta(elapsed, 100, 100), Some(Duration::ZERO));
+        assert_eq!(compute_eta(elapsed, 101, 100), None);
+    }
+}
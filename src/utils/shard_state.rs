@@ -3,7 +3,7 @@
 /// Changes:
 /// - replaced old `failure` crate with `anyhow`
 ///
-use std::collections::hash_map;
+use std::collections::{hash_map, HashSet};
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
 
@@ -11,7 +11,7 @@ use anyhow::{anyhow, Result};
 use once_cell::sync::Lazy;
 use rustc_hash::FxHashMap;
 use ton_block::{Deserializable, Serializable};
-use ton_types::{Cell, UInt256};
+use ton_types::{BuilderData, Cell, SliceData, UInt256, UsageTree};
 
 /// Full persistent state block id (relative to the masterchain)
 pub struct FullStateId {
@@ -119,8 +119,236 @@ impl ShardStateStuff {
     pub fn config_params(&self) -> Result<&ton_block::ConfigParams> {
         Ok(&self.shard_state_extra()?.config)
     }
+
+    /// Returns addresses of the accounts which differ between two states.
+    ///
+    /// NOTE: unchanged subtrees of the accounts dictionary are shared
+    /// between states, so they are skipped by comparing cell hashes
+    pub fn changed_accounts(&self, other: &ShardStateStuff) -> Result<Vec<UInt256>> {
+        let mut result = Vec::new();
+        diff_dict(
+            self.accounts_dict_root()?,
+            other.accounts_dict_root()?,
+            BuilderData::new(),
+            ACCOUNT_KEY_BITS,
+            &mut result,
+        )?;
+        result.sort_unstable();
+        result.dedup();
+        Ok(result)
+    }
+
+    /// Returns the account state and the Merkle proof of it against the state root.
+    ///
+    /// NOTE: the whole account subtree is included into the proof.
+    /// For the non-existing account an empty one is returned along with
+    /// the proof of its absence
+    pub fn account_with_proof(
+        &self,
+        account: &UInt256,
+    ) -> Result<(ton_block::ShardAccount, ton_block::MerkleProof)> {
+        let usage_tree = UsageTree::with_root(self.root.clone());
+        let shard_state =
+            ton_block::ShardStateUnsplit::construct_from_cell(usage_tree.root_cell())?;
+        let shard_account = shard_state
+            .read_accounts()?
+            .get(account)?
+            .unwrap_or_default();
+
+        let mut visited = usage_tree.build_visited_set();
+        collect_subtree_hashes(shard_account.account_cell(), &mut visited);
+
+        let proof = ton_block::MerkleProof::create(&self.root, |hash| visited.contains(hash))?;
+        Ok((shard_account, proof))
+    }
+
+    /// Returns cell statistics of the whole state tree.
+    ///
+    /// Values are read from the tree counters of the root cell (which are
+    /// stored along with each cell), so the tree is not traversed.
+    /// NOTE: cells are counted as in a tree, i.e. shared subtrees are counted
+    /// multiple times
+    pub fn stats(&self) -> Result<StateStats> {
+        let cells = self.root.tree_cell_count();
+        if cells == 0 {
+            return Err(anyhow!(
+                "Tree counters are not stored for state {}",
+                self.block_id
+            ));
+        }
+
+        Ok(StateStats {
+            cells,
+            bits: self.root.tree_bits_count(),
+            max_depth: self.root.repr_depth(),
+        })
+    }
+
+    fn accounts_dict_root(&self) -> Result<Option<Cell>> {
+        // `HashmapAugE` is serialized as `Maybe ^root` followed by the root extra
+        let accounts = self.shard_state.read_accounts()?.serialize()?;
+        let mut slice = SliceData::from(accounts);
+        Ok(if slice.get_next_bit()? {
+            Some(slice.checked_drain_reference()?)
+        } else {
+            None
+        })
+    }
+}
+
+/// Cell statistics of the state tree
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct StateStats {
+    pub cells: u64,
+    pub bits: u64,
+    pub max_depth: u16,
+}
+
+fn collect_subtree_hashes(root: Cell, result: &mut HashSet<UInt256>) {
+    let mut stack = vec![root];
+    while let Some(cell) = stack.pop() {
+        if result.insert(cell.repr_hash()) {
+            stack.extend((0..cell.references_count()).filter_map(|i| cell.reference(i).ok()));
+        }
+    }
+}
+
+/// Collects keys of the dictionary entries which differ between two subtrees
+fn diff_dict(
+    left: Option<Cell>,
+    right: Option<Cell>,
+    key: BuilderData,
+    bit_len: usize,
+    result: &mut Vec<UInt256>,
+) -> Result<()> {
+    let (left, right) = match (left, right) {
+        (Some(left), Some(right)) if left.repr_hash() == right.repr_hash() => return Ok(()),
+        (Some(left), Some(right)) => (left, right),
+        (Some(cell), None) | (None, Some(cell)) => {
+            let mut values = FxHashMap::default();
+            collect_dict_values(cell, key, bit_len, &mut values)?;
+            result.extend(values.into_keys());
+            return Ok(());
+        }
+        (None, None) => return Ok(()),
+    };
+
+    let left_node = DictNode::parse(left.clone(), bit_len)?;
+    let right_node = DictNode::parse(right.clone(), bit_len)?;
+
+    if left_node.label_bits() == right_node.label_bits() {
+        let mut key = key.clone();
+        key.append_bytestring(&left_node.label)?;
+
+        match (left_node.children, right_node.children) {
+            // Same key with a different value
+            (None, None) => {
+                result.push(make_account_key(&key)?);
+                return Ok(());
+            }
+            // Descend into both branches
+            (Some(left_children), Some(right_children)) => {
+                let bit_len = bit_len - left_node.label.remaining_bits() - 1;
+                for (bit, (left, right)) in [
+                    (false, (left_children.0, right_children.0)),
+                    (true, (left_children.1, right_children.1)),
+                ] {
+                    let mut key = key.clone();
+                    key.append_bit_bool(bit)?;
+                    diff_dict(Some(left), Some(right), key, bit_len, result)?;
+                }
+                return Ok(());
+            }
+            _ => {}
+        }
+    }
+
+    // Subtrees have different structure so compare all their entries
+    let mut left_values = FxHashMap::default();
+    collect_dict_values(left, key.clone(), bit_len, &mut left_values)?;
+    let mut right_values = FxHashMap::default();
+    collect_dict_values(right, key, bit_len, &mut right_values)?;
+
+    for (key, value_hash) in &left_values {
+        if right_values.remove(key).as_ref() != Some(value_hash) {
+            result.push(*key);
+        }
+    }
+    result.extend(right_values.into_keys());
+
+    Ok(())
 }
 
+/// Collects hashes of all values of the dictionary subtree
+fn collect_dict_values(
+    cell: Cell,
+    key: BuilderData,
+    bit_len: usize,
+    result: &mut FxHashMap<UInt256, UInt256>,
+) -> Result<()> {
+    let node = DictNode::parse(cell, bit_len)?;
+
+    let mut key = key;
+    key.append_bytestring(&node.label)?;
+
+    match node.children {
+        Some((left, right)) => {
+            let bit_len = bit_len - node.label.remaining_bits() - 1;
+            for (bit, child) in [(false, left), (true, right)] {
+                let mut key = key.clone();
+                key.append_bit_bool(bit)?;
+                collect_dict_values(child, key, bit_len, result)?;
+            }
+        }
+        None => {
+            // NOTE: leaf cell hash depends on its label, so only the value is compared
+            let value_hash = node.value.into_cell().repr_hash();
+            result.insert(make_account_key(&key)?, value_hash);
+        }
+    }
+
+    Ok(())
+}
+
+struct DictNode {
+    label: SliceData,
+    children: Option<(Cell, Cell)>,
+    /// Leaf value with the augmentation
+    value: SliceData,
+}
+
+impl DictNode {
+    fn parse(cell: Cell, bit_len: usize) -> Result<Self> {
+        let mut slice = SliceData::from(cell);
+        let label = slice.get_label(bit_len)?;
+
+        let children = if label.remaining_bits() < bit_len {
+            Some((slice.reference(0)?, slice.reference(1)?))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            label,
+            children,
+            value: slice,
+        })
+    }
+
+    fn label_bits(&self) -> (usize, Vec<u8>) {
+        (self.label.remaining_bits(), self.label.get_bytestring(0))
+    }
+}
+
+fn make_account_key(key: &BuilderData) -> Result<UInt256> {
+    if key.length_in_bits() != ACCOUNT_KEY_BITS {
+        return Err(anyhow!("Invalid accounts dictionary key"));
+    }
+    Ok(UInt256::from_slice(&key.data()[..32]))
+}
+
+const ACCOUNT_KEY_BITS: usize = 256;
+
 pub struct RefMcStateHandle {
     min_ref_mc_state: Arc<MinRefMcState>,
     mc_seq_no: u32,
@@ -148,6 +376,22 @@ impl MinRefMcState {
         self.counters.read().0
     }
 
+    /// Creates state with the specified min ref mc seqno
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn with_seq_no(seq_no: u32) -> Arc<Self> {
+        let state = Self::new();
+        state.set_seq_no(Some(seq_no));
+        state
+    }
+
+    /// Overrides current min ref mc seqno.
+    ///
+    /// NOTE: the value is recomputed when the state with the current min seqno is dropped
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn set_seq_no(&self, seq_no: Option<u32>) {
+        self.counters.write().0 = seq_no;
+    }
+
     fn insert(self: &Arc<Self>, mc_seq_no: u32) -> Arc<RefMcStateHandle> {
         // Fast path, just increase existing counter
         let counters = self.counters.read();
@@ -219,8 +463,120 @@ static ZEROSTATE_REFS: Lazy<Arc<MinRefMcState>> = Lazy::new(MinRefMcState::new);
 
 #[cfg(test)]
 mod tests {
+    use ton_types::{HashmapE, HashmapType};
+
     use super::*;
 
+    fn make_dict(entries: impl IntoIterator<Item = (u8, u64)>) -> HashmapE {
+        let mut dict = HashmapE::with_bit_len(ACCOUNT_KEY_BITS);
+        for (key, value) in entries {
+            let key = SliceData::from_raw(vec![key; 32], ACCOUNT_KEY_BITS);
+            let value = SliceData::from_raw(value.to_be_bytes().to_vec(), 64);
+            dict.set(key, &value).unwrap();
+        }
+        dict
+    }
+
+    fn diff(left: &HashmapE, right: &HashmapE) -> Vec<UInt256> {
+        let mut result = Vec::new();
+        diff_dict(
+            left.data().cloned(),
+            right.data().cloned(),
+            BuilderData::new(),
+            ACCOUNT_KEY_BITS,
+            &mut result,
+        )
+        .unwrap();
+        result.sort_unstable();
+        result
+    }
+
+    #[test]
+    fn changed_accounts_diff() {
+        let key = |byte: u8| UInt256::from_slice(&[byte; 32]);
+
+        let entries = (0..64).map(|i| (i * 4, i as u64)).collect::<Vec<_>>();
+        let base = make_dict(entries.clone());
+
+        // Same dictionary
+        assert!(diff(&base, &base).is_empty());
+
+        // Changed, added and removed accounts
+        let mut changed = entries;
+        changed[3].1 = 1000;
+        changed.retain(|(key, _)| *key != 40);
+        changed.push((41, 1));
+        let changed = make_dict(changed);
+        assert_eq!(diff(&base, &changed), [key(12), key(40), key(41)]);
+        assert_eq!(diff(&changed, &base), [key(12), key(40), key(41)]);
+
+        // Empty dictionary
+        let empty = make_dict([]);
+        assert_eq!(diff(&empty, &base).len(), 64);
+        assert!(diff(&empty, &empty).is_empty());
+    }
+
+    #[test]
+    fn account_proof_of_absence() {
+        let shard_id = ton_block::ShardIdent::with_tagged_prefix(0, ton_block::SHARD_FULL).unwrap();
+        let mut shard_state = ton_block::ShardStateUnsplit::with_ident(shard_id);
+        shard_state.set_seq_no(1);
+        let root = shard_state.serialize().unwrap();
+
+        let block_id = ton_block::BlockIdExt {
+            shard_id,
+            seq_no: 1,
+            root_hash: root.repr_hash(),
+            file_hash: Default::default(),
+        };
+        let state = ShardStateStuff::new(block_id, root.clone(), &MinRefMcState::new()).unwrap();
+
+        let account = UInt256::from_slice(&[1; 32]);
+        let (shard_account, proof) = state.account_with_proof(&account).unwrap();
+        assert!(shard_account.read_account().unwrap().is_none());
+
+        // Proof is built against the state root and contains the accounts dictionary
+        assert_eq!(proof.hash, root.repr_hash());
+        let virt_state =
+            ton_block::ShardStateUnsplit::construct_from_cell(proof.proof.virtualize(1)).unwrap();
+        assert!(virt_state
+            .read_accounts()
+            .unwrap()
+            .get(&account)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn state_stats_from_tree_counters() {
+        let shard_id = ton_block::ShardIdent::with_tagged_prefix(0, ton_block::SHARD_FULL).unwrap();
+        let mut shard_state = ton_block::ShardStateUnsplit::with_ident(shard_id);
+        shard_state.set_seq_no(1);
+        let root = shard_state.serialize().unwrap();
+
+        let block_id = ton_block::BlockIdExt {
+            shard_id,
+            seq_no: 1,
+            root_hash: root.repr_hash(),
+            file_hash: Default::default(),
+        };
+        let state = ShardStateStuff::new(block_id, root.clone(), &MinRefMcState::new()).unwrap();
+
+        // Count the tree explicitly
+        let (mut cells, mut bits) = (0, 0);
+        let mut stack = vec![root.clone()];
+        while let Some(cell) = stack.pop() {
+            cells += 1;
+            bits += cell.bit_length() as u64;
+            stack.extend((0..cell.references_count()).map(|i| cell.reference(i).unwrap()));
+        }
+
+        let stats = state.stats().unwrap();
+        assert_eq!(stats.cells, cells);
+        assert_eq!(stats.bits, bits);
+        assert_eq!(stats.max_depth, root.repr_depth());
+    }
+
     #[test]
     fn min_ref_mc_state() {
         let state = Arc::new(MinRefMcState::default());
@@ -245,4 +601,23 @@ mod tests {
         }
         assert_eq!(state.seq_no(), None);
     }
+
+    #[test]
+    fn min_ref_mc_state_override() {
+        let state = MinRefMcState::with_seq_no(20);
+        assert_eq!(state.seq_no(), Some(20));
+
+        {
+            let _handle = state.insert(30);
+            assert_eq!(state.seq_no(), Some(20));
+            let _handle = state.insert(10);
+            assert_eq!(state.seq_no(), Some(10));
+
+            state.set_seq_no(Some(5));
+            assert_eq!(state.seq_no(), Some(5));
+        }
+
+        state.set_seq_no(None);
+        assert_eq!(state.seq_no(), None);
+    }
 }
@@ -0,0 +1,70 @@
+use std::collections::VecDeque;
+
+use parking_lot::Mutex;
+
+/// LRU cache for a few entries which are reused many times in a row
+/// (e.g. key block proofs during sync).
+///
+/// NOTE: lookups are linear, so the capacity must be small
+pub struct SmallLruCache<K, V> {
+    capacity: usize,
+    entries: Mutex<VecDeque<(K, V)>>,
+}
+
+impl<K, V> SmallLruCache<K, V>
+where
+    K: Eq,
+    V: Clone,
+{
+    pub fn new(capacity: usize) -> Self {
+        let capacity = std::cmp::max(capacity, 1);
+        Self {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        let mut entries = self.entries.lock();
+        let index = entries.iter().position(|(k, _)| k == key)?;
+
+        // Move the entry to the front
+        let entry = entries.remove(index)?;
+        let value = entry.1.clone();
+        entries.push_front(entry);
+        Some(value)
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        let mut entries = self.entries.lock();
+        if let Some(index) = entries.iter().position(|(k, _)| k == &key) {
+            entries.remove(index);
+        }
+        entries.truncate(self.capacity - 1);
+        entries.push_front((key, value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = SmallLruCache::new(2);
+        cache.insert(1, "a");
+        cache.insert(2, "b");
+        assert_eq!(cache.get(&1), Some("a"));
+
+        // `2` is the least recently used
+        cache.insert(3, "c");
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.get(&1), Some("a"));
+        assert_eq!(cache.get(&3), Some("c"));
+
+        // Reinserting replaces the value
+        cache.insert(1, "d");
+        assert_eq!(cache.get(&1), Some("d"));
+        assert_eq!(cache.get(&3), Some("c"));
+    }
+}
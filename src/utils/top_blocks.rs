@@ -5,7 +5,7 @@ use ton_types::ByteOrderRead;
 use super::{BlockStuff, StoredValue, StoredValueBuffer};
 
 /// Stores last blocks for each workchain and shard
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct TopBlocks {
     pub mc_block: ton_block::BlockIdExt,
     pub shard_heights: FxHashMap<ton_block::ShardIdent, u32>,
@@ -48,6 +48,54 @@ impl TopBlocks {
     }
 }
 
+impl TopBlocks {
+    /// Checks whether the set of shards differs from the previous one
+    /// (i.e. some shards were split or merged)
+    pub fn shard_config_changed(&self, prev: &TopBlocks) -> bool {
+        self.shard_heights.len() != prev.shard_heights.len()
+            || self
+                .shard_heights
+                .keys()
+                .any(|shard| !prev.shard_heights.contains_key(shard))
+    }
+
+    /// Returns shards which appeared and disappeared since the previous top blocks
+    pub fn shard_diff(&self, prev: &TopBlocks) -> ShardDiff {
+        let diff = |left: &TopBlocks, right: &TopBlocks| {
+            let mut shards = left
+                .shard_heights
+                .keys()
+                .filter(|shard| !right.shard_heights.contains_key(shard))
+                .cloned()
+                .collect::<Vec<_>>();
+            shards.sort_unstable_by_key(|shard| {
+                (shard.workchain_id(), shard.shard_prefix_with_tag())
+            });
+            shards
+        };
+
+        ShardDiff {
+            added: diff(self, prev),
+            removed: diff(prev, self),
+        }
+    }
+}
+
+/// Shards changes between two masterchain blocks
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+pub struct ShardDiff {
+    /// New shards (after split or merge). Sorted by workchain and prefix
+    pub added: Vec<ton_block::ShardIdent>,
+    /// Shards which no longer exist. Sorted by workchain and prefix
+    pub removed: Vec<ton_block::ShardIdent>,
+}
+
+impl ShardDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
 impl StoredValue for TopBlocks {
     const SIZE_HINT: usize = 512;
 
@@ -142,4 +190,49 @@ mod tests {
             ..Default::default()
         }));
     }
+
+    #[test]
+    fn shard_config_diff() {
+        let make_top_blocks = |mc_seq_no: u32, shards: &[(ton_block::ShardIdent, u32)]| TopBlocks {
+            mc_block: ton_block::BlockIdExt {
+                shard_id: ton_block::ShardIdent::masterchain(),
+                seq_no: mc_seq_no,
+                ..Default::default()
+            },
+            shard_heights: shards.iter().cloned().collect(),
+        };
+
+        let main_shard =
+            ton_block::ShardIdent::with_tagged_prefix(0, ton_block::SHARD_FULL).unwrap();
+        let (left_shard, right_shard) = main_shard.split().unwrap();
+
+        let before_split = make_top_blocks(100, &[(main_shard, 1000)]);
+        let same_shards = make_top_blocks(101, &[(main_shard, 1001)]);
+        let after_split = make_top_blocks(102, &[(left_shard, 1002), (right_shard, 1002)]);
+
+        // Only seqnos changed
+        assert!(!same_shards.shard_config_changed(&before_split));
+        assert!(same_shards.shard_diff(&before_split).is_empty());
+        assert_ne!(same_shards, before_split);
+
+        // Split
+        assert!(after_split.shard_config_changed(&same_shards));
+        assert_eq!(
+            after_split.shard_diff(&same_shards),
+            ShardDiff {
+                added: vec![left_shard, right_shard],
+                removed: vec![main_shard],
+            }
+        );
+
+        // Merge
+        assert!(same_shards.shard_config_changed(&after_split));
+        assert_eq!(
+            same_shards.shard_diff(&after_split),
+            ShardDiff {
+                added: vec![main_shard],
+                removed: vec![left_shard, right_shard],
+            }
+        );
+    }
 }
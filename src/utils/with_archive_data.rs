@@ -40,6 +40,14 @@ impl<T> WithArchiveData<T> {
             ArchiveData::Existing => Err(WithArchiveDataError),
         }
     }
+
+    /// Same as [`WithArchiveData::new_archive_data`], but returns a shared buffer
+    pub fn new_archive_bytes(&self) -> Result<Bytes, WithArchiveDataError> {
+        match &self.archive_data {
+            ArchiveData::New(data) => Ok(data.clone()),
+            ArchiveData::Existing => Err(WithArchiveDataError),
+        }
+    }
 }
 
 impl<T> std::ops::Deref for WithArchiveData<T> {
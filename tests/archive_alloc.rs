@@ -0,0 +1,147 @@
+//! Checks that parsed archive entries share the archive buffer
+//! and that pinned block reads don't copy the stored data.
+//!
+//! NOTE: this is a separate test binary because it replaces the global allocator
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use ton_block::Serializable;
+use ton_indexer::test_util::*;
+use ton_indexer::utils::{make_archive_segment, BlockStuff, PackageEntryId, ARCHIVE_PREFIX};
+
+/// Archive with the same block entry repeated the specified number of times
+fn make_archive(entry_count: usize) -> (bytes::Bytes, ton_block::BlockIdExt, Vec<u8>) {
+    let root = ton_block::Block::default().serialize().unwrap();
+    let block_data = ton_types::serialize_toc(&root).unwrap();
+    let block_id = ton_block::BlockIdExt {
+        shard_id: ton_block::ShardIdent::masterchain(),
+        seq_no: 1,
+        root_hash: root.repr_hash(),
+        file_hash: ton_types::UInt256::calc_file_hash(&block_data),
+    };
+
+    let mut archive = ARCHIVE_PREFIX.to_vec();
+    let segment =
+        make_archive_segment(&PackageEntryId::Block(&block_id).to_filename(), &block_data);
+    for _ in 0..entry_count {
+        archive.extend_from_slice(&segment);
+    }
+    (archive.into(), block_id, block_data)
+}
+
+#[test]
+fn archive_parse_shares_buffer() {
+    const ENTRY_COUNT: usize = 100;
+
+    let (archive, _, block_data) = make_archive(ENTRY_COUNT);
+
+    let (copied, copied_allocated) = count_allocated(|| import_package_copied(&archive).unwrap());
+    let (shared, shared_allocated) = count_allocated(|| import_package(archive.clone()).unwrap());
+
+    assert_eq!(copied, 1);
+    assert_eq!(shared, 1);
+    assert!(copied_allocated >= shared_allocated + ENTRY_COUNT * block_data.len());
+}
+
+#[test]
+fn pinned_block_reads_do_not_copy_data() {
+    const ROUNDS: usize = 100;
+
+    let (archive, block_id, block_data) = make_archive(1);
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    // Baseline: parsing the block which is already in memory
+    let (_, parsed_copies) = count_copies(block_data.len(), || {
+        for _ in 0..ROUNDS {
+            BlockStuff::deserialize_unchecked(block_id.clone(), &block_data).unwrap();
+        }
+    });
+
+    let temp_dir =
+        std::env::temp_dir().join(format!("ton_indexer_pinned_reads_{}", std::process::id()));
+    let blocks = rt.block_on(StoredBlocks::new(temp_dir, archive)).unwrap();
+    assert_eq!(blocks.block_count(), 1);
+
+    let load = |mode| {
+        count_copies(block_data.len(), || {
+            rt.block_on(async {
+                for _ in 0..ROUNDS {
+                    blocks.load_all(mode).await.unwrap();
+                }
+            })
+        })
+        .1
+    };
+
+    // Owned reads copy the data, pinned reads parse it in place
+    assert_eq!(load(BlockReadMode::Owned), parsed_copies + ROUNDS);
+    assert_eq!(load(BlockReadMode::Pinned), parsed_copies);
+}
+
+thread_local! {
+    /// Allocation counters (enabled only for the current thread)
+    static COUNTERS: Cell<Option<Counters>> = const { Cell::new(None) };
+}
+
+#[derive(Default, Copy, Clone)]
+struct Counters {
+    /// Total allocated bytes
+    allocated: usize,
+    /// Size of the buffer which is considered a copy of the data
+    copy_size: usize,
+    /// Number of allocations of exactly `copy_size` bytes
+    copies: usize,
+}
+
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = COUNTERS.try_with(|counters| {
+            if let Some(mut current) = counters.get() {
+                current.allocated += layout.size();
+                if layout.size() == current.copy_size {
+                    current.copies += 1;
+                }
+                counters.set(Some(current));
+            }
+        });
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn count<R>(copy_size: usize, f: impl FnOnce() -> R) -> (R, Counters) {
+    COUNTERS.with(|counters| {
+        counters.set(Some(Counters {
+            copy_size,
+            ..Default::default()
+        }))
+    });
+    let result = f();
+    let counters = COUNTERS.with(|counters| counters.take());
+    (result, counters.unwrap_or_default())
+}
+
+/// Returns the number of allocated bytes
+fn count_allocated<R>(f: impl FnOnce() -> R) -> (R, usize) {
+    let (result, counters) = count(usize::MAX, f);
+    (result, counters.allocated)
+}
+
+/// Returns the number of allocated buffers of exactly the specified size
+fn count_copies<R>(size: usize, f: impl FnOnce() -> R) -> (R, usize) {
+    let (result, counters) = count(size, f);
+    (result, counters.copies)
+}
@@ -0,0 +1,34 @@
+#!/usr/bin/env bash
+# Copies the DB of the stopped node into the fixture directory and removes
+# blocks which are not required to boot from it.
+#
+# Blocks are removed before the last key block not greater than `gc-before-seqno`
+# (the last masterchain block of the snapshot by default).
+#
+# Usage: snapshot.sh <db-dir> <fixture-dir> <node-a|node-b> [gc-before-seqno]
+
+set -euo pipefail
+
+if [ "$#" -lt 3 ] || [ "$#" -gt 4 ]; then
+    echo "Usage: $0 <db-dir> <fixture-dir> <node-a|node-b> [gc-before-seqno]" >&2
+    exit 1
+fi
+
+db_dir=$1
+out_dir=$2/$3
+tool=${TOOL:-target/debug/ton-indexer-tool}
+
+rm -rf "$out_dir"
+mkdir -p "$out_dir"
+cp -r "$db_dir/rocksdb" "$db_dir/files" "$out_dir/"
+rm -f "$out_dir/rocksdb/LOCK"
+
+run_tool() {
+    "$tool" --rocks-db-path "$out_dir/rocksdb" --file-db-path "$out_dir/files" "$@"
+}
+
+last_seqno=$(run_tool status | sed -n 's/.*"last_mc_block_seqno": *\([0-9]*\).*/\1/p')
+run_tool verify-storage --range "$last_seqno..$last_seqno" > /dev/null
+run_tool gc --blocks-before-seqno "${4:-$last_seqno}" > /dev/null
+
+echo "$3: last masterchain block $last_seqno"
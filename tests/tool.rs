@@ -0,0 +1,205 @@
+//! Runs `ton-indexer-tool` against generated DBs.
+//!
+//! DBs with blocks are created from the signed synthetic chain
+//! (see `test_util::SyntheticChain`), so no external fixtures are required.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use serde_json::Value;
+
+use ton_indexer::maintenance::MaintenanceDb;
+use ton_indexer::test_util::{create_db, SyntheticChain};
+use ton_indexer::utils::ArchivePackageViewReader;
+
+const MAX_RANGE_LEN: u64 = 16;
+const CHAIN_LEN: u32 = 20;
+
+#[test]
+fn status_does_not_modify_db() {
+    let db = TestDb::generated("status", 10);
+    let files_before = list_files(&db.path);
+
+    let status = db.run(&["status"]).success_json();
+    assert_eq!(status["init_mc_block_seqno"].as_u64(), Some(10));
+    assert_eq!(status["last_mc_block_seqno"].as_u64(), Some(10));
+    assert_eq!(status["shards_client_mc_block_seqno"].as_u64(), Some(10));
+
+    assert_eq!(list_files(&db.path), files_before);
+}
+
+#[test]
+fn read_only_commands_work_on_locked_db() {
+    let db = TestDb::generated("read-only", 10);
+    let (_runtime, _live) = db.open_live();
+
+    let status = db.run(&["status"]).success_json();
+    assert_eq!(status["last_mc_block_seqno"].as_u64(), Some(10));
+}
+
+#[test]
+fn refuses_locked_db() {
+    let db = TestDb::generated("locked", 10);
+    let (_runtime, _live) = db.open_live();
+
+    let output = db.run(&["gc", "--blocks-before-seqno", "0"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("locked"));
+}
+
+#[test]
+fn verify_storage_and_export_archive() {
+    let db = TestDb::synthetic("verify", CHAIN_LEN);
+
+    let status = db.run(&["status"]).success_json();
+    let last = status["last_mc_block_seqno"].as_u64().unwrap();
+    assert_eq!(last, CHAIN_LEN as u64);
+    let from = last.saturating_sub(MAX_RANGE_LEN);
+
+    let report = db
+        .run(&["verify-storage", "--range", &format!("{from}..{last}")])
+        .success_json();
+    assert_eq!(report["mc_blocks"].as_u64().unwrap(), last - from + 1);
+    assert!(report["missing"].as_array().unwrap().is_empty());
+    assert!(report["corrupted"].as_array().unwrap().is_empty());
+
+    let archive_path = db.path.join("exported.pack");
+    let report = db
+        .run(&[
+            "export-archive",
+            "--from",
+            &from.to_string(),
+            "--to",
+            &last.to_string(),
+            "--out",
+            archive_path.to_str().unwrap(),
+        ])
+        .success_json();
+
+    let archive = std::fs::read(&archive_path).unwrap();
+    assert_eq!(report["bytes"].as_u64().unwrap(), archive.len() as u64);
+
+    let mut reader = ArchivePackageViewReader::new(&archive).unwrap();
+    let mut entries = 0;
+    while reader.read_next().unwrap().is_some() {
+        entries += 1;
+    }
+
+    // Each masterchain block commits one shard block,
+    // and each block is stored with its proof
+    let range_len = last - from + 1;
+    assert_eq!(report["mc_blocks"].as_u64().unwrap(), range_len);
+    assert_eq!(report["shard_blocks"].as_u64().unwrap(), range_len);
+    assert_eq!(entries, range_len * 4);
+}
+
+struct TestDb {
+    path: PathBuf,
+}
+
+impl TestDb {
+    /// Creates a fresh DB with all masterchain pointers set to `mc_seqno`
+    fn generated(name: &str, mc_seqno: u32) -> Self {
+        let db = Self::empty(name);
+        let mc_block_id = ton_block::BlockIdExt {
+            shard_id: ton_block::ShardIdent::masterchain(),
+            seq_no: mc_seqno,
+            root_hash: Default::default(),
+            file_hash: Default::default(),
+        };
+
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(create_db(
+                db.path.join("rocksdb"),
+                db.path.join("files"),
+                &mc_block_id,
+            ))
+            .unwrap();
+        db
+    }
+
+    /// Creates the DB of the node which has applied the synthetic chain of `len` blocks
+    fn synthetic(name: &str, len: u32) -> Self {
+        let db = Self::empty(name);
+        let chain = SyntheticChain::generate(len, 1000, |seq_no| 1000 + seq_no).unwrap();
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(chain.create_db(&db.path))
+            .unwrap();
+
+        // NOTE: the chain DB uses the `file` subdirectory
+        std::fs::rename(db.path.join("file"), db.path.join("files")).unwrap();
+        db
+    }
+
+    fn empty(name: &str) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("ton-indexer-tool-{}-{name}", std::process::id()));
+        std::fs::remove_dir_all(&path).ok();
+        Self { path }
+    }
+
+    /// Opens the DB for writing as the running node does
+    fn open_live(&self) -> (tokio::runtime::Runtime, MaintenanceDb) {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let db = runtime
+            .block_on(MaintenanceDb::open(
+                self.path.join("rocksdb"),
+                self.path.join("files"),
+                64 << 20,
+                false,
+            ))
+            .unwrap();
+        (runtime, db)
+    }
+
+    fn run(&self, args: &[&str]) -> std::process::Output {
+        Command::new(env!("CARGO_BIN_EXE_ton-indexer-tool"))
+            .arg("--rocks-db-path")
+            .arg(self.path.join("rocksdb"))
+            .arg("--file-db-path")
+            .arg(self.path.join("files"))
+            .args(args)
+            .output()
+            .unwrap()
+    }
+}
+
+impl Drop for TestDb {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.path).ok();
+    }
+}
+
+trait OutputExt {
+    fn success_json(self) -> Value;
+}
+
+impl OutputExt for std::process::Output {
+    fn success_json(self) -> Value {
+        assert!(
+            self.status.success(),
+            "{}",
+            String::from_utf8_lossy(&self.stderr)
+        );
+        serde_json::from_slice(&self.stdout).unwrap()
+    }
+}
+
+/// Returns all file paths with their sizes.
+///
+/// NOTE: RocksDB info logs are skipped since they are rotated on each open
+fn list_files(path: &Path) -> Vec<(PathBuf, u64)> {
+    let mut result = Vec::new();
+    for entry in std::fs::read_dir(path).unwrap() {
+        let entry = entry.unwrap();
+        if entry.file_type().unwrap().is_dir() {
+            result.extend(list_files(&entry.path()));
+        } else if !entry.file_name().to_string_lossy().starts_with("LOG") {
+            result.push((entry.path(), entry.metadata().unwrap().len()));
+        }
+    }
+    result.sort();
+    result
+}
@@ -0,0 +1,200 @@
+//! Syncs one node from another one within the same process over loopback.
+//!
+//! The fixture is a directory specified by the `TWO_NODES_FIXTURE` env variable
+//! (see `tests/fixtures/README.md`), so the tests are ignored by default.
+//! The cold boot and the sync from archives between two engines is covered
+//! without any fixtures by `engine_syncs_from_mock_peer` (see `src/engine/mock_network.rs`),
+//! which generates a signed chain with `test_util::SyntheticChain`.
+//!
+//! - Node A is started from the `node-a` DB and serves blocks and archives.
+//! - Node B is started from the older `node-b` DB of the same chain and
+//!   syncs the missing blocks using node A as the only DHT node.
+//!
+//! ```bash
+//! TWO_NODES_FIXTURE=path/to/fixture cargo test --features test-util --test two_nodes -- --ignored
+//! ```
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use parking_lot::Mutex;
+
+use ton_indexer::utils::BlockIdExtDisplay;
+use ton_indexer::{Engine, GlobalConfig, NodeConfig, ProcessBlockContext, Subscriber};
+
+const SYNC_TIMEOUT: Duration = Duration::from_secs(600);
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "requires chain snapshots specified by TWO_NODES_FIXTURE"]
+async fn node_syncs_from_another_node() {
+    let fixture = Fixture::new();
+
+    let port = 30000 + (std::process::id() % 10000) as u16 * 2;
+
+    // Start node A without any DHT nodes so it only serves its blocks
+    let mut global_config_a = fixture.global_config();
+    global_config_a.dht_nodes.clear();
+
+    let node_a = Engine::new(
+        fixture.node_config("node-a", port),
+        global_config_a,
+        Vec::new(),
+    )
+    .await
+    .unwrap();
+    node_a.start().await.unwrap();
+
+    let head_a = node_a.load_last_applied_mc_block_id().unwrap();
+
+    // Start node B with node A as the only DHT node
+    let mut global_config_b = fixture.global_config();
+    global_config_b.dht_nodes = vec![node_a.network().dht().make_signed_node()];
+
+    let subscriber_b = Arc::new(RecordingSubscriber::default());
+    let node_b = Engine::new(
+        fixture.node_config("node-b", port + 1),
+        global_config_b,
+        vec![subscriber_b.clone() as Arc<dyn Subscriber>],
+    )
+    .await
+    .unwrap();
+
+    let start_b = node_b.load_last_applied_mc_block_id().unwrap();
+    assert!(
+        start_b.seq_no < head_a.seq_no,
+        "node B must be behind node A"
+    );
+
+    node_b.start().await.unwrap();
+
+    tokio::time::timeout(SYNC_TIMEOUT, async {
+        loop {
+            let head_b = node_b.load_last_applied_mc_block_id().unwrap();
+            if head_b.seq_no >= head_a.seq_no {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+    })
+    .await
+    .expect("node B didn't reach the head of node A");
+
+    // Applied head
+    let head_b = node_b.load_last_applied_mc_block_id().unwrap();
+    assert_eq!(head_b, head_a);
+
+    // Subscriber delivery: each masterchain block of node A which node B
+    // didn't have is delivered exactly once and in order
+    let delivered = subscriber_b.delivered();
+    let mc_seqnos = delivered
+        .iter()
+        .filter(|id| id.shard_id.is_masterchain())
+        .map(|id| id.seq_no)
+        .collect::<Vec<_>>();
+    let expected = (start_b.seq_no + 1..=head_a.seq_no).collect::<Vec<_>>();
+    assert_eq!(mc_seqnos, expected);
+
+    let mut unique = delivered.clone();
+    unique.sort_by_key(|id| (id.shard_id, id.seq_no));
+    unique.dedup();
+    assert_eq!(unique.len(), delivered.len(), "duplicate notifications");
+
+    // Index contents
+    for id in &delivered {
+        let data_a = node_a.get_raw_block_data(id).await.unwrap();
+        let data_b = node_b.get_raw_block_data(id).await.unwrap();
+        assert!(data_b.is_some(), "block {} was not stored", id.display());
+        assert_eq!(data_a, data_b, "block {} differs", id.display());
+
+        let meta_a = node_a.block_meta(id).unwrap().unwrap();
+        let meta_b = node_b.block_meta(id).unwrap().unwrap();
+        assert_eq!(meta_a.gen_utime(), meta_b.gen_utime());
+        assert_eq!(
+            meta_a.masterchain_ref_seqno(),
+            meta_b.masterchain_ref_seqno()
+        );
+    }
+
+    node_b.shutdown();
+    node_a.shutdown();
+}
+
+#[derive(Default)]
+struct RecordingSubscriber {
+    delivered: Mutex<Vec<ton_block::BlockIdExt>>,
+}
+
+impl RecordingSubscriber {
+    fn delivered(&self) -> Vec<ton_block::BlockIdExt> {
+        self.delivered.lock().clone()
+    }
+}
+
+#[async_trait::async_trait]
+impl Subscriber for RecordingSubscriber {
+    async fn process_block(&self, ctx: ProcessBlockContext<'_>) -> Result<()> {
+        self.delivered.lock().push(ctx.id().clone());
+        Ok(())
+    }
+}
+
+struct Fixture {
+    source: PathBuf,
+    path: PathBuf,
+}
+
+impl Fixture {
+    fn new() -> Self {
+        let source = PathBuf::from(
+            std::env::var_os("TWO_NODES_FIXTURE").expect("TWO_NODES_FIXTURE is not set"),
+        );
+
+        let path =
+            std::env::temp_dir().join(format!("ton-indexer-two-nodes-{}", std::process::id()));
+        for node in ["node-a", "node-b"] {
+            copy_dir(&source.join(node), &path.join(node)).unwrap();
+        }
+
+        Self { source, path }
+    }
+
+    fn global_config(&self) -> GlobalConfig {
+        GlobalConfig::load(self.source.join("global.config.json")).unwrap()
+    }
+
+    fn node_config(&self, node: &str, port: u16) -> NodeConfig {
+        let path = self.path.join(node);
+        NodeConfig {
+            ip_address: SocketAddrV4::new(Ipv4Addr::LOCALHOST, port),
+            rocks_db_path: path.join("rocksdb"),
+            file_db_path: path.join("files"),
+            temp_files_path: path.join("downloads"),
+            max_db_memory_usage: 256 << 20,
+            require_preseeded: true,
+            ..Default::default()
+        }
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        std::fs::remove_dir_all(&self.path).ok();
+    }
+}
+
+fn copy_dir(from: &Path, to: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else if entry.file_name() != "LOCK" {
+            std::fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}